@@ -0,0 +1,148 @@
+//! Google Additional Consent (AC) string support.
+//!
+//! Google's Additional Consent Mode lets CMPs collect consent for ad tech
+//! providers that are not registered with the IAB TCF. The AC string is
+//! written next to the TC string as `<version>~<consented IDs>`, with a
+//! third `dv.<disclosed IDs>` part from version 2 on, e.g.
+//! `2~1.35.41.101~dv.9.21.81`. IDs are Google ATP list provider IDs. It is
+//! read from the `addtl_consent` query parameter or cookie.
+
+use fastly::Request;
+
+use crate::cookies;
+
+/// Name of the cookie and query parameter holding the AC string.
+pub const ADDTL_CONSENT_PARAM: &str = "addtl_consent";
+
+/// Highest supported AC string specification version.
+const MAX_VERSION: u8 = 2;
+
+/// Parsed Additional Consent string.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AdditionalConsent {
+    /// Original AC string.
+    pub ac_string: String,
+    /// Specification version.
+    pub version: u8,
+    /// Providers the user consented to.
+    pub consented: Vec<u32>,
+    /// Providers disclosed to the user without consent (version 2).
+    pub disclosed: Vec<u32>,
+}
+
+/// Parses a `.`-separated list of provider IDs; an empty list is valid.
+fn parse_ids(ids: &str) -> Result<Vec<u32>, String> {
+    ids.split('.')
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            id.parse()
+                .map_err(|_| format!("Invalid Additional Consent provider ID {:?}", id))
+        })
+        .collect()
+}
+
+impl AdditionalConsent {
+    /// Parses an AC string such as `2~1.35.41~dv.9.21`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error message if the string is not a version 1 or 2 AC string.
+    pub fn parse(ac_string: &str) -> Result<Self, String> {
+        let mut parts = ac_string.split('~');
+        let version = parts
+            .next()
+            .and_then(|version| version.parse::<u8>().ok())
+            .filter(|version| (1..=MAX_VERSION).contains(version))
+            .ok_or_else(|| format!("Unsupported Additional Consent string {:?}", ac_string))?;
+        let consented = parse_ids(parts.next().unwrap_or_default())?;
+        let disclosed = match parts.next() {
+            Some(disclosed) if version >= 2 => parse_ids(
+                disclosed
+                    .strip_prefix("dv.")
+                    .ok_or("Invalid Additional Consent disclosed vendors")?,
+            )?,
+            Some(_) => return Err("Unexpected part in a version 1 AC string".to_string()),
+            None => Vec::new(),
+        };
+        if parts.next().is_some() {
+            return Err("Too many parts in the Additional Consent string".to_string());
+        }
+
+        Ok(Self {
+            ac_string: ac_string.to_string(),
+            version,
+            consented,
+            disclosed,
+        })
+    }
+
+    /// Whether the user consented to the provider `provider_id`.
+    pub fn has_consent(&self, provider_id: u32) -> bool {
+        self.consented.contains(&provider_id)
+    }
+}
+
+/// Extracts the AC string from the `addtl_consent` query parameter or cookie.
+///
+/// Returns [`None`] if neither is present or the string is invalid.
+pub fn get_additional_consent_from_request(req: &Request) -> Option<AdditionalConsent> {
+    let ac_string = req
+        .get_query_parameter(ADDTL_CONSENT_PARAM)
+        .map(str::to_string)
+        .or_else(|| {
+            let jar = cookies::handle_request_cookies(req).ok().flatten()?;
+            jar.get(ADDTL_CONSENT_PARAM).map(|c| c.value().to_string())
+        })
+        .filter(|value| !value.is_empty())?;
+    match AdditionalConsent::parse(&ac_string) {
+        Ok(consent) => Some(consent),
+        Err(e) => {
+            log::warn!("Ignoring invalid Additional Consent string: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_additional_consent() {
+        let consent = AdditionalConsent::parse("2~1.35.41.101~dv.9.21.81").unwrap();
+        assert_eq!(consent.version, 2);
+        assert_eq!(consent.consented, vec![1, 35, 41, 101]);
+        assert_eq!(consent.disclosed, vec![9, 21, 81]);
+        assert!(consent.has_consent(35));
+        assert!(!consent.has_consent(9));
+
+        let consent = AdditionalConsent::parse("1~7.89").unwrap();
+        assert_eq!(consent.consented, vec![7, 89]);
+        assert!(consent.disclosed.is_empty());
+
+        let consent = AdditionalConsent::parse("2~~dv.").unwrap();
+        assert!(consent.consented.is_empty());
+
+        for invalid in ["", "3~1.2", "1~1.2~dv.3", "2~1.x", "2~1~9.21", "2~1~dv.2~3"] {
+            assert!(AdditionalConsent::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_get_additional_consent_from_request() {
+        let req = Request::get("https://example.com").with_header("Cookie", "addtl_consent=1~7.89");
+        let consent = get_additional_consent_from_request(&req).unwrap();
+        assert_eq!(consent.ac_string, "1~7.89");
+
+        let req = Request::get("https://example.com/?addtl_consent=2~1~dv.2")
+            .with_header("Cookie", "addtl_consent=1~7.89");
+        let consent = get_additional_consent_from_request(&req).unwrap();
+        assert_eq!(consent.disclosed, vec![2]);
+
+        let req = Request::get("https://example.com").with_header("Cookie", "addtl_consent=bogus");
+        assert!(get_additional_consent_from_request(&req).is_none());
+        assert!(
+            get_additional_consent_from_request(&Request::get("https://example.com")).is_none()
+        );
+    }
+}
@@ -0,0 +1,374 @@
+//! AMP real-time config (RTC) endpoint.
+//!
+//! AMP pages cannot run Prebid.js; instead `amp-ad` calls the RTC vendor URLs
+//! of the publisher before requesting an ad and merges the `targeting` they
+//! return into the ad server request. `GET /amp-rtc` ([`handle_amp_rtc`]) is
+//! such a vendor URL: it auctions the slot described by its query string on
+//! the edge and returns the `hb_*` targeting of the winning bid. Its
+//! creative is cached (see [`crate::prebid_cache`]), so the ad server's
+//! Prebid line item renders it through `hb_cache_id`.
+//!
+//! A typical RTC config:
+//!
+//! ```text
+//! https://ts.example.com/amp-rtc?slot=ATTR(data-slot)&w=ATTR(width)&h=ATTR(height)
+//!     &ms=ATTR(data-multi-size)&curl=CANONICAL_URL&timeout=TIMEOUT
+//!     &consent_string=CONSENT_STRING&consent_type=CONSENT_METADATA(consentStringType)
+//! ```
+
+use std::time::Duration;
+
+use error_stack::{Report, ResultExt};
+use fastly::http::{header, HeaderName, Method, StatusCode};
+use fastly::{Error, Request, Response};
+use serde::Deserialize;
+use serde_json::json;
+use url::Url;
+
+use crate::consent::ConsentDecision;
+use crate::deadline::Deadline;
+use crate::error::TrustedServerError;
+use crate::gpp::GPP_PARAM;
+use crate::prebid::{execute_auction, parse_auction_request, AuctionRequest};
+use crate::settings::Settings;
+use crate::stored_request::StoredRequests;
+use crate::us_privacy::US_PRIVACY_COOKIES;
+
+/// Header AMP checks against the `__amp_source_origin` of its requests.
+const AMP_ALLOW_SOURCE_ORIGIN: HeaderName =
+    HeaderName::from_static("amp-access-control-allow-source-origin");
+
+/// AMP `consentStringType` of a TCF v2 string
+const CONSENT_TYPE_TCF_V2: u8 = 2;
+
+/// AMP `consentStringType` of a US Privacy string
+const CONSENT_TYPE_US_PRIVACY: u8 = 3;
+
+/// AMP `consentStringType` of a GPP string
+const CONSENT_TYPE_GPP: u8 = 4;
+
+/// Query parameters of `GET /amp-rtc`
+#[derive(Debug, Default, Deserialize)]
+struct AmpRtcParams {
+    /// Slot code, used as the impression ID and tag ID
+    #[serde(default)]
+    slot: Option<String>,
+    /// Stored slot configuration (see [`StoredRequests`]) auctioned instead
+    /// of the sizes of the request
+    #[serde(default)]
+    tag_id: Option<String>,
+    /// Width of the `amp-ad`
+    #[serde(default)]
+    w: Option<u32>,
+    /// Height of the `amp-ad`
+    #[serde(default)]
+    h: Option<u32>,
+    /// Further sizes as `300x250,320x50` (`data-multi-size`)
+    #[serde(default)]
+    ms: Option<String>,
+    /// Canonical URL of the AMP page
+    #[serde(default)]
+    curl: Option<String>,
+    /// Milliseconds AMP waits for the RTC response
+    #[serde(default)]
+    timeout: Option<u64>,
+    /// Consent string of the AMP consent management platform
+    #[serde(default)]
+    consent_string: Option<String>,
+    /// AMP `consentStringType` of `consent_string`, TCF v2 when missing
+    #[serde(default)]
+    consent_type: Option<u8>,
+}
+
+impl AmpRtcParams {
+    fn from_request(req: &Request) -> Result<Self, Report<TrustedServerError>> {
+        req.get_query().change_context(TrustedServerError::Prebid {
+            message: "Invalid AMP RTC parameters".to_string(),
+        })
+    }
+
+    /// Slot sizes: `w`x`h` followed by the `ms` sizes. Malformed `ms` entries
+    /// are kept as `(0, 0)` so validation rejects them.
+    fn sizes(&self) -> Vec<(u32, u32)> {
+        let primary = self.w.zip(self.h);
+        let multi = self
+            .ms
+            .iter()
+            .flat_map(|ms| ms.split(','))
+            .filter_map(|size| {
+                let size = size.trim();
+                (!size.is_empty()).then(|| {
+                    size.split_once('x')
+                        .and_then(|(w, h)| Some((w.trim().parse().ok()?, h.trim().parse().ok()?)))
+                        .unwrap_or((0, 0))
+                })
+            });
+        let mut sizes: Vec<(u32, u32)> = Vec::new();
+        for size in primary.into_iter().chain(multi) {
+            if !sizes.contains(&size) {
+                sizes.push(size);
+            }
+        }
+        sizes
+    }
+
+    /// Cookie `consent_string` is read from by the consent decision, or
+    /// [`None`] for unsupported consent types and strings that are not
+    /// cookie-safe.
+    fn consent_cookie(&self) -> Option<String> {
+        let consent = self.consent_string.as_deref().filter(|consent| {
+            !consent.is_empty()
+                && consent
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-_.~+/=".contains(&b))
+        })?;
+        let name = match self.consent_type.unwrap_or(CONSENT_TYPE_TCF_V2) {
+            CONSENT_TYPE_TCF_V2 => "euconsent-v2",
+            CONSENT_TYPE_US_PRIVACY => US_PRIVACY_COOKIES[0],
+            CONSENT_TYPE_GPP => GPP_PARAM,
+            _ => return None,
+        };
+        Some(format!("{name}={consent}"))
+    }
+}
+
+/// Parses the slot of an AMP RTC request into an auction request, expanding
+/// `tag_id` from `stored`.
+///
+/// # Errors
+///
+/// - [`TrustedServerError::Prebid`] if the parameters do not describe a valid
+///   slot or reference an unknown stored request
+/// - [`TrustedServerError::KvStore`] if a stored request cannot be looked up
+fn parse_amp_request(
+    params: &AmpRtcParams,
+    stored: Option<&StoredRequests>,
+) -> Result<AuctionRequest, Report<TrustedServerError>> {
+    let slot = match (&params.tag_id, &params.slot) {
+        (Some(tag_id), code) => json!({ "storedrequest": tag_id, "code": code }),
+        (None, Some(code)) => json!({ "code": code, "sizes": params.sizes() }),
+        (None, None) => {
+            return Err(Report::new(TrustedServerError::Prebid {
+                message: "Expected slot or tag_id".to_string(),
+            }))
+        }
+    };
+    parse_auction_request(json!([slot]).to_string().as_bytes(), stored)
+}
+
+/// Request the auction is run for: `req` with the AMP consent string as the
+/// cookie it is read from and the canonical URL as `Referer`, since RTC calls
+/// are made from the AMP cache rather than the publisher's pages.
+fn amp_auction_request(req: &Request, params: &AmpRtcParams) -> Request {
+    let mut auction_req = req.clone_without_body();
+    if let Some(consent_cookie) = params.consent_cookie() {
+        let cookie = match req.get_header_str(header::COOKIE) {
+            Some(existing) if !existing.is_empty() => format!("{existing}; {consent_cookie}"),
+            _ => consent_cookie,
+        };
+        auction_req.set_header(header::COOKIE, cookie);
+    }
+    let canonical_url = params
+        .curl
+        .as_deref()
+        .and_then(|curl| Url::parse(curl).ok())
+        .filter(|url| url.scheme() == "https" && url.host_str().is_some());
+    if let Some(url) = canonical_url {
+        auction_req.set_header(header::REFERER, url.as_str());
+    }
+    auction_req
+}
+
+/// Adds the CORS headers AMP requires of RTC responses.
+fn with_amp_cors(mut resp: Response, req: &Request) -> Response {
+    if let Some(origin) = req.get_header_str(header::ORIGIN) {
+        resp.set_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        resp.set_header(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+    }
+    let source_origin = req
+        .get_url()
+        .query_pairs()
+        .find_map(|(name, value)| (name == "__amp_source_origin").then_some(value));
+    if let Some(source_origin) = source_origin {
+        resp.set_header(AMP_ALLOW_SOURCE_ORIGIN, source_origin.as_ref());
+        resp.set_header(
+            header::ACCESS_CONTROL_EXPOSE_HEADERS,
+            AMP_ALLOW_SOURCE_ORIGIN.as_str(),
+        );
+    }
+    resp
+}
+
+/// Handles `GET /amp-rtc`, the AMP real-time config callout.
+///
+/// Auctions the slot given by `slot` and the `w`, `h` and `ms` sizes, or the
+/// stored slot configuration `tag_id`, with the consent of `consent_string`
+/// (see [`execute_auction`]), and responds with `{"targeting": {...}}`
+/// holding the `hb_*` keys of the winning bid, empty without one. The auction
+/// ends within AMP's `timeout` when it is shorter than `deadline`. Responds
+/// `400` with a JSON error when the parameters do not describe a valid slot.
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if response creation or the auction fails, or
+/// stored requests cannot be read.
+pub async fn handle_amp_rtc(
+    settings: &Settings,
+    req: Request,
+    deadline: Deadline,
+) -> Result<Response, Error> {
+    if *req.get_method() != Method::GET {
+        return Ok(
+            Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_body("Method not allowed")
+        );
+    }
+    let stored = StoredRequests::open(settings).map_err(|e| Error::msg(format!("{e:?}")))?;
+    let parsed = AmpRtcParams::from_request(&req)
+        .and_then(|params| Ok((parse_amp_request(&params, stored.as_ref())?, params)));
+    let (auction, params) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) if matches!(e.current_context(), TrustedServerError::KvStore { .. }) => {
+            return Err(Error::msg(format!("{e:?}")));
+        }
+        Err(e) => {
+            log::warn!("Rejected AMP RTC request: {:?}", e);
+            let resp = Response::from_status(StatusCode::BAD_REQUEST)
+                .with_header(header::CONTENT_TYPE, "application/json")
+                .with_body_json(&json!({ "error": e.current_context().to_string() }))?;
+            return Ok(with_amp_cors(resp, &req));
+        }
+    };
+
+    let mut auction_req = amp_auction_request(&req, &params);
+    let consent = ConsentDecision::from_request(settings, &auction_req)
+        .with_objection(settings, &auction_req);
+    let deadline = match params.timeout {
+        Some(timeout) => deadline.within(Duration::from_millis(timeout)),
+        None => deadline,
+    };
+    let result = execute_auction(settings, &consent, &mut auction_req, auction, deadline).await?;
+    let targeting = result
+        .bids
+        .into_iter()
+        .next()
+        .map(|bid| bid.targeting)
+        .unwrap_or_default();
+
+    let resp = Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_header(header::CACHE_CONTROL, "no-store, private")
+        .with_body_json(&json!({ "targeting": targeting }))?;
+    Ok(with_amp_cors(resp, &req))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::test_support::tests::create_test_settings;
+
+    const TC_STRING: &str = "COvFyGBOvFyGBAbAAAENAPCAAOAAAAAAAAAAAEEUACCKAAA";
+
+    fn params(query: &str) -> AmpRtcParams {
+        AmpRtcParams::from_request(&Request::get(format!(
+            "https://example.com/amp-rtc?{query}"
+        )))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_amp_request() {
+        let auction =
+            parse_amp_request(&params("slot=amp-top&w=300&h=250&ms=320x50,300x250"), None).unwrap();
+        assert_eq!(auction.slots.len(), 1);
+        assert_eq!(auction.slots[0].code, "amp-top");
+        assert_eq!(auction.slots[0].sizes, vec![(300, 250), (320, 50)]);
+
+        let invalid = ["w=300&h=250", "slot=amp-top", "slot=amp-top&ms=300by250"];
+        for query in invalid {
+            assert!(parse_amp_request(&params(query), None).is_err(), "{query}");
+        }
+    }
+
+    #[test]
+    fn test_parse_amp_request_stored() {
+        let mut settings = create_test_settings();
+        settings.prebid.stored_requests_kv_store = "test_stored_request_store".to_string();
+        let stored = StoredRequests::open(&settings).unwrap();
+        let auction =
+            parse_amp_request(&params("tag_id=homepage-top&slot=amp-top"), stored.as_ref())
+                .unwrap();
+        assert_eq!(auction.slots[0].code, "amp-top");
+        assert_eq!(auction.slots[0].sizes, vec![(728, 90), (970, 250)]);
+    }
+
+    #[test]
+    fn test_amp_auction_request() {
+        let settings = create_test_settings();
+        let query = format!(
+            "slot=amp-top&curl=https%3A%2F%2Fnews.example%2Farticle&consent_string={TC_STRING}"
+        );
+        let mut req = Request::get(format!("https://example.com/amp-rtc?{query}"));
+        req.set_header(header::COOKIE, "other=1");
+        let auction_req = amp_auction_request(&req, &params(&query));
+        assert_eq!(
+            auction_req.get_header_str(header::COOKIE),
+            Some(format!("other=1; euconsent-v2={TC_STRING}").as_str())
+        );
+        assert_eq!(
+            auction_req.get_header_str(header::REFERER),
+            Some("https://news.example/article")
+        );
+        let consent = ConsentDecision::from_request(&settings, &auction_req);
+        assert_eq!(consent.signals.tcf.tc_string, TC_STRING);
+    }
+
+    #[test]
+    fn test_consent_cookie() {
+        let cases = [
+            (
+                "consent_string=1YNN&consent_type=3",
+                Some("us_privacy=1YNN"),
+            ),
+            (
+                "consent_string=DBABMA~CPXxRfA&consent_type=4",
+                Some("gpp=DBABMA~CPXxRfA"),
+            ),
+            ("consent_string=1YNN&consent_type=1", None),
+            ("consent_string=a%3Bb", None),
+            ("consent_type=2", None),
+        ];
+        for (query, expected) in cases {
+            assert_eq!(
+                params(query).consent_cookie().as_deref(),
+                expected,
+                "{query}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_handle_amp_rtc_rejections() {
+        let settings = create_test_settings();
+        let deadline = Deadline::new(Instant::now(), Duration::from_secs(1));
+        let mut req = Request::get(
+            "https://example.com/amp-rtc?w=300&h=250&__amp_source_origin=https%3A%2F%2Fnews.example",
+        );
+        req.set_header(header::ORIGIN, "https://news-example.cdn.ampproject.org");
+        let resp = futures::executor::block_on(handle_amp_rtc(&settings, req, deadline)).unwrap();
+        assert_eq!(resp.get_status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            resp.get_header_str(header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://news-example.cdn.ampproject.org")
+        );
+        assert_eq!(
+            resp.get_header_str(AMP_ALLOW_SOURCE_ORIGIN),
+            Some("https://news.example")
+        );
+
+        let req = Request::post("https://example.com/amp-rtc?slot=amp-top&w=300&h=250");
+        let resp = futures::executor::block_on(handle_amp_rtc(&settings, req, deadline)).unwrap();
+        assert_eq!(resp.get_status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
@@ -0,0 +1,432 @@
+//! Anonymized visit aggregates for long-term analytics.
+//!
+//! Visit counters in `synthetic.counter_store` are keyed by synthetic ID, so
+//! they are personal data and expire with the store's retention period.
+//! [`anonymize_counters`] folds the counters of synthetic IDs not seen for
+//! `anonymization.window_days` into monthly [`VisitAggregate`]s, which count
+//! synthetic IDs per visit count bucket and hold no identifier, IP or
+//! timestamp finer than the month, and then deletes the counters.
+//! [`AggregateReport`] publishes an aggregate with every bucket smaller than
+//! `anonymization.min_bucket_size` suppressed, so no reported bucket describes
+//! fewer than k users.
+//!
+//! Operators (see [`crate::auth`]) run the fold one page of counters at a
+//! time with `POST /admin/anonymize` and read reports with
+//! `GET /admin/aggregates?period=YYYY-MM`. Runs read and rewrite aggregates
+//! without locking, so they should not overlap.
+
+use std::collections::BTreeMap;
+
+use error_stack::Report;
+use fastly::http::{header, Method, StatusCode};
+use fastly::{Error, Request, Response};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::authenticate;
+use crate::error::TrustedServerError;
+use crate::kv_store::JsonKvStore;
+use crate::retention::SECONDS_PER_DAY;
+use crate::settings::Settings;
+
+/// Path folding idle visit counters into aggregates.
+pub const ANONYMIZE_PATH: &str = "/admin/anonymize";
+
+/// Path reporting the aggregate of a month.
+pub const AGGREGATES_PATH: &str = "/admin/aggregates";
+
+/// Counters examined per anonymization run.
+const PAGE_SIZE: u32 = 100;
+
+/// Synthetic IDs whose last visit fell in one month, counted per visit count
+/// bucket.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VisitAggregate {
+    /// Lower bound of a visit count bucket → synthetic IDs in it.
+    pub buckets: BTreeMap<u64, u64>,
+}
+
+impl VisitAggregate {
+    /// Counts one synthetic ID with `visits` visits.
+    fn add(&mut self, visits: u64, bounds: &[u64]) {
+        let bucket = bounds
+            .iter()
+            .copied()
+            .filter(|&bound| bound <= visits)
+            .max()
+            .unwrap_or_default();
+        *self.buckets.entry(bucket).or_default() += 1;
+    }
+
+    /// Adds the counts of `other`.
+    fn merge(&mut self, other: &VisitAggregate) {
+        for (&bucket, &count) in &other.buckets {
+            *self.buckets.entry(bucket).or_default() += count;
+        }
+    }
+}
+
+/// Number of synthetic IDs in a reported visit count bucket.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketCount {
+    /// Visit count range of the bucket, such as `"2-4"` or `"100+"`.
+    pub visits: String,
+    /// Synthetic IDs in the bucket.
+    pub synthetic_ids: u64,
+}
+
+/// A [`VisitAggregate`] with buckets below the k-anonymity threshold suppressed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AggregateReport {
+    /// Month of the aggregate (`YYYY-MM`).
+    pub period: String,
+    /// Buckets holding at least `anonymization.min_bucket_size` synthetic IDs.
+    pub buckets: Vec<BucketCount>,
+    /// Synthetic IDs in buckets too small to report.
+    pub suppressed: u64,
+}
+
+impl AggregateReport {
+    /// Reports `aggregate`, suppressing buckets with fewer than
+    /// `min_bucket_size` synthetic IDs.
+    pub fn new(period: &str, aggregate: &VisitAggregate, settings: &Settings) -> Self {
+        let config = &settings.anonymization;
+        let mut report = Self {
+            period: period.to_string(),
+            buckets: Vec::new(),
+            suppressed: 0,
+        };
+        for (&bucket, &count) in &aggregate.buckets {
+            if count < config.min_bucket_size {
+                report.suppressed += count;
+                continue;
+            }
+            report.buckets.push(BucketCount {
+                visits: bucket_label(bucket, &config.visit_buckets),
+                synthetic_ids: count,
+            });
+        }
+        report
+    }
+}
+
+/// Label of the bucket starting at `bucket`: `"1"`, `"2-4"` or `"100+"`.
+fn bucket_label(bucket: u64, bounds: &[u64]) -> String {
+    match bounds.iter().copied().filter(|&bound| bound > bucket).min() {
+        Some(next) if next == bucket + 1 => bucket.to_string(),
+        Some(next) => format!("{}-{}", bucket, next - 1),
+        None => format!("{bucket}+"),
+    }
+}
+
+/// KV store of monthly aggregates keyed by period.
+pub struct AggregateStore {
+    store: JsonKvStore,
+}
+
+impl AggregateStore {
+    /// Opens the store configured in `anonymization.aggregate_store`.
+    ///
+    /// Returns [`None`] when anonymization is disabled.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the configured store cannot be opened
+    pub fn open(settings: &Settings) -> Result<Option<Self>, Report<TrustedServerError>> {
+        if settings.anonymization.aggregate_store.is_empty() {
+            return Ok(None);
+        }
+        let store = JsonKvStore::open(&settings.anonymization.aggregate_store)?;
+        Ok(Some(Self { store }))
+    }
+
+    fn key(period: &str) -> String {
+        format!("aggregate:{period}")
+    }
+
+    /// Returns the aggregate of `period`, empty if nothing was folded into it.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the lookup fails
+    pub fn get(&self, period: &str) -> Result<VisitAggregate, Report<TrustedServerError>> {
+        Ok(self.store.get(&Self::key(period))?.unwrap_or_default())
+    }
+
+    /// Adds the counts of `aggregate` to the aggregate of `period`.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the store cannot be read or written
+    pub fn merge(
+        &self,
+        period: &str,
+        aggregate: &VisitAggregate,
+    ) -> Result<(), Report<TrustedServerError>> {
+        let mut stored = self.get(period)?;
+        stored.merge(aggregate);
+        self.store.put(&Self::key(period), &stored)
+    }
+}
+
+/// Outcome of one [`anonymize_counters`] run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnonymizationRun {
+    /// Counters examined.
+    pub scanned: usize,
+    /// Counters folded into aggregates and deleted.
+    pub anonymized: usize,
+    /// Cursor to pass to the next run, or [`None`] once every counter was examined.
+    pub next_cursor: Option<String>,
+}
+
+/// Month (`YYYY-MM`) of the Unix timestamp `timestamp`.
+fn period_of(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .unwrap_or_default()
+        .format("%Y-%m")
+        .to_string()
+}
+
+/// Folds one page of idle visit counters into `aggregates`, starting after
+/// `cursor`.
+///
+/// A counter is idle once its last write is older than
+/// `anonymization.window_days`; it is counted in the aggregate of the month of
+/// that write. Aggregates are stored before the counters are deleted, so a
+/// failed run may count a counter twice but never loses one. Counters without
+/// a recorded write time or with a non-numeric value are left alone.
+///
+/// # Errors
+///
+/// - [`TrustedServerError::KvStore`] if a store cannot be listed, read or written
+pub fn anonymize_counters(
+    settings: &Settings,
+    aggregates: &AggregateStore,
+    cursor: Option<&str>,
+) -> Result<AnonymizationRun, Report<TrustedServerError>> {
+    let counters = JsonKvStore::open(&settings.synthetic.counter_store)?;
+    let (keys, next_cursor) = counters.list_keys(cursor, PAGE_SIZE)?;
+    let window = i64::from(settings.anonymization.window_days) * SECONDS_PER_DAY as i64;
+    let cutoff = chrono::Utc::now().timestamp() - window;
+
+    let mut folded: BTreeMap<String, VisitAggregate> = BTreeMap::new();
+    let mut idle = Vec::new();
+    for key in &keys {
+        let Some(entry) = counters.get_text_stamped(key)? else {
+            continue;
+        };
+        let Some(written_at) = entry.written_at.filter(|&at| at < cutoff) else {
+            continue;
+        };
+        let Ok(visits) = entry.value.trim().parse::<u64>() else {
+            continue;
+        };
+        folded
+            .entry(period_of(written_at))
+            .or_default()
+            .add(visits, &settings.anonymization.visit_buckets);
+        idle.push(key);
+    }
+
+    for (period, aggregate) in &folded {
+        aggregates.merge(period, aggregate)?;
+    }
+    for key in &idle {
+        counters.delete(key)?;
+    }
+    log::info!("Anonymized {} of {} visit counters", idle.len(), keys.len());
+
+    Ok(AnonymizationRun {
+        scanned: keys.len(),
+        anonymized: idle.len(),
+        next_cursor,
+    })
+}
+
+fn json_response(body: &impl Serialize) -> Result<Response, Error> {
+    Ok(Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_header(header::CACHE_CONTROL, "no-store, private")
+        .with_body_json(body)?)
+}
+
+/// Handles `POST /admin/anonymize`.
+///
+/// Runs [`anonymize_counters`] from the `cursor` query parameter and responds
+/// with the [`AnonymizationRun`]; callers repeat with its `next_cursor` until
+/// it is `null`. Requires an operator credential checked by [`authenticate`],
+/// and responds `404` while anonymization is disabled.
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if a store fails or response creation fails.
+pub fn handle_anonymize(settings: &Settings, req: Request) -> Result<Response, Error> {
+    if *req.get_method() != Method::POST {
+        return Ok(
+            Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_body("Method not allowed")
+        );
+    }
+    if let Err(e) = authenticate(settings, &req) {
+        return Ok(e.into_response());
+    }
+    let Some(aggregates) =
+        AggregateStore::open(settings).map_err(|e| Error::msg(format!("{e:?}")))?
+    else {
+        return Ok(Response::from_status(StatusCode::NOT_FOUND).with_body("Not Found"));
+    };
+
+    let run = anonymize_counters(settings, &aggregates, req.get_query_parameter("cursor"))
+        .map_err(|e| Error::msg(format!("{e:?}")))?;
+    json_response(&run)
+}
+
+/// Handles `GET /admin/aggregates?period=YYYY-MM`.
+///
+/// Responds with the [`AggregateReport`] of the month. Requires an operator
+/// credential checked by [`authenticate`], and responds `404` while
+/// anonymization is disabled.
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if the store fails or response creation fails.
+pub fn handle_aggregates(settings: &Settings, req: Request) -> Result<Response, Error> {
+    if *req.get_method() != Method::GET {
+        return Ok(
+            Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_body("Method not allowed")
+        );
+    }
+    if let Err(e) = authenticate(settings, &req) {
+        return Ok(e.into_response());
+    }
+    let Some(aggregates) =
+        AggregateStore::open(settings).map_err(|e| Error::msg(format!("{e:?}")))?
+    else {
+        return Ok(Response::from_status(StatusCode::NOT_FOUND).with_body("Not Found"));
+    };
+    let Some(period) = req
+        .get_query_parameter("period")
+        .filter(|p| chrono::NaiveDate::parse_from_str(&format!("{p}-01"), "%Y-%m-%d").is_ok())
+    else {
+        return Ok(Response::from_status(StatusCode::BAD_REQUEST).with_body("Invalid period"));
+    };
+
+    let aggregate = aggregates
+        .get(period)
+        .map_err(|e| Error::msg(format!("{e:?}")))?;
+    json_response(&AggregateReport::new(period, &aggregate, settings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::tests::create_test_settings;
+
+    fn anonymization_settings() -> Settings {
+        let mut settings = create_test_settings();
+        settings.anonymization.aggregate_store = "test_aggregate_store".to_string();
+        settings.anonymization.min_bucket_size = 2;
+        settings
+    }
+
+    #[test]
+    fn test_buckets_and_suppression() {
+        let settings = anonymization_settings();
+        let bounds = &settings.anonymization.visit_buckets;
+        assert_eq!(bucket_label(1, bounds), "1");
+        assert_eq!(bucket_label(2, bounds), "2-4");
+        assert_eq!(bucket_label(100, bounds), "100+");
+
+        let mut aggregate = VisitAggregate::default();
+        for visits in [1, 1, 3, 4, 250] {
+            aggregate.add(visits, bounds);
+        }
+        assert_eq!(
+            aggregate.buckets,
+            BTreeMap::from([(1, 2), (2, 2), (100, 1)])
+        );
+
+        let report = AggregateReport::new("2026-01", &aggregate, &settings);
+        assert_eq!(
+            report.buckets,
+            vec![
+                BucketCount {
+                    visits: "1".to_string(),
+                    synthetic_ids: 2
+                },
+                BucketCount {
+                    visits: "2-4".to_string(),
+                    synthetic_ids: 2
+                },
+            ]
+        );
+        assert_eq!(report.suppressed, 1);
+    }
+
+    #[test]
+    fn test_anonymize_counters() {
+        let settings = anonymization_settings();
+        let counters = fastly::KVStore::open(&settings.synthetic.counter_store)
+            .expect("should open the KV store")
+            .expect("should have a KV store configured");
+        let idle_at = chrono::Utc::now().timestamp() - 40 * SECONDS_PER_DAY as i64;
+        counters
+            .build_insert()
+            .metadata(&idle_at.to_string())
+            .execute("anonymize-idle-synthetic", "7")
+            .expect("should write to the store");
+        JsonKvStore::open(&settings.synthetic.counter_store)
+            .expect("should open the KV store")
+            .put_text("anonymize-active-synthetic", "3")
+            .expect("should write to the store");
+
+        let aggregates = AggregateStore::open(&settings)
+            .expect("should open the aggregate store")
+            .expect("should have an aggregate store configured");
+        let mut cursor = None;
+        loop {
+            let run = anonymize_counters(&settings, &aggregates, cursor.as_deref())
+                .expect("should anonymize the counters");
+            cursor = run.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        let store =
+            JsonKvStore::open(&settings.synthetic.counter_store).expect("should open the KV store");
+        assert_eq!(
+            store
+                .get_text("anonymize-idle-synthetic")
+                .expect("should read the store"),
+            None
+        );
+        assert!(store
+            .get_text("anonymize-active-synthetic")
+            .expect("should read the store")
+            .is_some());
+        let aggregate = aggregates
+            .get(&period_of(idle_at))
+            .expect("should read the store");
+        assert_eq!(aggregate.buckets.get(&5), Some(&1));
+    }
+
+    #[test]
+    fn test_handle_aggregates() {
+        let mut settings = anonymization_settings();
+        settings.admin.api_keys = vec!["admin-key".to_string()];
+        let request = |query: &str| {
+            Request::get(format!("https://example.com/admin/aggregates{query}"))
+                .with_header(header::AUTHORIZATION, "Bearer admin-key")
+        };
+
+        let response = handle_aggregates(&settings, request("?period=1999-01"))
+            .expect("should handle the request");
+        assert_eq!(response.get_status(), StatusCode::OK);
+        let response = handle_aggregates(&settings, request("?period=yesterday"))
+            .expect("should handle the request");
+        assert_eq!(response.get_status(), StatusCode::BAD_REQUEST);
+        let response = handle_aggregates(&create_test_settings(), request(""))
+            .expect("should handle the request");
+        assert_eq!(response.get_status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,342 @@
+//! Operator authentication for admin and debug endpoints.
+//!
+//! [`authenticate`] accepts either an API key, sent as a bearer token or in
+//! `X-API-Key`, or an HS256 JWT bearer token. Keys come from `admin.api_keys`
+//! and `debug.auth_token`, the JWT secret from `admin.jwt_secret`, and both may
+//! also be provisioned through the Fastly secret store named in
+//! `admin.secret_store`. Handlers turn a failed check into a response with
+//! [`AuthError::into_response`].
+
+use std::fmt;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use fastly::http::{header, StatusCode};
+use fastly::secret_store::SecretStore;
+use fastly::{Request, Response};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::constants::HEADER_X_API_KEY;
+use crate::settings::Settings;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Secret store entry holding an additional API key.
+const SECRET_API_KEY: &str = "api_key";
+
+/// Secret store entry holding an additional JWT secret.
+const SECRET_JWT_SECRET: &str = "jwt_secret";
+
+/// Operator authenticated by [`authenticate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Principal {
+    /// Caller presented a configured API key.
+    ApiKey,
+    /// Caller presented a valid JWT, naming its `sub` claim if it has one.
+    Jwt { subject: Option<String> },
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ApiKey => write!(f, "API key"),
+            Self::Jwt { subject: Some(sub) } => write!(f, "JWT ({sub})"),
+            Self::Jwt { subject: None } => write!(f, "JWT"),
+        }
+    }
+}
+
+/// Why [`authenticate`] rejected a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// No credentials are configured, so admin endpoints are disabled.
+    Disabled,
+    /// The request carries no valid credential.
+    Unauthorized,
+}
+
+impl AuthError {
+    /// Response rejecting the request: `404` while admin endpoints are
+    /// disabled, so they stay undiscoverable, and `401` otherwise.
+    pub fn into_response(self) -> Response {
+        match self {
+            Self::Disabled => Response::from_status(StatusCode::NOT_FOUND).with_body("Not Found"),
+            Self::Unauthorized => Response::from_status(StatusCode::UNAUTHORIZED)
+                .with_header(header::WWW_AUTHENTICATE, "Bearer")
+                .with_body("Unauthorized"),
+        }
+    }
+}
+
+/// API keys and JWT secrets gathered from settings and the secret store.
+struct Credentials {
+    api_keys: Vec<String>,
+    jwt_secrets: Vec<String>,
+}
+
+impl Credentials {
+    fn load(settings: &Settings) -> Self {
+        let mut credentials = Self {
+            api_keys: settings.admin.api_keys.clone(),
+            jwt_secrets: vec![settings.admin.jwt_secret.clone()],
+        };
+        credentials.api_keys.push(settings.debug.auth_token.clone());
+        if !settings.admin.secret_store.is_empty() {
+            match SecretStore::open(&settings.admin.secret_store) {
+                Ok(store) => {
+                    credentials
+                        .api_keys
+                        .extend(secret_text(&store, SECRET_API_KEY));
+                    credentials
+                        .jwt_secrets
+                        .extend(secret_text(&store, SECRET_JWT_SECRET));
+                }
+                Err(e) => log::warn!(
+                    "Failed to open secret store {}: {:?}",
+                    settings.admin.secret_store,
+                    e
+                ),
+            }
+        }
+        credentials.api_keys.retain(|key| !key.is_empty());
+        credentials.jwt_secrets.retain(|secret| !secret.is_empty());
+        credentials
+    }
+
+    fn is_empty(&self) -> bool {
+        self.api_keys.is_empty() && self.jwt_secrets.is_empty()
+    }
+}
+
+/// Reads `name` from `store` as UTF-8, logging lookup failures.
+fn secret_text(store: &SecretStore, name: &str) -> Option<String> {
+    let secret = store
+        .try_get(name)
+        .inspect_err(|e| log::warn!("Failed to look up secret {}: {:?}", name, e))
+        .ok()??;
+    String::from_utf8(secret.plaintext().to_vec()).ok()
+}
+
+/// JOSE header of a JWT.
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+}
+
+/// Registered JWT claims checked by [`verify_jwt`].
+#[derive(Deserialize)]
+struct JwtClaims {
+    exp: i64,
+    #[serde(default)]
+    nbf: Option<i64>,
+    #[serde(default)]
+    iss: Option<String>,
+    #[serde(default)]
+    sub: Option<String>,
+}
+
+/// Verifies an HS256 JWT signed with `secret`, returning its claims.
+///
+/// The token must carry an `exp` claim in the future, must not be used before
+/// its `nbf`, and must name `issuer` in `iss` unless `issuer` is empty.
+fn verify_jwt(token: &str, secret: &str, issuer: &str, now: i64) -> Option<JwtClaims> {
+    let (signing_input, signature) = token.rsplit_once('.')?;
+    let (header, claims) = signing_input.split_once('.')?;
+
+    let header: JwtHeader = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header).ok()?).ok()?;
+    if header.alg != "HS256" {
+        return None;
+    }
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).ok()?;
+    mac.update(signing_input.as_bytes());
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+    mac.verify_slice(&signature).ok()?;
+
+    let claims: JwtClaims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).ok()?).ok()?;
+    let issuer_matches = issuer.is_empty() || claims.iss.as_deref() == Some(issuer);
+    let active = claims.exp > now && claims.nbf.is_none_or(|nbf| nbf <= now);
+    (issuer_matches && active).then_some(claims)
+}
+
+/// Returns `true` if `provided` is one of `keys`, comparing digests so the
+/// check does not leak a key's prefix through timing.
+fn is_api_key(keys: &[String], provided: &str) -> bool {
+    let provided = Sha256::digest(provided);
+    keys.iter().fold(false, |found, key| {
+        found | (Sha256::digest(key) == provided)
+    })
+}
+
+/// Authenticates the operator calling an admin or debug endpoint.
+///
+/// Accepts `Authorization: Bearer <token>` with an API key or a JWT, or
+/// `X-API-Key: <key>`.
+///
+/// # Errors
+///
+/// - [`AuthError::Disabled`] if no API key or JWT secret is configured
+/// - [`AuthError::Unauthorized`] if the request carries no valid credential
+pub fn authenticate(settings: &Settings, req: &Request) -> Result<Principal, AuthError> {
+    let credentials = Credentials::load(settings);
+    if credentials.is_empty() {
+        return Err(AuthError::Disabled);
+    }
+
+    let bearer = req
+        .get_header(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    let api_key = req
+        .get_header(HEADER_X_API_KEY)
+        .and_then(|h| h.to_str().ok());
+
+    if [api_key, bearer]
+        .into_iter()
+        .flatten()
+        .any(|key| is_api_key(&credentials.api_keys, key))
+    {
+        return Ok(Principal::ApiKey);
+    }
+    if let Some(token) = bearer {
+        let now = chrono::Utc::now().timestamp();
+        let claims = credentials
+            .jwt_secrets
+            .iter()
+            .find_map(|secret| verify_jwt(token, secret, &settings.admin.jwt_issuer, now));
+        if let Some(claims) = claims {
+            return Ok(Principal::Jwt {
+                subject: claims.sub,
+            });
+        }
+    }
+    log::warn!("Rejected unauthenticated request to {}", req.get_path());
+    Err(AuthError::Unauthorized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::tests::create_test_settings;
+    use serde_json::json;
+
+    fn sign_jwt(secret: &str, alg: &str, claims: serde_json::Value) -> String {
+        let header = URL_SAFE_NO_PAD.encode(json!({ "alg": alg, "typ": "JWT" }).to_string());
+        let claims = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let signing_input = format!("{header}.{claims}");
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(signing_input.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{signing_input}.{signature}")
+    }
+
+    fn bearer(token: &str) -> Request {
+        Request::get("https://example.com/debug/consent")
+            .with_header(header::AUTHORIZATION, format!("Bearer {token}"))
+    }
+
+    #[test]
+    fn test_disabled_without_credentials() {
+        let settings = create_test_settings();
+        assert_eq!(
+            authenticate(&settings, &bearer("anything")),
+            Err(AuthError::Disabled)
+        );
+        assert_eq!(
+            AuthError::Disabled.into_response().get_status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn test_api_keys() {
+        let mut settings = create_test_settings();
+        settings.admin.api_keys = vec!["admin-key".to_string()];
+        settings.debug.auth_token = "debug-token".to_string();
+
+        assert_eq!(
+            authenticate(&settings, &bearer("admin-key")),
+            Ok(Principal::ApiKey)
+        );
+        assert_eq!(
+            authenticate(&settings, &bearer("debug-token")),
+            Ok(Principal::ApiKey)
+        );
+        let req = Request::get("https://example.com/").with_header(HEADER_X_API_KEY, "admin-key");
+        assert_eq!(authenticate(&settings, &req), Ok(Principal::ApiKey));
+        assert_eq!(
+            authenticate(&settings, &bearer("wrong-key")),
+            Err(AuthError::Unauthorized)
+        );
+        assert_eq!(
+            authenticate(&settings, &Request::get("https://example.com/")),
+            Err(AuthError::Unauthorized)
+        );
+    }
+
+    #[test]
+    fn test_secret_store_api_key() {
+        let mut settings = create_test_settings();
+        settings.admin.secret_store = "test_admin_secrets".to_string();
+        assert_eq!(
+            authenticate(&settings, &bearer("secret-store-key")),
+            Ok(Principal::ApiKey)
+        );
+    }
+
+    #[test]
+    fn test_jwt() {
+        let mut settings = create_test_settings();
+        settings.admin.jwt_secret = "jwt-secret".to_string();
+        settings.admin.jwt_issuer = "https://auth.example.com".to_string();
+        let exp = chrono::Utc::now().timestamp() + 300;
+
+        let valid = sign_jwt(
+            "jwt-secret",
+            "HS256",
+            json!({ "sub": "dpo@example.com", "iss": "https://auth.example.com", "exp": exp }),
+        );
+        assert_eq!(
+            authenticate(&settings, &bearer(&valid)),
+            Ok(Principal::Jwt {
+                subject: Some("dpo@example.com".to_string())
+            })
+        );
+
+        let rejected = [
+            sign_jwt(
+                "jwt-secret",
+                "HS256",
+                json!({ "iss": "https://auth.example.com", "exp": exp - 600 }),
+            ),
+            sign_jwt(
+                "jwt-secret",
+                "HS256",
+                json!({ "iss": "https://other.example.com", "exp": exp }),
+            ),
+            sign_jwt(
+                "other-secret",
+                "HS256",
+                json!({ "iss": "https://auth.example.com", "exp": exp }),
+            ),
+            sign_jwt(
+                "jwt-secret",
+                "none",
+                json!({ "iss": "https://auth.example.com", "exp": exp }),
+            ),
+            sign_jwt(
+                "jwt-secret",
+                "HS256",
+                json!({ "iss": "https://auth.example.com", "exp": exp, "nbf": exp }),
+            ),
+            "not-a-jwt".to_string(),
+        ];
+        for token in rejected {
+            assert_eq!(
+                authenticate(&settings, &bearer(&token)),
+                Err(AuthError::Unauthorized),
+                "{token}"
+            );
+        }
+    }
+}
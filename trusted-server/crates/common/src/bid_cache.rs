@@ -0,0 +1,297 @@
+//! Edge cache of non-personalized bids.
+//!
+//! Bids for anonymous traffic do not vary per user, so the bids an impression
+//! received can be reused for the same slot, site, country and consent outcome
+//! for a few seconds instead of asking every SSP again. [`BidCache`] keeps the bids
+//! of each auctioned slot in the KV store named in `bid_cache.kv_store` for
+//! `bid_cache.ttl_seconds`, and [`BidCache::take_cached`] removes the slots
+//! with cached bids from the next bid request. OpenRTB bids are single use,
+//! with their own win and billing notices, so a cached bid is removed from the
+//! cache when it is taken and served to at most one more impression. Auctions
+//! that allow personalized ads are never cached.
+
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use error_stack::Report;
+use fastly::geo::geo_lookup;
+use fastly::Request;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::consent::{ConsentDecision, PersonalizationLevel};
+use crate::error::TrustedServerError;
+use crate::kv_store::JsonKvStore;
+use crate::prebid::{AdSlot, BidResponse, SeatBid};
+use crate::privacy::Regime;
+use crate::settings::Settings;
+
+/// What the bids of a slot may depend on besides the slot itself. Consent is
+/// keyed on its outcome rather than the raw consent strings, so visitors with
+/// the same choices share the cached bids.
+#[derive(Debug, Serialize)]
+struct CacheContext<'a> {
+    domain: &'a str,
+    country: &'a str,
+    regime: Regime,
+    personalization: PersonalizationLevel,
+    child_directed: bool,
+    precise_geolocation_allowed: bool,
+    bidders: &'a BTreeSet<String>,
+}
+
+/// Bids of recent non-personalized auctions, per slot.
+pub struct BidCache {
+    store: JsonKvStore,
+    ttl: Duration,
+    context: String,
+}
+
+impl BidCache {
+    /// Opens the cache in `bid_cache.kv_store` for the auctions of
+    /// `incoming_req` on `domain` among `bidders`, the bidders with consent to
+    /// take part.
+    ///
+    /// Returns [`None`] when bid caching is disabled or `consent` allows
+    /// personalized ads.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the configured store cannot be opened
+    pub fn open(
+        settings: &Settings,
+        consent: &ConsentDecision,
+        incoming_req: &Request,
+        domain: &str,
+        bidders: &BTreeSet<String>,
+    ) -> Result<Option<Self>, Report<TrustedServerError>> {
+        let config = &settings.bid_cache;
+        if config.kv_store.is_empty() || consent.is_personalized() {
+            return Ok(None);
+        }
+        let country = incoming_req
+            .get_client_ip_addr()
+            .and_then(geo_lookup)
+            .map(|geo| geo.country_code().to_string())
+            .unwrap_or_default();
+        let context = CacheContext {
+            domain,
+            country: &country,
+            regime: consent.regime,
+            personalization: consent.personalization,
+            child_directed: consent.child_directed,
+            precise_geolocation_allowed: consent.precise_geolocation_allowed,
+            bidders,
+        };
+        Ok(Some(Self {
+            store: JsonKvStore::open(&config.kv_store)?,
+            ttl: Duration::from_secs(config.ttl_seconds),
+            context: json!(context).to_string(),
+        }))
+    }
+
+    /// KV key of the bids of `slot`: a hash of the slot configuration and the
+    /// cache context.
+    fn key(&self, slot: &AdSlot) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.context.as_bytes());
+        hasher.update(json!(slot).to_string().as_bytes());
+        format!("bids:{}", hex::encode(hasher.finalize()))
+    }
+
+    /// Removes and returns the bids cached for `slot` within the last
+    /// `bid_cache.ttl_seconds`. Lookup failures are logged and treated as a
+    /// miss, and bids that cannot be removed are not reused.
+    fn take(&self, slot: &AdSlot) -> Option<BidResponse> {
+        let key = self.key(slot);
+        let entry = self
+            .store
+            .get_stamped::<BidResponse>(&key)
+            .inspect_err(|e| log::warn!("Failed to read cached bids of {}: {:?}", slot.code, e))
+            .ok()??;
+        self.store
+            .delete(&key)
+            .inspect_err(|e| log::warn!("Failed to take cached bids of {}: {:?}", slot.code, e))
+            .ok()?;
+        let age = chrono::Utc::now().timestamp() - entry.written_at?;
+        (u64::try_from(age).ok()? < self.ttl.as_secs()).then_some(entry.value)
+    }
+
+    /// Removes the impressions of the `slots` with cached bids from the
+    /// OpenRTB request `body`, returning their cached bids, which are removed
+    /// from the cache.
+    pub fn take_cached(&self, slots: &[AdSlot], body: &mut Value) -> Vec<BidResponse> {
+        let mut cached = Vec::new();
+        let mut cached_codes = Vec::new();
+        for slot in slots {
+            if let Some(response) = self.take(slot) {
+                cached.push(response);
+                cached_codes.push(slot.code.as_str());
+            }
+        }
+        if let Some(imps) = body["imp"].as_array_mut() {
+            imps.retain(|imp| !cached_codes.contains(&imp["id"].as_str().unwrap_or_default()));
+        }
+        if !cached_codes.is_empty() {
+            log::info!("Reusing cached bids for {}", cached_codes.join(", "));
+        }
+        cached
+    }
+
+    /// Caches the bids in `response` for each of the `slots` that received
+    /// any. Failures are logged, as caching must not fail the auction.
+    pub fn put(&self, slots: &[AdSlot], response: &BidResponse) {
+        for slot in slots {
+            let seatbid: Vec<SeatBid> = response
+                .seatbid
+                .iter()
+                .map(|seat| SeatBid {
+                    seat: seat.seat.clone(),
+                    bid: seat
+                        .bid
+                        .iter()
+                        .filter(|bid| bid.impid == slot.code)
+                        .cloned()
+                        .collect(),
+                })
+                .filter(|seat| !seat.bid.is_empty())
+                .collect();
+            if seatbid.is_empty() {
+                continue;
+            }
+            let slot_response = BidResponse {
+                id: response.id.clone(),
+                seatbid,
+                cur: response.cur.clone(),
+            };
+            if let Err(e) = self
+                .store
+                .put_with_ttl(&self.key(slot), &slot_response, self.ttl)
+            {
+                log::warn!("Failed to cache bids of {}: {:?}", slot.code, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prebid::{Bid, SlotFormat, SlotPosition};
+    use crate::test_support::tests::create_test_settings;
+
+    fn slot(code: &str) -> AdSlot {
+        AdSlot {
+            code: code.to_string(),
+            sizes: vec![(300, 250)],
+            position: SlotPosition::default(),
+            video: None,
+            native: None,
+            format: SlotFormat::Standard,
+        }
+    }
+
+    fn cache_settings() -> Settings {
+        let mut settings = create_test_settings();
+        settings.bid_cache.kv_store = "test_bid_cache_store".to_string();
+        settings
+    }
+
+    fn bidders(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(ToString::to_string).collect()
+    }
+
+    fn open(settings: &Settings, req: &Request, domain: &str) -> Option<BidCache> {
+        let consent = ConsentDecision::from_request(settings, req);
+        BidCache::open(
+            settings,
+            &consent,
+            req,
+            domain,
+            &bidders(&["smartadserver"]),
+        )
+        .expect("should open the bid cache")
+    }
+
+    #[test]
+    fn test_open_disabled() {
+        let req = Request::get("https://example.com/auction");
+        assert!(open(&create_test_settings(), &req, "test.com").is_none());
+        assert!(open(&cache_settings(), &req, "test.com").is_some());
+    }
+
+    #[test]
+    fn test_put_and_take_cached() {
+        let settings = cache_settings();
+        let req = Request::get("https://example.com/auction");
+        let cache = open(&settings, &req, "cache-test.com").unwrap();
+        let slots = [slot("top"), slot("side"), slot("bottom")];
+        let bid = |impid: &str| Bid {
+            id: format!("bid-{impid}"),
+            impid: impid.to_string(),
+            price: 1.0,
+            ..Default::default()
+        };
+        let response = BidResponse {
+            id: "auction-1".to_string(),
+            seatbid: vec![SeatBid {
+                seat: "smartadserver".to_string(),
+                bid: vec![bid("top"), bid("side")],
+            }],
+            cur: Some("EUR".to_string()),
+        };
+        cache.put(&slots[..2], &response);
+
+        let mut body = json!({ "imp": [{ "id": "top" }, { "id": "side" }, { "id": "bottom" }] });
+        let cached = cache.take_cached(&slots, &mut body);
+        assert_eq!(body["imp"], json!([{ "id": "bottom" }]));
+        assert_eq!(cached.len(), 2);
+        assert_eq!(cached[0].seatbid[0].bid, vec![bid("top")]);
+        assert_eq!(cached[1].seatbid[0].bid, vec![bid("side")]);
+        assert_eq!(cached[0].cur.as_deref(), Some("EUR"));
+
+        // Bids are single use: the next auction asks the SSPs again, so a
+        // cached bid is served, and its notices fired, at most once
+        let mut body = json!({ "imp": [{ "id": "top" }, { "id": "side" }] });
+        assert!(cache.take_cached(&slots, &mut body).is_empty());
+        assert_eq!(body["imp"], json!([{ "id": "top" }, { "id": "side" }]));
+
+        // Another site does not share the cached bids
+        let other = open(&settings, &req, "other-test.com").unwrap();
+        let mut body = json!({ "imp": [{ "id": "top" }] });
+        assert!(other.take_cached(&slots, &mut body).is_empty());
+        assert_eq!(body["imp"], json!([{ "id": "top" }]));
+    }
+
+    #[test]
+    fn test_context_keys_on_consent_outcome() {
+        let settings = cache_settings();
+        let cache = |tc_string: &str, names: &[&str]| {
+            let req = Request::get("https://example.com/auction")
+                .with_header("Cookie", format!("euconsent-v2={tc_string}"));
+            let consent = ConsentDecision::from_request(&settings, &req);
+            BidCache::open(
+                &settings,
+                &consent,
+                &req,
+                "outcome-test.com",
+                &bidders(names),
+            )
+            .expect("should open the bid cache")
+            .expect("should cache non-personalized bids")
+        };
+        let key = |cache: BidCache| cache.key(&slot("top"));
+
+        // Differently encoded consent strings with the same outcome share
+        // cached bids, while a different set of consented bidders does not
+        assert_eq!(
+            key(cache("not-a-tc-string", &["smartadserver"])),
+            key(cache("also-not-a-tc-string", &["smartadserver"]))
+        );
+        assert_ne!(
+            key(cache("not-a-tc-string", &["smartadserver"])),
+            key(cache("not-a-tc-string", &["smartadserver", "rubicon"]))
+        );
+    }
+}
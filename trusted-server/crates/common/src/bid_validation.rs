@@ -0,0 +1,262 @@
+//! Rejection of bids from blocked advertisers and creatives.
+//!
+//! Publishers block advertisers by domain and single creatives by creative ID
+//! in `bid_validation`, or in the KV store named there for lists that change
+//! without a deploy. [`BlockList::reject_blocked`] removes the matching bids
+//! from a bid response before winners are picked, so a blocked bid is never
+//! rendered, cached or notified as a win; its bidder receives a loss notice
+//! instead.
+
+use std::collections::HashSet;
+
+use error_stack::Report;
+use serde::Deserialize;
+
+use crate::error::TrustedServerError;
+use crate::kv_store::JsonKvStore;
+use crate::macros::AuctionMacros;
+use crate::prebid::{Bid, BidResponse};
+use crate::settings::Settings;
+use crate::win_notice::LossReason;
+
+/// Key of the block list document in the KV store.
+const BLOCK_LIST_KEY: &str = "blocklist";
+
+/// Block list document stored in the KV store
+#[derive(Debug, Default, Deserialize)]
+struct StoredBlockList {
+    #[serde(default)]
+    advertiser_domains: Vec<String>,
+    #[serde(default)]
+    creative_ids: Vec<String>,
+}
+
+/// Normalizes an advertiser domain for comparison: lower case, without
+/// scheme, path or `www.` prefix.
+fn normalize_domain(domain: &str) -> String {
+    let domain = domain.trim().to_ascii_lowercase();
+    let domain = domain
+        .strip_prefix("https://")
+        .or_else(|| domain.strip_prefix("http://"))
+        .unwrap_or(&domain);
+    let domain = domain.split(['/', '?', '#']).next().unwrap_or_default();
+    domain
+        .strip_prefix("www.")
+        .unwrap_or(domain)
+        .trim_end_matches('.')
+        .to_string()
+}
+
+/// Blocked advertiser domains and creative IDs.
+#[derive(Debug, Default)]
+pub struct BlockList {
+    domains: HashSet<String>,
+    creative_ids: HashSet<String>,
+}
+
+impl BlockList {
+    /// Block list of `bid_validation`, with the document stored under
+    /// `blocklist` in `bid_validation.kv_store` when set.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the configured store cannot be read
+    ///   or its block list is not valid JSON
+    pub fn load(settings: &Settings) -> Result<Self, Report<TrustedServerError>> {
+        let config = &settings.bid_validation;
+        let stored = if config.kv_store.is_empty() {
+            StoredBlockList::default()
+        } else {
+            JsonKvStore::open(&config.kv_store)?
+                .get(BLOCK_LIST_KEY)?
+                .unwrap_or_default()
+        };
+        let domains = config
+            .blocked_advertiser_domains
+            .iter()
+            .chain(&stored.advertiser_domains)
+            .map(|domain| normalize_domain(domain))
+            .filter(|domain| !domain.is_empty())
+            .collect();
+        let creative_ids = config
+            .blocked_creative_ids
+            .iter()
+            .chain(&stored.creative_ids)
+            .cloned()
+            .collect();
+        Ok(Self {
+            domains,
+            creative_ids,
+        })
+    }
+
+    /// Whether no advertiser or creative is blocked.
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty() && self.creative_ids.is_empty()
+    }
+
+    /// Whether `domain` or one of its parent domains is blocked.
+    fn blocks_domain(&self, domain: &str) -> bool {
+        let domain = normalize_domain(domain);
+        let mut rest = domain.as_str();
+        loop {
+            if self.domains.contains(rest) {
+                return true;
+            }
+            match rest.split_once('.') {
+                Some((_, parent)) => rest = parent,
+                None => return false,
+            }
+        }
+    }
+
+    /// Why `bid` is rejected, or [`None`] if it is allowed.
+    pub fn check(&self, bid: &Bid) -> Option<LossReason> {
+        if bid.adomain.iter().any(|domain| self.blocks_domain(domain)) {
+            return Some(LossReason::AdvertiserExcluded);
+        }
+        if bid
+            .crid
+            .as_ref()
+            .is_some_and(|crid| self.creative_ids.contains(crid))
+        {
+            return Some(LossReason::CreativeDisapproved);
+        }
+        None
+    }
+
+    /// Removes the bids this list rejects from `response`, returning the loss
+    /// notice URLs of the rejected bids with their auction macros and loss
+    /// reason substituted.
+    pub fn reject_blocked(&self, settings: &Settings, response: &mut BidResponse) -> Vec<String> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let currency = response.currency().to_string();
+        let mut loss_notices = Vec::new();
+        for seat in &mut response.seatbid {
+            seat.bid.retain(|bid| {
+                let Some(reason) = self.check(bid) else {
+                    return true;
+                };
+                log::info!(
+                    "Rejected bid {} of {} for {}: {:?}",
+                    bid.id,
+                    seat.seat,
+                    bid.impid,
+                    reason
+                );
+                if let Some(lurl) = &bid.lurl {
+                    let macros =
+                        AuctionMacros::for_bid(settings, &response.id, &seat.seat, bid, &currency)
+                            .with_loss(reason, None);
+                    loss_notices.push(macros.expand(lurl));
+                }
+                false
+            });
+        }
+        loss_notices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prebid::SeatBid;
+    use crate::test_support::tests::create_test_settings;
+
+    fn block_list() -> BlockList {
+        let mut settings = create_test_settings();
+        settings.bid_validation.blocked_advertiser_domains = vec![
+            "Blocked.example".to_string(),
+            "https://www.rival.example/".to_string(),
+        ];
+        settings.bid_validation.blocked_creative_ids = vec!["creative-13".to_string()];
+        BlockList::load(&settings).unwrap()
+    }
+
+    fn bid(id: &str, adomain: &[&str], crid: Option<&str>) -> Bid {
+        Bid {
+            id: id.to_string(),
+            impid: "top".to_string(),
+            price: 1.0,
+            adomain: adomain.iter().map(|domain| domain.to_string()).collect(),
+            crid: crid.map(str::to_string),
+            lurl: Some(format!(
+                "https://dsp.example/loss?b={id}&r=${{AUCTION_LOSS}}"
+            )),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let list = block_list();
+        let cases = [
+            (
+                bid("1", &["blocked.example"], None),
+                Some(LossReason::AdvertiserExcluded),
+            ),
+            (
+                bid("2", &["shop.blocked.example"], None),
+                Some(LossReason::AdvertiserExcluded),
+            ),
+            (
+                bid("3", &["WWW.RIVAL.EXAMPLE"], None),
+                Some(LossReason::AdvertiserExcluded),
+            ),
+            (bid("4", &["notblocked.example"], None), None),
+            (
+                bid("5", &["brand.example"], Some("creative-13")),
+                Some(LossReason::CreativeDisapproved),
+            ),
+            (bid("6", &[], Some("creative-14")), None),
+        ];
+        for (bid, expected) in cases {
+            assert_eq!(list.check(&bid), expected, "{}", bid.id);
+        }
+        let blocked = bid("7", &["blocked.example"], Some("creative-13"));
+        assert!(BlockList::default().check(&blocked).is_none());
+    }
+
+    #[test]
+    fn test_load_from_kv_store() {
+        let mut settings = create_test_settings();
+        settings.bid_validation.kv_store = "test_blocklist_store".to_string();
+        let list = BlockList::load(&settings).unwrap();
+        assert_eq!(
+            list.check(&bid("1", &["ads.stored.example"], None)),
+            Some(LossReason::AdvertiserExcluded)
+        );
+        assert_eq!(
+            list.check(&bid("2", &[], Some("stored-creative"))),
+            Some(LossReason::CreativeDisapproved)
+        );
+    }
+
+    #[test]
+    fn test_reject_blocked() {
+        let mut response = BidResponse {
+            id: "auction-1".to_string(),
+            seatbid: vec![SeatBid {
+                seat: "smartadserver".to_string(),
+                bid: vec![
+                    bid("1", &["blocked.example"], None),
+                    bid("2", &["brand.example"], None),
+                    bid("3", &[], Some("creative-13")),
+                ],
+            }],
+            cur: None,
+        };
+        let notices = block_list().reject_blocked(&create_test_settings(), &mut response);
+        assert_eq!(
+            notices,
+            vec![
+                "https://dsp.example/loss?b=1&r=205".to_string(),
+                "https://dsp.example/loss?b=3&r=202".to_string(),
+            ]
+        );
+        assert_eq!(response.seatbid[0].bid.len(), 1);
+        assert_eq!(response.seatbid[0].bid[0].id, "2");
+    }
+}
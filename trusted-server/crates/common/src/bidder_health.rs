@@ -0,0 +1,372 @@
+//! Health of auction endpoints and bidder introspection.
+//!
+//! With `bidder_health.enabled`, the outcome of every bid request (bids, no
+//! bid, error or timeout) is counted per endpoint in a Fastly rate counter.
+//! An endpoint whose share of errors and timeouts in the last minute reaches
+//! `bidder_health.failure_threshold_percent` is put in a Fastly penalty box
+//! for `bidder_health.open_minutes`: its circuit is open and auctions skip it
+//! (see [`BidderHealth::is_open`]) instead of waiting on it.
+//!
+//! Operators see why a bidder stopped filling through two endpoints, both
+//! requiring an operator credential checked by [`authenticate`]:
+//!
+//! - `GET /prebid/bidders` ([`handle_prebid_bidders`]): the configured
+//!   bidders and auction endpoints
+//! - `GET /prebid/status` ([`handle_prebid_status`]): the last minute's
+//!   outcomes and circuit state of every endpoint
+
+use std::time::Duration;
+
+use fastly::erl::{CounterDuration, ERLError, Penaltybox, RateCounter};
+use fastly::http::{header, Method, StatusCode};
+use fastly::{Error, Request, Response};
+use serde::Serialize;
+
+use crate::auth::authenticate;
+use crate::prebid::auction_endpoints;
+use crate::settings::{AuctionEndpoint, Settings};
+
+/// Path of the bidder configuration endpoint.
+pub const BIDDERS_PATH: &str = "/prebid/bidders";
+
+/// Path of the endpoint health endpoint.
+pub const STATUS_PATH: &str = "/prebid/status";
+
+/// Rate counter entry suffix counting the bid requests sent to an endpoint.
+const REQUESTS: &str = "requests";
+
+/// Outcome of a bid request sent to an auction endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The endpoint answered with at least one bid
+    Bids,
+    /// The endpoint answered without bids
+    NoBid,
+    /// The request failed, or the endpoint answered with an error status or an
+    /// invalid body
+    Error,
+    /// The endpoint did not answer within its timeout
+    Timeout,
+}
+
+impl Outcome {
+    /// Rate counter entry suffix of the outcome
+    fn counter(self) -> &'static str {
+        match self {
+            Self::Bids => "bids",
+            Self::NoBid => "no_bids",
+            Self::Error => "errors",
+            Self::Timeout => "timeouts",
+        }
+    }
+
+    /// Whether the outcome counts against the endpoint's circuit
+    fn is_failure(self) -> bool {
+        matches!(self, Self::Error | Self::Timeout)
+    }
+}
+
+/// Outcomes of the bid requests sent to an endpoint in the last minute
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct EndpointStats {
+    /// Bid requests sent
+    pub requests: u32,
+    /// Responses with bids
+    pub bids: u32,
+    /// Responses without bids
+    pub no_bids: u32,
+    /// Failed requests and error responses
+    pub errors: u32,
+    /// Requests abandoned at their timeout
+    pub timeouts: u32,
+    /// Share of errors and timeouts among the requests, from 0 to 1
+    pub failure_rate: f64,
+}
+
+/// Outcome counters and circuits of the auction endpoints.
+pub struct BidderHealth {
+    counter: RateCounter,
+    penalty_box: Penaltybox,
+    failure_threshold_percent: u32,
+    min_requests: u32,
+    open_for: Duration,
+}
+
+impl BidderHealth {
+    /// Opens the rate counter and penalty box of `bidder_health`.
+    ///
+    /// Returns [`None`] when health tracking is disabled.
+    pub fn open(settings: &Settings) -> Option<Self> {
+        let config = &settings.bidder_health;
+        config.enabled.then(|| Self {
+            counter: RateCounter::open(&config.rate_counter),
+            penalty_box: Penaltybox::open(&config.penalty_box),
+            failure_threshold_percent: config.failure_threshold_percent,
+            min_requests: config.min_requests,
+            open_for: Duration::from_secs(config.open_minutes.clamp(1, 60) * 60),
+        })
+    }
+
+    fn entry(endpoint: &str, counter: &str) -> String {
+        format!("{endpoint}:{counter}")
+    }
+
+    fn count(&self, endpoint: &str, counter: &str) -> Result<u32, ERLError> {
+        self.counter
+            .lookup_count(&Self::entry(endpoint, counter), CounterDuration::SixtySecs)
+    }
+
+    /// Whether the circuit of `endpoint` is open, so auctions skip it.
+    /// Failed lookups are logged and treated as closed.
+    pub fn is_open(&self, endpoint: &str) -> bool {
+        self.penalty_box
+            .has(endpoint)
+            .inspect_err(|e| log::warn!("Failed to look up the circuit of {}: {}", endpoint, e))
+            .unwrap_or(false)
+    }
+
+    /// Counts `outcome` of a bid request to `endpoint`, opening its circuit
+    /// when the failures of the last minute reach the threshold. Failures are
+    /// logged, as health tracking must not fail the auction.
+    pub fn record(&self, endpoint: &str, outcome: Outcome) {
+        let recorded = self
+            .counter
+            .increment(&Self::entry(endpoint, REQUESTS), 1)
+            .and_then(|()| {
+                self.counter
+                    .increment(&Self::entry(endpoint, outcome.counter()), 1)
+            });
+        if let Err(e) = recorded {
+            log::warn!("Failed to record the outcome of {}: {}", endpoint, e);
+            return;
+        }
+        if !outcome.is_failure() {
+            return;
+        }
+        let stats = match self.stats(endpoint) {
+            Ok(stats) => stats,
+            Err(e) => {
+                log::warn!("Failed to look up the outcomes of {}: {}", endpoint, e);
+                return;
+            }
+        };
+        if self.should_open(&stats) {
+            log::warn!(
+                "Opening the circuit of {} after {} failures in {} requests",
+                endpoint,
+                stats.errors + stats.timeouts,
+                stats.requests
+            );
+            if let Err(e) = self.penalty_box.add(endpoint, self.open_for) {
+                log::warn!("Failed to open the circuit of {}: {}", endpoint, e);
+            }
+        }
+    }
+
+    /// Whether `stats` reach the failure threshold.
+    fn should_open(&self, stats: &EndpointStats) -> bool {
+        let failures = u64::from(stats.errors) + u64::from(stats.timeouts);
+        stats.requests >= self.min_requests.max(1)
+            && failures * 100
+                >= u64::from(self.failure_threshold_percent) * u64::from(stats.requests)
+    }
+
+    /// Outcomes of the bid requests sent to `endpoint` in the last minute.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`ERLError`] if the rate counter cannot be read.
+    pub fn stats(&self, endpoint: &str) -> Result<EndpointStats, ERLError> {
+        let requests = self.count(endpoint, REQUESTS)?;
+        let errors = self.count(endpoint, Outcome::Error.counter())?;
+        let timeouts = self.count(endpoint, Outcome::Timeout.counter())?;
+        let failure_rate = if requests == 0 {
+            0.0
+        } else {
+            f64::from(errors.saturating_add(timeouts)) / f64::from(requests)
+        };
+        Ok(EndpointStats {
+            requests,
+            bids: self.count(endpoint, Outcome::Bids.counter())?,
+            no_bids: self.count(endpoint, Outcome::NoBid.counter())?,
+            errors,
+            timeouts,
+            failure_rate,
+        })
+    }
+}
+
+/// Bidder of `GET /prebid/bidders`
+#[derive(Debug, Serialize)]
+struct BidderInfo<'a> {
+    code: &'a str,
+    vendor_id: u16,
+    price_encryption: bool,
+}
+
+/// Health of an auction endpoint in `GET /prebid/status`
+#[derive(Debug, Serialize)]
+struct EndpointStatus {
+    #[serde(flatten)]
+    endpoint: AuctionEndpoint,
+    circuit_open: bool,
+    last_minute: EndpointStats,
+}
+
+fn json_response(body: &impl Serialize) -> Result<Response, Error> {
+    Ok(Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_header(header::CACHE_CONTROL, "no-store, private")
+        .with_body_json(body)?)
+}
+
+/// Handles `GET /prebid/bidders`.
+///
+/// Responds with the bidders of `prebid.bidders` and their vendor IDs,
+/// whether `${AUCTION_PRICE}` is encrypted for them, the auction endpoints
+/// and the auction budget. Requires an operator credential checked by
+/// [`authenticate`].
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if response creation fails.
+pub fn handle_prebid_bidders(settings: &Settings, req: Request) -> Result<Response, Error> {
+    if *req.get_method() != Method::GET {
+        return Ok(
+            Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_body("Method not allowed")
+        );
+    }
+    if let Err(e) = authenticate(settings, &req) {
+        return Ok(e.into_response());
+    }
+    let mut bidders: Vec<BidderInfo> = settings
+        .prebid
+        .bidders
+        .iter()
+        .map(|(code, &vendor_id)| BidderInfo {
+            code,
+            vendor_id,
+            price_encryption: settings.prebid.price_encryption.contains_key(code),
+        })
+        .collect();
+    bidders.sort_by_key(|bidder| bidder.code);
+    json_response(&serde_json::json!({
+        "bidders": bidders,
+        "endpoints": auction_endpoints(settings),
+        "auction_timeout_ms": settings.prebid.auction_timeout_ms,
+    }))
+}
+
+/// Handles `GET /prebid/status`.
+///
+/// Responds with every auction endpoint, whether its circuit is open and the
+/// outcomes of its bid requests in the last minute. Requires an operator
+/// credential checked by [`authenticate`], and responds `404` while
+/// `bidder_health` is disabled.
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if the rate counter cannot be read or response
+/// creation fails.
+pub fn handle_prebid_status(settings: &Settings, req: Request) -> Result<Response, Error> {
+    if *req.get_method() != Method::GET {
+        return Ok(
+            Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_body("Method not allowed")
+        );
+    }
+    if let Err(e) = authenticate(settings, &req) {
+        return Ok(e.into_response());
+    }
+    let Some(health) = BidderHealth::open(settings) else {
+        return Ok(Response::from_status(StatusCode::NOT_FOUND).with_body("Not Found"));
+    };
+    let endpoints = auction_endpoints(settings)
+        .into_iter()
+        .map(|endpoint| {
+            Ok(EndpointStatus {
+                circuit_open: health.is_open(&endpoint.name),
+                last_minute: health.stats(&endpoint.name)?,
+                endpoint,
+            })
+        })
+        .collect::<Result<Vec<_>, ERLError>>()
+        .map_err(|e| Error::msg(format!("Failed to read endpoint health: {e}")))?;
+    json_response(&serde_json::json!({ "endpoints": endpoints }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::tests::create_test_settings;
+
+    fn health_settings() -> Settings {
+        let mut settings = create_test_settings();
+        settings.bidder_health.enabled = true;
+        settings.admin.api_keys = vec!["ops-key".to_string()];
+        settings
+    }
+
+    #[test]
+    fn test_should_open() {
+        let health = BidderHealth::open(&health_settings()).unwrap();
+        let stats = |requests, errors, timeouts| EndpointStats {
+            requests,
+            errors,
+            timeouts,
+            ..Default::default()
+        };
+        assert!(health.should_open(&stats(20, 6, 4)));
+        assert!(health.should_open(&stats(40, 40, 0)));
+        assert!(!health.should_open(&stats(20, 5, 4)));
+        assert!(!health.should_open(&stats(19, 19, 0)));
+        assert!(!health.should_open(&stats(0, 0, 0)));
+    }
+
+    #[test]
+    fn test_open_disabled() {
+        assert!(BidderHealth::open(&create_test_settings()).is_none());
+    }
+
+    #[test]
+    fn test_handle_prebid_bidders() {
+        let settings = health_settings();
+        let mut req = Request::get("https://example.com/prebid/bidders");
+        req.set_header(header::AUTHORIZATION, "Bearer ops-key");
+        let mut resp = handle_prebid_bidders(&settings, req).unwrap();
+        assert_eq!(resp.get_status(), StatusCode::OK);
+        let body: serde_json::Value = resp.take_body_json().unwrap();
+        assert_eq!(
+            body["bidders"],
+            serde_json::json!([
+                { "code": "smartadserver", "vendor_id": 45, "price_encryption": false }
+            ])
+        );
+        assert_eq!(body["endpoints"][0]["name"], "prebid");
+        assert_eq!(body["auction_timeout_ms"], 1000);
+
+        let req = Request::get("https://example.com/prebid/bidders");
+        let resp = handle_prebid_bidders(&settings, req).unwrap();
+        assert_eq!(resp.get_status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_handle_prebid_status() {
+        let settings = health_settings();
+        let mut req = Request::get("https://example.com/prebid/status");
+        req.set_header(header::AUTHORIZATION, "Bearer ops-key");
+        let mut resp = handle_prebid_status(&settings, req).unwrap();
+        assert_eq!(resp.get_status(), StatusCode::OK);
+        let body: serde_json::Value = resp.take_body_json().unwrap();
+        let endpoint = &body["endpoints"][0];
+        assert_eq!(endpoint["name"], "prebid");
+        assert_eq!(endpoint["circuit_open"], false);
+        assert_eq!(endpoint["last_minute"]["requests"], 0);
+
+        let mut disabled = create_test_settings();
+        disabled.admin.api_keys = vec!["ops-key".to_string()];
+        let mut req = Request::get("https://example.com/prebid/status");
+        req.set_header(header::AUTHORIZATION, "Bearer ops-key");
+        let resp = handle_prebid_status(&disabled, req).unwrap();
+        assert_eq!(resp.get_status(), StatusCode::NOT_FOUND);
+    }
+}
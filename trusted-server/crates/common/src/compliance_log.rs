@@ -0,0 +1,205 @@
+//! Compliance log of accesses to subject data.
+//!
+//! Every handler that reads or changes a data subject's data records an
+//! [`AccessEvent`] naming who accessed it ([`Actor`]), through which endpoint,
+//! when, which synthetic ID it concerned and how the request ended, so
+//! publishers can reconstruct access during a breach investigation. Events are
+//! shipped as one JSON line each to the Fastly log endpoint named in
+//! `compliance_log.endpoint`, separate from the application log, and carry an
+//! HMAC-SHA256 signature so altered or forged lines can be detected with
+//! [`verify_event`]. Recording never fails the request it describes.
+
+use std::io::Write;
+
+use fastly::http::StatusCode;
+use fastly::log::Endpoint;
+use fastly::Request;
+use hmac::Mac;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::auth::Principal;
+use crate::settings::Settings;
+use crate::signing::{keyed_mac, HmacSha256};
+use crate::synthetic::SyntheticIdSource;
+
+/// Who accessed subject data.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Actor {
+    /// The data subject, identified by `verified_by`: a `dsar_token` or the
+    /// source of their synthetic ID.
+    Subject { verified_by: String },
+    /// An operator authenticated by [`crate::auth::authenticate`].
+    Operator { principal: String },
+    /// A caller whose credentials were missing or invalid.
+    Unauthenticated,
+}
+
+impl Actor {
+    /// The subject, verified with a token from `/gdpr/data/verify`.
+    pub fn dsar_token() -> Self {
+        Self::Subject {
+            verified_by: "dsar_token".to_string(),
+        }
+    }
+}
+
+impl From<SyntheticIdSource> for Actor {
+    fn from(source: SyntheticIdSource) -> Self {
+        let verified_by = match source {
+            SyntheticIdSource::Header => "header",
+            SyntheticIdSource::Cookie => "cookie",
+            SyntheticIdSource::Linked => "linked",
+            SyntheticIdSource::Fresh => "fresh",
+        };
+        Self::Subject {
+            verified_by: verified_by.to_string(),
+        }
+    }
+}
+
+impl From<&Principal> for Actor {
+    fn from(principal: &Principal) -> Self {
+        Self::Operator {
+            principal: principal.to_string(),
+        }
+    }
+}
+
+/// One access to subject data.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessEvent {
+    /// Unique event ID (UUID v4).
+    pub event_id: String,
+    /// Time of the access (RFC 3339, UTC).
+    pub timestamp: String,
+    /// Who accessed the data.
+    pub actor: Actor,
+    /// HTTP method of the request.
+    pub method: String,
+    /// Path of the endpoint.
+    pub endpoint: String,
+    /// What was done, such as `access` or `erasure`.
+    pub action: String,
+    /// Synthetic ID whose data was accessed, if known.
+    pub subject: Option<String>,
+    /// Status the request was answered with.
+    pub status: u16,
+    /// Fastly trace ID of the request, for correlation with other logs.
+    pub trace_id: Option<String>,
+}
+
+impl AccessEvent {
+    /// Starts an event for `req`; complete it with [`AccessEvent::record`].
+    pub fn new(req: &Request, action: impl ToString, actor: Actor, subject: Option<&str>) -> Self {
+        Self {
+            event_id: Uuid::new_v4().to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            actor,
+            method: req.get_method_str().to_string(),
+            endpoint: req.get_path().to_string(),
+            action: action.to_string(),
+            subject: subject.map(str::to_string),
+            status: 0,
+            trace_id: std::env::var("FASTLY_TRACE_ID").ok(),
+        }
+    }
+
+    /// Records the event with the response `status` to the compliance log.
+    ///
+    /// Does nothing while `compliance_log.endpoint` is empty; failures to
+    /// write are logged to the application log.
+    pub fn record(mut self, settings: &Settings, status: StatusCode) {
+        let name = &settings.compliance_log.endpoint;
+        if name.is_empty() {
+            return;
+        }
+        self.status = status.as_u16();
+        let result = signed_line(settings, &self)
+            .map_err(|e| e.to_string())
+            .and_then(|line| {
+                let mut endpoint = Endpoint::try_from_name(name).map_err(|e| e.to_string())?;
+                writeln!(endpoint, "{line}").map_err(|e| e.to_string())
+            });
+        if let Err(e) = result {
+            log::error!("Failed to record compliance event {}: {}", self.event_id, e);
+        }
+    }
+}
+
+fn event_mac(settings: &Settings, payload: &str) -> HmacSha256 {
+    keyed_mac(
+        settings.synthetic.secret_key.as_bytes(),
+        b"compliance-event:",
+        &[payload.as_bytes()],
+    )
+}
+
+/// Serializes `event` as `{"event":{...},"signature":"<hex>"}`, signing the
+/// exact bytes of the event object.
+fn signed_line(settings: &Settings, event: &AccessEvent) -> Result<String, serde_json::Error> {
+    let payload = serde_json::to_string(event)?;
+    let signature = hex::encode(event_mac(settings, &payload).finalize().into_bytes());
+    Ok(format!(
+        r#"{{"event":{payload},"signature":"{signature}"}}"#
+    ))
+}
+
+/// Checks the signature of a compliance log line, returning its event.
+///
+/// Returns [`None`] if the line is malformed or was modified.
+pub fn verify_event(settings: &Settings, line: &str) -> Option<AccessEvent> {
+    #[derive(Deserialize)]
+    struct SignedEvent {
+        event: AccessEvent,
+        signature: String,
+    }
+
+    let signed: SignedEvent = serde_json::from_str(line).ok()?;
+    let payload = serde_json::to_string(&signed.event).ok()?;
+    let signature = hex::decode(&signed.signature).ok()?;
+    event_mac(settings, &payload)
+        .verify_slice(&signature)
+        .ok()?;
+    Some(signed.event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::tests::create_test_settings;
+
+    #[test]
+    fn test_signed_line_verifies() {
+        let settings = create_test_settings();
+        let req = Request::delete("https://example.com/gdpr/data");
+        let mut event = AccessEvent::new(&req, "erasure", Actor::dsar_token(), Some("abc.def"));
+        event.status = 200;
+
+        let line = signed_line(&settings, &event).unwrap();
+        assert_eq!(verify_event(&settings, &line), Some(event.clone()));
+
+        let tampered = line.replace("abc.def", "xyz.def");
+        assert_eq!(verify_event(&settings, &tampered), None);
+
+        let mut other_key = create_test_settings();
+        other_key.synthetic.secret_key = "other-secret".to_string();
+        assert_eq!(verify_event(&other_key, &line), None);
+    }
+
+    #[test]
+    fn test_actor_serialization() {
+        let actor = Actor::from(&Principal::Jwt {
+            subject: Some("dpo@example.com".to_string()),
+        });
+        assert_eq!(
+            serde_json::to_value(&actor).unwrap(),
+            serde_json::json!({ "type": "operator", "principal": "JWT (dpo@example.com)" })
+        );
+        assert_eq!(
+            serde_json::to_value(Actor::from(SyntheticIdSource::Cookie)).unwrap(),
+            serde_json::json!({ "type": "subject", "verified_by": "cookie" })
+        );
+    }
+}
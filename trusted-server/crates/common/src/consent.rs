@@ -0,0 +1,343 @@
+//! Unified consent decision for ad handlers.
+//!
+//! [`ConsentDecision`] collects every consent-related input of a request —
+//! the TCF, US Privacy and GPP signals, the privacy regime, COPPA and Do Not
+//! Track — and reduces them to the handful of answers ad handlers need. It is
+//! computed once per request before routing, so handlers branch on the same
+//! decision instead of repeating their own purpose checks.
+
+use fastly::Request;
+use serde::Serialize;
+
+use crate::objection::has_objected;
+use crate::privacy::{is_child_directed, ConsentSignals, Regime};
+use crate::settings::Settings;
+use crate::synthetic::is_dnt_enforced;
+use crate::tcf_consent::{get_tcf_consent_or_default, purpose_ids, VendorList};
+
+/// How personalized the ads served to a request may be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PersonalizationLevel {
+    /// Ads may use the synthetic ID and partner identifiers.
+    Personalized,
+    /// Only non-personalized ads, without identifiers.
+    NonPersonalized,
+}
+
+/// Why a [`ConsentDecision`] restricts processing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecisionReason {
+    /// The request is child-directed under COPPA.
+    ChildDirected,
+    /// The privacy regime's consent signals do not allow personalized ads.
+    AdvertisingNotAllowed,
+    /// The request carries a binding Global Privacy Control opt-out.
+    GlobalPrivacyControl,
+    /// Strict Do Not Track mode is on and the request sends `DNT: 1`.
+    DoNotTrack,
+    /// The user objected to processing through `/gdpr/object`.
+    Objected,
+    /// The publisher has no consent to store or access information on the device.
+    NoStorageConsent,
+    /// The publisher has no consent for measurement.
+    NoAnalyticsConsent,
+    /// Precise geolocation lacks the TCF Special Feature 1 opt-in.
+    NoPreciseGeolocation,
+}
+
+/// Consent decision for a request, computed once by [`ConsentDecision::from_request`].
+#[derive(Debug, Clone)]
+pub struct ConsentDecision {
+    /// Privacy regime of the client's location.
+    pub regime: Regime,
+    /// Personalization allowed for ads.
+    pub personalization: PersonalizationLevel,
+    /// Whether visits may be counted against the synthetic ID.
+    pub analytics_allowed: bool,
+    /// Whether the synthetic ID and partner identifiers may be persisted.
+    pub storage_allowed: bool,
+    /// Whether precise geolocation may be shared with ad partners.
+    pub precise_geolocation_allowed: bool,
+    /// Whether the request is child-directed under COPPA.
+    pub child_directed: bool,
+    /// Reasons for every restriction, in the order they were found.
+    pub reasons: Vec<DecisionReason>,
+    /// Consent signals the decision was made from, forwarded to ad partners.
+    pub signals: ConsentSignals,
+}
+
+impl ConsentDecision {
+    /// Decides consent for a request.
+    ///
+    /// Under opt-in regimes, storage and analytics need the publisher's
+    /// consent for TCF purpose 1 and purposes 7-9 respectively; COPPA and
+    /// strict DNT rule out both, and COPPA also rules out personalization.
+    /// Objections to processing are not looked up here, see
+    /// [`ConsentDecision::with_objection`].
+    pub fn from_request(settings: &Settings, req: &Request) -> Self {
+        let signals =
+            ConsentSignals::from_request(settings, req, get_tcf_consent_or_default(settings, req));
+        Self::decide(settings, req, Regime::for_request(req), signals)
+    }
+
+    /// Decides consent for a request under `regime` from its `signals`.
+    ///
+    /// The TCF `gdpr_applies` flag forwarded to ad partners follows `regime`,
+    /// so clients that cannot be located are flagged as in scope of GDPR just
+    /// as they are decided under it.
+    fn decide(
+        settings: &Settings,
+        req: &Request,
+        regime: Regime,
+        mut signals: ConsentSignals,
+    ) -> Self {
+        signals.tcf.gdpr_applies = regime == Regime::Gdpr;
+        let policy = regime.policy();
+        let child_directed = is_child_directed(settings, req);
+        let dnt_enforced = is_dnt_enforced(settings, req);
+
+        let mut reasons = Vec::new();
+        if child_directed {
+            reasons.push(DecisionReason::ChildDirected);
+        }
+        let advertising_allowed = policy.allows_personalized_advertising(&signals);
+        if !advertising_allowed {
+            reasons.push(DecisionReason::AdvertisingNotAllowed);
+        }
+        if signals.gpc {
+            reasons.push(DecisionReason::GlobalPrivacyControl);
+        }
+        if dnt_enforced {
+            reasons.push(DecisionReason::DoNotTrack);
+        }
+        let storage_consent = !policy.requires_opt_in
+            || signals
+                .tcf
+                .has_publisher_consent(purpose_ids::DEVICE_ACCESS);
+        if !storage_consent {
+            reasons.push(DecisionReason::NoStorageConsent);
+        }
+        let analytics_consent =
+            !policy.requires_opt_in || signals.tcf.has_publisher_consent(purpose_ids::ANALYTICS);
+        if !analytics_consent {
+            reasons.push(DecisionReason::NoAnalyticsConsent);
+        }
+        let precise_geolocation_allowed = signals.tcf.allows_precise_geolocation();
+        if !precise_geolocation_allowed {
+            reasons.push(DecisionReason::NoPreciseGeolocation);
+        }
+
+        let personalization = if advertising_allowed && !child_directed {
+            PersonalizationLevel::Personalized
+        } else {
+            PersonalizationLevel::NonPersonalized
+        };
+        let decision = Self {
+            regime,
+            personalization,
+            analytics_allowed: analytics_consent && !child_directed && !dnt_enforced,
+            storage_allowed: storage_consent && !child_directed && !dnt_enforced,
+            precise_geolocation_allowed,
+            child_directed,
+            reasons,
+            signals,
+        };
+        log::debug!(
+            "Consent decision: regime {:?}, {:?}, analytics {}, storage {}, reasons {:?}",
+            decision.regime,
+            decision.personalization,
+            decision.analytics_allowed,
+            decision.storage_allowed,
+            decision.reasons
+        );
+        decision
+    }
+
+    /// This decision, with the user's objection to processing applied.
+    ///
+    /// An objection rules out personalization and analytics whatever the
+    /// consent signals say. Looking it up reads the objection store, so only
+    /// handlers personalizing ads or counting visits apply it.
+    pub fn with_objection(mut self, settings: &Settings, req: &Request) -> Self {
+        if has_objected(settings, req) {
+            self.personalization = PersonalizationLevel::NonPersonalized;
+            self.analytics_allowed = false;
+            self.reasons.push(DecisionReason::Objected);
+        }
+        self
+    }
+
+    /// Whether ads may be personalized.
+    pub fn is_personalized(&self) -> bool {
+        self.personalization == PersonalizationLevel::Personalized
+    }
+
+    /// Whether an ad partner may process the given purposes.
+    ///
+    /// Where GDPR applies, this is the partner's TCF consent for `vendor_id`,
+    /// validated against the vendor list when one is given. A partner without
+    /// a vendor ID is only allowed where GDPR does not apply.
+    pub fn allows_vendor(
+        &self,
+        vendor_id: Option<u16>,
+        purposes: &[u8],
+        vendor_list: Option<&VendorList>,
+    ) -> bool {
+        if !self.signals.tcf.gdpr_applies {
+            return true;
+        }
+        vendor_id.is_some_and(|vendor_id| {
+            self.signals
+                .tcf
+                .has_consent(vendor_id, purposes, vendor_list)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::constants::HEADER_SYNTHETIC_TRUSTED_SERVER;
+    use crate::objection::ObjectionStore;
+    use crate::tcf_consent::TcfConsent;
+    use crate::test_support::tests::create_test_settings;
+
+    // Purposes 1-4 consented
+    const TC_STRING: &str = "COvFyGBOvFyGBAbAAAENAPCAAPAAAAAAAAAAAEEUACCKAAA";
+
+    #[test]
+    fn test_consent_decision_personalized() {
+        let settings = create_test_settings();
+        let req = Request::get("https://example.com")
+            .with_header("Cookie", format!("euconsent-v2={}", TC_STRING));
+
+        let decision = ConsentDecision::from_request(&settings, &req);
+        assert_eq!(decision.regime, Regime::Gdpr);
+        assert!(decision.is_personalized());
+        assert!(decision.storage_allowed);
+        // Purposes 7-9 are not consented
+        assert!(!decision.analytics_allowed);
+        assert_eq!(
+            decision.reasons,
+            vec![
+                DecisionReason::NoAnalyticsConsent,
+                DecisionReason::NoPreciseGeolocation
+            ]
+        );
+        assert_eq!(decision.signals.tcf.tc_string, TC_STRING);
+    }
+
+    #[test]
+    fn test_consent_decision_without_consent() {
+        let settings = create_test_settings();
+        let req = Request::get("https://example.com");
+
+        let decision = ConsentDecision::from_request(&settings, &req);
+        assert_eq!(
+            decision.personalization,
+            PersonalizationLevel::NonPersonalized
+        );
+        assert!(!decision.storage_allowed);
+        assert!(decision
+            .reasons
+            .contains(&DecisionReason::AdvertisingNotAllowed));
+        assert!(decision.reasons.contains(&DecisionReason::NoStorageConsent));
+    }
+
+    #[test]
+    fn test_consent_decision_gpc_under_ccpa() {
+        let mut settings = create_test_settings();
+        settings.gpc.binding_regions = vec!["US-CA".to_string()];
+        // A California request with `Sec-GPC: 1` and no cookies
+        let req = Request::get("https://example.com").with_header("Sec-GPC", "1");
+        let signals = ConsentSignals {
+            gpc: true,
+            ..ConsentSignals::from_request(&settings, &req, TcfConsent::default())
+        };
+
+        let decision = ConsentDecision::decide(&settings, &req, Regime::Ccpa, signals);
+        assert_eq!(
+            decision.personalization,
+            PersonalizationLevel::NonPersonalized
+        );
+        assert!(decision
+            .reasons
+            .contains(&DecisionReason::GlobalPrivacyControl));
+
+        let decision =
+            ConsentDecision::decide(&settings, &req, Regime::Ccpa, ConsentSignals::default());
+        assert!(decision.is_personalized());
+    }
+
+    #[test]
+    fn test_allows_vendor() {
+        let settings = create_test_settings();
+        // Vendor consent for vendors 2, 6 and 8 only
+        let req = Request::get("https://example.com")
+            .with_header("Cookie", format!("euconsent-v2={}", TC_STRING));
+
+        let decision = ConsentDecision::from_request(&settings, &req);
+        assert!(decision.allows_vendor(Some(8), purpose_ids::ADVERTISING, None));
+        assert!(!decision.allows_vendor(Some(45), purpose_ids::BASIC_ADS, None));
+        assert!(!decision.allows_vendor(None, purpose_ids::BASIC_ADS, None));
+
+        // Clients that cannot be located are decided under GDPR...
+        let decision =
+            ConsentDecision::from_request(&settings, &Request::get("https://example.com"));
+        assert_eq!(decision.regime, Regime::Gdpr);
+        assert!(decision.signals.tcf.gdpr_applies);
+        assert!(!decision.allows_vendor(Some(8), purpose_ids::BASIC_ADS, None));
+        assert!(!decision.allows_vendor(None, purpose_ids::BASIC_ADS, None));
+
+        // ...while partners are not gated by TCF outside it
+        let decision = ConsentDecision::decide(
+            &settings,
+            &req,
+            Regime::Ccpa,
+            ConsentSignals::from_request(&settings, &req, TcfConsent::default()),
+        );
+        assert!(!decision.signals.tcf.gdpr_applies);
+        assert!(decision.allows_vendor(None, purpose_ids::BASIC_ADS, None));
+    }
+
+    #[test]
+    fn test_consent_decision_child_directed() {
+        let mut settings = create_test_settings();
+        settings.publisher.coppa = true;
+        let req = Request::get("https://example.com")
+            .with_header("Cookie", format!("euconsent-v2={}", TC_STRING));
+
+        let decision = ConsentDecision::from_request(&settings, &req);
+        assert!(decision.child_directed);
+        assert!(!decision.is_personalized());
+        assert!(!decision.storage_allowed);
+        assert_eq!(decision.reasons[0], DecisionReason::ChildDirected);
+    }
+
+    #[test]
+    fn test_consent_decision_objected() {
+        let mut settings = create_test_settings();
+        settings.gdpr.objection_store = "test_objection_store".to_string();
+        ObjectionStore::open(&settings)
+            .expect("should open the objection store")
+            .expect("should have an objection store configured")
+            .add("objected-synthetic")
+            .expect("should write to the store");
+        let req = Request::get("https://example.com")
+            .with_header("Cookie", format!("euconsent-v2={}", TC_STRING))
+            .with_header(HEADER_SYNTHETIC_TRUSTED_SERVER, "objected-synthetic");
+
+        let decision = ConsentDecision::from_request(&settings, &req);
+        assert!(!decision.reasons.contains(&DecisionReason::Objected));
+
+        let decision = decision.with_objection(&settings, &req);
+        assert!(!decision.is_personalized());
+        assert!(!decision.analytics_allowed);
+        // The synthetic ID cookie must survive for the objection to be recognized
+        assert!(decision.storage_allowed);
+        assert!(decision.reasons.contains(&DecisionReason::Objected));
+    }
+}
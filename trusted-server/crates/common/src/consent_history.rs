@@ -0,0 +1,166 @@
+//! Per-subject history of consent choices.
+//!
+//! Every consent choice made through `/gdpr/consent` is appended as a
+//! [`ConsentSnapshot`] to the history of the user's synthetic ID in the
+//! `gdpr.consent_history_store` KV store. Data access requests return the
+//! history as [`crate::gdpr::UserData::consent_history`], and erasure deletes
+//! it. Unlike [`crate::consent_receipt`], which keeps proof of consent, the
+//! history also holds the decoded TCF signals in effect at the time.
+
+use error_stack::Report;
+use serde::{Deserialize, Serialize};
+
+use crate::error::TrustedServerError;
+use crate::gdpr::GdprConsent;
+use crate::kv_store::JsonKvStore;
+use crate::settings::Settings;
+use crate::tcf_consent::{decode_tc_string, TcfConsent};
+
+/// Maximum number of snapshots kept per synthetic ID; the oldest are dropped first.
+const MAX_SNAPSHOTS: usize = 100;
+
+/// The consent in effect after a single consent choice.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConsentSnapshot {
+    /// Unix timestamp (seconds) when the choice was recorded.
+    pub recorded_at: i64,
+    /// Consent categories chosen.
+    pub consent: GdprConsent,
+    /// TCF consent decoded from the TC string in effect, if any.
+    pub tcf: Option<TcfConsent>,
+}
+
+/// KV store of consent snapshots keyed by synthetic ID.
+pub struct ConsentHistoryStore {
+    store: JsonKvStore,
+}
+
+impl ConsentSnapshot {
+    /// Snapshots `consent` and the TCF consent decoded from `tc_string`, if any.
+    pub fn new(consent: &GdprConsent, tc_string: Option<&str>) -> Self {
+        Self {
+            recorded_at: chrono::Utc::now().timestamp(),
+            consent: consent.clone(),
+            tcf: tc_string.and_then(|tc_string| decode_tc_string(tc_string).ok()),
+        }
+    }
+}
+
+impl ConsentHistoryStore {
+    /// Opens the store configured in `gdpr.consent_history_store`.
+    ///
+    /// Returns [`None`] when consent history is disabled.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the configured store cannot be opened
+    pub fn open(settings: &Settings) -> Result<Option<Self>, Report<TrustedServerError>> {
+        if settings.gdpr.consent_history_store.is_empty() {
+            return Ok(None);
+        }
+        let store = JsonKvStore::open(&settings.gdpr.consent_history_store)?;
+        Ok(Some(Self { store }))
+    }
+
+    fn key(synthetic_id: &str) -> String {
+        format!("history:{synthetic_id}")
+    }
+
+    /// Returns the consent history of `synthetic_id`, oldest first.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the lookup fails
+    pub fn get(
+        &self,
+        synthetic_id: &str,
+    ) -> Result<Vec<ConsentSnapshot>, Report<TrustedServerError>> {
+        Ok(self
+            .store
+            .get(&Self::key(synthetic_id))?
+            .unwrap_or_default())
+    }
+
+    /// Appends `snapshot` to the consent history of `synthetic_id`.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the store cannot be read or written
+    pub fn append(
+        &self,
+        synthetic_id: &str,
+        snapshot: ConsentSnapshot,
+    ) -> Result<(), Report<TrustedServerError>> {
+        let mut history = self.get(synthetic_id)?;
+        history.push(snapshot);
+        if history.len() > MAX_SNAPSHOTS {
+            history.drain(..history.len() - MAX_SNAPSHOTS);
+        }
+        self.store.put(&Self::key(synthetic_id), &history)
+    }
+
+    /// Deletes the consent history of `synthetic_id`.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the deletion fails
+    pub fn delete(&self, synthetic_id: &str) -> Result<(), Report<TrustedServerError>> {
+        self.store.delete(&Self::key(synthetic_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::tests::create_test_settings;
+
+    const TC_STRING: &str = "COvFyGBOvFyGBAbAAAENAPCAAPAAAAAAAAAAAEEUACCKAAA";
+
+    #[test]
+    fn test_consent_history_store() {
+        assert!(ConsentHistoryStore::open(&create_test_settings())
+            .expect("should open the consent history store")
+            .is_none());
+        let mut settings = create_test_settings();
+        settings.gdpr.consent_history_store = "test_history_store".to_string();
+        let store = ConsentHistoryStore::open(&settings)
+            .expect("should open the consent history store")
+            .expect("should have a consent history store configured");
+
+        let granted = GdprConsent {
+            advertising: true,
+            ..Default::default()
+        };
+        store
+            .append(
+                "history-synthetic",
+                ConsentSnapshot::new(&granted, Some(TC_STRING)),
+            )
+            .expect("should write to the store");
+        store
+            .append(
+                "history-synthetic",
+                ConsentSnapshot::new(&GdprConsent::default(), Some("invalid")),
+            )
+            .expect("should write to the store");
+
+        let history = store
+            .get("history-synthetic")
+            .expect("should read the store");
+        assert_eq!(history.len(), 2);
+        assert!(history[0].consent.advertising);
+        let tcf = history[0].tcf.as_ref().expect("should have TCF signals");
+        assert_eq!(tcf.tc_string, TC_STRING);
+        assert!(tcf.purpose_consents.contains(1));
+        assert!(!history[1].consent.advertising);
+        assert!(history[1].tcf.is_none());
+
+        store
+            .delete("history-synthetic")
+            .expect("should delete from the store");
+        assert!(store
+            .get("history-synthetic")
+            .expect("should read the store")
+            .is_empty());
+    }
+}
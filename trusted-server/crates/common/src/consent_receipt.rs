@@ -0,0 +1,272 @@
+//! Consent receipts in the style of ISO/IEC TS 27560.
+//!
+//! Every consent choice made through `/gdpr/consent` produces a
+//! [`ConsentReceipt`] recording what was agreed to and when. Receipts are kept
+//! per synthetic ID in the `gdpr.receipt_store` KV store so users can retrieve
+//! them from `/gdpr/receipts`.
+
+use error_stack::Report;
+use fastly::http::{header, StatusCode};
+use fastly::{Error, Request, Response};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::compliance_log::{AccessEvent, Actor};
+use crate::error::TrustedServerError;
+use crate::gdpr::GdprConsent;
+use crate::kv_store::JsonKvStore;
+use crate::settings::Settings;
+use crate::synthetic::resolve_synthetic_id;
+
+/// Maximum number of receipts kept per synthetic ID; the oldest are dropped first.
+const MAX_RECEIPTS: usize = 100;
+
+/// Record of a single consent choice.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsentReceipt {
+    /// Unique receipt ID (UUID v4).
+    pub receipt_id: String,
+    /// Unix timestamp (seconds) when the receipt was issued.
+    pub timestamp: i64,
+    /// TCF purposes the user consented to.
+    pub purposes: Vec<u8>,
+    /// Hex-encoded SHA-256 of the TC string in effect, if any.
+    pub tc_string_hash: Option<String>,
+    /// Version of the consent policy the choice was made under.
+    pub policy_version: String,
+}
+
+impl ConsentReceipt {
+    /// Issues a receipt for a consent choice and the TC string recording it.
+    pub fn new(consent: &GdprConsent, tc_string: Option<&str>) -> Self {
+        Self {
+            receipt_id: Uuid::new_v4().to_string(),
+            timestamp: chrono::Utc::now().timestamp(),
+            purposes: consent.purposes(),
+            tc_string_hash: tc_string.map(|s| hex::encode(Sha256::digest(s.as_bytes()))),
+            policy_version: consent.version.clone(),
+        }
+    }
+}
+
+/// KV store of consent receipts keyed by synthetic ID.
+pub struct ConsentReceiptStore {
+    store: JsonKvStore,
+}
+
+impl ConsentReceiptStore {
+    /// Opens the store configured in `gdpr.receipt_store`.
+    ///
+    /// Returns [`None`] when consent receipts are disabled.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the configured store cannot be opened
+    pub fn open(settings: &Settings) -> Result<Option<Self>, Report<TrustedServerError>> {
+        if settings.gdpr.receipt_store.is_empty() {
+            return Ok(None);
+        }
+        let store = JsonKvStore::open(&settings.gdpr.receipt_store)?;
+        Ok(Some(Self { store }))
+    }
+
+    fn key(synthetic_id: &str) -> String {
+        format!("receipts:{synthetic_id}")
+    }
+
+    /// Returns the receipts issued for `synthetic_id`, oldest first.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the lookup fails
+    pub fn get(
+        &self,
+        synthetic_id: &str,
+    ) -> Result<Vec<ConsentReceipt>, Report<TrustedServerError>> {
+        Ok(self
+            .store
+            .get(&Self::key(synthetic_id))?
+            .unwrap_or_default())
+    }
+
+    /// Appends `receipt` to the receipts of `synthetic_id`.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the store cannot be read or written
+    pub fn append(
+        &self,
+        synthetic_id: &str,
+        receipt: ConsentReceipt,
+    ) -> Result<(), Report<TrustedServerError>> {
+        let mut receipts = self.get(synthetic_id)?;
+        receipts.push(receipt);
+        if receipts.len() > MAX_RECEIPTS {
+            receipts.drain(..receipts.len() - MAX_RECEIPTS);
+        }
+        self.store.put(&Self::key(synthetic_id), &receipts)
+    }
+
+    /// Deletes the receipts issued for `synthetic_id`.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the deletion fails
+    pub fn delete(&self, synthetic_id: &str) -> Result<(), Report<TrustedServerError>> {
+        self.store.delete(&Self::key(synthetic_id))
+    }
+}
+
+/// Handles `GET /gdpr/receipts`, returning the consent receipts of the
+/// requesting user's synthetic ID.
+///
+/// Only IDs proven by a signed `synthetic_id` cookie qualify (see
+/// [`SyntheticId::is_cookie_backed`](crate::synthetic::SyntheticId::is_cookie_backed));
+/// IDs generated from the request, claimed through the
+/// `X-Synthetic-Trusted-Server` header or found through an identity link are
+/// refused with `403 Forbidden`.
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if the synthetic ID cannot be resolved or the
+/// receipt store cannot be read.
+pub fn handle_consent_receipts(settings: &Settings, req: Request) -> Result<Response, Error> {
+    let Some(store) =
+        ConsentReceiptStore::open(settings).map_err(|e| Error::msg(format!("{e:?}")))?
+    else {
+        return Ok(Response::from_status(StatusCode::NOT_FOUND).with_body("Not Found"));
+    };
+
+    let synthetic_id =
+        resolve_synthetic_id(settings, &req).map_err(|e| Error::msg(format!("{e:?}")))?;
+    let event = AccessEvent::new(
+        &req,
+        "consent_receipts",
+        Actor::from(synthetic_id.source),
+        Some(&synthetic_id.value),
+    );
+    if !synthetic_id.is_cookie_backed() {
+        event.record(settings, StatusCode::FORBIDDEN);
+        return Ok(Response::from_status(StatusCode::FORBIDDEN).with_body("Forbidden"));
+    }
+    let receipts = store
+        .get(&synthetic_id.value)
+        .map_err(|e| Error::msg(format!("{e:?}")))?;
+    event.record(settings, StatusCode::OK);
+
+    Ok(Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_header(header::CACHE_CONTROL, "no-store, private")
+        .with_body_json(&receipts)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::HEADER_SYNTHETIC_TRUSTED_SERVER;
+    use crate::synthetic::{sign_synthetic_id, SyntheticId};
+    use crate::test_support::tests::create_test_settings;
+
+    fn receipt_settings() -> Settings {
+        let mut settings = create_test_settings();
+        settings.gdpr.receipt_store = "test_receipt_store".to_string();
+        settings
+    }
+
+    #[test]
+    fn test_consent_receipt_new() {
+        let consent = GdprConsent {
+            advertising: true,
+            functional: true,
+            ..Default::default()
+        };
+        let receipt = ConsentReceipt::new(&consent, Some("CQABC"));
+        assert_eq!(receipt.purposes, vec![1, 2, 3, 4]);
+        assert_eq!(receipt.policy_version, "1.0");
+        assert_eq!(receipt.tc_string_hash.as_deref().map(str::len), Some(64));
+        assert_ne!(
+            ConsentReceipt::new(&consent, Some("CQABC")).receipt_id,
+            receipt.receipt_id
+        );
+
+        let receipt = ConsentReceipt::new(&GdprConsent::default(), None);
+        assert!(receipt.purposes.is_empty());
+        assert_eq!(receipt.tc_string_hash, None);
+    }
+
+    #[test]
+    fn test_consent_receipt_store() {
+        let settings = receipt_settings();
+        let store = ConsentReceiptStore::open(&settings)
+            .expect("should open the consent receipt store")
+            .expect("should have a consent receipt store configured");
+
+        let first = ConsentReceipt::new(&GdprConsent::default(), None);
+        let second = ConsentReceipt::new(&GdprConsent::default(), Some("CQABC"));
+        store
+            .append("receipt-synthetic", first.clone())
+            .expect("should write to the store");
+        store
+            .append("receipt-synthetic", second.clone())
+            .expect("should write to the store");
+        assert_eq!(
+            store
+                .get("receipt-synthetic")
+                .expect("should read the store"),
+            vec![first, second]
+        );
+        assert!(store
+            .get("other-synthetic")
+            .expect("should read the store")
+            .is_empty());
+    }
+
+    #[test]
+    fn test_handle_consent_receipts_disabled() {
+        let settings = create_test_settings();
+        let req = Request::get("https://example.com/gdpr/receipts");
+
+        let resp = handle_consent_receipts(&settings, req).expect("should handle the request");
+        assert_eq!(resp.get_status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_handle_consent_receipts() {
+        let settings = receipt_settings();
+        let store = ConsentReceiptStore::open(&settings)
+            .expect("should open the consent receipt store")
+            .expect("should have a consent receipt store configured");
+        let receipt = ConsentReceipt::new(&GdprConsent::default(), None);
+        store
+            .append("handler-synthetic", receipt.clone())
+            .expect("should write to the store");
+
+        let signed = sign_synthetic_id(&settings, &SyntheticId::new("handler-synthetic".into()));
+        let req = Request::get("https://example.com/gdpr/receipts")
+            .with_header(header::COOKIE, format!("synthetic_id={signed}"));
+        let resp = handle_consent_receipts(&settings, req).expect("should handle the request");
+        assert_eq!(resp.get_status(), StatusCode::OK);
+
+        let receipts: Vec<ConsentReceipt> =
+            serde_json::from_str(&resp.into_body_str()).expect("should parse JSON");
+        assert_eq!(receipts, vec![receipt]);
+    }
+
+    #[test]
+    fn test_handle_consent_receipts_refuses_claimed_id() {
+        let settings = receipt_settings();
+        let store = ConsentReceiptStore::open(&settings)
+            .expect("should open the consent receipt store")
+            .expect("should have a consent receipt store configured");
+        let receipt = ConsentReceipt::new(&GdprConsent::default(), None);
+        store
+            .append("claimed-synthetic", receipt)
+            .expect("should write to the store");
+
+        let req = Request::get("https://example.com/gdpr/receipts")
+            .with_header(HEADER_SYNTHETIC_TRUSTED_SERVER, "claimed-synthetic");
+        let resp = handle_consent_receipts(&settings, req).expect("should handle the request");
+        assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,198 @@
+//! Webhooks notifying the publisher of consent changes.
+//!
+//! When `/gdpr/consent` grants or withdraws purposes, a [`ConsentEvent`] is
+//! POSTed as JSON to `consent_webhook.url` so the publisher's backend and DMPs
+//! can react, e.g. by purging server-side profiles. The body is signed with
+//! HMAC-SHA256 using `consent_webhook.secret`; receivers should recompute the
+//! signature over the raw body and compare it to `X-Trusted-Server-Signature`.
+
+use error_stack::{Report, ResultExt};
+use fastly::http::{header, Method};
+use fastly::Request;
+use hmac::Mac;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::constants::HEADER_X_TRUSTED_SERVER_SIGNATURE;
+use crate::error::TrustedServerError;
+use crate::gdpr::GdprConsent;
+use crate::settings::Settings;
+use crate::signing::keyed_mac;
+
+/// Consent change sent to the webhook.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsentEvent {
+    /// Unique event ID (UUID v4), usable for deduplication.
+    pub event_id: String,
+    /// Unix timestamp (seconds) of the change.
+    pub timestamp: i64,
+    /// Synthetic ID of the user whose consent changed.
+    pub synthetic_id: String,
+    /// TCF purposes granted by this change.
+    pub granted: Vec<u8>,
+    /// TCF purposes withdrawn by this change.
+    pub withdrawn: Vec<u8>,
+    /// TCF purposes consented to after the change.
+    pub purposes: Vec<u8>,
+    /// Receipt stored for the choice, if consent receipts are enabled.
+    pub receipt_id: Option<String>,
+}
+
+impl ConsentEvent {
+    /// Builds the event for a change from `previous` to `current` consent.
+    ///
+    /// Returns [`None`] if no purpose was granted or withdrawn.
+    pub fn new(
+        synthetic_id: &str,
+        previous: Option<&GdprConsent>,
+        current: &GdprConsent,
+        receipt_id: Option<String>,
+    ) -> Option<Self> {
+        let before = previous.map(GdprConsent::purposes).unwrap_or_default();
+        let purposes = current.purposes();
+        let granted: Vec<u8> = purposes
+            .iter()
+            .copied()
+            .filter(|p| !before.contains(p))
+            .collect();
+        let withdrawn: Vec<u8> = before
+            .iter()
+            .copied()
+            .filter(|p| !purposes.contains(p))
+            .collect();
+        if granted.is_empty() && withdrawn.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            event_id: Uuid::new_v4().to_string(),
+            timestamp: chrono::Utc::now().timestamp(),
+            synthetic_id: synthetic_id.to_string(),
+            granted,
+            withdrawn,
+            purposes,
+            receipt_id,
+        })
+    }
+}
+
+fn webhook_error(message: impl Into<String>) -> TrustedServerError {
+    TrustedServerError::Webhook {
+        message: message.into(),
+    }
+}
+
+/// Signs a webhook body, returning the `sha256=<hex>` signature header value.
+pub fn sign_body(secret: &str, body: &[u8]) -> String {
+    let mac = keyed_mac(secret.as_bytes(), b"", &[body]);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// POSTs a consent event to `consent_webhook.url`.
+///
+/// Does nothing when webhooks are disabled.
+///
+/// # Errors
+///
+/// - [`TrustedServerError::Webhook`] if the event cannot be serialized, sent,
+///   or the endpoint does not answer with a success status
+pub fn send_consent_event(
+    settings: &Settings,
+    event: &ConsentEvent,
+) -> Result<(), Report<TrustedServerError>> {
+    let webhook = &settings.consent_webhook;
+    if webhook.url.is_empty() {
+        return Ok(());
+    }
+
+    let body =
+        serde_json::to_vec(event).change_context(webhook_error("Failed to serialize event"))?;
+    let resp = Request::new(Method::POST, &webhook.url)
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_header(
+            HEADER_X_TRUSTED_SERVER_SIGNATURE,
+            sign_body(&webhook.secret, &body),
+        )
+        .with_body(body)
+        .send(webhook.backend.as_str())
+        .change_context(webhook_error(format!(
+            "Failed to send event to {}",
+            webhook.url
+        )))?;
+
+    if !resp.get_status().is_success() {
+        return Err(Report::new(webhook_error(format!(
+            "{} returned {}",
+            webhook.url,
+            resp.get_status()
+        ))));
+    }
+    log::info!("Sent consent event {} to {}", event.event_id, webhook.url);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::tests::create_test_settings;
+
+    #[test]
+    fn test_consent_event_new() {
+        let previous = GdprConsent {
+            functional: true,
+            advertising: true,
+            ..Default::default()
+        };
+        let current = GdprConsent {
+            functional: true,
+            analytics: true,
+            ..Default::default()
+        };
+
+        let event = ConsentEvent::new("abc", Some(&previous), &current, None).unwrap();
+        assert_eq!(event.synthetic_id, "abc");
+        assert_eq!(event.granted, vec![7, 8, 9]);
+        assert_eq!(event.withdrawn, vec![2, 3, 4]);
+        assert_eq!(event.purposes, vec![1, 7, 8, 9]);
+
+        let event = ConsentEvent::new("abc", None, &previous, Some("r1".to_string())).unwrap();
+        assert_eq!(event.granted, vec![1, 2, 3, 4]);
+        assert!(event.withdrawn.is_empty());
+        assert_eq!(event.receipt_id.as_deref(), Some("r1"));
+    }
+
+    #[test]
+    fn test_consent_event_unchanged() {
+        let consent = GdprConsent {
+            advertising: true,
+            ..Default::default()
+        };
+        assert!(ConsentEvent::new("abc", Some(&consent), &consent, None).is_none());
+        assert!(ConsentEvent::new("abc", None, &GdprConsent::default(), None).is_none());
+    }
+
+    #[test]
+    fn test_sign_body() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign_body("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_send_consent_event_disabled() {
+        let settings = create_test_settings();
+        let event = ConsentEvent::new(
+            "abc",
+            None,
+            &GdprConsent {
+                functional: true,
+                ..Default::default()
+            },
+            None,
+        )
+        .unwrap();
+        assert!(send_consent_event(&settings, &event).is_ok());
+    }
+}
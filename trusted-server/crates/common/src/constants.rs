@@ -7,6 +7,7 @@ pub const HEADER_SYNTHETIC_TRUSTED_SERVER: HeaderName =
     HeaderName::from_static("x-synthetic-trusted-server");
 pub const HEADER_X_CONSENT_ADVERTISING: HeaderName =
     HeaderName::from_static("x-consent-advertising");
+pub const HEADER_X_CONSENT_RECEIPT_ID: HeaderName = HeaderName::from_static("x-consent-receipt-id");
 pub const HEADER_X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
 pub const HEADER_X_GEO_CITY: HeaderName = HeaderName::from_static("x-geo-city");
 pub const HEADER_X_GEO_CONTINENT: HeaderName = HeaderName::from_static("x-geo-continent");
@@ -16,6 +17,14 @@ pub const HEADER_X_GEO_INFO_AVAILABLE: HeaderName = HeaderName::from_static("x-g
 pub const HEADER_X_GEO_METRO_CODE: HeaderName = HeaderName::from_static("x-geo-metro-code");
 pub const HEADER_X_GEO_REGION: HeaderName = HeaderName::from_static("x-geo-region");
 pub const HEADER_X_SUBJECT_ID: HeaderName = HeaderName::from_static("x-subject-id");
+pub const HEADER_X_DSAR_TOKEN: HeaderName = HeaderName::from_static("x-dsar-token");
+pub const HEADER_X_DSAR_REQUEST_ID: HeaderName = HeaderName::from_static("x-dsar-request-id");
+pub const HEADER_X_API_KEY: HeaderName = HeaderName::from_static("x-api-key");
 pub const HEADER_X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
 pub const HEADER_X_COMPRESS_HINT: HeaderName = HeaderName::from_static("x-compress-hint");
 pub const HEADER_X_DEBUG_FASTLY_POP: HeaderName = HeaderName::from_static("x-debug-fastly-pop");
+pub const HEADER_X_COPPA: HeaderName = HeaderName::from_static("x-coppa");
+pub const HEADER_DNT: HeaderName = HeaderName::from_static("dnt");
+pub const HEADER_SEC_GPC: HeaderName = HeaderName::from_static("sec-gpc");
+pub const HEADER_X_TRUSTED_SERVER_SIGNATURE: HeaderName =
+    HeaderName::from_static("x-trusted-server-signature");
@@ -3,16 +3,36 @@
 //! This module provides functionality for parsing and creating cookies
 //! used in the trusted server system.
 
+use std::fmt;
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
 use cookie::{Cookie, CookieJar};
 use error_stack::{Report, ResultExt};
 use fastly::http::header;
-use fastly::Request;
+use fastly::{Request, Response};
+use hmac::Mac;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
 
 use crate::error::TrustedServerError;
-use crate::settings::Settings;
+use crate::settings::{CookiePrefix, Settings};
+use crate::signing::{keyed_mac, HmacSha256};
+use crate::synthetic::{sign_synthetic_id, SyntheticId};
+use crate::tcf_consent::TcfConsent;
 
 const COOKIE_MAX_AGE: i32 = 365 * 24 * 60 * 60; // 1 year
 
+/// Base name of the synthetic ID cookie, before any prefix.
+pub const SYNTHETIC_COOKIE: &str = "synthetic_id";
+
+/// Prefixes the synthetic ID cookie may have been issued with.
+const COOKIE_PREFIXES: [CookiePrefix; 3] =
+    [CookiePrefix::Host, CookiePrefix::Secure, CookiePrefix::None];
+
 /// Parses a cookie string into a [`CookieJar`].
 ///
 /// Returns an empty jar if the cookie string is unparseable.
@@ -58,17 +78,480 @@ pub fn handle_request_cookies(
     }
 }
 
-/// Creates a synthetic ID cookie string.
+/// Separator between a cookie value and its signature.
+const SIGNATURE_SEPARATOR: char = '.';
+
+fn value_mac(settings: &Settings, name: &str, value: &str) -> HmacSha256 {
+    keyed_mac(
+        settings.synthetic.secret_key.as_bytes(),
+        format!("{name}:").as_bytes(),
+        &[value.as_bytes()],
+    )
+}
+
+/// Signs a cookie value with the configured secret key.
 ///
-/// Generates a properly formatted cookie with security attributes
-/// for storing the synthetic ID.
-pub fn create_synthetic_cookie(settings: &Settings, synthetic_id: &str) -> String {
+/// Returns `<value>.<signature>` where the signature is a hex-encoded
+/// HMAC-SHA256 over the cookie name and value. Binding the name means a value
+/// signed for one cookie is rejected when replayed in another.
+pub fn sign_value(settings: &Settings, name: &str, value: &str) -> String {
+    let signature = hex::encode(value_mac(settings, name, value).finalize().into_bytes());
+    format!("{value}{SIGNATURE_SEPARATOR}{signature}")
+}
+
+/// Verifies a cookie value produced by [`sign_value`].
+///
+/// Returns the original value if the signature matches, or [`None`] if the
+/// value is malformed or has been tampered with. The comparison is performed in
+/// constant time.
+pub fn verify_value<'a>(settings: &Settings, name: &str, signed_value: &'a str) -> Option<&'a str> {
+    let (value, signature) = signed_value.rsplit_once(SIGNATURE_SEPARATOR)?;
+    let signature = hex::decode(signature).ok()?;
+    value_mac(settings, name, value)
+        .verify_slice(&signature)
+        .ok()
+        .map(|()| value)
+}
+
+/// Length of the AES-GCM nonce prepended to encrypted cookie values.
+const ENCRYPTION_NONCE_LEN: usize = 12;
+
+/// Derives the cookie encryption key from the configured secret key.
+///
+/// The key is domain-separated from the signing key so the two never coincide.
+fn encryption_cipher(settings: &Settings) -> Aes256Gcm {
+    let key = Sha256::new()
+        .chain_update(b"cookie-encryption:")
+        .chain_update(settings.synthetic.secret_key.as_bytes())
+        .finalize();
+    Aes256Gcm::new(&key)
+}
+
+/// Encrypts a cookie value with AES-256-GCM.
+///
+/// Returns the URL-safe base64 encoding of a random nonce followed by the
+/// ciphertext. The cookie name is authenticated as associated data, so values
+/// cannot be moved between cookies.
+pub fn encrypt_value(settings: &Settings, name: &str, value: &str) -> String {
+    let mut nonce = [0u8; ENCRYPTION_NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let ciphertext = encryption_cipher(settings)
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: value.as_bytes(),
+                aad: name.as_bytes(),
+            },
+        )
+        .expect("should encrypt cookie values of any length");
+
+    URL_SAFE_NO_PAD.encode([nonce.as_slice(), ciphertext.as_slice()].concat())
+}
+
+/// Decrypts a cookie value produced by [`encrypt_value`].
+///
+/// Returns [`None`] if the value is malformed, was encrypted for another
+/// cookie, or has been modified.
+pub fn decrypt_value(settings: &Settings, name: &str, encrypted: &str) -> Option<String> {
+    let bytes = URL_SAFE_NO_PAD.decode(encrypted).ok()?;
+    if bytes.len() < ENCRYPTION_NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = bytes.split_at(ENCRYPTION_NONCE_LEN);
+    let plaintext = encryption_cipher(settings)
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: name.as_bytes(),
+            },
+        )
+        .ok()?;
+    String::from_utf8(plaintext).ok()
+}
+
+/// Value of the `SameSite` cookie attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Strict => write!(f, "Strict"),
+            Self::Lax => write!(f, "Lax"),
+            Self::None => write!(f, "None"),
+        }
+    }
+}
+
+/// Builder for `Set-Cookie` header values.
+///
+/// Attributes are emitted in a fixed order so generated headers are stable.
+/// `Partitioned` and `SameSite=None` cookies are always marked `Secure`, as
+/// browsers reject them otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CookieBuilder {
+    name: String,
+    value: String,
+    domain: Option<String>,
+    path: Option<String>,
+    max_age: Option<i64>,
+    expires: Option<DateTime<Utc>>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+    partitioned: bool,
+}
+
+impl CookieBuilder {
+    /// Creates a builder for a cookie with no attributes.
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            value: value.into(),
+            domain: None,
+            path: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+            partitioned: false,
+        }
+    }
+
+    /// Sets the `Domain` attribute.
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    /// Sets the `Path` attribute.
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Sets the `Max-Age` attribute in seconds.
+    pub fn max_age(mut self, seconds: i64) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    /// Sets the `Expires` attribute.
+    pub fn expires(mut self, expires: DateTime<Utc>) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    /// Sets the `Secure` flag.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Sets the `HttpOnly` flag.
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    /// Sets the `SameSite` attribute.
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    /// Sets the `Partitioned` (CHIPS) flag.
+    pub fn partitioned(mut self, partitioned: bool) -> Self {
+        self.partitioned = partitioned;
+        self
+    }
+
+    /// Turns the cookie into a deletion: empty value, `Max-Age=0` and an
+    /// `Expires` date in the past.
+    ///
+    /// Browsers only delete a cookie when Domain and Path match the ones it
+    /// was issued with, so set those the same way as when creating it.
+    pub fn expired(mut self) -> Self {
+        self.value = String::new();
+        self.max_age = Some(0);
+        self.expires = Some(DateTime::UNIX_EPOCH);
+        self
+    }
+
+    /// Builds the `Set-Cookie` header value.
+    pub fn build(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for CookieBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={domain}")?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, "; Path={path}")?;
+        }
+        if let Some(expires) = &self.expires {
+            write!(
+                f,
+                "; Expires={}",
+                expires.format("%a, %d %b %Y %H:%M:%S GMT")
+            )?;
+        }
+        if self.secure || self.partitioned || self.same_site == Some(SameSite::None) {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={same_site}")?;
+        }
+        if self.partitioned {
+            write!(f, "; Partitioned")?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={max_age}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Accumulates `Set-Cookie` values for a response.
+///
+/// `Response::set_header` replaces any previous `Set-Cookie` header, so handlers
+/// that issue several cookies should collect them here and call [`apply`] once.
+/// Adding a cookie with the same name as an earlier one replaces it.
+///
+/// [`apply`]: ResponseCookies::apply
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ResponseCookies {
+    cookies: Vec<String>,
+}
+
+impl ResponseCookies {
+    /// Creates an empty cookie collection.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a `Set-Cookie` value, e.g. from [`CookieBuilder`].
+    pub fn add(&mut self, cookie: impl ToString) -> &mut Self {
+        let cookie = cookie.to_string();
+        let name = cookie_name(&cookie);
+        self.cookies
+            .retain(|existing| cookie_name(existing) != name);
+        self.cookies.push(cookie);
+        self
+    }
+
+    /// Returns `true` if no cookies were added.
+    pub fn is_empty(&self) -> bool {
+        self.cookies.is_empty()
+    }
+
+    /// Returns the collected `Set-Cookie` values in insertion order.
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.cookies.iter().map(String::as_str)
+    }
+
+    /// Appends one `Set-Cookie` header per collected cookie to `response`.
+    pub fn apply(&self, response: &mut Response) {
+        for cookie in &self.cookies {
+            response.append_header(header::SET_COOKIE, cookie.as_str());
+        }
+    }
+}
+
+/// Strips a `__Host-` or `__Secure-` prefix from a cookie name.
+fn unprefixed_name(name: &str) -> &str {
+    name.strip_prefix(CookiePrefix::Host.as_str())
+        .or_else(|| name.strip_prefix(CookiePrefix::Secure.as_str()))
+        .unwrap_or(name)
+}
+
+/// Returns `true` if `consent` allows setting the cookie `name`.
+///
+/// The required TCF purpose is looked up in `cookie_policy` by the unprefixed
+/// cookie name; cookies without an entry are treated as strictly necessary.
+/// These are first-party cookies, so the purpose is checked against the
+/// publisher's consent.
+pub fn is_cookie_allowed(settings: &Settings, consent: &TcfConsent, name: &str) -> bool {
+    match settings.cookie_policy.get(unprefixed_name(name)) {
+        Some(purpose) => consent.has_publisher_consent(&[*purpose]),
+        None => true,
+    }
+}
+
+/// Drops the cookies in `cookies` that `consent` does not allow.
+///
+/// Deletions (`Max-Age=0`) always pass, since removing a cookie never needs
+/// consent. Every handler that sets cookies should run them through this
+/// function before calling [`ResponseCookies::apply`].
+pub fn filter_for_consent(
+    settings: &Settings,
+    consent: &TcfConsent,
+    cookies: &ResponseCookies,
+) -> ResponseCookies {
+    let mut allowed = ResponseCookies::new();
+    for cookie in cookies.iter() {
+        let is_deletion = Cookie::parse(cookie)
+            .ok()
+            .and_then(|c| c.max_age())
+            .is_some_and(|max_age| max_age.is_zero());
+        let name = cookie_name(cookie);
+        if is_deletion || is_cookie_allowed(settings, consent, name) {
+            allowed.add(cookie);
+        } else {
+            log::debug!("Dropping cookie {} without the required consent", name);
+        }
+    }
+    allowed
+}
+
+/// Returns the name portion of a `Set-Cookie` value.
+fn cookie_name(cookie: &str) -> &str {
+    cookie
+        .split_once('=')
+        .map_or(cookie, |(name, _)| name)
+        .trim()
+}
+
+/// Creates a `Set-Cookie` value deleting the cookie `name`.
+///
+/// Pass the same `domain` the cookie was issued with, or [`None`] for host-only
+/// cookies.
+pub fn delete_cookie(name: &str, domain: Option<&str>) -> String {
+    let mut cookie = CookieBuilder::new(name, "").path("/").secure(true);
+    if let Some(domain) = domain {
+        cookie = cookie.domain(domain);
+    }
+    cookie.same_site(SameSite::Lax).expired().build()
+}
+
+/// Creates a `Set-Cookie` value deleting the synthetic ID cookie issued under `prefix`.
+fn delete_synthetic_cookie(settings: &Settings, prefix: CookiePrefix) -> String {
+    let name = format!("{}{}", prefix.as_str(), SYNTHETIC_COOKIE);
+    match prefix {
+        CookiePrefix::Host => delete_cookie(&name, None),
+        _ => delete_cookie(&name, Some(&settings.publisher.cookie_domain)),
+    }
+}
+
+/// Creates `Set-Cookie` values deleting the synthetic ID cookie under every prefix.
+///
+/// All variants are cleared so that a cookie issued before
+/// `synthetic.cookie_prefix` changed is removed as well.
+pub fn delete_synthetic_cookies(settings: &Settings) -> Vec<String> {
+    COOKIE_PREFIXES
+        .iter()
+        .map(|prefix| delete_synthetic_cookie(settings, *prefix))
+        .collect()
+}
+
+/// Creates `Set-Cookie` values deleting the synthetic ID cookies in `jar` that
+/// were issued under a prefix other than `synthetic.cookie_prefix`.
+///
+/// Sent alongside the re-issued cookie, so that legacy cookies read during
+/// `synthetic.legacy_cookies_until` are migrated to the configured name.
+pub fn delete_legacy_synthetic_cookies(settings: &Settings, jar: &CookieJar) -> Vec<String> {
+    legacy_prefixes(settings)
+        .filter(|prefix| {
+            jar.get(&format!("{}{}", prefix.as_str(), SYNTHETIC_COOKIE))
+                .is_some()
+        })
+        .map(|prefix| delete_synthetic_cookie(settings, prefix))
+        .collect()
+}
+
+/// Returns the cookie prefixes other than `synthetic.cookie_prefix`.
+fn legacy_prefixes(settings: &Settings) -> impl Iterator<Item = CookiePrefix> + '_ {
+    COOKIE_PREFIXES
+        .into_iter()
+        .filter(|prefix| *prefix != settings.synthetic.cookie_prefix)
+}
+
+/// Returns the name of the synthetic ID cookie, including the configured prefix.
+pub fn synthetic_cookie_name(settings: &Settings) -> String {
     format!(
-        "synthetic_id={}; Domain={}; Path=/; Secure; SameSite=Lax; Max-Age={}",
-        synthetic_id, settings.publisher.cookie_domain, COOKIE_MAX_AGE,
+        "{}{}",
+        settings.synthetic.cookie_prefix.as_str(),
+        SYNTHETIC_COOKIE
     )
 }
 
+/// Returns the synthetic ID cookie from `jar`.
+///
+/// Only the cookie named by the configured prefix is read, so that e.g. a
+/// `__Host-` cookie cannot be shadowed by a `synthetic_id` cookie set from a
+/// sibling subdomain. Until `synthetic.legacy_cookies_until`, cookies issued
+/// under another prefix are still accepted so that changing
+/// `synthetic.cookie_prefix` does not reset every user's ID; see
+/// [`delete_legacy_synthetic_cookies`].
+pub fn get_synthetic_cookie<'a>(
+    settings: &Settings,
+    jar: &'a CookieJar,
+) -> Option<&'a Cookie<'static>> {
+    jar.get(&synthetic_cookie_name(settings)).or_else(|| {
+        if chrono::Utc::now().timestamp() >= settings.synthetic.legacy_cookies_until {
+            return None;
+        }
+        legacy_prefixes(settings)
+            .find_map(|prefix| jar.get(&format!("{}{}", prefix.as_str(), SYNTHETIC_COOKIE)))
+    })
+}
+
+/// Creates a synthetic ID cookie string.
+///
+/// Generates a properly formatted cookie with security attributes
+/// for storing the synthetic ID. The value is signed with
+/// [`sign_synthetic_id`] so that tampering can be detected when it is read back,
+/// and the cookie never outlives the ID's remaining retention window.
+///
+/// With `synthetic.cookie_prefix = "host"` the cookie is issued as
+/// `__Host-synthetic_id` without a `Domain` attribute, as the prefix requires.
+pub fn create_synthetic_cookie(settings: &Settings, synthetic_id: &SyntheticId) -> String {
+    let max_age = synthetic_id
+        .remaining_lifetime(settings, chrono::Utc::now().timestamp())
+        .min(i64::from(COOKIE_MAX_AGE));
+    let mut cookie = CookieBuilder::new(
+        synthetic_cookie_name(settings),
+        sign_synthetic_id(settings, synthetic_id),
+    );
+    if settings.synthetic.cookie_prefix != CookiePrefix::Host {
+        cookie = cookie.domain(&settings.publisher.cookie_domain);
+    }
+    cookie
+        .path("/")
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .max_age(max_age)
+        .build()
+}
+
+/// Creates the synthetic ID cookie for `synthetic_id` if the server can vouch
+/// for the ID (see [`SyntheticId::is_verified`]).
+///
+/// The signed cookie proves ownership of the ID to DSAR verification and
+/// objection withdrawal, so IDs claimed through the `X-Synthetic-Trusted-Server`
+/// header or an identity link are never minted into one.
+pub fn verified_synthetic_cookie(
+    settings: &Settings,
+    synthetic_id: &SyntheticId,
+) -> Option<String> {
+    synthetic_id
+        .is_verified()
+        .then(|| create_synthetic_cookie(settings, synthetic_id))
+}
+
 #[cfg(test)]
 mod tests {
     use crate::test_support::tests::create_test_settings;
@@ -150,16 +633,314 @@ mod tests {
         assert!(jar.iter().count() == 0);
     }
 
+    #[test]
+    fn test_sign_and_verify_value() {
+        let settings = create_test_settings();
+        let signed = sign_value(&settings, "experiment", "variant-b");
+
+        assert!(signed.starts_with("variant-b."));
+        assert_eq!(
+            verify_value(&settings, "experiment", &signed),
+            Some("variant-b")
+        );
+    }
+
+    #[test]
+    fn test_verify_value_rejects_tampering() {
+        let settings = create_test_settings();
+        let signed = sign_value(&settings, "experiment", "variant-b");
+
+        let tampered = signed.replacen("variant-b", "variant-a", 1);
+        assert_eq!(verify_value(&settings, "experiment", &tampered), None);
+        assert_eq!(verify_value(&settings, "other_cookie", &signed), None);
+        assert_eq!(verify_value(&settings, "experiment", "variant-b"), None);
+        assert_eq!(verify_value(&settings, "experiment", "variant-b.zz"), None);
+    }
+
+    #[test]
+    fn test_verify_value_with_other_key() {
+        let settings = create_test_settings();
+        let signed = sign_value(&settings, "experiment", "variant-b");
+
+        let mut rotated = create_test_settings();
+        rotated.synthetic.secret_key = "rotated-secret-key".to_string();
+        assert_eq!(verify_value(&rotated, "experiment", &signed), None);
+    }
+
+    #[test]
+    fn test_encrypt_and_decrypt_value() {
+        let settings = create_test_settings();
+        let encrypted = encrypt_value(&settings, "gdpr_consent", "{\"analytics\":true}");
+
+        assert!(!encrypted.contains("analytics"));
+        assert_ne!(
+            encrypted,
+            encrypt_value(&settings, "gdpr_consent", "{\"analytics\":true}"),
+            "each encryption should use a fresh nonce"
+        );
+        assert_eq!(
+            decrypt_value(&settings, "gdpr_consent", &encrypted).as_deref(),
+            Some("{\"analytics\":true}")
+        );
+    }
+
+    #[test]
+    fn test_decrypt_value_rejects_tampering() {
+        let settings = create_test_settings();
+        let encrypted = encrypt_value(&settings, "gdpr_consent", "payload");
+
+        let mut bytes = URL_SAFE_NO_PAD.decode(&encrypted).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let tampered = URL_SAFE_NO_PAD.encode(bytes);
+
+        assert_eq!(decrypt_value(&settings, "gdpr_consent", &tampered), None);
+        assert_eq!(decrypt_value(&settings, "other_cookie", &encrypted), None);
+        assert_eq!(decrypt_value(&settings, "gdpr_consent", "payload"), None);
+        assert_eq!(decrypt_value(&settings, "gdpr_consent", ""), None);
+    }
+
+    #[test]
+    fn test_response_cookies_appends_headers() {
+        let mut cookies = ResponseCookies::new();
+        assert!(cookies.is_empty());
+        cookies
+            .add(CookieBuilder::new("c1", "v1").path("/"))
+            .add("c2=v2; Path=/");
+
+        let mut response = Response::new();
+        cookies.apply(&mut response);
+
+        let set_cookies: Vec<_> = response
+            .get_header_all_str(header::SET_COOKIE)
+            .into_iter()
+            .collect();
+        assert_eq!(set_cookies, vec!["c1=v1; Path=/", "c2=v2; Path=/"]);
+    }
+
+    #[test]
+    fn test_response_cookies_replaces_same_name() {
+        let mut cookies = ResponseCookies::new();
+        cookies.add("c1=old").add("c2=v2").add("c1=new; Max-Age=0");
+
+        assert_eq!(
+            cookies.iter().collect::<Vec<_>>(),
+            vec!["c2=v2", "c1=new; Max-Age=0"]
+        );
+    }
+
+    #[test]
+    fn test_delete_cookie() {
+        let cookie = delete_cookie("c1", Some(".example.com"));
+        assert_eq!(
+            cookie,
+            "c1=; Domain=.example.com; Path=/; Expires=Thu, 01 Jan 1970 00:00:00 GMT; \
+             Secure; SameSite=Lax; Max-Age=0"
+        );
+
+        let cookie = delete_cookie("__Host-c1", None);
+        assert!(!cookie.contains("Domain="));
+        assert!(cookie.ends_with("Max-Age=0"));
+    }
+
+    #[test]
+    fn test_delete_synthetic_cookies() {
+        let settings = create_test_settings();
+        let cookies = delete_synthetic_cookies(&settings);
+
+        assert_eq!(cookies.len(), 3);
+        assert!(cookies[0].starts_with("__Host-synthetic_id=;"));
+        assert!(!cookies[0].contains("Domain="));
+        assert!(cookies[1].starts_with("__Secure-synthetic_id=;"));
+        assert!(cookies[2].starts_with("synthetic_id=;"));
+        assert!(cookies[2].contains(&format!("Domain={}", settings.publisher.cookie_domain)));
+    }
+
+    #[test]
+    fn test_is_cookie_allowed() {
+        let settings = create_test_settings();
+        let mut consent = TcfConsent::default();
+
+        assert!(!is_cookie_allowed(&settings, &consent, "synthetic_id"));
+        assert!(!is_cookie_allowed(
+            &settings,
+            &consent,
+            "__Host-synthetic_id"
+        ));
+        assert!(is_cookie_allowed(&settings, &consent, "gdpr_consent"));
+
+        consent.purpose_consents.insert(1);
+        assert!(is_cookie_allowed(
+            &settings,
+            &consent,
+            "__Host-synthetic_id"
+        ));
+    }
+
+    #[test]
+    fn test_filter_for_consent() {
+        let settings = create_test_settings();
+        let synthetic_id = SyntheticId::new("12345".to_string());
+        let mut cookies = ResponseCookies::new();
+        cookies
+            .add(create_synthetic_cookie(&settings, &synthetic_id))
+            .add("gdpr_consent=value; Path=/")
+            .add(delete_cookie("__Secure-synthetic_id", None));
+
+        let filtered = filter_for_consent(&settings, &TcfConsent::default(), &cookies);
+        let names: Vec<_> = filtered.iter().map(cookie_name).collect();
+        assert_eq!(names, vec!["gdpr_consent", "__Secure-synthetic_id"]);
+
+        let mut consent = TcfConsent::default();
+        consent.purpose_consents.insert(1);
+        let filtered = filter_for_consent(&settings, &consent, &cookies);
+        assert_eq!(filtered, cookies);
+    }
+
+    #[test]
+    fn test_cookie_builder_minimal() {
+        assert_eq!(CookieBuilder::new("c1", "v1").build(), "c1=v1");
+    }
+
+    #[test]
+    fn test_cookie_builder_all_attributes() {
+        let expires = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let cookie = CookieBuilder::new("c1", "v1")
+            .domain(".example.com")
+            .path("/app")
+            .expires(expires)
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .partitioned(true)
+            .max_age(3600)
+            .build();
+
+        assert_eq!(
+            cookie,
+            "c1=v1; Domain=.example.com; Path=/app; Expires=Tue, 14 Nov 2023 22:13:20 GMT; \
+             Secure; HttpOnly; SameSite=Strict; Partitioned; Max-Age=3600"
+        );
+    }
+
+    #[test]
+    fn test_cookie_builder_forces_secure() {
+        let cookie = CookieBuilder::new("c1", "v1")
+            .same_site(SameSite::None)
+            .build();
+        assert_eq!(cookie, "c1=v1; Secure; SameSite=None");
+
+        let cookie = CookieBuilder::new("c1", "v1").partitioned(true).build();
+        assert_eq!(cookie, "c1=v1; Secure; Partitioned");
+    }
+
     #[test]
     fn test_create_synthetic_cookie() {
         let settings = create_test_settings();
-        let result = create_synthetic_cookie(&settings, "12345");
+        let synthetic_id = SyntheticId::new("12345".to_string());
+        let result = create_synthetic_cookie(&settings, &synthetic_id);
         assert_eq!(
             result,
             format!(
-                "synthetic_id=12345; Domain={}; Path=/; Secure; SameSite=Lax; Max-Age={}",
-                settings.publisher.cookie_domain, COOKIE_MAX_AGE,
+                "synthetic_id={}; Domain={}; Path=/; Secure; SameSite=Lax; Max-Age={}",
+                sign_synthetic_id(&settings, &synthetic_id),
+                settings.publisher.cookie_domain,
+                COOKIE_MAX_AGE,
             )
         );
+        assert!(result.starts_with("synthetic_id=12345."));
+    }
+
+    #[test]
+    fn test_create_synthetic_cookie_with_host_prefix() {
+        let mut settings = create_test_settings();
+        settings.synthetic.cookie_prefix = CookiePrefix::Host;
+        let synthetic_id = SyntheticId::new("12345".to_string());
+
+        let result = create_synthetic_cookie(&settings, &synthetic_id);
+        assert!(result.starts_with("__Host-synthetic_id=12345."));
+        assert!(!result.contains("Domain="));
+        assert!(result.contains("; Path=/; Secure;"));
+    }
+
+    #[test]
+    fn test_create_synthetic_cookie_with_secure_prefix() {
+        let mut settings = create_test_settings();
+        settings.synthetic.cookie_prefix = CookiePrefix::Secure;
+        let synthetic_id = SyntheticId::new("12345".to_string());
+
+        let result = create_synthetic_cookie(&settings, &synthetic_id);
+        assert!(result.starts_with("__Secure-synthetic_id=12345."));
+        assert!(result.contains(&format!("Domain={}", settings.publisher.cookie_domain)));
+    }
+
+    #[test]
+    fn test_get_synthetic_cookie_reads_configured_prefix() {
+        let mut settings = create_test_settings();
+        let jar = parse_cookies_to_jar("synthetic_id=plain; __Host-synthetic_id=host");
+
+        assert_eq!(
+            get_synthetic_cookie(&settings, &jar)
+                .expect("should read the unprefixed cookie")
+                .value(),
+            "plain"
+        );
+        settings.synthetic.cookie_prefix = CookiePrefix::Host;
+        assert_eq!(
+            get_synthetic_cookie(&settings, &jar)
+                .expect("should read the __Host- cookie")
+                .value(),
+            "host"
+        );
+
+        // Cookies issued under another prefix are ignored...
+        settings.synthetic.cookie_prefix = CookiePrefix::Secure;
+        assert!(get_synthetic_cookie(&settings, &jar).is_none());
+        assert!(get_synthetic_cookie(&settings, &CookieJar::new()).is_none());
+
+        // ...unless still within the migration window
+        settings.synthetic.legacy_cookies_until = chrono::Utc::now().timestamp() + 60;
+        assert_eq!(
+            get_synthetic_cookie(&settings, &jar)
+                .expect("should read a legacy cookie during migration")
+                .value(),
+            "host"
+        );
+    }
+
+    #[test]
+    fn test_delete_legacy_synthetic_cookies() {
+        let mut settings = create_test_settings();
+        settings.synthetic.cookie_prefix = CookiePrefix::Host;
+        let jar = parse_cookies_to_jar("synthetic_id=plain; __Host-synthetic_id=host");
+
+        let deleted = delete_legacy_synthetic_cookies(&settings, &jar);
+        assert_eq!(deleted.len(), 1);
+        assert!(deleted[0].starts_with("synthetic_id=;"));
+        assert!(deleted[0].contains(&format!("Domain={}", settings.publisher.cookie_domain)));
+
+        settings.synthetic.cookie_prefix = CookiePrefix::None;
+        let deleted = delete_legacy_synthetic_cookies(&settings, &jar);
+        assert_eq!(deleted.len(), 1);
+        assert!(deleted[0].starts_with("__Host-synthetic_id=;"));
+        assert!(!deleted[0].contains("Domain="));
+    }
+
+    #[test]
+    fn test_create_synthetic_cookie_capped_by_remaining_lifetime() {
+        let settings = create_test_settings();
+        let now = chrono::Utc::now().timestamp();
+        let max_age = i64::from(settings.synthetic.max_age_days) * 24 * 60 * 60;
+        let synthetic_id = SyntheticId {
+            issued_at: now - max_age + 60,
+            ..SyntheticId::new("12345".to_string())
+        };
+
+        let result = create_synthetic_cookie(&settings, &synthetic_id);
+        let cookie_max_age: i64 = result
+            .rsplit_once("Max-Age=")
+            .and_then(|(_, v)| v.parse().ok())
+            .expect("should contain a numeric Max-Age");
+        assert!(cookie_max_age <= 60);
     }
 }
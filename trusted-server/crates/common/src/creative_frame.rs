@@ -0,0 +1,184 @@
+//! Sandboxed rendering of ad server creatives.
+//!
+//! Creative HTML returned by the ad server is never inlined into trusted
+//! server pages. [`CreativeFrameStore::put`] stores it under a random,
+//! one-time ID in `creative_frame.kv_store`, and pages load it SafeFrame
+//! style in an iframe from `GET /creative/<id>` ([`handle_creative`]):
+//!
+//! - the iframe is sandboxed with [`CREATIVE_SANDBOX`], which leaves out
+//!   `allow-same-origin`, so the creative runs in an opaque origin and
+//!   cannot reach the page, its cookies or storage
+//! - the creative response carries its own Content Security Policy with the
+//!   same `sandbox` and a `frame-ancestors` limited to the publisher, so it
+//!   stays isolated when opened directly or framed elsewhere
+//! - the ID is deleted on first fetch and expires after
+//!   `creative_frame.ttl_seconds` otherwise
+
+use std::time::Duration;
+
+use error_stack::Report;
+use fastly::http::{header, StatusCode};
+use fastly::{Error, Request, Response};
+use uuid::Uuid;
+
+use crate::error::TrustedServerError;
+use crate::kv_store::JsonKvStore;
+use crate::settings::Settings;
+
+/// Path prefix creatives are served from.
+pub const CREATIVE_PATH: &str = "/creative/";
+
+/// Sandbox of creative iframes and responses: scripts, forms and popups
+/// escaping the sandbox for clicks, but no access to the embedding origin.
+pub const CREATIVE_SANDBOX: &str =
+    "allow-scripts allow-forms allow-popups allow-popups-to-escape-sandbox";
+
+/// One-time store of creatives configured in `creative_frame`.
+pub struct CreativeFrameStore {
+    store: JsonKvStore,
+    ttl: Duration,
+}
+
+impl CreativeFrameStore {
+    /// Opens the KV store in `creative_frame.kv_store`.
+    ///
+    /// Returns [`None`] when sandboxed rendering is not configured.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the configured store cannot be opened
+    pub fn open(settings: &Settings) -> Result<Option<Self>, Report<TrustedServerError>> {
+        let config = &settings.creative_frame;
+        if config.kv_store.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            store: JsonKvStore::open(&config.kv_store)?,
+            ttl: Duration::from_secs(config.ttl_seconds.max(1)),
+        }))
+    }
+
+    fn key(id: &str) -> String {
+        format!("frame:{id}")
+    }
+
+    /// Stores creative `html`, returning the URL path it is served from.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the write fails
+    pub fn put(&self, html: &str) -> Result<String, Report<TrustedServerError>> {
+        let id = Uuid::new_v4().to_string();
+        self.store
+            .put_text_with_ttl(&Self::key(&id), html, self.ttl)?;
+        Ok(format!("{CREATIVE_PATH}{id}"))
+    }
+
+    /// Removes and returns the creative stored under `id`, or [`None`] if it
+    /// was already served, expired or never existed.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the lookup or delete fails
+    pub fn take(&self, id: &str) -> Result<Option<String>, Report<TrustedServerError>> {
+        let key = Self::key(id);
+        let html = self.store.get_text(&key)?;
+        if html.is_some() {
+            self.store.delete(&key)?;
+        }
+        Ok(html)
+    }
+}
+
+/// Content Security Policy of served creatives.
+fn creative_csp(settings: &Settings) -> String {
+    let domain = &settings.publisher.domain;
+    format!(
+        "sandbox {CREATIVE_SANDBOX}; frame-ancestors 'self' https://{domain} https://*.{domain}; \
+         base-uri 'none'"
+    )
+}
+
+/// Handles `GET /creative/<id>`, serving a stored creative once.
+///
+/// Responds `404` when sandboxed rendering is not configured or nothing is
+/// stored under the ID, and `400` when the ID is malformed.
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if the store cannot be read.
+pub fn handle_creative(settings: &Settings, req: Request) -> Result<Response, Error> {
+    let Some(store) =
+        CreativeFrameStore::open(settings).map_err(|e| Error::msg(format!("{e:?}")))?
+    else {
+        return Ok(Response::from_status(StatusCode::NOT_FOUND).with_body("Not Found"));
+    };
+    let Some(id) = req
+        .get_path()
+        .strip_prefix(CREATIVE_PATH)
+        .filter(|id| Uuid::parse_str(id).is_ok())
+    else {
+        return Ok(Response::from_status(StatusCode::BAD_REQUEST).with_body("Invalid creative ID"));
+    };
+
+    match store.take(id).map_err(|e| Error::msg(format!("{e:?}")))? {
+        Some(html) => Ok(Response::from_status(StatusCode::OK)
+            .with_header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .with_header(header::CACHE_CONTROL, "no-store, private")
+            .with_header(header::CONTENT_SECURITY_POLICY, creative_csp(settings))
+            .with_header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+            .with_body(html)),
+        None => Ok(Response::from_status(StatusCode::NOT_FOUND).with_body("Not Found")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::tests::create_test_settings;
+
+    fn frame_settings() -> Settings {
+        let mut settings = create_test_settings();
+        settings.creative_frame.kv_store = "test_creative_frame_store".to_string();
+        settings
+    }
+
+    #[test]
+    fn test_creative_served_once() {
+        let settings = frame_settings();
+        let store = CreativeFrameStore::open(&settings).unwrap().unwrap();
+        let html = r#"<div onclick="alert('x')">ad "quoted"</div>"#;
+        let path = store.put(html).unwrap();
+        assert!(path.starts_with(CREATIVE_PATH));
+
+        let url = format!("https://example.com{path}");
+        let mut resp = handle_creative(&settings, Request::get(&url)).unwrap();
+        assert_eq!(resp.get_status(), StatusCode::OK);
+        let csp = resp
+            .get_header_str(header::CONTENT_SECURITY_POLICY)
+            .unwrap()
+            .to_string();
+        assert!(csp.starts_with("sandbox allow-scripts "));
+        assert!(!csp.contains("allow-same-origin"));
+        assert!(csp.contains("frame-ancestors 'self' https://test-publisher.com"));
+        assert_eq!(resp.take_body_str(), html);
+
+        let resp = handle_creative(&settings, Request::get(&url)).unwrap();
+        assert_eq!(resp.get_status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_handle_creative_rejections() {
+        let settings = frame_settings();
+        let resp =
+            handle_creative(&settings, Request::get("https://example.com/creative/../x")).unwrap();
+        assert_eq!(resp.get_status(), StatusCode::BAD_REQUEST);
+
+        let unknown = format!("https://example.com/creative/{}", Uuid::new_v4());
+        let resp = handle_creative(&settings, Request::get(&unknown)).unwrap();
+        assert_eq!(resp.get_status(), StatusCode::NOT_FOUND);
+
+        let resp = handle_creative(&create_test_settings(), Request::get(&unknown)).unwrap();
+        assert_eq!(resp.get_status(), StatusCode::NOT_FOUND);
+    }
+}
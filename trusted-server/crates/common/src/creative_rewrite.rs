@@ -0,0 +1,226 @@
+//! Rewriting of winning creatives to first-party hosts.
+//!
+//! Creatives served from an SSP's CDN load from a third-party origin, which
+//! browsers and content blockers treat differently from the publisher's own
+//! content. `creative_rewrite.hosts` maps such CDN hosts to first-party hosts
+//! serving the same files, and [`rewrite_creative`] points every URL on a
+//! mapped host in the markup (`adm`) or creative URL of a winning bid, or in
+//! a GAM creative, to its first-party host instead.
+//!
+//! CDNs without a first-party host are listed in `creative_rewrite.proxies`:
+//! their URLs are rewritten to a path on the publisher domain, which
+//! [`handle_creative_proxy`] serves from the CDN's backend.
+
+use fastly::http::{header, StatusCode};
+use fastly::{Error, Request, Response};
+
+use crate::settings::{CreativeProxy, Settings};
+
+/// Whether `c` can continue a host name, so that `ads.example` does not match
+/// the start of `ads.example.net`.
+fn is_host_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_')
+}
+
+/// Replaces the lower case host `from` with `to` wherever it is the host of
+/// a URL in `text`, in any case: where it follows `//` (or `\/\/` in escaped
+/// JSON) and is not followed by more of a host name.
+fn replace_host(text: &str, from: &str, to: &str) -> String {
+    let mut rewritten = String::with_capacity(text.len());
+    let mut last = 0;
+    for (index, _) in text.to_ascii_lowercase().match_indices(from) {
+        let before = &text[..index];
+        let after = &text[index + from.len()..];
+        if (before.ends_with("//") || before.ends_with("\\/\\/"))
+            && !after.starts_with(is_host_char)
+        {
+            rewritten.push_str(&text[last..index]);
+            rewritten.push_str(to);
+            last = index + from.len();
+        }
+    }
+    rewritten.push_str(&text[last..]);
+    rewritten
+}
+
+/// Points the URLs of `creative` on a host in `creative_rewrite.hosts` to
+/// the mapped first-party host, and those on a host in
+/// `creative_rewrite.proxies` to its path on the publisher domain. Host names
+/// are matched case-insensitively.
+pub fn rewrite_creative(settings: &Settings, creative: &str) -> String {
+    let config = &settings.creative_rewrite;
+    let hosts = config
+        .hosts
+        .iter()
+        .filter(|mapping| !mapping.from.is_empty())
+        .map(|mapping| (mapping.from.to_ascii_lowercase(), mapping.to.clone()));
+    let proxies = config
+        .proxies
+        .iter()
+        .filter(|proxy| !proxy.host.is_empty())
+        .map(|proxy| {
+            let path = proxy.path.trim_end_matches('/');
+            (
+                proxy.host.to_ascii_lowercase(),
+                format!("{}{path}", settings.publisher.domain),
+            )
+        });
+    hosts
+        .chain(proxies)
+        .fold(creative.to_string(), |creative, (from, to)| {
+            replace_host(&creative, &from, &to)
+        })
+}
+
+/// The proxied CDN serving `path`, if any.
+pub fn creative_proxy<'a>(settings: &'a Settings, path: &str) -> Option<&'a CreativeProxy> {
+    settings.creative_rewrite.proxies.iter().find(|proxy| {
+        let prefix = proxy.path.trim_end_matches('/');
+        !prefix.is_empty()
+            && path
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.starts_with('/'))
+    })
+}
+
+/// Handles `GET` requests for a path of `creative_rewrite.proxies`, serving
+/// the file from the CDN without the visitor's cookies.
+///
+/// Responds `404` when no proxy serves the path.
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if the CDN cannot be reached.
+pub fn handle_creative_proxy(settings: &Settings, req: Request) -> Result<Response, Error> {
+    let Some(proxy) = creative_proxy(settings, req.get_path()) else {
+        return Ok(Response::from_status(StatusCode::NOT_FOUND).with_body("Not Found"));
+    };
+    let path = &req.get_path()[proxy.path.trim_end_matches('/').len()..];
+    let mut url = format!("https://{}{path}", proxy.host);
+    if let Some(query) = req.get_query_str() {
+        url.push('?');
+        url.push_str(query);
+    }
+    log::debug!("Proxying creative file {url}");
+
+    let mut cdn_req = Request::get(url);
+    for name in [header::ACCEPT, header::ACCEPT_ENCODING, header::USER_AGENT] {
+        if let Some(value) = req.get_header(&name) {
+            cdn_req.set_header(name, value.clone());
+        }
+    }
+    let mut res = cdn_req.send(proxy.backend.as_str())?;
+    res.remove_header(header::SET_COOKIE);
+    // Creatives run in opaque-origin frames, which need CORS for fonts and
+    // fetches
+    res.set_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*");
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::{CreativeProxy, HostMapping};
+    use crate::test_support::tests::create_test_settings;
+
+    fn settings() -> Settings {
+        let mut settings = create_test_settings();
+        settings.creative_rewrite.hosts = vec![HostMapping {
+            from: "creatives.sascdn.com".to_string(),
+            to: "creatives.auburndao.com".to_string(),
+        }];
+        settings
+    }
+
+    #[test]
+    fn test_rewrite_creative() {
+        let settings = settings();
+        let cases = [
+            (
+                r#"<img src="https://creatives.sascdn.com/diff/12345/banner.jpg">"#,
+                r#"<img src="https://creatives.auburndao.com/diff/12345/banner.jpg">"#,
+            ),
+            (
+                "<script src='//Creatives.SASCDN.com/lib.js'></script>",
+                "<script src='//creatives.auburndao.com/lib.js'></script>",
+            ),
+            (
+                r#"{"img":{"url":"https:\/\/creatives.sascdn.com\/native.png"}}"#,
+                r#"{"img":{"url":"https:\/\/creatives.auburndao.com\/native.png"}}"#,
+            ),
+            (
+                "https://creatives.sascdn.com:443/a.jpg https://creatives.sascdn.com",
+                "https://creatives.auburndao.com:443/a.jpg https://creatives.auburndao.com",
+            ),
+            // Other hosts and mentions outside of URLs are left alone
+            (
+                "https://creatives.sascdn.com.evil.example/a.jpg",
+                "https://creatives.sascdn.com.evil.example/a.jpg",
+            ),
+            (
+                "https://cdn.example/?ref=creatives.sascdn.com",
+                "https://cdn.example/?ref=creatives.sascdn.com",
+            ),
+        ];
+        for (creative, expected) in cases {
+            assert_eq!(
+                rewrite_creative(&settings, creative),
+                expected,
+                "{creative}"
+            );
+        }
+    }
+
+    fn proxy_settings() -> Settings {
+        let mut settings = create_test_settings();
+        settings.creative_rewrite.proxies = vec![CreativeProxy {
+            host: "tpc.googlesyndication.com".to_string(),
+            path: "/creative-cdn/tpc/".to_string(),
+            backend: "tpc_googlesyndication".to_string(),
+        }];
+        settings
+    }
+
+    #[test]
+    fn test_rewrite_creative_to_proxy() {
+        let settings = proxy_settings();
+        let creative = concat!(
+            r#"<script src="https://tpc.googlesyndication.com/sodar/sodar2.js"></script>"#,
+            r#"<img src="//TPC.googlesyndication.com/simgad/123">"#,
+            r#"<a href="https://tpc.googlesyndication.com.evil.example/">"#,
+        );
+        assert_eq!(
+            rewrite_creative(&settings, creative),
+            concat!(
+                r#"<script src="https://test-publisher.com/creative-cdn/tpc/sodar/sodar2.js"></script>"#,
+                r#"<img src="//test-publisher.com/creative-cdn/tpc/simgad/123">"#,
+                r#"<a href="https://tpc.googlesyndication.com.evil.example/">"#,
+            )
+        );
+    }
+
+    #[test]
+    fn test_creative_proxy() {
+        let settings = proxy_settings();
+        let proxy = creative_proxy(&settings, "/creative-cdn/tpc/simgad/123").unwrap();
+        assert_eq!(proxy.backend, "tpc_googlesyndication");
+        assert!(creative_proxy(&settings, "/creative-cdn/tpc").is_none());
+        assert!(creative_proxy(&settings, "/creative-cdn/tpcx/a.js").is_none());
+
+        let resp = handle_creative_proxy(
+            &settings,
+            Request::get("https://example.com/creative-cdn/x"),
+        )
+        .unwrap();
+        assert_eq!(resp.get_status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_rewrite_creative_without_mappings() {
+        let creative = r#"<img src="https://creatives.sascdn.com/banner.jpg">"#;
+        assert_eq!(
+            rewrite_creative(&create_test_settings(), creative),
+            creative
+        );
+    }
+}
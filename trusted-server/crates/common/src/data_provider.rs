@@ -0,0 +1,378 @@
+//! Concurrent audience segment lookups.
+//!
+//! Each [`DataProvider`] looks up the segments it holds for a visitor
+//! asynchronously. A [`DataProviderManager`] fans out to all providers the
+//! request allows (Permutive, the DMPs of [`crate::dmp`] and the publisher's
+//! `data_providers`), waits for each until its own timeout and for all of
+//! them within `audience.budget_ms`, and records how long each one took. A
+//! provider that fails or answers late only loses its own segments.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use fastly::http::request::{PendingRequest, PollResult, SendError};
+use fastly::Response;
+use futures::future::poll_fn;
+
+use crate::consent::ConsentDecision;
+use crate::deadline::{Deadline, POLL_INTERVAL};
+use crate::dmp;
+use crate::first_party_data::{FirstPartyData, SegmentGroup, KEY_SEGMENTS};
+use crate::permutive::PermutiveProvider;
+use crate::settings::Settings;
+
+/// Segment lookup of a [`DataProvider`].
+pub type SegmentsFuture<'a> = Pin<Box<dyn Future<Output = Vec<String>> + 'a>>;
+
+/// Source of audience segments, sent to GAM in `cust_params` under its key
+/// and to bidders in `user.data`.
+pub trait DataProvider {
+    /// `cust_params` key of the segments, e.g. `permutive`
+    fn key(&self) -> &str;
+
+    /// `user.data` name of the segments in bid requests, the key by default.
+    fn data_name(&self) -> &str {
+        self.key()
+    }
+
+    /// Longest wait for the segments, within the manager's budget.
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Segment IDs of the visitor with `synthetic_id`. Failures are logged
+    /// by the provider and give no segments.
+    fn segments<'a>(&'a self, synthetic_id: &'a str) -> SegmentsFuture<'a>;
+}
+
+/// Segments already known for the visitor, such as those captured on the
+/// page by a data provider's tag.
+pub struct StaticSegments {
+    key: String,
+    segments: Vec<String>,
+}
+
+impl StaticSegments {
+    /// Segments under `key`, from a comma-separated list.
+    pub fn new(key: impl Into<String>, segments: &str) -> Self {
+        Self {
+            key: key.into(),
+            segments: segments
+                .split(',')
+                .map(str::trim)
+                .filter(|segment| !segment.is_empty())
+                .map(str::to_string)
+                .collect(),
+        }
+    }
+}
+
+impl DataProvider for StaticSegments {
+    fn key(&self) -> &str {
+        &self.key
+    }
+
+    fn segments<'a>(&'a self, _synthetic_id: &'a str) -> SegmentsFuture<'a> {
+        Box::pin(std::future::ready(self.segments.clone()))
+    }
+}
+
+/// The publisher's own segments, under [`KEY_SEGMENTS`].
+impl DataProvider for FirstPartyData {
+    fn key(&self) -> &str {
+        KEY_SEGMENTS
+    }
+
+    fn segments<'a>(&'a self, _synthetic_id: &'a str) -> SegmentsFuture<'a> {
+        let segments = self
+            .segments
+            .iter()
+            .flat_map(|group| group.ids.iter().cloned())
+            .collect();
+        Box::pin(std::future::ready(segments))
+    }
+}
+
+/// Response of a request sent with [`fastly::Request::send_async`], for
+/// lookups awaiting it alongside others.
+pub fn response(request: PendingRequest) -> impl Future<Output = Result<Response, SendError>> {
+    let mut request = Some(request);
+    poll_fn(move |cx| {
+        let Some(pending) = request.take() else {
+            panic!("response polled after completion");
+        };
+        match pending.poll() {
+            PollResult::Done(result) => Poll::Ready(result),
+            PollResult::Pending(pending) => {
+                request = Some(pending);
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    })
+}
+
+/// Outcome of one provider's lookup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProviderResult {
+    /// `cust_params` key of the provider
+    pub key: String,
+    /// `user.data` name of the provider
+    pub data_name: String,
+    /// Segment IDs found, none if the lookup failed or timed out
+    pub segments: Vec<String>,
+    /// Time from the fan-out until the lookup finished or was abandoned
+    pub latency: Duration,
+    /// Whether the lookup was abandoned at its timeout
+    pub timed_out: bool,
+}
+
+/// Segments of all providers of a request, in the order the providers were
+/// added.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AudienceSegments {
+    /// Outcome of each provider's lookup
+    pub results: Vec<ProviderResult>,
+}
+
+impl AudienceSegments {
+    /// Segments of the providers that found any, under their key.
+    pub fn by_key(&self) -> impl Iterator<Item = (&str, &[String])> {
+        self.results
+            .iter()
+            .filter(|result| !result.segments.is_empty())
+            .map(|result| (result.key.as_str(), result.segments.as_slice()))
+    }
+
+    /// All segments as `<key>:<segment>`, without duplicates.
+    pub fn merged(&self) -> Vec<String> {
+        let mut merged = Vec::new();
+        for (key, segments) in self.by_key() {
+            for segment in segments {
+                let segment = format!("{key}:{segment}");
+                if !merged.contains(&segment) {
+                    merged.push(segment);
+                }
+            }
+        }
+        merged
+    }
+
+    /// The segments for the `user.data` of bid requests.
+    pub fn segment_groups(&self) -> Vec<SegmentGroup> {
+        self.results
+            .iter()
+            .filter(|result| !result.segments.is_empty())
+            .map(|result| SegmentGroup {
+                provider: result.data_name.clone(),
+                segtax: None,
+                ids: result.segments.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Fans segment lookups out to several providers.
+pub struct DataProviderManager<'a> {
+    providers: Vec<Box<dyn DataProvider + 'a>>,
+    budget: Duration,
+}
+
+impl<'a> DataProviderManager<'a> {
+    /// Manager without providers, waiting at most `budget` for all lookups.
+    pub fn new(budget: Duration) -> Self {
+        Self {
+            providers: Vec::new(),
+            budget,
+        }
+    }
+
+    /// Manager of the providers `settings` configure that the request
+    /// allows, none without consent to personalized ads.
+    pub fn from_settings(settings: &'a Settings, consent: &ConsentDecision) -> Self {
+        let mut manager = Self::new(Duration::from_millis(settings.audience.budget_ms));
+        if !consent.is_personalized() {
+            return manager;
+        }
+        if let Some(permutive) = PermutiveProvider::for_request(settings, consent) {
+            manager = manager.with_provider(Box::new(permutive));
+        }
+        for provider in dmp::providers(settings, consent) {
+            manager = manager.with_provider(Box::new(provider));
+        }
+        manager
+    }
+
+    /// Adds `provider`.
+    pub fn with_provider(mut self, provider: Box<dyn DataProvider + 'a>) -> Self {
+        self.providers.push(provider);
+        self
+    }
+
+    /// Shortens the budget so that the lookups end by `deadline`.
+    pub fn within(mut self, deadline: Deadline) -> Self {
+        self.budget = self.budget.min(deadline.remaining());
+        self
+    }
+
+    /// Whether no provider was added.
+    pub fn is_empty(&self) -> bool {
+        self.providers.is_empty()
+    }
+
+    /// Segments of the visitor with `synthetic_id` from all providers.
+    ///
+    /// The lookups run concurrently, each until its provider's timeout and
+    /// all within the budget; lookups still running then are abandoned and
+    /// give no segments. There is no lookup without a synthetic ID.
+    pub async fn collect(&self, synthetic_id: &str) -> AudienceSegments {
+        if synthetic_id.is_empty() || synthetic_id == "unknown" || self.providers.is_empty() {
+            return AudienceSegments::default();
+        }
+        let start = Instant::now();
+        let budget = Deadline::new(start, self.budget);
+        let mut pending: Vec<_> = self
+            .providers
+            .iter()
+            .enumerate()
+            .map(|(index, provider)| {
+                let deadline = provider
+                    .timeout()
+                    .map_or(budget, |timeout| budget.within(timeout));
+                (index, provider.segments(synthetic_id), deadline)
+            })
+            .collect();
+        let mut results: Vec<Option<ProviderResult>> = vec![None; self.providers.len()];
+        let result = |index: usize, segments, timed_out| {
+            let provider = &self.providers[index];
+            ProviderResult {
+                key: provider.key().to_string(),
+                data_name: provider.data_name().to_string(),
+                segments,
+                latency: start.elapsed(),
+                timed_out,
+            }
+        };
+
+        poll_fn(|cx| {
+            let mut still_pending = Vec::new();
+            for (index, mut lookup, deadline) in pending.drain(..) {
+                match lookup.as_mut().poll(cx) {
+                    Poll::Ready(segments) => results[index] = Some(result(index, segments, false)),
+                    Poll::Pending if deadline.is_expired() => {
+                        results[index] = Some(result(index, Vec::new(), true));
+                    }
+                    Poll::Pending => still_pending.push((index, lookup, deadline)),
+                }
+            }
+            pending = still_pending;
+            if pending.is_empty() {
+                return Poll::Ready(());
+            }
+            // Fastly requests can't wake the task, so pending lookups are
+            // polled again after a pause, like `wait_until` does
+            std::thread::sleep(POLL_INTERVAL);
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+        .await;
+
+        let results: Vec<ProviderResult> = results.into_iter().flatten().collect();
+        for result in &results {
+            log::info!(
+                "Data provider {}: {} segments in {:?}{}",
+                result.key,
+                result.segments.len(),
+                result.latency,
+                if result.timed_out { ", timed out" } else { "" }
+            );
+        }
+        AudienceSegments { results }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consent::PersonalizationLevel;
+    use crate::test_support::tests::create_test_settings;
+
+    /// Provider whose lookup never finishes.
+    struct Hanging;
+
+    impl DataProvider for Hanging {
+        fn key(&self) -> &str {
+            "hanging"
+        }
+
+        fn timeout(&self) -> Option<Duration> {
+            Some(Duration::from_millis(5))
+        }
+
+        fn segments<'a>(&'a self, _synthetic_id: &'a str) -> SegmentsFuture<'a> {
+            Box::pin(std::future::pending())
+        }
+    }
+
+    #[test]
+    fn test_collect() {
+        let manager = DataProviderManager::new(Duration::from_secs(1))
+            .with_provider(Box::new(StaticSegments::new("permutive", "129627, 137412")))
+            .with_provider(Box::new(Hanging))
+            .with_provider(Box::new(StaticSegments::new("lotame", "99123, 129627")));
+        let segments = futures::executor::block_on(manager.collect("synthetic-1"));
+
+        let keys: Vec<(&str, bool)> = segments
+            .results
+            .iter()
+            .map(|result| (result.key.as_str(), result.timed_out))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![("permutive", false), ("hanging", true), ("lotame", false)]
+        );
+        assert!(segments.results[1].latency >= Duration::from_millis(5));
+        assert_eq!(
+            segments.merged(),
+            vec![
+                "permutive:129627",
+                "permutive:137412",
+                "lotame:99123",
+                "lotame:129627"
+            ]
+        );
+        assert_eq!(segments.segment_groups().len(), 2);
+        assert_eq!(segments.by_key().count(), 2);
+
+        let unknown = futures::executor::block_on(manager.collect("unknown"));
+        assert!(unknown.results.is_empty());
+    }
+
+    #[test]
+    fn test_budget() {
+        let manager = DataProviderManager::new(Duration::ZERO).with_provider(Box::new(Hanging));
+        let segments = futures::executor::block_on(manager.collect("synthetic-1"));
+        assert!(segments.results[0].timed_out);
+        assert!(segments.results[0].latency < Duration::from_millis(5));
+    }
+
+    #[test]
+    fn test_from_settings() {
+        let mut settings = create_test_settings();
+        settings.permutive.api_url = "https://api.permutive.test/segments".to_string();
+        settings.lotame.api_url = "https://ad.crwdcntrl.net/5/pe=y".to_string();
+        let mut consent =
+            ConsentDecision::from_request(&settings, &fastly::Request::get("https://test.com"));
+        consent.personalization = PersonalizationLevel::Personalized;
+        // Outside GDPR, providers need no TCF vendor consent
+        consent.signals.tcf.gdpr_applies = false;
+        let manager = DataProviderManager::from_settings(&settings, &consent);
+        let keys: Vec<&str> = manager.providers.iter().map(|p| p.key()).collect();
+        assert_eq!(keys, vec!["permutive", "lotame"]);
+
+        consent.personalization = PersonalizationLevel::NonPersonalized;
+        assert!(DataProviderManager::from_settings(&settings, &consent).is_empty());
+    }
+}
@@ -0,0 +1,106 @@
+//! Time budget of an auction.
+//!
+//! An auction has `prebid.auction_timeout_ms` from the arrival of the
+//! request, including the consent and identity work done before the bid
+//! requests go out. [`Deadline`] tracks what is left of that budget, which
+//! is sent to bidders as `tmax`, and [`wait_until`] collects the responses
+//! of the fanned out bid requests that arrive before their deadline, so the
+//! auction closes with partial results instead of waiting on the slowest
+//! endpoint.
+
+use std::time::{Duration, Instant};
+
+use fastly::http::request::SendError;
+use fastly::http::request::{PendingRequest, PollResult};
+use fastly::Response;
+
+/// Pause between two polls of the pending requests.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+/// Point in time by which a piece of work has to finish.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    /// Deadline `budget` after `start`.
+    pub fn new(start: Instant, budget: Duration) -> Self {
+        Self(start + budget)
+    }
+
+    /// Time left until the deadline, zero once it passed.
+    pub fn remaining(&self) -> Duration {
+        self.0.saturating_duration_since(Instant::now())
+    }
+
+    /// Whether the deadline passed.
+    pub fn is_expired(&self) -> bool {
+        self.remaining().is_zero()
+    }
+
+    /// This deadline, brought forward by `margin`.
+    pub fn minus(self, margin: Duration) -> Self {
+        Self(self.0.checked_sub(margin).unwrap_or(self.0))
+    }
+
+    /// The earlier of this deadline and the one `timeout` from now.
+    pub fn within(self, timeout: Duration) -> Self {
+        self.min(Self::new(Instant::now(), timeout))
+    }
+}
+
+/// Waits for `pending` requests until each one's deadline, returning the
+/// results that arrived in time in the order they arrived.
+///
+/// Requests still pending at their deadline are abandoned and logged under
+/// their name.
+pub fn wait_until<T: std::fmt::Display>(
+    pending: Vec<(T, PendingRequest, Deadline)>,
+) -> Vec<(T, Result<Response, SendError>)> {
+    let mut pending = pending;
+    let mut results = Vec::new();
+    while !pending.is_empty() {
+        let mut still_pending = Vec::new();
+        for (name, request, deadline) in pending {
+            match request.poll() {
+                PollResult::Done(result) => results.push((name, result)),
+                PollResult::Pending(_) if deadline.is_expired() => {
+                    log::warn!("{} timed out", name);
+                }
+                PollResult::Pending(request) => still_pending.push((name, request, deadline)),
+            }
+        }
+        pending = still_pending;
+        if !pending.is_empty() {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deadline() {
+        let start = Instant::now();
+        let deadline = Deadline::new(start, Duration::from_secs(10));
+        assert!(!deadline.is_expired());
+        assert!(deadline.remaining() <= Duration::from_secs(10));
+        assert!(deadline.remaining() > Duration::from_secs(9));
+
+        let earlier = deadline.minus(Duration::from_secs(4));
+        assert_eq!(earlier, Deadline::new(start, Duration::from_secs(6)));
+        assert_eq!(deadline.within(Duration::from_secs(60)), deadline);
+        assert!(deadline.within(Duration::from_secs(1)) < earlier);
+
+        let expired = Deadline::new(start, Duration::ZERO);
+        assert!(expired.is_expired());
+        assert_eq!(expired.remaining(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_wait_until_nothing_pending() {
+        assert!(wait_until::<&str>(Vec::new()).is_empty());
+    }
+}
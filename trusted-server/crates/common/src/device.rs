@@ -0,0 +1,402 @@
+//! OpenRTB `device` object of bid requests.
+//!
+//! [`device`] describes the visitor's device from the `User-Agent`, the UA
+//! Client Hints (`Sec-CH-UA-*`, as the structured user agent `sua`),
+//! `Accept-Language`, `DNT`, the client IP and its Fastly geolocation.
+//! Without consent to personalized advertising the IP is truncated to its /24
+//! (IPv4) or /56 (IPv6) network, and coordinates and postal code are only sent
+//! with consent to precise geolocation.
+//!
+//! [`viewport_width`] reads the width of the visitor's viewport from the
+//! client hints pages ask for with [`ACCEPT_CH`], so that ad requests leave
+//! out sizes the screen cannot show.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use fastly::geo::geo_lookup;
+use fastly::http::header;
+use fastly::Request;
+use serde_json::{json, Map, Value};
+
+use crate::consent::ConsentDecision;
+use crate::constants::HEADER_DNT;
+
+/// IPv4 prefix kept when the client IP is truncated.
+const IPV4_TRUNCATED_PREFIX: u32 = 24;
+
+/// IPv6 prefix kept when the client IP is truncated.
+const IPV6_TRUNCATED_PREFIX: u32 = 56;
+
+/// OpenRTB `devicetype` of a desktop or laptop.
+const DEVICE_TYPE_PC: u8 = 2;
+/// OpenRTB `devicetype` of a phone.
+const DEVICE_TYPE_PHONE: u8 = 4;
+/// OpenRTB `devicetype` of a tablet.
+const DEVICE_TYPE_TABLET: u8 = 5;
+
+/// OpenRTB `sua.source` of low-entropy client hints.
+const SUA_SOURCE_LOW_ENTROPY: u8 = 1;
+/// OpenRTB `sua.source` of high-entropy client hints.
+const SUA_SOURCE_HIGH_ENTROPY: u8 = 2;
+
+/// `Accept-CH` value of pages, asking browsers for the client hints
+/// [`viewport_width`] reads.
+pub const ACCEPT_CH: &str = "Sec-CH-Viewport-Width, Viewport-Width, Sec-CH-UA-Mobile";
+
+/// Widest viewport assumed for phones not sending their viewport width.
+const PHONE_VIEWPORT_WIDTH: u32 = 480;
+
+/// OpenRTB `geo.type` of a location derived from the IP address.
+const GEO_TYPE_IP: u8 = 2;
+
+/// Zeroes all but the leading /24 (IPv4) or /56 (IPv6) bits of `ip`.
+pub fn truncate_client_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let mask = u32::MAX << (32 - IPV4_TRUNCATED_PREFIX);
+            IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+        }
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX << (128 - IPV6_TRUNCATED_PREFIX);
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+        }
+    }
+}
+
+/// Splits `value` on `separator` outside double quotes.
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (index, c) in value.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c == separator && !in_quotes => {
+                parts.push(value[start..index].trim());
+                start = index + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(value[start..].trim());
+    parts
+}
+
+/// Value of a structured header string (`"Windows"`), without its quotes.
+fn unquote(value: &str) -> &str {
+    let value = value.trim();
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+/// Parses a `Sec-CH-UA` style brand list (`"Chromium";v="124", ...`) into
+/// `(brand, version)` pairs.
+fn parse_brand_list(value: &str) -> Vec<(String, String)> {
+    split_unquoted(value, ',')
+        .into_iter()
+        .filter_map(|item| {
+            let mut params = split_unquoted(item, ';').into_iter();
+            let brand = unquote(params.next()?);
+            let version = params
+                .find_map(|param| param.strip_prefix("v="))
+                .map(unquote)?;
+            (!brand.is_empty()).then(|| (brand.to_string(), version.to_string()))
+        })
+        .collect()
+}
+
+/// OpenRTB `BrandVersion` object.
+fn brand_version(brand: &str, version: &str) -> Value {
+    let version: Vec<&str> = version.split('.').filter(|v| !v.is_empty()).collect();
+    json!({ "brand": brand, "version": version })
+}
+
+/// Structured user agent (`sua`) from the UA Client Hints of `req`, or
+/// [`None`] if the browser sent none.
+fn structured_user_agent(req: &Request) -> Option<Value> {
+    let full_version_list = req.get_header_str("sec-ch-ua-full-version-list");
+    let brands = full_version_list.or_else(|| req.get_header_str("sec-ch-ua"))?;
+    let high_entropy = |name| req.get_header_str(name).map(unquote);
+
+    let mut sua = Map::new();
+    let browsers: Vec<Value> = parse_brand_list(brands)
+        .iter()
+        .map(|(brand, version)| brand_version(brand, version))
+        .collect();
+    sua.insert("browsers".to_string(), json!(browsers));
+    if let Some(platform) = req.get_header_str("sec-ch-ua-platform").map(unquote) {
+        let version = high_entropy("sec-ch-ua-platform-version").unwrap_or_default();
+        sua.insert("platform".to_string(), brand_version(platform, version));
+    }
+    if let Some(mobile) = req.get_header_str("sec-ch-ua-mobile") {
+        sua.insert("mobile".to_string(), json!(u8::from(mobile.trim() == "?1")));
+    }
+    for (key, name) in [
+        ("architecture", "sec-ch-ua-arch"),
+        ("bitness", "sec-ch-ua-bitness"),
+        ("model", "sec-ch-ua-model"),
+    ] {
+        if let Some(value) = high_entropy(name).filter(|v| !v.is_empty()) {
+            sua.insert(key.to_string(), json!(value));
+        }
+    }
+
+    let is_high_entropy = full_version_list.is_some()
+        || sua.contains_key("architecture")
+        || sua.contains_key("bitness")
+        || sua.contains_key("model")
+        || req.get_header_str("sec-ch-ua-platform-version").is_some();
+    let source = if is_high_entropy {
+        SUA_SOURCE_HIGH_ENTROPY
+    } else {
+        SUA_SOURCE_LOW_ENTROPY
+    };
+    sua.insert("source".to_string(), json!(source));
+    Some(Value::Object(sua))
+}
+
+/// OpenRTB `devicetype` from the mobile client hint or the user agent.
+fn device_type(req: &Request, user_agent: &str) -> Option<u8> {
+    if req.get_header_str("sec-ch-ua-mobile").map(str::trim) == Some("?1") {
+        return Some(DEVICE_TYPE_PHONE);
+    }
+    if user_agent.is_empty() {
+        return None;
+    }
+    let device_type = if user_agent.contains("iPad") || user_agent.contains("Tablet") {
+        DEVICE_TYPE_TABLET
+    } else if user_agent.contains("Mobi") {
+        DEVICE_TYPE_PHONE
+    } else if user_agent.contains("Android") {
+        // Android tablets leave "Mobile" out of their user agent
+        DEVICE_TYPE_TABLET
+    } else {
+        DEVICE_TYPE_PC
+    };
+    Some(device_type)
+}
+
+/// Width of the visitor's viewport in CSS pixels, from the
+/// `Sec-CH-Viewport-Width` client hint or its legacy `Viewport-Width` form,
+/// or else at most [`PHONE_VIEWPORT_WIDTH`] on phones. Unknown on other
+/// devices without the hint.
+pub fn viewport_width(req: &Request) -> Option<u32> {
+    let hinted = ["sec-ch-viewport-width", "viewport-width"]
+        .into_iter()
+        .find_map(|name| req.get_header_str(name)?.trim().parse::<u32>().ok())
+        .filter(|width| *width > 0);
+    hinted.or_else(|| {
+        let user_agent = req.get_header_str(header::USER_AGENT).unwrap_or_default();
+        (device_type(req, user_agent) == Some(DEVICE_TYPE_PHONE)).then_some(PHONE_VIEWPORT_WIDTH)
+    })
+}
+
+/// ISO 639-1 code of the preferred language in `Accept-Language`.
+fn language(req: &Request) -> Option<String> {
+    let preferred = req
+        .get_header_str(header::ACCEPT_LANGUAGE)?
+        .split(',')
+        .next()?
+        .split(';')
+        .next()?
+        .trim();
+    let code = preferred.split('-').next()?.to_ascii_lowercase();
+    (code.len() == 2 && code.chars().all(|c| c.is_ascii_alphabetic())).then_some(code)
+}
+
+/// OpenRTB `geo` object from the Fastly geolocation of `ip`.
+fn geo(ip: IpAddr, consent: &ConsentDecision) -> Option<Value> {
+    let location = geo_lookup(ip)?;
+    let mut geo = Map::new();
+    geo.insert("type".to_string(), json!(GEO_TYPE_IP));
+    let fields = [
+        ("country", Some(location.country_code3())),
+        ("region", location.region()),
+        ("city", Some(location.city())),
+    ];
+    for (key, value) in fields {
+        if let Some(value) = value.filter(|v| !v.is_empty() && *v != "?") {
+            geo.insert(key.to_string(), json!(value));
+        }
+    }
+    if location.metro_code() > 0 {
+        geo.insert(
+            "metro".to_string(),
+            json!(location.metro_code().to_string()),
+        );
+    }
+    if let Some(offset) = location.utc_offset() {
+        geo.insert("utcoffset".to_string(), json!(offset.whole_minutes()));
+    }
+    if consent.precise_geolocation_allowed {
+        geo.insert("lat".to_string(), json!(location.latitude()));
+        geo.insert("lon".to_string(), json!(location.longitude()));
+        if !location.postal_code().is_empty() {
+            geo.insert("zip".to_string(), json!(location.postal_code()));
+        }
+    }
+    Some(Value::Object(geo))
+}
+
+/// The IP sent to bidders for `client_ip`: truncated unless ads may be personalized.
+pub fn bid_request_ip(client_ip: IpAddr, consent: &ConsentDecision) -> IpAddr {
+    if consent.is_personalized() {
+        client_ip
+    } else {
+        truncate_client_ip(client_ip)
+    }
+}
+
+/// Builds the OpenRTB `device` object for a bid request made on behalf of `req`.
+pub fn device(req: &Request, client_ip: Option<IpAddr>, consent: &ConsentDecision) -> Value {
+    let user_agent = req.get_header_str(header::USER_AGENT).unwrap_or_default();
+    let mut device = Map::new();
+    if !user_agent.is_empty() {
+        device.insert("ua".to_string(), json!(user_agent));
+    }
+    if let Some(sua) = structured_user_agent(req) {
+        device.insert("sua".to_string(), sua);
+    }
+    if let Some(device_type) = device_type(req, user_agent) {
+        device.insert("devicetype".to_string(), json!(device_type));
+    }
+    if let Some(language) = language(req) {
+        device.insert("language".to_string(), json!(language));
+    }
+    if req.get_header_str(HEADER_DNT).map(str::trim) == Some("1") {
+        device.insert("dnt".to_string(), json!(1));
+    }
+    if let Some(ip) = client_ip {
+        match bid_request_ip(ip, consent) {
+            IpAddr::V4(v4) => device.insert("ip".to_string(), json!(v4.to_string())),
+            IpAddr::V6(v6) => device.insert("ipv6".to_string(), json!(v6.to_string())),
+        };
+        if let Some(geo) = geo(ip, consent) {
+            device.insert("geo".to_string(), geo);
+        }
+    }
+    Value::Object(device)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consent::PersonalizationLevel;
+    use crate::test_support::tests::create_test_settings;
+
+    const CHROME_UA: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+                             (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36";
+
+    fn consent(personalized: bool) -> ConsentDecision {
+        let settings = create_test_settings();
+        let mut consent =
+            ConsentDecision::from_request(&settings, &Request::get("https://example.com"));
+        if personalized {
+            consent.personalization = PersonalizationLevel::Personalized;
+        }
+        consent
+    }
+
+    #[test]
+    fn test_truncate_client_ip() {
+        let cases = [
+            ("203.0.113.77", "203.0.113.0"),
+            ("2001:db8:1234:5678:9abc::1", "2001:db8:1234:5600::"),
+        ];
+        for (ip, truncated) in cases {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert_eq!(truncate_client_ip(ip).to_string(), truncated);
+            assert_eq!(bid_request_ip(ip, &consent(true)), ip);
+            assert_eq!(bid_request_ip(ip, &consent(false)).to_string(), truncated);
+        }
+    }
+
+    #[test]
+    fn test_parse_brand_list() {
+        let brands = parse_brand_list(
+            r#""Chromium";v="124", "Not;A=Brand";v="99", "Google Chrome";v="124""#,
+        );
+        assert_eq!(
+            brands,
+            vec![
+                ("Chromium".to_string(), "124".to_string()),
+                ("Not;A=Brand".to_string(), "99".to_string()),
+                ("Google Chrome".to_string(), "124".to_string()),
+            ]
+        );
+        assert!(parse_brand_list("garbage").is_empty());
+    }
+
+    #[test]
+    fn test_device_with_client_hints() {
+        let req = Request::get("https://example.com/auction")
+            .with_header(header::USER_AGENT, CHROME_UA)
+            .with_header(
+                "sec-ch-ua-full-version-list",
+                r#""Chromium";v="124.0.6367.91", "Google Chrome";v="124.0.6367.91""#,
+            )
+            .with_header("sec-ch-ua-platform", r#""Windows""#)
+            .with_header("sec-ch-ua-platform-version", r#""15.0.0""#)
+            .with_header("sec-ch-ua-mobile", "?0")
+            .with_header("sec-ch-ua-arch", r#""x86""#)
+            .with_header(header::ACCEPT_LANGUAGE, "de-CH,de;q=0.9,en;q=0.8")
+            .with_header(HEADER_DNT, "1");
+
+        let device = device(&req, None, &consent(false));
+        assert_eq!(device["ua"], CHROME_UA);
+        assert_eq!(device["devicetype"], DEVICE_TYPE_PC);
+        assert_eq!(device["language"], "de");
+        assert_eq!(device["dnt"], 1);
+        assert_eq!(
+            device["sua"],
+            json!({
+                "browsers": [
+                    { "brand": "Chromium", "version": ["124", "0", "6367", "91"] },
+                    { "brand": "Google Chrome", "version": ["124", "0", "6367", "91"] }
+                ],
+                "platform": { "brand": "Windows", "version": ["15", "0", "0"] },
+                "mobile": 0,
+                "architecture": "x86",
+                "source": SUA_SOURCE_HIGH_ENTROPY
+            })
+        );
+        assert!(device.get("ip").is_none());
+    }
+
+    #[test]
+    fn test_device_without_client_hints() {
+        let req = Request::get("https://example.com/auction").with_header(
+            header::USER_AGENT,
+            "Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) Mobile/15E148",
+        );
+        let device = device(&req, "198.51.100.23".parse().ok(), &consent(false));
+        assert_eq!(device["devicetype"], DEVICE_TYPE_PHONE);
+        assert_eq!(device["ip"], "198.51.100.0");
+        assert!(device.get("sua").is_none());
+        assert!(device.get("dnt").is_none());
+        assert!(device["geo"].get("lat").is_none());
+
+        let low_entropy = Request::get("https://example.com/auction")
+            .with_header("sec-ch-ua", r#""Chromium";v="124""#)
+            .with_header("sec-ch-ua-mobile", "?1");
+        let device = super::device(&low_entropy, None, &consent(true));
+        assert_eq!(device["devicetype"], DEVICE_TYPE_PHONE);
+        assert_eq!(device["sua"]["source"], SUA_SOURCE_LOW_ENTROPY);
+        assert_eq!(device["sua"]["mobile"], 1);
+    }
+
+    #[test]
+    fn test_viewport_width() {
+        let req = Request::get("https://example.com").with_header("sec-ch-viewport-width", "390");
+        assert_eq!(viewport_width(&req), Some(390));
+        let req = Request::get("https://example.com").with_header("viewport-width", "1280");
+        assert_eq!(viewport_width(&req), Some(1280));
+
+        let req = Request::get("https://example.com").with_header("sec-ch-ua-mobile", "?1");
+        assert_eq!(viewport_width(&req), Some(PHONE_VIEWPORT_WIDTH));
+        let req = Request::get("https://example.com").with_header(header::USER_AGENT, CHROME_UA);
+        assert_eq!(viewport_width(&req), None);
+    }
+}
@@ -187,8 +187,6 @@ impl DidomiProxy {
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-    
     #[test]
     fn test_consent_path_extraction() {
         let path = "/consent/api/events";
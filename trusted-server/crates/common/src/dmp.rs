@@ -0,0 +1,455 @@
+//! Server-side Lotame, Neustar and publisher-defined audience segments.
+//!
+//! With `lotame.api_url` or `neustar.api_url` set, or `data_providers`
+//! configured (see [`CustomProvider`]), the segments each DMP holds for a
+//! visitor are looked up by synthetic ID, only with consent to
+//! personalized ads and the DMP's TCF consent for the advertising purposes.
+//! Each DMP is a [`DmpProvider`], looked up concurrently with the other
+//! data providers by a [`crate::data_provider::DataProviderManager`] and
+//! waited for until its own `timeout_ms`: a DMP that fails, answers late or
+//! with an invalid body is logged and only loses its own segments, so it
+//! can't break ad serving.
+
+use std::fmt;
+use std::time::Duration;
+
+use error_stack::{Report, ResultExt};
+use fastly::http::request::SendError;
+use fastly::http::{Method, StatusCode};
+use fastly::{Request, Response};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::consent::ConsentDecision;
+use crate::data_provider::{response, DataProvider, SegmentsFuture};
+use crate::error::TrustedServerError;
+use crate::settings::{CustomProvider, Settings};
+use crate::tcf_consent::purpose_ids;
+use crate::tcf_consent::vendor_list_manager::load_vendor_list;
+
+/// Data management platform queried for segments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dmp<'a> {
+    /// Lotame, through its audience extraction API
+    Lotame,
+    /// Neustar, through its segment API
+    Neustar,
+    /// Publisher-defined provider of `data_providers`
+    Custom(&'a CustomProvider),
+}
+
+impl<'a> Dmp<'a> {
+    /// All DMPs of `settings`, configured or not.
+    fn all(settings: &'a Settings) -> Vec<Self> {
+        [Self::Lotame, Self::Neustar]
+            .into_iter()
+            .chain(settings.data_providers.iter().map(Self::Custom))
+            .collect()
+    }
+
+    /// `cust_params` key of the DMP's segments.
+    pub fn key(self) -> &'a str {
+        match self {
+            Self::Lotame => "lotame",
+            Self::Neustar => "neustar",
+            Self::Custom(provider) => &provider.name,
+        }
+    }
+
+    /// `user.data` name of the DMP's segments in bid requests.
+    fn data_name(self) -> &'a str {
+        match self {
+            Self::Lotame => "lotame.com",
+            Self::Neustar => "neustar.biz",
+            Self::Custom(provider) => provider.data_name.as_deref().unwrap_or(&provider.name),
+        }
+    }
+
+    /// Fastly backend pointing at the DMP's API.
+    fn backend(self) -> &'a str {
+        match self {
+            Self::Lotame => "lotame_api",
+            Self::Neustar => "neustar_api",
+            Self::Custom(provider) => &provider.backend,
+        }
+    }
+
+    /// Configured API endpoint; the DMP is disabled when empty.
+    fn api_url(self, settings: &'a Settings) -> &'a str {
+        match self {
+            Self::Lotame => &settings.lotame.api_url,
+            Self::Neustar => &settings.neustar.api_url,
+            Self::Custom(provider) => &provider.url,
+        }
+    }
+
+    fn vendor_id(self, settings: &Settings) -> Option<u16> {
+        match self {
+            Self::Lotame => settings.lotame.vendor_id,
+            Self::Neustar => settings.neustar.vendor_id,
+            Self::Custom(provider) => provider.vendor_id,
+        }
+    }
+
+    fn timeout(self, settings: &Settings) -> Duration {
+        Duration::from_millis(match self {
+            Self::Lotame => settings.lotame.timeout_ms,
+            Self::Neustar => settings.neustar.timeout_ms,
+            Self::Custom(provider) => provider.timeout_ms,
+        })
+    }
+
+    /// Segment lookup of `synthetic_id`.
+    fn request(self, settings: &Settings, synthetic_id: &str) -> Request {
+        let synthetic_id = urlencoding::encode(synthetic_id);
+        match self {
+            Self::Lotame => {
+                let config = &settings.lotame;
+                let url = format!(
+                    "{}?c={}&mid={}",
+                    config.api_url,
+                    urlencoding::encode(&config.client_id),
+                    synthetic_id
+                );
+                Request::new(Method::GET, url).with_header("X-API-Key", &config.api_key)
+            }
+            Self::Neustar => {
+                let config = &settings.neustar;
+                let url = format!("{}?user_id={}", config.api_url, synthetic_id);
+                Request::new(Method::GET, url).with_header("X-API-Key", &config.api_key)
+            }
+            Self::Custom(provider) => {
+                let url = provider.url.replace("{{synthetic_id}}", &synthetic_id);
+                let req = Request::new(Method::GET, url);
+                if provider.auth_header.is_empty() {
+                    req
+                } else {
+                    req.with_header(provider.auth_header.as_str(), &provider.auth_value)
+                }
+            }
+        }
+    }
+
+    /// Segment IDs of a lookup response body: Lotame answers
+    /// `{"Profile": {"Audiences": {"Audience": [{"id": ...}]}}}`, Neustar
+    /// `{"segments": [...]}` and custom providers an array at their
+    /// `segments_path` of IDs or objects with an `id`.
+    fn parse(self, body: &[u8]) -> Result<Vec<String>, Report<TrustedServerError>> {
+        let ids: Vec<Value> = match self {
+            Self::Lotame => {
+                let body: LotameResponse = serde_json::from_slice(body)
+                    .change_context(dmp_error(self, "Invalid audience extraction response"))?;
+                body.profile
+                    .audiences
+                    .audience
+                    .into_iter()
+                    .map(|audience| audience.id)
+                    .collect()
+            }
+            Self::Neustar => {
+                let body: NeustarResponse = serde_json::from_slice(body)
+                    .change_context(dmp_error(self, "Invalid segment API response"))?;
+                body.segments
+            }
+            Self::Custom(provider) => {
+                let mut body: Value = serde_json::from_slice(body)
+                    .change_context(dmp_error(self, "Invalid segment response"))?;
+                let pointer = match provider.segments_path.as_str() {
+                    "" => String::new(),
+                    path => format!("/{}", path.replace('.', "/")),
+                };
+                match body.pointer_mut(&pointer).map(Value::take) {
+                    Some(Value::Array(segments)) => segments
+                        .into_iter()
+                        .map(|segment| match segment {
+                            Value::Object(mut segment) => segment.remove("id").unwrap_or_default(),
+                            segment => segment,
+                        })
+                        .collect(),
+                    _ => {
+                        return Err(Report::new(dmp_error(
+                            self,
+                            format!("No segment array at {:?}", provider.segments_path),
+                        )))
+                    }
+                }
+            }
+        };
+        Ok(ids
+            .into_iter()
+            .filter_map(|id| match id {
+                Value::String(id) => Some(id),
+                Value::Number(id) => Some(id.to_string()),
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+impl fmt::Display for Dmp<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.key())
+    }
+}
+
+/// Lotame audience extraction response.
+#[derive(Deserialize)]
+struct LotameResponse {
+    #[serde(rename = "Profile")]
+    profile: LotameProfile,
+}
+
+#[derive(Deserialize)]
+struct LotameProfile {
+    #[serde(rename = "Audiences", default)]
+    audiences: LotameAudiences,
+}
+
+#[derive(Default, Deserialize)]
+struct LotameAudiences {
+    #[serde(rename = "Audience", default)]
+    audience: Vec<LotameAudience>,
+}
+
+#[derive(Deserialize)]
+struct LotameAudience {
+    /// Audience ID, as a number or string
+    id: Value,
+}
+
+/// Neustar segment API response.
+#[derive(Deserialize)]
+struct NeustarResponse {
+    /// Segment IDs, as numbers or strings
+    segments: Vec<Value>,
+}
+
+fn dmp_error(dmp: Dmp, message: impl Into<String>) -> TrustedServerError {
+    TrustedServerError::DataProvider {
+        provider: dmp.key().to_string(),
+        message: message.into(),
+    }
+}
+
+/// DMPs that may be queried for the request: configured ones with their
+/// TCF consent for the advertising purposes, and none without consent to
+/// personalized ads.
+fn allowed_dmps<'a>(settings: &'a Settings, consent: &ConsentDecision) -> Vec<Dmp<'a>> {
+    if !consent.is_personalized() {
+        return Vec::new();
+    }
+    let configured: Vec<Dmp> = Dmp::all(settings)
+        .into_iter()
+        .filter(|dmp| !dmp.api_url(settings).is_empty())
+        .collect();
+    if configured.is_empty() {
+        return configured;
+    }
+    let vendor_list = load_vendor_list(settings);
+    configured
+        .into_iter()
+        .filter(|dmp| {
+            consent.allows_vendor(
+                dmp.vendor_id(settings),
+                purpose_ids::ADVERTISING,
+                vendor_list.as_ref(),
+            )
+        })
+        .collect()
+}
+
+/// Segments of a lookup result; failures are logged and give no segments.
+fn lookup_segments(dmp: Dmp, result: Result<Response, SendError>) -> Vec<String> {
+    result
+        .change_context(dmp_error(dmp, "Failed to fetch segments"))
+        .and_then(|mut resp| {
+            if resp.get_status() != StatusCode::OK {
+                return Err(Report::new(dmp_error(
+                    dmp,
+                    format!("Segment API returned {}", resp.get_status()),
+                )));
+            }
+            dmp.parse(&resp.take_body_bytes())
+        })
+        .inspect_err(|e| log::warn!("Failed to look up {} segments: {:?}", dmp, e))
+        .unwrap_or_default()
+}
+
+/// A DMP as a [`DataProvider`].
+pub struct DmpProvider<'a> {
+    dmp: Dmp<'a>,
+    settings: &'a Settings,
+}
+
+impl DataProvider for DmpProvider<'_> {
+    fn key(&self) -> &str {
+        self.dmp.key()
+    }
+
+    fn data_name(&self) -> &str {
+        self.dmp.data_name()
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        Some(self.dmp.timeout(self.settings))
+    }
+
+    fn segments<'a>(&'a self, synthetic_id: &'a str) -> SegmentsFuture<'a> {
+        Box::pin(async move {
+            let dmp = self.dmp;
+            let request = dmp.request(self.settings, synthetic_id);
+            match request.send_async(dmp.backend()) {
+                Ok(pending) => lookup_segments(dmp, response(pending).await),
+                Err(e) => {
+                    log::warn!("Failed to send the {} segment lookup: {:?}", dmp, e);
+                    Vec::new()
+                }
+            }
+        })
+    }
+}
+
+/// Providers of the DMPs the request allows.
+pub fn providers<'a>(settings: &'a Settings, consent: &ConsentDecision) -> Vec<DmpProvider<'a>> {
+    allowed_dmps(settings, consent)
+        .into_iter()
+        .map(|dmp| DmpProvider { dmp, settings })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consent::PersonalizationLevel;
+    use crate::test_support::tests::create_test_settings;
+
+    /// Decision outside GDPR, where DMPs need no TCF vendor consent.
+    fn consent(settings: &Settings, personalization: PersonalizationLevel) -> ConsentDecision {
+        let mut consent =
+            ConsentDecision::from_request(settings, &Request::get("https://test.com"));
+        consent.personalization = personalization;
+        consent.signals.tcf.gdpr_applies = false;
+        consent
+    }
+
+    #[test]
+    fn test_parse() {
+        let lotame = br#"{"Profile": {"tpid": "x", "Audiences": {"Audience": [
+            {"id": "99123", "abbr": "sports"}, {"id": 99456}
+        ]}}}"#;
+        assert_eq!(Dmp::Lotame.parse(lotame).unwrap(), vec!["99123", "99456"]);
+        let empty = br#"{"Profile": {"tpid": "x"}}"#;
+        assert!(Dmp::Lotame.parse(empty).unwrap().is_empty());
+
+        let neustar = br#"{"segments": [1001, "1002", null]}"#;
+        assert_eq!(Dmp::Neustar.parse(neustar).unwrap(), vec!["1001", "1002"]);
+        assert!(Dmp::Neustar.parse(b"<html>").is_err());
+        assert!(Dmp::Lotame.parse(neustar).is_err());
+    }
+
+    fn custom_provider() -> CustomProvider {
+        CustomProvider {
+            name: "inhouse".to_string(),
+            url: "https://dmp.example.com/users/{{synthetic_id}}/segments".to_string(),
+            backend: "inhouse_dmp".to_string(),
+            auth_header: "Authorization".to_string(),
+            auth_value: "Bearer token".to_string(),
+            segments_path: "data.segments".to_string(),
+            data_name: None,
+            timeout_ms: 50,
+            vendor_id: None,
+        }
+    }
+
+    #[test]
+    fn test_custom_provider() {
+        let mut provider = custom_provider();
+        let dmp = Dmp::Custom(&provider);
+        assert_eq!(dmp.key(), "inhouse");
+        assert_eq!(dmp.data_name(), "inhouse");
+        assert_eq!(dmp.backend(), "inhouse_dmp");
+
+        let req = dmp.request(&create_test_settings(), "synthetic/1");
+        assert_eq!(
+            req.get_url_str(),
+            "https://dmp.example.com/users/synthetic%2F1/segments"
+        );
+        assert_eq!(req.get_header_str("Authorization"), Some("Bearer token"));
+
+        let body = br#"{"data": {"segments": ["a1", 2, {"id": "c3", "score": 0.9}]}}"#;
+        assert_eq!(dmp.parse(body).unwrap(), vec!["a1", "2", "c3"]);
+        assert!(dmp.parse(br#"{"data": {}}"#).is_err());
+
+        provider.segments_path.clear();
+        provider.auth_header.clear();
+        provider.data_name = Some("dmp.example.com".to_string());
+        let dmp = Dmp::Custom(&provider);
+        assert_eq!(dmp.parse(br#"["x", "y"]"#).unwrap(), vec!["x", "y"]);
+        assert_eq!(dmp.data_name(), "dmp.example.com");
+        let req = dmp.request(&create_test_settings(), "synthetic-1");
+        assert!(req.get_header("Authorization").is_none());
+    }
+
+    #[test]
+    fn test_request() {
+        let mut settings = create_test_settings();
+        settings.lotame.api_url = "https://ad.crwdcntrl.net/5/pe=y".to_string();
+        settings.lotame.client_id = "1234".to_string();
+        settings.lotame.api_key = "secret".to_string();
+        let req = Dmp::Lotame.request(&settings, "synthetic 1");
+        assert_eq!(
+            req.get_url_str(),
+            "https://ad.crwdcntrl.net/5/pe=y?c=1234&mid=synthetic%201"
+        );
+        assert_eq!(req.get_header_str("X-API-Key"), Some("secret"));
+
+        settings.neustar.api_url = "https://api.neustar.test/segments".to_string();
+        let req = Dmp::Neustar.request(&settings, "synthetic-1");
+        assert_eq!(
+            req.get_url_str(),
+            "https://api.neustar.test/segments?user_id=synthetic-1"
+        );
+    }
+
+    #[test]
+    fn test_allowed_dmps() {
+        let mut settings = create_test_settings();
+        let personalized = consent(&settings, PersonalizationLevel::Personalized);
+        assert!(allowed_dmps(&settings, &personalized).is_empty());
+
+        settings.lotame.api_url = "https://ad.crwdcntrl.net/5/pe=y".to_string();
+        settings.neustar.api_url = "https://api.neustar.test/segments".to_string();
+        assert_eq!(
+            allowed_dmps(&settings, &personalized),
+            vec![Dmp::Lotame, Dmp::Neustar]
+        );
+
+        // Neustar has no vendor ID, so it needs GDPR not to apply
+        let mut gdpr = personalized.clone();
+        gdpr.signals.tcf.gdpr_applies = true;
+        assert!(!allowed_dmps(&settings, &gdpr).contains(&Dmp::Neustar));
+
+        settings.data_providers.push(custom_provider());
+        let allowed = allowed_dmps(&settings, &personalized);
+        assert_eq!(allowed.len(), 3);
+        assert_eq!(allowed[2].key(), "inhouse");
+
+        let non_personalized = consent(&settings, PersonalizationLevel::NonPersonalized);
+        assert!(allowed_dmps(&settings, &non_personalized).is_empty());
+        assert!(providers(&settings, &non_personalized).is_empty());
+        let providers = providers(&settings, &personalized);
+        assert_eq!(providers[0].timeout(), Some(Duration::from_millis(100)));
+        assert_eq!(providers[2].data_name(), "inhouse");
+    }
+
+    #[test]
+    fn test_lookup_segments() {
+        let resp = Response::from_body(r#"{"segments": ["1001"]}"#);
+        assert_eq!(lookup_segments(Dmp::Neustar, Ok(resp)), vec!["1001"]);
+
+        let error = Response::from_status(StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(lookup_segments(Dmp::Neustar, Ok(error)).is_empty());
+        let invalid = Response::from_body("not json");
+        assert!(lookup_segments(Dmp::Lotame, Ok(invalid)).is_empty());
+    }
+}
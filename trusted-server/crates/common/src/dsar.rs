@@ -0,0 +1,309 @@
+//! Tracking of data subject requests.
+//!
+//! Every authenticated access, portability, rectification or erasure request
+//! made through `/gdpr/data` is given a [`DsarRequest`] with a unique ID,
+//! returned in `X-DSAR-Request-ID`, whose state moves from
+//! [`DsarState::Received`] to [`DsarState::Verified`] once the requester's
+//! token or operator credentials are checked and then to
+//! [`DsarState::Fulfilled`] once the request is answered, [`DsarState::Rejected`]
+//! if it is invalid or [`DsarState::Failed`] if carrying it out fails. Requests failing authentication are not
+//! tracked. Requests are kept in the `gdpr.request_store` KV store
+//! so their progress can be checked from `GET /gdpr/requests/<id>`, which is
+//! what publishers fulfilling requests asynchronously report against.
+
+use std::fmt;
+
+use error_stack::Report;
+use fastly::http::{header, Method, StatusCode};
+use fastly::{Error, Request, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::error::TrustedServerError;
+use crate::kv_store::JsonKvStore;
+use crate::settings::Settings;
+
+/// Path prefix of the request status endpoint.
+pub const REQUEST_STATUS_PATH: &str = "/gdpr/requests/";
+
+/// Kind of data subject request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DsarKind {
+    /// Right of access (`GET /gdpr/data`).
+    Access,
+    /// Right to data portability (`GET /gdpr/data/export`).
+    Portability,
+    /// Right to rectification (`PATCH /gdpr/data`).
+    Rectification,
+    /// Right to erasure (`DELETE /gdpr/data`).
+    Erasure,
+}
+
+impl fmt::Display for DsarKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Access => "access",
+            Self::Portability => "portability",
+            Self::Rectification => "rectification",
+            Self::Erasure => "erasure",
+        })
+    }
+}
+
+/// Progress of a data subject request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DsarState {
+    /// The request was submitted but the requester is not yet verified.
+    Received,
+    /// The requester proved control of the subject's synthetic ID.
+    Verified,
+    /// The request was carried out.
+    Fulfilled,
+    /// The request was invalid, e.g. a malformed rectification.
+    Rejected,
+    /// Carrying out the request failed; the requester may submit it again.
+    Failed,
+}
+
+/// A data subject request and its progress.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DsarRequest {
+    /// Unique request ID (UUID v4).
+    pub request_id: String,
+    /// Kind of request.
+    pub kind: DsarKind,
+    /// Synthetic ID of the data subject.
+    pub synthetic_id: String,
+    /// Current state.
+    pub state: DsarState,
+    /// Unix timestamp (seconds) when the request was submitted.
+    pub submitted_at: i64,
+    /// Unix timestamp (seconds) of the last state change.
+    pub updated_at: i64,
+}
+
+impl DsarRequest {
+    /// Creates a [`DsarState::Received`] request.
+    pub fn new(kind: DsarKind, synthetic_id: &str) -> Self {
+        let now = chrono::Utc::now().timestamp();
+        Self {
+            request_id: Uuid::new_v4().to_string(),
+            kind,
+            synthetic_id: synthetic_id.to_string(),
+            state: DsarState::Received,
+            submitted_at: now,
+            updated_at: now,
+        }
+    }
+
+    /// Moves the request to `state`.
+    pub fn advance(&mut self, state: DsarState) {
+        self.state = state;
+        self.updated_at = chrono::Utc::now().timestamp();
+    }
+
+    /// Status reported to the requester, without the subject's synthetic ID.
+    pub fn status(&self) -> serde_json::Value {
+        json!({
+            "request_id": self.request_id,
+            "kind": self.kind,
+            "state": self.state,
+            "submitted_at": self.submitted_at,
+            "updated_at": self.updated_at,
+        })
+    }
+}
+
+/// KV store of data subject requests keyed by request ID.
+pub struct DsarStore {
+    store: JsonKvStore,
+}
+
+impl DsarStore {
+    /// Opens the store configured in `gdpr.request_store`.
+    ///
+    /// Returns [`None`] when request tracking is disabled.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the configured store cannot be opened
+    pub fn open(settings: &Settings) -> Result<Option<Self>, Report<TrustedServerError>> {
+        if settings.gdpr.request_store.is_empty() {
+            return Ok(None);
+        }
+        let store = JsonKvStore::open(&settings.gdpr.request_store)?;
+        Ok(Some(Self { store }))
+    }
+
+    fn key(request_id: &str) -> String {
+        format!("dsar:{request_id}")
+    }
+
+    /// Returns the request with ID `request_id`, if any.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the lookup fails
+    pub fn get(&self, request_id: &str) -> Result<Option<DsarRequest>, Report<TrustedServerError>> {
+        self.store.get(&Self::key(request_id))
+    }
+
+    /// Stores `request`, replacing its previous state.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the insert fails
+    pub fn put(&self, request: &DsarRequest) -> Result<(), Report<TrustedServerError>> {
+        self.store.put(&Self::key(&request.request_id), request)
+    }
+}
+
+/// Records `request` in `gdpr.request_store`, when tracking is enabled.
+///
+/// Failures are logged rather than returned so an unavailable store never
+/// blocks answering the request itself.
+pub fn track_request(settings: &Settings, request: &DsarRequest) {
+    let result = DsarStore::open(settings).and_then(|store| match store {
+        Some(store) => store.put(request),
+        None => Ok(()),
+    });
+    if let Err(e) = result {
+        log::warn!(
+            "Failed to record data subject request {}: {:?}",
+            request.request_id,
+            e
+        );
+    }
+}
+
+/// Handles `GET /gdpr/requests/<id>`, reporting the state of a data subject
+/// request.
+///
+/// The request ID is an unguessable UUID, so knowing it is enough to read the
+/// status; the status never includes the subject's synthetic ID.
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if the request store cannot be read or response
+/// creation fails.
+pub fn handle_request_status(settings: &Settings, req: Request) -> Result<Response, Error> {
+    if *req.get_method() != Method::GET {
+        return Ok(
+            Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_body("Method not allowed")
+        );
+    }
+    let Some(store) = DsarStore::open(settings).map_err(|e| Error::msg(format!("{e:?}")))? else {
+        return Ok(Response::from_status(StatusCode::NOT_FOUND).with_body("Not Found"));
+    };
+    let request_id = req
+        .get_path()
+        .strip_prefix(REQUEST_STATUS_PATH)
+        .unwrap_or_default();
+    if Uuid::parse_str(request_id).is_err() {
+        return Ok(Response::from_status(StatusCode::NOT_FOUND).with_body("Not Found"));
+    }
+    let Some(request) = store
+        .get(request_id)
+        .map_err(|e| Error::msg(format!("{e:?}")))?
+    else {
+        return Ok(Response::from_status(StatusCode::NOT_FOUND).with_body("Not Found"));
+    };
+
+    Ok(Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_header(header::CACHE_CONTROL, "no-store, private")
+        .with_body_json(&request.status())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::tests::create_test_settings;
+
+    fn dsar_settings() -> Settings {
+        let mut settings = create_test_settings();
+        settings.gdpr.request_store = "test_dsar_store".to_string();
+        settings
+    }
+
+    #[test]
+    fn test_dsar_request_lifecycle() {
+        let mut request = DsarRequest::new(DsarKind::Erasure, "dsar-synthetic");
+        assert_eq!(request.state, DsarState::Received);
+        assert!(Uuid::parse_str(&request.request_id).is_ok());
+
+        request.advance(DsarState::Verified);
+        assert_eq!(request.state, DsarState::Verified);
+        assert!(request.updated_at >= request.submitted_at);
+
+        let status = request.status();
+        assert_eq!(status["kind"], "erasure");
+        assert_eq!(status["state"], "verified");
+        assert!(status.get("synthetic_id").is_none());
+
+        request.advance(DsarState::Failed);
+        assert_eq!(request.status()["state"], "failed");
+    }
+
+    #[test]
+    fn test_dsar_store() {
+        let settings = dsar_settings();
+        assert!(DsarStore::open(&create_test_settings())
+            .expect("should open the DSAR store")
+            .is_none());
+        let store = DsarStore::open(&settings)
+            .expect("should open the DSAR store")
+            .expect("should have a DSAR store configured");
+
+        let mut request = DsarRequest::new(DsarKind::Access, "dsar-synthetic");
+        track_request(&settings, &request);
+        request.advance(DsarState::Fulfilled);
+        track_request(&settings, &request);
+        assert_eq!(
+            store
+                .get(&request.request_id)
+                .expect("should read the store"),
+            Some(request)
+        );
+        assert_eq!(
+            store
+                .get(&Uuid::new_v4().to_string())
+                .expect("should read the store"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_handle_request_status() {
+        let settings = dsar_settings();
+        let request = DsarRequest::new(DsarKind::Access, "status-synthetic");
+        DsarStore::open(&settings)
+            .expect("should open the DSAR store")
+            .expect("should have a DSAR store configured")
+            .put(&request)
+            .expect("should write to the store");
+
+        let path = format!("https://example.com/gdpr/requests/{}", request.request_id);
+        let resp = handle_request_status(&settings, Request::get(&path))
+            .expect("should handle the request");
+        assert_eq!(resp.get_status(), StatusCode::OK);
+        let body: serde_json::Value =
+            serde_json::from_str(&resp.into_body_str()).expect("should parse JSON");
+        assert_eq!(body, request.status());
+
+        for path in [
+            format!("https://example.com/gdpr/requests/{}", Uuid::new_v4()),
+            "https://example.com/gdpr/requests/not-a-uuid".to_string(),
+        ] {
+            let resp = handle_request_status(&settings, Request::get(&path))
+                .expect("should handle the request");
+            assert_eq!(resp.get_status(), StatusCode::NOT_FOUND);
+        }
+        let resp = handle_request_status(&create_test_settings(), Request::get(&path))
+            .expect("should handle the request");
+        assert_eq!(resp.get_status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,622 @@
+//! Right to erasure for data subject requests.
+//!
+//! [`erase_user_data`] deletes the visit count, opid and consent history stored
+//! for a synthetic ID and records a [`Tombstone`] for it, so that
+//! [`crate::synthetic::resolve_synthetic_id`] never issues the ID again even
+//! though the same request signals would regenerate it. [`notify_partners`]
+//! then fans an [`ErasureRequest`] out to the ad partners listed in
+//! `gdpr.erasure_endpoints` (Equativ, Prebid bidders, GAM) so they can delete
+//! what they hold for the user, retrying transient failures and reporting the
+//! outcome per partner.
+
+use std::time::{Duration, Instant};
+
+use error_stack::{Report, ResultExt};
+use fastly::http::{header, Method, StatusCode};
+use fastly::Request;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::consent_history::ConsentHistoryStore;
+use crate::consent_receipt::ConsentReceiptStore;
+use crate::deadline::{wait_until, Deadline};
+use crate::error::TrustedServerError;
+use crate::identity::IdentityStore;
+use crate::kv_store::JsonKvStore;
+use crate::settings::{ErasureEndpoint, ErasureMethod, Settings};
+use crate::user_sync::{PartnerUidStore, PartnerUids};
+
+/// Delay before the first retry of failed partner notifications; doubled for
+/// every further round of retries.
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Time the partner notifications of an erasure may take in total, including
+/// the backoff between retries. Partners still failing when it runs out are
+/// reported as failed.
+const NOTIFY_BUDGET: Duration = Duration::from_secs(5);
+
+/// Record of an erased synthetic ID.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tombstone {
+    /// Unix timestamp (seconds) when the ID's data was erased.
+    pub erased_at: i64,
+}
+
+/// KV store of tombstones keyed by synthetic ID.
+pub struct TombstoneStore {
+    store: JsonKvStore,
+}
+
+impl TombstoneStore {
+    /// Opens the store configured in `gdpr.tombstone_store`.
+    ///
+    /// Returns [`None`] when tombstones are disabled.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the configured store cannot be opened
+    pub fn open(settings: &Settings) -> Result<Option<Self>, Report<TrustedServerError>> {
+        if settings.gdpr.tombstone_store.is_empty() {
+            return Ok(None);
+        }
+        let store = JsonKvStore::open(&settings.gdpr.tombstone_store)?;
+        Ok(Some(Self { store }))
+    }
+
+    fn key(synthetic_id: &str) -> String {
+        format!("tombstone:{synthetic_id}")
+    }
+
+    /// Returns the tombstone of `synthetic_id`, if its data was erased.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the lookup fails
+    pub fn get(&self, synthetic_id: &str) -> Result<Option<Tombstone>, Report<TrustedServerError>> {
+        self.store.get(&Self::key(synthetic_id))
+    }
+
+    /// Records that the data of `synthetic_id` was erased.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the insert fails
+    pub fn add(&self, synthetic_id: &str) -> Result<(), Report<TrustedServerError>> {
+        let tombstone = Tombstone {
+            erased_at: chrono::Utc::now().timestamp(),
+        };
+        self.store.put(&Self::key(synthetic_id), &tombstone)
+    }
+}
+
+/// Returns `true` if the data of `synthetic_id` was erased.
+///
+/// Lookup failures are logged and treated as not erased, so an unavailable
+/// store never blocks ID resolution.
+pub fn is_tombstoned(settings: &Settings, synthetic_id: &str) -> bool {
+    let result = TombstoneStore::open(settings).and_then(|store| match store {
+        Some(store) => store.get(synthetic_id).map(|t| t.is_some()),
+        None => Ok(false),
+    });
+    result.unwrap_or_else(|e| {
+        log::warn!("Failed to look up tombstone: {:?}", e);
+        false
+    })
+}
+
+/// Deletion request sent to each of `gdpr.erasure_endpoints`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErasureRequest {
+    /// Unique request ID (UUID v4), usable for deduplication.
+    pub request_id: String,
+    /// Unix timestamp (seconds) of the erasure.
+    pub timestamp: i64,
+    /// Synthetic ID whose data was erased.
+    pub synthetic_id: String,
+    /// Ad server opid stored for the synthetic ID, if any.
+    pub opid: Option<String>,
+    /// UID synced for the endpoint's Prebid bidder, if it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partner_uid: Option<String>,
+}
+
+/// Outcome of notifying one ad partner.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartnerNotification {
+    /// Partner name from `gdpr.erasure_endpoints`.
+    pub name: String,
+    /// Whether the partner acknowledged the request with a success status.
+    pub delivered: bool,
+    /// Number of requests sent; `0` when the partner was skipped.
+    pub attempts: u32,
+    /// Status of the last response, if any was received.
+    pub status: Option<u16>,
+    /// Why the partner was skipped or the last attempt failed.
+    pub error: Option<String>,
+}
+
+/// Summary of an erasure, returned to the data subject.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErasureSummary {
+    /// Synthetic ID whose data was erased.
+    pub synthetic_id: String,
+    /// Whether the ID was tombstoned against reissue.
+    pub tombstoned: bool,
+    /// Ad partners notified of the erasure.
+    pub partners: Vec<PartnerNotification>,
+}
+
+fn erasure_error(message: impl Into<String>) -> TrustedServerError {
+    TrustedServerError::Erasure {
+        message: message.into(),
+    }
+}
+
+/// Returns the endpoint URL with the subject's identifiers substituted for
+/// its `{{synthetic_id}}`, `{{opid}}` and `{{partner_uid}}` placeholders.
+fn erasure_url(endpoint: &ErasureEndpoint, request: &ErasureRequest) -> String {
+    let encode = |value: Option<&str>| urlencoding::encode(value.unwrap_or_default()).into_owned();
+    endpoint
+        .url
+        .replace(
+            "{{synthetic_id}}",
+            &encode(Some(request.synthetic_id.as_str())),
+        )
+        .replace("{{opid}}", &encode(request.opid.as_deref()))
+        .replace("{{partner_uid}}", &encode(request.partner_uid.as_deref()))
+}
+
+/// Returns `true` if a partner answering with `status` may succeed on retry.
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Builds the erasure request for a partner's privacy endpoint.
+///
+/// # Errors
+///
+/// - [`TrustedServerError::Erasure`] if the request body cannot be serialized
+fn erasure_request(
+    endpoint: &ErasureEndpoint,
+    request: &ErasureRequest,
+) -> Result<Request, Report<TrustedServerError>> {
+    let url = erasure_url(endpoint, request);
+    let mut req = match endpoint.method {
+        ErasureMethod::Post => {
+            let body = serde_json::to_vec(request)
+                .change_context(erasure_error("Failed to serialize erasure request"))?;
+            Request::new(Method::POST, url)
+                .with_header(header::CONTENT_TYPE, "application/json")
+                .with_body(body)
+        }
+        ErasureMethod::Delete => Request::new(Method::DELETE, url),
+    };
+    if !endpoint.auth_token.is_empty() {
+        req.set_header(
+            header::AUTHORIZATION,
+            format!("Bearer {}", endpoint.auth_token),
+        );
+    }
+    Ok(req)
+}
+
+/// A partner notification still being delivered.
+struct Delivery<'a> {
+    endpoint: &'a ErasureEndpoint,
+    request: ErasureRequest,
+    notification: PartnerNotification,
+    /// Whether the last attempt failed in a way worth retrying.
+    retry: bool,
+}
+
+impl<'a> Delivery<'a> {
+    fn new(endpoint: &'a ErasureEndpoint, request: ErasureRequest) -> Self {
+        Self {
+            endpoint,
+            request,
+            notification: PartnerNotification {
+                name: endpoint.name.clone(),
+                delivered: false,
+                attempts: 0,
+                status: None,
+                error: None,
+            },
+            retry: false,
+        }
+    }
+
+    /// Records the partner's answer to the last attempt.
+    fn record(&mut self, status: StatusCode) {
+        self.notification.status = Some(status.as_u16());
+        if status.is_success() {
+            self.notification.delivered = true;
+            self.notification.error = None;
+            self.retry = false;
+            log::info!(
+                "Sent erasure request {} to {}",
+                self.request.request_id,
+                self.endpoint.name
+            );
+        } else {
+            self.notification.error = Some(format!("{} returned {}", self.endpoint.name, status));
+            self.retry = is_retryable(status);
+        }
+    }
+
+    /// Records that the last attempt got no answer.
+    fn fail(&mut self, error: String) {
+        log::warn!(
+            "Failed to notify {} of erasure: {}",
+            self.endpoint.name,
+            error
+        );
+        self.notification.status = None;
+        self.notification.error = Some(error);
+        self.retry = true;
+    }
+
+    /// Whether another attempt should be made.
+    fn should_retry(&self) -> bool {
+        self.retry && self.notification.attempts < self.endpoint.max_attempts
+    }
+}
+
+/// Sends one round of erasure requests, concurrently, to every delivery in
+/// `round`, waiting for the answers until `deadline`.
+fn send_round(round: &mut [&mut Delivery<'_>], deadline: Deadline) {
+    let mut pending = Vec::new();
+    for (index, delivery) in round.iter_mut().enumerate() {
+        delivery.notification.attempts += 1;
+        let sent = erasure_request(delivery.endpoint, &delivery.request).and_then(|req| {
+            req.send_async(delivery.endpoint.backend.as_str())
+                .change_context(erasure_error(format!(
+                    "Failed to send erasure request to {}",
+                    delivery.endpoint.name
+                )))
+        });
+        match sent {
+            Ok(request) => pending.push((index, request, deadline)),
+            Err(e) => delivery.fail(e.current_context().to_string()),
+        }
+    }
+
+    let mut unanswered: Vec<usize> = pending.iter().map(|(index, _, _)| *index).collect();
+    for (index, result) in wait_until(pending) {
+        unanswered.retain(|pending| *pending != index);
+        match result {
+            Ok(resp) => round[index].record(resp.get_status()),
+            Err(e) => round[index].fail(format!(
+                "Failed to send erasure request to {}: {}",
+                round[index].endpoint.name, e
+            )),
+        }
+    }
+    for index in unanswered {
+        round[index].fail(format!(
+            "{} did not answer in time",
+            round[index].endpoint.name
+        ));
+        // The budget is spent, so there is no time left for a retry either
+        round[index].retry = false;
+    }
+}
+
+/// Sends `request` to every endpoint in `gdpr.erasure_endpoints` and reports
+/// the outcome per partner.
+///
+/// Partners are notified concurrently. Network errors, `429` and `5xx`
+/// responses are retried in rounds, up to each endpoint's `max_attempts` and
+/// with exponential backoff between rounds, as long as the notifications stay
+/// within [`NOTIFY_BUDGET`].
+///
+/// Endpoints with a `bidder` receive the UID synced for that bidder in
+/// `partner_uids`; they are skipped when the subject has none, since the
+/// bidder cannot identify the subject otherwise.
+pub fn notify_partners(
+    settings: &Settings,
+    request: &ErasureRequest,
+    partner_uids: &PartnerUids,
+) -> Vec<PartnerNotification> {
+    let deadline = Deadline::new(Instant::now(), NOTIFY_BUDGET);
+    let mut deliveries: Vec<Delivery<'_>> = settings
+        .gdpr
+        .erasure_endpoints
+        .iter()
+        .map(|endpoint| {
+            let Some(bidder) = &endpoint.bidder else {
+                return Delivery::new(endpoint, request.clone());
+            };
+            let mut delivery = Delivery::new(
+                endpoint,
+                ErasureRequest {
+                    partner_uid: partner_uids.get(bidder).map(|uid| uid.uid.clone()),
+                    ..request.clone()
+                },
+            );
+            if delivery.request.partner_uid.is_none() {
+                delivery.notification.error = Some(format!("No UID synced for bidder {bidder}"));
+            }
+            delivery
+        })
+        .collect();
+
+    let mut round: Vec<&mut Delivery<'_>> = deliveries
+        .iter_mut()
+        .filter(|delivery| delivery.notification.error.is_none())
+        .collect();
+    let mut backoff = RETRY_BACKOFF;
+    loop {
+        send_round(&mut round, deadline);
+        round.retain(|delivery| delivery.should_retry());
+        if round.is_empty() {
+            break;
+        }
+        if backoff >= deadline.remaining() {
+            log::warn!("Erasure notification budget spent, not retrying");
+            break;
+        }
+        std::thread::sleep(backoff);
+        backoff = backoff.saturating_mul(2);
+    }
+
+    deliveries
+        .into_iter()
+        .map(|delivery| {
+            if !delivery.notification.delivered {
+                log::warn!(
+                    "Erasure request {} to {} failed after {} attempt(s): {}",
+                    request.request_id,
+                    delivery.endpoint.name,
+                    delivery.notification.attempts,
+                    delivery.notification.error.as_deref().unwrap_or_default()
+                );
+            }
+            delivery.notification
+        })
+        .collect()
+}
+
+/// Erases the data held for `synthetic_id`.
+///
+/// Deletes the visit count from `synthetic.counter_store`, the opid from
+/// `synthetic.opid_store`, the consent receipts from `gdpr.receipt_store`, the
+/// consent history from `gdpr.consent_history_store`, the identity links
+/// (publisher user IDs and hashed emails) from `identity.link_store` and the
+/// partner UIDs from `user_sync.uid_store`, tombstones the ID when `gdpr.tombstone_store` is set,
+/// and notifies every endpoint in `gdpr.erasure_endpoints` through
+/// [`notify_partners`]. Partner failures are logged and reported in the
+/// summary; they do not fail the erasure.
+///
+/// # Errors
+///
+/// - [`TrustedServerError::KvStore`] if a store cannot be opened or written
+pub fn erase_user_data(
+    settings: &Settings,
+    synthetic_id: &str,
+) -> Result<ErasureSummary, Report<TrustedServerError>> {
+    let counters = JsonKvStore::open(&settings.synthetic.counter_store)?;
+    counters.delete(synthetic_id)?;
+    let opids = JsonKvStore::open(&settings.synthetic.opid_store)?;
+    let opid = opids.get_text(synthetic_id)?;
+    opids.delete(synthetic_id)?;
+    if let Some(receipts) = ConsentReceiptStore::open(settings)? {
+        receipts.delete(synthetic_id)?;
+    }
+    if let Some(history) = ConsentHistoryStore::open(settings)? {
+        history.delete(synthetic_id)?;
+    }
+    if let Some(links) = IdentityStore::open(settings)? {
+        links.delete(synthetic_id)?;
+    }
+    let partner_uids = match PartnerUidStore::open(settings)? {
+        Some(uids) => {
+            let partner_uids = uids.get(synthetic_id)?;
+            uids.delete(synthetic_id)?;
+            partner_uids
+        }
+        None => PartnerUids::new(),
+    };
+
+    let tombstones = TombstoneStore::open(settings)?;
+    if let Some(tombstones) = &tombstones {
+        tombstones.add(synthetic_id)?;
+    }
+    log::info!("Erased data for synthetic ID: {}", synthetic_id);
+
+    let request = ErasureRequest {
+        request_id: Uuid::new_v4().to_string(),
+        timestamp: chrono::Utc::now().timestamp(),
+        synthetic_id: synthetic_id.to_string(),
+        opid,
+        partner_uid: None,
+    };
+    let partners = notify_partners(settings, &request, &partner_uids);
+
+    Ok(ErasureSummary {
+        synthetic_id: synthetic_id.to_string(),
+        tombstoned: tombstones.is_some(),
+        partners,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consent_history::ConsentSnapshot;
+    use crate::consent_receipt::ConsentReceipt;
+    use crate::gdpr::{GdprConsent, UserData};
+    use crate::identity::IdentityKey;
+    use crate::synthetic::SyntheticId;
+    use crate::test_support::tests::create_test_settings;
+
+    fn tombstone_settings() -> Settings {
+        let mut settings = create_test_settings();
+        settings.gdpr.tombstone_store = "test_tombstone_store".to_string();
+        settings
+    }
+
+    #[test]
+    fn test_tombstone_store() {
+        let settings = tombstone_settings();
+        let store = TombstoneStore::open(&settings)
+            .expect("should open the tombstone store")
+            .expect("should have a tombstone store configured");
+        assert_eq!(
+            store
+                .get("tombstone-synthetic")
+                .expect("should read the store"),
+            None
+        );
+
+        store
+            .add("tombstone-synthetic")
+            .expect("should write to the store");
+        assert!(store
+            .get("tombstone-synthetic")
+            .expect("should read the store")
+            .is_some());
+        assert!(is_tombstoned(&settings, "tombstone-synthetic"));
+        assert!(!is_tombstoned(
+            &create_test_settings(),
+            "tombstone-synthetic"
+        ));
+    }
+
+    #[test]
+    fn test_erase_user_data() {
+        let settings = tombstone_settings();
+        let counters =
+            JsonKvStore::open(&settings.synthetic.counter_store).expect("should open the KV store");
+        counters
+            .put("erased-synthetic", &7)
+            .expect("should write to the store");
+        let opids = fastly::KVStore::open(&settings.synthetic.opid_store)
+            .expect("should open the KV store")
+            .expect("should have a KV store configured");
+        opids
+            .insert("erased-synthetic", "opid-456")
+            .expect("should write to the store");
+
+        let summary =
+            erase_user_data(&settings, "erased-synthetic").expect("should erase the user data");
+        assert!(summary.tombstoned);
+        assert!(summary.partners.is_empty());
+        assert_eq!(
+            counters
+                .get_text("erased-synthetic")
+                .expect("should read the store"),
+            None
+        );
+        assert_eq!(
+            JsonKvStore::open(&settings.synthetic.opid_store)
+                .expect("should open the KV store")
+                .get_text("erased-synthetic")
+                .expect("should read the store"),
+            None
+        );
+        assert!(is_tombstoned(&settings, "erased-synthetic"));
+    }
+
+    #[test]
+    fn test_erase_user_data_leaves_nothing_to_export() {
+        let mut settings = tombstone_settings();
+        settings.gdpr.receipt_store = "test_receipt_store".to_string();
+        settings.gdpr.consent_history_store = "test_history_store".to_string();
+        settings.identity.link_store = "test_identity_store".to_string();
+        let subject = "fully-erased-synthetic";
+        let consent = GdprConsent {
+            advertising: true,
+            ..Default::default()
+        };
+        ConsentReceiptStore::open(&settings)
+            .expect("should open the consent receipt store")
+            .expect("should have a consent receipt store configured")
+            .append(subject, ConsentReceipt::new(&consent, None))
+            .expect("should write to the store");
+        ConsentHistoryStore::open(&settings)
+            .expect("should open the consent history store")
+            .expect("should have a consent history store configured")
+            .append(subject, ConsentSnapshot::new(&consent, None))
+            .expect("should write to the store");
+        let links = IdentityStore::open(&settings)
+            .expect("should open the identity store")
+            .expect("should have an identity store configured");
+        let hem = IdentityKey::HashedEmail("ab".repeat(32));
+        let synthetic_id = SyntheticId::new(subject.to_string());
+        links
+            .link(
+                &synthetic_id,
+                &IdentityKey::PublisherUserId("erased-user".into()),
+            )
+            .expect("should write to the store");
+        links
+            .link(&synthetic_id, &hem)
+            .expect("should write to the store");
+        assert!(!UserData::load(&settings, subject)
+            .expect("should load the user data")
+            .records
+            .is_empty());
+
+        erase_user_data(&settings, subject).expect("should erase the user data");
+        assert!(UserData::load(&settings, subject)
+            .expect("should load the user data")
+            .records
+            .is_empty());
+        assert_eq!(links.lookup(&hem).expect("should read the store"), None);
+    }
+
+    fn endpoint(name: &str, url: &str) -> ErasureEndpoint {
+        ErasureEndpoint {
+            name: name.to_string(),
+            url: url.to_string(),
+            backend: "missing_privacy_backend".to_string(),
+            method: ErasureMethod::Delete,
+            bidder: None,
+            auth_token: String::new(),
+            max_attempts: 2,
+        }
+    }
+
+    fn request() -> ErasureRequest {
+        ErasureRequest {
+            request_id: "req-1".to_string(),
+            timestamp: 0,
+            synthetic_id: "abc.def".to_string(),
+            opid: None,
+            partner_uid: Some("uid 1&2".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_erasure_url_substitutes_identifiers() {
+        let endpoint = endpoint(
+            "bidder",
+            "https://privacy.example.com/users/{{partner_uid}}?id={{synthetic_id}}&opid={{opid}}",
+        );
+        assert_eq!(
+            erasure_url(&endpoint, &request()),
+            "https://privacy.example.com/users/uid%201%262?id=abc.def&opid="
+        );
+    }
+
+    #[test]
+    fn test_notify_partners_retries_and_skips() {
+        let mut settings = create_test_settings();
+        let mut bidder = endpoint("bidder", "https://privacy.example.com/{{partner_uid}}");
+        bidder.bidder = Some("examplebidder".to_string());
+        settings.gdpr.erasure_endpoints =
+            vec![endpoint("equativ", "https://privacy.example.com/"), bidder];
+
+        let partners = notify_partners(&settings, &request(), &PartnerUids::new());
+        assert_eq!(partners.len(), 2);
+        assert!(!partners[0].delivered);
+        assert_eq!(partners[0].attempts, 2);
+        assert!(partners[0].error.is_some());
+        assert!(!partners[1].delivered);
+        assert_eq!(partners[1].attempts, 0);
+        assert_eq!(
+            partners[1].error.as_deref(),
+            Some("No UID synced for bidder examplebidder")
+        );
+    }
+}
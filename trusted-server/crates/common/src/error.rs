@@ -43,10 +43,38 @@ pub enum TrustedServerError {
     #[display("Synthetic ID error: {message}")]
     SyntheticId { message: String },
 
+    /// GPP string could not be parsed.
+    #[display("GPP error: {message}")]
+    Gpp { message: String },
+
     /// Prebid integration error.
     #[display("Prebid error: {message}")]
     Prebid { message: String },
 
+    /// UID2 operator request or token handling failed.
+    #[display("UID2 error: {message}")]
+    Uid2 { message: String },
+
+    /// Identity partner request failed.
+    #[display("Identity provider error: {provider} - {message}")]
+    IdentityProvider { provider: String, message: String },
+
+    /// Audience data provider request failed.
+    #[display("Data provider error: {provider} - {message}")]
+    DataProvider { provider: String, message: String },
+
+    /// IAB Global Vendor List fetch or parsing failed.
+    #[display("Vendor list error: {message}")]
+    VendorList { message: String },
+
+    /// Consent webhook delivery failed.
+    #[display("Webhook error: {message}")]
+    Webhook { message: String },
+
+    /// Erasure request to an ad partner failed.
+    #[display("Erasure error: {message}")]
+    Erasure { message: String },
+
     /// Key-value store operation failed.
     #[display("KV store error: {store_name} - {message}")]
     KvStore { store_name: String, message: String },
@@ -77,7 +105,14 @@ impl IntoHttpResponse for TrustedServerError {
             Self::InvalidHeaderValue { .. } => StatusCode::BAD_REQUEST,
             Self::GdprConsent { .. } => StatusCode::BAD_REQUEST,
             Self::SyntheticId { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Gpp { .. } => StatusCode::BAD_REQUEST,
             Self::Prebid { .. } => StatusCode::BAD_GATEWAY,
+            Self::Uid2 { .. } => StatusCode::BAD_GATEWAY,
+            Self::IdentityProvider { .. } => StatusCode::BAD_GATEWAY,
+            Self::DataProvider { .. } => StatusCode::BAD_GATEWAY,
+            Self::VendorList { .. } => StatusCode::BAD_GATEWAY,
+            Self::Webhook { .. } => StatusCode::BAD_GATEWAY,
+            Self::Erasure { .. } => StatusCode::BAD_GATEWAY,
             Self::KvStore { .. } => StatusCode::SERVICE_UNAVAILABLE,
             Self::Template { .. } => StatusCode::INTERNAL_SERVER_ERROR,
         }
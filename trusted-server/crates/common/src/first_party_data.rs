@@ -0,0 +1,211 @@
+//! Publisher first-party data for auctions and ad server requests.
+//!
+//! Publishers send [`FirstPartyData`] with an auction: page context
+//! (content categories and keywords) and the visitor's audience segments from
+//! their own data providers. [`FirstPartyData::apply_to_bid_request`] maps the
+//! page context into `site.content` and `site.keywords` and the segments into
+//! `user.data` of the OpenRTB request, and
+//! [`crate::gam_builder::DynamicGamBuilder`] sends both in the GAM
+//! `cust_params`. Audience segments describe the visitor, so they are only
+//! sent when the consent decision allows personalized advertising.
+
+use error_stack::Report;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::error::TrustedServerError;
+
+/// Most values accepted in each list of first-party data.
+const MAX_VALUES: usize = 100;
+
+/// Longest accepted category, keyword or segment ID.
+const MAX_VALUE_LENGTH: usize = 100;
+
+/// Ad server key carrying the page categories.
+pub const KEY_CATEGORIES: &str = "fpd_cat";
+/// Ad server key carrying the page keywords.
+pub const KEY_KEYWORDS: &str = "fpd_kw";
+/// Ad server key carrying the audience segment IDs.
+pub const KEY_SEGMENTS: &str = "fpd_seg";
+
+/// Audience segments of one data provider.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SegmentGroup {
+    /// Name of the data provider, sent as `user.data.name`
+    pub provider: String,
+    /// Segment taxonomy ID (`segtax`) of the segment IDs, if any
+    #[serde(default)]
+    pub segtax: Option<u16>,
+    /// Segment IDs the visitor belongs to
+    pub ids: Vec<String>,
+}
+
+/// Page context and audience segments supplied by the publisher page.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FirstPartyData {
+    /// Content categories of the page
+    #[serde(default)]
+    pub categories: Vec<String>,
+    /// Content taxonomy ID (`segtax`) of `categories`, such as 7 for IAB
+    /// Content Taxonomy 3.0
+    #[serde(default)]
+    pub category_taxonomy: Option<u16>,
+    /// Keywords of the page
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    /// Audience segments of the visitor, grouped by data provider
+    #[serde(default)]
+    pub segments: Vec<SegmentGroup>,
+}
+
+impl FirstPartyData {
+    /// Checks that every list holds at most 100 non-empty values of at most
+    /// 100 characters and that each segment group names its provider.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::Prebid`] naming the first invalid field
+    pub fn validate(&self) -> Result<(), Report<TrustedServerError>> {
+        let invalid = |field: &str| {
+            Report::new(TrustedServerError::Prebid {
+                message: format!("Invalid first-party data: {field}"),
+            })
+        };
+        let valid = |values: &[String]| {
+            values.len() <= MAX_VALUES
+                && values
+                    .iter()
+                    .all(|v| !v.is_empty() && v.len() <= MAX_VALUE_LENGTH)
+        };
+
+        if !valid(&self.categories) {
+            return Err(invalid("categories"));
+        }
+        if !valid(&self.keywords) {
+            return Err(invalid("keywords"));
+        }
+        if self.segments.len() > MAX_VALUES {
+            return Err(invalid("segments"));
+        }
+        for group in &self.segments {
+            if group.provider.is_empty() || group.ids.is_empty() || !valid(&group.ids) {
+                return Err(invalid("segments"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the page context to `site` of an OpenRTB bid request and, when
+    /// `personalized`, the audience segments to `user.data`.
+    pub fn apply_to_bid_request(&self, bid_request: &mut Value, personalized: bool) {
+        if !self.categories.is_empty() {
+            let mut data = json!({
+                "name": "publisher",
+                "segment": segments(&self.categories),
+            });
+            if let Some(segtax) = self.category_taxonomy {
+                data["ext"] = json!({ "segtax": segtax });
+            }
+            bid_request["site"]["content"]["data"] = json!([data]);
+        }
+        if !self.keywords.is_empty() {
+            let keywords = self.keywords.join(",");
+            bid_request["site"]["keywords"] = json!(keywords);
+            bid_request["site"]["content"]["keywords"] = json!(keywords);
+        }
+        if personalized && !self.segments.is_empty() {
+            let data: Vec<Value> = self
+                .segments
+                .iter()
+                .map(|group| {
+                    let mut data = json!({
+                        "name": group.provider,
+                        "segment": segments(&group.ids),
+                    });
+                    if let Some(segtax) = group.segtax {
+                        data["ext"] = json!({ "segtax": segtax });
+                    }
+                    data
+                })
+                .collect();
+            bid_request["user"]["data"] = json!(data);
+        }
+    }
+}
+
+/// OpenRTB `segment` objects for `ids`.
+fn segments(ids: &[String]) -> Vec<Value> {
+    ids.iter().map(|id| json!({ "id": id })).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn first_party_data() -> FirstPartyData {
+        FirstPartyData {
+            categories: vec!["483".to_string(), "488".to_string()],
+            category_taxonomy: Some(7),
+            keywords: vec!["electric cars".to_string(), "reviews".to_string()],
+            segments: vec![SegmentGroup {
+                provider: "publisher.example".to_string(),
+                segtax: Some(4),
+                ids: vec!["101".to_string(), "205".to_string()],
+            }],
+        }
+    }
+
+    #[test]
+    fn test_apply_to_bid_request() {
+        let mut bid_request = json!({ "site": { "page": "https://publisher.example" } });
+        first_party_data().apply_to_bid_request(&mut bid_request, true);
+        assert_eq!(
+            bid_request,
+            json!({
+                "site": {
+                    "page": "https://publisher.example",
+                    "keywords": "electric cars,reviews",
+                    "content": {
+                        "keywords": "electric cars,reviews",
+                        "data": [{
+                            "name": "publisher",
+                            "segment": [{ "id": "483" }, { "id": "488" }],
+                            "ext": { "segtax": 7 }
+                        }]
+                    }
+                },
+                "user": {
+                    "data": [{
+                        "name": "publisher.example",
+                        "segment": [{ "id": "101" }, { "id": "205" }],
+                        "ext": { "segtax": 4 }
+                    }]
+                }
+            })
+        );
+
+        let mut bid_request = json!({ "user": { "id": "5280" } });
+        first_party_data().apply_to_bid_request(&mut bid_request, false);
+        assert_eq!(bid_request["user"], json!({ "id": "5280" }));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(first_party_data().validate().is_ok());
+        assert!(FirstPartyData::default().validate().is_ok());
+
+        let mut data = first_party_data();
+        data.keywords.push(String::new());
+        assert!(data.validate().is_err());
+
+        let mut data = first_party_data();
+        data.categories = vec!["1".to_string(); MAX_VALUES + 1];
+        assert!(data.validate().is_err());
+
+        let mut data = first_party_data();
+        data.segments[0].provider.clear();
+        assert!(data.validate().is_err());
+    }
+}
@@ -1,26 +1,54 @@
+use crate::consent::ConsentDecision;
+use crate::constants::HEADER_X_GEO_COORDINATES;
+use crate::creative_frame::{CreativeFrameStore, CREATIVE_SANDBOX};
+use crate::creative_rewrite::rewrite_creative;
+use crate::data_provider::{response, DataProviderManager};
+use crate::device::viewport_width;
+use crate::first_party_data::FirstPartyData;
+use crate::gam_builder::{
+    consent_params, parse_pvsid, ppid, random_request_id, AdUnitConfig, DynamicGamBuilder,
+    GamConfigTemplate, PageContext, RequestContext, VideoAdRequest,
+};
+use crate::house_ad::{house_ad, house_ad_response};
+use crate::native::escape;
 use crate::settings::Settings;
-use crate::tcf_consent::get_tcf_consent_from_request;
+use crate::tcf_consent::vendor_list_manager::load_vendor_list;
+use crate::tcf_consent::AdvertisingConsentLevel;
 use fastly::http::{header, Method, StatusCode};
 use fastly::{Error, Request, Response};
+use serde::Deserialize;
 use serde_json::json;
-use std::collections::HashMap;
-use uuid::Uuid;
+use std::collections::BTreeMap;
 
 /// GAM request builder for server-side ad requests
+#[derive(Clone)]
 pub struct GamRequest {
-    pub publisher_id: String,
-    pub ad_units: Vec<String>,
     pub page_url: String,
+    /// Page view ID, from the `pvsid` query parameter of the page's requests
+    pub pvsid: String,
     pub correlator: String,
-    pub prmtvctx: Option<String>, // Permutive context - initially hardcoded, then dynamic
     pub user_agent: String,
     pub synthetic_id: String,
+    /// Precise `lat,long` coordinates, only set with a precise geolocation opt-in
+    pub geo_coordinates: Option<String>,
+    /// Builder of the ad request URL
+    pub builder: DynamicGamBuilder,
 }
 
 impl GamRequest {
-    /// Create a new GAM request with default parameters
-    pub fn new(settings: &Settings, req: &Request) -> Result<Self, Error> {
-        let correlator = Uuid::new_v4().to_string();
+    /// Create a new GAM request for the ad units of the settings
+    pub fn new(
+        settings: &Settings,
+        consent: &ConsentDecision,
+        req: &Request,
+    ) -> Result<Self, Error> {
+        // The page passes its pvsid with every ad request of a page view;
+        // the correlator is new for each request
+        let pvsid = req
+            .get_query_parameter("pvsid")
+            .and_then(parse_pvsid)
+            .unwrap_or_else(random_request_id);
+        let correlator = random_request_id();
         let page_url = req.get_url().to_string();
         let user_agent = req
             .get_header(header::USER_AGENT)
@@ -35,98 +63,104 @@ impl GamRequest {
             .unwrap_or("unknown")
             .to_string();
 
+        let geo_coordinates = if consent.precise_geolocation_allowed {
+            req.get_header_str(HEADER_X_GEO_COORDINATES)
+                .map(|c| c.to_string())
+        } else {
+            None
+        };
+
+        let page = PageContext {
+            url: page_url.clone(),
+            viewport_width: viewport_width(req),
+            ..Default::default()
+        };
+        let context = RequestContext {
+            user_id: synthetic_id.clone(),
+            pvsid: pvsid.clone(),
+            correlator: correlator.clone(),
+            child_directed: consent.child_directed,
+            consent_level: advertising_consent_level(settings, consent),
+            ppid: ppid(settings, consent, &synthetic_id),
+            consent_params: consent_params(consent),
+        };
+        let builder =
+            DynamicGamBuilder::new(GamConfigTemplate::from_settings(settings), page, context);
+
         Ok(Self {
-            publisher_id: settings.gam.publisher_id.clone(),
-            ad_units: settings
-                .gam
-                .ad_units
-                .iter()
-                .map(|u| u.name.clone())
-                .collect(),
             page_url,
+            pvsid,
             correlator,
-            prmtvctx: None, // Will be set later with captured value
             user_agent,
             synthetic_id,
+            geo_coordinates,
+            builder,
         })
     }
 
-    /// Set the Permutive context (initially hardcoded from captured request)
-    pub fn with_prmtvctx(mut self, prmtvctx: String) -> Self {
-        self.prmtvctx = Some(prmtvctx);
+    /// Add the segments of the data providers the request allows, looked up
+    /// concurrently (see [`DataProviderManager::collect`])
+    pub async fn with_audience_segments(
+        mut self,
+        settings: &Settings,
+        consent: &ConsentDecision,
+    ) -> Self {
+        let audience = DataProviderManager::from_settings(settings, consent)
+            .collect(&self.synthetic_id)
+            .await;
+        self.builder = self.builder.with_audience_segments(&audience);
         self
     }
 
-    /// Build the GAM request URL for the "Golden URL" replay phase
-    pub fn build_golden_url(&self) -> String {
-        // This will be replaced with the actual captured URL from autoblog.com
-        // For now, using a template based on the captured Golden URL
-        let mut params = HashMap::new();
-
-        // Core GAM parameters (based on captured URL)
-        params.insert("pvsid".to_string(), "3290837576990024".to_string()); // Publisher Viewability ID
-        params.insert("correlator".to_string(), self.correlator.clone());
-        params.insert(
-            "eid".to_string(),
-            "31086815,31093089,95353385,31085777,83321072".to_string(),
-        ); // Event IDs
-        params.insert("output".to_string(), "ldjh".to_string()); // Important: not 'json'
-        params.insert("gdfp_req".to_string(), "1".to_string());
-        params.insert("vrg".to_string(), "202506170101".to_string()); // Version/Region
-        params.insert("ptt".to_string(), "17".to_string()); // Page Type
-        params.insert("impl".to_string(), "fifs".to_string()); // Implementation
-
-        // Ad unit parameters (simplified version of captured format)
-        params.insert(
-            "iu_parts".to_string(),
-            format!("{},{},homepage", self.publisher_id, "trustedserver"),
-        );
-        params.insert(
-            "enc_prev_ius".to_string(),
-            "/0/1/2,/0/1/2,/0/1/2".to_string(),
-        );
-        params.insert("prev_iu_szs".to_string(), "320x50|300x250|728x90|970x90|970x250|1x2,320x50|300x250|728x90|970x90|970x250|1x2,320x50|300x250|728x90|970x90|970x250|1x2".to_string());
-        params.insert("fluid".to_string(), "height,height,height".to_string());
-
-        // Browser context (simplified)
-        params.insert("biw".to_string(), "1512".to_string());
-        params.insert("bih".to_string(), "345".to_string());
-        params.insert("u_tz".to_string(), "-300".to_string());
-        params.insert("u_cd".to_string(), "30".to_string());
-        params.insert("u_sd".to_string(), "2".to_string());
-
-        // Page context
-        params.insert("url".to_string(), self.page_url.clone());
-        params.insert(
-            "dt".to_string(),
-            chrono::Utc::now().timestamp_millis().to_string(),
-        );
+    /// Request `ad_units` instead of the ad units of the settings
+    pub fn with_ad_units(mut self, ad_units: Vec<AdUnitConfig>) -> Self {
+        self.builder = self.builder.with_ad_units(ad_units);
+        self
+    }
 
-        // Add Permutive context if available (in cust_params like the captured URL)
-        if let Some(ref prmtvctx) = self.prmtvctx {
-            let cust_params = format!("permutive={}&puid={}", prmtvctx, self.synthetic_id);
-            params.insert("cust_params".to_string(), cust_params);
-        }
+    /// Add the header bidding key-values (see [`crate::targeting::targeting_keys`])
+    pub fn with_targeting(mut self, targeting: BTreeMap<String, String>) -> Self {
+        self.builder = self.builder.with_targeting(targeting);
+        self
+    }
 
-        // Build query string
-        let query_string = params
-            .iter()
-            .map(|(k, v)| format!("{}={}", k, urlencoding::encode(v)))
-            .collect::<Vec<_>>()
-            .join("&");
+    /// Add the page context and segments of the publisher's first-party data
+    pub fn with_first_party_data(mut self, data: FirstPartyData) -> Self {
+        self.builder = self.builder.with_first_party_data(data);
+        self
+    }
 
-        format!("{}?{}", self.get_base_url(), query_string)
+    /// Sizes of the requested ad units, without duplicates
+    pub fn requested_sizes(&self) -> Vec<String> {
+        let mut sizes: Vec<String> = Vec::new();
+        for ad_unit in &self.builder.ad_units() {
+            for size in &ad_unit.sizes {
+                if !sizes.contains(size) {
+                    sizes.push(size.clone());
+                }
+            }
+        }
+        sizes
     }
 
-    /// Get the base GAM server URL
-    pub fn get_base_url(&self) -> String {
-        // This will be updated with the actual GAM endpoint from captured request
-        "https://securepubads.g.doubleclick.net/gampad/ads".to_string()
+    /// Build the GAM ad request URL
+    pub fn build_url(&self) -> String {
+        self.builder.build_url()
     }
 
     /// Send the GAM request and return the response
     pub async fn send_request(&self, _settings: &Settings) -> Result<Response, Error> {
-        let url = self.build_golden_url();
+        self.send(self.build_url()).await
+    }
+
+    /// Send the video ad request for `video` and return the VAST response
+    pub async fn send_video_request(&self, video: &VideoAdRequest) -> Result<Response, Error> {
+        let mut response = self.send(self.builder.build_video_url(video)).await?;
+        response.set_header(header::CONTENT_TYPE, "application/xml; charset=utf-8");
+        Ok(response)
+    }
+
+    async fn send(&self, url: String) -> Result<Response, Error> {
         log::info!("Sending GAM request to: {}", url);
 
         // Create the request
@@ -139,13 +173,23 @@ impl GamRequest {
         req.set_header(header::ACCEPT_ENCODING, "gzip, deflate, br");
         req.set_header(header::REFERER, &self.page_url);
         req.set_header(header::ORIGIN, &self.page_url);
-        req.set_header("X-Synthetic-ID", &self.synthetic_id);
+        if self.builder.context().is_personalized() {
+            req.set_header("X-Synthetic-ID", &self.synthetic_id);
+        }
+        if let Some(coordinates) = &self.geo_coordinates {
+            req.set_header(HEADER_X_GEO_COORDINATES, coordinates);
+        }
 
         // Send the request to the GAM backend
         let backend_name = "gam_backend";
         log::info!("Sending request to backend: {}", backend_name);
 
-        match req.send(backend_name) {
+        // Sent asynchronously so that requests for several slots overlap
+        let result = match req.send_async(backend_name) {
+            Ok(pending) => response(pending).await,
+            Err(e) => Err(e),
+        };
+        match result {
             Ok(mut response) => {
                 log::info!(
                     "Received GAM response with status: {}",
@@ -211,6 +255,7 @@ impl GamRequest {
                     .with_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
                     .with_header("X-GAM-Test", "true")
                     .with_header("X-Synthetic-ID", &self.synthetic_id)
+                    .with_header("X-Pvsid", &self.pvsid)
                     .with_header("X-Correlator", &self.correlator)
                     .with_header("x-compress-hint", "on")
                     .with_body(body))
@@ -223,8 +268,43 @@ impl GamRequest {
     }
 }
 
+/// Creative HTML of a GAM ad response, which follows the JSON of the ad unit:
+/// `{"/ad_unit_path":["html",0,null,...,"creative_id","line_item_id"],"<!doctype html>..."}`
+pub(crate) fn extract_creative(response_body: &str) -> Option<&str> {
+    response_body
+        .find("<!doctype html>")
+        .map(|start| &response_body[start..])
+}
+
+/// Ads GAM may serve, from Google's TCF consent where GDPR applies:
+/// personalized ads also need personalized advertising to be allowed,
+/// non-personalized ads (`npa=1`) need consent to select basic ads, and
+/// otherwise limited ads (`ltd=1`) are requested.
+fn advertising_consent_level(
+    settings: &Settings,
+    consent: &ConsentDecision,
+) -> AdvertisingConsentLevel {
+    let tcf = &consent.signals.tcf;
+    let level = if tcf.gdpr_applies {
+        let vendor_list = load_vendor_list(settings);
+        tcf.get_advertising_consent_level(settings.gam.vendor_id, vendor_list.as_ref())
+    } else {
+        AdvertisingConsentLevel::Personalized
+    };
+    match level {
+        AdvertisingConsentLevel::Personalized if !consent.is_personalized() => {
+            AdvertisingConsentLevel::BasicOnly
+        }
+        level => level,
+    }
+}
+
 /// Handle GAM test requests (Phase 1: Capture & Replay)
-pub async fn handle_gam_test(settings: &Settings, req: Request) -> Result<Response, Error> {
+pub async fn handle_gam_test(
+    settings: &Settings,
+    consent: &ConsentDecision,
+    req: Request,
+) -> Result<Response, Error> {
     log::info!("Starting GAM test request handling");
 
     // Debug: Log all request headers
@@ -233,41 +313,24 @@ pub async fn handle_gam_test(settings: &Settings, req: Request) -> Result<Respon
         log::debug!("  {}: {:?}", name, value);
     }
 
-    // Extract TCF consent from euconsent-v2 cookie
-    let tcf_consent = get_tcf_consent_from_request(&req).unwrap_or_default();
-    
-    // TODO: For GAM, should read Google Consent Mode status (g111, g101, g100) instead of TCF
-    // Google has their own consent framework separate from IAB TCF
-    // For demo purposes, checking basic advertising consent (Purpose 2: Select basic ads)
-    // GAM works with multiple vendors so we check purpose-level consent
-    let advertising_consent = tcf_consent.purpose_consents.get(&2).unwrap_or(&false);
-    
-    log::debug!("GAM Test - TCF GDPR applies: {}", tcf_consent.gdpr_applies);
-    log::debug!("GAM Test - TCF purpose consents: {:?}", tcf_consent.purpose_consents);
-    log::debug!("GAM Test - Basic advertising consent (Purpose 2): {}", advertising_consent);
+    // The request carries the TCF, Additional Consent and Consent Mode state (see
+    // `consent_params`), and its mode follows Google's TCF consent
+    let tcf_consent = &consent.signals.tcf;
+    let consent_level = advertising_consent_level(settings, consent);
 
-    let final_consent = *advertising_consent;
-    log::info!("GAM Test - Final advertising consent: {}", final_consent);
-
-    if !final_consent {
-        return Ok(Response::from_status(StatusCode::OK)
-            .with_header(header::CONTENT_TYPE, "application/json")
-            .with_body_json(&json!({
-                "error": "No advertising consent",
-                "message": "GAM requests require advertising consent",
-                "debug": {
-                    "tcf_gdpr_applies": tcf_consent.gdpr_applies,
-                    "tcf_purpose_consents": tcf_consent.purpose_consents,
-                    "final_consent": final_consent
-                }
-            }))?);
-    }
+    log::debug!("GAM Test - TCF GDPR applies: {}", tcf_consent.gdpr_applies);
+    log::debug!(
+        "GAM Test - TCF purpose consents: {:?}",
+        tcf_consent.purpose_consents
+    );
+    log::debug!("GAM Test - Reasons: {:?}", consent.reasons);
+    log::info!("GAM Test - Advertising consent level: {:?}", consent_level);
 
     // Create GAM request
-    let gam_req = match GamRequest::new(settings, &req) {
+    let gam_req = match GamRequest::new(settings, consent, &req) {
         Ok(req) => {
             log::info!("Successfully created GAM request");
-            req
+            req.with_audience_segments(settings, consent).await
         }
         Err(e) => {
             log::error!("Error creating GAM request: {:?}", e);
@@ -280,22 +343,30 @@ pub async fn handle_gam_test(settings: &Settings, req: Request) -> Result<Respon
         }
     };
 
-    // For Phase 1, we'll use a hardcoded prmtvctx value from captured request
-    // This will be replaced with the actual value from autoblog.com
-    let gam_req_with_context = gam_req.with_prmtvctx("129627,137412,138272,139095,139096,139218,141364,143196,143210,143211,143214,143217,144331,144409,144438,144444,144488,144543,144663,144679,144731,144824,144916,145933,146347,146348,146349,146350,146351,146370,146383,146391,146392,146393,146424,146995,147077,147740,148616,148627,148628,149007,150420,150663,150689,150690,150692,150752,150753,150755,150756,150757,150764,150770,150781,150862,154609,155106,155109,156204,164183,164573,165512,166017,166019,166484,166486,166487,166488,166492,166494,166495,166497,166511,167639,172203,172544,173548,176066,178053,178118,178120,178121,178133,180321,186069,199642,199691,202074,202075,202081,233782,238158,adv,bhgp,bhlp,bhgw,bhlq,bhlt,bhgx,bhgv,bhgu,bhhb,rts".to_string());
-
     log::info!(
         "Sending GAM request with correlator: {}",
-        gam_req_with_context.correlator
+        gam_req.correlator
     );
 
-    match gam_req_with_context.send_request(settings).await {
-        Ok(response) => {
+    match gam_req.send_request(settings).await {
+        Ok(mut response) => {
+            let body = response.take_body_str();
+            let filled = response.get_status().is_success() && extract_creative(&body).is_some();
+            if !filled {
+                if let Some(ad) = house_ad(settings, &gam_req.requested_sizes()) {
+                    log::warn!("GAM returned no creative, serving {} house ad", ad.size);
+                    return Ok(house_ad_response(&ad));
+                }
+            }
             log::info!("GAM request successful");
-            Ok(response)
+            Ok(response.with_body(body))
         }
         Err(e) => {
             log::error!("GAM request failed: {:?}", e);
+            if let Some(ad) = house_ad(settings, &gam_req.requested_sizes()) {
+                log::warn!("Serving {} house ad", ad.size);
+                return Ok(house_ad_response(&ad));
+            }
             Ok(Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
                 .with_header(header::CONTENT_TYPE, "application/json")
                 .with_body_json(&json!({
@@ -319,7 +390,7 @@ pub async fn handle_gam_golden_url(_settings: &Settings, _req: Request) -> Resul
             "message": "Ready for captured URL testing",
             "next_steps": [
                 "1. Capture complete GAM request URL from autoblog.com",
-                "2. Replace placeholder URL in GamRequest::build_golden_url()",
+                "2. Compare it with the URL built by GamRequest::build_url()",
                 "3. Test with exact captured parameters"
             ]
         }))?)
@@ -327,17 +398,14 @@ pub async fn handle_gam_golden_url(_settings: &Settings, _req: Request) -> Resul
 
 /// Handle GAM custom URL testing (for testing captured URLs directly)
 pub async fn handle_gam_custom_url(
-    _settings: &Settings,
+    settings: &Settings,
+    consent: &ConsentDecision,
     mut req: Request,
 ) -> Result<Response, Error> {
     log::info!("Handling GAM custom URL test");
 
-    // TODO: For GAM, should read Google Consent Mode status (g111, g101, g100) instead of TCF
-    // Extract TCF consent from euconsent-v2 cookie for demo purposes
-    let tcf_consent = get_tcf_consent_from_request(&req).unwrap_or_default();
-    let advertising_consent = tcf_consent.purpose_consents.get(&2).unwrap_or(&false);
-
-    if !advertising_consent {
+    // Captured URLs are replayed as is, so they need consent to personalized ads
+    if advertising_consent_level(settings, consent) != AdvertisingConsentLevel::Personalized {
         return Ok(Response::from_status(StatusCode::OK)
             .with_header(header::CONTENT_TYPE, "application/json")
             .with_body_json(&json!({
@@ -456,26 +524,18 @@ pub async fn handle_gam_custom_url(
 }
 
 /// Handle GAM response rendering in iframe
-pub async fn handle_gam_render(settings: &Settings, req: Request) -> Result<Response, Error> {
+pub async fn handle_gam_render(
+    settings: &Settings,
+    consent: &ConsentDecision,
+    req: Request,
+) -> Result<Response, Error> {
     log::info!("Handling GAM response rendering");
 
-    // TODO: For GAM, should read Google Consent Mode status (g111, g101, g100) instead of TCF
-    // Extract TCF consent from euconsent-v2 cookie for demo purposes
-    let tcf_consent = get_tcf_consent_from_request(&req).unwrap_or_default();
-    let advertising_consent = tcf_consent.purpose_consents.get(&2).unwrap_or(&false);
-
-    if !advertising_consent {
-        return Ok(Response::from_status(StatusCode::OK)
-            .with_header(header::CONTENT_TYPE, "application/json")
-            .with_body_json(&json!({
-                "error": "No advertising consent",
-                "message": "GAM requests require advertising consent"
-            }))?);
-    }
+    // Requests without consent to personalized ads ask for non-personalized or limited ads
 
     // Create GAM request and get response
-    let gam_req = match GamRequest::new(settings, &req) {
-        Ok(req) => req.with_prmtvctx("129627,137412,138272,139095,139096,139218,141364,143196,143210,143211,143214,143217,144331,144409,144438,144444,144488,144543,144663,144679,144731,144824,144916,145933,146347,146348,146349,146350,146351,146370,146383,146391,146392,146393,146424,146995,147077,147740,148616,148627,148628,149007,150420,150663,150689,150690,150692,150752,150753,150755,150756,150757,150764,150770,150781,150862,154609,155106,155109,156204,164183,164573,165512,166017,166019,166484,166486,166487,166488,166492,166494,166495,166497,166511,167639,172203,172544,173548,176066,178053,178118,178120,178121,178133,180321,186069,199642,199691,202074,202075,202081,233782,238158,adv,bhgp,bhlp,bhgw,bhlq,bhlt,bhgx,bhgv,bhgu,bhhb,rts".to_string()),
+    let gam_req = match GamRequest::new(settings, consent, &req) {
+        Ok(req) => req.with_audience_segments(settings, consent).await,
         Err(e) => {
             return Ok(Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
                 .with_header(header::CONTENT_TYPE, "application/json")
@@ -487,9 +547,26 @@ pub async fn handle_gam_render(settings: &Settings, req: Request) -> Result<Resp
     };
 
     // Get GAM response
-    let gam_response = match gam_req.send_request(settings).await {
-        Ok(response) => response,
-        Err(e) => {
+    let (succeeded, response_body) = match gam_req.send_request(settings).await {
+        Ok(response) => (
+            response.get_status().is_success(),
+            Ok(response.into_body_str()),
+        ),
+        Err(e) => (false, Err(e)),
+    };
+
+    // Slots GAM fails to fill get a house ad instead
+    let fallback = match &response_body {
+        Ok(body) if succeeded && extract_creative(body).is_some() => None,
+        _ => house_ad(settings, &gam_req.requested_sizes()),
+    };
+
+    let html_content = match (&fallback, response_body) {
+        (Some(ad), _) => {
+            log::warn!("GAM returned no creative, rendering {} house ad", ad.size);
+            ad.html.clone()
+        }
+        (None, Err(e)) => {
             return Ok(Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
                 .with_header(header::CONTENT_TYPE, "application/json")
                 .with_body_json(&json!({
@@ -497,31 +574,46 @@ pub async fn handle_gam_render(settings: &Settings, req: Request) -> Result<Resp
                     "details": format!("{:?}", e)
                 }))?);
         }
+        (None, Ok(response_body)) => {
+            log::info!("Parsing GAM response for HTML extraction");
+            match extract_creative(&response_body) {
+                Some(html) => {
+                    log::debug!("Extracted HTML content: {} bytes", html.len());
+                    html.to_string()
+                }
+                // Fallback: return the raw response in a safe HTML wrapper
+                None => format!(
+                    "<html><body><p>GAM Response (no HTML found):</p><pre>{}</pre></body></html>",
+                    escape(&response_body.chars().take(1000).collect::<String>())
+                ),
+            }
+        }
     };
-
-    // Parse the GAM response to extract HTML
-    let response_body = gam_response.into_body_str();
-    log::info!("Parsing GAM response for HTML extraction");
-
-    // The GAM response format is: {"/ad_unit_path":["html",0,null,null,0,90,728,0,0,null,null,null,null,null,[...],null,null,null,null,null,null,null,0,null,null,null,null,null,null,"creative_id","line_item_id"],"<!doctype html>..."}
-    // We need to extract the HTML part after the JSON array
-
-    let html_content = if response_body.contains("<!doctype html>") {
-        // Find the start of HTML content
-        if let Some(html_start) = response_body.find("<!doctype html>") {
-            let html = &response_body[html_start..];
-            log::debug!("Extracted HTML content: {} bytes", html.len());
-            html.to_string()
-        } else {
-            format!("<html><body><p>Error: Could not find HTML content in GAM response</p><pre>{}</pre></body></html>", 
-                   response_body.chars().take(500).collect::<String>())
+    // Third-party CDN URLs load through first-party hosts and proxy paths
+    let html_content = rewrite_creative(settings, &html_content);
+
+    // The creative is served from its own one-time URL into a sandboxed
+    // iframe instead of being inlined into this page
+    let creative_src = match CreativeFrameStore::open(settings)
+        .and_then(|store| store.map(|store| store.put(&html_content)).transpose())
+    {
+        Ok(Some(src)) => src,
+        Ok(None) => {
+            return Ok(Response::from_status(StatusCode::SERVICE_UNAVAILABLE)
+                .with_header(header::CONTENT_TYPE, "application/json")
+                .with_body_json(&json!({
+                    "error": "Creative rendering is disabled",
+                    "details": "creative_frame.kv_store is not configured"
+                }))?);
+        }
+        Err(e) => {
+            return Ok(Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+                .with_header(header::CONTENT_TYPE, "application/json")
+                .with_body_json(&json!({
+                    "error": "Failed to store creative",
+                    "details": format!("{:?}", e)
+                }))?);
         }
-    } else {
-        // Fallback: return the raw response in a safe HTML wrapper
-        format!(
-            "<html><body><p>GAM Response (no HTML found):</p><pre>{}</pre></body></html>",
-            response_body.chars().take(1000).collect::<String>()
-        )
     };
 
     // Create a safe HTML page that renders the ad content in an iframe
@@ -616,8 +708,8 @@ pub async fn handle_gam_render(settings: &Settings, req: Request) -> Result<Resp
         <iframe 
             id="adFrame" 
             class="ad-frame" 
-            srcdoc="{}"
-            sandbox="allow-scripts allow-same-origin allow-forms allow-popups allow-popups-to-escape-sandbox"
+            src="{}"
+            sandbox="{}"
             title="GAM Ad Content">
         </iframe>
         
@@ -651,17 +743,280 @@ pub async fn handle_gam_render(settings: &Settings, req: Request) -> Result<Resp
 </html>"#,
         html_content.len(),
         chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC"),
-        html_content.replace("\"", "&quot;").replace("'", "&#39;"),
+        creative_src,
+        CREATIVE_SANDBOX,
         html_content.len(),
-        html_content.chars().take(200).collect::<String>()
+        escape(&html_content.chars().take(200).collect::<String>())
     );
 
-    Ok(Response::from_status(StatusCode::OK)
+    let mut response = Response::from_status(StatusCode::OK)
         .with_header(header::CONTENT_TYPE, "text/html; charset=utf-8")
         .with_header(header::CACHE_CONTROL, "no-store, private")
         .with_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
         .with_header("X-GAM-Render", "true")
         .with_header("X-Synthetic-ID", &gam_req.synthetic_id)
+        .with_header("X-Pvsid", &gam_req.pvsid)
         .with_header("X-Correlator", &gam_req.correlator)
-        .with_body(render_page))
+        .with_body(render_page);
+    if let Some(ad) = &fallback {
+        response.set_header("X-House-Ad", &ad.size);
+    }
+    Ok(response)
+}
+
+/// Query parameters of `/gam-vast` requests.
+#[derive(Deserialize)]
+struct VastParams {
+    iu: Option<String>,
+    sz: Option<String>,
+    description_url: Option<String>,
+    cmsid: Option<String>,
+    vid: Option<String>,
+}
+
+/// Video ad request of a `/gam-vast` request, from its parameters:
+/// - `iu`: ad unit name or path, `gam.video_ad_unit` by default
+/// - `sz`: player sizes as `<w>x<h>` separated by `|`, `gam.video_sizes` by
+///   default
+/// - `description_url`: page describing the video, the `Referer` by default
+/// - `cmsid` and `vid`: content source and video IDs, sent together
+fn video_request(settings: &Settings, req: &Request) -> Result<VideoAdRequest, String> {
+    let params: VastParams = req
+        .get_query()
+        .map_err(|e| format!("Invalid video ad parameters: {e}"))?;
+    let param = |value: &Option<String>| {
+        value
+            .as_deref()
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+    };
+    let ad_unit = param(&params.iu)
+        .or(Some(settings.gam.video_ad_unit.clone()).filter(|unit| !unit.is_empty()))
+        .ok_or("Missing video ad unit (iu)")?;
+    let sizes = param(&params.sz).unwrap_or_else(|| settings.gam.video_sizes.clone());
+    let sizes: Vec<String> = sizes.split('|').map(str::to_string).collect();
+    let valid_size = |size: &String| {
+        size.split_once('x').is_some_and(|(w, h)| {
+            w.parse::<u32>().is_ok_and(|w| w > 0) && h.parse::<u32>().is_ok_and(|h| h > 0)
+        })
+    };
+    if let Some(size) = sizes.iter().find(|size| !valid_size(size)) {
+        return Err(format!("Invalid video size {size:?}"));
+    }
+    let description_url = param(&params.description_url)
+        .or_else(|| req.get_header_str(header::REFERER).map(str::to_string))
+        .ok_or("Missing description_url")?;
+    let content = match (param(&params.cmsid), param(&params.vid)) {
+        (Some(cmsid), Some(vid)) => Some((cmsid, vid)),
+        (None, None) => None,
+        _ => return Err("cmsid and vid must be sent together".to_string()),
+    };
+    let mut ad_unit = AdUnitConfig::from_name(&settings.gam.publisher_id, &ad_unit, "");
+    ad_unit.sizes = sizes;
+    Ok(VideoAdRequest {
+        ad_unit,
+        description_url,
+        content,
+    })
+}
+
+/// Handle `GET /gam-vast`: requests a video ad for an instream player and
+/// responds with GAM's VAST XML (see [`video_request`] for the parameters)
+pub async fn handle_gam_vast(
+    settings: &Settings,
+    consent: &ConsentDecision,
+    req: Request,
+) -> Result<Response, Error> {
+    let video = match video_request(settings, &req) {
+        Ok(video) => video,
+        Err(e) => {
+            return Ok(Response::from_status(StatusCode::BAD_REQUEST)
+                .with_header(header::CONTENT_TYPE, "application/json")
+                .with_body_json(&json!({ "error": e }))?);
+        }
+    };
+    log::info!("Requesting GAM video ad for {}", video.ad_unit.path);
+
+    let gam_req = match GamRequest::new(settings, consent, &req) {
+        Ok(req) => req.with_audience_segments(settings, consent).await,
+        Err(e) => {
+            return Ok(Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+                .with_header(header::CONTENT_TYPE, "application/json")
+                .with_body_json(&json!({
+                    "error": "Failed to create GAM request",
+                    "details": format!("{:?}", e)
+                }))?);
+        }
+    };
+    match gam_req.send_video_request(&video).await {
+        Ok(response) => Ok(response),
+        Err(e) => {
+            log::error!("Error sending GAM video request: {:?}", e);
+            Ok(Response::from_status(StatusCode::BAD_GATEWAY)
+                .with_header(header::CONTENT_TYPE, "application/json")
+                .with_body_json(&json!({
+                    "error": "Failed to get GAM VAST response",
+                    "details": format!("{:?}", e)
+                }))?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consent::PersonalizationLevel;
+    use crate::settings::GamAdUnit;
+    use crate::test_support::tests::create_test_settings;
+
+    #[test]
+    fn test_advertising_consent_level() {
+        let settings = create_test_settings();
+        let req = Request::get("https://test.com");
+        let mut consent = ConsentDecision::from_request(&settings, &req);
+        // Outside GDPR, Google needs no TCF vendor consent
+        consent.signals.tcf.gdpr_applies = false;
+        consent.personalization = PersonalizationLevel::Personalized;
+        let level = advertising_consent_level(&settings, &consent);
+        assert_eq!(level, AdvertisingConsentLevel::Personalized);
+
+        consent.personalization = PersonalizationLevel::NonPersonalized;
+        let level = advertising_consent_level(&settings, &consent);
+        assert_eq!(level, AdvertisingConsentLevel::BasicOnly);
+        let gam_req = GamRequest::new(&settings, &consent, &req).unwrap();
+        assert!(gam_req.build_url().contains("&npa=1&"));
+
+        // Without Google's consent to select basic ads where GDPR applies
+        consent.signals.tcf.gdpr_applies = true;
+        let level = advertising_consent_level(&settings, &consent);
+        assert_eq!(level, AdvertisingConsentLevel::None);
+        let gam_req = GamRequest::new(&settings, &consent, &req).unwrap();
+        assert!(gam_req.build_url().contains("&ltd=1&"));
+    }
+
+    #[test]
+    fn test_video_request() {
+        let mut settings = create_test_settings();
+        let req = Request::get(
+            "https://test.com/gam-vast?iu=trustedserver/video&sz=640x480|400x300\
+             &description_url=https%3A%2F%2Ftest.com%2Fvideo&cmsid=2477953&vid=cars",
+        );
+        let video = video_request(&settings, &req).unwrap();
+        assert_eq!(video.ad_unit.path, "/test-publisher-id/trustedserver/video");
+        assert_eq!(video.ad_unit.sizes, vec!["640x480", "400x300"]);
+        assert_eq!(video.description_url, "https://test.com/video");
+        assert_eq!(
+            video.content,
+            Some(("2477953".to_string(), "cars".to_string()))
+        );
+
+        // Defaults from the settings and the Referer
+        let req = Request::get("https://test.com/gam-vast")
+            .with_header(header::REFERER, "https://test.com/article");
+        assert!(video_request(&settings, &req).is_err());
+        settings.gam.video_ad_unit = "/3790/video".to_string();
+        let video = video_request(&settings, &req).unwrap();
+        assert_eq!(video.ad_unit.path, "/3790/video");
+        assert_eq!(video.ad_unit.sizes, vec!["640x480"]);
+        assert_eq!(video.description_url, "https://test.com/article");
+        assert_eq!(video.content, None);
+
+        for query in ["sz=640x0", "sz=big", "vid=cars", "description_url="] {
+            let req = Request::get(format!("https://test.com/gam-vast?{query}"));
+            assert!(video_request(&settings, &req).is_err(), "{query}");
+        }
+    }
+
+    /// Permutive segments of a visitor captured from a GAM request of another
+    /// site, once sent with every request.
+    const CAPTURED_SEGMENTS: [&str; 6] =
+        ["129627", "137412", "138272", "139095", "139096", "139218"];
+
+    fn cust_params(settings: &Settings, consent: &ConsentDecision, req: &Request) -> String {
+        let gam_req = GamRequest::new(settings, consent, req).unwrap();
+        let gam_req =
+            futures::executor::block_on(gam_req.with_audience_segments(settings, consent));
+        let params = gam_req.builder.params();
+        params
+            .iter()
+            .find(|(name, _)| *name == "cust_params")
+            .map(|(_, value)| value.clone())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_no_captured_segments() {
+        let mut settings = create_test_settings();
+        let mut req = Request::get("https://test.com/gam-test");
+        req.set_header("X-Synthetic-Trusted-Server", "synthetic-1");
+        let mut consent = ConsentDecision::from_request(&settings, &req);
+        consent.signals.tcf.gdpr_applies = false;
+        consent.personalization = PersonalizationLevel::Personalized;
+
+        // Without data providers, no segments are sent at all
+        let sent = cust_params(&settings, &consent, &req);
+        assert!(
+            !sent.contains("prmtvctx") && !sent.contains("permutive"),
+            "{sent}"
+        );
+        for segment in CAPTURED_SEGMENTS {
+            assert!(!sent.contains(segment), "{segment} sent in {sent}");
+        }
+
+        // The override stands in for the lookup, under the same consent
+        settings.permutive.segments_override = "1001,1002".to_string();
+        let sent = cust_params(&settings, &consent, &req);
+        assert!(sent.contains("permutive=1001,1002"), "{sent}");
+        consent.personalization = PersonalizationLevel::NonPersonalized;
+        let sent = cust_params(&settings, &consent, &req);
+        assert!(!sent.contains("permutive"), "{sent}");
+    }
+
+    #[test]
+    fn test_pvsid_and_correlator() {
+        let settings = create_test_settings();
+        let req = Request::get("https://test.com/gam-test?pvsid=4052337541963874");
+        let consent = ConsentDecision::from_request(&settings, &req);
+        let first = GamRequest::new(&settings, &consent, &req).unwrap();
+        let second = GamRequest::new(&settings, &consent, &req).unwrap();
+        assert_eq!(first.pvsid, "4052337541963874");
+        assert_eq!(second.pvsid, first.pvsid);
+        assert_ne!(second.correlator, first.correlator);
+
+        let req = Request::get("https://test.com/gam-test?pvsid=abc");
+        let generated = GamRequest::new(&settings, &consent, &req).unwrap();
+        assert!(parse_pvsid(&generated.pvsid).is_some());
+    }
+
+    #[test]
+    fn test_extract_creative() {
+        let body = r#"{"/123/top":["html",0,null,"1","2"]}<!doctype html><p>ad</p>"#;
+        assert_eq!(extract_creative(body), Some("<!doctype html><p>ad</p>"));
+        assert_eq!(extract_creative(r#"{"/123/top":["html",0]}"#), None);
+    }
+
+    #[test]
+    fn test_requested_sizes() {
+        let mut settings = create_test_settings();
+        settings.slot_registry.slots.clear();
+        settings.gam.ad_units = vec![
+            GamAdUnit {
+                name: "top".to_string(),
+                size: "728x90".to_string(),
+            },
+            GamAdUnit {
+                name: "side".to_string(),
+                size: "300x250".to_string(),
+            },
+            GamAdUnit {
+                name: "bottom".to_string(),
+                size: "728x90".to_string(),
+            },
+        ];
+        let req = Request::get("https://test.com/gam-render");
+        let consent = ConsentDecision::from_request(&settings, &req);
+        let gam_req = GamRequest::new(&settings, &consent, &req).unwrap();
+        assert_eq!(gam_req.requested_sizes(), vec!["728x90", "300x250"]);
+    }
 }
@@ -0,0 +1,897 @@
+//! Configuration-driven GAM ad request URLs.
+//!
+//! A [`DynamicGamBuilder`] assembles the query of a GAM ad request from a
+//! [`GamConfigTemplate`] (the network, server URL and ad units of the
+//! settings), the [`PageContext`] of the page the ads are for and the
+//! audience segments of each [`crate::data_provider::DataProvider`].
+//! Segments describe the visitor, so they are only sent when the
+//! [`RequestContext`] is personalized; other requests ask for
+//! non-personalized ads with `npa=1`, or for limited ads with `ltd=1` without
+//! consent to basic ads.
+
+use std::collections::BTreeMap;
+
+use rand::Rng;
+
+use crate::consent::ConsentDecision;
+use crate::data_provider::AudienceSegments;
+use crate::first_party_data::{FirstPartyData, KEY_CATEGORIES, KEY_KEYWORDS, KEY_SEGMENTS};
+use crate::settings::{RegisteredSlot, Settings};
+use crate::synthetic::partner_scoped_id;
+use crate::targeting::MAX_KEY_LENGTH;
+use crate::tcf_consent::AdvertisingConsentLevel;
+
+/// `gam.ad_units` size of ad units resizing to their content.
+const FLEXIBLE_SIZE: &str = "flexible";
+
+/// Longest `cust_params` value sent, before the URL encoding of the
+/// parameter itself.
+const MAX_CUST_PARAMS_LENGTH: usize = 2000;
+
+/// Upper bound of `pvsid` and `correlator` values, which GAM keeps within
+/// the integers JavaScript represents exactly.
+const MAX_REQUEST_ID: u64 = 1 << 53;
+
+/// New random `pvsid` or `correlator` value.
+pub fn random_request_id() -> String {
+    rand::thread_rng().gen_range(1..MAX_REQUEST_ID).to_string()
+}
+
+/// Page view ID sent by the page as `pvsid`, if it is one GAM accepts.
+pub fn parse_pvsid(value: &str) -> Option<String> {
+    value
+        .parse::<u64>()
+        .ok()
+        .filter(|pvsid| (1..MAX_REQUEST_ID).contains(pvsid))
+        .map(|pvsid| pvsid.to_string())
+}
+
+/// GAM ad unit requested.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdUnitConfig {
+    /// Full ad unit path, e.g. `/3790/trustedserver/homepage`
+    pub path: String,
+    /// Accepted creative sizes as `<w>x<h>`
+    pub sizes: Vec<String>,
+    /// Whether the ad unit resizes to the height of its creative
+    pub fluid: bool,
+}
+
+impl AdUnitConfig {
+    /// Ad unit of a registered slot.
+    pub fn from_slot(slot: &RegisteredSlot) -> Self {
+        Self {
+            path: slot.gam_ad_unit.clone(),
+            sizes: slot.sizes.iter().map(|(w, h)| format!("{w}x{h}")).collect(),
+            fluid: false,
+        }
+    }
+
+    /// Ad unit `name` of the network `publisher_id`, with its `gam.ad_units`
+    /// size: comma-separated `<w>x<h>` sizes or `flexible`. Names starting
+    /// with `/` are full paths.
+    pub fn from_name(publisher_id: &str, name: &str, size: &str) -> Self {
+        let path = if name.starts_with('/') {
+            name.to_string()
+        } else {
+            format!("/{publisher_id}/{name}")
+        };
+        let fluid = size.trim() == FLEXIBLE_SIZE;
+        let sizes = if fluid {
+            // GAM requests fluid ad units with the 1x1 placeholder size
+            vec!["1x1".to_string()]
+        } else {
+            size.split(',')
+                .map(str::trim)
+                .filter(|size| !size.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        Self { path, sizes, fluid }
+    }
+
+    /// The ad unit with only the sizes fitting a viewport `width` pixels
+    /// wide, or [`None`] if none fits. Fluid ad units fit any viewport.
+    pub fn fitting(&self, width: u32) -> Option<Self> {
+        if self.fluid {
+            return Some(self.clone());
+        }
+        let sizes: Vec<String> = self
+            .sizes
+            .iter()
+            .filter(|size| size_width(size).is_none_or(|size_width| size_width <= width))
+            .cloned()
+            .collect();
+        (!sizes.is_empty()).then(|| Self {
+            sizes,
+            ..self.clone()
+        })
+    }
+}
+
+/// Width of a `<w>x<h>` size.
+fn size_width(size: &str) -> Option<u32> {
+    size.split_once('x')?.0.parse().ok()
+}
+
+/// Video ad request of an instream player, answered with VAST.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VideoAdRequest {
+    /// Video ad unit and its player sizes
+    pub ad_unit: AdUnitConfig,
+    /// URL of a page describing the video content, which GAM requires
+    pub description_url: String,
+    /// Content source ID (`cmsid`) and video ID (`vid`) of the video, for
+    /// content targeting
+    pub content: Option<(String, String)>,
+}
+
+/// Page the ads are requested for.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PageContext {
+    /// URL of the page
+    pub url: String,
+    /// Content categories of the page
+    pub categories: Vec<String>,
+    /// Keywords of the page
+    pub keywords: Vec<String>,
+    /// Width of the visitor's viewport, see [`crate::device::viewport_width`]
+    pub viewport_width: Option<u32>,
+}
+
+/// Visitor and request state of one ad request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestContext {
+    /// Synthetic ID of the visitor, sent as `puid` when personalized
+    pub user_id: String,
+    /// Page view ID (`pvsid`), the same for all ad requests of one page view
+    /// so that GAM correlates them
+    pub pvsid: String,
+    /// Correlator of this ad request, shared by its ad units and new for
+    /// every request
+    pub correlator: String,
+    /// Child-directed under COPPA, sent as `tfcd=1`
+    pub child_directed: bool,
+    /// Ads the visitor consented to
+    pub consent_level: AdvertisingConsentLevel,
+    /// Publisher provided ID of the visitor, see [`ppid`]
+    pub ppid: Option<String>,
+    /// Consent parameters of the request, see [`consent_params`]
+    pub consent_params: Vec<(&'static str, String)>,
+}
+
+impl RequestContext {
+    /// Whether personalized ads may be requested.
+    pub fn is_personalized(&self) -> bool {
+        self.consent_level == AdvertisingConsentLevel::Personalized
+    }
+}
+
+/// GAM consent parameters of `consent`:
+/// - `gdpr`: whether GDPR applies
+/// - `gdpr_consent` and `addtl_consent`: the TC string and Google Additional
+///   Consent string, where GDPR applies
+/// - `gcs`: the Consent Mode state, `G1` followed by whether ad storage and
+///   analytics storage are granted, e.g. `G110`
+pub fn consent_params(consent: &ConsentDecision) -> Vec<(&'static str, String)> {
+    let tcf = &consent.signals.tcf;
+    let mut params = vec![("gdpr", u8::from(tcf.gdpr_applies).to_string())];
+    if tcf.gdpr_applies {
+        if !tcf.tc_string.is_empty() {
+            params.push(("gdpr_consent", tcf.tc_string.clone()));
+        }
+        if let Some(additional_consent) = &consent.signals.additional_consent {
+            params.push(("addtl_consent", additional_consent.ac_string.clone()));
+        }
+    }
+    let gcs = format!(
+        "G1{}{}",
+        u8::from(consent.storage_allowed),
+        u8::from(consent.analytics_allowed)
+    );
+    params.push(("gcs", gcs));
+    params
+}
+
+/// Publisher provided ID (PPID) of the visitor with `synthetic_id`: its
+/// partner-scoped ID for GAM, a 64 character hex string as GAM requires
+/// hashed, alphanumeric IDs of 22 to 150 characters.
+///
+/// Only derived with `gam.ppid` and consent to both personalized ads and
+/// storage, since GAM uses it to recognize the visitor across page views.
+pub fn ppid(settings: &Settings, consent: &ConsentDecision, synthetic_id: &str) -> Option<String> {
+    let allowed = settings.gam.ppid && consent.is_personalized() && consent.storage_allowed;
+    if !allowed || synthetic_id.is_empty() || synthetic_id == "unknown" {
+        return None;
+    }
+    Some(partner_scoped_id(settings, synthetic_id, "gam"))
+}
+
+/// Builder of the `cust_params` value of an ad request, such as
+/// `hb_pb=1.20&permutive=129627,137412&puid=abc123`.
+///
+/// Keys are truncated to the length GAM matches on, and each value is URL
+/// encoded on its own so that the commas separating multiple values are
+/// kept. Key-values are sent in the order they are added, which is also
+/// their priority: values that would make `cust_params` longer than
+/// [`MAX_CUST_PARAMS_LENGTH`] are left out.
+#[derive(Debug, Default)]
+pub struct CustParamsBuilder {
+    params: Vec<(String, Vec<String>)>,
+}
+
+impl CustParamsBuilder {
+    /// Builder without key-values.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `values` under `key`, after the values already added under it.
+    /// Empty values are skipped.
+    pub fn add<I, V>(mut self, key: &str, values: I) -> Self
+    where
+        I: IntoIterator<Item = V>,
+        V: Into<String>,
+    {
+        let key: String = key.chars().take(MAX_KEY_LENGTH).collect();
+        let values = values
+            .into_iter()
+            .map(Into::into)
+            .filter(|value: &String| !value.is_empty());
+        match self.params.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, existing)) => existing.extend(values),
+            None => self.params.push((key, values.collect())),
+        }
+        self
+    }
+
+    /// The encoded `cust_params` value, empty without any values.
+    pub fn build(&self) -> String {
+        let mut cust_params = String::new();
+        for (key, values) in &self.params {
+            let separator = if cust_params.is_empty() { "" } else { "&" };
+            let prefix = format!("{}{}=", separator, urlencoding::encode(key));
+            let mut encoded = String::new();
+            for value in values {
+                let value = urlencoding::encode(value);
+                let comma = usize::from(!encoded.is_empty());
+                let length = cust_params.len() + prefix.len() + encoded.len() + comma + value.len();
+                if length > MAX_CUST_PARAMS_LENGTH {
+                    log::debug!("Leaving {}={} out of cust_params", key, value);
+                    continue;
+                }
+                if comma == 1 {
+                    encoded.push(',');
+                }
+                encoded.push_str(&value);
+            }
+            if !encoded.is_empty() {
+                cust_params.push_str(&prefix);
+                cust_params.push_str(&encoded);
+            }
+        }
+        cust_params
+    }
+}
+
+/// Network and ad units the requests are built for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GamConfigTemplate {
+    /// GAM network code
+    pub publisher_id: String,
+    /// Ad request endpoint
+    pub server_url: String,
+    /// Ad units requested together
+    pub ad_units: Vec<AdUnitConfig>,
+}
+
+impl GamConfigTemplate {
+    /// Template of the `gam` settings. Slots of the slot registry with a GAM
+    /// ad unit take precedence over `gam.ad_units`.
+    pub fn from_settings(settings: &Settings) -> Self {
+        let gam = &settings.gam;
+        let slots: Vec<AdUnitConfig> = settings
+            .slot_registry
+            .slots
+            .iter()
+            .filter(|slot| !slot.gam_ad_unit.is_empty())
+            .map(AdUnitConfig::from_slot)
+            .collect();
+        let ad_units = if slots.is_empty() {
+            gam.ad_units
+                .iter()
+                .map(|unit| AdUnitConfig::from_name(&gam.publisher_id, &unit.name, &unit.size))
+                .collect()
+        } else {
+            slots
+        };
+        Self {
+            publisher_id: gam.publisher_id.clone(),
+            server_url: gam.server_url.clone(),
+            ad_units,
+        }
+    }
+}
+
+/// Builder of GAM ad request URLs.
+#[derive(Debug, Clone)]
+pub struct DynamicGamBuilder {
+    config: GamConfigTemplate,
+    page: PageContext,
+    context: RequestContext,
+    segments: Vec<(String, Vec<String>)>,
+    targeting: BTreeMap<String, String>,
+}
+
+impl DynamicGamBuilder {
+    /// Builder of requests for the ad units of `config` on `page`.
+    pub fn new(config: GamConfigTemplate, page: PageContext, context: RequestContext) -> Self {
+        Self {
+            config,
+            page,
+            context,
+            segments: Vec::new(),
+            targeting: BTreeMap::new(),
+        }
+    }
+
+    /// Requests `ad_units` instead of those of the template.
+    pub fn with_ad_units(mut self, ad_units: Vec<AdUnitConfig>) -> Self {
+        self.config.ad_units = ad_units;
+        self
+    }
+
+    /// Adds the segments of a data provider to `cust_params` under `key`.
+    pub fn with_segments<I, V>(mut self, key: &str, segments: I) -> Self
+    where
+        I: IntoIterator<Item = V>,
+        V: Into<String>,
+    {
+        let segments = segments.into_iter().map(Into::into).collect();
+        self.segments.push((key.to_string(), segments));
+        self
+    }
+
+    /// Adds the segments each provider found (see
+    /// [`crate::data_provider::DataProviderManager::collect`]).
+    pub fn with_audience_segments(self, audience: &AudienceSegments) -> Self {
+        audience.by_key().fold(self, |builder, (key, segments)| {
+            builder.with_segments(key, segments.iter().cloned())
+        })
+    }
+
+    /// Adds key-values to `cust_params`, such as the header bidding keys of
+    /// the winning bid (see [`crate::targeting::targeting_keys`]).
+    pub fn with_targeting(mut self, targeting: BTreeMap<String, String>) -> Self {
+        self.targeting.extend(targeting);
+        self
+    }
+
+    /// Adds the page context and segments of `data`.
+    pub fn with_first_party_data(mut self, data: FirstPartyData) -> Self {
+        self.page.categories.extend(data.categories.iter().cloned());
+        self.page.keywords.extend(data.keywords.iter().cloned());
+        let segments = data.segments.into_iter().flat_map(|group| group.ids);
+        self.with_segments(KEY_SEGMENTS, segments)
+    }
+
+    /// Network and ad units the URLs are built for.
+    pub fn config(&self) -> &GamConfigTemplate {
+        &self.config
+    }
+
+    /// Request state the URLs are built for.
+    pub fn context(&self) -> &RequestContext {
+        &self.context
+    }
+
+    /// Ad units requested: those of the template, without the sizes too wide
+    /// for the visitor's viewport and the ad units left without a size.
+    pub fn ad_units(&self) -> Vec<AdUnitConfig> {
+        match self.page.viewport_width {
+            Some(width) => {
+                let ad_units: Vec<AdUnitConfig> = self
+                    .config
+                    .ad_units
+                    .iter()
+                    .filter_map(|ad_unit| ad_unit.fitting(width))
+                    .collect();
+                if ad_units.len() < self.config.ad_units.len() {
+                    log::debug!("Left out ad units too wide for a {width}px viewport");
+                }
+                ad_units
+            }
+            None => self.config.ad_units.clone(),
+        }
+    }
+
+    /// `iu_parts`, `enc_prev_ius` and `prev_iu_szs` of the ad units: the
+    /// distinct ad unit path components, each ad unit's path as indices into
+    /// them, and each ad unit's sizes.
+    fn ad_unit_params(&self) -> Vec<(&'static str, String)> {
+        let ad_units = &self.ad_units();
+        let mut parts: Vec<&str> = Vec::new();
+        let mut encoded = Vec::new();
+        for ad_unit in ad_units {
+            let mut path = String::new();
+            for part in ad_unit.path.split('/').filter(|part| !part.is_empty()) {
+                let index = parts.iter().position(|p| *p == part).unwrap_or_else(|| {
+                    parts.push(part);
+                    parts.len() - 1
+                });
+                path.push_str(&format!("/{index}"));
+            }
+            encoded.push(path);
+        }
+        let sizes: Vec<String> = ad_units.iter().map(|unit| unit.sizes.join("|")).collect();
+        let mut params = vec![
+            ("iu_parts", parts.join(",")),
+            ("enc_prev_ius", encoded.join(",")),
+            ("prev_iu_szs", sizes.join(",")),
+        ];
+        if ad_units.iter().any(|unit| unit.fluid) {
+            let fluid: Vec<&str> = ad_units
+                .iter()
+                .map(|unit| if unit.fluid { "height" } else { "0" })
+                .collect();
+            params.push(("fluid", fluid.join(",")));
+        }
+        params
+    }
+
+    /// `cust_params` of the request, by priority: the added targeting,
+    /// then, when personalized, the `puid`, then the page context and, when
+    /// personalized, the segments of each data provider.
+    fn cust_params(&self) -> String {
+        let personalized = self.context.is_personalized();
+        let mut builder = CustParamsBuilder::new();
+        for (key, value) in &self.targeting {
+            builder = builder.add(key, [value.as_str()]);
+        }
+        if personalized {
+            builder = builder.add("puid", [self.context.user_id.as_str()]);
+        }
+        builder = builder
+            .add(KEY_CATEGORIES, self.page.categories.iter().cloned())
+            .add(KEY_KEYWORDS, self.page.keywords.iter().cloned());
+        if personalized {
+            for (key, segments) in &self.segments {
+                builder = builder.add(key, segments.iter().cloned());
+            }
+        }
+        builder.build()
+    }
+
+    /// Query parameters of the ad request, in a stable order.
+    pub fn params(&self) -> Vec<(&'static str, String)> {
+        let mut params = vec![
+            ("correlator", self.context.correlator.clone()),
+            ("pvsid", self.context.pvsid.clone()),
+            ("output", "ldjh".to_string()),
+            ("gdfp_req", "1".to_string()),
+            ("impl", "fifs".to_string()),
+        ];
+        params.extend(self.ad_unit_params());
+        params.extend(self.request_params());
+        params
+    }
+
+    /// Query parameters of the video ad request for `video`, asking for a
+    /// VAST response, in a stable order.
+    pub fn video_params(&self, video: &VideoAdRequest) -> Vec<(&'static str, String)> {
+        let mut params = vec![
+            ("correlator", self.context.correlator.clone()),
+            ("pvsid", self.context.pvsid.clone()),
+            ("output", "vast".to_string()),
+            ("gdfp_req", "1".to_string()),
+            ("impl", "s".to_string()),
+            ("env", "vp".to_string()),
+            ("unviewed_position_start", "1".to_string()),
+            ("iu", video.ad_unit.path.clone()),
+            ("sz", video.ad_unit.sizes.join("|")),
+            ("description_url", video.description_url.clone()),
+        ];
+        if let Some((cmsid, vid)) = &video.content {
+            params.push(("cmsid", cmsid.clone()));
+            params.push(("vid", vid.clone()));
+        }
+        params.extend(self.request_params());
+        params
+    }
+
+    /// Parameters shared by display and video requests: the consent,
+    /// visitor and page state and the `cust_params`.
+    fn request_params(&self) -> Vec<(&'static str, String)> {
+        let mut params = Vec::new();
+        if self.context.child_directed {
+            params.push(("tfcd", "1".to_string()));
+        }
+        match self.context.consent_level {
+            AdvertisingConsentLevel::Personalized => {}
+            AdvertisingConsentLevel::BasicOnly => params.push(("npa", "1".to_string())),
+            AdvertisingConsentLevel::None => params.push(("ltd", "1".to_string())),
+        }
+        params.extend(self.context.consent_params.iter().cloned());
+        if let Some(ppid) = self
+            .context
+            .ppid
+            .as_ref()
+            .filter(|_| self.context.is_personalized())
+        {
+            params.push(("ppid", ppid.clone()));
+        }
+        params.push(("url", self.page.url.clone()));
+        params.push(("dt", chrono::Utc::now().timestamp_millis().to_string()));
+        let cust_params = self.cust_params();
+        if !cust_params.is_empty() {
+            params.push(("cust_params", cust_params));
+        }
+        params
+    }
+
+    /// URL of the ad request.
+    pub fn build_url(&self) -> String {
+        self.url(&self.params())
+    }
+
+    /// URL of the video ad request for `video`.
+    pub fn build_video_url(&self, video: &VideoAdRequest) -> String {
+        self.url(&self.video_params(video))
+    }
+
+    fn url(&self, params: &[(&'static str, String)]) -> String {
+        let query = params
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, urlencoding::encode(value)))
+            .collect::<Vec<_>>()
+            .join("&");
+        format!("{}?{}", self.config.server_url, query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::additional_consent::AdditionalConsent;
+    use crate::consent::PersonalizationLevel;
+    use crate::first_party_data::SegmentGroup;
+    use crate::settings::GamAdUnit;
+    use crate::test_support::tests::create_test_settings;
+
+    fn context(consent_level: AdvertisingConsentLevel) -> RequestContext {
+        RequestContext {
+            user_id: "synthetic-1".to_string(),
+            pvsid: "7".to_string(),
+            correlator: "42".to_string(),
+            child_directed: false,
+            consent_level,
+            ppid: Some("ab12".to_string()),
+            consent_params: vec![("gdpr", "0".to_string())],
+        }
+    }
+
+    fn page() -> PageContext {
+        PageContext {
+            url: "https://test.com/cars".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn param<'a>(params: &'a [(&str, String)], name: &str) -> Option<&'a str> {
+        params
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn test_config_from_settings() {
+        let mut settings = create_test_settings();
+        settings.gam.ad_units = vec![
+            GamAdUnit {
+                name: "Flex8:1".to_string(),
+                size: "flexible".to_string(),
+            },
+            GamAdUnit {
+                name: "/3790/trustedserver/homepage".to_string(),
+                size: "728x90".to_string(),
+            },
+        ];
+        let config = GamConfigTemplate::from_settings(&settings);
+        assert_eq!(config.server_url, settings.gam.server_url);
+        assert_eq!(
+            config.ad_units,
+            vec![
+                AdUnitConfig {
+                    path: format!("/{}/Flex8:1", settings.gam.publisher_id),
+                    sizes: vec!["1x1".to_string()],
+                    fluid: true,
+                },
+                AdUnitConfig {
+                    path: "/3790/trustedserver/homepage".to_string(),
+                    sizes: vec!["728x90".to_string()],
+                    fluid: false,
+                },
+            ]
+        );
+
+        settings.slot_registry.slots = vec![RegisteredSlot {
+            code: "side".to_string(),
+            sizes: vec![(300, 250), (300, 600)],
+            gam_ad_unit: "/3790/trustedserver/article".to_string(),
+            ..Default::default()
+        }];
+        let config = GamConfigTemplate::from_settings(&settings);
+        assert_eq!(
+            config.ad_units,
+            vec![AdUnitConfig {
+                path: "/3790/trustedserver/article".to_string(),
+                sizes: vec!["300x250".to_string(), "300x600".to_string()],
+                fluid: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_ad_unit_params() {
+        let config = GamConfigTemplate {
+            publisher_id: "3790".to_string(),
+            server_url: "https://securepubads.g.doubleclick.net/gampad/ads".to_string(),
+            ad_units: vec![
+                AdUnitConfig {
+                    path: "/3790/trustedserver/homepage".to_string(),
+                    sizes: vec!["728x90".to_string(), "970x250".to_string()],
+                    fluid: false,
+                },
+                AdUnitConfig::from_name("3790", "trustedserver/article", "flexible"),
+            ],
+        };
+        let builder = DynamicGamBuilder::new(
+            config,
+            page(),
+            context(AdvertisingConsentLevel::Personalized),
+        );
+        let params = builder.params();
+        assert_eq!(
+            param(&params, "iu_parts"),
+            Some("3790,trustedserver,homepage,article")
+        );
+        assert_eq!(param(&params, "enc_prev_ius"), Some("/0/1/2,/0/1/3"));
+        assert_eq!(param(&params, "prev_iu_szs"), Some("728x90|970x250,1x1"));
+        assert_eq!(param(&params, "fluid"), Some("0,height"));
+        assert_eq!(param(&params, "correlator"), Some("42"));
+        assert_eq!(param(&params, "pvsid"), Some("7"));
+        assert_eq!(param(&params, "url"), Some("https://test.com/cars"));
+        assert_eq!(param(&params, "tfcd"), None);
+        assert_eq!(param(&params, "npa"), None);
+        assert_eq!(param(&params, "ppid"), Some("ab12"));
+        assert_eq!(param(&params, "gdpr"), Some("0"));
+    }
+
+    #[test]
+    fn test_responsive_ad_units() {
+        let leaderboard = AdUnitConfig::from_name("3790", "top", "970x250, 728x90,320x50");
+        assert_eq!(leaderboard.sizes, vec!["970x250", "728x90", "320x50"]);
+        assert!(!leaderboard.fluid);
+        let flexible = AdUnitConfig::from_name("3790", "native", "flexible");
+        let billboard = AdUnitConfig::from_name("3790", "billboard", "970x250");
+        let config = GamConfigTemplate {
+            publisher_id: "3790".to_string(),
+            server_url: "https://securepubads.g.doubleclick.net/gampad/ads".to_string(),
+            ad_units: vec![leaderboard, billboard, flexible],
+        };
+        let context = context(AdvertisingConsentLevel::Personalized);
+
+        let desktop = DynamicGamBuilder::new(config.clone(), page(), context.clone()).params();
+        assert_eq!(
+            param(&desktop, "prev_iu_szs"),
+            Some("970x250|728x90|320x50,970x250,1x1")
+        );
+
+        let mobile = PageContext {
+            viewport_width: Some(390),
+            ..page()
+        };
+        let builder = DynamicGamBuilder::new(config, mobile, context);
+        let params = builder.params();
+        assert_eq!(param(&params, "enc_prev_ius"), Some("/0/1,/0/2"));
+        assert_eq!(param(&params, "prev_iu_szs"), Some("320x50,1x1"));
+        assert_eq!(param(&params, "fluid"), Some("0,height"));
+        assert_eq!(builder.ad_units().len(), 2);
+    }
+
+    #[test]
+    fn test_request_ids() {
+        let id: u64 = random_request_id().parse().unwrap();
+        assert!((1..MAX_REQUEST_ID).contains(&id));
+        assert_ne!(random_request_id(), random_request_id());
+
+        assert_eq!(
+            parse_pvsid("4052337541963874"),
+            Some("4052337541963874".to_string())
+        );
+        for invalid in ["", "0", "-1", "12ab", "9007199254740992"] {
+            assert_eq!(parse_pvsid(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_video_params() {
+        let builder = DynamicGamBuilder::new(
+            GamConfigTemplate::from_settings(&create_test_settings()),
+            page(),
+            context(AdvertisingConsentLevel::BasicOnly),
+        )
+        .with_segments("permutive", ["129627"]);
+        let mut video = VideoAdRequest {
+            ad_unit: AdUnitConfig {
+                path: "/3790/trustedserver/video".to_string(),
+                sizes: vec!["640x480".to_string(), "400x300".to_string()],
+                fluid: false,
+            },
+            description_url: "https://test.com/videos/cars".to_string(),
+            content: Some(("2477953".to_string(), "cars-review".to_string())),
+        };
+        let params = builder.video_params(&video);
+        assert_eq!(param(&params, "output"), Some("vast"));
+        assert_eq!(param(&params, "env"), Some("vp"));
+        assert_eq!(param(&params, "iu"), Some("/3790/trustedserver/video"));
+        assert_eq!(param(&params, "sz"), Some("640x480|400x300"));
+        assert_eq!(
+            param(&params, "description_url"),
+            Some("https://test.com/videos/cars")
+        );
+        assert_eq!(param(&params, "cmsid"), Some("2477953"));
+        assert_eq!(param(&params, "vid"), Some("cars-review"));
+        assert_eq!(param(&params, "npa"), Some("1"));
+        assert_eq!(param(&params, "iu_parts"), None);
+        assert_eq!(param(&params, "cust_params"), None);
+
+        video.content = None;
+        let url = builder.build_video_url(&video);
+        assert!(url.starts_with("https://securepubads.g.doubleclick.net/gampad/ads?correlator=42"));
+        assert!(url.contains("&description_url=https%3A%2F%2Ftest.com%2Fvideos%2Fcars&"));
+        assert!(!url.contains("vid="));
+    }
+
+    #[test]
+    fn test_consent_params() {
+        let settings = create_test_settings();
+        let req = fastly::Request::get("https://test.com");
+        let mut consent = ConsentDecision::from_request(&settings, &req);
+        consent.signals.tcf.gdpr_applies = false;
+        consent.storage_allowed = true;
+        consent.analytics_allowed = false;
+        consent.signals.additional_consent = Some(AdditionalConsent::parse("2~1.35~dv.9").unwrap());
+        assert_eq!(
+            consent_params(&consent),
+            vec![("gdpr", "0".to_string()), ("gcs", "G110".to_string())]
+        );
+
+        let tc_string = "CPXxRfAPXxRfAAfKABENB-CgAAAAAAAAAAYgAAAAAAAA";
+        consent.signals.tcf.gdpr_applies = true;
+        consent.signals.tcf.tc_string = tc_string.to_string();
+        consent.storage_allowed = false;
+        assert_eq!(
+            consent_params(&consent),
+            vec![
+                ("gdpr", "1".to_string()),
+                ("gdpr_consent", tc_string.to_string()),
+                ("addtl_consent", "2~1.35~dv.9".to_string()),
+                ("gcs", "G100".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ppid() {
+        let mut settings = create_test_settings();
+        let req = fastly::Request::get("https://test.com");
+        let mut consent = ConsentDecision::from_request(&settings, &req);
+        consent.personalization = PersonalizationLevel::Personalized;
+        consent.storage_allowed = true;
+
+        let ppid = ppid(&settings, &consent, "synthetic-1").unwrap();
+        assert_eq!(ppid, partner_scoped_id(&settings, "synthetic-1", "gam"));
+        assert_eq!(ppid.len(), 64);
+        assert!(ppid.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert!(super::ppid(&settings, &consent, "unknown").is_none());
+
+        consent.storage_allowed = false;
+        assert!(super::ppid(&settings, &consent, "synthetic-1").is_none());
+        consent.storage_allowed = true;
+        consent.personalization = PersonalizationLevel::NonPersonalized;
+        assert!(super::ppid(&settings, &consent, "synthetic-1").is_none());
+        consent.personalization = PersonalizationLevel::Personalized;
+        settings.gam.ppid = false;
+        assert!(super::ppid(&settings, &consent, "synthetic-1").is_none());
+
+        let config = GamConfigTemplate::from_settings(&settings);
+        let builder =
+            DynamicGamBuilder::new(config, page(), context(AdvertisingConsentLevel::BasicOnly));
+        assert_eq!(param(&builder.params(), "ppid"), None);
+        assert_eq!(param(&builder.params(), "npa"), Some("1"));
+
+        let config = GamConfigTemplate::from_settings(&settings);
+        let limited = context(AdvertisingConsentLevel::None);
+        let params = DynamicGamBuilder::new(config, page(), limited).params();
+        assert_eq!(param(&params, "ltd"), Some("1"));
+        assert_eq!(param(&params, "npa"), None);
+        assert_eq!(param(&params, "ppid"), None);
+    }
+
+    #[test]
+    fn test_cust_params() {
+        let settings = create_test_settings();
+        let data = FirstPartyData {
+            categories: vec!["483".to_string()],
+            keywords: vec!["electric cars".to_string()],
+            segments: vec![SegmentGroup {
+                provider: "publisher.example".to_string(),
+                ids: vec!["s1".to_string(), "s2".to_string()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let builder = |consent_level| {
+            DynamicGamBuilder::new(
+                GamConfigTemplate::from_settings(&settings),
+                page(),
+                context(consent_level),
+            )
+            .with_segments("permutive", ["129627", "137412"])
+            .with_first_party_data(data.clone())
+            .with_targeting(BTreeMap::from([("hb_pb".to_string(), "1.20".to_string())]))
+        };
+
+        let personalized = builder(AdvertisingConsentLevel::Personalized);
+        assert_eq!(
+            param(&personalized.params(), "cust_params"),
+            Some(
+                "hb_pb=1.20&puid=synthetic-1&fpd_cat=483&fpd_kw=electric%20cars\
+                 &permutive=129627,137412&fpd_seg=s1,s2"
+            )
+        );
+        let url = personalized.build_url();
+        assert!(url.starts_with("https://securepubads.g.doubleclick.net/gampad/ads?correlator=42&"));
+
+        assert_eq!(
+            param(
+                &builder(AdvertisingConsentLevel::BasicOnly).params(),
+                "cust_params"
+            ),
+            Some("hb_pb=1.20&fpd_cat=483&fpd_kw=electric%20cars")
+        );
+    }
+
+    #[test]
+    fn test_cust_params_builder() {
+        let cust_params = CustParamsBuilder::new()
+            .add("hb_format", ["a&b"])
+            .add("hb_bidder_smartadserver", ["smartadserver"])
+            .add("permutive", ["1", "", "2"])
+            .add("permutive", ["3"])
+            .add("empty", Vec::<String>::new())
+            .build();
+        assert_eq!(
+            cust_params,
+            "hb_format=a%26b&hb_bidder_smartadser=smartadserver&permutive=1,2,3"
+        );
+        assert_eq!(CustParamsBuilder::new().build(), "");
+
+        // Lower priority values past the length limit are left out
+        let segments: Vec<String> = (0..500).map(|i| format!("{i:05}")).collect();
+        let cust_params = CustParamsBuilder::new()
+            .add("permutive", segments)
+            .add("puid", ["synthetic-1"])
+            .build();
+        assert!(cust_params.len() <= MAX_CUST_PARAMS_LENGTH);
+        assert!(cust_params.starts_with("permutive=00000,00001,"));
+        assert!(!cust_params.contains("puid"));
+        let cust_params = CustParamsBuilder::new()
+            .add("puid", ["synthetic-1"])
+            .add("permutive", (0..500).map(|i| format!("{i:05}")))
+            .build();
+        assert!(cust_params.starts_with("puid=synthetic-1&permutive=00000,"));
+    }
+}
@@ -0,0 +1,366 @@
+//! Header bidding handoff to GAM.
+//!
+//! `POST /gam-auction` ([`handle_gam_auction`]) makes the trusted server a
+//! complete server-side wrapper: it runs the Prebid auction of the posted
+//! slots (see [`execute_auction`]) and then asks GAM for each slot, with the
+//! `hb_*` targeting keys of the slot's winning bid in `cust_params`, so that
+//! Prebid line items compete with GAM demand. The slot requests share the
+//! page view's `pvsid` and correlator and are sent concurrently.
+//!
+//! When GAM picks a Prebid line item, its creative references the winning
+//! bid by `hb_adid` or `hb_cache_id`. The cached Prebid creative is rendered
+//! then, instead of the line item's Prebid Universal Creative, which needs
+//! Prebid.js on the page. Slots GAM fails to fill get a house ad (see
+//! [`crate::house_ad`]). Creatives are served from one-time sandboxed frames
+//! when `creative_frame` is configured (see [`crate::creative_frame`]), and
+//! returned inline otherwise.
+
+use std::collections::BTreeMap;
+
+use fastly::http::{header, Method, StatusCode};
+use fastly::{Error, Request, Response};
+use futures::future::join_all;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::consent::ConsentDecision;
+use crate::creative_frame::CreativeFrameStore;
+use crate::creative_rewrite::rewrite_creative;
+use crate::deadline::Deadline;
+use crate::error::TrustedServerError;
+use crate::gam::{extract_creative, GamRequest};
+use crate::gam_builder::AdUnitConfig;
+use crate::house_ad::house_ad;
+use crate::prebid::{execute_auction, parse_auction_request, AdSlot, WinningBid};
+use crate::prebid_cache::{CreativeCache, TARGETING_CACHE_ID};
+use crate::settings::Settings;
+use crate::slot_registry::SlotRegistry;
+use crate::stored_request::StoredRequests;
+use crate::targeting::KEY_AD_ID;
+
+/// Where the creative of a slot comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdSource {
+    /// The winning Prebid bid, picked by its GAM line item
+    Prebid,
+    /// GAM demand
+    Gam,
+    /// A house ad, GAM having failed or returned no creative
+    HouseAd,
+    /// No creative
+    None,
+}
+
+/// Creative of one slot.
+#[derive(Debug, Serialize)]
+pub struct SlotAd {
+    /// Slot code
+    pub code: String,
+    /// Source of the creative
+    pub source: AdSource,
+    /// URL path of the creative's sandboxed frame
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub src: Option<String>,
+    /// Creative HTML, when creative frames are not configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub html: Option<String>,
+    /// Targeting keys of the slot's winning bid sent to GAM
+    pub targeting: BTreeMap<String, String>,
+}
+
+/// Response of `POST /gam-auction`.
+#[derive(Debug, Serialize)]
+pub struct HandoffResult {
+    /// ID of the Prebid auction
+    pub auction_id: String,
+    /// Page view ID of the GAM requests
+    pub pvsid: String,
+    /// Correlator of the GAM requests
+    pub correlator: String,
+    /// Creative of each posted slot, in the posted order
+    pub slots: Vec<SlotAd>,
+}
+
+/// GAM ad unit of `slot`: the one registered for it, or else the ad unit
+/// named after its code with its sizes.
+fn ad_unit(settings: &Settings, registry: &SlotRegistry, slot: &AdSlot) -> AdUnitConfig {
+    match registry
+        .get(&slot.code)
+        .filter(|registered| !registered.gam_ad_unit.is_empty())
+    {
+        Some(registered) => AdUnitConfig::from_slot(&registered),
+        None => AdUnitConfig {
+            sizes: slot.sizes.iter().map(|(w, h)| format!("{w}x{h}")).collect(),
+            ..AdUnitConfig::from_name(&settings.gam.publisher_id, &slot.code, "")
+        },
+    }
+}
+
+/// Whether the GAM creative `html` is the Prebid line item creative of `bid`.
+fn is_prebid_creative(html: &str, bid: &WinningBid) -> bool {
+    [KEY_AD_ID, TARGETING_CACHE_ID]
+        .iter()
+        .filter_map(|key| bid.targeting.get(*key))
+        .any(|value| !value.is_empty() && html.contains(value.as_str()))
+}
+
+/// Markup of `bid`, from the creative cache its line item reads, or else as
+/// it was bid.
+fn prebid_markup(cache: Option<&CreativeCache>, bid: &WinningBid) -> Option<String> {
+    let cached = cache
+        .zip(bid.targeting.get(TARGETING_CACHE_ID))
+        .and_then(|(cache, uuid)| {
+            cache
+                .get(uuid)
+                .inspect_err(|e| log::warn!("Failed to read cached creative {uuid}: {e:?}"))
+                .ok()
+                .flatten()
+        });
+    cached.or_else(|| bid.adm.clone())
+}
+
+/// Creative of a slot of `sizes`, from GAM's creative `gam_html` and the
+/// slot's winning `bid`.
+fn pick_creative(
+    settings: &Settings,
+    cache: Option<&CreativeCache>,
+    gam_html: Option<&str>,
+    bid: Option<&WinningBid>,
+    sizes: &[String],
+) -> (AdSource, Option<String>) {
+    match gam_html {
+        Some(html) => {
+            let prebid = bid
+                .filter(|bid| is_prebid_creative(html, bid))
+                .and_then(|bid| prebid_markup(cache, bid));
+            match prebid {
+                Some(markup) => (AdSource::Prebid, Some(markup)),
+                None => (AdSource::Gam, Some(html.to_string())),
+            }
+        }
+        None => match house_ad(settings, sizes) {
+            Some(ad) => (AdSource::HouseAd, Some(ad.html)),
+            None => (AdSource::None, None),
+        },
+    }
+}
+
+/// Handles `POST /gam-auction`.
+///
+/// Takes the body of `POST /auction` (see [`parse_auction_request`]), runs
+/// the auction and the GAM requests of its slots and responds with the
+/// [`HandoffResult`].
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if the auction fails, the GAM request cannot be
+/// built or the configured stores cannot be read.
+pub async fn handle_gam_auction(
+    settings: &Settings,
+    consent: &ConsentDecision,
+    mut req: Request,
+    deadline: Deadline,
+) -> Result<Response, Error> {
+    if *req.get_method() != Method::POST {
+        return Ok(
+            Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_body("Method not allowed")
+        );
+    }
+    let stored = StoredRequests::open(settings).map_err(|e| Error::msg(format!("{e:?}")))?;
+    let auction = match parse_auction_request(&req.take_body_bytes(), stored.as_ref()) {
+        Ok(auction) => auction,
+        Err(e) if matches!(e.current_context(), TrustedServerError::KvStore { .. }) => {
+            return Err(Error::msg(format!("{e:?}")));
+        }
+        Err(e) => {
+            log::warn!("Rejected GAM auction request: {:?}", e);
+            return Ok(Response::from_status(StatusCode::BAD_REQUEST)
+                .with_header(header::CONTENT_TYPE, "application/json")
+                .with_body_json(&json!({ "error": e.current_context().to_string() }))?);
+        }
+    };
+    let slots = auction.slots.clone();
+    let first_party_data = auction.first_party_data.clone();
+    let result = execute_auction(settings, consent, &mut req, auction, deadline).await?;
+
+    // The auction set the synthetic ID of the request, and its segments are
+    // reused rather than looked up again
+    let mut gam_req = GamRequest::new(settings, consent, &req)?;
+    gam_req.builder = gam_req
+        .builder
+        .with_first_party_data(first_party_data)
+        .with_audience_segments(&result.audience);
+    let registry = SlotRegistry::open(settings);
+    let slot_requests: Vec<(&AdSlot, Option<&WinningBid>, GamRequest)> = slots
+        .iter()
+        .map(|slot| {
+            let bid = result.bids.iter().find(|bid| bid.imp_id == slot.code);
+            let targeting = bid.map(|bid| bid.targeting.clone()).unwrap_or_default();
+            let slot_req = gam_req
+                .clone()
+                .with_ad_units(vec![ad_unit(settings, &registry, slot)])
+                .with_targeting(targeting);
+            (slot, bid, slot_req)
+        })
+        .collect();
+    let responses = join_all(
+        slot_requests
+            .iter()
+            .map(|(_, _, slot_req)| slot_req.send_request(settings)),
+    )
+    .await;
+
+    let cache = CreativeCache::open(settings).map_err(|e| Error::msg(format!("{e:?}")))?;
+    let frames = CreativeFrameStore::open(settings).map_err(|e| Error::msg(format!("{e:?}")))?;
+    let mut slot_ads = Vec::new();
+    for ((slot, bid, slot_req), response) in slot_requests.iter().zip(responses) {
+        let body = match response {
+            Ok(response) if response.get_status().is_success() => Some(response.into_body_str()),
+            Ok(response) => {
+                log::warn!("GAM answered {} for {}", response.get_status(), slot.code);
+                None
+            }
+            Err(e) => {
+                log::warn!("GAM request for {} failed: {:?}", slot.code, e);
+                None
+            }
+        };
+        let gam_html = body
+            .as_deref()
+            .and_then(extract_creative)
+            .map(|html| rewrite_creative(settings, html));
+        let (source, creative) = pick_creative(
+            settings,
+            cache.as_ref(),
+            gam_html.as_deref(),
+            *bid,
+            &slot_req.requested_sizes(),
+        );
+        log::info!("Slot {} filled by {:?}", slot.code, source);
+
+        let (src, html) = match (&frames, creative) {
+            (Some(frames), Some(creative)) => (
+                Some(
+                    frames
+                        .put(&creative)
+                        .map_err(|e| Error::msg(format!("{e:?}")))?,
+                ),
+                None,
+            ),
+            (None, creative) => (None, creative),
+            (Some(_), None) => (None, None),
+        };
+        slot_ads.push(SlotAd {
+            code: slot.code.clone(),
+            source,
+            src,
+            html,
+            targeting: bid.map(|bid| bid.targeting.clone()).unwrap_or_default(),
+        });
+    }
+
+    let handoff = HandoffResult {
+        auction_id: result.id,
+        pvsid: gam_req.pvsid.clone(),
+        correlator: gam_req.correlator.clone(),
+        slots: slot_ads,
+    };
+    Ok(Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_header(header::CACHE_CONTROL, "no-store, private")
+        .with_body_json(&handoff)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::house_ad::DEFAULT_SIZE;
+    use crate::settings::{HouseAdCreative, RegisteredSlot};
+    use crate::test_support::tests::create_test_settings;
+
+    fn slot(code: &str) -> AdSlot {
+        serde_json::from_value(json!({ "code": code, "sizes": [[300, 250], [300, 600]] })).unwrap()
+    }
+
+    fn bid() -> WinningBid {
+        WinningBid {
+            imp_id: "sidebar".to_string(),
+            bid_id: "bid-1".to_string(),
+            bidder: "smartadserver".to_string(),
+            price: 1.5,
+            currency: "USD".to_string(),
+            adm: Some("<div>prebid ad</div>".to_string()),
+            adomain: Vec::new(),
+            crid: None,
+            width: Some(300),
+            height: Some(250),
+            targeting: BTreeMap::from([
+                ("hb_pb".to_string(), "1.50".to_string()),
+                (KEY_AD_ID.to_string(), "bid-1".to_string()),
+            ]),
+            nurl: None,
+            burl: None,
+            billing_id: None,
+        }
+    }
+
+    #[test]
+    fn test_ad_unit() {
+        let mut settings = create_test_settings();
+        settings.slot_registry.slots = vec![RegisteredSlot {
+            code: "leaderboard".to_string(),
+            sizes: vec![(728, 90)],
+            gam_ad_unit: "/123/site/top".to_string(),
+            bidders: Default::default(),
+        }];
+        let registry = SlotRegistry::open(&settings);
+
+        let registered = ad_unit(&settings, &registry, &slot("leaderboard"));
+        assert_eq!(registered.path, "/123/site/top");
+        assert_eq!(registered.sizes, vec!["728x90"]);
+
+        let unregistered = ad_unit(&settings, &registry, &slot("sidebar"));
+        assert_eq!(
+            unregistered.path,
+            format!("/{}/sidebar", settings.gam.publisher_id)
+        );
+        assert_eq!(unregistered.sizes, vec!["300x250", "300x600"]);
+    }
+
+    #[test]
+    fn test_pick_creative() {
+        let mut settings = create_test_settings();
+        let bid = bid();
+        let sizes = vec!["300x250".to_string()];
+
+        let puc = r#"<!doctype html><script>ucTag.renderAd(document, {adId: "bid-1"});</script>"#;
+        assert_eq!(
+            pick_creative(&settings, None, Some(puc), Some(&bid), &sizes),
+            (AdSource::Prebid, Some("<div>prebid ad</div>".to_string()))
+        );
+
+        let direct = "<!doctype html><div>direct campaign</div>";
+        assert_eq!(
+            pick_creative(&settings, None, Some(direct), Some(&bid), &sizes),
+            (AdSource::Gam, Some(direct.to_string()))
+        );
+        assert_eq!(
+            pick_creative(&settings, None, Some(puc), None, &sizes).0,
+            AdSource::Gam
+        );
+
+        assert_eq!(
+            pick_creative(&settings, None, None, Some(&bid), &sizes),
+            (AdSource::None, None)
+        );
+        settings.house_ads.creatives = vec![HouseAdCreative {
+            size: DEFAULT_SIZE.to_string(),
+            html: "<p>house</p>".to_string(),
+        }];
+        assert_eq!(
+            pick_creative(&settings, None, None, Some(&bid), &sizes),
+            (AdSource::HouseAd, Some("<p>house</p>".to_string()))
+        );
+    }
+}
@@ -3,14 +3,71 @@
 //! This module provides functionality for managing GDPR consent, including
 //! consent tracking, data subject requests, and compliance with EU privacy regulations.
 
+use error_stack::{Report, ResultExt};
+use fastly::geo::{geo_lookup, Continent};
 use fastly::http::{header, Method, StatusCode};
-use fastly::{Error, Request, Response};
+use fastly::{Body, Error, Request, Response};
+use handlebars::Handlebars;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::collections::HashMap;
 
-use crate::constants::HEADER_X_SUBJECT_ID;
-use crate::cookies;
+use crate::auth::authenticate;
+use crate::compliance_log::{AccessEvent, Actor};
+use crate::consent_history::{ConsentHistoryStore, ConsentSnapshot};
+use crate::consent_receipt::{ConsentReceipt, ConsentReceiptStore};
+use crate::consent_webhook::{send_consent_event, ConsentEvent};
+use crate::constants::{
+    HEADER_X_CONSENT_RECEIPT_ID, HEADER_X_DSAR_REQUEST_ID, HEADER_X_DSAR_TOKEN, HEADER_X_SUBJECT_ID,
+};
+use crate::cookies::{
+    self, decrypt_value, delete_synthetic_cookies, encrypt_value, filter_for_consent, sign_value,
+    verify_value, CookieBuilder, ResponseCookies, SameSite,
+};
+use crate::dsar::{track_request, DsarKind, DsarRequest, DsarState};
+use crate::erasure::erase_user_data;
+use crate::error::TrustedServerError;
+use crate::identity::{IdentityKey, IdentityStore};
+use crate::portability::write_bundle;
+use crate::rectification::{rectify_user_data, Rectification};
+use crate::retention::RetainedStore;
 use crate::settings::Settings;
+use crate::synthetic::{resolve_synthetic_id, signed_cookie_id, SyntheticId};
+use crate::tc_string::{create_tcf_consent_cookie, TcString, TCF_CONSENT_COOKIE};
+use crate::tcf_consent::vendor_list_manager::load_vendor_list;
+use crate::tcf_consent::{decode_tc_string, get_tcf_consent_or_default, purpose_ids, VendorList};
+use crate::templates::CONSENT_DEBUG_TEMPLATE;
+
+/// Name of the cookie holding [`GdprConsent`].
+const CONSENT_COOKIE: &str = "gdpr_consent";
+
+/// ISO 3166-1 alpha-2 codes of the EEA member states and the United Kingdom,
+/// where the GDPR or the UK GDPR applies.
+pub const GDPR_COUNTRIES: &[&str] = &[
+    "AT", "BE", "BG", "CY", "CZ", "DE", "DK", "EE", "ES", "FI", "FR", "GB", "GR", "HR", "HU", "IE",
+    "IS", "IT", "LI", "LT", "LU", "LV", "MT", "NL", "NO", "PL", "PT", "RO", "SE", "SI", "SK",
+];
+
+/// Returns `true` if the GDPR applies to visitors from the given country.
+pub fn is_gdpr_country(country_code: &str) -> bool {
+    GDPR_COUNTRIES
+        .iter()
+        .any(|code| code.eq_ignore_ascii_case(country_code))
+}
+
+/// Determines from Fastly geolocation whether the GDPR applies to the client.
+///
+/// Uses the client's country, falling back to the continent when geolocation
+/// only resolves to Europe as a whole. Returns [`None`] when the client cannot
+/// be located.
+pub fn gdpr_applies_to_request(req: &Request) -> Option<bool> {
+    let geo = req.get_client_ip_addr().and_then(geo_lookup)?;
+    let country = geo.country_code();
+    if matches!(country, "" | "**" | "EU") {
+        return Some(geo.continent() == Continent::Europe);
+    }
+    Some(is_gdpr_country(country))
+}
 
 /// GDPR consent information for a user.
 ///
@@ -33,16 +90,100 @@ pub struct GdprConsent {
 ///
 /// Contains all data collected about a user that must be made available
 /// for data subject access requests.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct UserData {
     /// Number of visits by the user.
     pub visit_count: i32,
-    /// Unix timestamp of the last visit.
-    pub last_visit: i64,
+    /// Unix timestamp of the last visit, if recorded.
+    pub last_visit: Option<i64>,
+    /// Ad server opid stored for the user, if any.
+    #[serde(default)]
+    pub opid: Option<String>,
     /// List of ad interaction events.
     pub ad_interactions: Vec<String>,
     /// History of consent changes.
     pub consent_history: Vec<GdprConsent>,
+    /// Every stored value above with its provenance.
+    #[serde(default)]
+    pub records: Vec<DataRecord>,
+}
+
+/// A single stored value exported for a data subject request, with the KV
+/// store it came from and when it was written.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DataRecord {
+    /// Kind of data, e.g. `visit_count`, `opid` or `consent_receipt`.
+    pub category: String,
+    /// The stored value as text; consent receipts are JSON.
+    pub value: String,
+    /// Name of the KV store holding the value.
+    pub store: String,
+    /// Unix timestamp (seconds) of the write, if recorded.
+    pub written_at: Option<i64>,
+}
+
+/// Format of a data subject access export, negotiated from `Accept`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// A `{"<subject id>": UserData}` JSON object (default).
+    Json,
+    /// One CSV row per [`DataRecord`].
+    Csv,
+    /// One JSON [`DataRecord`] per line.
+    Ndjson,
+}
+
+impl ExportFormat {
+    /// Picks the first supported media type listed in an `Accept` header,
+    /// falling back to JSON.
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        accept
+            .unwrap_or_default()
+            .split(',')
+            .find_map(|media_type| {
+                match media_type
+                    .split(';')
+                    .next()?
+                    .trim()
+                    .to_ascii_lowercase()
+                    .as_str()
+                {
+                    "application/json" => Some(Self::Json),
+                    "text/csv" => Some(Self::Csv),
+                    "application/x-ndjson" | "application/ndjson" => Some(Self::Ndjson),
+                    _ => None,
+                }
+            })
+            .unwrap_or(Self::Json)
+    }
+
+    /// Content type of the export.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Ndjson => "application/x-ndjson",
+        }
+    }
+}
+
+/// Serializes a stored value from `store` for a [`DataRecord`].
+fn to_record_value<T: Serialize>(
+    value: &T,
+    store: &str,
+) -> Result<String, Report<TrustedServerError>> {
+    serde_json::to_string(value).change_context(TrustedServerError::KvStore {
+        store_name: store.to_string(),
+        message: "Failed to serialize stored value".to_string(),
+    })
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
 }
 
 impl Default for GdprConsent {
@@ -57,13 +198,178 @@ impl Default for GdprConsent {
     }
 }
 
-impl Default for UserData {
-    fn default() -> Self {
+impl GdprConsent {
+    /// TCF purposes granted by the consent categories.
+    pub fn purposes(&self) -> Vec<u8> {
+        let mut purposes = Vec::new();
+        if self.functional {
+            purposes.extend_from_slice(purpose_ids::DEVICE_ACCESS);
+        }
+        if self.advertising {
+            purposes.extend_from_slice(purpose_ids::ADVERTISING);
+        }
+        if self.analytics {
+            purposes.extend_from_slice(purpose_ids::ANALYTICS);
+        }
+        purposes.sort_unstable();
+        purposes
+    }
+}
+
+impl From<&ConsentReceipt> for GdprConsent {
+    fn from(receipt: &ConsentReceipt) -> Self {
+        let granted = |purposes: &[u8]| purposes.iter().all(|p| receipt.purposes.contains(p));
         Self {
-            visit_count: 0,
-            last_visit: chrono::Utc::now().timestamp(),
-            ad_interactions: Vec::new(),
-            consent_history: Vec::new(),
+            analytics: granted(purpose_ids::ANALYTICS),
+            advertising: granted(purpose_ids::ADVERTISING),
+            functional: granted(purpose_ids::DEVICE_ACCESS),
+            timestamp: receipt.timestamp,
+            version: receipt.policy_version.clone(),
+        }
+    }
+}
+
+impl UserData {
+    /// Collects the data held for a synthetic ID.
+    ///
+    /// Reads the visit count from `synthetic.counter_store`, the opid from
+    /// `synthetic.opid_store`, the identity links in `identity.link_store`, the
+    /// consent receipts in `gdpr.receipt_store` and
+    /// the consent history in `gdpr.consent_history_store`, when those are
+    /// enabled. Without a history store the consent history is rebuilt from
+    /// the receipts. Visit counts and
+    /// opids past their retention period are expired rather than returned (see
+    /// [`RetainedStore`]). Visit times and ad interactions are not stored, so
+    /// they are always empty.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if a store cannot be opened or read
+    pub fn load(
+        settings: &Settings,
+        synthetic_id: &str,
+    ) -> Result<Self, Report<TrustedServerError>> {
+        let mut records = Vec::new();
+        let mut record = |category: &str, value: String, store: &str, written_at| {
+            records.push(DataRecord {
+                category: category.to_string(),
+                value,
+                store: store.to_string(),
+                written_at,
+            });
+        };
+
+        let counter_store = &settings.synthetic.counter_store;
+        let count = RetainedStore::open(settings, counter_store)?.get_stamped(synthetic_id)?;
+        let visit_count = count
+            .as_ref()
+            .and_then(|count| count.value.trim().parse().ok())
+            .unwrap_or(0);
+        if let Some(count) = count {
+            record("visit_count", count.value, counter_store, count.written_at);
+        }
+
+        let opid_store = &settings.synthetic.opid_store;
+        let opid = RetainedStore::open(settings, opid_store)?.get_stamped(synthetic_id)?;
+        if let Some(opid) = &opid {
+            record("opid", opid.value.clone(), opid_store, opid.written_at);
+        }
+
+        let receipts = match ConsentReceiptStore::open(settings)? {
+            Some(store) => store.get(synthetic_id)?,
+            None => Vec::new(),
+        };
+        if let Some(store) = IdentityStore::open(settings)? {
+            let links = store.links_for(synthetic_id)?;
+            let link_store = &settings.identity.link_store;
+            let written_at = Some(links.updated_at).filter(|&t| t > 0);
+            for key in links.keys() {
+                let (category, value) = match key {
+                    IdentityKey::PublisherUserId(id) => ("pub_user_id", id),
+                    IdentityKey::HashedEmail(hash) => ("hashed_email", hash),
+                };
+                record(category, value, link_store, written_at);
+            }
+        }
+
+        let receipt_store = &settings.gdpr.receipt_store;
+        for receipt in &receipts {
+            let value = to_record_value(receipt, receipt_store)?;
+            record(
+                "consent_receipt",
+                value,
+                receipt_store,
+                Some(receipt.timestamp),
+            );
+        }
+
+        let history = match ConsentHistoryStore::open(settings)? {
+            Some(store) => Some(store.get(synthetic_id)?),
+            None => None,
+        };
+        let history_store = &settings.gdpr.consent_history_store;
+        for snapshot in history.iter().flatten() {
+            let value = to_record_value(snapshot, history_store)?;
+            record(
+                "consent_snapshot",
+                value,
+                history_store,
+                Some(snapshot.recorded_at),
+            );
+        }
+        // Without a history store the receipts are the only record of consent changes
+        let consent_history = match history {
+            Some(history) => history.into_iter().map(|s| s.consent).collect(),
+            None => receipts.iter().map(GdprConsent::from).collect(),
+        };
+
+        Ok(Self {
+            visit_count,
+            opid: opid.map(|opid| opid.value),
+            consent_history,
+            records,
+            ..Default::default()
+        })
+    }
+
+    /// Renders the data held for `synthetic_id` in `format`.
+    ///
+    /// CSV and NDJSON exports list [`Self::records`] only; every row names the
+    /// subject, the category, the value and its provenance.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`serde_json::Error`] if serialization fails.
+    pub fn export(
+        self,
+        synthetic_id: &str,
+        format: ExportFormat,
+    ) -> Result<String, serde_json::Error> {
+        match format {
+            ExportFormat::Json => serde_json::to_string(&HashMap::from([(synthetic_id, self)])),
+            ExportFormat::Csv => {
+                let mut csv = String::from("subject_id,category,value,store,written_at\r\n");
+                for record in &self.records {
+                    let written_at = record.written_at.map(|t| t.to_string()).unwrap_or_default();
+                    let row = [
+                        synthetic_id,
+                        &record.category,
+                        &record.value,
+                        &record.store,
+                        &written_at,
+                    ];
+                    csv.push_str(&row.map(csv_field).join(","));
+                    csv.push_str("\r\n");
+                }
+                Ok(csv)
+            }
+            ExportFormat::Ndjson => self.records.iter().try_fold(String::new(), |mut out, r| {
+                let mut line = serde_json::to_value(r)?;
+                line["subject_id"] = json!(synthetic_id);
+                out.push_str(&serde_json::to_string(&line)?);
+                out.push('\n');
+                Ok(out)
+            }),
         }
     }
 }
@@ -71,18 +377,20 @@ impl Default for UserData {
 /// Extracts GDPR consent information from a request.
 ///
 /// Looks for consent information in the `gdpr_consent` cookie and parses
-/// it into a [`GdprConsent`] structure.
+/// it into a [`GdprConsent`] structure. When `gdpr.encrypt_consent_cookie` is
+/// enabled the cookie must have been encrypted by [`create_consent_cookie`].
 ///
 /// Returns [`None`] if no consent cookie is found or parsing fails.
-pub fn get_consent_from_request(req: &Request) -> Option<GdprConsent> {
+pub fn get_consent_from_request(settings: &Settings, req: &Request) -> Option<GdprConsent> {
     match cookies::handle_request_cookies(req) {
         Ok(Some(jar)) => {
-            if let Some(consent_cookie) = jar.get("gdpr_consent") {
-                if let Ok(consent) = serde_json::from_str(consent_cookie.value()) {
-                    return Some(consent);
-                }
-            }
-            None
+            let consent_cookie = jar.get(CONSENT_COOKIE)?;
+            let value = if settings.gdpr.encrypt_consent_cookie {
+                decrypt_value(settings, CONSENT_COOKIE, consent_cookie.value())?
+            } else {
+                consent_cookie.value().to_string()
+            };
+            serde_json::from_str(&value).ok()
         }
         Ok(None) => None,
         Err(e) => {
@@ -95,44 +403,276 @@ pub fn get_consent_from_request(req: &Request) -> Option<GdprConsent> {
 /// Creates a GDPR consent cookie string.
 ///
 /// Generates a properly formatted cookie string with the consent data,
-/// including security attributes and domain settings.
+/// including security attributes and domain settings. The consent JSON is
+/// encrypted when `gdpr.encrypt_consent_cookie` is enabled.
 pub fn create_consent_cookie(settings: &Settings, consent: &GdprConsent) -> String {
-    format!(
-        "gdpr_consent={}; Domain={}; Path=/; Secure; SameSite=Lax; Max-Age=31536000",
-        serde_json::to_string(consent).unwrap_or_default(),
-        settings.publisher.cookie_domain,
-    )
+    let value = serde_json::to_string(consent).unwrap_or_default();
+    let value = if settings.gdpr.encrypt_consent_cookie {
+        encrypt_value(settings, CONSENT_COOKIE, &value)
+    } else {
+        value
+    };
+    CookieBuilder::new(CONSENT_COOKIE, value)
+        .domain(&settings.publisher.cookie_domain)
+        .path("/")
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .max_age(31536000)
+        .build()
+}
+
+/// Builds the TC string equivalent of a banner choice.
+///
+/// Each [`GdprConsent`] category grants its TCF purposes; legitimate interest is
+/// established for the granted purposes TCF v2.2 allows it for. Vendor consent
+/// and legitimate interest are only given to the vendors of
+/// `gdpr.disclosed_vendors`, as the banner names no others, and only when
+/// the GVL lists them declaring one of those purposes.
+pub fn tc_string_for_consent(
+    settings: &Settings,
+    consent: &GdprConsent,
+    vendor_list: &VendorList,
+) -> TcString {
+    let purposes_consent = consent.purposes();
+
+    let purposes_li_transparency: Vec<u8> = purposes_consent
+        .iter()
+        .copied()
+        .filter(|p| !purpose_ids::CONSENT_ONLY.contains(p))
+        .collect();
+
+    let mut vendors_consent = Vec::new();
+    let mut vendors_li_consent = Vec::new();
+    for &id in &settings.gdpr.disclosed_vendors {
+        let Some(vendor) = vendor_list.vendors.get(&id) else {
+            continue;
+        };
+        if purposes_consent
+            .iter()
+            .any(|p| vendor.purposes.contains(p) || vendor.flexible_purposes.contains(p))
+        {
+            vendors_consent.push(id);
+        }
+        if purposes_li_transparency
+            .iter()
+            .any(|&p| vendor_list.vendor_declares_legitimate_interest(id, p))
+        {
+            vendors_li_consent.push(id);
+        }
+    }
+    vendors_consent.sort_unstable();
+    vendors_consent.dedup();
+    vendors_li_consent.sort_unstable();
+    vendors_li_consent.dedup();
+
+    TcString {
+        cmp_id: settings.gdpr.cmp_id,
+        cmp_version: settings.gdpr.cmp_version,
+        consent_language: settings.gdpr.consent_language.clone(),
+        vendor_list_version: u16::try_from(vendor_list.version).unwrap_or(0),
+        purposes_consent,
+        purposes_li_transparency,
+        vendors_consent,
+        vendors_li_consent,
+        ..Default::default()
+    }
+}
+
+/// Encodes the TC string written to `euconsent-v2` for a banner choice.
+///
+/// Returns [`None`] when no CMP ID is configured or the Global Vendor List is
+/// unavailable, since a valid TC string needs both.
+fn banner_tc_string(settings: &Settings, consent: &GdprConsent) -> Option<String> {
+    if settings.gdpr.cmp_id == 0 {
+        return None;
+    }
+    let Some(vendor_list) = load_vendor_list(settings) else {
+        log::warn!("Global Vendor List unavailable, not writing euconsent-v2 cookie");
+        return None;
+    };
+    Some(tc_string_for_consent(settings, consent, &vendor_list).encode())
+}
+
+/// Resolves the synthetic ID a consent choice is recorded under.
+///
+/// Returns [`None`] unless the requester proved the ID with a signed
+/// `synthetic_id` cookie (see [`SyntheticId::is_cookie_backed`]): anyone can
+/// claim an ID through the `X-Synthetic-Trusted-Server` header, and a freshly
+/// generated one can be recomputed by others on the same network.
+fn consent_subject(
+    settings: &Settings,
+    req: &Request,
+) -> Result<Option<SyntheticId>, Report<TrustedServerError>> {
+    let synthetic_id = resolve_synthetic_id(settings, req)?;
+    if !synthetic_id.is_cookie_backed() {
+        log::info!(
+            "Not recording consent for synthetic ID from {:?}",
+            synthetic_id.source
+        );
+        return Ok(None);
+    }
+    Ok(Some(synthetic_id))
+}
+
+/// Stores a receipt for a banner choice, returning its ID.
+///
+/// Returns [`None`] when receipts are disabled or cannot be stored, or the
+/// requester's synthetic ID is not cookie-backed (see [`consent_subject`]); a
+/// missing receipt never fails the consent update itself.
+fn store_consent_receipt(
+    settings: &Settings,
+    req: &Request,
+    consent: &GdprConsent,
+    tc_string: Option<&str>,
+) -> Option<String> {
+    let store = match ConsentReceiptStore::open(settings) {
+        Ok(store) => store?,
+        Err(e) => {
+            log::warn!("Failed to open consent receipt store: {:?}", e);
+            return None;
+        }
+    };
+    let receipt = ConsentReceipt::new(consent, tc_string);
+    let result = consent_subject(settings, req).and_then(|synthetic_id| {
+        synthetic_id
+            .map(|synthetic_id| store.append(&synthetic_id.value, receipt.clone()))
+            .transpose()
+    });
+    match result {
+        Ok(Some(())) => Some(receipt.receipt_id),
+        Ok(None) => None,
+        Err(e) => {
+            log::warn!("Failed to store consent receipt: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Appends the consent in effect after a consent choice to the user's history
+/// in `gdpr.consent_history_store`, if configured and the requester's synthetic
+/// ID is cookie-backed (see [`consent_subject`]).
+///
+/// Failures are logged; the consent update itself always succeeds.
+fn store_consent_snapshot(
+    settings: &Settings,
+    req: &Request,
+    consent: &GdprConsent,
+    tc_string: Option<&str>,
+) {
+    let store = match ConsentHistoryStore::open(settings) {
+        Ok(Some(store)) => store,
+        Ok(None) => return,
+        Err(e) => {
+            log::warn!("Failed to open consent history store: {:?}", e);
+            return;
+        }
+    };
+    let snapshot = ConsentSnapshot::new(consent, tc_string);
+    let result = consent_subject(settings, req).and_then(|synthetic_id| match synthetic_id {
+        Some(synthetic_id) => store.append(&synthetic_id.value, snapshot),
+        None => Ok(()),
+    });
+    if let Err(e) = result {
+        log::warn!("Failed to store consent history: {:?}", e);
+    }
+}
+
+/// POSTs a consent change event to `consent_webhook.url`, if configured and
+/// the requester's synthetic ID is cookie-backed (see [`consent_subject`]).
+///
+/// Failures are logged; the consent update itself always succeeds.
+fn notify_consent_change(
+    settings: &Settings,
+    req: &Request,
+    previous: Option<&GdprConsent>,
+    consent: &GdprConsent,
+    receipt_id: Option<String>,
+) {
+    if settings.consent_webhook.url.is_empty() {
+        return;
+    }
+    let synthetic_id = match consent_subject(settings, req) {
+        Ok(Some(synthetic_id)) => synthetic_id.value,
+        Ok(None) => return,
+        Err(e) => {
+            log::warn!(
+                "Failed to resolve synthetic ID for consent webhook: {:?}",
+                e
+            );
+            return;
+        }
+    };
+    let Some(event) = ConsentEvent::new(&synthetic_id, previous, consent, receipt_id) else {
+        return;
+    };
+    if let Err(e) = send_consent_event(settings, &event) {
+        log::warn!("Failed to send consent webhook: {:?}", e);
+    }
 }
 
 /// Handles GDPR consent management requests.
 ///
 /// Processes GET and POST requests to the `/gdpr/consent` endpoint:
 /// - GET: Returns current consent status
-/// - POST: Updates consent preferences
+/// - POST: Updates consent preferences, also writing an `euconsent-v2` TC
+///   string when `gdpr.cmp_id` is configured, storing a consent receipt
+///   when `gdpr.receipt_store` is configured and appending to the consent
+///   history when `gdpr.consent_history_store` is configured. Changes are reported to
+///   `consent_webhook.url` when it is set. Receipts, history and webhook
+///   events are only produced for synthetic IDs proven by a signed
+///   `synthetic_id` cookie.
 ///
 /// # Errors
 ///
 /// Returns a Fastly [`Error`] if response creation fails.
-pub fn handle_consent_request(settings: &Settings, req: Request) -> Result<Response, Error> {
+pub fn handle_consent_request(settings: &Settings, mut req: Request) -> Result<Response, Error> {
     match *req.get_method() {
         Method::GET => {
             // Return current consent status
-            let consent = get_consent_from_request(&req).unwrap_or_default();
+            let consent = get_consent_from_request(settings, &req).unwrap_or_default();
             Ok(Response::from_status(StatusCode::OK)
                 .with_header(header::CONTENT_TYPE, "application/json")
                 .with_body(serde_json::to_string(&consent)?))
         }
         Method::POST => {
             // Update consent preferences
-            let consent: GdprConsent = serde_json::from_slice(req.into_body_bytes().as_slice())?;
+            let tcf_consent = get_tcf_consent_or_default(settings, &req);
+            let previous_consent = get_consent_from_request(settings, &req);
+            let consent: GdprConsent = serde_json::from_slice(req.take_body_bytes().as_slice())?;
             let mut response = Response::from_status(StatusCode::OK)
                 .with_header(header::CONTENT_TYPE, "application/json")
                 .with_body(serde_json::to_string(&consent)?);
 
-            response.set_header(
-                header::SET_COOKIE,
-                create_consent_cookie(settings, &consent),
+            let mut cookies = ResponseCookies::new();
+            cookies.add(create_consent_cookie(settings, &consent));
+            let tc_string = banner_tc_string(settings, &consent);
+            if let Some(tc_string) = &tc_string {
+                cookies.add(create_tcf_consent_cookie(settings, tc_string));
+            }
+
+            // The receipt records the TC string written now, or the CMP's existing one
+            let receipt_tc_string = tc_string
+                .as_deref()
+                .or(Some(tcf_consent.tc_string.as_str()).filter(|s| !s.is_empty()));
+            let receipt_id = store_consent_receipt(settings, &req, &consent, receipt_tc_string);
+            store_consent_snapshot(settings, &req, &consent, receipt_tc_string);
+            if let Some(receipt_id) = &receipt_id {
+                response.set_header(HEADER_X_CONSENT_RECEIPT_ID, receipt_id);
+            }
+            notify_consent_change(
+                settings,
+                &req,
+                previous_consent.as_ref(),
+                &consent,
+                receipt_id,
             );
+            if !consent.advertising {
+                // Advertising consent was withdrawn: drop the tracking cookies it covered
+                for cookie in delete_synthetic_cookies(settings) {
+                    cookies.add(cookie);
+                }
+            }
+            filter_for_consent(settings, &tcf_consent, &cookies).apply(&mut response);
             Ok(response)
         }
         _ => {
@@ -142,51 +682,429 @@ pub fn handle_consent_request(settings: &Settings, req: Request) -> Result<Respo
     }
 }
 
+/// Body of a `POST /consent/tcf` request.
+#[derive(Debug, Deserialize)]
+struct TcfCookieRequest {
+    /// TC string generated by the CMP.
+    tc_string: String,
+}
+
+/// Handles `POST /consent/tcf`, storing a TC string generated by a CMP in a
+/// first-party context in the `euconsent-v2` cookie on the publisher's cookie
+/// domain.
+///
+/// The TC string must decode and use TCF v2.2 policies (policy version 4 or
+/// later); anything else is rejected with `400 Bad Request`.
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if response creation fails.
+pub fn handle_tcf_cookie_request(settings: &Settings, mut req: Request) -> Result<Response, Error> {
+    if *req.get_method() != Method::POST {
+        return Ok(
+            Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_body("Method not allowed")
+        );
+    }
+
+    let Ok(body) = serde_json::from_slice::<TcfCookieRequest>(&req.take_body_bytes()) else {
+        return Ok(Response::from_status(StatusCode::BAD_REQUEST)
+            .with_body("Expected a TC string in the `tc_string` field"));
+    };
+    let tc_string = body.tc_string.trim();
+    let consent = match decode_tc_string(tc_string) {
+        Ok(consent) => consent,
+        Err(e) => {
+            log::warn!("Rejecting invalid TC string: {}", e);
+            return Ok(
+                Response::from_status(StatusCode::BAD_REQUEST).with_body("Invalid TC string")
+            );
+        }
+    };
+    if !consent.is_tcf_v2_2() {
+        return Ok(
+            Response::from_status(StatusCode::BAD_REQUEST).with_body(format!(
+                "Unsupported TCF policy version {}",
+                consent.policy_version
+            )),
+        );
+    }
+
+    let mut response = Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_header(header::CACHE_CONTROL, "no-store, private")
+        .with_body_json(&json!({
+            "policy_version": consent.policy_version,
+            "vendor_list_version": consent.vendor_list_version,
+            "last_updated": consent.last_updated,
+        }))?;
+    let mut cookies = ResponseCookies::new();
+    cookies.add(create_tcf_consent_cookie(settings, tc_string));
+    cookies.apply(&mut response);
+    Ok(response)
+}
+
+/// Formats a Unix timestamp as RFC 3339 for display.
+fn format_timestamp(timestamp: i64) -> Option<String> {
+    chrono::DateTime::from_timestamp(timestamp, 0).map(|t| t.to_rfc3339())
+}
+
+/// Decodes a TC string into the human-readable form served by [`handle_consent_debug`].
+fn describe_tc_string(settings: &Settings, tc_string: &str, source: &str) -> serde_json::Value {
+    let consent = match decode_tc_string(tc_string) {
+        Ok(consent) => consent,
+        Err(e) => {
+            return json!({
+                "source": source,
+                "tc_string": tc_string,
+                "error": e,
+            })
+        }
+    };
+    let max_age_days = settings.gdpr.tcf_max_age_days;
+    let age_days = (chrono::Utc::now().timestamp() - consent.last_updated) / (24 * 60 * 60);
+
+    json!({
+        "source": source,
+        "tc_string": tc_string,
+        "policy_version": consent.policy_version,
+        "tcf_v2_2": consent.is_tcf_v2_2(),
+        "vendor_list_version": consent.vendor_list_version,
+        "created": format_timestamp(consent.created),
+        "last_updated": format_timestamp(consent.last_updated),
+        "age_days": age_days,
+        "max_age_days": max_age_days,
+        "expired": consent.is_expired(max_age_days),
+        "purposes": {
+            "consents": consent.purpose_consents.iter().collect::<Vec<_>>(),
+            "legitimate_interests": consent.purpose_legitimate_interests.iter().collect::<Vec<_>>(),
+        },
+        "vendors": {
+            "consents": consent.vendor_consents.iter().collect::<Vec<_>>(),
+            "legitimate_interests": consent.vendor_legitimate_interests.iter().collect::<Vec<_>>(),
+        },
+        "special_features": consent.special_feature_opt_ins.iter().collect::<Vec<_>>(),
+        "publisher_restrictions": consent.publisher_restrictions,
+        "publisher_tc": consent.has_publisher_tc.then(|| json!({
+            "consents": consent.publisher_purpose_consents.iter().collect::<Vec<_>>(),
+            "legitimate_interests": consent.publisher_purpose_legitimate_interests.iter().collect::<Vec<_>>(),
+        })),
+        "disclosed_vendors": consent.disclosed_vendors,
+    })
+}
+
+/// Handles the `/debug/consent` endpoint.
+///
+/// Decodes the TC string passed in the `tc_string` query parameter, or else
+/// the request's `euconsent-v2` cookie, and lists its purposes, vendors,
+/// legitimate interests, special features, publisher restrictions and
+/// staleness. Responds with JSON, or with an HTML page for `?format=html`.
+/// Like [`crate::synthetic::handle_synthetic_id_debug`], the endpoint
+/// requires an operator credential checked by [`authenticate`].
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if response creation or rendering fails.
+pub fn handle_consent_debug(settings: &Settings, req: Request) -> Result<Response, Error> {
+    if *req.get_method() != Method::GET {
+        return Ok(
+            Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_body("Method not allowed")
+        );
+    }
+    let principal = match authenticate(settings, &req) {
+        Ok(principal) => principal,
+        Err(e) => return Ok(e.into_response()),
+    };
+    AccessEvent::new(&req, "debug_consent", Actor::from(&principal), None)
+        .record(settings, StatusCode::OK);
+
+    let cookie_value = cookies::handle_request_cookies(&req)
+        .ok()
+        .flatten()
+        .and_then(|jar| jar.get(TCF_CONSENT_COOKIE).map(|c| c.value().to_string()));
+    let consent = match (req.get_query_parameter("tc_string"), cookie_value) {
+        (Some(tc_string), _) => describe_tc_string(settings, tc_string.trim(), "query"),
+        (None, Some(tc_string)) => describe_tc_string(settings, &tc_string, "cookie"),
+        (None, None) => json!({ "source": "none" }),
+    };
+
+    if req.get_query_parameter("format") == Some("html") {
+        let pretty = serde_json::to_string_pretty(&consent)?;
+        let body = Handlebars::new()
+            .render_template(
+                CONSENT_DEBUG_TEMPLATE,
+                &json!({ "consent": consent, "json": pretty }),
+            )
+            .map_err(|e| Error::msg(format!("{e:?}")))?;
+        return Ok(Response::from_status(StatusCode::OK)
+            .with_header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .with_header(header::CACHE_CONTROL, "no-store, private")
+            .with_body(body));
+    }
+
+    Ok(Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_header(header::CACHE_CONTROL, "no-store, private")
+        .with_body_json(&consent)?)
+}
+
+/// Name the verification token signature is bound to (see [`sign_value`]).
+const VERIFICATION_TOKEN: &str = "dsar_token";
+
+/// Issues a token authorizing data subject requests for `synthetic_id` until `expires_at`.
+///
+/// The token is `<synthetic_id>.<issued_at>.<expires_at>` signed with
+/// [`sign_value`]. Binding it to the ID's issue time ties it to the signed
+/// `synthetic_id` cookie it was issued against, so it stops working once that
+/// cookie is rotated.
+pub fn issue_verification_token(
+    settings: &Settings,
+    synthetic_id: &SyntheticId,
+    expires_at: i64,
+) -> String {
+    sign_value(
+        settings,
+        VERIFICATION_TOKEN,
+        &format!(
+            "{}.{}.{expires_at}",
+            synthetic_id.value, synthetic_id.issued_at
+        ),
+    )
+}
+
+/// Checks a token from [`issue_verification_token`] against the subject's
+/// synthetic ID, rejecting tampered, expired and mismatched tokens.
+pub fn verify_verification_token(
+    settings: &Settings,
+    token: &str,
+    synthetic_id: &SyntheticId,
+    now: i64,
+) -> bool {
+    let Some(value) = verify_value(settings, VERIFICATION_TOKEN, token) else {
+        return false;
+    };
+    let Some((rest, expires_at)) = value.rsplit_once('.') else {
+        return false;
+    };
+    rest.rsplit_once('.').is_some_and(|(token_id, issued_at)| {
+        token_id == synthetic_id.value
+            && issued_at == synthetic_id.issued_at.to_string()
+            && expires_at.parse::<i64>().is_ok_and(|e| now < e)
+    })
+}
+
+/// Handles `POST /gdpr/data/verify`.
+///
+/// Issues a verification token for the requester's own synthetic ID, valid for
+/// `gdpr.verification_token_ttl_secs`. Only IDs proven by a signed
+/// `synthetic_id` cookie qualify (see [`SyntheticId::is_cookie_backed`]); IDs
+/// generated from the request, claimed through the `X-Synthetic-Trusted-Server`
+/// header or found through an identity link are refused with `403 Forbidden`.
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if the synthetic ID cannot be resolved or
+/// response creation fails.
+pub fn handle_data_verification_request(
+    settings: &Settings,
+    req: Request,
+) -> Result<Response, Error> {
+    if *req.get_method() != Method::POST {
+        return Ok(
+            Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_body("Method not allowed")
+        );
+    }
+
+    let synthetic_id =
+        resolve_synthetic_id(settings, &req).map_err(|e| Error::msg(format!("{e:?}")))?;
+    if !synthetic_id.is_cookie_backed() {
+        log::warn!(
+            "Refusing DSAR verification for synthetic ID from {:?}",
+            synthetic_id.source
+        );
+        return Ok(Response::from_status(StatusCode::FORBIDDEN).with_body("Forbidden"));
+    }
+
+    let expires_at = chrono::Utc::now().timestamp() + settings.gdpr.verification_token_ttl_secs;
+    Ok(Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_header(header::CACHE_CONTROL, "no-store, private")
+        .with_body_json(&json!({
+            "synthetic_id": synthetic_id.value,
+            "token": issue_verification_token(settings, &synthetic_id, expires_at),
+            "expires_at": expires_at,
+        }))?)
+}
+
+/// Path of the data portability bundle download.
+pub const DATA_EXPORT_PATH: &str = "/gdpr/data/export";
+
 /// Handles GDPR data subject access requests.
 ///
 /// Processes requests to view or delete user data as required by GDPR:
-/// - GET: Returns all collected user data
-/// - DELETE: Removes all user data
+/// - GET: Returns the [`UserData`] held for the subject (see [`UserData::load`])
+///   as JSON, CSV or NDJSON depending on `Accept` (see [`ExportFormat`])
+/// - GET on [`DATA_EXPORT_PATH`]: Returns the subject's data as a signed
+///   portability bundle (see [`write_bundle`])
+/// - PATCH: Corrects the subject's identity links and opid as described by a
+///   JSON [`Rectification`] body (see [`rectify_user_data`])
+/// - DELETE: Erases the subject's data and tombstones the ID (see [`erase_user_data`])
+///
+/// Requires the `X-Subject-ID` header naming the subject's synthetic ID, an
+/// `X-DSAR-Token` header with a token for that ID from
+/// [`handle_data_verification_request`], and the signed `synthetic_id` cookie
+/// the token was issued against. Operators authenticated by
+/// [`authenticate`] may act on any subject without a token, for requests the
+/// subject made through another channel.
 ///
-/// Requires the `X-Subject-ID` header for authentication.
+/// When `gdpr.request_store` is set, the progress of authenticated requests is
+/// tracked as a [`DsarRequest`] whose ID is returned in `X-DSAR-Request-ID`;
+/// unauthenticated ones are neither tracked nor given an ID. Every request
+/// naming a subject, including rejected and failed ones, is recorded as an
+/// [`AccessEvent`].
 ///
 /// # Errors
 ///
-/// Returns a Fastly [`Error`] if response creation fails.
-pub fn handle_data_subject_request(_settings: &Settings, req: Request) -> Result<Response, Error> {
-    match *req.get_method() {
-        Method::GET => {
+/// Returns a Fastly [`Error`] if the KV stores cannot be read or response
+/// creation fails.
+pub fn handle_data_subject_request(
+    settings: &Settings,
+    mut req: Request,
+) -> Result<Response, Error> {
+    let kind = match *req.get_method() {
+        Method::GET if req.get_path() == DATA_EXPORT_PATH => DsarKind::Portability,
+        Method::GET => DsarKind::Access,
+        Method::PATCH => DsarKind::Rectification,
+        Method::DELETE => DsarKind::Erasure,
+        _ => {
+            return Ok(Response::from_status(StatusCode::METHOD_NOT_ALLOWED)
+                .with_body("Method not allowed"))
+        }
+    };
+    let Some(synthetic_id) = req.get_header(HEADER_X_SUBJECT_ID) else {
+        return Ok(Response::from_status(StatusCode::BAD_REQUEST).with_body("Missing subject ID"));
+    };
+    let synthetic_id = &synthetic_id.to_str()?.to_string();
+    let mut dsar = DsarRequest::new(kind, synthetic_id);
+
+    let actor = match req.get_header(HEADER_X_DSAR_TOKEN) {
+        Some(token) => signed_cookie_id(settings, &req)
+            .filter(|cookie_id| cookie_id.value == *synthetic_id)
+            .is_some_and(|cookie_id| {
+                token.to_str().is_ok_and(|token| {
+                    verify_verification_token(
+                        settings,
+                        token,
+                        &cookie_id,
+                        chrono::Utc::now().timestamp(),
+                    )
+                })
+            })
+            .then(Actor::dsar_token),
+        None => authenticate(settings, &req).ok().map(|principal| {
+            log::info!(
+                "{} request {} for {} made by operator ({})",
+                kind,
+                dsar.request_id,
+                synthetic_id,
+                principal
+            );
+            Actor::from(&principal)
+        }),
+    };
+    let Some(actor) = actor else {
+        AccessEvent::new(&req, kind, Actor::Unauthenticated, Some(synthetic_id))
+            .record(settings, StatusCode::UNAUTHORIZED);
+        return Ok(Response::from_status(StatusCode::UNAUTHORIZED)
+            .with_body("Missing or invalid verification token"));
+    };
+
+    // Every outcome of an authenticated request is tracked and logged here,
+    // failures included
+    let event = AccessEvent::new(&req, kind, actor, Some(synthetic_id));
+    dsar.advance(DsarState::Verified);
+    track_request(settings, &dsar);
+    let result = fulfil_data_subject_request(settings, &mut req, kind, synthetic_id);
+    let status = result
+        .as_ref()
+        .map_or(StatusCode::INTERNAL_SERVER_ERROR, Response::get_status);
+    dsar.advance(if status.is_success() {
+        DsarState::Fulfilled
+    } else if status.is_client_error() {
+        DsarState::Rejected
+    } else {
+        DsarState::Failed
+    });
+    track_request(settings, &dsar);
+    event.record(settings, status);
+
+    Ok(result?
+        .with_header(header::CACHE_CONTROL, "no-store, private")
+        .with_header(HEADER_X_DSAR_REQUEST_ID, &dsar.request_id))
+}
+
+/// Carries out an authenticated data subject request of `kind` for
+/// [`handle_data_subject_request`].
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if the KV stores cannot be read or written or
+/// response creation fails.
+fn fulfil_data_subject_request(
+    settings: &Settings,
+    req: &mut Request,
+    kind: DsarKind,
+    synthetic_id: &str,
+) -> Result<Response, Error> {
+    let response = match kind {
+        DsarKind::Access => {
             // Handle data access request
-            if let Some(synthetic_id) = req.get_header(HEADER_X_SUBJECT_ID) {
-                // Create a HashMap to store all user-related data
-                let mut data: HashMap<String, UserData> = HashMap::new();
+            let format = ExportFormat::from_accept(req.get_header_str(header::ACCEPT));
+            let user_data =
+                UserData::load(settings, synthetic_id).map_err(|e| Error::msg(format!("{e:?}")))?;
 
-                // TODO: Implement actual data retrieval from KV store
-                // For now, return empty user data
-                data.insert(synthetic_id.to_str()?.to_string(), UserData::default());
+            Response::from_status(StatusCode::OK)
+                .with_header(header::CONTENT_TYPE, format.content_type())
+                .with_header(header::VARY, "Accept")
+                .with_body(user_data.export(synthetic_id, format)?)
+        }
+        DsarKind::Portability => {
+            let user_data =
+                UserData::load(settings, synthetic_id).map_err(|e| Error::msg(format!("{e:?}")))?;
+            let mut body = Body::new();
+            write_bundle(settings, synthetic_id, &user_data.records, &mut body)?;
 
-                Ok(Response::from_status(StatusCode::OK)
-                    .with_header(header::CONTENT_TYPE, "application/json")
-                    .with_body(serde_json::to_string(&data)?))
-            } else {
-                Ok(Response::from_status(StatusCode::BAD_REQUEST).with_body("Missing subject ID"))
-            }
+            Response::from_status(StatusCode::OK)
+                .with_header(header::CONTENT_TYPE, "application/json")
+                .with_header(
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"personal-data.json\"",
+                )
+                .with_body(body)
         }
-        Method::DELETE => {
-            // Handle right to erasure (right to be forgotten)
-            if let Some(_synthetic_id) = req.get_header(HEADER_X_SUBJECT_ID) {
-                // TODO: Implement data deletion from KV store
-                Ok(Response::from_status(StatusCode::OK)
-                    .with_body("Data deletion request processed"))
-            } else {
-                Ok(Response::from_status(StatusCode::BAD_REQUEST).with_body("Missing subject ID"))
-            }
+        DsarKind::Rectification => {
+            let Ok(rectification) = serde_json::from_slice::<Rectification>(&req.take_body_bytes())
+            else {
+                return Ok(Response::from_status(StatusCode::BAD_REQUEST)
+                    .with_body("Invalid rectification"));
+            };
+            let summary = rectify_user_data(settings, synthetic_id, &rectification)
+                .map_err(|e| Error::msg(format!("{e:?}")))?;
+
+            Response::from_status(StatusCode::OK)
+                .with_header(header::CONTENT_TYPE, "application/json")
+                .with_body_json(&summary)?
         }
-        _ => {
-            Ok(Response::from_status(StatusCode::METHOD_NOT_ALLOWED)
-                .with_body("Method not allowed"))
+        DsarKind::Erasure => {
+            // Handle right to erasure (right to be forgotten)
+            let summary = erase_user_data(settings, synthetic_id)
+                .map_err(|e| Error::msg(format!("{e:?}")))?;
+
+            Response::from_status(StatusCode::OK)
+                .with_header(header::CONTENT_TYPE, "application/json")
+                .with_body_json(&summary)?
         }
-    }
+    };
+    Ok(response)
 }
 
 #[cfg(test)]
@@ -194,8 +1112,37 @@ mod tests {
     use super::*;
     use fastly::{Body, Request};
 
+    use crate::constants::HEADER_SYNTHETIC_TRUSTED_SERVER;
+    use crate::dsar::DsarStore;
+    use crate::erasure::ErasureSummary;
+    use crate::kv_store::JsonKvStore;
+    use crate::rectification::RectificationSummary;
+    use crate::synthetic::{sign_synthetic_id, SyntheticId};
     use crate::test_support::tests::create_test_settings;
 
+    trait WithDsarToken {
+        /// Adds a DSAR token for `synthetic_id` and the signed cookie it is bound to.
+        fn with_dsar_token(self, settings: &Settings, synthetic_id: &str) -> Self;
+    }
+
+    impl WithDsarToken for Request {
+        fn with_dsar_token(self, settings: &Settings, synthetic_id: &str) -> Self {
+            let synthetic_id = SyntheticId::new(synthetic_id.to_string());
+            let expires_at = chrono::Utc::now().timestamp() + 60;
+            self.with_header(
+                header::COOKIE,
+                format!(
+                    "synthetic_id={}",
+                    sign_synthetic_id(settings, &synthetic_id)
+                ),
+            )
+            .with_header(
+                HEADER_X_DSAR_TOKEN,
+                issue_verification_token(settings, &synthetic_id, expires_at),
+            )
+        }
+    }
+
     #[test]
     fn test_gdpr_consent_default() {
         let consent = GdprConsent::default();
@@ -206,13 +1153,31 @@ mod tests {
         assert!(consent.timestamp > 0);
     }
 
+    #[test]
+    fn test_is_gdpr_country() {
+        assert!(is_gdpr_country("FR"));
+        assert!(is_gdpr_country("gb"));
+        assert!(is_gdpr_country("NO"));
+        assert!(!is_gdpr_country("US"));
+        assert!(!is_gdpr_country("CH"));
+        assert!(!is_gdpr_country(""));
+    }
+
+    #[test]
+    fn test_gdpr_applies_to_request_without_client_ip() {
+        let req = Request::get("https://example.com");
+        assert_eq!(gdpr_applies_to_request(&req), None);
+    }
+
     #[test]
     fn test_user_data_default() {
         let data = UserData::default();
         assert_eq!(data.visit_count, 0);
-        assert!(data.last_visit > 0);
+        assert_eq!(data.last_visit, None);
+        assert_eq!(data.opid, None);
         assert!(data.ad_interactions.is_empty());
         assert!(data.consent_history.is_empty());
+        assert!(data.records.is_empty());
     }
 
     #[test]
@@ -262,13 +1227,15 @@ mod tests {
 
     #[test]
     fn test_get_consent_from_request_no_cookie() {
+        let settings = create_test_settings();
         let req = Request::get("https://example.com");
-        let consent = get_consent_from_request(&req);
+        let consent = get_consent_from_request(&settings, &req);
         assert!(consent.is_none());
     }
 
     #[test]
     fn test_get_consent_from_request_with_valid_cookie() {
+        let settings = create_test_settings();
         let mut req = Request::get("https://example.com");
         let consent_data = GdprConsent {
             analytics: true,
@@ -283,7 +1250,7 @@ mod tests {
         );
         req.set_header(header::COOKIE, cookie_value);
 
-        let consent = get_consent_from_request(&req);
+        let consent = get_consent_from_request(&settings, &req);
         assert!(consent.is_some());
         let consent = consent.unwrap();
         assert!(consent.analytics);
@@ -293,13 +1260,136 @@ mod tests {
 
     #[test]
     fn test_get_consent_from_request_with_invalid_cookie() {
+        let settings = create_test_settings();
         let mut req = Request::get("https://example.com");
         req.set_header(header::COOKIE, "gdpr_consent=invalid-json");
 
-        let consent = get_consent_from_request(&req);
+        let consent = get_consent_from_request(&settings, &req);
         assert!(consent.is_none());
     }
 
+    #[test]
+    fn test_encrypted_consent_cookie_round_trip() {
+        let mut settings = create_test_settings();
+        settings.gdpr.encrypt_consent_cookie = true;
+        let consent = GdprConsent {
+            analytics: false,
+            advertising: true,
+            functional: true,
+            timestamp: 1234567890,
+            version: "1.0".to_string(),
+        };
+
+        let cookie = create_consent_cookie(&settings, &consent);
+        assert!(!cookie.contains("advertising"));
+
+        let value = cookie.split(';').next().unwrap();
+        let req = Request::get("https://example.com").with_header(header::COOKIE, value);
+        let parsed = get_consent_from_request(&settings, &req).expect("should decrypt consent");
+        assert!(!parsed.analytics);
+        assert!(parsed.advertising);
+        assert!(parsed.functional);
+    }
+
+    #[test]
+    fn test_encrypted_consent_cookie_rejects_plain_json() {
+        let mut settings = create_test_settings();
+        settings.gdpr.encrypt_consent_cookie = true;
+        let req = Request::get("https://example.com").with_header(
+            header::COOKIE,
+            r#"gdpr_consent={"analytics":true,"advertising":true,"functional":true,"timestamp":0,"version":"1.0"}"#,
+        );
+
+        assert!(get_consent_from_request(&settings, &req).is_none());
+    }
+
+    #[test]
+    fn test_handle_tcf_cookie_request() {
+        let settings = create_test_settings();
+        // Policy version 4 (TCF v2.2)
+        let tc_string = "COztr8AOztr8AAHABBENAPEAAPAAAGIAAAAAAWgAAAAAACAFoAAAAAAAgAA";
+        let req = Request::post("https://example.com/consent/tcf")
+            .with_body(json!({ "tc_string": tc_string }).to_string());
+
+        let response = handle_tcf_cookie_request(&settings, req).unwrap();
+        assert_eq!(response.get_status(), StatusCode::OK);
+        let cookie = response.get_header_str(header::SET_COOKIE).unwrap();
+        assert!(cookie.starts_with(&format!(
+            "euconsent-v2={tc_string}; Domain=.test-publisher.com;"
+        )));
+    }
+
+    #[test]
+    fn test_handle_tcf_cookie_request_invalid() {
+        let settings = create_test_settings();
+        for body in [
+            json!({ "tc_string": "not a tc string" }).to_string(),
+            // Policy version 2 predates TCF v2.2
+            json!({ "tc_string": "COvFyGBOvFyGBAbAAAENAPCAAOAAAAAAAAAAAEEUACCKAAA" }).to_string(),
+            "{}".to_string(),
+        ] {
+            let req = Request::post("https://example.com/consent/tcf").with_body(body);
+            let response = handle_tcf_cookie_request(&settings, req).unwrap();
+            assert_eq!(response.get_status(), StatusCode::BAD_REQUEST);
+            assert!(response.get_header(header::SET_COOKIE).is_none());
+        }
+
+        let req = Request::get("https://example.com/consent/tcf");
+        let response = handle_tcf_cookie_request(&settings, req).unwrap();
+        assert_eq!(response.get_status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn test_handle_consent_debug_disabled_without_token() {
+        let settings = create_test_settings();
+        let req = Request::get("https://example.com/debug/consent");
+        let response = handle_consent_debug(&settings, req).unwrap();
+        assert_eq!(response.get_status(), StatusCode::NOT_FOUND);
+
+        let mut settings = create_test_settings();
+        settings.debug.auth_token = "debug-token".to_string();
+        let req = Request::get("https://example.com/debug/consent");
+        let response = handle_consent_debug(&settings, req).unwrap();
+        assert_eq!(response.get_status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_handle_consent_debug() {
+        let mut settings = create_test_settings();
+        settings.debug.auth_token = "debug-token".to_string();
+        // Purposes 1-4 and vendors 2, 6 and 8 consented
+        let req = Request::get("https://example.com/debug/consent")
+            .with_header(header::AUTHORIZATION, "Bearer debug-token")
+            .with_header(
+                header::COOKIE,
+                "euconsent-v2=COvFyGBOvFyGBAbAAAENAPCAAPAAAAAAAAAAAEEUACCKAAA",
+            );
+
+        let mut response = handle_consent_debug(&settings, req).unwrap();
+        assert_eq!(response.get_status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&response.take_body_str()).unwrap();
+        assert_eq!(body["source"], "cookie");
+        assert_eq!(body["purposes"]["consents"], json!([1, 2, 3, 4]));
+        assert_eq!(body["vendors"]["consents"], json!([2, 6, 8]));
+        assert_eq!(body["expired"], false);
+
+        // The query parameter takes precedence over the cookie
+        let req = Request::get("https://example.com/debug/consent?tc_string=invalid&format=html")
+            .with_header(header::AUTHORIZATION, "Bearer debug-token")
+            .with_header(
+                header::COOKIE,
+                "euconsent-v2=COvFyGBOvFyGBAbAAAENAPCAAPAAAAAAAAAAAEEUACCKAAA",
+            );
+        let mut response = handle_consent_debug(&settings, req).unwrap();
+        assert_eq!(
+            response.get_header_str(header::CONTENT_TYPE),
+            Some("text/html; charset=utf-8")
+        );
+        let body = response.take_body_str();
+        assert!(body.contains("<td>query</td>"));
+        assert!(body.contains("class=\"error\""));
+    }
+
     #[test]
     fn test_handle_consent_request_get() {
         let settings = create_test_settings();
@@ -357,6 +1447,223 @@ mod tests {
         assert!(!returned_consent.functional);
     }
 
+    #[test]
+    fn test_handle_consent_request_post_stores_receipt() {
+        let mut settings = create_test_settings();
+        settings.gdpr.receipt_store = "test_receipt_store".to_string();
+        let consent_data = GdprConsent {
+            advertising: true,
+            ..Default::default()
+        };
+
+        let body = serde_json::to_string(&consent_data).expect("should serialize consent");
+        let signed = sign_synthetic_id(
+            &settings,
+            &SyntheticId::new("consent-synthetic".to_string()),
+        );
+
+        let req = Request::post("https://example.com/gdpr/consent")
+            .with_header(header::COOKIE, format!("synthetic_id={signed}"))
+            .with_body(body.clone());
+        let response = handle_consent_request(&settings, req).expect("should handle consent");
+        let receipt_id = response
+            .get_header_str(HEADER_X_CONSENT_RECEIPT_ID)
+            .expect("should return a receipt ID");
+
+        let store = ConsentReceiptStore::open(&settings)
+            .expect("should open receipt store")
+            .expect("should enable receipts");
+        let receipts = store
+            .get("consent-synthetic")
+            .expect("should read receipts");
+        let receipt = receipts
+            .iter()
+            .find(|r| r.receipt_id == receipt_id)
+            .expect("should store the receipt");
+        assert_eq!(receipt.purposes, vec![2, 3, 4]);
+
+        // An ID claimed through the header gets no receipt written under it
+        let req = Request::post("https://example.com/gdpr/consent")
+            .with_header(HEADER_SYNTHETIC_TRUSTED_SERVER, "claimed-consent-synthetic")
+            .with_body(body);
+        let response = handle_consent_request(&settings, req).expect("should handle consent");
+        assert_eq!(response.get_status(), StatusCode::OK);
+        assert!(response.get_header(HEADER_X_CONSENT_RECEIPT_ID).is_none());
+        assert!(store
+            .get("claimed-consent-synthetic")
+            .expect("should read receipts")
+            .is_empty());
+    }
+
+    #[test]
+    fn test_consent_history_returned_by_access_request() {
+        let mut settings = create_test_settings();
+        settings.gdpr.consent_history_store = "test_history_store".to_string();
+        let tc_string = "COvFyGBOvFyGBAbAAAENAPCAAPAAAAAAAAAAAEEUACCKAAA";
+        let signed = sign_synthetic_id(&settings, &SyntheticId::new("history-subject".to_string()));
+        for advertising in [true, false] {
+            let consent_data = GdprConsent {
+                advertising,
+                ..Default::default()
+            };
+            let req = Request::post("https://example.com/gdpr/consent")
+                .with_header(
+                    header::COOKIE,
+                    format!("synthetic_id={signed}; euconsent-v2={tc_string}"),
+                )
+                .with_body(serde_json::to_string(&consent_data).expect("should serialize consent"));
+            let response = handle_consent_request(&settings, req).expect("should handle consent");
+            assert_eq!(response.get_status(), StatusCode::OK);
+        }
+
+        // An ID claimed through the header gets no history written under it
+        let req = Request::post("https://example.com/gdpr/consent")
+            .with_header(HEADER_SYNTHETIC_TRUSTED_SERVER, "claimed-history-subject")
+            .with_body(
+                serde_json::to_string(&GdprConsent::default()).expect("should serialize consent"),
+            );
+        let response = handle_consent_request(&settings, req).expect("should handle consent");
+        assert_eq!(response.get_status(), StatusCode::OK);
+        assert!(ConsentHistoryStore::open(&settings)
+            .expect("should open history store")
+            .expect("should enable consent history")
+            .get("claimed-history-subject")
+            .expect("should read consent history")
+            .is_empty());
+
+        let req = Request::get("https://example.com/gdpr/data")
+            .with_header(HEADER_X_SUBJECT_ID, "history-subject")
+            .with_dsar_token(&settings, "history-subject");
+        let response =
+            handle_data_subject_request(&settings, req).expect("should handle access request");
+        let mut data: HashMap<String, UserData> =
+            serde_json::from_str(&response.into_body_str()).expect("should parse user data");
+        let user_data = data
+            .remove("history-subject")
+            .expect("should return the subject's data");
+        let advertising: Vec<bool> = user_data
+            .consent_history
+            .iter()
+            .map(|c| c.advertising)
+            .collect();
+        assert_eq!(advertising, [true, false]);
+
+        let snapshot = user_data
+            .records
+            .iter()
+            .find(|r| r.category == "consent_snapshot")
+            .expect("should export consent snapshots");
+        assert_eq!(snapshot.store, "test_history_store");
+        let snapshot: ConsentSnapshot =
+            serde_json::from_str(&snapshot.value).expect("should parse consent snapshot");
+        assert_eq!(
+            snapshot.tcf.expect("should record TCF consent").tc_string,
+            tc_string
+        );
+    }
+
+    #[test]
+    fn test_handle_consent_request_withdrawal_clears_synthetic_cookie() {
+        let settings = create_test_settings();
+        let consent_data = GdprConsent {
+            analytics: true,
+            advertising: false,
+            functional: true,
+            timestamp: 1234567890,
+            version: "1.0".to_string(),
+        };
+
+        let mut req = Request::post("https://example.com/gdpr/consent");
+        req.set_body(Body::from(serde_json::to_string(&consent_data).unwrap()));
+
+        let response = handle_consent_request(&settings, req).unwrap();
+        let set_cookies: Vec<_> = response.get_header_all_str(header::SET_COOKIE);
+        assert!(set_cookies[0].starts_with("gdpr_consent="));
+        assert!(set_cookies
+            .iter()
+            .any(|c| c.starts_with("synthetic_id=;") && c.ends_with("Max-Age=0")));
+        assert!(set_cookies
+            .iter()
+            .any(|c| c.starts_with("__Host-synthetic_id=;")));
+    }
+
+    #[test]
+    fn test_tc_string_for_consent() {
+        use crate::tcf_consent::{TcfConsent, VendorInfo};
+        use lib_tcstring::TcModelV2;
+        use std::convert::TryFrom;
+
+        let mut settings = create_test_settings();
+        settings.gdpr.cmp_id = 300;
+        settings.gdpr.disclosed_vendors = vec![45, 50, 99];
+        let mut vendor_list = VendorList::new();
+        vendor_list.version = 71;
+        vendor_list.vendors.insert(
+            45,
+            VendorInfo {
+                id: 45,
+                name: "Equativ".to_string(),
+                purposes: vec![1, 2, 3, 4],
+                legitimate_interests: vec![7],
+                ..Default::default()
+            },
+        );
+        vendor_list.vendors.insert(
+            50,
+            VendorInfo {
+                id: 50,
+                name: "Analytics Only".to_string(),
+                purposes: vec![8],
+                ..Default::default()
+            },
+        );
+        // Declares the purposes but is not disclosed in the banner
+        vendor_list.vendors.insert(
+            60,
+            VendorInfo {
+                id: 60,
+                name: "Undisclosed".to_string(),
+                purposes: vec![1, 2, 3, 4],
+                legitimate_interests: vec![7],
+                ..Default::default()
+            },
+        );
+        let consent = GdprConsent {
+            analytics: true,
+            advertising: true,
+            functional: true,
+            ..Default::default()
+        };
+
+        let tc_string = tc_string_for_consent(&settings, &consent, &vendor_list).encode();
+        let model = TcModelV2::try_from(tc_string.as_str()).unwrap();
+        assert_eq!(model.cmp_id, 300);
+        assert_eq!(model.vendor_list_version, 71);
+        assert_eq!(model.purposes_consent, vec![1, 2, 3, 4, 7, 8, 9]);
+        assert_eq!(model.purposes_li_transparency, vec![2, 7, 8, 9]);
+        assert_eq!(model.vendors_consent, vec![45, 50]);
+        assert_eq!(model.vendors_li_consent, vec![45]);
+
+        let tcf_consent = TcfConsent::from_tc_model(model, tc_string).unwrap();
+        assert!(tcf_consent.has_personalized_advertising_consent(45, Some(&vendor_list)));
+    }
+
+    #[test]
+    fn test_tc_string_for_rejected_consent() {
+        use lib_tcstring::TcModelV2;
+        use std::convert::TryFrom;
+
+        let settings = create_test_settings();
+        let mut vendor_list = VendorList::new();
+        vendor_list.vendors.insert(45, Default::default());
+        let tc_string =
+            tc_string_for_consent(&settings, &GdprConsent::default(), &vendor_list).encode();
+
+        let model = TcModelV2::try_from(tc_string.as_str()).unwrap();
+        assert!(model.purposes_consent.is_empty());
+        assert!(model.vendors_consent.is_empty());
+    }
+
     #[test]
     fn test_handle_consent_request_invalid_method() {
         let settings = create_test_settings();
@@ -372,6 +1679,7 @@ mod tests {
         let settings = create_test_settings();
         let mut req = Request::get("https://example.com/gdpr/data");
         req.set_header(HEADER_X_SUBJECT_ID, "test-subject-123");
+        req = req.with_dsar_token(&settings, "test-subject-123");
 
         let response = handle_data_subject_request(&settings, req).unwrap();
         assert_eq!(response.get_status(), StatusCode::OK);
@@ -386,6 +1694,307 @@ mod tests {
         assert_eq!(data["test-subject-123"].visit_count, 0); // Default value
     }
 
+    #[test]
+    fn test_handle_data_subject_request_get_reads_stores() {
+        let mut settings = create_test_settings();
+        settings.gdpr.receipt_store = "test_receipt_store".to_string();
+        let counters = fastly::KVStore::open(&settings.synthetic.counter_store)
+            .unwrap()
+            .unwrap();
+        counters.insert("dsar-subject", "3").unwrap();
+        let opids = fastly::KVStore::open(&settings.synthetic.opid_store)
+            .unwrap()
+            .unwrap();
+        opids.insert("dsar-subject", "opid-123").unwrap();
+        let consent = GdprConsent {
+            advertising: true,
+            ..Default::default()
+        };
+        ConsentReceiptStore::open(&settings)
+            .unwrap()
+            .unwrap()
+            .append("dsar-subject", ConsentReceipt::new(&consent, None))
+            .unwrap();
+
+        let req = Request::get("https://example.com/gdpr/data")
+            .with_header(HEADER_X_SUBJECT_ID, "dsar-subject")
+            .with_dsar_token(&settings, "dsar-subject");
+        let response = handle_data_subject_request(&settings, req).unwrap();
+        assert_eq!(response.get_status(), StatusCode::OK);
+
+        let mut data: HashMap<String, UserData> =
+            serde_json::from_str(&response.into_body_str()).unwrap();
+        let user_data = data.remove("dsar-subject").unwrap();
+        assert_eq!(user_data.visit_count, 3);
+        assert_eq!(user_data.opid.as_deref(), Some("opid-123"));
+        assert_eq!(user_data.consent_history.len(), 1);
+        assert!(user_data.consent_history[0].advertising);
+        assert!(!user_data.consent_history[0].analytics);
+        let categories: Vec<&str> = user_data
+            .records
+            .iter()
+            .map(|r| r.category.as_str())
+            .collect();
+        assert_eq!(categories, ["visit_count", "opid", "consent_receipt"]);
+        assert_eq!(user_data.records[2].store, "test_receipt_store");
+    }
+
+    #[test]
+    fn test_export_format_from_accept() {
+        assert_eq!(ExportFormat::from_accept(None), ExportFormat::Json);
+        assert_eq!(ExportFormat::from_accept(Some("*/*")), ExportFormat::Json);
+        assert_eq!(
+            ExportFormat::from_accept(Some("text/html, text/csv;q=0.9")),
+            ExportFormat::Csv
+        );
+        assert_eq!(
+            ExportFormat::from_accept(Some("application/x-ndjson")),
+            ExportFormat::Ndjson
+        );
+        assert_eq!(
+            ExportFormat::from_accept(Some("application/json, text/csv")),
+            ExportFormat::Json
+        );
+    }
+
+    #[test]
+    fn test_handle_data_subject_request_export_formats() {
+        let settings = create_test_settings();
+        JsonKvStore::open(&settings.synthetic.counter_store)
+            .unwrap()
+            .put_text("export-subject", "5")
+            .unwrap();
+        JsonKvStore::open(&settings.synthetic.opid_store)
+            .unwrap()
+            .put_text("export-subject", "opid,\"quoted\"")
+            .unwrap();
+        let request = |accept: &str| {
+            Request::get("https://example.com/gdpr/data")
+                .with_header(header::ACCEPT, accept)
+                .with_header(HEADER_X_SUBJECT_ID, "export-subject")
+                .with_dsar_token(&settings, "export-subject")
+        };
+
+        let response = handle_data_subject_request(&settings, request("application/json")).unwrap();
+        let data: HashMap<String, UserData> =
+            serde_json::from_str(&response.into_body_str()).unwrap();
+        let records = &data["export-subject"].records;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].category, "visit_count");
+        assert_eq!(records[0].store, settings.synthetic.counter_store);
+        assert!(records[0].written_at.is_some());
+
+        let response = handle_data_subject_request(&settings, request("text/csv")).unwrap();
+        assert_eq!(
+            response.get_header_str(header::CONTENT_TYPE),
+            Some("text/csv; charset=utf-8")
+        );
+        let body = response.into_body_str();
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines[0], "subject_id,category,value,store,written_at");
+        assert!(lines[1].starts_with("export-subject,visit_count,5,test_counter_store,"));
+        assert!(
+            lines[2].starts_with("export-subject,opid,\"opid,\"\"quoted\"\"\",test-opid-store,")
+        );
+
+        let response =
+            handle_data_subject_request(&settings, request("application/x-ndjson")).unwrap();
+        assert_eq!(
+            response.get_header_str(header::CONTENT_TYPE),
+            Some("application/x-ndjson")
+        );
+        let lines: Vec<serde_json::Value> = response
+            .into_body_str()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["subject_id"], "export-subject");
+        assert_eq!(lines[1]["category"], "opid");
+        assert_eq!(lines[1]["value"], "opid,\"quoted\"");
+    }
+
+    #[test]
+    fn test_handle_data_subject_request_tracks_state() {
+        let mut settings = create_test_settings();
+        settings.gdpr.request_store = "test_dsar_store".to_string();
+        let store = DsarStore::open(&settings)
+            .expect("should open the DSAR store")
+            .expect("should have a DSAR store configured");
+        let tracked = |resp: &Response| {
+            let request_id = resp
+                .get_header_str(HEADER_X_DSAR_REQUEST_ID)
+                .expect("should return the DSAR request ID");
+            store
+                .get(request_id)
+                .expect("should read the DSAR store")
+                .expect("should have tracked the request")
+        };
+
+        // Unauthenticated requests are not tracked
+        let req = Request::get("https://example.com/gdpr/data")
+            .with_header(HEADER_X_SUBJECT_ID, "tracked-subject");
+        let resp = handle_data_subject_request(&settings, req).expect("should answer the request");
+        assert_eq!(resp.get_status(), StatusCode::UNAUTHORIZED);
+        assert!(resp.get_header(HEADER_X_DSAR_REQUEST_ID).is_none());
+
+        let req = Request::delete("https://example.com/gdpr/data")
+            .with_header(HEADER_X_SUBJECT_ID, "tracked-subject")
+            .with_dsar_token(&settings, "tracked-subject");
+        let resp = handle_data_subject_request(&settings, req).expect("should erase the data");
+        assert_eq!(resp.get_status(), StatusCode::OK);
+        let dsar = tracked(&resp);
+        assert_eq!(dsar.state, DsarState::Fulfilled);
+        assert_eq!(dsar.kind, DsarKind::Erasure);
+        assert_eq!(dsar.synthetic_id, "tracked-subject");
+
+        // Invalid requests end up rejected rather than stuck in verified
+        let req = Request::patch("https://example.com/gdpr/data")
+            .with_header(HEADER_X_SUBJECT_ID, "tracked-subject")
+            .with_dsar_token(&settings, "tracked-subject")
+            .with_body("not json");
+        let resp = handle_data_subject_request(&settings, req).expect("should answer the request");
+        assert_eq!(resp.get_status(), StatusCode::BAD_REQUEST);
+        let dsar = tracked(&resp);
+        assert_eq!(dsar.state, DsarState::Rejected);
+        assert_eq!(dsar.kind, DsarKind::Rectification);
+    }
+
+    #[test]
+    fn test_handle_data_subject_request_requires_token() {
+        let settings = create_test_settings();
+        let now = chrono::Utc::now().timestamp();
+        let subject = SyntheticId {
+            issued_at: now - 3600,
+            source: crate::synthetic::SyntheticIdSource::Cookie,
+            ..SyntheticId::new("test-subject-123".to_string())
+        };
+        let cookie = format!("synthetic_id={}", sign_synthetic_id(&settings, &subject));
+        let rotated = SyntheticId {
+            issued_at: now,
+            ..subject.clone()
+        };
+        let valid = issue_verification_token(&settings, &subject, now + 60);
+        for (cookie, token) in [
+            (Some(&cookie), None),
+            (Some(&cookie), Some("garbage".to_string())),
+            (
+                Some(&cookie),
+                Some(issue_verification_token(
+                    &settings,
+                    &SyntheticId::new("other-subject".to_string()),
+                    now + 60,
+                )),
+            ),
+            (
+                Some(&cookie),
+                Some(issue_verification_token(&settings, &subject, now - 1)),
+            ),
+            // Issued against an earlier cookie for the same ID
+            (
+                Some(&cookie),
+                Some(issue_verification_token(&settings, &rotated, now + 60)),
+            ),
+            // A valid token without the cookie it was issued against
+            (None, Some(valid.clone())),
+        ] {
+            let mut req = Request::get("https://example.com/gdpr/data")
+                .with_header(HEADER_X_SUBJECT_ID, "test-subject-123");
+            if let Some(cookie) = cookie {
+                req.set_header(header::COOKIE, cookie);
+            }
+            if let Some(token) = token {
+                req.set_header(HEADER_X_DSAR_TOKEN, token);
+            }
+            let response = handle_data_subject_request(&settings, req)
+                .expect("should handle data subject request");
+            assert_eq!(response.get_status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let req = Request::get("https://example.com/gdpr/data")
+            .with_header(HEADER_X_SUBJECT_ID, "test-subject-123")
+            .with_header(header::COOKIE, &cookie)
+            .with_header(HEADER_X_DSAR_TOKEN, valid);
+        let response = handle_data_subject_request(&settings, req)
+            .expect("should handle data subject request");
+        assert_eq!(response.get_status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_handle_data_subject_request_operator_access() {
+        let mut settings = create_test_settings();
+        settings.admin.api_keys = vec!["admin-key".to_string()];
+
+        let req = Request::get("https://example.com/gdpr/data")
+            .with_header(HEADER_X_SUBJECT_ID, "test-subject-123")
+            .with_header(header::AUTHORIZATION, "Bearer admin-key");
+        let response = handle_data_subject_request(&settings, req).unwrap();
+        assert_eq!(response.get_status(), StatusCode::OK);
+
+        let req = Request::get("https://example.com/gdpr/data")
+            .with_header(HEADER_X_SUBJECT_ID, "test-subject-123")
+            .with_header(header::AUTHORIZATION, "Bearer wrong-key");
+        let response = handle_data_subject_request(&settings, req).unwrap();
+        assert_eq!(response.get_status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_handle_data_verification_request() {
+        let settings = create_test_settings();
+        let cookie_id = SyntheticId::new("verify-subject".to_string());
+        let req = Request::post("https://example.com/gdpr/data/verify").with_header(
+            header::COOKIE,
+            format!("synthetic_id={}", sign_synthetic_id(&settings, &cookie_id)),
+        );
+        let mut response = handle_data_verification_request(&settings, req)
+            .expect("should handle verification request");
+        assert_eq!(response.get_status(), StatusCode::OK);
+        let body: serde_json::Value =
+            serde_json::from_str(&response.take_body_str()).expect("should parse token response");
+        assert_eq!(body["synthetic_id"], "verify-subject");
+        assert!(verify_verification_token(
+            &settings,
+            body["token"].as_str().expect("should include token"),
+            &cookie_id,
+            chrono::Utc::now().timestamp()
+        ));
+
+        // A synthetic ID generated from the request signals can be recomputed
+        // by anyone sharing the client's network and browser
+        let req = Request::post("https://example.com/gdpr/data/verify")
+            .with_header(header::USER_AGENT, "Verify/1.0");
+        let response = handle_data_verification_request(&settings, req)
+            .expect("should handle verification request");
+        assert_eq!(response.get_status(), StatusCode::FORBIDDEN);
+
+        // A synthetic ID claimed through the header is not proof of ownership
+        let req = Request::post("https://example.com/gdpr/data/verify")
+            .with_header(HEADER_SYNTHETIC_TRUSTED_SERVER, "someone-else");
+        let response = handle_data_verification_request(&settings, req)
+            .expect("should handle verification request");
+        assert_eq!(response.get_status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn test_header_supplied_id_cannot_get_token() {
+        let settings = create_test_settings();
+        // The main page never signs a claimed ID into the cookie...
+        let req = Request::get("https://example.com/")
+            .with_header(HEADER_SYNTHETIC_TRUSTED_SERVER, "victim-synthetic");
+        let resolved = crate::synthetic::resolve_synthetic_id(&settings, &req).unwrap();
+        assert_eq!(resolved.value, "victim-synthetic");
+        assert_eq!(
+            crate::cookies::verified_synthetic_cookie(&settings, &resolved),
+            None
+        );
+
+        // ...so the claim alone is all the requester has to show for it
+        let req = Request::post("https://example.com/gdpr/data/verify")
+            .with_header(HEADER_SYNTHETIC_TRUSTED_SERVER, "victim-synthetic");
+        let response = handle_data_verification_request(&settings, req).unwrap();
+        assert_eq!(response.get_status(), StatusCode::FORBIDDEN);
+    }
+
     #[test]
     fn test_handle_data_subject_request_get_without_id() {
         let settings = create_test_settings();
@@ -401,10 +2010,14 @@ mod tests {
         let settings = create_test_settings();
         let mut req = Request::delete("https://example.com/gdpr/data");
         req.set_header(HEADER_X_SUBJECT_ID, "test-subject-123");
+        req = req.with_dsar_token(&settings, "test-subject-123");
 
         let response = handle_data_subject_request(&settings, req).unwrap();
         assert_eq!(response.get_status(), StatusCode::OK);
-        assert_eq!(response.into_body_str(), "Data deletion request processed");
+        let summary: ErasureSummary =
+            serde_json::from_str(&response.into_body_str()).expect("should parse JSON");
+        assert_eq!(summary.synthetic_id, "test-subject-123");
+        assert!(!summary.tombstoned);
     }
 
     #[test]
@@ -417,6 +2030,87 @@ mod tests {
         assert_eq!(response.into_body_str(), "Missing subject ID");
     }
 
+    #[test]
+    fn test_handle_data_subject_request_patch_rectifies() {
+        let mut settings = create_test_settings();
+        settings.identity.link_store = "test_identity_store".to_string();
+        let store = IdentityStore::open(&settings)
+            .expect("should open the identity store")
+            .expect("should have an identity store configured");
+        let key = IdentityKey::PublisherUserId("shared-device-user".to_string());
+        store
+            .link(&SyntheticId::new("rectify-subject".to_string()), &key)
+            .expect("should write to the store");
+        let request = |body: &str| {
+            Request::patch("https://example.com/gdpr/data")
+                .with_header(HEADER_X_SUBJECT_ID, "rectify-subject")
+                .with_dsar_token(&settings, "rectify-subject")
+                .with_body(body.to_string())
+        };
+
+        let req = Request::get("https://example.com/gdpr/data")
+            .with_header(HEADER_X_SUBJECT_ID, "rectify-subject")
+            .with_dsar_token(&settings, "rectify-subject");
+        let response =
+            handle_data_subject_request(&settings, req).expect("should handle the request");
+        let data: HashMap<String, UserData> =
+            serde_json::from_str(&response.into_body_str()).expect("should parse JSON");
+        assert!(data["rectify-subject"]
+            .records
+            .iter()
+            .any(|r| r.category == "pub_user_id" && r.value == "shared-device-user"));
+
+        let response = handle_data_subject_request(&settings, request("not json"))
+            .expect("should handle the request");
+        assert_eq!(response.get_status(), StatusCode::BAD_REQUEST);
+
+        let response = handle_data_subject_request(
+            &settings,
+            request(r#"{"unlink": {"pub_user_ids": ["shared-device-user"]}}"#),
+        )
+        .expect("should handle the request");
+        assert_eq!(response.get_status(), StatusCode::OK);
+        let summary: RectificationSummary =
+            serde_json::from_str(&response.into_body_str()).expect("should parse JSON");
+        assert_eq!(summary.unlinked.pub_user_ids, vec!["shared-device-user"]);
+        assert!(store
+            .links_for("rectify-subject")
+            .expect("should read the store")
+            .pub_user_ids
+            .is_empty());
+    }
+
+    #[test]
+    fn test_handle_data_export_bundle() {
+        let settings = create_test_settings();
+        JsonKvStore::open(&settings.synthetic.counter_store)
+            .unwrap()
+            .put_text("bundle-subject", "4")
+            .unwrap();
+
+        let req = Request::get("https://example.com/gdpr/data/export")
+            .with_header(HEADER_X_SUBJECT_ID, "bundle-subject");
+        let response = handle_data_subject_request(&settings, req).unwrap();
+        assert_eq!(response.get_status(), StatusCode::UNAUTHORIZED);
+
+        let req = Request::get("https://example.com/gdpr/data/export")
+            .with_header(HEADER_X_SUBJECT_ID, "bundle-subject")
+            .with_dsar_token(&settings, "bundle-subject");
+        let response = handle_data_subject_request(&settings, req).unwrap();
+        assert_eq!(response.get_status(), StatusCode::OK);
+        assert!(response
+            .get_header_str(header::CONTENT_DISPOSITION)
+            .unwrap()
+            .starts_with("attachment"));
+
+        let bytes = response.into_body_bytes();
+        assert!(crate::portability::verify_bundle(&settings, &bytes));
+        let bundle: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(bundle["subject_id"], "bundle-subject");
+        assert_eq!(bundle["records"][0]["category"], "visit_count");
+        assert_eq!(bundle["records"][0]["value"], "4");
+    }
+
     #[test]
     fn test_handle_data_subject_request_invalid_method() {
         let settings = create_test_settings();
@@ -431,9 +2125,11 @@ mod tests {
     fn test_user_data_serialization() {
         let user_data = UserData {
             visit_count: 5,
-            last_visit: 1234567890,
+            last_visit: Some(1234567890),
+            opid: Some("opid-123".to_string()),
             ad_interactions: vec!["click1".to_string(), "view2".to_string()],
             consent_history: vec![GdprConsent::default()],
+            records: Vec::new(),
         };
 
         let json = serde_json::to_string(&user_data).unwrap();
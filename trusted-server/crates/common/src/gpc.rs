@@ -0,0 +1,82 @@
+//! Global Privacy Control (`Sec-GPC`) handling.
+//!
+//! Browsers with GPC enabled send `Sec-GPC: 1` on every request. In regions
+//! where GPC is a legally binding opt-out (see `gpc.binding_regions`), the
+//! signal is a consent signal of its own in
+//! [`ConsentSignals`](crate::privacy::ConsentSignals): it denies personalized
+//! advertising under every regime, whether or not the request carries a TC
+//! string, US Privacy string or GPP string.
+
+use fastly::geo::geo_lookup;
+use fastly::Request;
+
+use crate::constants::HEADER_SEC_GPC;
+use crate::settings::Settings;
+
+/// Returns `true` if the request carries `Sec-GPC: 1`.
+pub fn has_gpc_signal(req: &Request) -> bool {
+    req.get_header_str(HEADER_SEC_GPC)
+        .is_some_and(|value| value.trim() == "1")
+}
+
+/// Returns `true` if a country and optional ISO 3166-2 subdivision match one
+/// of the configured binding regions.
+fn is_binding_region(binding_regions: &[String], country: &str, region: Option<&str>) -> bool {
+    binding_regions
+        .iter()
+        .any(|binding| match binding.split_once('-') {
+            Some((binding_country, binding_region)) => {
+                binding_country.eq_ignore_ascii_case(country)
+                    && region.is_some_and(|region| binding_region.eq_ignore_ascii_case(region))
+            }
+            None => binding == "*" || binding.eq_ignore_ascii_case(country),
+        })
+}
+
+/// Returns `true` if the request carries a GPC signal that is binding in the
+/// client's region.
+///
+/// The region is looked up from the client IP; requests without geo data are
+/// only bound by a `*` entry.
+pub fn is_gpc_binding(settings: &Settings, req: &Request) -> bool {
+    if !has_gpc_signal(req) {
+        return false;
+    }
+    let binding_regions = &settings.gpc.binding_regions;
+    if binding_regions.iter().any(|binding| binding == "*") {
+        return true;
+    }
+    match req.get_client_ip_addr().and_then(geo_lookup) {
+        Some(geo) => is_binding_region(binding_regions, geo.country_code(), geo.region()),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_has_gpc_signal() {
+        let req = Request::get("https://example.com").with_header("Sec-GPC", "1");
+        assert!(has_gpc_signal(&req));
+
+        let req = Request::get("https://example.com").with_header("Sec-GPC", "0");
+        assert!(!has_gpc_signal(&req));
+
+        let req = Request::get("https://example.com");
+        assert!(!has_gpc_signal(&req));
+    }
+
+    #[test]
+    fn test_is_binding_region() {
+        let regions = vec!["US-CA".to_string(), "FR".to_string()];
+        assert!(is_binding_region(&regions, "US", Some("CA")));
+        assert!(is_binding_region(&regions, "us", Some("ca")));
+        assert!(!is_binding_region(&regions, "US", Some("NY")));
+        assert!(!is_binding_region(&regions, "US", None));
+        assert!(is_binding_region(&regions, "FR", None));
+        assert!(is_binding_region(&["*".to_string()], "DE", None));
+        assert!(!is_binding_region(&[], "US", Some("CA")));
+    }
+}
@@ -0,0 +1,545 @@
+//! IAB Global Privacy Platform (GPP) string parsing.
+//!
+//! A GPP string is a `~`-separated list: a header listing the section IDs it
+//! carries, followed by one encoded string per section. This module decodes
+//! the header and the sections relevant to ad serving:
+//! - TCF EU v2 (section 2), decoded into [`TcfConsent`]
+//! - US Privacy (section 6), kept as the raw `usp_v1` string
+//! - US National and US State sections (7-12), reduced to their opt-out signals
+//!
+//! [`GppConsent::allows_personalized_advertising`] combines them into a single
+//! decision for the ad handlers.
+
+use std::convert::TryFrom;
+
+use error_stack::Report;
+use fastly::Request;
+use lib_tcstring::TcModelV2;
+
+use crate::cookies;
+use crate::error::TrustedServerError;
+use crate::tcf_consent::{purpose_ids, TcfConsent};
+
+/// Name of the cookie and query parameter holding the GPP string.
+pub const GPP_PARAM: &str = "gpp";
+
+/// Name of the cookie and query parameter holding the applicable section IDs.
+pub const GPP_SID_PARAM: &str = "gpp_sid";
+
+/// GPP section IDs.
+pub mod section_ids {
+    pub const TCF_EU_V2: u16 = 2;
+    pub const US_PRIVACY: u16 = 6;
+    pub const US_NATIONAL: u16 = 7;
+    pub const US_CALIFORNIA: u16 = 8;
+    pub const US_VIRGINIA: u16 = 9;
+    pub const US_COLORADO: u16 = 10;
+    pub const US_UTAH: u16 = 11;
+    pub const US_CONNECTICUT: u16 = 12;
+}
+
+/// Header type value identifying a GPP header.
+const HEADER_TYPE: u64 = 3;
+
+/// Opt-out field of a US section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptOut {
+    NotApplicable,
+    OptedOut,
+    DidNotOptOut,
+}
+
+impl OptOut {
+    fn from_bits(value: u64) -> Self {
+        match value {
+            1 => Self::OptedOut,
+            2 => Self::DidNotOptOut,
+            _ => Self::NotApplicable,
+        }
+    }
+}
+
+/// Opt-out signals of a US National or US State section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsSection {
+    pub section_id: u16,
+    pub sale_opt_out: OptOut,
+    /// Not present in every state section.
+    pub sharing_opt_out: Option<OptOut>,
+    /// Not present in every state section.
+    pub targeted_advertising_opt_out: Option<OptOut>,
+    /// Global Privacy Control signal from the optional GPC subsection.
+    pub gpc: bool,
+}
+
+impl UsSection {
+    /// Whether the user opted out of sale, sharing, targeted advertising or sent GPC.
+    pub fn is_opted_out(&self) -> bool {
+        self.gpc
+            || self.sale_opt_out == OptOut::OptedOut
+            || self.sharing_opt_out == Some(OptOut::OptedOut)
+            || self.targeted_advertising_opt_out == Some(OptOut::OptedOut)
+    }
+}
+
+/// Decoded GPP string.
+#[derive(Debug, Clone)]
+pub struct GppConsent {
+    /// Original GPP string.
+    pub gpp_string: String,
+    /// Section IDs present in the string.
+    pub section_ids: Vec<u16>,
+    /// Section IDs that apply to the current transaction (`gpp_sid`); defaults
+    /// to all present sections.
+    pub applicable_sections: Vec<u16>,
+    pub tcf_eu: Option<TcfConsent>,
+    /// Raw US Privacy (`usp_v1`) string.
+    pub us_privacy: Option<String>,
+    pub us_sections: Vec<UsSection>,
+}
+
+impl GppConsent {
+    /// Returns `true` if the section applies to the current transaction.
+    pub fn applies(&self, section_id: u16) -> bool {
+        self.applicable_sections.contains(&section_id)
+    }
+
+    /// Unified advertising decision across the applicable sections.
+    ///
+    /// Personalized advertising is denied when an applicable US section signals
+    /// an opt-out, the US Privacy string signals an opt-out of sale, or the TCF
+    /// EU section lacks consent for the advertising purposes.
+    pub fn allows_personalized_advertising(&self) -> bool {
+        if self
+            .us_sections
+            .iter()
+            .any(|s| self.applies(s.section_id) && s.is_opted_out())
+        {
+            return false;
+        }
+        if self.applies(section_ids::US_PRIVACY)
+            && self
+                .us_privacy
+                .as_deref()
+                .is_some_and(|usp| usp.chars().nth(2) == Some('Y'))
+        {
+            return false;
+        }
+        if let Some(tcf) = self
+            .tcf_eu
+            .as_ref()
+            .filter(|_| self.applies(section_ids::TCF_EU_V2))
+        {
+            return tcf.purpose_consents.contains_all(purpose_ids::ADVERTISING);
+        }
+        true
+    }
+}
+
+fn gpp_error(message: impl Into<String>) -> Report<TrustedServerError> {
+    Report::new(TrustedServerError::Gpp {
+        message: message.into(),
+    })
+}
+
+/// MSB-first reader over the bits of a base64url encoded GPP segment.
+struct BitReader {
+    bits: Vec<bool>,
+    pos: usize,
+}
+
+impl BitReader {
+    fn new(segment: &str) -> Result<Self, Report<TrustedServerError>> {
+        let mut bits = Vec::with_capacity(segment.len() * 6);
+        for c in segment.trim_end_matches('=').bytes() {
+            let value = match c {
+                b'A'..=b'Z' => c - b'A',
+                b'a'..=b'z' => c - b'a' + 26,
+                b'0'..=b'9' => c - b'0' + 52,
+                b'-' => 62,
+                b'_' => 63,
+                _ => return Err(gpp_error(format!("Invalid character {:?}", c as char))),
+            };
+            bits.extend((0..6).rev().map(|i| (value >> i) & 1 == 1));
+        }
+        Ok(Self { bits, pos: 0 })
+    }
+
+    fn int(&mut self, width: usize) -> Result<u64, Report<TrustedServerError>> {
+        if self.pos + width > self.bits.len() {
+            return Err(gpp_error("Unexpected end of segment"));
+        }
+        let value = self.bits[self.pos..self.pos + width]
+            .iter()
+            .fold(0, |acc, &bit| (acc << 1) | u64::from(bit));
+        self.pos += width;
+        Ok(value)
+    }
+
+    fn skip(&mut self, width: usize) -> Result<(), Report<TrustedServerError>> {
+        self.int(width).map(|_| ())
+    }
+
+    /// Reads a Fibonacci coded integer, terminated by two consecutive 1 bits.
+    /// Integers too large for a `u64` are rejected.
+    fn fibonacci(&mut self) -> Result<u64, Report<TrustedServerError>> {
+        let overflow = || gpp_error("Fibonacci integer out of range");
+        let (mut value, mut prev, mut fib) = (0u64, 1u64, 1u64);
+        let mut last_bit = false;
+        loop {
+            let bit = self.int(1)? == 1;
+            if bit && last_bit {
+                return Ok(value);
+            }
+            if bit {
+                value = value.checked_add(fib).ok_or_else(overflow)?;
+            }
+            (prev, fib) = (fib, prev.checked_add(fib).ok_or_else(overflow)?);
+            last_bit = bit;
+        }
+    }
+}
+
+/// Section ID `delta` after `offset`.
+fn offset_by(delta: u64, offset: u64) -> Result<u64, Report<TrustedServerError>> {
+    delta
+        .checked_add(offset)
+        .ok_or_else(|| gpp_error("Section ID out of range"))
+}
+
+/// Decodes the header's Fibonacci range of section IDs.
+fn parse_header(header: &str) -> Result<Vec<u16>, Report<TrustedServerError>> {
+    let mut reader = BitReader::new(header)?;
+    if reader.int(6)? != HEADER_TYPE {
+        return Err(gpp_error("Not a GPP header"));
+    }
+    // Version
+    reader.skip(6)?;
+
+    let mut section_ids = Vec::new();
+    let mut offset = 0u64;
+    for _ in 0..reader.int(12)? {
+        let is_range = reader.int(1)? == 1;
+        let start = offset_by(reader.fibonacci()?, offset)?;
+        offset = start;
+        let end = if is_range {
+            let end = offset_by(reader.fibonacci()?, offset)?;
+            offset = end;
+            end
+        } else {
+            start
+        };
+        for id in start..=end {
+            let id = u16::try_from(id).map_err(|_| gpp_error("Section ID out of range"))?;
+            section_ids.push(id);
+        }
+    }
+    Ok(section_ids)
+}
+
+/// Bit offsets after the 6-bit version of the sale, sharing and targeted
+/// advertising opt-out fields of a US section.
+fn us_section_layout(section_id: u16) -> Option<(usize, Option<usize>, Option<usize>)> {
+    match section_id {
+        section_ids::US_NATIONAL => Some((12, Some(14), Some(16))),
+        section_ids::US_CALIFORNIA => Some((6, Some(8), None)),
+        section_ids::US_VIRGINIA | section_ids::US_COLORADO | section_ids::US_CONNECTICUT => {
+            Some((6, None, Some(8)))
+        }
+        section_ids::US_UTAH => Some((8, None, Some(10))),
+        _ => None,
+    }
+}
+
+fn parse_us_section(
+    section_id: u16,
+    layout: (usize, Option<usize>, Option<usize>),
+    section: &str,
+) -> Result<UsSection, Report<TrustedServerError>> {
+    let mut segments = section.split('.');
+    let core = segments.next().unwrap_or_default();
+
+    let read_opt_out = |offset: usize| -> Result<OptOut, Report<TrustedServerError>> {
+        let mut reader = BitReader::new(core)?;
+        reader.skip(6 + offset)?;
+        Ok(OptOut::from_bits(reader.int(2)?))
+    };
+    let (sale, sharing, targeted) = layout;
+
+    // The GPC subsection starts with a 2-bit subsection type of 1
+    let mut gpc = false;
+    for segment in segments {
+        let mut reader = BitReader::new(segment)?;
+        if reader.int(2)? == 1 {
+            gpc = reader.int(1)? == 1;
+        }
+    }
+
+    Ok(UsSection {
+        section_id,
+        sale_opt_out: read_opt_out(sale)?,
+        sharing_opt_out: sharing.map(read_opt_out).transpose()?,
+        targeted_advertising_opt_out: targeted.map(read_opt_out).transpose()?,
+        gpc,
+    })
+}
+
+/// Parses a GPP string.
+///
+/// `applicable_sections` comes from `gpp_sid`; when empty, all sections in the
+/// string apply. Sections this module does not understand are skipped.
+///
+/// # Errors
+///
+/// - [`TrustedServerError::Gpp`] if the header or a known section is malformed
+pub fn parse_gpp_string(
+    gpp_string: &str,
+    applicable_sections: &[u16],
+) -> Result<GppConsent, Report<TrustedServerError>> {
+    let mut parts = gpp_string.split('~');
+    let section_ids = parse_header(parts.next().unwrap_or_default())?;
+    let sections: Vec<&str> = parts.collect();
+    if sections.len() != section_ids.len() {
+        return Err(gpp_error(format!(
+            "Header lists {} sections but {} are present",
+            section_ids.len(),
+            sections.len()
+        )));
+    }
+
+    let mut consent = GppConsent {
+        gpp_string: gpp_string.to_string(),
+        applicable_sections: if applicable_sections.is_empty() {
+            section_ids.clone()
+        } else {
+            applicable_sections.to_vec()
+        },
+        section_ids,
+        tcf_eu: None,
+        us_privacy: None,
+        us_sections: Vec::new(),
+    };
+
+    for (&section_id, &section) in consent.section_ids.iter().zip(&sections) {
+        match section_id {
+            section_ids::TCF_EU_V2 => {
+                let model = TcModelV2::try_from(section)
+                    .map_err(|e| gpp_error(format!("Invalid TCF EU v2 section: {:?}", e)))?;
+                let tcf = TcfConsent::from_tc_model(model, section.to_string())
+                    .map_err(|e| gpp_error(format!("Invalid TCF EU v2 section: {}", e)))?;
+                consent.tcf_eu = Some(tcf);
+            }
+            section_ids::US_PRIVACY => consent.us_privacy = Some(section.to_string()),
+            _ => match us_section_layout(section_id) {
+                Some(layout) => consent
+                    .us_sections
+                    .push(parse_us_section(section_id, layout, section)?),
+                None => log::debug!("Skipping unsupported GPP section {}", section_id),
+            },
+        }
+    }
+    Ok(consent)
+}
+
+/// Parses a comma-separated `gpp_sid` list, ignoring invalid entries.
+pub fn parse_gpp_sid(gpp_sid: &str) -> Vec<u16> {
+    gpp_sid
+        .split(',')
+        .filter_map(|id| id.trim().parse().ok())
+        .collect()
+}
+
+/// Extracts GPP consent from the `gpp`/`gpp_sid` query parameters, falling
+/// back to the `gpp`/`gpp_sid` cookies.
+///
+/// Returns [`None`] if no GPP string is present or it cannot be parsed.
+pub fn get_gpp_consent_from_request(req: &Request) -> Option<GppConsent> {
+    let jar = cookies::handle_request_cookies(req).ok().flatten();
+    let lookup = |name: &str| {
+        req.get_query_parameter(name)
+            .map(str::to_string)
+            .or_else(|| {
+                jar.as_ref()
+                    .and_then(|jar| jar.get(name))
+                    .map(|c| c.value().to_string())
+            })
+            .filter(|value| !value.is_empty())
+    };
+
+    let gpp_string = lookup(GPP_PARAM)?;
+    let applicable_sections = lookup(GPP_SID_PARAM)
+        .map(|sid| parse_gpp_sid(&sid))
+        .unwrap_or_default();
+    match parse_gpp_string(&gpp_string, &applicable_sections) {
+        Ok(consent) => Some(consent),
+        Err(e) => {
+            log::warn!("Failed to parse GPP string: {:?}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TCF_SECTION: &str = "CPXxRfAPXxRfAAfKABENB-CgAAAAAAAAAAYgAAAAAAAA";
+
+    /// Encodes a bit string as a base64url GPP segment.
+    fn encode_bits(bits: &str) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+        let padded = format!("{:0<width$}", bits, width = bits.len().div_ceil(6) * 6);
+        padded
+            .as_bytes()
+            .chunks(6)
+            .map(|chunk| {
+                let value =
+                    usize::from_str_radix(std::str::from_utf8(chunk).expect("should be UTF-8"), 2)
+                        .expect("should be a binary number");
+                ALPHABET[value] as char
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_header() {
+        assert_eq!(
+            parse_header("DBABMA").expect("should parse the GPP header"),
+            vec![2]
+        );
+        assert_eq!(
+            parse_header("DBACNY").expect("should parse the GPP header"),
+            vec![2, 6]
+        );
+        assert_eq!(
+            parse_header("DBABzw").expect("should parse the GPP header"),
+            vec![6, 7]
+        );
+        assert!(parse_header("BVVqAAEABCA").is_err());
+    }
+
+    #[test]
+    fn test_parse_header_fibonacci_overflow() {
+        // Type 3, version 1, one single section ID whose Fibonacci code
+        // outgrows u64
+        let header = format!("0000110000010000000000010{}11", "10".repeat(100));
+        let err = parse_header(&encode_bits(&header)).unwrap_err();
+        assert!(format!("{err:?}").contains("Fibonacci integer out of range"));
+    }
+
+    #[test]
+    fn test_parse_tcf_and_usp_sections() {
+        let gpp = format!("DBACNY~{}~1YNN", TCF_SECTION);
+        let consent = parse_gpp_string(&gpp, &[]).expect("should parse the GPP string");
+
+        assert_eq!(consent.section_ids, vec![2, 6]);
+        assert_eq!(consent.applicable_sections, vec![2, 6]);
+        assert_eq!(
+            consent
+                .tcf_eu
+                .as_ref()
+                .expect("should have a TCF EU section")
+                .tc_string,
+            TCF_SECTION
+        );
+        assert_eq!(consent.us_privacy.as_deref(), Some("1YNN"));
+    }
+
+    #[test]
+    fn test_parse_us_national_section() {
+        let consent =
+            parse_gpp_string("DBABL~BVVqAAEABCA.QA", &[]).expect("should parse the GPP string");
+
+        let usnat = &consent.us_sections[0];
+        assert_eq!(usnat.section_id, section_ids::US_NATIONAL);
+        assert_eq!(usnat.sale_opt_out, OptOut::DidNotOptOut);
+        assert_eq!(usnat.sharing_opt_out, Some(OptOut::DidNotOptOut));
+        assert_eq!(
+            usnat.targeted_advertising_opt_out,
+            Some(OptOut::DidNotOptOut)
+        );
+        assert!(!usnat.gpc);
+        assert!(consent.allows_personalized_advertising());
+    }
+
+    #[test]
+    fn test_us_national_opt_out() {
+        // Version 1, all notices given, targeted advertising opted out
+        let core = encode_bits(&format!("000001{}101001{}", "01".repeat(6), "0".repeat(40)));
+        let consent =
+            parse_gpp_string(&format!("DBABL~{}", core), &[]).expect("should parse the GPP string");
+
+        let usnat = &consent.us_sections[0];
+        assert_eq!(usnat.sale_opt_out, OptOut::DidNotOptOut);
+        assert_eq!(usnat.targeted_advertising_opt_out, Some(OptOut::OptedOut));
+        assert!(!consent.allows_personalized_advertising());
+    }
+
+    #[test]
+    fn test_us_california_section() {
+        let consent =
+            parse_gpp_string("DBABBg~BVoYYZoI", &[]).expect("should parse the GPP string");
+
+        let usca = &consent.us_sections[0];
+        assert_eq!(usca.section_id, section_ids::US_CALIFORNIA);
+        assert_eq!(usca.sale_opt_out, OptOut::DidNotOptOut);
+        assert_eq!(usca.sharing_opt_out, Some(OptOut::DidNotOptOut));
+        assert_eq!(usca.targeted_advertising_opt_out, None);
+    }
+
+    #[test]
+    fn test_gpc_subsection_opts_out() {
+        let consent =
+            parse_gpp_string("DBABL~BVVqAAEABCA.YA", &[]).expect("should parse the GPP string");
+
+        assert!(consent.us_sections[0].gpc);
+        assert!(!consent.allows_personalized_advertising());
+    }
+
+    #[test]
+    fn test_usp_opt_out_of_sale() {
+        let consent =
+            parse_gpp_string("DBABzw~1YYN~BVVqAAEABCA", &[]).expect("should parse the GPP string");
+        assert!(!consent.allows_personalized_advertising());
+
+        // Only the US National section applies
+        let consent =
+            parse_gpp_string("DBABzw~1YYN~BVVqAAEABCA", &[7]).expect("should parse the GPP string");
+        assert!(consent.allows_personalized_advertising());
+    }
+
+    #[test]
+    fn test_tcf_section_without_advertising_consent() {
+        let consent = parse_gpp_string(&format!("DBABMA~{}", TCF_SECTION), &[])
+            .expect("should parse the GPP string");
+        assert!(!consent.allows_personalized_advertising());
+    }
+
+    #[test]
+    fn test_section_count_mismatch() {
+        assert!(parse_gpp_string("DBACNY~1YNN", &[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_gpp_sid() {
+        assert_eq!(parse_gpp_sid("2,6"), vec![2, 6]);
+        assert_eq!(parse_gpp_sid(" 7 ,x,8"), vec![7, 8]);
+        assert!(parse_gpp_sid("").is_empty());
+    }
+
+    #[test]
+    fn test_get_gpp_consent_from_request() {
+        let req = Request::get("https://example.com/?gpp=DBABL~BVVqAAEABCA.QA&gpp_sid=7");
+        let consent = get_gpp_consent_from_request(&req).expect("should read the GPP consent");
+        assert_eq!(consent.applicable_sections, vec![7]);
+
+        let req = Request::get("https://example.com/")
+            .with_header("Cookie", "gpp=DBABzw~1YNN~BVVqAAEABCA; gpp_sid=6");
+        let consent = get_gpp_consent_from_request(&req).expect("should read the GPP consent");
+        assert_eq!(consent.section_ids, vec![6, 7]);
+        assert_eq!(consent.applicable_sections, vec![6]);
+
+        let req = Request::get("https://example.com/");
+        assert!(get_gpp_consent_from_request(&req).is_none());
+    }
+}
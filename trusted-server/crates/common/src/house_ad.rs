@@ -0,0 +1,130 @@
+//! House ads filling slots GAM leaves empty.
+//!
+//! When the GAM backend fails, answers with an error status or returns no
+//! creative, the GAM handlers serve a house ad for the requested slot sizes
+//! instead of an error, so pages never show a blank slot. Creatives are
+//! looked up by `<w>x<h>` size, first in `house_ads.kv_store` under
+//! `house_ad:<size>` and then in `house_ads.creatives`; the first requested
+//! size with a creative wins, and the [`DEFAULT_SIZE`] creative fills slots
+//! no size matches.
+
+use fastly::http::{header, StatusCode};
+use fastly::Response;
+
+use crate::kv_store::JsonKvStore;
+use crate::settings::Settings;
+
+/// Size of the house ad serving any slot.
+pub const DEFAULT_SIZE: &str = "default";
+
+/// House ad creative picked for a slot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HouseAd {
+    /// Size the creative was configured for, or [`DEFAULT_SIZE`]
+    pub size: String,
+    /// Creative HTML
+    pub html: String,
+}
+
+/// Creative configured for `size`, from the KV store before the settings.
+fn creative(settings: &Settings, store: Option<&JsonKvStore>, size: &str) -> Option<String> {
+    let stored = store.and_then(|store| {
+        store
+            .get_text(&format!("house_ad:{size}"))
+            .inspect_err(|e| log::warn!("Failed to read {size} house ad: {e:?}"))
+            .ok()
+            .flatten()
+    });
+    stored.or_else(|| {
+        settings
+            .house_ads
+            .creatives
+            .iter()
+            .find(|creative| creative.size == size)
+            .map(|creative| creative.html.clone())
+    })
+}
+
+/// House ad for a slot of the requested `sizes`, if one is configured.
+pub fn house_ad(settings: &Settings, sizes: &[String]) -> Option<HouseAd> {
+    let config = &settings.house_ads;
+    let store = if config.kv_store.is_empty() {
+        None
+    } else {
+        JsonKvStore::open(&config.kv_store)
+            .inspect_err(|e| log::warn!("Failed to open house ad store: {e:?}"))
+            .ok()
+    };
+    sizes
+        .iter()
+        .map(String::as_str)
+        .chain([DEFAULT_SIZE])
+        .find_map(|size| {
+            creative(settings, store.as_ref(), size).map(|html| HouseAd {
+                size: size.to_string(),
+                html,
+            })
+        })
+}
+
+/// Response serving `ad` in place of the GAM creative.
+pub fn house_ad_response(ad: &HouseAd) -> Response {
+    Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .with_header(header::CACHE_CONTROL, "no-store, private")
+        .with_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .with_header("X-House-Ad", &ad.size)
+        .with_body(ad.html.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::HouseAdCreative;
+    use crate::test_support::tests::create_test_settings;
+
+    fn sizes(sizes: &[&str]) -> Vec<String> {
+        sizes.iter().map(|size| size.to_string()).collect()
+    }
+
+    #[test]
+    fn test_house_ad() {
+        let mut settings = create_test_settings();
+        assert_eq!(house_ad(&settings, &sizes(&["300x250"])), None);
+
+        settings.house_ads.creatives = vec![
+            HouseAdCreative {
+                size: "728x90".to_string(),
+                html: "<a href=\"/subscribe\">Subscribe</a>".to_string(),
+            },
+            HouseAdCreative {
+                size: DEFAULT_SIZE.to_string(),
+                html: "<p>Thanks for reading</p>".to_string(),
+            },
+        ];
+        let ad = house_ad(&settings, &sizes(&["970x250", "728x90"])).unwrap();
+        assert_eq!(ad.size, "728x90");
+        assert_eq!(ad.html, "<a href=\"/subscribe\">Subscribe</a>");
+        let ad = house_ad(&settings, &sizes(&["300x250"])).unwrap();
+        assert_eq!(ad.size, DEFAULT_SIZE);
+
+        let mut resp = house_ad_response(&ad);
+        assert_eq!(resp.get_header_str("X-House-Ad"), Some(DEFAULT_SIZE));
+        assert_eq!(resp.take_body_str(), "<p>Thanks for reading</p>");
+    }
+
+    #[test]
+    fn test_house_ad_from_kv_store() {
+        let mut settings = create_test_settings();
+        settings.house_ads.kv_store = "test_house_ad_store".to_string();
+        settings.house_ads.creatives = vec![HouseAdCreative {
+            size: "300x250".to_string(),
+            html: "<p>Settings</p>".to_string(),
+        }];
+        let store = JsonKvStore::open("test_house_ad_store").unwrap();
+        store.put_text("house_ad:300x250", "<p>Stored</p>").unwrap();
+
+        let ad = house_ad(&settings, &sizes(&["300x250"])).unwrap();
+        assert_eq!(ad.html, "<p>Stored</p>");
+    }
+}
@@ -0,0 +1,781 @@
+//! Server-side identity linking.
+//!
+//! This module records associations between publisher user IDs, hashed emails,
+//! and synthetic IDs in a KV store so that logged-in traffic keeps a stable
+//! synthetic ID across devices. Links are only written when the user has
+//! granted storage consent (TCF Purpose 1).
+//!
+//! Publisher user IDs are only trusted when the publisher signed them with
+//! `identity.pub_user_id_secret` (see [`get_publisher_user_id`]), since they
+//! select whose synthetic ID a request resolves to. The first device to link a
+//! publisher user keeps the link, so the user's other devices converge on its
+//! synthetic ID.
+//!
+//! The store uses three key families:
+//!
+//! - `pub:<pub_userid>` → synthetic ID
+//! - `hem:<hashed email>` → synthetic ID
+//! - `syn:<synthetic ID>` → [`IdentityLinks`] recorded for that ID
+
+use error_stack::Report;
+use fastly::http::{header, Method, StatusCode};
+use fastly::{Error, Request, Response};
+use hmac::Mac;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::constants::HEADER_SYNTHETIC_PUB_USER_ID;
+use crate::cookies::handle_request_cookies;
+use crate::error::TrustedServerError;
+use crate::kv_store::JsonKvStore;
+use crate::settings::Settings;
+use crate::signing::keyed_mac;
+use crate::synthetic::{resolve_synthetic_id, SyntheticId, SyntheticIdSource};
+use crate::tcf_consent::{get_tcf_consent_or_default, TcfConsent};
+
+/// TCF purpose required before identity links may be stored.
+const STORAGE_PURPOSE: u8 = 1;
+
+/// OpenRTB `atype` for person-based IDs that are stable across devices.
+const ATYPE_PERSON_BASED: u8 = 3;
+
+/// Body of a `POST /identity/hem` request.
+#[derive(Debug, Deserialize)]
+struct HemRequest {
+    /// Hex-encoded SHA-256 hash of the user's normalized email address.
+    hem: String,
+}
+
+/// An identifier that can be linked to a synthetic ID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdentityKey {
+    /// Publisher-provided user ID (`pub_userid` cookie or `X-Pub-User-ID` header).
+    PublisherUserId(String),
+    /// Hex-encoded SHA-256 hash of a normalized email address.
+    HashedEmail(String),
+}
+
+impl IdentityKey {
+    /// Returns the KV key under which this identifier is stored.
+    fn kv_key(&self) -> String {
+        match self {
+            Self::PublisherUserId(id) => format!("pub:{id}"),
+            Self::HashedEmail(hash) => format!("hem:{hash}"),
+        }
+    }
+}
+
+/// Identifiers linked to a single synthetic ID.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdentityLinks {
+    /// Publisher user IDs linked to the synthetic ID.
+    #[serde(default)]
+    pub pub_user_ids: Vec<String>,
+    /// Hashed emails linked to the synthetic ID.
+    #[serde(default)]
+    pub hashed_emails: Vec<String>,
+    /// Unix timestamp (seconds) of the most recent link.
+    #[serde(default)]
+    pub updated_at: i64,
+    /// Unix timestamp (seconds) when the synthetic ID was issued, so linked
+    /// IDs retire with `synthetic.max_age_days` like cookies do.
+    #[serde(default)]
+    pub issued_at: i64,
+}
+
+impl IdentityLinks {
+    /// Adds `key` to the links, returning `true` if it was not already present.
+    pub fn add(&mut self, key: &IdentityKey) -> bool {
+        let (values, value) = match key {
+            IdentityKey::PublisherUserId(id) => (&mut self.pub_user_ids, id),
+            IdentityKey::HashedEmail(hash) => (&mut self.hashed_emails, hash),
+        };
+        if values.contains(value) {
+            return false;
+        }
+        values.push(value.clone());
+        true
+    }
+
+    /// Removes `key` from the links, returning `true` if it was present.
+    pub fn remove(&mut self, key: &IdentityKey) -> bool {
+        let (values, value) = match key {
+            IdentityKey::PublisherUserId(id) => (&mut self.pub_user_ids, id),
+            IdentityKey::HashedEmail(hash) => (&mut self.hashed_emails, hash),
+        };
+        let len = values.len();
+        values.retain(|v| v != value);
+        values.len() != len
+    }
+
+    /// Every linked identifier as an [`IdentityKey`].
+    pub fn keys(&self) -> impl Iterator<Item = IdentityKey> + '_ {
+        let pub_user_ids = self.pub_user_ids.iter().cloned();
+        let hashed_emails = self.hashed_emails.iter().cloned();
+        pub_user_ids
+            .map(IdentityKey::PublisherUserId)
+            .chain(hashed_emails.map(IdentityKey::HashedEmail))
+    }
+}
+
+/// KV-backed store of identity links.
+pub struct IdentityStore {
+    store: JsonKvStore,
+}
+
+impl IdentityStore {
+    /// Opens the identity link store configured in `identity.link_store`.
+    ///
+    /// Returns [`None`] when identity linking is disabled.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the configured store cannot be opened
+    pub fn open(settings: &Settings) -> Result<Option<Self>, Report<TrustedServerError>> {
+        if settings.identity.link_store.is_empty() {
+            return Ok(None);
+        }
+        let store = JsonKvStore::open(&settings.identity.link_store)?;
+        Ok(Some(Self { store }))
+    }
+
+    /// Links `key` to `synthetic_id`, recording the ID's issue time.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the store cannot be read or written
+    pub fn link(
+        &self,
+        synthetic_id: &SyntheticId,
+        key: &IdentityKey,
+    ) -> Result<(), Report<TrustedServerError>> {
+        self.store.put(&key.kv_key(), &synthetic_id.value)?;
+
+        let mut links = self.links_for(&synthetic_id.value)?;
+        links.add(key);
+        links.updated_at = chrono::Utc::now().timestamp();
+        links.issued_at = links.issued_at.max(synthetic_id.issued_at);
+        self.store
+            .put(&format!("syn:{}", synthetic_id.value), &links)
+    }
+
+    /// Removes the link between `key` and `synthetic_id`, returning `true` if
+    /// it existed.
+    ///
+    /// The `key` → synthetic ID entry is only deleted while it still points at
+    /// `synthetic_id`, so a subject can never unlink another subject's ID.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the store cannot be read or written
+    pub fn unlink(
+        &self,
+        synthetic_id: &str,
+        key: &IdentityKey,
+    ) -> Result<bool, Report<TrustedServerError>> {
+        if self.lookup(key)?.as_deref() == Some(synthetic_id) {
+            self.store.delete(&key.kv_key())?;
+        }
+
+        let mut links = self.links_for(synthetic_id)?;
+        if !links.remove(key) {
+            return Ok(false);
+        }
+        links.updated_at = chrono::Utc::now().timestamp();
+        self.store.put(&format!("syn:{synthetic_id}"), &links)?;
+        Ok(true)
+    }
+
+    /// Deletes every link of `synthetic_id`.
+    ///
+    /// As with [`IdentityStore::unlink`], `key` → synthetic ID entries are only
+    /// deleted while they still point at `synthetic_id`.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the store cannot be read or written
+    pub fn delete(&self, synthetic_id: &str) -> Result<(), Report<TrustedServerError>> {
+        for key in self.links_for(synthetic_id)?.keys() {
+            if self.lookup(&key)?.as_deref() == Some(synthetic_id) {
+                self.store.delete(&key.kv_key())?;
+            }
+        }
+        self.store.delete(&format!("syn:{synthetic_id}"))
+    }
+
+    /// Returns the synthetic ID linked to `key`, if any.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the lookup fails
+    pub fn lookup(&self, key: &IdentityKey) -> Result<Option<String>, Report<TrustedServerError>> {
+        self.store.get(&key.kv_key())
+    }
+
+    /// Returns every identifier linked to `synthetic_id`.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the lookup fails
+    pub fn links_for(
+        &self,
+        synthetic_id: &str,
+    ) -> Result<IdentityLinks, Report<TrustedServerError>> {
+        Ok(self
+            .store
+            .get(&format!("syn:{synthetic_id}"))?
+            .unwrap_or_default())
+    }
+}
+
+/// Returns `true` if the user consented to the publisher storing identity
+/// links (TCF Purpose 1).
+pub fn has_storage_consent(consent: &TcfConsent) -> bool {
+    consent.has_publisher_consent(&[STORAGE_PURPOSE])
+}
+
+/// Normalizes a hashed email, returning [`None`] unless it is a SHA-256 hex digest.
+pub fn normalize_hashed_email(hem: &str) -> Option<String> {
+    let hem = hem.trim().to_ascii_lowercase();
+    (hem.len() == 64 && hem.bytes().all(|b| b.is_ascii_hexdigit())).then_some(hem)
+}
+
+/// Builds an OpenRTB `user.ext.eids` entry for a hashed email.
+pub fn hashed_email_eid(settings: &Settings, hem: &str) -> serde_json::Value {
+    json!({
+        "source": settings.publisher.domain,
+        "uids": [{
+            "id": hem,
+            "atype": ATYPE_PERSON_BASED,
+            "ext": {
+                "stype": "hem"
+            }
+        }]
+    })
+}
+
+/// Builds an OpenRTB `user.ext.eids` entry for a publisher user ID.
+pub fn publisher_user_id_eid(settings: &Settings, pub_user_id: &str) -> serde_json::Value {
+    json!({
+        "source": settings.publisher.domain,
+        "uids": [{
+            "id": pub_user_id,
+            "atype": ATYPE_PERSON_BASED,
+            "ext": {
+                "stype": "ppuid"
+            }
+        }]
+    })
+}
+
+/// Verifies a publisher user ID signed as `<id>.<signature>`, the signature
+/// being the hex-encoded HMAC-SHA256 of the ID under
+/// `identity.pub_user_id_secret`.
+///
+/// Returns the ID, or [`None`] if no secret is configured or the signature
+/// does not match. The comparison is performed in constant time.
+fn verify_publisher_user_id(settings: &Settings, signed_id: &str) -> Option<String> {
+    let secret = &settings.identity.pub_user_id_secret;
+    if secret.is_empty() {
+        return None;
+    }
+    let (id, signature) = signed_id.rsplit_once('.')?;
+    let signature = hex::decode(signature).ok()?;
+    keyed_mac(secret.as_bytes(), b"", &[id.as_bytes()])
+        .verify_slice(&signature)
+        .ok()?;
+    (!id.is_empty()).then(|| id.to_string())
+}
+
+/// Extracts the publisher user ID from the request.
+///
+/// Prefers the `X-Pub-User-ID` header and falls back to the `pub_userid`
+/// cookie. Both are set by the client, so only IDs the publisher signed (see
+/// [`verify_publisher_user_id`]) are accepted; anything else is ignored.
+pub fn get_publisher_user_id(settings: &Settings, req: &Request) -> Option<String> {
+    let header = req
+        .get_header(HEADER_SYNTHETIC_PUB_USER_ID)
+        .and_then(|h| h.to_str().ok())
+        .map(str::to_string);
+    let cookie = || {
+        handle_request_cookies(req)
+            .ok()
+            .flatten()
+            .and_then(|jar| jar.get("pub_userid").map(|c| c.value().to_string()))
+    };
+    let signed_id = header.filter(|id| !id.is_empty()).or_else(cookie)?;
+    let id = verify_publisher_user_id(settings, &signed_id);
+    if id.is_none() {
+        log::warn!("Ignoring publisher user ID without a valid signature");
+    }
+    id
+}
+
+/// Returns the synthetic ID previously linked to the request's publisher user
+/// ID, with its issue time.
+///
+/// # Errors
+///
+/// - [`TrustedServerError::KvStore`] if the link store cannot be read
+pub fn find_linked_synthetic_id(
+    settings: &Settings,
+    req: &Request,
+) -> Result<Option<SyntheticId>, Report<TrustedServerError>> {
+    let Some(pub_user_id) = get_publisher_user_id(settings, req) else {
+        return Ok(None);
+    };
+    let Some(store) = IdentityStore::open(settings)? else {
+        return Ok(None);
+    };
+    let Some(value) = store.lookup(&IdentityKey::PublisherUserId(pub_user_id))? else {
+        return Ok(None);
+    };
+    let issued_at = store.links_for(&value)?.issued_at;
+    Ok(Some(SyntheticId {
+        value,
+        issued_at,
+        source: SyntheticIdSource::Linked,
+    }))
+}
+
+/// Links the request's publisher user ID to `synthetic_id` when consent allows it.
+///
+/// Only IDs the server can vouch for (see [`SyntheticId::is_verified`]) are
+/// linked, and a publisher user already linked to an unexpired synthetic ID
+/// keeps it. Returns `true` if a link was written.
+///
+/// # Errors
+///
+/// - [`TrustedServerError::KvStore`] if the link store cannot be read or written
+pub fn link_request_identity(
+    settings: &Settings,
+    req: &Request,
+    synthetic_id: &SyntheticId,
+    consent: &TcfConsent,
+) -> Result<bool, Report<TrustedServerError>> {
+    if !has_storage_consent(consent) {
+        log::debug!("Skipping identity linking without storage consent");
+        return Ok(false);
+    }
+    if !synthetic_id.is_verified() {
+        return Ok(false);
+    }
+    // The first device to link the user keeps the link until its ID retires
+    let now = chrono::Utc::now().timestamp();
+    if find_linked_synthetic_id(settings, req)?.is_some_and(|id| !id.is_expired(settings, now)) {
+        return Ok(false);
+    }
+    let Some(pub_user_id) = get_publisher_user_id(settings, req) else {
+        return Ok(false);
+    };
+    let Some(store) = IdentityStore::open(settings)? else {
+        return Ok(false);
+    };
+
+    store.link(synthetic_id, &IdentityKey::PublisherUserId(pub_user_id))?;
+    Ok(true)
+}
+
+/// Handles `POST /identity/hem` requests from the publisher page.
+///
+/// Accepts a JSON body of the form `{"hem": "<sha256 hex>"}` and links the hashed
+/// email to the request's synthetic ID when the user granted storage consent.
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if the synthetic ID cannot be resolved or the link
+/// store cannot be written.
+pub fn handle_hem_request(settings: &Settings, mut req: Request) -> Result<Response, Error> {
+    if *req.get_method() != Method::POST {
+        return Ok(
+            Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_body("Method not allowed")
+        );
+    }
+    let Some(store) = IdentityStore::open(settings).map_err(|e| Error::msg(format!("{e:?}")))?
+    else {
+        return Ok(Response::from_status(StatusCode::NOT_FOUND).with_body("Not Found"));
+    };
+
+    let consent = get_tcf_consent_or_default(settings, &req);
+    if !has_storage_consent(&consent) {
+        return Ok(Response::from_status(StatusCode::FORBIDDEN)
+            .with_body("Storage consent (Purpose 1) is required"));
+    }
+
+    let Some(hem) = serde_json::from_slice::<HemRequest>(&req.take_body_bytes())
+        .ok()
+        .and_then(|body| normalize_hashed_email(&body.hem))
+    else {
+        return Ok(Response::from_status(StatusCode::BAD_REQUEST)
+            .with_body("Expected a SHA-256 hex digest in the `hem` field"));
+    };
+
+    let synthetic_id =
+        resolve_synthetic_id(settings, &req).map_err(|e| Error::msg(format!("{e:?}")))?;
+    store
+        .link(&synthetic_id, &IdentityKey::HashedEmail(hem))
+        .map_err(|e| Error::msg(format!("{e:?}")))?;
+    log::info!(
+        "Linked hashed email to Synthetic ID: {}",
+        synthetic_id.value
+    );
+
+    Ok(Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_header(header::CACHE_CONTROL, "no-store, private")
+        .with_body_json(&json!({
+            "synthetic_id": synthetic_id.value,
+            "linked": true,
+        }))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::HEADER_SYNTHETIC_TRUSTED_SERVER;
+    use crate::test_support::tests::create_test_settings;
+
+    const HEM: &str = "b4c9a289323b21a01c3e940f150eb9b8c542587f1abfd8f0e1cc1ffc5e475514";
+    /// TC string granting consent to purposes 1-3.
+    const CONSENT_COOKIE: &str = "euconsent-v2=COvFyGBOvFyGBAbAAAENAPCAAOAAAAAAAAAAAEEUACCKAAA";
+
+    fn consent_with_storage() -> TcfConsent {
+        let mut consent = TcfConsent::default();
+        consent.purpose_consents.insert(STORAGE_PURPOSE);
+        consent
+    }
+
+    fn linking_settings() -> Settings {
+        let mut settings = create_test_settings();
+        settings.identity.link_store = "test_identity_store".to_string();
+        settings.identity.pub_user_id_secret = "test-pub-user-secret".to_string();
+        settings
+    }
+
+    /// Signs `id` the way the publisher does.
+    fn signed(settings: &Settings, id: &str) -> String {
+        let secret = settings.identity.pub_user_id_secret.as_bytes();
+        let mac = keyed_mac(secret, b"", &[id.as_bytes()]);
+        format!("{id}.{}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    fn pub_user_request(settings: &Settings, id: &str) -> Request {
+        Request::get("http://example.com").with_header(
+            header::COOKIE,
+            format!("pub_userid={}", signed(settings, id)),
+        )
+    }
+
+    #[test]
+    fn test_identity_links_add_deduplicates() {
+        let mut links = IdentityLinks::default();
+        let key = IdentityKey::PublisherUserId("user-1".to_string());
+
+        assert!(links.add(&key));
+        assert!(!links.add(&key));
+        assert!(links.add(&IdentityKey::HashedEmail("abc".to_string())));
+        assert_eq!(links.pub_user_ids, vec!["user-1"]);
+        assert_eq!(links.hashed_emails, vec!["abc"]);
+        assert_eq!(links.keys().count(), 2);
+
+        assert!(links.remove(&key));
+        assert!(!links.remove(&key));
+        assert!(links.pub_user_ids.is_empty());
+    }
+
+    #[test]
+    fn test_get_publisher_user_id_prefers_header() {
+        let settings = linking_settings();
+        let req = Request::get("http://example.com")
+            .with_header(
+                HEADER_SYNTHETIC_PUB_USER_ID,
+                signed(&settings, "header-user"),
+            )
+            .with_header(
+                header::COOKIE,
+                format!("pub_userid={}", signed(&settings, "cookie-user")),
+            );
+        assert_eq!(
+            get_publisher_user_id(&settings, &req),
+            Some("header-user".to_string())
+        );
+
+        let req = pub_user_request(&settings, "cookie-user");
+        assert_eq!(
+            get_publisher_user_id(&settings, &req),
+            Some("cookie-user".to_string())
+        );
+
+        let req = Request::get("http://example.com");
+        assert_eq!(get_publisher_user_id(&settings, &req), None);
+    }
+
+    #[test]
+    fn test_get_publisher_user_id_requires_signature() {
+        let settings = linking_settings();
+        let req = Request::get("http://example.com")
+            .with_header(HEADER_SYNTHETIC_PUB_USER_ID, "victim-user");
+        assert_eq!(get_publisher_user_id(&settings, &req), None);
+
+        let forged = format!("victim-user.{}", "00".repeat(32));
+        let req = Request::get("http://example.com")
+            .with_header(header::COOKIE, format!("pub_userid={forged}"));
+        assert_eq!(get_publisher_user_id(&settings, &req), None);
+
+        // Without a secret no publisher user ID is trusted
+        let req = pub_user_request(&settings, "user-1");
+        assert_eq!(get_publisher_user_id(&create_test_settings(), &req), None);
+    }
+
+    #[test]
+    fn test_linking_disabled_without_store() {
+        let mut settings = linking_settings();
+        settings.identity.link_store = String::new();
+        let req = pub_user_request(&settings, "user-1");
+
+        assert!(IdentityStore::open(&settings)
+            .expect("should open the identity store")
+            .is_none());
+        let synthetic_id = SyntheticId::new("synthetic".to_string());
+        let linked = link_request_identity(&settings, &req, &synthetic_id, &consent_with_storage())
+            .expect("should link the identity");
+        assert!(!linked);
+    }
+
+    #[test]
+    fn test_linking_requires_storage_consent() {
+        let settings = linking_settings();
+        let req = pub_user_request(&settings, "no-consent-user");
+
+        let synthetic_id = SyntheticId::new("synthetic".to_string());
+        let linked = link_request_identity(&settings, &req, &synthetic_id, &TcfConsent::default())
+            .expect("should link the identity");
+        assert!(!linked);
+        assert_eq!(
+            find_linked_synthetic_id(&settings, &req).expect("should look up the linked ID"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_link_and_lookup() {
+        let settings = linking_settings();
+        let req = pub_user_request(&settings, "linked-user");
+        let synthetic_id = SyntheticId {
+            issued_at: chrono::Utc::now().timestamp() - 60,
+            ..SyntheticId::new("synthetic-1".to_string())
+        };
+
+        let linked = link_request_identity(&settings, &req, &synthetic_id, &consent_with_storage())
+            .expect("should link the identity");
+        assert!(linked);
+        let found = find_linked_synthetic_id(&settings, &req)
+            .expect("should look up the linked ID")
+            .expect("should find the linked ID");
+        assert_eq!(found.value, "synthetic-1");
+        assert_eq!(found.source, SyntheticIdSource::Linked);
+        // The linked ID keeps its issue time instead of starting a new retention window
+        assert_eq!(found.issued_at, synthetic_id.issued_at);
+
+        // An unsigned claim to the same publisher user finds nothing
+        let forged = Request::get("http://example.com")
+            .with_header(HEADER_SYNTHETIC_PUB_USER_ID, "linked-user");
+        assert_eq!(
+            find_linked_synthetic_id(&settings, &forged).expect("should look up the linked ID"),
+            None
+        );
+
+        let store = IdentityStore::open(&settings)
+            .expect("should open the identity store")
+            .expect("should have an identity store configured");
+        store
+            .link(&synthetic_id, &IdentityKey::HashedEmail("hash".to_string()))
+            .expect("should write to the store");
+        let links = store
+            .links_for("synthetic-1")
+            .expect("should read the store");
+        assert_eq!(links.pub_user_ids, vec!["linked-user"]);
+        assert_eq!(links.hashed_emails, vec!["hash"]);
+        assert!(links.updated_at > 0);
+    }
+
+    #[test]
+    fn test_first_device_keeps_link() {
+        let settings = linking_settings();
+        let req = pub_user_request(&settings, "multi-device-user");
+        let first = SyntheticId::new("first-device".to_string());
+        let second = SyntheticId {
+            source: SyntheticIdSource::Cookie,
+            ..SyntheticId::new("second-device".to_string())
+        };
+
+        assert!(
+            link_request_identity(&settings, &req, &first, &consent_with_storage())
+                .expect("should link the identity")
+        );
+        assert!(
+            !link_request_identity(&settings, &req, &second, &consent_with_storage())
+                .expect("should link the identity")
+        );
+        let found = find_linked_synthetic_id(&settings, &req)
+            .expect("should look up the linked ID")
+            .expect("should find the linked ID");
+        assert_eq!(found.value, "first-device");
+
+        // Claimed IDs are never linked
+        let req = pub_user_request(&settings, "claimed-user");
+        let claimed = SyntheticId {
+            source: SyntheticIdSource::Header,
+            ..SyntheticId::new("claimed-device".to_string())
+        };
+        assert!(
+            !link_request_identity(&settings, &req, &claimed, &consent_with_storage())
+                .expect("should link the identity")
+        );
+
+        // A link whose ID retired is handed over
+        let max_age = i64::from(settings.synthetic.max_age_days) * 24 * 60 * 60;
+        let req = pub_user_request(&settings, "retired-user");
+        let retired = SyntheticId {
+            issued_at: chrono::Utc::now().timestamp() - max_age - 1,
+            ..SyntheticId::new("retired-device".to_string())
+        };
+        assert!(
+            link_request_identity(&settings, &req, &retired, &consent_with_storage())
+                .expect("should link the identity")
+        );
+        assert!(
+            link_request_identity(&settings, &req, &second, &consent_with_storage())
+                .expect("should link the identity")
+        );
+        let found = find_linked_synthetic_id(&settings, &req)
+            .expect("should look up the linked ID")
+            .expect("should find the linked ID");
+        assert_eq!(found.value, "second-device");
+    }
+
+    #[test]
+    fn test_unlink() {
+        let settings = linking_settings();
+        let store = IdentityStore::open(&settings)
+            .expect("should open the identity store")
+            .expect("should have an identity store configured");
+        let key = IdentityKey::PublisherUserId("unlinked-user".to_string());
+        store
+            .link(&SyntheticId::new("synthetic-2".to_string()), &key)
+            .expect("should write to the store");
+        store
+            .link(&SyntheticId::new("synthetic-3".to_string()), &key)
+            .expect("should write to the store");
+
+        // The key now resolves to synthetic-3, which unlinking synthetic-2 must not touch
+        assert!(store
+            .unlink("synthetic-2", &key)
+            .expect("should update the store"));
+        assert!(!store
+            .unlink("synthetic-2", &key)
+            .expect("should update the store"));
+        assert_eq!(
+            store.lookup(&key).expect("should read the store"),
+            Some("synthetic-3".to_string())
+        );
+
+        assert!(store
+            .unlink("synthetic-3", &key)
+            .expect("should update the store"));
+        assert_eq!(store.lookup(&key).expect("should read the store"), None);
+        assert!(store
+            .links_for("synthetic-3")
+            .expect("should read the store")
+            .pub_user_ids
+            .is_empty());
+    }
+
+    #[test]
+    fn test_normalize_hashed_email() {
+        assert_eq!(
+            normalize_hashed_email(&format!(" {} ", HEM.to_uppercase())),
+            Some(HEM.to_string())
+        );
+        assert_eq!(normalize_hashed_email("user@example.com"), None);
+        assert_eq!(normalize_hashed_email(&HEM[..63]), None);
+    }
+
+    #[test]
+    fn test_hashed_email_eid_format() {
+        let settings = create_test_settings();
+        let eid = hashed_email_eid(&settings, HEM);
+
+        assert_eq!(eid["source"], "test-publisher.com");
+        assert_eq!(eid["uids"][0]["id"], HEM);
+        assert_eq!(eid["uids"][0]["atype"], 3);
+    }
+
+    #[test]
+    fn test_handle_hem_request_disabled() {
+        let settings = create_test_settings();
+        let req = Request::post("http://example.com/identity/hem")
+            .with_body_json(&json!({ "hem": HEM }))
+            .expect("should serialize the body");
+
+        let resp = handle_hem_request(&settings, req).expect("should handle the request");
+        assert_eq!(resp.get_status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_handle_hem_request_rejects_get() {
+        let settings = linking_settings();
+        let req = Request::get("http://example.com/identity/hem");
+
+        let resp = handle_hem_request(&settings, req).expect("should handle the request");
+        assert_eq!(resp.get_status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn test_handle_hem_request_requires_consent() {
+        let settings = linking_settings();
+        let req = Request::post("http://example.com/identity/hem")
+            .with_body_json(&json!({ "hem": HEM }))
+            .expect("should serialize the body");
+
+        let resp = handle_hem_request(&settings, req).expect("should handle the request");
+        assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn test_handle_hem_request_rejects_invalid_body() {
+        let settings = linking_settings();
+        let req = Request::post("http://example.com/identity/hem")
+            .with_header(header::COOKIE, CONSENT_COOKIE)
+            .with_body_json(&json!({ "hem": "user@example.com" }))
+            .expect("should serialize the body");
+
+        let resp = handle_hem_request(&settings, req).expect("should handle the request");
+        assert_eq!(resp.get_status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_handle_hem_request_links_hashed_email() {
+        let settings = linking_settings();
+        let req = Request::post("http://example.com/identity/hem")
+            .with_header(HEADER_SYNTHETIC_TRUSTED_SERVER, "posted-synthetic")
+            .with_header(header::COOKIE, CONSENT_COOKIE)
+            .with_body_json(&json!({ "hem": HEM }))
+            .expect("should serialize the body");
+
+        let mut resp = handle_hem_request(&settings, req).expect("should handle the request");
+        assert_eq!(resp.get_status(), StatusCode::OK);
+        let body: serde_json::Value = resp.take_body_json().expect("should parse JSON");
+        assert_eq!(body["synthetic_id"], "posted-synthetic");
+
+        let store = IdentityStore::open(&settings)
+            .expect("should open the identity store")
+            .expect("should have an identity store configured");
+        assert_eq!(
+            store
+                .lookup(&IdentityKey::HashedEmail(HEM.to_string()))
+                .expect("should read the store"),
+            Some("posted-synthetic".to_string())
+        );
+    }
+}
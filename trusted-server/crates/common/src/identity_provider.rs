@@ -0,0 +1,558 @@
+//! Identity provider adapters for OpenRTB `user.ext.eids`.
+//!
+//! Each [`IdentityProvider`] resolves zero or more eids for the current user.
+//! Prebid requests merge the output of every enabled provider into one eid
+//! per source (see [`resolve_eids`]), so adding a new identity partner only
+//! requires a new implementation registered in [`identity_providers`].
+//! Providers calling a remote API, like ID5 and LiveRamp ATS, send their
+//! lookups concurrently and are only waited for until the auction deadline.
+
+use error_stack::{Report, ResultExt};
+use fastly::http::request::PendingRequest;
+use fastly::http::{header, Method, StatusCode};
+use fastly::{Request, Response};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::deadline::{wait_until, Deadline};
+use crate::error::TrustedServerError;
+use crate::identity::{
+    has_storage_consent, hashed_email_eid, publisher_user_id_eid, IdentityLinks, IdentityStore,
+};
+use crate::settings::Settings;
+use crate::tcf_consent::vendor_list_manager::load_vendor_list;
+use crate::tcf_consent::{TcfConsent, VendorList};
+use crate::uid2::uid2_eids;
+
+/// Fastly backend pointing at the ID5 API.
+const ID5_BACKEND: &str = "id5_backend";
+
+/// Fastly backend pointing at the LiveRamp ATS API.
+const LIVERAMP_BACKEND: &str = "liveramp_backend";
+
+/// IAB Global Vendor List ID of ID5.
+const ID5_VENDOR_ID: u16 = 131;
+
+/// IAB Global Vendor List ID of LiveRamp.
+const LIVERAMP_VENDOR_ID: u16 = 97;
+
+/// Request-scoped inputs shared by all identity providers.
+pub struct IdentityContext<'a> {
+    /// Stable synthetic ID of the user.
+    pub synthetic_id: &'a str,
+    /// Domain of the page the request is made for.
+    pub domain: &'a str,
+    /// TCF consent of the user.
+    pub consent: &'a TcfConsent,
+    /// Publisher user IDs linked to the synthetic ID.
+    pub pub_user_ids: Vec<String>,
+    /// Hashed emails linked to the synthetic ID.
+    pub hashed_emails: Vec<String>,
+    /// IAB Global Vendor List used to validate vendor consent, when available.
+    pub vendor_list: Option<VendorList>,
+}
+
+impl<'a> IdentityContext<'a> {
+    /// Creates a context, loading the publisher user IDs and hashed emails
+    /// linked to `synthetic_id` and the Global Vendor List.
+    ///
+    /// Linked identifiers are only loaded with storage consent; lookup
+    /// failures are logged and treated as no links or no vendor list.
+    pub fn new(
+        settings: &Settings,
+        synthetic_id: &'a str,
+        domain: &'a str,
+        consent: &'a TcfConsent,
+    ) -> Self {
+        let links = if has_storage_consent(consent) {
+            IdentityStore::open(settings)
+                .and_then(|store| match store {
+                    Some(store) => store.links_for(synthetic_id),
+                    None => Ok(IdentityLinks::default()),
+                })
+                .unwrap_or_else(|e| {
+                    log::warn!("Failed to load identity links: {:?}", e);
+                    IdentityLinks::default()
+                })
+        } else {
+            IdentityLinks::default()
+        };
+
+        Self {
+            synthetic_id,
+            domain,
+            consent,
+            pub_user_ids: links.pub_user_ids,
+            hashed_emails: links.hashed_emails,
+            vendor_list: load_vendor_list(settings),
+        }
+    }
+}
+
+/// Eids of an [`IdentityProvider`] for the current user.
+pub enum Resolution {
+    /// Eids known without a remote call.
+    Eids(Vec<serde_json::Value>),
+    /// Lookup sent to the provider's API, answered through
+    /// [`IdentityProvider::parse`].
+    Pending(Box<PendingRequest>),
+}
+
+/// A source of user identifiers for `user.ext.eids`.
+pub trait IdentityProvider {
+    /// Short name used in logs.
+    fn name(&self) -> &'static str;
+
+    /// Resolves the eids this provider contributes for the current user, or
+    /// sends the lookup answering them without waiting for it.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`TrustedServerError`] if the provider fails to resolve an ID.
+    fn resolve(
+        &self,
+        settings: &Settings,
+        ctx: &IdentityContext,
+    ) -> Result<Resolution, Report<TrustedServerError>>;
+
+    /// Eids in the response to a [`Resolution::Pending`] lookup. Providers
+    /// that never send one find none.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`TrustedServerError`] if the response is an error or invalid.
+    fn parse(&self, _resp: Response) -> Result<Vec<serde_json::Value>, Report<TrustedServerError>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Publisher first-party synthetic ID.
+pub struct FirstPartyIdProvider;
+
+impl IdentityProvider for FirstPartyIdProvider {
+    fn name(&self) -> &'static str {
+        "first_party"
+    }
+
+    fn resolve(
+        &self,
+        settings: &Settings,
+        ctx: &IdentityContext,
+    ) -> Result<Resolution, Report<TrustedServerError>> {
+        Ok(Resolution::Eids(vec![json!({
+            "source": settings.publisher.domain,
+            "uids": [{
+                "id": ctx.synthetic_id,
+                "atype": 1
+            }]
+        })]))
+    }
+}
+
+/// Publisher user IDs (`pub_userid`) linked to the synthetic ID.
+pub struct PublisherUserIdProvider;
+
+impl IdentityProvider for PublisherUserIdProvider {
+    fn name(&self) -> &'static str {
+        "pub_userid"
+    }
+
+    fn resolve(
+        &self,
+        settings: &Settings,
+        ctx: &IdentityContext,
+    ) -> Result<Resolution, Report<TrustedServerError>> {
+        Ok(Resolution::Eids(
+            ctx.pub_user_ids
+                .iter()
+                .map(|id| publisher_user_id_eid(settings, id))
+                .collect(),
+        ))
+    }
+}
+
+/// Hashed emails linked through `POST /identity/hem`.
+pub struct HashedEmailProvider;
+
+impl IdentityProvider for HashedEmailProvider {
+    fn name(&self) -> &'static str {
+        "hem"
+    }
+
+    fn resolve(
+        &self,
+        settings: &Settings,
+        ctx: &IdentityContext,
+    ) -> Result<Resolution, Report<TrustedServerError>> {
+        Ok(Resolution::Eids(
+            ctx.hashed_emails
+                .iter()
+                .map(|hem| hashed_email_eid(settings, hem))
+                .collect(),
+        ))
+    }
+}
+
+/// Cached UID2 advertising tokens.
+pub struct Uid2Provider;
+
+impl IdentityProvider for Uid2Provider {
+    fn name(&self) -> &'static str {
+        "uid2"
+    }
+
+    fn resolve(
+        &self,
+        settings: &Settings,
+        ctx: &IdentityContext,
+    ) -> Result<Resolution, Report<TrustedServerError>> {
+        uid2_eids(settings, ctx.synthetic_id, ctx.consent).map(Resolution::Eids)
+    }
+}
+
+/// ID5 universal ID resolved through the ID5 server-side API.
+pub struct Id5Provider {
+    partner_id: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct Id5Response {
+    universal_uid: String,
+    #[serde(default)]
+    ext: Option<serde_json::Value>,
+}
+
+impl IdentityProvider for Id5Provider {
+    fn name(&self) -> &'static str {
+        "id5"
+    }
+
+    fn resolve(
+        &self,
+        settings: &Settings,
+        ctx: &IdentityContext,
+    ) -> Result<Resolution, Report<TrustedServerError>> {
+        if !ctx
+            .consent
+            .has_functional_consent(ID5_VENDOR_ID, ctx.vendor_list.as_ref())
+        {
+            return Ok(Resolution::Eids(Vec::new()));
+        }
+
+        let mut body = json!({
+            "partner": self.partner_id,
+            "v": env!("CARGO_PKG_VERSION"),
+            "o": "api",
+            "tml": format!("https://{}", ctx.domain),
+            "gdpr": if ctx.consent.gdpr_applies { 1 } else { 0 },
+            "gdpr_consent": ctx.consent.tc_string,
+            "1puid": ctx.synthetic_id,
+        });
+        if let Some(hem) = ctx.hashed_emails.first() {
+            body["hem"] = json!(hem);
+        }
+
+        let pending = Request::new(
+            Method::POST,
+            format!(
+                "{}/g/v2/{}.json",
+                settings.identity.id5.api_url.trim_end_matches('/'),
+                self.partner_id
+            ),
+        )
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_body_json(&body)
+        .change_context(provider_error("id5", "Failed to serialize request"))?
+        .send_async(ID5_BACKEND)
+        .change_context(provider_error("id5", "Request failed"))?;
+        Ok(Resolution::Pending(Box::new(pending)))
+    }
+
+    fn parse(
+        &self,
+        mut resp: Response,
+    ) -> Result<Vec<serde_json::Value>, Report<TrustedServerError>> {
+        if resp.get_status() != StatusCode::OK {
+            return Err(Report::new(provider_error(
+                "id5",
+                &format!("Unexpected status {}", resp.get_status()),
+            )));
+        }
+
+        let id5: Id5Response = resp
+            .take_body_json()
+            .change_context(provider_error("id5", "Invalid response"))?;
+        if id5.universal_uid.is_empty() || id5.universal_uid == "0" {
+            return Ok(Vec::new());
+        }
+
+        let mut uid = json!({
+            "id": id5.universal_uid,
+            "atype": 1
+        });
+        if let Some(ext) = id5.ext {
+            uid["ext"] = ext;
+        }
+        Ok(vec![json!({
+            "source": "id5-sync.com",
+            "uids": [uid]
+        })])
+    }
+}
+
+/// LiveRamp RampID envelope resolved through the ATS API.
+pub struct LiveRampProvider {
+    placement_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LiveRampResponse {
+    envelope: String,
+}
+
+impl IdentityProvider for LiveRampProvider {
+    fn name(&self) -> &'static str {
+        "liveramp"
+    }
+
+    fn resolve(
+        &self,
+        settings: &Settings,
+        ctx: &IdentityContext,
+    ) -> Result<Resolution, Report<TrustedServerError>> {
+        // ATS only resolves authenticated users
+        let Some(hem) = ctx.hashed_emails.first() else {
+            return Ok(Resolution::Eids(Vec::new()));
+        };
+        if !ctx
+            .consent
+            .has_functional_consent(LIVERAMP_VENDOR_ID, ctx.vendor_list.as_ref())
+        {
+            return Ok(Resolution::Eids(Vec::new()));
+        }
+
+        let pending = Request::new(
+            Method::GET,
+            format!(
+                "{}/api/identity/envelope?pid={}&it=4&iv={}",
+                settings.identity.liveramp.api_url.trim_end_matches('/'),
+                urlencoding::encode(&self.placement_id),
+                hem
+            ),
+        )
+        .send_async(LIVERAMP_BACKEND)
+        .change_context(provider_error("liveramp", "Request failed"))?;
+        Ok(Resolution::Pending(Box::new(pending)))
+    }
+
+    fn parse(
+        &self,
+        mut resp: Response,
+    ) -> Result<Vec<serde_json::Value>, Report<TrustedServerError>> {
+        if resp.get_status() != StatusCode::OK {
+            return Err(Report::new(provider_error(
+                "liveramp",
+                &format!("Unexpected status {}", resp.get_status()),
+            )));
+        }
+
+        let envelope: LiveRampResponse = resp
+            .take_body_json()
+            .change_context(provider_error("liveramp", "Invalid response"))?;
+        Ok(vec![json!({
+            "source": "liveramp.com",
+            "uids": [{
+                "id": envelope.envelope,
+                "atype": 3
+            }]
+        })])
+    }
+}
+
+fn provider_error(provider: &str, message: &str) -> TrustedServerError {
+    TrustedServerError::IdentityProvider {
+        provider: provider.to_string(),
+        message: message.to_string(),
+    }
+}
+
+/// Returns every identity provider enabled in `settings`.
+pub fn identity_providers(settings: &Settings) -> Vec<Box<dyn IdentityProvider>> {
+    let mut providers: Vec<Box<dyn IdentityProvider>> = vec![
+        Box::new(FirstPartyIdProvider),
+        Box::new(PublisherUserIdProvider),
+        Box::new(HashedEmailProvider),
+        Box::new(Uid2Provider),
+    ];
+    if settings.identity.id5.partner_id != 0 {
+        providers.push(Box::new(Id5Provider {
+            partner_id: settings.identity.id5.partner_id,
+        }));
+    }
+    if !settings.identity.liveramp.placement_id.is_empty() {
+        providers.push(Box::new(LiveRampProvider {
+            placement_id: settings.identity.liveramp.placement_id.clone(),
+        }));
+    }
+    providers
+}
+
+/// Merges eids with the same `source` into one, as OpenRTB expects a single
+/// eid per source, dropping repeated uids.
+fn merge_eids(eids: impl IntoIterator<Item = serde_json::Value>) -> Vec<serde_json::Value> {
+    let mut merged: Vec<serde_json::Value> = Vec::new();
+    for eid in eids {
+        let Some(existing) = merged.iter_mut().find(|e| e["source"] == eid["source"]) else {
+            merged.push(eid);
+            continue;
+        };
+        let Some(uids) = existing["uids"].as_array_mut() else {
+            continue;
+        };
+        for uid in eid["uids"].as_array().into_iter().flatten() {
+            if !uids.contains(uid) {
+                uids.push(uid.clone());
+            }
+        }
+    }
+    merged
+}
+
+/// Builds `user.ext.eids` from every enabled identity provider, with one eid
+/// per source.
+///
+/// Lookups of providers calling a remote API are sent concurrently and
+/// waited for until `deadline`; those still pending then are abandoned.
+/// Provider failures are logged and skipped so a single partner outage does
+/// not block the auction.
+pub fn resolve_eids(
+    settings: &Settings,
+    ctx: &IdentityContext,
+    deadline: Deadline,
+) -> Vec<serde_json::Value> {
+    let providers = identity_providers(settings);
+    let mut eids: Vec<Vec<serde_json::Value>> = vec![Vec::new(); providers.len()];
+    let mut pending = Vec::new();
+    for (index, provider) in providers.iter().enumerate() {
+        match provider.resolve(settings, ctx) {
+            Ok(Resolution::Eids(resolved)) => eids[index] = resolved,
+            Ok(Resolution::Pending(request)) => pending.push((provider.name(), *request, deadline)),
+            Err(e) => log::warn!("Identity provider {} failed: {:?}", provider.name(), e),
+        }
+    }
+
+    for (name, result) in wait_until(pending) {
+        let Some(index) = providers
+            .iter()
+            .position(|provider| provider.name() == name)
+        else {
+            continue;
+        };
+        let resolved = result
+            .change_context(provider_error(name, "Request failed"))
+            .and_then(|resp| providers[index].parse(resp));
+        match resolved {
+            Ok(resolved) => eids[index] = resolved,
+            Err(e) => log::warn!("Identity provider {} failed: {:?}", name, e),
+        }
+    }
+    merge_eids(eids.into_iter().flatten())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::test_support::tests::create_test_settings;
+
+    fn deadline() -> Deadline {
+        Deadline::new(Instant::now(), Duration::from_secs(1))
+    }
+
+    #[test]
+    fn test_default_providers() {
+        let settings = create_test_settings();
+        let names: Vec<_> = identity_providers(&settings)
+            .iter()
+            .map(|p| p.name())
+            .collect();
+        assert_eq!(names, vec!["first_party", "pub_userid", "hem", "uid2"]);
+    }
+
+    #[test]
+    fn test_partner_providers_enabled_by_settings() {
+        let mut settings = create_test_settings();
+        settings.identity.id5.partner_id = 1234;
+        settings.identity.liveramp.placement_id = "13332".to_string();
+
+        let names: Vec<_> = identity_providers(&settings)
+            .iter()
+            .map(|p| p.name())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "first_party",
+                "pub_userid",
+                "hem",
+                "uid2",
+                "id5",
+                "liveramp"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_eids_without_consent() {
+        let mut settings = create_test_settings();
+        settings.identity.id5.partner_id = 1234;
+        settings.identity.liveramp.placement_id = "13332".to_string();
+        let consent = TcfConsent::default();
+        let ctx = IdentityContext::new(&settings, "synthetic-1", "test.com", &consent);
+
+        let eids = resolve_eids(&settings, &ctx, deadline());
+        assert_eq!(
+            eids,
+            vec![json!({
+                "source": "test-publisher.com",
+                "uids": [{ "id": "synthetic-1", "atype": 1 }]
+            })]
+        );
+    }
+
+    #[test]
+    fn test_resolve_eids_merges_sources() {
+        let settings = create_test_settings();
+        let consent = TcfConsent::default();
+        let mut ctx = IdentityContext::new(&settings, "synthetic-1", "test.com", &consent);
+        ctx.pub_user_ids = vec!["user-42".to_string()];
+        ctx.hashed_emails = vec!["abc".to_string(), "abc".to_string()];
+
+        let eids = resolve_eids(&settings, &ctx, deadline());
+        assert_eq!(
+            eids,
+            vec![json!({
+                "source": "test-publisher.com",
+                "uids": [
+                    { "id": "synthetic-1", "atype": 1 },
+                    { "id": "user-42", "atype": 3, "ext": { "stype": "ppuid" } },
+                    { "id": "abc", "atype": 3, "ext": { "stype": "hem" } }
+                ]
+            })]
+        );
+    }
+
+    #[test]
+    fn test_hashed_email_provider() {
+        let settings = create_test_settings();
+        let consent = TcfConsent::default();
+        let mut ctx = IdentityContext::new(&settings, "synthetic-1", "test.com", &consent);
+        ctx.hashed_emails = vec!["abc".to_string()];
+
+        let Resolution::Eids(eids) = HashedEmailProvider.resolve(&settings, &ctx).unwrap() else {
+            panic!("hashed emails are resolved locally");
+        };
+        assert_eq!(eids, vec![hashed_email_eid(&settings, "abc")]);
+    }
+}
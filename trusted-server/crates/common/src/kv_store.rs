@@ -0,0 +1,326 @@
+//! JSON helpers on top of Fastly KV stores.
+//!
+//! This module wraps [`KVStore`] with typed get/put operations that serialize
+//! values as JSON and report failures as [`TrustedServerError::KvStore`].
+
+use std::time::Duration;
+
+use error_stack::{Report, ResultExt};
+use fastly::kv_store::KVStoreError;
+use fastly::KVStore;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::TrustedServerError;
+
+/// A value read from a [`JsonKvStore`] with the time it was written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stamped<T> {
+    /// The stored value.
+    pub value: T,
+    /// Unix timestamp (seconds) of the write, if it was recorded.
+    ///
+    /// Every write through [`JsonKvStore`] records it in the item metadata;
+    /// items written by other means have none.
+    pub written_at: Option<i64>,
+}
+
+/// A Fastly KV store holding JSON-encoded values.
+pub struct JsonKvStore {
+    name: String,
+    store: KVStore,
+}
+
+impl JsonKvStore {
+    /// Opens the KV store with the given name.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the store cannot be opened or does not exist
+    pub fn open(name: &str) -> Result<Self, Report<TrustedServerError>> {
+        let store = KVStore::open(name)
+            .change_context(TrustedServerError::KvStore {
+                store_name: name.to_string(),
+                message: "Failed to open KV store".to_string(),
+            })?
+            .ok_or_else(|| {
+                Report::new(TrustedServerError::KvStore {
+                    store_name: name.to_string(),
+                    message: "KV store not found".to_string(),
+                })
+            })?;
+
+        Ok(Self {
+            name: name.to_string(),
+            store,
+        })
+    }
+
+    /// Returns the name of the underlying KV store.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn error(&self, message: impl Into<String>) -> TrustedServerError {
+        TrustedServerError::KvStore {
+            store_name: self.name.clone(),
+            message: message.into(),
+        }
+    }
+
+    /// Looks up the raw value stored under `key` and its write time.
+    fn lookup(&self, key: &str) -> Result<Option<Stamped<Vec<u8>>>, Report<TrustedServerError>> {
+        match self.store.lookup(key) {
+            Ok(mut response) => Ok(Some(Stamped {
+                written_at: response
+                    .metadata()
+                    .and_then(|m| std::str::from_utf8(&m).ok()?.parse().ok()),
+                value: response.take_body_bytes(),
+            })),
+            Err(KVStoreError::ItemNotFound) => Ok(None),
+            Err(e) => {
+                Err(Report::new(e)
+                    .change_context(self.error(format!("Failed to look up key {key}"))))
+            }
+        }
+    }
+
+    /// Stores `body` under `key`, recording the write time as item metadata.
+    fn insert(
+        &self,
+        key: &str,
+        body: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<(), Report<TrustedServerError>> {
+        let mut insert = self
+            .store
+            .build_insert()
+            .metadata(&chrono::Utc::now().timestamp().to_string());
+        if let Some(ttl) = ttl {
+            insert = insert.time_to_live(ttl);
+        }
+        insert
+            .execute(key, body)
+            .change_context(self.error(format!("Failed to insert key {key}")))
+    }
+
+    /// Looks up and deserializes the value stored under `key`.
+    ///
+    /// Returns [`None`] if the key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the lookup fails or the value is not valid JSON
+    pub fn get<T: DeserializeOwned>(
+        &self,
+        key: &str,
+    ) -> Result<Option<T>, Report<TrustedServerError>> {
+        Ok(self.get_stamped(key)?.map(|entry| entry.value))
+    }
+
+    /// Looks up and deserializes the value stored under `key` together with
+    /// the time it was written.
+    ///
+    /// Returns [`None`] if the key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the lookup fails or the value is not valid JSON
+    pub fn get_stamped<T: DeserializeOwned>(
+        &self,
+        key: &str,
+    ) -> Result<Option<Stamped<T>>, Report<TrustedServerError>> {
+        let Some(entry) = self.lookup(key)? else {
+            return Ok(None);
+        };
+        let value = serde_json::from_slice(&entry.value)
+            .change_context(self.error(format!("Invalid JSON stored under key {key}")))?;
+        Ok(Some(Stamped {
+            value,
+            written_at: entry.written_at,
+        }))
+    }
+
+    /// Looks up the value stored under `key` as UTF-8 text, for stores written
+    /// without JSON encoding such as `synthetic.counter_store`.
+    ///
+    /// Returns [`None`] if the key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the lookup fails or the value is not valid UTF-8
+    pub fn get_text(&self, key: &str) -> Result<Option<String>, Report<TrustedServerError>> {
+        Ok(self.get_text_stamped(key)?.map(|entry| entry.value))
+    }
+
+    /// Looks up the value stored under `key` as UTF-8 text together with the
+    /// time it was written.
+    ///
+    /// Returns [`None`] if the key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the lookup fails or the value is not valid UTF-8
+    pub fn get_text_stamped(
+        &self,
+        key: &str,
+    ) -> Result<Option<Stamped<String>>, Report<TrustedServerError>> {
+        let Some(entry) = self.lookup(key)? else {
+            return Ok(None);
+        };
+        let value = String::from_utf8(entry.value)
+            .change_context(self.error(format!("Invalid UTF-8 stored under key {key}")))?;
+        Ok(Some(Stamped {
+            value,
+            written_at: entry.written_at,
+        }))
+    }
+
+    /// Serializes `value` as JSON and stores it under `key`.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if serialization or the insert fails
+    pub fn put<T: Serialize>(
+        &self,
+        key: &str,
+        value: &T,
+    ) -> Result<(), Report<TrustedServerError>> {
+        let body = serde_json::to_vec(value)
+            .change_context(self.error(format!("Failed to serialize value for key {key}")))?;
+        self.insert(key, body, None)
+    }
+
+    /// Serializes `value` as JSON and stores it under `key`, expiring after `ttl`.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if serialization or the insert fails
+    pub fn put_with_ttl<T: Serialize>(
+        &self,
+        key: &str,
+        value: &T,
+        ttl: Duration,
+    ) -> Result<(), Report<TrustedServerError>> {
+        let body = serde_json::to_vec(value)
+            .change_context(self.error(format!("Failed to serialize value for key {key}")))?;
+        self.insert(key, body, Some(ttl))
+    }
+
+    /// Stores `value` under `key` as plain UTF-8 text (see [`Self::get_text`]).
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the insert fails
+    pub fn put_text(&self, key: &str, value: &str) -> Result<(), Report<TrustedServerError>> {
+        self.insert(key, value.as_bytes().to_vec(), None)
+    }
+
+    /// Stores `value` under `key` as plain UTF-8 text, expiring after `ttl`.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the insert fails
+    pub fn put_text_with_ttl(
+        &self,
+        key: &str,
+        value: &str,
+        ttl: Duration,
+    ) -> Result<(), Report<TrustedServerError>> {
+        self.insert(key, value.as_bytes().to_vec(), Some(ttl))
+    }
+
+    /// Deletes the value stored under `key`. Missing keys are not an error.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the delete fails
+    pub fn delete(&self, key: &str) -> Result<(), Report<TrustedServerError>> {
+        match self.store.delete(key) {
+            Ok(()) | Err(KVStoreError::ItemNotFound) => Ok(()),
+            Err(e) => {
+                Err(Report::new(e)
+                    .change_context(self.error(format!("Failed to delete key {key}"))))
+            }
+        }
+    }
+
+    /// Lists up to `limit` keys, starting after `cursor` when given.
+    ///
+    /// Returns the keys and the cursor of the next page, if there is one.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the listing fails
+    pub fn list_keys(
+        &self,
+        cursor: Option<&str>,
+        limit: u32,
+    ) -> Result<(Vec<String>, Option<String>), Report<TrustedServerError>> {
+        let mut list = self.store.build_list().limit(limit);
+        if let Some(cursor) = cursor {
+            list = list.cursor(cursor);
+        }
+        let page = list
+            .execute()
+            .change_context(self.error("Failed to list keys"))?;
+        let next_cursor = page.next_cursor().filter(|cursor| !cursor.is_empty());
+        Ok((page.into_keys(), next_cursor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Entry {
+        value: u32,
+    }
+
+    #[test]
+    fn test_open_missing_store() {
+        let result = JsonKvStore::open("does-not-exist");
+        assert!(result.is_err(), "Opening an unknown store should fail");
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let store = JsonKvStore::open("test_kv_store").expect("should open test store");
+        assert_eq!(store.name(), "test_kv_store");
+
+        store
+            .put("entry", &Entry { value: 7 })
+            .expect("should insert value");
+        let entry: Option<Entry> = store.get("entry").expect("should look up value");
+        assert_eq!(entry, Some(Entry { value: 7 }));
+
+        let stamped = store
+            .get_stamped::<Entry>("entry")
+            .expect("should look up value")
+            .expect("should find value");
+        assert!(stamped.written_at.is_some_and(|t| t > 0));
+
+        store.delete("entry").expect("should delete value");
+        let entry: Option<Entry> = store.get("entry").expect("should look up value");
+        assert_eq!(entry, None);
+        store.delete("entry").expect("should ignore missing keys");
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        let store = JsonKvStore::open("test_kv_store").expect("should open test store");
+        store.put_text("text", "42").expect("should insert value");
+        let stamped = store
+            .get_text_stamped("text")
+            .expect("should look up value")
+            .expect("should find value");
+        assert_eq!(stamped.value, "42");
+        assert!(stamped.written_at.is_some());
+        assert_eq!(
+            store.get_text("missing").expect("should look up value"),
+            None
+        );
+    }
+}
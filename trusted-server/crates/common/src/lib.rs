@@ -5,33 +5,130 @@
 //!
 //! # Modules
 //!
+//! - [`additional_consent`]: Google Additional Consent (AC) string support
+//! - [`amp`]: AMP real-time config endpoint
+//! - [`anonymization`]: Anonymized visit aggregates for long-term analytics
+//! - [`auth`]: Operator authentication for admin and debug endpoints
+//! - [`bid_cache`]: Edge cache of non-personalized bids
+//! - [`bid_validation`]: Rejection of bids from blocked advertisers and creatives
+//! - [`bidder_health`]: Health of auction endpoints and bidder introspection
+//! - [`compliance_log`]: Signed log of accesses to subject data
+//! - [`consent`]: Unified consent decision for ad handlers
+//! - [`consent_history`]: Per-subject history of consent choices
+//! - [`consent_receipt`]: ISO/IEC TS 27560 style consent receipts
+//! - [`consent_webhook`]: Signed webhooks for consent changes
 //! - [`constants`]: Application-wide constants and configuration values
 //! - [`cookies`]: Cookie parsing and generation utilities
+//! - [`creative_frame`]: Sandboxed one-time frames serving ad server creatives
+//! - [`creative_rewrite`]: Rewriting of winning creatives to first-party hosts
+//! - [`data_provider`]: Concurrent audience segment lookups of the data providers
+//! - [`deadline`]: Time budget of an auction and deadline-bound waits
+//! - [`device`]: OpenRTB device object from client hints and geolocation
 //! - [`didomi`]: Didomi CMP reverse proxy functionality
+//! - [`dmp`]: Server-side Lotame, Neustar and publisher-defined audience segments
+//! - [`dsar`]: Data subject request tracking
+//! - [`erasure`]: Right to erasure with ID tombstones and partner notification
 //! - [`error`]: Error types and error handling utilities
+//! - [`first_party_data`]: Publisher first-party data for auctions and ad requests
+//! - [`gam_builder`]: Configuration-driven GAM ad request URLs
+//! - [`gam_handoff`]: Header bidding handoff to GAM through `hb_*` key-values
 //! - [`gdpr`]: GDPR consent management and TCF string parsing
+//! - [`gpc`]: Global Privacy Control (`Sec-GPC`) handling
+//! - [`gpp`]: IAB Global Privacy Platform string parsing
+//! - [`house_ad`]: House ads filling slots GAM leaves empty
+//! - [`identity`]: Server-side identity linking store
+//! - [`identity_provider`]: Identity partner adapters for OpenRTB eids
+//! - [`kv_store`]: JSON helpers on top of Fastly KV stores
+//! - [`macros`]: OpenRTB auction macro substitution and price encryption
 //! - [`models`]: Data models for ad serving and callbacks
+//! - [`native`]: OpenRTB Native 1.2 requests and safe native ad rendering
+//! - [`objection`]: Right to object to processing
+//! - [`permutive`]: Server-side Permutive audience segments
 //! - [`prebid`]: Prebid integration and real-time bidding support
+//! - [`prebid_cache`]: Cache of winning creatives for ad server handoff
+//! - [`processing`]: Records of processing activities generated from settings
+//! - [`portability`]: Signed data portability bundles
 //! - [`privacy`]: Privacy utilities and helpers
+//! - [`rectification`]: Right to rectification of identity links and opids
+//! - [`request_validation`]: Validation of outgoing OpenRTB bid requests
+//! - [`retention`]: Retention periods for per-user KV entries
 //! - [`settings`]: Configuration management and validation
+//! - [`slot_registry`]: Publisher ad slots shared by the Prebid and GAM requests
+//! - [`stored_request`]: Stored slot configurations for `/auction`
 //! - [`synthetic`]: Synthetic ID generation using HMAC
+//! - [`targeting`]: Ad server targeting keys for winning bids
+//! - [`tc_string`]: IAB TCF v2 consent string encoding
+//! - [`tcf_bitset`]: Bitsets for TCF purpose and vendor signals
 //! - [`templates`]: Handlebars template handling
+//! - [`uid2`]: UID2 token generation, refresh and caching
+//! - [`us_privacy`]: IAB US Privacy (CCPA) string support
 //! - [`test_support`]: Testing utilities and mocks
+//! - [`user_sync`]: Prebid Server compatible `/setuid` user syncing
 //! - [`why`]: Debugging and introspection utilities
+//! - [`win_notice`]: Win and billing notifications fired from the edge
 
+pub mod additional_consent;
+pub mod amp;
+pub mod anonymization;
+pub mod auth;
+pub mod bid_cache;
+pub mod bid_validation;
+pub mod bidder_health;
+pub mod compliance_log;
+pub mod consent;
+pub mod consent_history;
+pub mod consent_receipt;
+pub mod consent_webhook;
 pub mod constants;
 pub mod cookies;
+pub mod creative_frame;
+pub mod creative_rewrite;
+pub mod data_provider;
+pub mod deadline;
+pub mod device;
 pub mod didomi;
+pub mod dmp;
+pub mod dsar;
+pub mod erasure;
 pub mod error;
+pub mod first_party_data;
 pub mod gam;
+pub mod gam_builder;
+pub mod gam_handoff;
 pub mod gdpr;
+pub mod gpc;
+pub mod gpp;
+pub mod house_ad;
+pub mod identity;
+pub mod identity_provider;
+pub mod kv_store;
+pub mod macros;
 pub mod models;
+pub mod native;
+pub mod objection;
+pub mod permutive;
+pub mod portability;
 pub mod prebid;
+pub mod prebid_cache;
 pub mod privacy;
+pub mod processing;
+pub mod rectification;
+pub mod request_validation;
+pub mod retention;
 pub mod settings;
+pub mod signing;
+pub mod slot_registry;
+pub mod stored_request;
 pub mod synthetic;
+pub mod targeting;
+pub mod tc_string;
+pub mod tcf_bitset;
 pub mod tcf_consent;
 pub mod tcf_test;
 pub mod templates;
 pub mod test_support;
+pub mod uid2;
+pub mod us_privacy;
+pub mod user_sync;
 pub mod why;
+pub mod win_notice;
@@ -0,0 +1,306 @@
+//! OpenRTB auction macro substitution.
+//!
+//! Bidders put macros such as `${AUCTION_PRICE}` in the notification URLs
+//! (`nurl`, `burl`, `lurl`) and markup (`adm`) of their bids, to be replaced
+//! with the outcome of the auction. [`AuctionMacros`] holds those values for
+//! one bid and [`AuctionMacros::expand`] substitutes them.
+//!
+//! Bidders with keys in `prebid.price_encryption` receive `${AUCTION_PRICE}`
+//! encrypted (see [`encrypt_price`]), so the clearing price cannot be read
+//! by anyone the URL or markup passes through.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::Mac;
+use rand::RngCore;
+
+use crate::prebid::{Bid, WinningBid};
+use crate::settings::{PriceEncryptionKeys, Settings};
+use crate::signing::keyed_mac;
+use crate::win_notice::LossReason;
+
+/// Length of the initialization vector of an encrypted price.
+const IV_LENGTH: usize = 16;
+
+/// Length of the integrity signature of an encrypted price.
+const SIGNATURE_LENGTH: usize = 4;
+
+/// Encrypts `price` with `keys` in the DoubleClick price encryption scheme,
+/// using HMAC-SHA256.
+///
+/// The price in micros is XORed with the first 8 bytes of
+/// `HMAC(encryption_key, iv)`, and signed with the first 4 bytes of
+/// `HMAC(integrity_key, price || iv)`. The result is `iv || encrypted price
+/// || signature`, encoded as web-safe base64 without padding. The IV is the
+/// current time in microseconds followed by 8 random bytes.
+///
+/// Returns [`None`] if the keys are not valid web-safe base64.
+pub fn encrypt_price(price: f64, keys: &PriceEncryptionKeys) -> Option<String> {
+    let mut iv = [0u8; IV_LENGTH];
+    let micros = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or_default();
+    iv[..8].copy_from_slice(&micros.to_be_bytes());
+    rand::thread_rng().fill_bytes(&mut iv[8..]);
+    encrypt_price_with_iv(price, keys, iv)
+}
+
+fn encrypt_price_with_iv(
+    price: f64,
+    keys: &PriceEncryptionKeys,
+    iv: [u8; IV_LENGTH],
+) -> Option<String> {
+    let encryption_key = decode_key(&keys.encryption_key)?;
+    let integrity_key = decode_key(&keys.integrity_key)?;
+    let price = ((price * 1_000_000.0).round() as u64).to_be_bytes();
+
+    let pad = hmac(&encryption_key, &[&iv]);
+    let encrypted: Vec<u8> = price.iter().zip(&pad).map(|(p, k)| p ^ k).collect();
+    let signature = hmac(&integrity_key, &[&price, &iv]);
+
+    let mut token = iv.to_vec();
+    token.extend_from_slice(&encrypted);
+    token.extend_from_slice(&signature[..SIGNATURE_LENGTH]);
+    Some(URL_SAFE_NO_PAD.encode(token))
+}
+
+/// Decodes a web-safe base64 key, with or without padding.
+fn decode_key(key: &str) -> Option<Vec<u8>> {
+    URL_SAFE_NO_PAD
+        .decode(key.trim_end_matches('='))
+        .ok()
+        .filter(|key| !key.is_empty())
+}
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    keyed_mac(key, b"", parts).finalize().into_bytes().to_vec()
+}
+
+/// Values of the OpenRTB auction macros for one bid
+#[derive(Debug, Clone, Copy)]
+pub struct AuctionMacros<'a> {
+    /// `${AUCTION_ID}`: ID of the bid request
+    pub auction_id: &'a str,
+    /// `${AUCTION_BID_ID}`: ID of the bid
+    pub bid_id: &'a str,
+    /// `${AUCTION_IMP_ID}`: ID of the impression
+    pub imp_id: &'a str,
+    /// `${AUCTION_SEAT_ID}`: seat (bidder code) that made the bid
+    pub seat: &'a str,
+    /// `${AUCTION_AD_ID}`: creative ID of the bid
+    pub ad_id: &'a str,
+    /// `${AUCTION_PRICE}`: clearing price, the bid price in this first price
+    /// auction
+    pub price: f64,
+    /// `${AUCTION_CURRENCY}`: currency of the prices
+    pub currency: &'a str,
+    /// `${AUCTION_LOSS}`: why the bid lost, for loss notices
+    pub loss: Option<LossReason>,
+    /// `${AUCTION_MIN_TO_WIN}`: price of the winning bid, for loss notices
+    pub min_to_win: Option<f64>,
+    /// Keys `${AUCTION_PRICE}` is encrypted with for the seat
+    pub price_keys: Option<&'a PriceEncryptionKeys>,
+}
+
+impl<'a> AuctionMacros<'a> {
+    /// Macros of `bid`, made by `seat` in the auction `auction_id`.
+    pub fn for_bid(
+        settings: &'a Settings,
+        auction_id: &'a str,
+        seat: &'a str,
+        bid: &'a Bid,
+        currency: &'a str,
+    ) -> Self {
+        Self {
+            auction_id,
+            bid_id: &bid.id,
+            imp_id: &bid.impid,
+            seat,
+            ad_id: bid.crid.as_deref().unwrap_or_default(),
+            price: bid.price,
+            currency,
+            loss: None,
+            min_to_win: None,
+            price_keys: settings.prebid.price_encryption.get(seat),
+        }
+    }
+
+    /// Macros of the winning `bid` of the auction `auction_id`.
+    pub fn for_winning_bid(
+        settings: &'a Settings,
+        auction_id: &'a str,
+        bid: &'a WinningBid,
+    ) -> Self {
+        Self {
+            auction_id,
+            bid_id: &bid.bid_id,
+            imp_id: &bid.imp_id,
+            seat: &bid.bidder,
+            ad_id: bid.crid.as_deref().unwrap_or_default(),
+            price: bid.price,
+            currency: &bid.currency,
+            loss: None,
+            min_to_win: None,
+            price_keys: settings.prebid.price_encryption.get(&bid.bidder),
+        }
+    }
+
+    /// Adds the loss macros of a bid that lost for `reason` to a bid of
+    /// `min_to_win`, if the impression had a winner.
+    pub fn with_loss(self, reason: LossReason, min_to_win: Option<f64>) -> Self {
+        Self {
+            loss: Some(reason),
+            min_to_win,
+            ..self
+        }
+    }
+
+    fn price(&self) -> String {
+        match self.price_keys {
+            Some(keys) => encrypt_price(self.price, keys).unwrap_or_else(|| {
+                log::error!("Invalid price encryption keys for {}", self.seat);
+                String::new()
+            }),
+            None => self.price.to_string(),
+        }
+    }
+
+    /// Replaces the macros in `text`, a notification URL or ad markup.
+    ///
+    /// Values are URL-encoded, as macros stand in URLs even within markup.
+    /// The loss macros are left empty outside loss notices, and macros
+    /// Trusted Server does not know are kept.
+    pub fn expand(&self, text: &str) -> String {
+        if !text.contains("${") {
+            return text.to_string();
+        }
+        let optional = |value: Option<String>| value.unwrap_or_default();
+        [
+            ("${AUCTION_ID}", self.auction_id.to_string()),
+            ("${AUCTION_BID_ID}", self.bid_id.to_string()),
+            ("${AUCTION_IMP_ID}", self.imp_id.to_string()),
+            ("${AUCTION_SEAT_ID}", self.seat.to_string()),
+            ("${AUCTION_AD_ID}", self.ad_id.to_string()),
+            ("${AUCTION_PRICE}", self.price()),
+            ("${AUCTION_CURRENCY}", self.currency.to_string()),
+            (
+                "${AUCTION_LOSS}",
+                optional(self.loss.map(|reason| reason.code().to_string())),
+            ),
+            (
+                "${AUCTION_MIN_TO_WIN}",
+                optional(self.min_to_win.map(|price| price.to_string())),
+            ),
+        ]
+        .into_iter()
+        .filter(|(name, _)| text.contains(name))
+        .fold(text.to_string(), |text, (name, value)| {
+            text.replace(name, &urlencoding::encode(&value))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::tests::create_test_settings;
+
+    fn keys() -> PriceEncryptionKeys {
+        PriceEncryptionKeys {
+            encryption_key: URL_SAFE_NO_PAD.encode([7u8; 32]),
+            integrity_key: URL_SAFE_NO_PAD.encode([9u8; 32]),
+        }
+    }
+
+    /// Decrypts `token` as a bidder would, checking its signature.
+    fn decrypt_price(token: &str, keys: &PriceEncryptionKeys) -> Option<f64> {
+        let token = URL_SAFE_NO_PAD.decode(token).ok()?;
+        let (iv, rest) = token.split_at(IV_LENGTH);
+        let (encrypted, signature) = rest.split_at(8);
+        let pad = hmac(&decode_key(&keys.encryption_key)?, &[iv]);
+        let price: Vec<u8> = encrypted.iter().zip(&pad).map(|(e, k)| e ^ k).collect();
+        let expected = hmac(&decode_key(&keys.integrity_key)?, &[&price, iv]);
+        if expected[..SIGNATURE_LENGTH] != *signature {
+            return None;
+        }
+        let micros = u64::from_be_bytes(price.try_into().ok()?);
+        Some(micros as f64 / 1_000_000.0)
+    }
+
+    fn bid() -> Bid {
+        Bid {
+            id: "bid 1".to_string(),
+            impid: "top".to_string(),
+            price: 2.5,
+            crid: Some("creative-9".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_expand() {
+        let settings = create_test_settings();
+        let bid = bid();
+        let macros = AuctionMacros::for_bid(&settings, "auction-1", "smartadserver", &bid, "EUR");
+        let url = "https://dsp.example/win?p=${AUCTION_PRICE}&c=${AUCTION_CURRENCY}\
+                   &a=${AUCTION_ID}&b=${AUCTION_BID_ID}&i=${AUCTION_IMP_ID}\
+                   &s=${AUCTION_SEAT_ID}&ad=${AUCTION_AD_ID}&l=${AUCTION_LOSS}&x=${UNKNOWN}";
+        assert_eq!(
+            macros.expand(url),
+            "https://dsp.example/win?p=2.5&c=EUR&a=auction-1&b=bid%201&i=top\
+             &s=smartadserver&ad=creative-9&l=&x=${UNKNOWN}"
+        );
+
+        let loss = macros.with_loss(LossReason::LostToHigherBid, Some(3.0));
+        assert_eq!(
+            loss.expand("https://dsp.example/loss?r=${AUCTION_LOSS}&m=${AUCTION_MIN_TO_WIN}"),
+            "https://dsp.example/loss?r=102&m=3"
+        );
+        assert_eq!(macros.expand("<div>ad</div>"), "<div>ad</div>");
+    }
+
+    #[test]
+    fn test_encrypted_price() {
+        let mut settings = create_test_settings();
+        settings
+            .prebid
+            .price_encryption
+            .insert("smartadserver".to_string(), keys());
+        let bid = bid();
+        let macros = AuctionMacros::for_bid(&settings, "auction-1", "smartadserver", &bid, "USD");
+
+        let token = macros.expand("${AUCTION_PRICE}");
+        assert_eq!(token.len(), 38);
+        assert_eq!(decrypt_price(&token, &keys()), Some(2.5));
+        assert_eq!(
+            decrypt_price(
+                &token,
+                &PriceEncryptionKeys {
+                    integrity_key: URL_SAFE_NO_PAD.encode([1u8; 32]),
+                    ..keys()
+                }
+            ),
+            None
+        );
+
+        let other = AuctionMacros::for_bid(&settings, "auction-1", "other", &bid, "USD");
+        assert_eq!(other.expand("${AUCTION_PRICE}"), "2.5");
+    }
+
+    #[test]
+    fn test_encrypt_price_is_deterministic_per_iv() {
+        let iv = [3u8; IV_LENGTH];
+        let token = encrypt_price_with_iv(1.23, &keys(), iv).unwrap();
+        assert_eq!(encrypt_price_with_iv(1.23, &keys(), iv).unwrap(), token);
+        assert_ne!(encrypt_price_with_iv(1.24, &keys(), iv).unwrap(), token);
+
+        let invalid = PriceEncryptionKeys {
+            encryption_key: "not base64!".to_string(),
+            ..keys()
+        };
+        assert_eq!(encrypt_price(1.23, &invalid), None);
+    }
+}
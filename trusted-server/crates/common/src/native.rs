@@ -0,0 +1,437 @@
+//! OpenRTB Native 1.2 ads.
+//!
+//! A slot with [`NativeParams`] is auctioned as a native impression: the
+//! bidder returns the ad's parts (title, main image and data such as the
+//! sponsor name) instead of ready-made markup. [`native_request`] builds the
+//! Native 1.2 request embedded in `imp.native`, [`NativeResponse::parse`]
+//! reads the response carried in the bid's `adm`, and
+//! [`NativeResponse::render`] turns it into HTML for the `ad-container` of
+//! the publisher page. Every text is HTML-escaped and only `https` URLs are
+//! rendered, so bidder-supplied values cannot inject markup or scripts.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Native specification version requested and answered
+pub const NATIVE_VERSION: &str = "1.2";
+
+/// Asset ID of the title
+const ASSET_TITLE: u32 = 1;
+/// Asset ID of the main image
+const ASSET_IMAGE: u32 = 2;
+/// Data assets use their data type code offset by this value as asset ID, so
+/// the response can be rendered without the request.
+const ASSET_DATA_OFFSET: u32 = 100;
+
+/// Native 1.2 image type of a main image
+const IMAGE_TYPE_MAIN: u8 = 3;
+/// Native 1.2 event tracker type of an impression
+const EVENT_IMPRESSION: u8 = 1;
+/// Native 1.2 event tracking method of an image pixel
+const METHOD_IMAGE: u8 = 1;
+
+/// Data asset of a native ad, sent as a Native 1.2 `DataAssetType`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NativeData {
+    /// Name of the brand or advertiser
+    Sponsored,
+    /// Descriptive text of the product or service
+    Description,
+    /// Call to action text, such as "Shop now"
+    CallToAction,
+}
+
+impl NativeData {
+    /// Native 1.2 `DataAssetType` code
+    fn openrtb_code(self) -> u32 {
+        match self {
+            Self::Sponsored => 1,
+            Self::Description => 2,
+            Self::CallToAction => 12,
+        }
+    }
+
+    fn from_asset_id(id: u32) -> Option<Self> {
+        [Self::Sponsored, Self::Description, Self::CallToAction]
+            .into_iter()
+            .find(|data| ASSET_DATA_OFFSET + data.openrtb_code() == id)
+    }
+
+    /// CSS class of the rendered element
+    fn class(self) -> &'static str {
+        match self {
+            Self::Sponsored => "native-ad-sponsored",
+            Self::Description => "native-ad-description",
+            Self::CallToAction => "native-ad-cta",
+        }
+    }
+}
+
+/// Native ad settings of a slot
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NativeParams {
+    /// Maximum length of the title
+    #[serde(default = "default_title_length")]
+    pub title_length: u32,
+    /// Minimum size of the main image as `[width, height]`; the slot has no
+    /// image when unset
+    #[serde(default)]
+    pub image: Option<(u32, u32)>,
+    /// Data assets shown besides the title, all optional for the bidder
+    #[serde(default)]
+    pub data: Vec<NativeData>,
+}
+
+fn default_title_length() -> u32 {
+    90
+}
+
+/// Native 1.2 request for a slot with `params`, as sent in `imp.native`.
+///
+/// The request itself is a JSON string in `imp.native.request`.
+pub fn native_request(params: &NativeParams) -> Value {
+    let mut assets = vec![json!({
+        "id": ASSET_TITLE,
+        "required": 1,
+        "title": { "len": params.title_length }
+    })];
+    if let Some((w, h)) = params.image {
+        assets.push(json!({
+            "id": ASSET_IMAGE,
+            "required": 1,
+            "img": { "type": IMAGE_TYPE_MAIN, "wmin": w, "hmin": h }
+        }));
+    }
+    assets.extend(params.data.iter().map(|data| {
+        json!({
+            "id": ASSET_DATA_OFFSET + data.openrtb_code(),
+            "required": 0,
+            "data": { "type": data.openrtb_code() }
+        })
+    }));
+
+    let request = json!({
+        "ver": NATIVE_VERSION,
+        "assets": assets,
+        "eventtrackers": [{ "event": EVENT_IMPRESSION, "methods": [METHOD_IMAGE] }],
+        "privacy": 1
+    });
+    json!({
+        "request": request.to_string(),
+        "ver": NATIVE_VERSION
+    })
+}
+
+/// Native 1.2 response carried in the `adm` of a native bid
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct NativeResponse {
+    /// Assets the bidder filled in
+    #[serde(default)]
+    pub assets: Vec<ResponseAsset>,
+    /// Click destination of the ad
+    #[serde(default)]
+    pub link: Link,
+    /// Impression tracking pixels (Native 1.1)
+    #[serde(default)]
+    pub imptrackers: Vec<String>,
+    /// Event trackers (Native 1.2)
+    #[serde(default)]
+    pub eventtrackers: Vec<EventTracker>,
+    /// URL of the advertiser's privacy notice
+    #[serde(default)]
+    pub privacy: Option<String>,
+}
+
+/// One asset of a native response
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct ResponseAsset {
+    /// ID of the requested asset
+    #[serde(default)]
+    pub id: u32,
+    /// Title text
+    #[serde(default)]
+    pub title: Option<TitleAsset>,
+    /// Image
+    #[serde(default)]
+    pub img: Option<ImageAsset>,
+    /// Data text
+    #[serde(default)]
+    pub data: Option<DataAsset>,
+}
+
+/// Title asset of a native response
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct TitleAsset {
+    /// Title text
+    pub text: String,
+}
+
+/// Image asset of a native response
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct ImageAsset {
+    /// Image URL
+    pub url: String,
+    /// Width in pixels
+    #[serde(default)]
+    pub w: Option<u32>,
+    /// Height in pixels
+    #[serde(default)]
+    pub h: Option<u32>,
+}
+
+/// Data asset of a native response
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct DataAsset {
+    /// Data text
+    pub value: String,
+}
+
+/// Click destination of a native ad
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct Link {
+    /// Landing page URL
+    #[serde(default)]
+    pub url: String,
+}
+
+/// Event tracker of a native response
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct EventTracker {
+    /// Native 1.2 event type, 1 for impressions
+    pub event: u8,
+    /// Native 1.2 tracking method, 1 for an image pixel and 2 for JavaScript
+    pub method: u8,
+    /// Tracker URL
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+impl NativeResponse {
+    /// Parses the `adm` of a native bid, with or without the `native` wrapper
+    /// object of Native 1.0 responses.
+    ///
+    /// Returns [`None`] if `adm` is not a native response.
+    pub fn parse(adm: &str) -> Option<Self> {
+        let mut value: Value = serde_json::from_str(adm).ok()?;
+        if let Some(native) = value.get_mut("native") {
+            value = native.take();
+        }
+        serde_json::from_value(value).ok()
+    }
+
+    fn title(&self) -> Option<&str> {
+        self.assets
+            .iter()
+            .find(|asset| asset.id == ASSET_TITLE)
+            .and_then(|asset| asset.title.as_ref())
+            .map(|title| title.text.as_str())
+    }
+
+    fn image(&self) -> Option<&ImageAsset> {
+        self.assets
+            .iter()
+            .find(|asset| asset.id == ASSET_IMAGE)
+            .and_then(|asset| asset.img.as_ref())
+            .filter(|img| is_https(&img.url))
+    }
+
+    /// Impression pixel URLs of `imptrackers` and of image pixel event
+    /// trackers. JavaScript trackers are dropped.
+    fn impression_pixels(&self) -> impl Iterator<Item = &str> {
+        let event_pixels = self
+            .eventtrackers
+            .iter()
+            .filter(|tracker| tracker.event == EVENT_IMPRESSION && tracker.method == METHOD_IMAGE)
+            .filter_map(|tracker| tracker.url.as_deref());
+        self.imptrackers
+            .iter()
+            .map(String::as_str)
+            .chain(event_pixels)
+            .filter(|url| is_https(url))
+    }
+
+    /// Renders the ad as HTML for the `ad-container` of the publisher page.
+    ///
+    /// Returns [`None`] when the response has no title or no `https` landing
+    /// page. Assets with other URLs are left out.
+    pub fn render(&self) -> Option<String> {
+        let title = self.title().filter(|title| !title.is_empty())?;
+        let link = Some(self.link.url.as_str()).filter(|url| is_https(url))?;
+
+        let mut html = format!(
+            "<div class=\"native-ad\"><a class=\"native-ad-link\" href=\"{}\" \
+             target=\"_blank\" rel=\"noopener sponsored\">",
+            escape(link)
+        );
+        if let Some(img) = self.image() {
+            html.push_str(&format!(
+                "<img class=\"native-ad-image\" src=\"{}\" alt=\"\"",
+                escape(&img.url)
+            ));
+            if let (Some(w), Some(h)) = (img.w, img.h) {
+                html.push_str(&format!(" width=\"{w}\" height=\"{h}\""));
+            }
+            html.push('>');
+        }
+        html.push_str(&format!(
+            "<h3 class=\"native-ad-title\">{}</h3>",
+            escape(title)
+        ));
+        for asset in &self.assets {
+            let (Some(data), Some(value)) = (NativeData::from_asset_id(asset.id), &asset.data)
+            else {
+                continue;
+            };
+            if !value.value.is_empty() {
+                html.push_str(&format!(
+                    "<p class=\"{}\">{}</p>",
+                    data.class(),
+                    escape(&value.value)
+                ));
+            }
+        }
+        html.push_str("</a>");
+        if let Some(privacy) = self.privacy.as_deref().filter(|url| is_https(url)) {
+            html.push_str(&format!(
+                "<a class=\"native-ad-privacy\" href=\"{}\" target=\"_blank\" \
+                 rel=\"noopener\">AdChoices</a>",
+                escape(privacy)
+            ));
+        }
+        for pixel in self.impression_pixels() {
+            html.push_str(&format!(
+                "<img class=\"native-ad-pixel\" src=\"{}\" width=\"1\" height=\"1\" alt=\"\">",
+                escape(pixel)
+            ));
+        }
+        html.push_str("</div>");
+        Some(html)
+    }
+}
+
+/// Whether `url` is an absolute `https` URL.
+fn is_https(url: &str) -> bool {
+    url::Url::parse(url).is_ok_and(|url| url.scheme() == "https")
+}
+
+/// Escapes `text` for use in HTML text and double-quoted attributes.
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adm() -> Value {
+        json!({
+            "ver": "1.2",
+            "assets": [
+                { "id": 1, "title": { "text": "Drive <electric>" } },
+                { "id": 2, "img": { "url": "https://cdn.example/car.jpg", "w": 1200, "h": 627 } },
+                { "id": 101, "data": { "value": "Brand & Co" } },
+                { "id": 112, "data": { "value": "Book a test drive" } }
+            ],
+            "link": { "url": "https://brand.example/?a=1&b=\"2\"" },
+            "imptrackers": ["https://track.example/imp", "http://track.example/insecure"],
+            "eventtrackers": [
+                { "event": 1, "method": 1, "url": "https://track.example/event" },
+                { "event": 1, "method": 2, "url": "https://track.example/tracker.js" }
+            ]
+        })
+    }
+
+    #[test]
+    fn test_native_request() {
+        let params = NativeParams {
+            title_length: 50,
+            image: Some((600, 314)),
+            data: vec![NativeData::Sponsored, NativeData::CallToAction],
+        };
+        let native = native_request(&params);
+        assert_eq!(native["ver"], "1.2");
+        let request: Value = serde_json::from_str(native["request"].as_str().unwrap()).unwrap();
+        assert_eq!(
+            request["assets"],
+            json!([
+                { "id": 1, "required": 1, "title": { "len": 50 } },
+                { "id": 2, "required": 1, "img": { "type": 3, "wmin": 600, "hmin": 314 } },
+                { "id": 101, "required": 0, "data": { "type": 1 } },
+                { "id": 112, "required": 0, "data": { "type": 12 } }
+            ])
+        );
+        assert_eq!(
+            request["eventtrackers"][0],
+            json!({ "event": 1, "methods": [1] })
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let response = NativeResponse::parse(&adm().to_string()).unwrap();
+        assert_eq!(response.title(), Some("Drive <electric>"));
+
+        let wrapped = json!({ "native": adm() }).to_string();
+        assert_eq!(NativeResponse::parse(&wrapped), Some(response));
+        assert_eq!(NativeResponse::parse("<div>banner</div>"), None);
+    }
+
+    #[test]
+    fn test_render() {
+        let html = NativeResponse::parse(&adm().to_string())
+            .unwrap()
+            .render()
+            .unwrap();
+        assert_eq!(
+            html,
+            "<div class=\"native-ad\"><a class=\"native-ad-link\" \
+             href=\"https://brand.example/?a=1&amp;b=&quot;2&quot;\" target=\"_blank\" \
+             rel=\"noopener sponsored\"><img class=\"native-ad-image\" \
+             src=\"https://cdn.example/car.jpg\" alt=\"\" width=\"1200\" height=\"627\">\
+             <h3 class=\"native-ad-title\">Drive &lt;electric&gt;</h3>\
+             <p class=\"native-ad-sponsored\">Brand &amp; Co</p>\
+             <p class=\"native-ad-cta\">Book a test drive</p></a>\
+             <img class=\"native-ad-pixel\" src=\"https://track.example/imp\" width=\"1\" \
+             height=\"1\" alt=\"\"><img class=\"native-ad-pixel\" \
+             src=\"https://track.example/event\" width=\"1\" height=\"1\" alt=\"\"></div>"
+        );
+    }
+
+    #[test]
+    fn test_render_rejects_unsafe_urls() {
+        let mut adm = adm();
+        adm["assets"][1]["img"]["url"] = json!("javascript:alert(1)");
+        let html = NativeResponse::parse(&adm.to_string())
+            .unwrap()
+            .render()
+            .unwrap();
+        assert!(!html.contains("native-ad-image"));
+
+        adm["link"]["url"] = json!("javascript:alert(1)");
+        assert_eq!(
+            NativeResponse::parse(&adm.to_string()).unwrap().render(),
+            None
+        );
+
+        let no_title = json!({ "assets": [], "link": { "url": "https://brand.example" } });
+        assert_eq!(
+            NativeResponse::parse(&no_title.to_string())
+                .unwrap()
+                .render(),
+            None
+        );
+    }
+}
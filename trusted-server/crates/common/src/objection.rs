@@ -0,0 +1,289 @@
+//! Right to object to processing (GDPR Article 21).
+//!
+//! `POST /gdpr/object` records an [`Objection`] for the requester's synthetic
+//! ID in the `gdpr.objection_store` KV store, and `DELETE /gdpr/object`
+//! withdraws it. Handlers serving ads or counting visits apply it to their
+//! consent decision through [`crate::consent::ConsentDecision::with_objection`],
+//! which then serves only non-personalized ads and stops visit counting even
+//! if the consent string grants every purpose.
+
+use error_stack::Report;
+use fastly::http::{header, Method, StatusCode};
+use fastly::{Error, Request, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::compliance_log::{AccessEvent, Actor};
+use crate::error::TrustedServerError;
+use crate::kv_store::JsonKvStore;
+use crate::settings::Settings;
+use crate::synthetic::resolve_synthetic_id;
+
+/// Record of a user's objection to processing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Objection {
+    /// Unix timestamp (seconds) when the objection was made.
+    pub objected_at: i64,
+}
+
+/// KV store of objections keyed by synthetic ID.
+pub struct ObjectionStore {
+    store: JsonKvStore,
+}
+
+impl ObjectionStore {
+    /// Opens the store configured in `gdpr.objection_store`.
+    ///
+    /// Returns [`None`] when objections are disabled.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the configured store cannot be opened
+    pub fn open(settings: &Settings) -> Result<Option<Self>, Report<TrustedServerError>> {
+        if settings.gdpr.objection_store.is_empty() {
+            return Ok(None);
+        }
+        let store = JsonKvStore::open(&settings.gdpr.objection_store)?;
+        Ok(Some(Self { store }))
+    }
+
+    fn key(synthetic_id: &str) -> String {
+        format!("objection:{synthetic_id}")
+    }
+
+    /// Returns the objection recorded for `synthetic_id`, if any.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the lookup fails
+    pub fn get(&self, synthetic_id: &str) -> Result<Option<Objection>, Report<TrustedServerError>> {
+        self.store.get(&Self::key(synthetic_id))
+    }
+
+    /// Records an objection for `synthetic_id`.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the insert fails
+    pub fn add(&self, synthetic_id: &str) -> Result<Objection, Report<TrustedServerError>> {
+        let objection = Objection {
+            objected_at: chrono::Utc::now().timestamp(),
+        };
+        self.store.put(&Self::key(synthetic_id), &objection)?;
+        Ok(objection)
+    }
+
+    /// Withdraws the objection of `synthetic_id`.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the deletion fails
+    pub fn remove(&self, synthetic_id: &str) -> Result<(), Report<TrustedServerError>> {
+        self.store.delete(&Self::key(synthetic_id))
+    }
+}
+
+/// Returns `true` if the user behind `req` objected to processing.
+///
+/// Returns `false` without resolving the synthetic ID when objections are
+/// disabled. Lookup failures are logged and treated as no objection, so an
+/// unavailable store never blocks a request.
+pub fn has_objected(settings: &Settings, req: &Request) -> bool {
+    if settings.gdpr.objection_store.is_empty() {
+        return false;
+    }
+    let result = ObjectionStore::open(settings).and_then(|store| match store {
+        Some(store) => {
+            let synthetic_id = resolve_synthetic_id(settings, req)?;
+            store.get(&synthetic_id.value).map(|o| o.is_some())
+        }
+        None => Ok(false),
+    });
+    result.unwrap_or_else(|e| {
+        log::warn!("Failed to look up objection: {:?}", e);
+        false
+    })
+}
+
+/// Handles `/gdpr/object` for the requester's synthetic ID.
+///
+/// - POST: Records an objection to processing.
+/// - DELETE: Withdraws the objection.
+///
+/// Both act on the ID proven by a signed `synthetic_id` cookie only; claimed
+/// or freshly generated IDs are refused with `403 Forbidden`, so nobody can
+/// object or withdraw on behalf of another user.
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if the synthetic ID cannot be resolved, the
+/// objection store cannot be written or response creation fails.
+pub fn handle_objection_request(settings: &Settings, req: Request) -> Result<Response, Error> {
+    if !matches!(*req.get_method(), Method::POST | Method::DELETE) {
+        return Ok(
+            Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_body("Method not allowed")
+        );
+    }
+    let Some(store) = ObjectionStore::open(settings).map_err(|e| Error::msg(format!("{e:?}")))?
+    else {
+        return Ok(Response::from_status(StatusCode::NOT_FOUND).with_body("Not Found"));
+    };
+    let synthetic_id =
+        resolve_synthetic_id(settings, &req).map_err(|e| Error::msg(format!("{e:?}")))?;
+
+    let action = if *req.get_method() == Method::POST {
+        "object"
+    } else {
+        "withdraw_objection"
+    };
+    let event = AccessEvent::new(
+        &req,
+        action,
+        Actor::from(synthetic_id.source),
+        Some(&synthetic_id.value),
+    );
+
+    if !synthetic_id.is_cookie_backed() {
+        event.record(settings, StatusCode::FORBIDDEN);
+        return Ok(Response::from_status(StatusCode::FORBIDDEN).with_body("Forbidden"));
+    }
+
+    let body = if *req.get_method() == Method::POST {
+        let objection = store
+            .add(&synthetic_id.value)
+            .map_err(|e| Error::msg(format!("{e:?}")))?;
+        log::info!(
+            "Recorded objection for synthetic ID: {}",
+            synthetic_id.value
+        );
+        json!({ "objected": true, "objected_at": objection.objected_at })
+    } else {
+        store
+            .remove(&synthetic_id.value)
+            .map_err(|e| Error::msg(format!("{e:?}")))?;
+        log::info!(
+            "Withdrew objection for synthetic ID: {}",
+            synthetic_id.value
+        );
+        json!({ "objected": false })
+    };
+    event.record(settings, StatusCode::OK);
+
+    Ok(Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_header(header::CACHE_CONTROL, "no-store, private")
+        .with_body_json(&body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::HEADER_SYNTHETIC_TRUSTED_SERVER;
+    use crate::synthetic::{sign_synthetic_id, SyntheticId};
+    use crate::test_support::tests::create_test_settings;
+
+    fn objection_settings() -> Settings {
+        let mut settings = create_test_settings();
+        settings.gdpr.objection_store = "test_objection_store".to_string();
+        settings
+    }
+
+    #[test]
+    fn test_handle_objection_request_disabled() {
+        let settings = create_test_settings();
+        let req = Request::post("https://example.com/gdpr/object");
+        let resp = handle_objection_request(&settings, req).expect("should answer the request");
+        assert_eq!(resp.get_status(), StatusCode::NOT_FOUND);
+        assert!(!has_objected(
+            &settings,
+            &Request::get("https://example.com")
+        ));
+    }
+
+    #[test]
+    fn test_handle_objection_request() {
+        let settings = objection_settings();
+        let cookie = format!(
+            "synthetic_id={}",
+            sign_synthetic_id(
+                &settings,
+                &SyntheticId::new("objecting-synthetic".to_string())
+            )
+        );
+        let request = |method: Method| {
+            Request::new(method, "https://example.com/gdpr/object")
+                .with_header(header::COOKIE, cookie.as_str())
+        };
+        assert!(!has_objected(&settings, &request(Method::GET)));
+
+        let resp = handle_objection_request(&settings, request(Method::POST))
+            .expect("should record the objection");
+        assert_eq!(resp.get_status(), StatusCode::OK);
+        let body: serde_json::Value =
+            serde_json::from_str(&resp.into_body_str()).expect("should return JSON");
+        assert_eq!(body["objected"], true);
+        assert!(has_objected(&settings, &request(Method::GET)));
+
+        let resp = handle_objection_request(&settings, request(Method::DELETE))
+            .expect("should withdraw the objection");
+        assert_eq!(resp.get_status(), StatusCode::OK);
+        assert!(!has_objected(&settings, &request(Method::GET)));
+
+        let resp = handle_objection_request(&settings, request(Method::GET))
+            .expect("should answer the request");
+        assert_eq!(resp.get_status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn test_handle_objection_request_requires_cookie() {
+        let settings = objection_settings();
+        let store = ObjectionStore::open(&settings)
+            .expect("should open the objection store")
+            .expect("should have an objection store configured");
+        store
+            .add("claimed-synthetic")
+            .expect("should add the objection");
+
+        // Neither a claimed nor a freshly generated ID may object or withdraw
+        for method in [Method::POST, Method::DELETE] {
+            let claimed = Request::new(method.clone(), "https://example.com/gdpr/object")
+                .with_header(HEADER_SYNTHETIC_TRUSTED_SERVER, "claimed-synthetic");
+            let resp =
+                handle_objection_request(&settings, claimed).expect("should answer the request");
+            assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+
+            let fresh = Request::new(method, "https://example.com/gdpr/object");
+            let resp =
+                handle_objection_request(&settings, fresh).expect("should answer the request");
+            assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+        }
+        assert!(store
+            .get("claimed-synthetic")
+            .expect("should read the objection store")
+            .is_some());
+    }
+
+    #[test]
+    fn test_objection_store_remove() {
+        let settings = objection_settings();
+        let store = ObjectionStore::open(&settings)
+            .expect("should open the objection store")
+            .expect("should have an objection store configured");
+        store
+            .add("withdrawn-synthetic")
+            .expect("should add the objection");
+        assert!(store
+            .get("withdrawn-synthetic")
+            .expect("should read the objection store")
+            .is_some());
+        store
+            .remove("withdrawn-synthetic")
+            .expect("should remove the objection");
+        assert_eq!(
+            store
+                .get("withdrawn-synthetic")
+                .expect("should read the objection store"),
+            None
+        );
+    }
+}
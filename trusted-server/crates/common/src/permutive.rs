@@ -0,0 +1,234 @@
+//! Server-side Permutive audience segments.
+//!
+//! With `permutive.api_url` set, the segments Permutive holds for a visitor
+//! are fetched by synthetic ID, as `GET <api_url>?user_id=<synthetic ID>`
+//! answering `{"segments": [...]}`, and cached in `permutive.kv_store` for
+//! `permutive.cache_ttl_secs`. They are sent to GAM in `cust_params` and to
+//! bidders in `user.data` through [`PermutiveProvider`], only with consent to
+//! personalized ads and Permutive's TCF consent for the advertising purposes.
+//! For testing, `permutive.segments_override` replaces the looked up segments
+//! under the same consent.
+
+use std::time::Duration;
+
+use error_stack::{Report, ResultExt};
+use fastly::http::{Method, StatusCode};
+use fastly::Request;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::consent::ConsentDecision;
+use crate::data_provider::{response, DataProvider, SegmentsFuture};
+use crate::error::TrustedServerError;
+use crate::kv_store::JsonKvStore;
+use crate::settings::{Permutive, Settings};
+use crate::tcf_consent::purpose_ids;
+use crate::tcf_consent::vendor_list_manager::load_vendor_list;
+
+/// Fastly backend pointing at the Permutive API.
+const PERMUTIVE_BACKEND: &str = "permutive_api";
+
+/// `cust_params` key of the Permutive segments, as set by Permutive's tag.
+pub const PERMUTIVE_KEY: &str = "permutive";
+
+/// `user.data` name of the Permutive segments in bid requests.
+const PERMUTIVE_DATA_NAME: &str = "permutive.com";
+
+/// Segment API response.
+#[derive(Deserialize)]
+struct SegmentsResponse {
+    /// Segment IDs, as numbers or strings
+    segments: Vec<Value>,
+}
+
+fn permutive_error(message: impl Into<String>) -> TrustedServerError {
+    TrustedServerError::DataProvider {
+        provider: PERMUTIVE_KEY.to_string(),
+        message: message.into(),
+    }
+}
+
+fn cache_key(synthetic_id: &str) -> String {
+    format!("permutive:{synthetic_id}")
+}
+
+/// Fetches the segments of `synthetic_id` from the segment API.
+async fn fetch(
+    config: &Permutive,
+    synthetic_id: &str,
+) -> Result<Vec<String>, Report<TrustedServerError>> {
+    let url = format!(
+        "{}?user_id={}",
+        config.api_url,
+        urlencoding::encode(synthetic_id)
+    );
+    let pending = Request::new(Method::GET, url)
+        .with_header("X-API-Key", &config.api_key)
+        .send_async(PERMUTIVE_BACKEND)
+        .change_context(permutive_error("Failed to fetch segments"))?;
+    let mut resp = response(pending)
+        .await
+        .change_context(permutive_error("Failed to fetch segments"))?;
+    if resp.get_status() != StatusCode::OK {
+        return Err(Report::new(permutive_error(format!(
+            "Segment API returned {}",
+            resp.get_status()
+        ))));
+    }
+    let body: SegmentsResponse = serde_json::from_slice(&resp.take_body_bytes())
+        .change_context(permutive_error("Invalid segment API response"))?;
+    Ok(body
+        .segments
+        .iter()
+        .filter_map(|segment| match segment {
+            Value::String(id) => Some(id.clone()),
+            Value::Number(id) => Some(id.to_string()),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Segments of `synthetic_id`, from `permutive.segments_override`, the cache
+/// in `permutive.kv_store` or else the segment API. Failures are logged and
+/// give no segments.
+pub async fn segments(config: &Permutive, synthetic_id: &str) -> Vec<String> {
+    if !config.segments_override.is_empty() {
+        log::debug!("Using permutive.segments_override");
+        return config
+            .segments_override
+            .split(',')
+            .map(str::trim)
+            .filter(|segment| !segment.is_empty())
+            .map(str::to_string)
+            .collect();
+    }
+    let store = if config.kv_store.is_empty() {
+        None
+    } else {
+        JsonKvStore::open(&config.kv_store)
+            .inspect_err(|e| log::warn!("Failed to open the Permutive cache: {:?}", e))
+            .ok()
+    };
+    if let Some(store) = &store {
+        match store.get::<Vec<String>>(&cache_key(synthetic_id)) {
+            Ok(Some(segments)) => return segments,
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to read cached Permutive segments: {:?}", e),
+        }
+    }
+
+    let segments = match fetch(config, synthetic_id).await {
+        Ok(segments) => segments,
+        Err(e) => {
+            log::warn!("Failed to fetch Permutive segments: {:?}", e);
+            return Vec::new();
+        }
+    };
+    if let Some(store) = &store {
+        let ttl = Duration::from_secs(config.cache_ttl_secs.max(1));
+        if let Err(e) = store.put_with_ttl(&cache_key(synthetic_id), &segments, ttl) {
+            log::warn!("Failed to cache Permutive segments: {:?}", e);
+        }
+    }
+    segments
+}
+
+/// Permutive as a [`DataProvider`] of GAM requests.
+pub struct PermutiveProvider {
+    config: Permutive,
+}
+
+impl PermutiveProvider {
+    /// Provider for a request, when `permutive.api_url` or
+    /// `permutive.segments_override` is set, ads may be personalized and
+    /// Permutive has consent for the advertising purposes.
+    pub fn for_request(settings: &Settings, consent: &ConsentDecision) -> Option<Self> {
+        let config = &settings.permutive;
+        let allowed = (!config.api_url.is_empty() || !config.segments_override.is_empty())
+            && consent.is_personalized()
+            && consent.allows_vendor(
+                Some(config.vendor_id),
+                purpose_ids::ADVERTISING,
+                load_vendor_list(settings).as_ref(),
+            );
+        allowed.then(|| Self {
+            config: config.clone(),
+        })
+    }
+}
+
+impl DataProvider for PermutiveProvider {
+    fn key(&self) -> &str {
+        PERMUTIVE_KEY
+    }
+
+    fn data_name(&self) -> &str {
+        PERMUTIVE_DATA_NAME
+    }
+
+    fn segments<'a>(&'a self, synthetic_id: &'a str) -> SegmentsFuture<'a> {
+        Box::pin(segments(&self.config, synthetic_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consent::PersonalizationLevel;
+    use crate::test_support::tests::create_test_settings;
+
+    fn settings() -> Settings {
+        let mut settings = create_test_settings();
+        settings.permutive.api_url = "https://api.permutive.test/segments".to_string();
+        settings.permutive.kv_store = "test_permutive_store".to_string();
+        settings
+    }
+
+    /// Decision outside GDPR, where Permutive needs no TCF vendor consent.
+    fn consent(settings: &Settings, personalization: PersonalizationLevel) -> ConsentDecision {
+        let mut consent =
+            ConsentDecision::from_request(settings, &Request::get("https://test.com"));
+        consent.personalization = personalization;
+        consent.signals.tcf.gdpr_applies = false;
+        consent
+    }
+
+    #[test]
+    fn test_cached_segments() {
+        let settings = settings();
+        assert_eq!(
+            futures::executor::block_on(segments(&settings.permutive, "synthetic-1")),
+            vec!["129627", "137412"]
+        );
+    }
+
+    #[test]
+    fn test_for_request() {
+        let mut settings = settings();
+        let personalized = consent(&settings, PersonalizationLevel::Personalized);
+        let provider = PermutiveProvider::for_request(&settings, &personalized).unwrap();
+        assert_eq!(provider.key(), PERMUTIVE_KEY);
+        assert_eq!(provider.data_name(), "permutive.com");
+
+        let non_personalized = consent(&settings, PersonalizationLevel::NonPersonalized);
+        assert!(PermutiveProvider::for_request(&settings, &non_personalized).is_none());
+
+        settings.permutive.api_url.clear();
+        assert!(PermutiveProvider::for_request(&settings, &personalized).is_none());
+    }
+
+    #[test]
+    fn test_segments_override() {
+        let mut settings = create_test_settings();
+        settings.permutive.segments_override = "1001, 1002,".to_string();
+        let personalized = consent(&settings, PersonalizationLevel::Personalized);
+        let provider = PermutiveProvider::for_request(&settings, &personalized).unwrap();
+        assert_eq!(
+            futures::executor::block_on(provider.segments("synthetic-1")),
+            vec!["1001", "1002"]
+        );
+
+        let non_personalized = consent(&settings, PersonalizationLevel::NonPersonalized);
+        assert!(PermutiveProvider::for_request(&settings, &non_personalized).is_none());
+    }
+}
@@ -0,0 +1,193 @@
+//! Data portability bundles.
+//!
+//! `GET /gdpr/data/export` returns everything held for a data subject as one
+//! downloadable JSON bundle:
+//!
+//! ```json
+//! {"format":"trusted-server-data-bundle","version":1,"subject_id":"…",
+//!  "generated_at":"…","records":[…],
+//!  "digest":{"algorithm":"HMAC-SHA256","value":"…"}}
+//! ```
+//!
+//! Each [`DataRecord`] is serialized and appended to the response body as it
+//! is written, so the bundle is never assembled as a single string. The digest
+//! is an HMAC-SHA256, keyed with `synthetic.secret_key`, over every byte
+//! preceding `,"digest":`; [`verify_bundle`] checks it.
+
+use std::io::{self, Write};
+
+use fastly::Body;
+use hmac::Mac;
+use serde_json::json;
+
+use crate::gdpr::DataRecord;
+use crate::settings::Settings;
+use crate::signing::{keyed_mac, HmacSha256};
+
+/// Value of the bundle's `format` member.
+pub const BUNDLE_FORMAT: &str = "trusted-server-data-bundle";
+
+/// Version of the bundle layout.
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// Member that follows the signed part of a bundle.
+const DIGEST_MEMBER: &[u8] = b",\"digest\":";
+
+fn bundle_mac(settings: &Settings) -> HmacSha256 {
+    keyed_mac(
+        settings.synthetic.secret_key.as_bytes(),
+        b"data-bundle:",
+        &[],
+    )
+}
+
+/// Appends bytes to a response body while feeding them to the digest.
+struct SignedBody<'a> {
+    body: &'a mut Body,
+    mac: HmacSha256,
+}
+
+impl Write for SignedBody<'_> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.body.write_all(bytes)?;
+        self.mac.update(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.body.flush()
+    }
+}
+
+/// Writes the portability bundle of `synthetic_id` holding `records` to `body`.
+///
+/// # Errors
+///
+/// Returns a [`serde_json::Error`] if a record cannot be serialized or written.
+pub fn write_bundle<'a>(
+    settings: &Settings,
+    synthetic_id: &str,
+    records: impl IntoIterator<Item = &'a DataRecord>,
+    body: &mut Body,
+) -> Result<(), serde_json::Error> {
+    let header = json!({
+        "format": BUNDLE_FORMAT,
+        "version": BUNDLE_VERSION,
+        "subject_id": synthetic_id,
+        "generated_at": chrono::Utc::now().to_rfc3339(),
+    })
+    .to_string();
+
+    let mut signed = SignedBody {
+        body,
+        mac: bundle_mac(settings),
+    };
+    // Reopen the header object to append the records array
+    signed
+        .write_all(&header.as_bytes()[..header.len() - 1])
+        .and_then(|()| signed.write_all(b",\"records\":["))
+        .map_err(serde_json::Error::io)?;
+    for (index, record) in records.into_iter().enumerate() {
+        if index > 0 {
+            signed.write_all(b",").map_err(serde_json::Error::io)?;
+        }
+        serde_json::to_writer(&mut signed, record)?;
+    }
+    signed.write_all(b"]").map_err(serde_json::Error::io)?;
+
+    let SignedBody { body, mac } = signed;
+    let digest = json!({
+        "algorithm": "HMAC-SHA256",
+        "value": hex::encode(mac.finalize().into_bytes()),
+    });
+    body.write_all(DIGEST_MEMBER)
+        .map_err(serde_json::Error::io)?;
+    serde_json::to_writer(&mut *body, &digest)?;
+    body.write_all(b"}").map_err(serde_json::Error::io)?;
+    Ok(())
+}
+
+/// Checks the digest of a bundle written by [`write_bundle`].
+///
+/// Returns `false` if the bundle is malformed or was modified.
+pub fn verify_bundle(settings: &Settings, bundle: &[u8]) -> bool {
+    let Some(signed_len) = bundle
+        .windows(DIGEST_MEMBER.len())
+        .rposition(|window| window == DIGEST_MEMBER)
+    else {
+        return false;
+    };
+    let Ok(parsed) = serde_json::from_slice::<serde_json::Value>(bundle) else {
+        return false;
+    };
+    let Some(digest) = parsed["digest"]["value"]
+        .as_str()
+        .and_then(|value| hex::decode(value).ok())
+    else {
+        return false;
+    };
+    let mut mac = bundle_mac(settings);
+    mac.update(&bundle[..signed_len]);
+    mac.verify_slice(&digest).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::tests::create_test_settings;
+
+    fn record(category: &str, value: &str) -> DataRecord {
+        DataRecord {
+            category: category.to_string(),
+            value: value.to_string(),
+            store: "test_store".to_string(),
+            written_at: Some(1_700_000_000),
+        }
+    }
+
+    fn bundle(settings: &Settings, records: &[DataRecord]) -> Vec<u8> {
+        let mut body = Body::new();
+        write_bundle(settings, "bundle-subject", records, &mut body).unwrap();
+        body.into_bytes()
+    }
+
+    #[test]
+    fn test_write_bundle() {
+        let settings = create_test_settings();
+        let records = [
+            record("visit_count", "3"),
+            record("opid", r#"tricky,"digest":"value"#),
+        ];
+        let bytes = bundle(&settings, &records);
+
+        let parsed: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(parsed["format"], BUNDLE_FORMAT);
+        assert_eq!(parsed["version"], BUNDLE_VERSION);
+        assert_eq!(parsed["subject_id"], "bundle-subject");
+        assert_eq!(parsed["records"].as_array().unwrap().len(), 2);
+        assert_eq!(parsed["records"][1]["value"], r#"tricky,"digest":"value"#);
+        assert_eq!(parsed["digest"]["algorithm"], "HMAC-SHA256");
+        assert!(verify_bundle(&settings, &bytes));
+
+        let empty = bundle(&settings, &[]);
+        let parsed: serde_json::Value = serde_json::from_slice(&empty).unwrap();
+        assert!(parsed["records"].as_array().unwrap().is_empty());
+        assert!(verify_bundle(&settings, &empty));
+    }
+
+    #[test]
+    fn test_verify_bundle_detects_tampering() {
+        let settings = create_test_settings();
+        let bytes = bundle(&settings, &[record("visit_count", "3")]);
+
+        let tampered = String::from_utf8(bytes.clone())
+            .unwrap()
+            .replace("\"value\":\"3\"", "\"value\":\"4\"");
+        assert!(!verify_bundle(&settings, tampered.as_bytes()));
+        assert!(!verify_bundle(&settings, b"{}"));
+
+        let mut other_key = create_test_settings();
+        other_key.synthetic.secret_key = "other-secret-key".to_string();
+        assert!(!verify_bundle(&other_key, &bytes));
+    }
+}
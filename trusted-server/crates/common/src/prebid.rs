@@ -1,20 +1,299 @@
 //! Prebid integration for real-time bidding.
 //!
 //! This module provides functionality for integrating with Prebid Server
-//! to enable header bidding and real-time ad auctions.
+//! to enable header bidding and real-time ad auctions. Prebid Server answers
+//! with an OpenRTB [`BidResponse`], from which [`BidResponse::winning_bids`]
+//! selects the highest bid per impression.
 
-use error_stack::Report;
-use fastly::http::{header, Method};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use error_stack::{Report, ResultExt};
+use fastly::http::request::SendError;
+use fastly::http::{header, Method, StatusCode};
 use fastly::{Error, Request, Response};
-use serde_json::json;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
 
-use crate::constants::{
-    HEADER_SYNTHETIC_FRESH, HEADER_SYNTHETIC_TRUSTED_SERVER, HEADER_X_FORWARDED_FOR,
-};
+use crate::bid_cache::BidCache;
+use crate::bid_validation::BlockList;
+use crate::bidder_health::{BidderHealth, Outcome};
+use crate::consent::ConsentDecision;
+use crate::constants::{HEADER_SYNTHETIC_TRUSTED_SERVER, HEADER_X_FORWARDED_FOR};
+use crate::creative_rewrite::rewrite_creative;
+use crate::data_provider::{AudienceSegments, DataProviderManager};
+use crate::deadline::{wait_until, Deadline};
+use crate::device::device;
 use crate::error::TrustedServerError;
-use crate::settings::Settings;
-use crate::synthetic::generate_synthetic_id;
-use crate::tcf_consent::get_tcf_consent_from_request;
+use crate::first_party_data::FirstPartyData;
+use crate::gpp::section_ids;
+use crate::identity_provider::{resolve_eids, IdentityContext};
+use crate::macros::AuctionMacros;
+use crate::native::{native_request, NativeParams, NativeResponse};
+use crate::prebid_cache::CreativeCache;
+use crate::request_validation::validate_bid_request;
+use crate::settings::{AuctionEndpoint, Profile, Settings, UserIdMode};
+use crate::slot_registry::{accepted_sizes, SlotRegistry};
+use crate::stored_request::{SlotEntry, StoredRequests};
+use crate::synthetic::{generate_synthetic_id, get_or_generate_synthetic_id, partner_scoped_id};
+use crate::targeting::apply_targeting;
+use crate::tcf_consent::vendor_list_manager::load_vendor_list;
+use crate::tcf_consent::{purpose_ids, VendorList};
+use crate::win_notice::{LossReason, WinNotifier};
+
+/// Maximum number of slots accepted by one `POST /auction`
+const MAX_AUCTION_SLOTS: usize = 20;
+
+/// Floor price (CPM in USD) of every impression
+const BID_FLOOR: f64 = 0.01;
+
+/// Part of the auction budget kept for picking the winners, caching and
+/// notifications once the bids are in
+const AUCTION_PROCESSING_MARGIN: Duration = Duration::from_millis(50);
+
+/// Shortest `tmax` a bid request is still sent with
+const MIN_BIDDER_TIMEOUT: Duration = Duration::from_millis(20);
+
+/// Smallest bid request body compressed for endpoints accepting gzip
+const MIN_GZIP_BODY_LEN: usize = 1024;
+
+/// Position of an ad slot on the page, sent as OpenRTB `banner.pos`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlotPosition {
+    /// Position not known
+    #[default]
+    Unknown,
+    /// Visible without scrolling
+    AboveTheFold,
+    /// Visible only after scrolling
+    BelowTheFold,
+    /// In the page header
+    Header,
+    /// In the page footer
+    Footer,
+    /// In a sidebar
+    Sidebar,
+    /// Covering the full screen
+    FullScreen,
+}
+
+impl SlotPosition {
+    /// OpenRTB 2.x `AdPosition` code
+    fn openrtb_code(self) -> u8 {
+        match self {
+            Self::Unknown => 0,
+            Self::AboveTheFold => 1,
+            Self::BelowTheFold => 3,
+            Self::Header => 4,
+            Self::Footer => 5,
+            Self::Sidebar => 6,
+            Self::FullScreen => 7,
+        }
+    }
+}
+
+/// Ad format of a slot
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlotFormat {
+    /// Ad placed in the page
+    #[default]
+    Standard,
+    /// Full screen ad shown between pages or content, sent as `instl`
+    Interstitial,
+    /// Full screen ad the user is rewarded for watching, sent as `rwdd`
+    /// with an unskippable video
+    Rewarded,
+}
+
+impl SlotFormat {
+    /// Whether the ad covers the full screen
+    fn is_full_screen(self) -> bool {
+        self != Self::Standard
+    }
+}
+
+/// Ad slot definition posted by the publisher page to `/auction`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdSlot {
+    /// Slot code, used as the impression ID and tag ID
+    pub code: String,
+    /// Accepted creative sizes as `[width, height]` pairs
+    pub sizes: Vec<(u32, u32)>,
+    /// Position of the slot on the page
+    #[serde(default)]
+    pub position: SlotPosition,
+    /// Video player of the slot; the slot is auctioned as a video impression
+    /// sized by its first size instead of a banner when set
+    #[serde(default)]
+    pub video: Option<VideoParams>,
+    /// Native ad settings of the slot; the slot is auctioned as a native
+    /// impression instead of a banner when set
+    #[serde(default)]
+    pub native: Option<NativeParams>,
+    /// Ad format of the slot; full screen formats are positioned full screen
+    /// unless `position` says otherwise
+    #[serde(default)]
+    pub format: SlotFormat,
+}
+
+/// Placement of a video player, sent as OpenRTB 2.6 `video.plcmt`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoPlacement {
+    /// Played before, during or after video content the user asked for
+    #[default]
+    Instream,
+    /// Played alongside video content, such as in a floating player
+    Accompanying,
+    /// Played in an interstitial between content
+    Interstitial,
+    /// Played standalone without video content (outstream)
+    NoContent,
+}
+
+impl VideoPlacement {
+    /// OpenRTB 2.6 `Plcmt` code
+    fn openrtb_code(self) -> u8 {
+        match self {
+            Self::Instream => 1,
+            Self::Accompanying => 2,
+            Self::Interstitial => 3,
+            Self::NoContent => 4,
+        }
+    }
+}
+
+/// Video player settings of a slot
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VideoParams {
+    /// Supported creative MIME types, such as `video/mp4`
+    pub mimes: Vec<String>,
+    /// Supported VAST versions as OpenRTB `Protocols` codes; VAST 2.0 to 4.0
+    /// and their wrappers by default
+    #[serde(default = "default_video_protocols")]
+    pub protocols: Vec<u8>,
+    /// OpenRTB `PlaybackMethod` codes, such as 2 for autoplay with sound off
+    #[serde(default)]
+    pub playback_methods: Vec<u8>,
+    /// Minimum ad duration in seconds
+    #[serde(default)]
+    pub min_duration: Option<u32>,
+    /// Maximum ad duration in seconds
+    #[serde(default)]
+    pub max_duration: Option<u32>,
+    /// Placement of the player
+    #[serde(default)]
+    pub placement: VideoPlacement,
+}
+
+fn default_video_protocols() -> Vec<u8> {
+    vec![2, 3, 5, 6, 7, 8]
+}
+
+/// Validated body of a `POST /auction` request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuctionRequest {
+    /// Slots auctioned as one impression each
+    pub slots: Vec<AdSlot>,
+    /// Page context and audience segments of the publisher
+    pub first_party_data: FirstPartyData,
+}
+
+/// `POST /auction` body with slots and first-party data, before stored slots
+/// are expanded
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AuctionBodyRequest {
+    slots: Vec<SlotEntry>,
+    #[serde(default)]
+    first_party_data: FirstPartyData,
+}
+
+/// `POST /auction` body: a bare list of slots, or slots with first-party data
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AuctionBody {
+    Slots(Vec<SlotEntry>),
+    Request(AuctionBodyRequest),
+}
+
+/// Parses and validates a `POST /auction` body: either a JSON array of slots
+/// or an object with `slots` and optional `first_party_data`.
+///
+/// Slots sent as `{"storedrequest": "<id>"}` are replaced with their
+/// configuration in `stored` (see [`StoredRequests::expand`]). Slots need a
+/// non-empty, unique code and at least one non-zero size; at most 20 slots
+/// are accepted. First-party data is checked with
+/// [`FirstPartyData::validate`].
+///
+/// # Errors
+///
+/// - [`TrustedServerError::Prebid`] if the body is not a valid auction request
+///   or references an unknown stored request
+/// - [`TrustedServerError::KvStore`] if a stored request cannot be looked up
+pub fn parse_auction_request(
+    body: &[u8],
+    stored: Option<&StoredRequests>,
+) -> Result<AuctionRequest, Report<TrustedServerError>> {
+    let invalid = |message: &str| TrustedServerError::Prebid {
+        message: message.to_string(),
+    };
+    let body: AuctionBody =
+        serde_json::from_slice(body).change_context(invalid("Invalid auction request"))?;
+    let (entries, first_party_data) = match body {
+        AuctionBody::Slots(entries) => (entries, FirstPartyData::default()),
+        AuctionBody::Request(request) => (request.slots, request.first_party_data),
+    };
+    // Checked before expanding, so one request causes at most 20 KV lookups
+    if entries.is_empty() || entries.len() > MAX_AUCTION_SLOTS {
+        return Err(Report::new(invalid("Expected between 1 and 20 slots")));
+    }
+    let request = AuctionRequest {
+        slots: StoredRequests::expand(stored, entries)?,
+        first_party_data,
+    };
+
+    let slots = &request.slots;
+    let mut codes = HashSet::new();
+    for slot in slots {
+        if slot.code.is_empty() || !codes.insert(slot.code.as_str()) {
+            return Err(Report::new(invalid(
+                "Slot codes must be non-empty and unique",
+            )));
+        }
+        if slot.sizes.is_empty() || slot.sizes.iter().any(|&(w, h)| w == 0 || h == 0) {
+            return Err(Report::new(invalid(
+                "Slots need at least one non-zero size",
+            )));
+        }
+        if let Some(video) = &slot.video {
+            let durations_valid = match (video.min_duration, video.max_duration) {
+                (Some(min), Some(max)) => min <= max,
+                _ => true,
+            };
+            if video.mimes.is_empty() || video.protocols.is_empty() || !durations_valid {
+                return Err(Report::new(invalid(
+                    "Video slots need MIME types, protocols and a valid duration range",
+                )));
+            }
+        }
+        if slot.video.is_some() && slot.native.is_some() {
+            return Err(Report::new(invalid(
+                "Slots cannot be both video and native",
+            )));
+        }
+    }
+    request.first_party_data.validate()?;
+    Ok(request)
+}
 
 /// Represents a request to the Prebid Server with all necessary parameters
 pub struct PrebidRequest {
@@ -22,8 +301,13 @@ pub struct PrebidRequest {
     pub synthetic_id: String,
     /// Domain for the ad request
     pub domain: String,
-    /// List of banner sizes as (width, height) tuples
+    /// List of banner sizes as (width, height) tuples, used for the single
+    /// default impression when no `slots` are given
     pub banner_sizes: Vec<(u32, u32)>,
+    /// Ad slots auctioned as one impression each
+    pub slots: Vec<AdSlot>,
+    /// Publisher first-party data added to the bid request
+    pub first_party_data: FirstPartyData,
     /// Client's IP address for geo-targeting and fraud prevention
     pub client_ip: String,
     /// Origin header for CORS and tracking
@@ -91,114 +375,259 @@ impl PrebidRequest {
             synthetic_id,
             domain,
             banner_sizes: vec![(728, 90)], // TODO: Make this configurable
+            slots: Vec::new(),
+            first_party_data: FirstPartyData::default(),
             client_ip,
             origin,
         })
     }
 
-    /// Sends bid request to Prebid Server with GDPR compliance
+    /// Builds one OpenRTB impression per slot, or a single `imp1` impression
+    /// with `banner_sizes` when no slots are given.
     ///
-    /// Makes an HTTP POST request to PBS with all necessary headers and body.
-    /// Includes GDPR fields in OpenRTB request based on TCF consent data.
-    /// Uses the stored synthetic ID for user identification.
-    ///
-    /// # Returns
-    /// * `Result<Response, Error>` - Prebid Server response or error
-    pub async fn send_bid_request(
-        &self,
-        settings: &Settings,
-        incoming_req: &Request,
-    ) -> Result<Response, Error> {
-        let mut req = Request::new(Method::POST, settings.prebid.server_url.to_owned());
+    /// Slots in the `registry` are only offered in their registered sizes,
+    /// tagged with their GAM ad unit path and sent with their registered
+    /// bidder parameters.
+    fn imps(&self, registry: &SlotRegistry) -> Vec<Value> {
+        let default_slot = AdSlot {
+            code: "imp1".to_string(),
+            sizes: self.banner_sizes.clone(),
+            position: SlotPosition::Unknown,
+            video: None,
+            native: None,
+            format: SlotFormat::Standard,
+        };
+        let slots = if self.slots.is_empty() {
+            std::slice::from_ref(&default_slot)
+        } else {
+            &self.slots[..]
+        };
 
-        // Get and store the POTSI ID value from the incoming request
-        let id: String = incoming_req
+        slots
+            .iter()
+            .map(|slot| {
+                let registered = registry.get(&slot.code);
+                let sizes = match &registered {
+                    Some(registered) => accepted_sizes(registered, &slot.sizes),
+                    None => slot.sizes.clone(),
+                };
+                let (media_type, mut media) = match (&slot.video, &slot.native) {
+                    (Some(video), _) => ("video", video_object(video, sizes[0])),
+                    (None, Some(native)) => ("native", native_request(native)),
+                    (None, None) => (
+                        "banner",
+                        json!({
+                            "format": sizes.iter().map(|(w, h)| {
+                                json!({ "w": w, "h": h })
+                            }).collect::<Vec<_>>()
+                        }),
+                    ),
+                };
+                let position = match slot.position {
+                    SlotPosition::Unknown if slot.format.is_full_screen() => {
+                        SlotPosition::FullScreen
+                    }
+                    position => position,
+                };
+                if position != SlotPosition::Unknown && slot.native.is_none() {
+                    media["pos"] = json!(position.openrtb_code());
+                }
+                if media_type == "video" && slot.format.is_full_screen() {
+                    media["plcmt"] = json!(VideoPlacement::Interstitial.openrtb_code());
+                }
+                if media_type == "video" && slot.format == SlotFormat::Rewarded {
+                    media["skip"] = json!(0);
+                }
+                let tagid = registered
+                    .as_ref()
+                    .map(|registered| registered.gam_ad_unit.as_str())
+                    .filter(|path| !path.is_empty())
+                    .unwrap_or(&slot.code);
+                let bidder = registered
+                    .as_ref()
+                    .filter(|registered| !registered.bidders.is_empty())
+                    .map(|registered| json!(registered.bidders))
+                    .unwrap_or_else(|| self.default_bidder_params());
+                let mut imp = json!({
+                    "id": slot.code,
+                    "tagid": tagid,
+                    media_type: media,
+                    "bidfloor": BID_FLOOR,
+                    "bidfloorcur": "USD",
+                    "ext": { "prebid": { "bidder": bidder } }
+                });
+                if slot.format.is_full_screen() {
+                    imp["instl"] = json!(1);
+                }
+                if slot.format == SlotFormat::Rewarded {
+                    imp["rwdd"] = json!(1);
+                    imp["ext"]["prebid"]["is_rewarded_inventory"] = json!(1);
+                }
+                imp
+            })
+            .collect()
+    }
+
+    /// Bidder parameters of slots without registered ones.
+    fn default_bidder_params(&self) -> Value {
+        json!({
+            "smartadserver": {
+                "siteId": 686105,
+                "networkId": 5280,
+                "pageId": 2040327,
+                "formatId": 137675,
+                "target": "testing=prebid",
+                "domain": &self.domain
+            }
+        })
+    }
+
+    /// Returns the ID the bid request is made for: the Trusted Server ID of the
+    /// incoming request, or else the stored synthetic ID.
+    fn request_id(&self, incoming_req: &Request) -> String {
+        incoming_req
             .get_header(HEADER_SYNTHETIC_TRUSTED_SERVER)
             .and_then(|h| h.to_str().ok())
             .map(|s| s.to_string())
-            .unwrap_or_else(|| self.synthetic_id.clone());
-
-        log::info!("Found Trusted Server ID from incoming request: {}", id);
+            .unwrap_or_else(|| self.synthetic_id.clone())
+    }
 
-        // Extract TCF consent from request (euconsent-v2 cookie)
-        let tcf_consent = get_tcf_consent_from_request(incoming_req).unwrap_or_default();
+    /// Builds the OpenRTB bid request body for `id`.
+    ///
+    /// Includes the privacy fields of the OpenRTB request from the consent
+    /// decision's signals. Identity provider lookups end early enough before
+    /// `deadline` to leave the bidders their minimum timeout. Returns
+    /// [`None`] when no bidder has TCF consent.
+    fn bid_request_body(
+        &self,
+        settings: &Settings,
+        consent: &ConsentDecision,
+        incoming_req: &Request,
+        id: &str,
+        deadline: Deadline,
+    ) -> Option<Value> {
+        let tcf_consent = &consent.signals.tcf;
         log::info!("TCF consent - GDPR applies: {}, TC string: {}", 
                    tcf_consent.gdpr_applies, 
                    if tcf_consent.tc_string.is_empty() { "none" } else { "present" });
 
-        // Construct the OpenRTB2 bid request with GDPR fields
-        let prebid_body = json!({
-            "id": id,
-            "imp": [{
-                "id": "imp1",
-                "banner": {
-                    "format": self.banner_sizes.iter().map(|(w, h)| {
-                        json!({ "w": w, "h": h })
-                    }).collect::<Vec<_>>()
-                },
-                "bidfloor": 0.01,
-                "bidfloorcur": "USD",
-                "ext": {
-                    "prebid": {
-                        "bidder": {
-                            "smartadserver": {
-                                "siteId": 686105,
-                                "networkId": 5280,
-                                "pageId": 2040327,
-                                "formatId": 137675,
-                                "target": "testing=prebid",
-                                "domain": &self.domain
-                            }
-                        }
-                    }
-                }
-            }],
+        // Without personalized advertising the request carries no EIDs
+        let eids = if !consent.is_personalized() {
+            log::info!("Personalized advertising not allowed, omitting EIDs");
+            Vec::new()
+        } else {
+            let identity = IdentityContext::new(settings, id, &self.domain, tcf_consent);
+            let identity_deadline = deadline.minus(AUCTION_PROCESSING_MARGIN + MIN_BIDDER_TIMEOUT);
+            resolve_eids(settings, &identity, identity_deadline)
+        };
+
+        // Construct the OpenRTB2 bid request with GDPR fields, under an auction
+        // ID of its own rather than the synthetic ID
+        let auction_id = Uuid::new_v4().to_string();
+        let mut prebid_body = json!({
+            "id": auction_id,
+            "imp": self.imps(&SlotRegistry::open(settings)),
             "site": { "page": format!("https://{}", self.domain) },
+            "device": device(incoming_req, self.client_ip.parse().ok(), consent),
             "user": {
-                "id": "5280",
                 "ext": {
                     "consent": tcf_consent.tc_string,
-                    "eids": [
-                        {
-                            "source": &self.domain,
-                            "uids": [{
-                                "id": self.synthetic_id,
-                                "atype": 1,
-                                "ext": {
-                                    "type": "fresh"
-                                }
-                            }],
-                        },
-                        {
-                            "source": &self.domain,
-                            "uids": [{
-                                "id": &id,
-                                "atype": 1,
-                                "ext": {
-                                    "type": "potsi" // TODO: remove reference to potsi
-                                }
-                            }]
-                        }
-                    ]
+                    "eids": eids
                 }
             },
-            "test": 1,
-            "debug": 1,
-            "tmax": 1000,
+            "tmax": settings.prebid.auction_timeout_ms,
             "at": 1,
-            // GDPR compliance fields per OpenRTB 2.5
-            "regs": {
-                "ext": {
-                    "gdpr": if tcf_consent.gdpr_applies { 1 } else { 0 }
-                }
-            }
+            "regs": regs(consent)
         });
+        apply_request_flags(settings, &mut prebid_body);
+
+        self.first_party_data
+            .apply_to_bid_request(&mut prebid_body, consent.is_personalized());
+
+        if let Some(schain) = supply_chain(settings, &auction_id) {
+            prebid_body["source"] = json!({ "ext": { "schain": schain } });
+        }
+
+        // Each bidder needs its own TCF consent to take part in the auction
+        let vendor_list = load_vendor_list(settings);
+        let remaining =
+            retain_consented_bidders(settings, consent, vendor_list.as_ref(), &mut prebid_body);
+        if remaining == 0 {
+            log::info!("No bidder has TCF consent, skipping the bid request");
+            return None;
+        }
+        Some(prebid_body)
+    }
 
+    /// Builds the POST of `body` to `url`, with the client and ID headers.
+    ///
+    /// The only ID sent in headers is the `user.id` of the bid request, which
+    /// is scoped to the endpoint or left out as configured in
+    /// `prebid.user_id`; the synthetic ID itself never leaves the edge.
+    fn outgoing_request(&self, url: &str, body: &Value, gzip: bool) -> Result<Request, Error> {
+        let mut req = Request::new(Method::POST, url);
         req.set_header(header::CONTENT_TYPE, "application/json");
-        req.set_header(HEADER_X_FORWARDED_FOR, &self.client_ip);
+        // Forward the IP as sent in the device object, truncated without consent
+        let forwarded_ip = body["device"]["ip"]
+            .as_str()
+            .or(body["device"]["ipv6"].as_str())
+            .unwrap_or_default();
+        req.set_header(HEADER_X_FORWARDED_FOR, forwarded_ip);
         req.set_header(header::ORIGIN, &self.origin);
-        req.set_header(HEADER_SYNTHETIC_FRESH, &self.synthetic_id);
-        req.set_header(HEADER_SYNTHETIC_TRUSTED_SERVER, &id);
+        if let Some(user_id) = body["user"]["id"].as_str() {
+            req.set_header(HEADER_SYNTHETIC_TRUSTED_SERVER, user_id);
+        }
+        let json = serde_json::to_vec(body)?;
+        let compressed = if gzip && json.len() >= MIN_GZIP_BODY_LEN {
+            gzip_body(&json)
+        } else {
+            None
+        };
+        match compressed {
+            Some(compressed) => {
+                req.set_header(header::CONTENT_ENCODING, "gzip");
+                req.set_body(compressed);
+            }
+            None => req.set_body(json),
+        }
+        Ok(req)
+    }
+
+    /// Sends bid request to Prebid Server with GDPR compliance
+    ///
+    /// Makes an HTTP POST request to PBS with all necessary headers and body.
+    /// Includes the privacy fields of the OpenRTB request from the consent
+    /// decision's signals. Uses the stored synthetic ID for user identification.
+    ///
+    /// # Returns
+    /// * `Result<Response, Error>` - Prebid Server response or error
+    pub async fn send_bid_request(
+        &self,
+        settings: &Settings,
+        consent: &ConsentDecision,
+        incoming_req: &Request,
+    ) -> Result<Response, Error> {
+        // Get and store the POTSI ID value from the incoming request
+        let id = self.request_id(incoming_req);
+        log::info!("Found Trusted Server ID from incoming request: {}", id);
+
+        let deadline = Deadline::new(
+            Instant::now(),
+            Duration::from_millis(settings.prebid.auction_timeout_ms),
+        );
+        let Some(mut prebid_body) =
+            self.bid_request_body(settings, consent, incoming_req, &id, deadline)
+        else {
+            return Ok(Response::from_status(StatusCode::NO_CONTENT));
+        };
+        if let Some(user_id) = user_id(settings, consent, &id, "prebid") {
+            prebid_body["user"]["id"] = json!(user_id);
+        }
+        let req = self.outgoing_request(
+            &settings.prebid.server_url,
+            &prebid_body,
+            settings.prebid.gzip,
+        )?;
 
         log::info!(
             "Sending prebid request with Fresh ID: {} and Trusted Server ID: {}",
@@ -206,19 +635,766 @@ impl PrebidRequest {
             id
         );
 
-        req.set_body_json(&prebid_body)?;
-
         let resp = req.send("prebid_backend")?;
         Ok(resp)
     }
+
+    /// Runs the auction on Prebid Server and every endpoint in
+    /// `prebid.endpoints` concurrently and merges their bids.
+    ///
+    /// Every endpoint receives the same bid request, with the time left until
+    /// `deadline` less a processing margin as `tmax`, capped at the endpoint's
+    /// `timeout_ms`. Endpoints still pending at their timeout are abandoned,
+    /// so the auction closes with the bids that arrived in time. Endpoints
+    /// that cannot be reached or answer with an error or an invalid body are
+    /// logged and left out of the merge, so a single failing SSP never fails
+    /// the auction. Slots with bids in the [`BidCache`] are not sent again
+    /// and their cached bids merged instead. With `bidder_health` enabled,
+    /// the outcome of every bid request is counted and endpoints whose
+    /// circuit is open skipped (see [`BidderHealth`]). In debug mode, bid
+    /// requests failing [`validate_bid_request`] are logged and not sent.
+    ///
+    /// # Errors
+    ///
+    /// Returns a Fastly [`Error`] if the bid request cannot be serialized.
+    pub async fn run_auction(
+        &self,
+        settings: &Settings,
+        consent: &ConsentDecision,
+        incoming_req: &Request,
+        deadline: Deadline,
+    ) -> Result<BidResponse, Error> {
+        let id = self.request_id(incoming_req);
+        let Some(mut prebid_body) =
+            self.bid_request_body(settings, consent, incoming_req, &id, deadline)
+        else {
+            return Ok(BidResponse {
+                id,
+                ..Default::default()
+            });
+        };
+
+        let bidders = auction_bidders(&prebid_body);
+        let bid_cache = BidCache::open(settings, consent, incoming_req, &self.domain, &bidders)
+            .map_err(|e| Error::msg(format!("{e:?}")))?;
+        let cached = match &bid_cache {
+            Some(cache) => cache.take_cached(&self.slots, &mut prebid_body),
+            None => Vec::new(),
+        };
+        let endpoints = match prebid_body["imp"].as_array() {
+            Some(imps) if !imps.is_empty() => auction_endpoints(settings),
+            _ => Vec::new(),
+        };
+
+        let health = BidderHealth::open(settings);
+        let record = |name: &str, outcome| {
+            if let Some(health) = &health {
+                health.record(name, outcome);
+            }
+        };
+        let bids_deadline = deadline.minus(AUCTION_PROCESSING_MARGIN);
+        let mut pending = Vec::new();
+        for endpoint in endpoints {
+            if health
+                .as_ref()
+                .is_some_and(|health| health.is_open(&endpoint.name))
+            {
+                log::warn!("Skipping {}, its circuit is open", endpoint.name);
+                continue;
+            }
+            let endpoint_deadline = match endpoint.timeout_ms {
+                Some(timeout_ms) => bids_deadline.within(Duration::from_millis(timeout_ms)),
+                None => bids_deadline,
+            };
+            let tmax = endpoint_deadline.remaining();
+            if tmax < MIN_BIDDER_TIMEOUT {
+                log::warn!("No time left for a bid request to {}", endpoint.name);
+                continue;
+            }
+            prebid_body["tmax"] = json!(tmax.as_millis() as u64);
+            if let Some(user_id) = user_id(settings, consent, &id, &endpoint.name) {
+                prebid_body["user"]["id"] = json!(user_id);
+            }
+            if prebid_body["debug"] == 1 {
+                if let Err(e) = validate_bid_request(&prebid_body) {
+                    log::error!("Not sending bid request to {}: {:?}", endpoint.name, e);
+                    continue;
+                }
+            }
+            let req = self.outgoing_request(&endpoint.url, &prebid_body, endpoint.gzip)?;
+            match req.send_async(endpoint.backend.as_str()) {
+                Ok(request) => pending.push((endpoint.name, request, endpoint_deadline)),
+                Err(e) => {
+                    log::warn!("Failed to send bid request to {}: {:?}", endpoint.name, e);
+                    record(&endpoint.name, Outcome::Error);
+                }
+            }
+        }
+        log::info!("Sent bid request {} to {} endpoints", id, pending.len());
+
+        let mut timed_out: Vec<String> = pending.iter().map(|(name, _, _)| name.clone()).collect();
+        let mut responses = Vec::new();
+        for (name, result) in wait_until(pending) {
+            timed_out.retain(|pending| *pending != name);
+            let (outcome, response) = endpoint_response(&name, result);
+            record(&name, outcome);
+            responses.extend(response);
+        }
+        for name in timed_out {
+            record(&name, Outcome::Timeout);
+        }
+        let fresh = BidResponse::merge(id.clone(), responses);
+        if let Some(cache) = &bid_cache {
+            cache.put(&self.slots, &fresh);
+        }
+        let fresh = Some(fresh).filter(|fresh| !fresh.seatbid.is_empty());
+        Ok(BidResponse::merge(id, cached.into_iter().chain(fresh)))
+    }
+}
+
+/// Gzip compressed `body`, or [`None`] if compressing fails.
+fn gzip_body(body: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    encoder
+        .write_all(body)
+        .and_then(|()| encoder.finish())
+        .inspect_err(|e| log::warn!("Failed to compress the bid request: {:?}", e))
+        .ok()
+}
+
+/// OpenRTB `user.id` of the bid request for `synthetic_id` to `endpoint`, as
+/// configured in `prebid.user_id`.
+fn user_id(
+    settings: &Settings,
+    consent: &ConsentDecision,
+    synthetic_id: &str,
+    endpoint: &str,
+) -> Option<String> {
+    if !consent.is_personalized() {
+        return None;
+    }
+    match settings.prebid.user_id {
+        UserIdMode::Scoped => Some(partner_scoped_id(settings, synthetic_id, endpoint)),
+        UserIdMode::Synthetic => Some(synthetic_id.to_string()),
+        UserIdMode::Omit => None,
+    }
+}
+
+/// Sets the `test` and `debug` flags of the OpenRTB request `body` as
+/// configured in `prebid`. Debug output is never requested in the
+/// production profile.
+fn apply_request_flags(settings: &Settings, body: &mut Value) {
+    let config = &settings.prebid;
+    if config.test {
+        body["test"] = json!(1);
+    }
+    if config.debug {
+        if config.profile == Profile::Production {
+            log::warn!("Ignoring prebid.debug in the production profile");
+        } else {
+            body["debug"] = json!(1);
+        }
+    }
+}
+
+/// Outcome and bid response of the bid request to endpoint `name`. Failed
+/// requests, error statuses and invalid bodies are logged and yield no bid
+/// response.
+fn endpoint_response(
+    name: &str,
+    result: Result<Response, SendError>,
+) -> (Outcome, Option<BidResponse>) {
+    let mut resp = match result {
+        Ok(resp) => resp,
+        Err(e) => {
+            log::warn!("Bid request to {} failed: {:?}", name, e);
+            return (Outcome::Error, None);
+        }
+    };
+    if resp.get_status() == StatusCode::NO_CONTENT {
+        return (Outcome::NoBid, None);
+    }
+    if !resp.get_status().is_success() {
+        log::warn!(
+            "{} answered the bid request with {}",
+            name,
+            resp.get_status()
+        );
+        return (Outcome::Error, None);
+    }
+    match BidResponse::parse(&resp.take_body_bytes()) {
+        Ok(response) if response.seatbid.iter().all(|seat| seat.bid.is_empty()) => {
+            (Outcome::NoBid, Some(response))
+        }
+        Ok(response) => (Outcome::Bids, Some(response)),
+        Err(e) => {
+            log::warn!("Invalid bid response from {}: {:?}", name, e);
+            (Outcome::Error, None)
+        }
+    }
+}
+
+/// OpenRTB `video` object of a slot with player size `(w, h)`.
+fn video_object(video: &VideoParams, (w, h): (u32, u32)) -> Value {
+    let mut object = json!({
+        "mimes": video.mimes,
+        "protocols": video.protocols,
+        "w": w,
+        "h": h,
+        "linearity": 1,
+        "plcmt": video.placement.openrtb_code()
+    });
+    if !video.playback_methods.is_empty() {
+        object["playbackmethod"] = json!(video.playback_methods);
+    }
+    if let Some(min_duration) = video.min_duration {
+        object["minduration"] = json!(min_duration);
+    }
+    if let Some(max_duration) = video.max_duration {
+        object["maxduration"] = json!(max_duration);
+    }
+    object
+}
+
+/// OpenRTB `regs` object carrying the privacy signals of `consent`.
+///
+/// GDPR applicability and the US Privacy string are set both in the OpenRTB
+/// 2.6 fields and in `regs.ext` for OpenRTB 2.5 bidders. A US Privacy string
+/// only carried in an applicable `usp_v1` section of the GPP string is
+/// forwarded as well.
+fn regs(consent: &ConsentDecision) -> Value {
+    let gdpr = u8::from(consent.signals.tcf.gdpr_applies);
+    let mut regs = json!({
+        "gdpr": gdpr,
+        "ext": { "gdpr": gdpr }
+    });
+
+    let gpp = consent.signals.gpp.as_ref();
+    if let Some(gpp) = gpp {
+        regs["gpp"] = json!(gpp.gpp_string);
+        regs["gpp_sid"] = json!(gpp.applicable_sections);
+    }
+
+    let us_privacy = consent
+        .signals
+        .us_privacy
+        .as_ref()
+        .map(|ccpa| ccpa.us_privacy.as_str())
+        .or_else(|| {
+            gpp.filter(|gpp| gpp.applies(section_ids::US_PRIVACY))
+                .and_then(|gpp| gpp.us_privacy.as_deref())
+        });
+    if let Some(us_privacy) = us_privacy {
+        regs["us_privacy"] = json!(us_privacy);
+        regs["ext"]["us_privacy"] = json!(us_privacy);
+    }
+
+    if consent.child_directed {
+        regs["coppa"] = json!(1);
+    }
+    regs
+}
+
+/// Supply chain object of a bid request originating at the publisher, with its
+/// node from `prebid.schain`, or [`None`] until `asi` and `sid` are set.
+fn supply_chain(settings: &Settings, request_id: &str) -> Option<Value> {
+    let node = &settings.prebid.schain;
+    if node.asi.is_empty() || node.sid.is_empty() {
+        return None;
+    }
+    Some(json!({
+        "complete": 1,
+        "ver": "1.0",
+        "nodes": [{
+            "asi": node.asi,
+            "sid": node.sid,
+            "rid": request_id,
+            "hp": if node.hp { 1 } else { 0 }
+        }]
+    }))
+}
+
+/// Prebid Server at `prebid.server_url`, followed by `prebid.endpoints`.
+pub fn auction_endpoints(settings: &Settings) -> Vec<AuctionEndpoint> {
+    let primary = AuctionEndpoint {
+        name: "prebid".to_string(),
+        url: settings.prebid.server_url.clone(),
+        backend: "prebid_backend".to_string(),
+        timeout_ms: None,
+        gzip: settings.prebid.gzip,
+    };
+    std::iter::once(primary)
+        .chain(settings.prebid.endpoints.iter().cloned())
+        .collect()
+}
+
+/// Removes the bidders without TCF consent from every impression of an
+/// OpenRTB request, looking up their vendor IDs in `prebid.bidders`.
+///
+/// Returns the number of bidders left.
+fn retain_consented_bidders(
+    settings: &Settings,
+    consent: &ConsentDecision,
+    vendor_list: Option<&VendorList>,
+    body: &mut Value,
+) -> usize {
+    let Some(imps) = body["imp"].as_array_mut() else {
+        return 0;
+    };
+    let mut remaining = 0;
+    for imp in imps {
+        let Some(bidders) = imp["ext"]["prebid"]["bidder"].as_object_mut() else {
+            continue;
+        };
+        bidders.retain(|bidder, _| {
+            let vendor_id = settings.prebid.bidders.get(bidder).copied();
+            let allowed = consent.allows_vendor(vendor_id, purpose_ids::BASIC_ADS, vendor_list);
+            if !allowed {
+                log::info!("No TCF consent for bidder {}, removing it", bidder);
+            }
+            allowed
+        });
+        remaining += bidders.len();
+    }
+    remaining
+}
+
+/// Names of the bidders left in any impression of an OpenRTB request.
+fn auction_bidders(body: &Value) -> BTreeSet<String> {
+    body["imp"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|imp| imp["ext"]["prebid"]["bidder"].as_object())
+        .flat_map(|bidders| bidders.keys().cloned())
+        .collect()
+}
+
+/// OpenRTB bid response returned by Prebid Server
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct BidResponse {
+    /// ID of the bid request this response answers
+    pub id: String,
+    /// Bids grouped by the seat (bidder) that made them
+    #[serde(default)]
+    pub seatbid: Vec<SeatBid>,
+    /// Currency of the bid prices; OpenRTB defaults to USD
+    #[serde(default)]
+    pub cur: Option<String>,
+}
+
+/// Bids made by one seat
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct SeatBid {
+    /// Bidder code of the seat
+    #[serde(default)]
+    pub seat: String,
+    /// Bids of the seat, at most one per impression
+    #[serde(default)]
+    pub bid: Vec<Bid>,
+}
+
+/// A single OpenRTB bid
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Bid {
+    /// Bidder-assigned bid ID
+    pub id: String,
+    /// ID of the impression the bid is for
+    pub impid: String,
+    /// Bid price (CPM) in the response currency
+    pub price: f64,
+    /// Ad markup, if delivered in the response
+    #[serde(default)]
+    pub adm: Option<String>,
+    /// Win notice URL, which serves the VAST of video bids without `adm`
+    #[serde(default)]
+    pub nurl: Option<String>,
+    /// Billing notice URL, to be fired once the creative rendered
+    #[serde(default)]
+    pub burl: Option<String>,
+    /// Loss notice URL, fired when another bid wins the impression
+    #[serde(default)]
+    pub lurl: Option<String>,
+    /// Advertiser domains, for block list checks
+    #[serde(default)]
+    pub adomain: Vec<String>,
+    /// Creative ID
+    #[serde(default)]
+    pub crid: Option<String>,
+    /// Creative width in pixels
+    #[serde(default)]
+    pub w: Option<u32>,
+    /// Creative height in pixels
+    #[serde(default)]
+    pub h: Option<u32>,
+    /// Bidder and Prebid extensions, including `prebid.targeting`
+    #[serde(default)]
+    pub ext: Value,
+}
+
+impl Bid {
+    /// Whether Prebid Server marked the bid as a video bid
+    fn is_video(&self) -> bool {
+        self.ext["prebid"]["type"] == "video"
+    }
+
+    /// Whether Prebid Server marked the bid as a native bid
+    fn is_native(&self) -> bool {
+        self.ext["prebid"]["type"] == "native"
+    }
+
+    /// Ad markup of the bid: `adm`, the HTML rendering of a native bid's
+    /// response (see [`NativeResponse::render`]), or for a video bid without
+    /// `adm` a VAST wrapper loading the VAST from `nurl`
+    pub fn markup(&self) -> Option<String> {
+        if self.is_native() {
+            let markup = self
+                .adm
+                .as_deref()
+                .and_then(NativeResponse::parse)?
+                .render();
+            if markup.is_none() {
+                log::warn!("Native bid {} has no renderable ad", self.id);
+            }
+            return markup;
+        }
+        if self.adm.is_some() || !self.is_video() {
+            return self.adm.clone();
+        }
+        self.nurl.as_ref().map(|nurl| {
+            format!(
+                "<VAST version=\"3.0\"><Ad><Wrapper><AdSystem>prebid.org wrapper</AdSystem>\
+                 <VASTAdTagURI><![CDATA[{nurl}]]></VASTAdTagURI><Impression></Impression>\
+                 <Creatives></Creatives></Wrapper></Ad></VAST>"
+            )
+        })
+    }
+
+    /// Ad server targeting keys Prebid Server set for the bid (`hb_pb`, `hb_bidder`, ...)
+    pub fn targeting(&self) -> BTreeMap<String, String> {
+        self.ext["prebid"]["targeting"]
+            .as_object()
+            .map(|targeting| {
+                targeting
+                    .iter()
+                    .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_string())))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Highest bid for an impression, with the fields needed to render it or hand
+/// it to the ad server
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WinningBid {
+    /// ID of the impression
+    pub imp_id: String,
+    /// ID of the bid, sent to the ad server as `hb_adid`
+    pub bid_id: String,
+    /// Bidder code of the winning seat
+    pub bidder: String,
+    /// Bid price (CPM)
+    pub price: f64,
+    /// Currency of the price
+    pub currency: String,
+    /// Ad markup, if delivered in the response
+    pub adm: Option<String>,
+    /// Advertiser domains
+    pub adomain: Vec<String>,
+    /// Creative ID
+    pub crid: Option<String>,
+    /// Creative width in pixels
+    pub width: Option<u32>,
+    /// Creative height in pixels
+    pub height: Option<u32>,
+    /// Ad server targeting keys
+    pub targeting: BTreeMap<String, String>,
+    /// Win notice URL; not set for bids without `adm`, whose `nurl` serves
+    /// the markup instead
+    #[serde(skip)]
+    pub nurl: Option<String>,
+    /// Billing notice URL
+    #[serde(skip)]
+    pub burl: Option<String>,
+    /// ID the page confirms the render with on `POST /billing`, when billing
+    /// notifications are enabled (see [`WinNotifier::notify_auction`])
+    pub billing_id: Option<String>,
+}
+
+impl BidResponse {
+    /// Parses a Prebid Server response body.
+    ///
+    /// An empty body, as sent with `204 No Content` when no bidder took part,
+    /// is a response without bids.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::Prebid`] if the body is not an OpenRTB bid response
+    pub fn parse(body: &[u8]) -> Result<Self, Report<TrustedServerError>> {
+        if body.iter().all(u8::is_ascii_whitespace) {
+            return Ok(Self::default());
+        }
+        serde_json::from_slice(body).change_context(TrustedServerError::Prebid {
+            message: "Invalid bid response".to_string(),
+        })
+    }
+
+    /// Currency of the bid prices
+    pub fn currency(&self) -> &str {
+        self.cur.as_deref().unwrap_or("USD")
+    }
+
+    /// Highest priced bid of each impression with its seat. Bids below the
+    /// floor are ignored; ties go to the bid listed first.
+    fn winners(&self) -> HashMap<&str, (&SeatBid, &Bid)> {
+        let mut winners: HashMap<&str, (&SeatBid, &Bid)> = HashMap::new();
+        for seat in &self.seatbid {
+            for bid in seat.bid.iter().filter(|bid| bid.price >= BID_FLOOR) {
+                let winner = winners.entry(&bid.impid).or_insert((seat, bid));
+                if bid.price > winner.1.price {
+                    *winner = (seat, bid);
+                }
+            }
+        }
+        winners
+    }
+
+    /// Selects the highest priced bid of each impression, in impression ID
+    /// order. Bids below the floor are ignored; ties go to the bid listed
+    /// first.
+    pub fn winning_bids(&self) -> Vec<WinningBid> {
+        let mut winners: Vec<WinningBid> = self
+            .winners()
+            .into_values()
+            .map(|(seat, bid)| WinningBid {
+                imp_id: bid.impid.clone(),
+                bid_id: bid.id.clone(),
+                bidder: seat.seat.clone(),
+                price: bid.price,
+                currency: self.currency().to_string(),
+                adm: bid.markup(),
+                adomain: bid.adomain.clone(),
+                crid: bid.crid.clone(),
+                width: bid.w,
+                height: bid.h,
+                targeting: bid.targeting(),
+                nurl: bid.adm.as_ref().and(bid.nurl.clone()),
+                burl: bid.burl.clone(),
+                billing_id: None,
+            })
+            .collect();
+        winners.sort_by(|a, b| a.imp_id.cmp(&b.imp_id));
+        winners
+    }
+
+    /// Loss notice URLs of every bid that did not win its impression, across
+    /// all seats of a merged response, with the auction macros of the bid and
+    /// its loss reason substituted (see [`AuctionMacros`]).
+    pub fn loss_notices(&self, settings: &Settings) -> Vec<String> {
+        let winners = self.winners();
+        self.seatbid
+            .iter()
+            .flat_map(|seat| seat.bid.iter().map(move |bid| (seat, bid)))
+            .filter_map(|(seat, bid)| {
+                let lurl = bid.lurl.as_ref()?;
+                let winner = winners.get(bid.impid.as_str()).map(|(_, winner)| *winner);
+                if winner.is_some_and(|winner| std::ptr::eq(winner, bid)) {
+                    return None;
+                }
+                let reason = if bid.price <= 0.0 {
+                    LossReason::MissingBidPrice
+                } else if bid.price < BID_FLOOR {
+                    LossReason::BelowAuctionFloor
+                } else {
+                    LossReason::LostToHigherBid
+                };
+                let macros =
+                    AuctionMacros::for_bid(settings, &self.id, &seat.seat, bid, self.currency())
+                        .with_loss(reason, winner.map(|winner| winner.price));
+                Some(macros.expand(lurl))
+            })
+            .collect()
+    }
+
+    /// Merges the seat bids of several responses to the bid request `id`.
+    ///
+    /// Prices are only comparable in one currency, so responses in another
+    /// currency than the first are left out.
+    pub fn merge(id: String, responses: impl IntoIterator<Item = BidResponse>) -> Self {
+        let mut merged = Self {
+            id,
+            ..Default::default()
+        };
+        for response in responses {
+            let currency = response.currency().to_string();
+            match &merged.cur {
+                Some(cur) if *cur != currency => {
+                    log::warn!("Ignoring bid response in {} instead of {}", currency, cur);
+                    continue;
+                }
+                _ => merged.cur = Some(currency),
+            }
+            merged.seatbid.extend(response.seatbid);
+        }
+        merged
+    }
+
+    /// Summarizes the auction as returned to the publisher page, with the
+    /// auction macros in the markup and notification URLs of each winning bid
+    /// substituted (see [`AuctionMacros`]), its markup pointed to first-party
+    /// creative hosts (see [`rewrite_creative`]) and its `hb_*` targeting keys
+    /// (see [`apply_targeting`]).
+    pub fn auction_result(&self, settings: &Settings) -> AuctionResult {
+        let mut bids = self.winning_bids();
+        for bid in &mut bids {
+            let macros = AuctionMacros::for_winning_bid(settings, &self.id, bid);
+            let [adm, nurl, burl] = [&bid.adm, &bid.nurl, &bid.burl]
+                .map(|text| text.as_deref().map(|text| macros.expand(text)));
+            let adm = adm.map(|adm| rewrite_creative(settings, &adm));
+            (bid.adm, bid.nurl, bid.burl) = (adm, nurl, burl);
+        }
+        apply_targeting(settings, &mut bids);
+        AuctionResult {
+            id: self.id.clone(),
+            currency: self.currency().to_string(),
+            bids,
+            audience: AudienceSegments::default(),
+        }
+    }
+}
+
+/// Outcome of an auction: the winning bid of each impression that received one
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuctionResult {
+    /// ID of the bid request
+    pub id: String,
+    /// Currency of the bid prices
+    pub currency: String,
+    /// Winning bids in impression ID order
+    pub bids: Vec<WinningBid>,
+    /// Audience segments sent to the bidders, for the ad server request
+    /// following the auction
+    #[serde(skip)]
+    pub audience: AudienceSegments,
+}
+
+/// Runs the auction of `auction` for `req` and picks the winners.
+///
+/// Every slot is auctioned as one impression on every configured endpoint
+/// (see [`PrebidRequest::run_auction`]); bids arriving after `deadline`, less
+/// a processing margin, are left out. The bids of blocked advertisers and
+/// creatives are dropped (see [`BlockList::reject_blocked`]), the creatives of
+/// the winning bids cached when `prebid_cache` is configured (see
+/// [`CreativeCache::cache_bids`]) and the win and loss notifications of all
+/// bids fired when `win_notifications` is enabled (see
+/// [`WinNotifier::notify_auction`]). The synthetic ID is only sent when the
+/// consent decision allows personalized ads.
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if the synthetic ID, the block list or the
+/// configured stores cannot be read, or a bid request cannot be built.
+pub async fn execute_auction(
+    settings: &Settings,
+    consent: &ConsentDecision,
+    req: &mut Request,
+    auction: AuctionRequest,
+    deadline: Deadline,
+) -> Result<AuctionResult, Error> {
+    let synthetic_id = if consent.is_personalized() {
+        get_or_generate_synthetic_id(settings, req).map_err(|e| Error::msg(format!("{e:?}")))?
+    } else {
+        "non-personalized".to_string()
+    };
+    req.set_header(HEADER_SYNTHETIC_TRUSTED_SERVER, &synthetic_id);
+
+    let mut prebid_req =
+        PrebidRequest::new(settings, req).map_err(|e| Error::msg(format!("{e:?}")))?;
+    prebid_req.slots = auction.slots;
+    prebid_req.first_party_data = auction.first_party_data;
+    let audience = DataProviderManager::from_settings(settings, consent)
+        .within(deadline.minus(AUCTION_PROCESSING_MARGIN))
+        .collect(&synthetic_id)
+        .await;
+    prebid_req
+        .first_party_data
+        .segments
+        .extend(audience.segment_groups());
+    log::info!("Running auction for {} slots", prebid_req.slots.len());
+
+    let mut bid_response = prebid_req
+        .run_auction(settings, consent, req, deadline)
+        .await?;
+    let block_list = BlockList::load(settings).map_err(|e| Error::msg(format!("{e:?}")))?;
+    let mut loss_notices = block_list.reject_blocked(settings, &mut bid_response);
+    let mut result = bid_response.auction_result(settings);
+    if let Some(cache) = CreativeCache::open(settings).map_err(|e| Error::msg(format!("{e:?}")))? {
+        cache.cache_bids(&mut result.bids);
+    }
+    if let Some(notifier) = WinNotifier::open(settings).map_err(|e| Error::msg(format!("{e:?}")))? {
+        loss_notices.extend(bid_response.loss_notices(settings));
+        notifier.notify_auction(&mut result.bids, loss_notices);
+    }
+    result.audience = audience;
+    Ok(result)
+}
+
+/// Handles `POST /auction`.
+///
+/// Runs an auction with one impression per slot in the JSON body (see
+/// [`parse_auction_request`] and [`execute_auction`]) and responds with the
+/// [`AuctionResult`].
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if response creation or the auction fails, or
+/// stored requests cannot be read.
+pub async fn handle_auction(
+    settings: &Settings,
+    consent: &ConsentDecision,
+    mut req: Request,
+    deadline: Deadline,
+) -> Result<Response, Error> {
+    if *req.get_method() != Method::POST {
+        return Ok(
+            Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_body("Method not allowed")
+        );
+    }
+    let stored = StoredRequests::open(settings).map_err(|e| Error::msg(format!("{e:?}")))?;
+    let auction = match parse_auction_request(&req.take_body_bytes(), stored.as_ref()) {
+        Ok(auction) => auction,
+        Err(e) if matches!(e.current_context(), TrustedServerError::KvStore { .. }) => {
+            return Err(Error::msg(format!("{e:?}")));
+        }
+        Err(e) => {
+            log::warn!("Rejected auction request: {:?}", e);
+            return Ok(Response::from_status(StatusCode::BAD_REQUEST)
+                .with_header(header::CONTENT_TYPE, "application/json")
+                .with_body_json(&json!({ "error": e.current_context().to_string() }))?);
+        }
+    };
+
+    let result = execute_auction(settings, consent, &mut req, auction, deadline).await?;
+    Ok(Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_header(header::CACHE_CONTROL, "no-store, private")
+        .with_body_json(&result)?)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::io::Read;
+
     use super::*;
     use fastly::Request;
+    use flate2::read::GzDecoder;
 
+    use crate::consent::PersonalizationLevel;
+    use crate::gpp::parse_gpp_string;
+    use crate::settings::RegisteredSlot;
     use crate::test_support::tests::create_test_settings;
+    use crate::us_privacy::CcpaConsent;
 
     #[test]
     fn test_prebid_request_new_with_full_headers() {
@@ -311,6 +1487,8 @@ mod tests {
             synthetic_id: "test-id".to_string(),
             domain: "test.com".to_string(),
             banner_sizes: vec![(300, 250), (728, 90)],
+            slots: Vec::new(),
+            first_party_data: FirstPartyData::default(),
             client_ip: "192.168.1.1".to_string(),
             origin: "https://test.com".to_string(),
         };
@@ -330,6 +1508,8 @@ mod tests {
             synthetic_id: "test-id".to_string(),
             domain: "test.com".to_string(),
             banner_sizes: vec![(300, 250), (728, 90), (160, 600)],
+            slots: Vec::new(),
+            first_party_data: FirstPartyData::default(),
             client_ip: "192.168.1.1".to_string(),
             origin: "https://test.com".to_string(),
         };
@@ -358,7 +1538,698 @@ mod tests {
         assert_eq!(prebid_req2.domain, settings.publisher.domain);
     }
 
+    #[test]
+    fn test_retain_consented_bidders() {
+        let mut settings = create_test_settings();
+        settings.prebid.bidders.insert("appnexus".to_string(), 32);
+        let body = json!({
+            "imp": [{ "ext": { "prebid": { "bidder": {
+                "smartadserver": {},
+                "appnexus": {},
+                "unknown": {}
+            } } } }]
+        });
+
+        // Clients that cannot be located are under GDPR, so no bidder stays
+        // without a TC string
+        let mut consent =
+            ConsentDecision::from_request(&settings, &Request::get("https://example.com"));
+        let mut none = body.clone();
+        assert_eq!(
+            retain_consented_bidders(&settings, &consent, None, &mut none),
+            0
+        );
+
+        // Without GDPR every bidder stays
+        consent.signals.tcf.gdpr_applies = false;
+        let mut all = body.clone();
+        assert_eq!(
+            retain_consented_bidders(&settings, &consent, None, &mut all),
+            3
+        );
+
+        // Purposes 1-4 with vendor consent for vendors 2, 6 and 8 only
+        settings.prebid.bidders.insert("appnexus".to_string(), 8);
+        let req = Request::get("https://example.com").with_header(
+            "Cookie",
+            "euconsent-v2=COvFyGBOvFyGBAbAAAENAPCAAPAAAAAAAAAAAEEUACCKAAA",
+        );
+        let consent = ConsentDecision::from_request(&settings, &req);
+        let mut consented = body.clone();
+        assert_eq!(
+            retain_consented_bidders(&settings, &consent, None, &mut consented),
+            1
+        );
+        assert!(consented["imp"][0]["ext"]["prebid"]["bidder"]["appnexus"].is_object());
+        assert_eq!(
+            auction_bidders(&consented),
+            BTreeSet::from(["appnexus".to_string()])
+        );
+    }
+
     // Note: Testing send_bid_request would require mocking the Fastly backend,
     // which isn't available in unit tests. This would be covered in integration tests.
     // The method constructs a proper OpenRTB request with all required fields.
+
+    #[test]
+    fn test_bid_response_winning_bids() {
+        let body = json!({
+            "id": "request-1",
+            "cur": "EUR",
+            "seatbid": [
+                {
+                    "seat": "smartadserver",
+                    "bid": [
+                        {
+                            "id": "b1", "impid": "imp1", "price": 1.2, "adm": "<div>a</div>",
+                            "adomain": ["advertiser.example"], "crid": "c1", "w": 728, "h": 90,
+                            "ext": {
+                                "prebid": {
+                                    "targeting": { "hb_pb": "1.20", "hb_bidder": "smartadserver" }
+                                }
+                            }
+                        },
+                        { "id": "b2", "impid": "imp2", "price": 0.0 }
+                    ]
+                },
+                {
+                    "seat": "other",
+                    "bid": [
+                        { "id": "b3", "impid": "imp1", "price": 0.8 },
+                        { "id": "b4", "impid": "imp2", "price": 0.5, "crid": "c4" }
+                    ]
+                }
+            ]
+        });
+        let response = BidResponse::parse(body.to_string().as_bytes()).unwrap();
+        let winners = response.winning_bids();
+
+        assert_eq!(winners.len(), 2);
+        assert_eq!(winners[0].imp_id, "imp1");
+        assert_eq!(winners[0].bidder, "smartadserver");
+        assert_eq!(winners[0].price, 1.2);
+        assert_eq!(winners[0].currency, "EUR");
+        assert_eq!(winners[0].adm.as_deref(), Some("<div>a</div>"));
+        assert_eq!(winners[0].adomain, vec!["advertiser.example"]);
+        assert_eq!(winners[0].width, Some(728));
+        assert_eq!(
+            winners[0].targeting.get("hb_pb").map(String::as_str),
+            Some("1.20")
+        );
+        assert_eq!(winners[1].bidder, "other");
+        assert_eq!(winners[1].crid.as_deref(), Some("c4"));
+    }
+
+    #[test]
+    fn test_bid_response_parse_empty_and_invalid() {
+        let response = BidResponse::parse(b"").unwrap();
+        assert!(response.winning_bids().is_empty());
+        assert_eq!(response.currency(), "USD");
+        assert!(BidResponse::parse(b"<html>").is_err());
+    }
+
+    #[test]
+    fn test_parse_auction_request() {
+        let body = json!([
+            { "code": "top", "sizes": [[728, 90], [970, 250]], "position": "above_the_fold" },
+            { "code": "side", "sizes": [[300, 250]] }
+        ]);
+        let auction = parse_auction_request(body.to_string().as_bytes(), None).unwrap();
+        assert_eq!(auction.first_party_data, FirstPartyData::default());
+        let slots = auction.slots;
+        assert_eq!(slots.len(), 2);
+        assert_eq!(slots[0].sizes, vec![(728, 90), (970, 250)]);
+        assert_eq!(slots[0].position, SlotPosition::AboveTheFold);
+        assert_eq!(slots[1].position, SlotPosition::Unknown);
+
+        for invalid in [
+            json!([]),
+            json!({ "code": "top" }),
+            json!([{ "code": "", "sizes": [[300, 250]] }]),
+            json!([{ "code": "a", "sizes": [[300, 250]] }, { "code": "a", "sizes": [[1, 1]] }]),
+            json!([{ "code": "a", "sizes": [] }]),
+            json!([{ "code": "a", "sizes": [[0, 250]] }]),
+            json!([{ "code": "a", "sizes": [[300, 250]], "floor": 1 }]),
+            json!([{ "storedrequest": "homepage-top" }]),
+            json!({ "slots": [{ "code": "a", "sizes": [[1, 1]] }], "fpd": {} }),
+            json!({
+                "slots": [{ "code": "a", "sizes": [[1, 1]] }],
+                "first_party_data": { "keywords": [""] }
+            }),
+        ] {
+            let result = parse_auction_request(invalid.to_string().as_bytes(), None);
+            assert!(result.is_err(), "{invalid}");
+        }
+
+        let body = json!({
+            "slots": [{ "code": "top", "sizes": [[728, 90]] }],
+            "first_party_data": { "categories": ["483"], "keywords": ["cars"] }
+        });
+        let auction = parse_auction_request(body.to_string().as_bytes(), None).unwrap();
+        assert_eq!(auction.slots.len(), 1);
+        assert_eq!(auction.first_party_data.keywords, vec!["cars".to_string()]);
+    }
+
+    #[test]
+    fn test_prebid_request_imps_from_slots() {
+        let settings = create_test_settings();
+        let mut prebid_req = PrebidRequest {
+            synthetic_id: "test-id".to_string(),
+            domain: "test.com".to_string(),
+            banner_sizes: vec![(728, 90)],
+            slots: Vec::new(),
+            first_party_data: FirstPartyData::default(),
+            client_ip: "192.168.1.1".to_string(),
+            origin: "https://test.com".to_string(),
+        };
+        let imps = prebid_req.imps(&SlotRegistry::open(&settings));
+        assert_eq!(imps.len(), 1);
+        assert_eq!(imps[0]["id"], "imp1");
+        assert!(imps[0]["banner"].get("pos").is_none());
+
+        prebid_req.slots = vec![
+            AdSlot {
+                code: "top".to_string(),
+                sizes: vec![(728, 90)],
+                position: SlotPosition::AboveTheFold,
+                video: None,
+                native: None,
+                format: SlotFormat::Standard,
+            },
+            AdSlot {
+                code: "side".to_string(),
+                sizes: vec![(300, 250), (300, 600)],
+                position: SlotPosition::Sidebar,
+                video: None,
+                native: None,
+                format: SlotFormat::Standard,
+            },
+        ];
+        let imps = prebid_req.imps(&SlotRegistry::open(&settings));
+        assert_eq!(imps.len(), 2);
+        assert_eq!(imps[0]["id"], "top");
+        assert_eq!(imps[0]["banner"]["pos"], 1);
+        assert_eq!(imps[1]["tagid"], "side");
+        assert_eq!(
+            imps[1]["banner"]["format"][1],
+            json!({ "w": 300, "h": 600 })
+        );
+        assert_eq!(imps[1]["banner"]["pos"], 6);
+        assert!(imps[1]["ext"]["prebid"]["bidder"]["smartadserver"].is_object());
+
+        // Registered slots use their registered sizes, ad unit and bidders
+        let mut settings = settings;
+        settings.slot_registry.slots = vec![RegisteredSlot {
+            code: "side".to_string(),
+            sizes: vec![(300, 250)],
+            gam_ad_unit: "/3790/trustedserver/homepage".to_string(),
+            bidders: HashMap::from([("appnexus".to_string(), json!({ "placementId": 13 }))]),
+        }];
+        let imps = prebid_req.imps(&SlotRegistry::open(&settings));
+        assert_eq!(imps[0]["tagid"], "top");
+        assert_eq!(imps[1]["tagid"], "/3790/trustedserver/homepage");
+        assert_eq!(imps[1]["banner"]["format"], json!([{ "w": 300, "h": 250 }]));
+        assert_eq!(
+            imps[1]["ext"]["prebid"]["bidder"],
+            json!({ "appnexus": { "placementId": 13 } })
+        );
+    }
+
+    #[test]
+    fn test_interstitial_and_rewarded_slots() {
+        let settings = create_test_settings();
+        let body = json!([
+            { "code": "top", "sizes": [[728, 90]] },
+            { "code": "between-pages", "sizes": [[320, 480]], "format": "interstitial" },
+            {
+                "code": "bonus",
+                "sizes": [[640, 480]],
+                "format": "rewarded",
+                "video": { "mimes": ["video/mp4"] }
+            }
+        ]);
+        let auction = parse_auction_request(body.to_string().as_bytes(), None).unwrap();
+        assert_eq!(auction.slots[1].format, SlotFormat::Interstitial);
+        let prebid_req = PrebidRequest {
+            synthetic_id: "test-id".to_string(),
+            domain: "test.com".to_string(),
+            banner_sizes: vec![(728, 90)],
+            slots: auction.slots,
+            first_party_data: FirstPartyData::default(),
+            client_ip: "192.168.1.1".to_string(),
+            origin: "https://test.com".to_string(),
+        };
+        let imps = prebid_req.imps(&SlotRegistry::open(&settings));
+        assert!(imps[0].get("instl").is_none());
+        assert!(imps[0].get("rwdd").is_none());
+
+        assert_eq!(imps[1]["instl"], 1);
+        assert!(imps[1].get("rwdd").is_none());
+        assert_eq!(imps[1]["banner"]["pos"], 7);
+
+        assert_eq!(imps[2]["instl"], 1);
+        assert_eq!(imps[2]["rwdd"], 1);
+        assert_eq!(imps[2]["ext"]["prebid"]["is_rewarded_inventory"], 1);
+        assert_eq!(imps[2]["video"]["pos"], 7);
+        assert_eq!(imps[2]["video"]["plcmt"], 3);
+        assert_eq!(imps[2]["video"]["skip"], 0);
+
+        let body = json!([{ "code": "top", "sizes": [[728, 90]], "format": "popup" }]);
+        assert!(parse_auction_request(body.to_string().as_bytes(), None).is_err());
+    }
+
+    #[test]
+    fn test_video_slots() {
+        let settings = create_test_settings();
+        let body = json!([{
+            "code": "preroll",
+            "sizes": [[640, 480]],
+            "video": {
+                "mimes": ["video/mp4"],
+                "playback_methods": [2],
+                "min_duration": 5,
+                "max_duration": 30,
+                "placement": "no_content"
+            }
+        }]);
+        let auction = parse_auction_request(body.to_string().as_bytes(), None).unwrap();
+        let prebid_req = PrebidRequest {
+            synthetic_id: "test-id".to_string(),
+            domain: "test.com".to_string(),
+            banner_sizes: vec![(728, 90)],
+            slots: auction.slots,
+            first_party_data: FirstPartyData::default(),
+            client_ip: "192.168.1.1".to_string(),
+            origin: "https://test.com".to_string(),
+        };
+        let imps = prebid_req.imps(&SlotRegistry::open(&settings));
+        assert!(imps[0].get("banner").is_none());
+        assert_eq!(
+            imps[0]["video"],
+            json!({
+                "mimes": ["video/mp4"],
+                "protocols": [2, 3, 5, 6, 7, 8],
+                "w": 640,
+                "h": 480,
+                "linearity": 1,
+                "plcmt": 4,
+                "playbackmethod": [2],
+                "minduration": 5,
+                "maxduration": 30
+            })
+        );
+
+        for video in [
+            json!({ "mimes": [] }),
+            json!({ "mimes": ["video/mp4"], "min_duration": 30, "max_duration": 5 }),
+        ] {
+            let body = json!([{ "code": "preroll", "sizes": [[640, 480]], "video": video }]);
+            assert!(
+                parse_auction_request(body.to_string().as_bytes(), None).is_err(),
+                "{video}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_video_bid_markup() {
+        let mut bid = Bid {
+            id: "bid-1".to_string(),
+            impid: "preroll".to_string(),
+            price: 5.0,
+            nurl: Some("https://dsp.example/vast?id=1".to_string()),
+            ext: json!({ "prebid": { "type": "video" } }),
+            ..Default::default()
+        };
+        let wrapper = bid.markup().unwrap();
+        assert!(wrapper.starts_with("<VAST version=\"3.0\">"));
+        assert!(wrapper.contains("<![CDATA[https://dsp.example/vast?id=1]]>"));
+
+        bid.adm = Some("<VAST version=\"4.0\"/>".to_string());
+        assert_eq!(bid.markup().as_deref(), Some("<VAST version=\"4.0\"/>"));
+
+        bid.adm = None;
+        bid.ext = json!({ "prebid": { "type": "banner" } });
+        assert_eq!(bid.markup(), None);
+    }
+
+    #[test]
+    fn test_native_slots_and_markup() {
+        let settings = create_test_settings();
+        let body = json!([{
+            "code": "feed",
+            "sizes": [[1, 1]],
+            "position": "above_the_fold",
+            "native": { "image": [600, 314], "data": ["sponsored"] }
+        }]);
+        let auction = parse_auction_request(body.to_string().as_bytes(), None).unwrap();
+        let prebid_req = PrebidRequest {
+            synthetic_id: "test-id".to_string(),
+            domain: "test.com".to_string(),
+            banner_sizes: vec![(728, 90)],
+            slots: auction.slots,
+            first_party_data: FirstPartyData::default(),
+            client_ip: "192.168.1.1".to_string(),
+            origin: "https://test.com".to_string(),
+        };
+        let imps = prebid_req.imps(&SlotRegistry::open(&settings));
+        assert!(imps[0].get("banner").is_none());
+        assert_eq!(imps[0]["native"]["ver"], "1.2");
+        assert!(imps[0]["native"].get("pos").is_none());
+
+        let body = json!([{
+            "code": "feed",
+            "sizes": [[640, 480]],
+            "video": { "mimes": ["video/mp4"] },
+            "native": {}
+        }]);
+        assert!(parse_auction_request(body.to_string().as_bytes(), None).is_err());
+
+        let adm = json!({
+            "assets": [{ "id": 1, "title": { "text": "Title" } }],
+            "link": { "url": "https://brand.example" }
+        });
+        let mut bid = Bid {
+            id: "bid-1".to_string(),
+            impid: "feed".to_string(),
+            price: 1.0,
+            adm: Some(adm.to_string()),
+            ext: json!({ "prebid": { "type": "native" } }),
+            ..Default::default()
+        };
+        let markup = bid.markup().unwrap();
+        assert!(markup.starts_with("<div class=\"native-ad\">"));
+        assert!(markup.contains("<h3 class=\"native-ad-title\">Title</h3>"));
+
+        bid.adm = Some("<script>alert(1)</script>".to_string());
+        assert_eq!(bid.markup(), None);
+    }
+
+    #[test]
+    fn test_winning_bid_notice_urls() {
+        let response = BidResponse {
+            id: "auction-1".to_string(),
+            seatbid: vec![SeatBid {
+                seat: "smartadserver".to_string(),
+                bid: vec![
+                    Bid {
+                        id: "bid-1".to_string(),
+                        impid: "top".to_string(),
+                        price: 1.5,
+                        adm: Some("<div>ad</div>".to_string()),
+                        nurl: Some("https://dsp.example/win?p=${AUCTION_PRICE}".to_string()),
+                        burl: Some("https://dsp.example/bill?a=${AUCTION_ID}".to_string()),
+                        ..Default::default()
+                    },
+                    Bid {
+                        id: "bid-2".to_string(),
+                        impid: "preroll".to_string(),
+                        price: 4.0,
+                        nurl: Some("https://dsp.example/vast?p=${AUCTION_PRICE}".to_string()),
+                        ext: json!({ "prebid": { "type": "video" } }),
+                        ..Default::default()
+                    },
+                ],
+            }],
+            cur: None,
+        };
+        let bids = response.auction_result(&create_test_settings()).bids;
+        assert_eq!(bids[0].imp_id, "preroll");
+        assert_eq!(bids[0].nurl, None);
+        assert!(bids[0]
+            .adm
+            .as_ref()
+            .unwrap()
+            .contains("https://dsp.example/vast?p=4"));
+        assert_eq!(
+            bids[1].nurl.as_deref(),
+            Some("https://dsp.example/win?p=1.5")
+        );
+        assert_eq!(
+            bids[1].burl.as_deref(),
+            Some("https://dsp.example/bill?a=auction-1")
+        );
+        assert!(serde_json::to_value(&bids[1])
+            .unwrap()
+            .get("burl")
+            .is_none());
+    }
+
+    #[test]
+    fn test_loss_notices() {
+        let bid = |id: &str, impid: &str, price: f64| Bid {
+            id: id.to_string(),
+            impid: impid.to_string(),
+            price,
+            lurl: Some(format!(
+                "https://dsp.example/loss?b={id}&r=${{AUCTION_LOSS}}&m=${{AUCTION_MIN_TO_WIN}}"
+            )),
+            ..Default::default()
+        };
+        let response = BidResponse::merge(
+            "auction-1".to_string(),
+            [
+                BidResponse {
+                    seatbid: vec![SeatBid {
+                        seat: "smartadserver".to_string(),
+                        bid: vec![bid("a", "top", 2.0), bid("b", "side", 0.005)],
+                    }],
+                    ..Default::default()
+                },
+                BidResponse {
+                    seatbid: vec![SeatBid {
+                        seat: "other".to_string(),
+                        bid: vec![bid("c", "top", 3.0), bid("d", "side", 0.0)],
+                    }],
+                    ..Default::default()
+                },
+            ],
+        );
+        assert_eq!(response.winning_bids().len(), 1);
+        assert_eq!(
+            response.loss_notices(&create_test_settings()),
+            vec![
+                "https://dsp.example/loss?b=a&r=102&m=3".to_string(),
+                "https://dsp.example/loss?b=b&r=100&m=".to_string(),
+                "https://dsp.example/loss?b=d&r=9&m=".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bid_response_merge() {
+        let response = |cur: Option<&str>, seat: &str, price: f64| BidResponse {
+            id: "request-1".to_string(),
+            cur: cur.map(str::to_string),
+            seatbid: vec![SeatBid {
+                seat: seat.to_string(),
+                bid: vec![Bid {
+                    id: format!("{seat}-bid"),
+                    impid: "imp1".to_string(),
+                    price,
+                    ..Default::default()
+                }],
+            }],
+        };
+
+        let merged = BidResponse::merge(
+            "request-1".to_string(),
+            [
+                response(None, "pbs", 1.0),
+                response(Some("USD"), "ssp", 2.5),
+                response(Some("EUR"), "euro-ssp", 9.0),
+            ],
+        );
+        assert_eq!(merged.seatbid.len(), 2);
+        assert_eq!(merged.currency(), "USD");
+        let winners = merged.winning_bids();
+        assert_eq!(winners.len(), 1);
+        assert_eq!(winners[0].bidder, "ssp");
+        assert_eq!(winners[0].price, 2.5);
+
+        let empty = BidResponse::merge("request-2".to_string(), []);
+        assert_eq!(empty.id, "request-2");
+        assert!(empty.winning_bids().is_empty());
+    }
+
+    #[test]
+    fn test_user_id() {
+        let mut settings = create_test_settings();
+        let mut consent =
+            ConsentDecision::from_request(&settings, &Request::get("https://example.com"));
+        consent.personalization = PersonalizationLevel::Personalized;
+        let scoped = user_id(&settings, &consent, "synthetic-1", "prebid").unwrap();
+        assert_ne!(scoped, "synthetic-1");
+        assert_ne!(
+            Some(scoped),
+            user_id(&settings, &consent, "synthetic-1", "example-ssp")
+        );
+
+        settings.prebid.user_id = UserIdMode::Synthetic;
+        assert_eq!(
+            user_id(&settings, &consent, "synthetic-1", "prebid").as_deref(),
+            Some("synthetic-1")
+        );
+        settings.prebid.user_id = UserIdMode::Omit;
+        assert!(user_id(&settings, &consent, "synthetic-1", "prebid").is_none());
+
+        // Without personalized ads no user ID is sent
+        settings.prebid.user_id = UserIdMode::Synthetic;
+        consent.personalization = PersonalizationLevel::NonPersonalized;
+        assert!(user_id(&settings, &consent, "synthetic-1", "prebid").is_none());
+    }
+
+    #[test]
+    fn test_apply_request_flags() {
+        let mut settings = create_test_settings();
+        let mut body = json!({});
+        apply_request_flags(&settings, &mut body);
+        assert_eq!(body, json!({}));
+
+        settings.prebid.test = true;
+        settings.prebid.debug = true;
+        apply_request_flags(&settings, &mut body);
+        assert_eq!(body, json!({ "test": 1, "debug": 1 }));
+
+        // Debug output is never requested in production
+        settings.prebid.profile = Profile::Production;
+        let mut body = json!({});
+        apply_request_flags(&settings, &mut body);
+        assert_eq!(body, json!({ "test": 1 }));
+    }
+
+    #[test]
+    fn test_regs() {
+        let settings = create_test_settings();
+        let mut consent =
+            ConsentDecision::from_request(&settings, &Request::get("https://example.com"));
+        consent.signals.tcf.gdpr_applies = false;
+        assert_eq!(regs(&consent), json!({ "gdpr": 0, "ext": { "gdpr": 0 } }));
+
+        // The US Privacy string of the GPP section is forwarded
+        let gpp_string = "DBABzw~1YYN~BVVqAAEABCA";
+        consent.signals.gpp = Some(parse_gpp_string(gpp_string, &[6, 7]).unwrap());
+        consent.child_directed = true;
+        assert_eq!(
+            regs(&consent),
+            json!({
+                "gdpr": 0,
+                "gpp": gpp_string,
+                "gpp_sid": [6, 7],
+                "us_privacy": "1YYN",
+                "coppa": 1,
+                "ext": { "gdpr": 0, "us_privacy": "1YYN" }
+            })
+        );
+
+        // ...unless that section does not apply, and the standalone string wins
+        consent.signals.gpp = Some(parse_gpp_string(gpp_string, &[7]).unwrap());
+        assert!(regs(&consent).get("us_privacy").is_none());
+        consent.signals.us_privacy = Some(CcpaConsent::parse("1YNN").unwrap());
+        assert_eq!(regs(&consent)["us_privacy"], "1YNN");
+        assert_eq!(regs(&consent)["ext"]["us_privacy"], "1YNN");
+    }
+
+    #[test]
+    fn test_supply_chain() {
+        let mut settings = create_test_settings();
+        assert_eq!(supply_chain(&settings, "request-1"), None);
+
+        settings.prebid.schain.asi = "publisher.example".to_string();
+        assert_eq!(supply_chain(&settings, "request-1"), None);
+
+        settings.prebid.schain.sid = "pub-123".to_string();
+        assert_eq!(
+            supply_chain(&settings, "request-1"),
+            Some(json!({
+                "complete": 1,
+                "ver": "1.0",
+                "nodes": [{
+                    "asi": "publisher.example",
+                    "sid": "pub-123",
+                    "rid": "request-1",
+                    "hp": 1
+                }]
+            }))
+        );
+    }
+
+    #[test]
+    fn test_auction_endpoints() {
+        let mut settings = create_test_settings();
+        settings.prebid.endpoints = vec![AuctionEndpoint {
+            name: "ssp".to_string(),
+            url: "https://ssp.example.com/openrtb2/auction".to_string(),
+            backend: "ssp_backend".to_string(),
+            timeout_ms: Some(300),
+            gzip: true,
+        }];
+        let endpoints = auction_endpoints(&settings);
+        assert_eq!(endpoints.len(), 2);
+        assert_eq!(endpoints[0].url, settings.prebid.server_url);
+        assert_eq!(endpoints[0].backend, "prebid_backend");
+        assert_eq!(endpoints[0].timeout_ms, None);
+        assert_eq!(endpoints[1].name, "ssp");
+        assert_eq!(endpoints[1].timeout_ms, Some(300));
+        assert!(!endpoints[0].gzip);
+        assert!(endpoints[1].gzip);
+    }
+
+    #[test]
+    fn test_outgoing_request_gzip() {
+        let prebid_req = PrebidRequest {
+            synthetic_id: "test-id".to_string(),
+            domain: "test.com".to_string(),
+            banner_sizes: vec![(728, 90)],
+            slots: Vec::new(),
+            first_party_data: FirstPartyData::default(),
+            client_ip: "192.168.1.1".to_string(),
+            origin: "https://test.com".to_string(),
+        };
+        let url = "https://ssp.example.com/openrtb2/auction";
+        let body = json!({ "id": "auction-1", "imp": vec![json!({ "id": "top" }); 100] });
+
+        let mut req = prebid_req.outgoing_request(url, &body, true).unwrap();
+        assert_eq!(req.get_header_str(header::CONTENT_ENCODING), Some("gzip"));
+        let mut json = String::new();
+        GzDecoder::new(&req.take_body_bytes()[..])
+            .read_to_string(&mut json)
+            .unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), body);
+
+        // Small bodies and endpoints without gzip support are sent as is
+        let mut req = prebid_req.outgoing_request(url, &body, false).unwrap();
+        assert!(req.get_header(header::CONTENT_ENCODING).is_none());
+        assert_eq!(req.take_body_json::<Value>().unwrap(), body);
+        let small = json!({ "id": "auction-1" });
+        let req = prebid_req.outgoing_request(url, &small, true).unwrap();
+        assert!(req.get_header(header::CONTENT_ENCODING).is_none());
+    }
+
+    #[test]
+    fn test_outgoing_request_sends_only_user_id() {
+        let prebid_req = PrebidRequest {
+            synthetic_id: "synthetic-1".to_string(),
+            domain: "test.com".to_string(),
+            banner_sizes: vec![(728, 90)],
+            slots: Vec::new(),
+            first_party_data: FirstPartyData::default(),
+            client_ip: "192.168.1.1".to_string(),
+            origin: "https://test.com".to_string(),
+        };
+        let url = "https://ssp.example.com/openrtb2/auction";
+        let body = json!({ "id": "auction-1", "user": { "id": "scoped-1" } });
+        let req = prebid_req.outgoing_request(url, &body, false).unwrap();
+        assert_eq!(
+            req.get_header_str(HEADER_SYNTHETIC_TRUSTED_SERVER),
+            Some("scoped-1")
+        );
+        assert!(req
+            .get_header(crate::constants::HEADER_SYNTHETIC_FRESH)
+            .is_none());
+
+        let body = json!({ "id": "auction-1", "user": {} });
+        let req = prebid_req.outgoing_request(url, &body, false).unwrap();
+        assert!(req.get_header(HEADER_SYNTHETIC_TRUSTED_SERVER).is_none());
+    }
 }
@@ -0,0 +1,338 @@
+//! Cache of winning creatives for ad server handoff.
+//!
+//! The ad server only receives targeting keys for a winning bid, so the
+//! creative it renders has to fetch the markup separately. After an auction,
+//! [`CreativeCache::cache_bids`] stores the markup of each winning bid (banner
+//! HTML or VAST) and adds its cache ID to the bid's targeting as
+//! `hb_cache_id`, the key Prebid creatives and GAM video line items read.
+//! The markup is cached in the KV store named in `prebid_cache.kv_store`, or
+//! else on the Prebid Cache server at `prebid_cache.endpoint`, and served back
+//! by `GET /cache?uuid=` ([`handle_cache`]).
+
+use std::time::Duration;
+
+use error_stack::{Report, ResultExt};
+use fastly::http::{header, Method, StatusCode};
+use fastly::{Error, Request, Response};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::error::TrustedServerError;
+use crate::kv_store::JsonKvStore;
+use crate::prebid::WinningBid;
+use crate::settings::Settings;
+
+/// Targeting key carrying the cache ID of a bid's creative.
+pub const TARGETING_CACHE_ID: &str = "hb_cache_id";
+
+/// Targeting key Prebid video line items read the VAST cache ID from.
+const TARGETING_VIDEO_UUID: &str = "hb_uuid";
+
+/// Whether `markup` is a VAST document rather than banner HTML.
+fn is_vast(markup: &str) -> bool {
+    let markup = markup.trim_start();
+    markup.starts_with("<VAST") || (markup.starts_with("<?xml") && markup.contains("<VAST"))
+}
+
+/// Content type `markup` is served with.
+fn content_type(markup: &str) -> &'static str {
+    if is_vast(markup) {
+        "application/xml"
+    } else {
+        "text/html; charset=utf-8"
+    }
+}
+
+/// Response of a Prebid Cache server to a `puts` request.
+#[derive(Deserialize)]
+struct PutResponse {
+    responses: Vec<PutResult>,
+}
+
+#[derive(Deserialize)]
+struct PutResult {
+    uuid: String,
+}
+
+/// Where creatives are cached.
+enum Backend {
+    Kv(JsonKvStore),
+    Server { endpoint: String, backend: String },
+}
+
+/// Creative cache configured in `prebid_cache`.
+pub struct CreativeCache {
+    backend: Backend,
+    ttl: Duration,
+}
+
+impl CreativeCache {
+    /// Opens the KV store in `prebid_cache.kv_store`, or else the Prebid Cache
+    /// server at `prebid_cache.endpoint`.
+    ///
+    /// Returns [`None`] when creative caching is disabled.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the configured store cannot be opened
+    pub fn open(settings: &Settings) -> Result<Option<Self>, Report<TrustedServerError>> {
+        let config = &settings.prebid_cache;
+        let backend = if !config.kv_store.is_empty() {
+            Backend::Kv(JsonKvStore::open(&config.kv_store)?)
+        } else if !config.endpoint.is_empty() {
+            Backend::Server {
+                endpoint: config.endpoint.clone(),
+                backend: config.backend.clone(),
+            }
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            backend,
+            ttl: Duration::from_secs(config.ttl_seconds),
+        }))
+    }
+
+    fn key(uuid: &str) -> String {
+        format!("creative:{uuid}")
+    }
+
+    /// Caches `markup`, returning its cache ID.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the write to the KV store fails
+    /// - [`TrustedServerError::Prebid`] if the Prebid Cache server rejects the
+    ///   creative or cannot be reached
+    pub fn put(&self, markup: &str) -> Result<String, Report<TrustedServerError>> {
+        match &self.backend {
+            Backend::Kv(store) => {
+                let uuid = Uuid::new_v4().to_string();
+                store.put_text_with_ttl(&Self::key(&uuid), markup, self.ttl)?;
+                Ok(uuid)
+            }
+            Backend::Server { endpoint, backend } => {
+                let error = || TrustedServerError::Prebid {
+                    message: format!("Failed to cache creative on {endpoint}"),
+                };
+                let put_type = if is_vast(markup) { "xml" } else { "json" };
+                let mut resp = Request::new(Method::POST, endpoint)
+                    .with_body_json(&json!({
+                        "puts": [{
+                            "type": put_type,
+                            "value": markup,
+                            "ttlseconds": self.ttl.as_secs(),
+                        }]
+                    }))
+                    .change_context_lazy(error)?
+                    .send(backend.as_str())
+                    .change_context_lazy(error)?;
+                if !resp.get_status().is_success() {
+                    return Err(Report::new(error())
+                        .attach_printable(format!("Status {}", resp.get_status())));
+                }
+                let put: PutResponse =
+                    serde_json::from_slice(&resp.take_body_bytes()).change_context_lazy(error)?;
+                put.responses
+                    .into_iter()
+                    .next()
+                    .map(|result| result.uuid)
+                    .ok_or_else(|| Report::new(error()).attach_printable("No UUID returned"))
+            }
+        }
+    }
+
+    /// Caches the markup of every bid in `bids` that carries one and adds its
+    /// cache ID to the bid's targeting.
+    ///
+    /// Bids whose markup cannot be cached are logged and keep their targeting
+    /// unchanged, so the auction still returns them.
+    pub fn cache_bids(&self, bids: &mut [WinningBid]) {
+        for bid in bids.iter_mut() {
+            let Some(markup) = bid.adm.as_deref() else {
+                continue;
+            };
+            match self.put(markup) {
+                Ok(uuid) => {
+                    if is_vast(markup) {
+                        bid.targeting
+                            .insert(TARGETING_VIDEO_UUID.to_string(), uuid.clone());
+                    }
+                    bid.targeting.insert(TARGETING_CACHE_ID.to_string(), uuid);
+                }
+                Err(e) => log::warn!("Failed to cache creative of {}: {:?}", bid.imp_id, e),
+            }
+        }
+    }
+
+    /// Returns the markup cached under `uuid`, or [`None`] if it expired or
+    /// never existed.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the KV store lookup fails
+    /// - [`TrustedServerError::Prebid`] if the Prebid Cache server cannot be reached
+    pub fn get(&self, uuid: &str) -> Result<Option<String>, Report<TrustedServerError>> {
+        match &self.backend {
+            Backend::Kv(store) => store.get_text(&Self::key(uuid)),
+            Backend::Server { endpoint, backend } => {
+                let mut resp = Request::get(format!("{endpoint}?uuid={uuid}"))
+                    .send(backend.as_str())
+                    .change_context(TrustedServerError::Prebid {
+                        message: format!("Failed to look up creative on {endpoint}"),
+                    })?;
+                if !resp.get_status().is_success() {
+                    return Ok(None);
+                }
+                let body = resp.take_body_str();
+                // Creatives put with type "json" come back as a JSON string
+                match serde_json::from_str::<Value>(&body) {
+                    Ok(Value::String(markup)) => Ok(Some(markup)),
+                    _ => Ok(Some(body)),
+                }
+            }
+        }
+    }
+}
+
+/// Handles `GET /cache?uuid=`, serving a cached creative.
+///
+/// Responds `404` when creative caching is disabled or nothing is cached under
+/// the UUID, and `400` when the UUID is missing or malformed.
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if the cache cannot be read.
+pub fn handle_cache(settings: &Settings, req: Request) -> Result<Response, Error> {
+    if *req.get_method() != Method::GET {
+        return Ok(
+            Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_body("Method not allowed")
+        );
+    }
+    let Some(cache) = CreativeCache::open(settings).map_err(|e| Error::msg(format!("{e:?}")))?
+    else {
+        return Ok(Response::from_status(StatusCode::NOT_FOUND).with_body("Not Found"));
+    };
+    let Some(uuid) = req
+        .get_query_parameter("uuid")
+        .filter(|uuid| Uuid::parse_str(uuid).is_ok())
+    else {
+        return Ok(Response::from_status(StatusCode::BAD_REQUEST).with_body("Invalid uuid"));
+    };
+
+    match cache.get(uuid).map_err(|e| Error::msg(format!("{e:?}")))? {
+        Some(markup) => Ok(Response::from_status(StatusCode::OK)
+            .with_header(header::CONTENT_TYPE, content_type(&markup))
+            .with_header(header::CACHE_CONTROL, "no-store, private")
+            // Creatives are fetched from ad server iframes on other origins
+            .with_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+            .with_body(markup)),
+        None => Ok(Response::from_status(StatusCode::NOT_FOUND).with_body("Not Found")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::tests::create_test_settings;
+
+    const VAST: &str = r#"<?xml version="1.0"?><VAST version="4.0"><Ad id="1"/></VAST>"#;
+
+    fn cache_settings() -> Settings {
+        let mut settings = create_test_settings();
+        settings.prebid_cache.kv_store = "test_creative_store".to_string();
+        settings
+    }
+
+    fn winning_bid(imp_id: &str, adm: Option<&str>) -> WinningBid {
+        WinningBid {
+            imp_id: imp_id.to_string(),
+            bid_id: format!("{imp_id}-bid"),
+            bidder: "smartadserver".to_string(),
+            price: 1.5,
+            currency: "USD".to_string(),
+            adm: adm.map(str::to_string),
+            adomain: Vec::new(),
+            crid: None,
+            width: None,
+            height: None,
+            targeting: Default::default(),
+            nurl: None,
+            burl: None,
+            billing_id: None,
+        }
+    }
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(content_type(VAST), "application/xml");
+        assert_eq!(content_type("  <VAST version=\"3.0\"/>"), "application/xml");
+        assert_eq!(content_type("<div>ad</div>"), "text/html; charset=utf-8");
+        assert_eq!(
+            content_type("<?xml version=\"1.0\"?><svg/>"),
+            "text/html; charset=utf-8"
+        );
+    }
+
+    #[test]
+    fn test_cache_bids_and_retrieve() {
+        let settings = cache_settings();
+        let cache = CreativeCache::open(&settings).unwrap().unwrap();
+        let mut bids = [
+            winning_bid("banner", Some("<div>ad</div>")),
+            winning_bid("video", Some(VAST)),
+            winning_bid("no-markup", None),
+        ];
+        cache.cache_bids(&mut bids);
+
+        let banner_id = bids[0].targeting[TARGETING_CACHE_ID].clone();
+        assert!(!bids[0].targeting.contains_key(TARGETING_VIDEO_UUID));
+        assert_eq!(
+            bids[1].targeting[TARGETING_CACHE_ID],
+            bids[1].targeting[TARGETING_VIDEO_UUID]
+        );
+        assert!(bids[2].targeting.is_empty());
+
+        let req = Request::get(format!("https://example.com/cache?uuid={banner_id}"));
+        let mut resp = handle_cache(&settings, req).unwrap();
+        assert_eq!(resp.get_status(), StatusCode::OK);
+        assert_eq!(
+            resp.get_header_str(header::CONTENT_TYPE),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(resp.take_body_str(), "<div>ad</div>");
+
+        let video_id = &bids[1].targeting[TARGETING_CACHE_ID];
+        let req = Request::get(format!("https://example.com/cache?uuid={video_id}"));
+        assert_eq!(handle_cache(&settings, req).unwrap().take_body_str(), VAST);
+    }
+
+    #[test]
+    fn test_handle_cache_rejections() {
+        let settings = cache_settings();
+        let missing = Uuid::new_v4();
+        let cases = [
+            ("https://example.com/cache", StatusCode::BAD_REQUEST),
+            (
+                "https://example.com/cache?uuid=../etc",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                &format!("https://example.com/cache?uuid={missing}"),
+                StatusCode::NOT_FOUND,
+            ),
+        ];
+        for (url, status) in cases {
+            let resp = handle_cache(&settings, Request::get(url)).unwrap();
+            assert_eq!(resp.get_status(), status, "{url}");
+        }
+
+        let disabled = create_test_settings();
+        let req = Request::get(format!("https://example.com/cache?uuid={missing}"));
+        assert_eq!(
+            handle_cache(&disabled, req).unwrap().get_status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+}
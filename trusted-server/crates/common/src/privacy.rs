@@ -1,3 +1,219 @@
+//! Privacy regimes and the privacy policy page.
+//!
+//! Visitors are subject to different privacy laws depending on where they
+//! are. [`Regime`] is resolved from Fastly geolocation, and its
+//! [`RegimePolicy`] decides which consent frameworks are authoritative and
+//! whether personalized advertising needs an explicit opt-in. Child-directed
+//! traffic under COPPA is flagged by [`is_child_directed`]. Both feed into
+//! [`ConsentDecision`](crate::consent::ConsentDecision).
+
+use fastly::geo::geo_lookup;
+use fastly::Request;
+use serde::Serialize;
+
+use crate::additional_consent::{get_additional_consent_from_request, AdditionalConsent};
+use crate::constants::HEADER_X_COPPA;
+use crate::gdpr::is_gdpr_country;
+use crate::gpc::is_gpc_binding;
+use crate::gpp::{get_gpp_consent_from_request, section_ids, GppConsent};
+use crate::settings::Settings;
+use crate::tcf_consent::TcfConsent;
+use crate::us_privacy::{get_ccpa_consent_from_request, CcpaConsent};
+
+/// Privacy law governing a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Regime {
+    /// EU/EEA GDPR and the UK GDPR.
+    Gdpr,
+    /// California Consumer Privacy Act.
+    Ccpa,
+    /// Brazil's Lei Geral de Proteção de Dados.
+    Lgpd,
+    /// Canada's Personal Information Protection and Electronic Documents Act.
+    Pipeda,
+    /// No specific privacy regime.
+    None,
+}
+
+/// Consent frameworks a regime can treat as authoritative.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsentFramework {
+    /// IAB TCF v2 (`euconsent-v2`).
+    Tcf,
+    /// IAB US Privacy string (`us_privacy`).
+    UsPrivacy,
+    /// IAB Global Privacy Platform (`gpp`).
+    Gpp,
+    /// Global Privacy Control (`Sec-GPC`), where it is binding.
+    Gpc,
+}
+
+/// How a [`Regime`] treats consent signals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RegimePolicy {
+    /// Frameworks whose signals decide personalized advertising.
+    pub authoritative: &'static [ConsentFramework],
+    /// Whether personalized advertising requires an explicit opt-in; when
+    /// `false` it is allowed until the user opts out.
+    pub requires_opt_in: bool,
+}
+
+const OPT_IN_POLICY: RegimePolicy = RegimePolicy {
+    authoritative: &[
+        ConsentFramework::Tcf,
+        ConsentFramework::Gpp,
+        ConsentFramework::Gpc,
+    ],
+    requires_opt_in: true,
+};
+
+const CCPA_POLICY: RegimePolicy = RegimePolicy {
+    authoritative: &[
+        ConsentFramework::UsPrivacy,
+        ConsentFramework::Gpp,
+        ConsentFramework::Gpc,
+    ],
+    requires_opt_in: false,
+};
+
+const PIPEDA_POLICY: RegimePolicy = RegimePolicy {
+    authoritative: &[
+        ConsentFramework::Tcf,
+        ConsentFramework::Gpp,
+        ConsentFramework::Gpc,
+    ],
+    requires_opt_in: false,
+};
+
+const NO_REGIME_POLICY: RegimePolicy = RegimePolicy {
+    authoritative: &[
+        ConsentFramework::Tcf,
+        ConsentFramework::UsPrivacy,
+        ConsentFramework::Gpp,
+        ConsentFramework::Gpc,
+    ],
+    requires_opt_in: false,
+};
+
+impl Regime {
+    /// Resolves the regime for a country and optional ISO 3166-2 subdivision.
+    pub fn from_location(country_code: &str, region: Option<&str>) -> Self {
+        match country_code.to_ascii_uppercase().as_str() {
+            country if is_gdpr_country(country) => Self::Gdpr,
+            "US" if region.is_some_and(|r| r.eq_ignore_ascii_case("CA")) => Self::Ccpa,
+            "BR" => Self::Lgpd,
+            "CA" => Self::Pipeda,
+            _ => Self::None,
+        }
+    }
+
+    /// Resolves the regime from the client's Fastly geolocation.
+    ///
+    /// Clients that cannot be located are treated as [`Regime::Gdpr`], the
+    /// strictest regime.
+    pub fn for_request(req: &Request) -> Self {
+        match req.get_client_ip_addr().and_then(geo_lookup) {
+            Some(geo) => Self::from_location(geo.country_code(), geo.region()),
+            None => Self::Gdpr,
+        }
+    }
+
+    /// Policy table entry for the regime.
+    pub fn policy(self) -> RegimePolicy {
+        match self {
+            Self::Gdpr | Self::Lgpd => OPT_IN_POLICY,
+            Self::Ccpa => CCPA_POLICY,
+            Self::Pipeda => PIPEDA_POLICY,
+            Self::None => NO_REGIME_POLICY,
+        }
+    }
+}
+
+/// Consent signals carried by a request.
+#[derive(Debug, Clone, Default)]
+pub struct ConsentSignals {
+    pub tcf: TcfConsent,
+    pub us_privacy: Option<CcpaConsent>,
+    pub gpp: Option<GppConsent>,
+    /// Google Additional Consent for providers outside the TCF.
+    pub additional_consent: Option<AdditionalConsent>,
+    /// Whether the request carries a binding Global Privacy Control opt-out.
+    pub gpc: bool,
+}
+
+impl ConsentSignals {
+    /// Collects the US Privacy, GPP, Additional Consent and Global Privacy
+    /// Control signals of a request alongside its TCF consent.
+    pub fn from_request(settings: &Settings, req: &Request, tcf: TcfConsent) -> Self {
+        Self {
+            tcf,
+            us_privacy: get_ccpa_consent_from_request(req),
+            gpp: get_gpp_consent_from_request(req),
+            additional_consent: get_additional_consent_from_request(req),
+            gpc: is_gpc_binding(settings, req),
+        }
+    }
+
+    /// Advertising decision of a single framework, or [`None`] if it sent no
+    /// signal that can grant consent under an opt-in regime.
+    fn decision(&self, framework: ConsentFramework, requires_opt_in: bool) -> Option<bool> {
+        match framework {
+            ConsentFramework::Tcf => {
+                (!self.tcf.tc_string.is_empty()).then(|| self.tcf.purpose_consents.contains(2))
+            }
+            ConsentFramework::UsPrivacy => match &self.us_privacy {
+                Some(ccpa) if ccpa.is_opted_out() => Some(false),
+                // US Privacy only signals opt-outs, so it never grants an opt-in
+                Some(_) if !requires_opt_in => Some(true),
+                _ => None,
+            },
+            ConsentFramework::Gpp => self.gpp.as_ref().and_then(|gpp| {
+                let allowed = gpp.allows_personalized_advertising();
+                if !allowed {
+                    Some(false)
+                } else if !requires_opt_in || gpp.applies(section_ids::TCF_EU_V2) {
+                    Some(true)
+                } else {
+                    None
+                }
+            }),
+            // GPC only signals opt-outs
+            ConsentFramework::Gpc => self.gpc.then_some(false),
+        }
+    }
+}
+
+impl RegimePolicy {
+    /// Decides whether personalized advertising is allowed.
+    ///
+    /// A denial from any authoritative framework wins; otherwise a grant from
+    /// one of them allows it, and without any signal the regime default applies.
+    pub fn allows_personalized_advertising(&self, signals: &ConsentSignals) -> bool {
+        let decisions: Vec<bool> = self
+            .authoritative
+            .iter()
+            .filter_map(|framework| signals.decision(*framework, self.requires_opt_in))
+            .collect();
+        if decisions.contains(&false) {
+            return false;
+        }
+        decisions.contains(&true) || !self.requires_opt_in
+    }
+}
+
+/// Returns `true` if the request is child-directed under COPPA.
+///
+/// Applies to all traffic when `publisher.coppa` is set; otherwise the
+/// publisher can flag individual requests with an `X-Coppa: 1` header.
+pub fn is_child_directed(settings: &Settings, req: &Request) -> bool {
+    settings.publisher.coppa
+        || req
+            .get_header_str(HEADER_X_COPPA)
+            .is_some_and(|value| value.trim() == "1")
+}
+
 pub const PRIVACY_TEMPLATE: &str = r#"<!DOCTYPE html>
 <html lang="en">
 <head>
@@ -139,3 +355,127 @@ pub const PRIVACY_TEMPLATE: &str = r#"<!DOCTYPE html>
     </div>
 </body>
 </html>"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcf_with_advertising(granted: bool) -> TcfConsent {
+        TcfConsent {
+            tc_string: "CQ".to_string(),
+            gdpr_applies: true,
+            purpose_consents: [2].into_iter().filter(|_| granted).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn us_privacy(value: &str) -> Option<CcpaConsent> {
+        Some(CcpaConsent::parse(value).unwrap())
+    }
+
+    #[test]
+    fn test_regime_from_location() {
+        assert_eq!(Regime::from_location("FR", None), Regime::Gdpr);
+        assert_eq!(Regime::from_location("gb", None), Regime::Gdpr);
+        assert_eq!(Regime::from_location("US", Some("CA")), Regime::Ccpa);
+        assert_eq!(Regime::from_location("US", Some("NY")), Regime::None);
+        assert_eq!(Regime::from_location("BR", None), Regime::Lgpd);
+        assert_eq!(Regime::from_location("CA", None), Regime::Pipeda);
+        assert_eq!(Regime::from_location("JP", None), Regime::None);
+    }
+
+    #[test]
+    fn test_regime_for_request_without_location() {
+        let req = Request::get("https://example.com");
+        assert_eq!(Regime::for_request(&req), Regime::Gdpr);
+    }
+
+    #[test]
+    fn test_opt_in_regime() {
+        let policy = Regime::Gdpr.policy();
+        assert!(!policy.allows_personalized_advertising(&ConsentSignals::default()));
+
+        let signals = ConsentSignals {
+            tcf: tcf_with_advertising(true),
+            ..Default::default()
+        };
+        assert!(policy.allows_personalized_advertising(&signals));
+
+        let signals = ConsentSignals {
+            tcf: tcf_with_advertising(false),
+            ..Default::default()
+        };
+        assert!(!policy.allows_personalized_advertising(&signals));
+
+        // US Privacy is not authoritative under the GDPR and cannot grant an opt-in
+        let signals = ConsentSignals {
+            us_privacy: us_privacy("1YNN"),
+            ..Default::default()
+        };
+        assert!(!policy.allows_personalized_advertising(&signals));
+    }
+
+    #[test]
+    fn test_opt_out_regime() {
+        let policy = Regime::Ccpa.policy();
+        assert!(policy.allows_personalized_advertising(&ConsentSignals::default()));
+
+        let signals = ConsentSignals {
+            us_privacy: us_privacy("1YYN"),
+            ..Default::default()
+        };
+        assert!(!policy.allows_personalized_advertising(&signals));
+
+        // The TCF is not authoritative under the CCPA
+        let signals = ConsentSignals {
+            tcf: tcf_with_advertising(false),
+            us_privacy: us_privacy("1YNN"),
+            ..Default::default()
+        };
+        assert!(policy.allows_personalized_advertising(&signals));
+    }
+
+    #[test]
+    fn test_no_regime_honors_any_opt_out() {
+        let policy = Regime::None.policy();
+        assert!(policy.allows_personalized_advertising(&ConsentSignals::default()));
+
+        let signals = ConsentSignals {
+            tcf: tcf_with_advertising(false),
+            ..Default::default()
+        };
+        assert!(!policy.allows_personalized_advertising(&signals));
+    }
+
+    #[test]
+    fn test_gpc_denies_under_every_regime() {
+        let signals = ConsentSignals {
+            tcf: tcf_with_advertising(true),
+            us_privacy: us_privacy("1YNN"),
+            gpc: true,
+            ..Default::default()
+        };
+        for regime in [
+            Regime::Gdpr,
+            Regime::Ccpa,
+            Regime::Lgpd,
+            Regime::Pipeda,
+            Regime::None,
+        ] {
+            assert!(!regime.policy().allows_personalized_advertising(&signals));
+        }
+    }
+
+    #[test]
+    fn test_is_child_directed() {
+        let mut settings = crate::test_support::tests::create_test_settings();
+        let req = Request::get("https://example.com");
+        assert!(!is_child_directed(&settings, &req));
+
+        let flagged = Request::get("https://example.com").with_header(HEADER_X_COPPA, "1");
+        assert!(is_child_directed(&settings, &flagged));
+
+        settings.publisher.coppa = true;
+        assert!(is_child_directed(&settings, &req));
+    }
+}
@@ -0,0 +1,369 @@
+//! Records of processing activities (GDPR Article 30).
+//!
+//! [`processing_activities`] describes what the server collects, why, where
+//! it is kept, for how long and which partners receive it. It is generated
+//! from [`Settings`] on every request, so it stays accurate as stores,
+//! template fields and partners are reconfigured. `/gdpr/processing-activities`
+//! serves it to operators authenticated by
+//! [`crate::auth::authenticate`].
+
+use fastly::http::{header, Method, StatusCode};
+use fastly::{Error, Request, Response};
+use serde_json::{json, Value};
+
+use crate::auth::authenticate;
+use crate::retention::{retention_period, SECONDS_PER_DAY};
+use crate::settings::Settings;
+use crate::synthetic::TEMPLATE_FIELDS;
+use crate::tcf_consent::purpose_ids;
+
+/// Retention of `store` in days, or `null` if its entries are kept forever.
+fn retention_days(settings: &Settings, store: &str) -> Value {
+    json!(retention_period(settings, store).map(|period| period.as_secs() / SECONDS_PER_DAY))
+}
+
+/// A processing activity that only exists when its KV store is configured.
+fn store_activity(store: &str, activity: Value) -> Option<Value> {
+    (!store.is_empty()).then_some(activity)
+}
+
+/// Describes the processing activities enabled by `settings`.
+///
+/// Template fields are listed as inputs of the synthetic ID only when the
+/// template uses them and `synthetic.exclude_fields` does not withhold them.
+/// Retention is reported for stores whose entries expire; `null` means entries
+/// are kept until erased.
+pub fn processing_activities(settings: &Settings) -> Value {
+    let synthetic = &settings.synthetic;
+    let inputs: Vec<&str> = TEMPLATE_FIELDS
+        .iter()
+        .copied()
+        .filter(|field| synthetic.template.contains(field))
+        .filter(|field| !synthetic.exclude_fields.iter().any(|f| f == field))
+        .collect();
+
+    let mut activities = vec![
+        json!({
+            "name": "synthetic_id",
+            "description": "Pseudonymous ID derived with HMAC-SHA256 from request signals",
+            "data": inputs,
+            "client_ip_prefix_len": {
+                "ipv4": synthetic.ipv4_prefix_len,
+                "ipv6": synthetic.ipv6_prefix_len,
+            },
+            "purposes": purpose_ids::DEVICE_ACCESS,
+            "store": "cookie",
+            "retention_days": synthetic.max_age_days,
+        }),
+        json!({
+            "name": "visit_count",
+            "description": "Number of visits per synthetic ID",
+            "data": ["visit_count"],
+            "purposes": purpose_ids::ANALYTICS,
+            "store": synthetic.counter_store,
+            "retention_days": retention_days(settings, &synthetic.counter_store),
+        }),
+        json!({
+            "name": "opid",
+            "description": "Ad server ID returned by the ad partner per synthetic ID",
+            "data": ["opid"],
+            "purposes": purpose_ids::ADVERTISING,
+            "store": synthetic.opid_store,
+            "retention_days": retention_days(settings, &synthetic.opid_store),
+        }),
+    ];
+    activities.extend(
+        [
+            store_activity(
+                &settings.gdpr.receipt_store,
+                json!({
+                    "name": "consent_receipts",
+                    "description": "Record of each consent choice, kept as proof of consent",
+                    "data": ["purposes", "tc_string_hash", "policy_version", "timestamp"],
+                    "purposes": [],
+                    "store": settings.gdpr.receipt_store,
+                    "retention_days": null,
+                }),
+            ),
+            store_activity(
+                &settings.gdpr.consent_history_store,
+                json!({
+                    "name": "consent_history",
+                    "description": "Consent categories and decoded TCF signals after each choice",
+                    "data": ["consent", "tcf"],
+                    "purposes": [],
+                    "store": settings.gdpr.consent_history_store,
+                    "retention_days": null,
+                }),
+            ),
+            store_activity(
+                &settings.gdpr.objection_store,
+                json!({
+                    "name": "objections",
+                    "description": "Synthetic IDs whose users objected to processing",
+                    "data": ["objected_at"],
+                    "purposes": [],
+                    "store": settings.gdpr.objection_store,
+                    "retention_days": null,
+                }),
+            ),
+            store_activity(
+                &settings.compliance_log.endpoint,
+                json!({
+                    "name": "compliance_log",
+                    "description": "Record of each access to subject data, kept for breach forensics",
+                    "data": ["synthetic_id", "actor", "endpoint", "timestamp"],
+                    "purposes": [],
+                    "store": settings.compliance_log.endpoint,
+                    "retention_days": null,
+                }),
+            ),
+            store_activity(
+                &settings.identity.link_store,
+                json!({
+                    "name": "identity_links",
+                    "description": "Synthetic IDs linked through a hashed email",
+                    "data": ["hashed_email", "synthetic_id"],
+                    "purposes": purpose_ids::ADVERTISING,
+                    "store": settings.identity.link_store,
+                    "retention_days": null,
+                }),
+            ),
+            store_activity(
+                &settings.user_sync.uid_store,
+                json!({
+                    "name": "partner_uids",
+                    "description": "Bidder user IDs synced through /setuid",
+                    "data": ["bidder", "uid"],
+                    "purposes": purpose_ids::ADVERTISING,
+                    "store": settings.user_sync.uid_store,
+                    "retention_days": null,
+                }),
+            ),
+            store_activity(
+                &settings.permutive.kv_store,
+                json!({
+                    "name": "permutive_segments",
+                    "description": "Permutive audience segments of a synthetic ID",
+                    "data": ["segments"],
+                    "purposes": purpose_ids::ADVERTISING,
+                    "store": settings.permutive.kv_store,
+                    "retention_days": settings.permutive.cache_ttl_secs.div_ceil(SECONDS_PER_DAY),
+                }),
+            ),
+            store_activity(
+                &settings.uid2.token_store,
+                json!({
+                    "name": "uid2_tokens",
+                    "description": "UID2 tokens generated from a hashed email",
+                    "data": ["advertising_token", "refresh_token"],
+                    "purposes": purpose_ids::ADVERTISING,
+                    "store": settings.uid2.token_store,
+                    "retention_days": null,
+                }),
+            ),
+        ]
+        .into_iter()
+        .flatten(),
+    );
+
+    let mut partners = vec![
+        json!({
+            "name": "ad_server",
+            "url": settings.ad_server.ad_partner_url,
+            "vendor_id": settings.ad_server.vendor_id,
+            "receives": ["synthetic_id"],
+        }),
+        json!({
+            "name": "gam",
+            "url": settings.gam.server_url,
+            "vendor_id": settings.gam.vendor_id,
+            "receives": ["synthetic_id"],
+        }),
+    ];
+    let mut bidders: Vec<_> = settings.prebid.bidders.iter().collect();
+    bidders.sort();
+    partners.extend(bidders.into_iter().map(|(name, vendor_id)| {
+        json!({
+            "name": name,
+            "url": settings.prebid.server_url,
+            "vendor_id": vendor_id,
+            "receives": ["synthetic_id", "eids"],
+        })
+    }));
+    if settings.identity.id5.partner_id != 0 {
+        partners.push(json!({
+            "name": "id5",
+            "url": settings.identity.id5.api_url,
+            "receives": ["hashed_email"],
+        }));
+    }
+    if !settings.identity.liveramp.placement_id.is_empty() {
+        partners.push(json!({
+            "name": "liveramp",
+            "url": settings.identity.liveramp.api_url,
+            "receives": ["hashed_email"],
+        }));
+    }
+    if !settings.permutive.api_url.is_empty() {
+        partners.push(json!({
+            "name": "permutive",
+            "url": settings.permutive.api_url,
+            "vendor_id": settings.permutive.vendor_id,
+            "receives": ["synthetic_id"],
+        }));
+    }
+    if !settings.lotame.api_url.is_empty() {
+        partners.push(json!({
+            "name": "lotame",
+            "url": settings.lotame.api_url,
+            "vendor_id": settings.lotame.vendor_id,
+            "receives": ["synthetic_id"],
+        }));
+    }
+    if !settings.neustar.api_url.is_empty() {
+        partners.push(json!({
+            "name": "neustar",
+            "url": settings.neustar.api_url,
+            "vendor_id": settings.neustar.vendor_id,
+            "receives": ["synthetic_id"],
+        }));
+    }
+    partners.extend(settings.data_providers.iter().map(|provider| {
+        json!({
+            "name": provider.name,
+            "url": provider.url,
+            "vendor_id": provider.vendor_id,
+            "receives": ["synthetic_id"],
+        })
+    }));
+    if !settings.uid2.operator_url.is_empty() {
+        partners.push(json!({
+            "name": "uid2",
+            "url": settings.uid2.operator_url,
+            "receives": ["hashed_email"],
+        }));
+    }
+    partners.extend(settings.gdpr.erasure_endpoints.iter().map(|endpoint| {
+        json!({
+            "name": endpoint.name,
+            "url": endpoint.url,
+            "receives": ["synthetic_id", "opid"],
+        })
+    }));
+    if !settings.consent_webhook.url.is_empty() {
+        partners.push(json!({
+            "name": "consent_webhook",
+            "url": settings.consent_webhook.url,
+            "receives": ["synthetic_id", "purposes"],
+        }));
+    }
+
+    json!({
+        "controller": settings.publisher.domain,
+        "generated_at": chrono::Utc::now().to_rfc3339(),
+        "activities": activities,
+        "partners": partners,
+    })
+}
+
+/// Handles `GET /gdpr/processing-activities`.
+///
+/// Requires an operator credential checked by [`authenticate`]; the endpoint
+/// is disabled while none is configured.
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if response creation fails.
+pub fn handle_processing_activities(settings: &Settings, req: Request) -> Result<Response, Error> {
+    if *req.get_method() != Method::GET {
+        return Ok(
+            Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_body("Method not allowed")
+        );
+    }
+    if let Err(e) = authenticate(settings, &req) {
+        return Ok(e.into_response());
+    }
+
+    Ok(Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_header(header::CACHE_CONTROL, "no-store, private")
+        .with_body_json(&processing_activities(settings))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::{ErasureEndpoint, ErasureMethod};
+    use crate::test_support::tests::create_test_settings;
+
+    fn activity<'a>(body: &'a Value, name: &str) -> Option<&'a Value> {
+        body["activities"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|a| a["name"] == name)
+    }
+
+    #[test]
+    fn test_processing_activities_follow_settings() {
+        let mut settings = create_test_settings();
+        settings.synthetic.exclude_fields = vec!["user_agent".to_string()];
+        settings
+            .retention
+            .stores
+            .insert(settings.synthetic.opid_store.clone(), 90);
+        settings.gdpr.receipt_store = "test_receipt_store".to_string();
+        settings.gdpr.erasure_endpoints = vec![ErasureEndpoint {
+            name: "partner".to_string(),
+            url: "https://partner.example/erase".to_string(),
+            backend: "partner".to_string(),
+            method: ErasureMethod::Post,
+            bidder: None,
+            auth_token: String::new(),
+            max_attempts: 3,
+        }];
+
+        let body = processing_activities(&settings);
+        assert_eq!(body["controller"], "test-publisher.com");
+
+        let inputs = &activity(&body, "synthetic_id").unwrap()["data"];
+        assert!(inputs.as_array().unwrap().contains(&json!("client_ip")));
+        assert!(!inputs.as_array().unwrap().contains(&json!("user_agent")));
+        assert_eq!(
+            activity(&body, "visit_count").unwrap()["retention_days"],
+            395
+        );
+        assert_eq!(activity(&body, "opid").unwrap()["retention_days"], 90);
+        assert!(activity(&body, "consent_receipts").is_some());
+        assert!(activity(&body, "partner_uids").is_none());
+
+        let partners: Vec<&Value> = body["partners"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| &p["name"])
+            .collect();
+        assert_eq!(partners, ["ad_server", "gam", "smartadserver", "partner"]);
+    }
+
+    #[test]
+    fn test_handle_processing_activities_requires_token() {
+        let mut settings = create_test_settings();
+        let req = Request::get("https://example.com/gdpr/processing-activities");
+        let resp = handle_processing_activities(&settings, req).unwrap();
+        assert_eq!(resp.get_status(), StatusCode::NOT_FOUND);
+
+        settings.debug.auth_token = "debug-token".to_string();
+        let req = Request::get("https://example.com/gdpr/processing-activities");
+        let resp = handle_processing_activities(&settings, req).unwrap();
+        assert_eq!(resp.get_status(), StatusCode::UNAUTHORIZED);
+
+        let req = Request::get("https://example.com/gdpr/processing-activities")
+            .with_header(header::AUTHORIZATION, "Bearer debug-token");
+        let resp = handle_processing_activities(&settings, req).unwrap();
+        assert_eq!(resp.get_status(), StatusCode::OK);
+        let body: Value = serde_json::from_str(&resp.into_body_str()).unwrap();
+        assert!(activity(&body, "synthetic_id").is_some());
+    }
+}
@@ -0,0 +1,130 @@
+//! Right to rectification for data subject requests.
+//!
+//! `PATCH /gdpr/data` lets a subject correct the data linked to their
+//! synthetic ID: a [`Rectification`] names identity links that are wrong, such
+//! as a shared device's `pub_userid`, and whether the stored opid should be
+//! cleared. [`rectify_user_data`] applies it and reports what actually changed.
+
+use error_stack::Report;
+use serde::{Deserialize, Serialize};
+
+use crate::error::TrustedServerError;
+use crate::identity::{IdentityLinks, IdentityStore};
+use crate::kv_store::JsonKvStore;
+use crate::settings::Settings;
+
+/// Body of a `PATCH /gdpr/data` request.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rectification {
+    /// Identity links to remove from the synthetic ID.
+    #[serde(default)]
+    pub unlink: IdentityLinks,
+    /// Whether to delete the ad server opid stored for the synthetic ID.
+    #[serde(default)]
+    pub clear_opid: bool,
+}
+
+/// Summary of a rectification, returned to the data subject.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RectificationSummary {
+    /// Synthetic ID whose data was corrected.
+    pub synthetic_id: String,
+    /// Identity links that were removed; links that did not exist are omitted.
+    pub unlinked: IdentityLinks,
+    /// Whether a stored opid was deleted.
+    pub opid_cleared: bool,
+}
+
+/// Applies `rectification` to the data held for `synthetic_id`.
+///
+/// Identity links are removed from `identity.link_store` when linking is
+/// enabled; the opid is deleted from `synthetic.opid_store`.
+///
+/// # Errors
+///
+/// - [`TrustedServerError::KvStore`] if a store cannot be opened, read or written
+pub fn rectify_user_data(
+    settings: &Settings,
+    synthetic_id: &str,
+    rectification: &Rectification,
+) -> Result<RectificationSummary, Report<TrustedServerError>> {
+    let mut unlinked = IdentityLinks::default();
+    if let Some(store) = IdentityStore::open(settings)? {
+        for key in rectification.unlink.keys() {
+            if store.unlink(synthetic_id, &key)? {
+                unlinked.add(&key);
+            }
+        }
+    }
+    if !unlinked.pub_user_ids.is_empty() || !unlinked.hashed_emails.is_empty() {
+        unlinked.updated_at = chrono::Utc::now().timestamp();
+    }
+
+    let mut opid_cleared = false;
+    if rectification.clear_opid {
+        let opids = JsonKvStore::open(&settings.synthetic.opid_store)?;
+        opid_cleared = opids.get_text(synthetic_id)?.is_some();
+        opids.delete(synthetic_id)?;
+    }
+    log::info!("Rectified data for synthetic ID: {}", synthetic_id);
+
+    Ok(RectificationSummary {
+        synthetic_id: synthetic_id.to_string(),
+        unlinked,
+        opid_cleared,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::IdentityKey;
+    use crate::synthetic::SyntheticId;
+    use crate::test_support::tests::create_test_settings;
+
+    #[test]
+    fn test_rectification_rejects_unknown_fields() {
+        let rectification: Rectification =
+            serde_json::from_str(r#"{"unlink": {"pub_user_ids": ["user"]}}"#)
+                .expect("should parse JSON");
+        assert_eq!(rectification.unlink.pub_user_ids, vec!["user"]);
+        assert!(!rectification.clear_opid);
+        assert!(serde_json::from_str::<Rectification>(r#"{"visit_count": 0}"#).is_err());
+    }
+
+    #[test]
+    fn test_rectify_user_data() {
+        let mut settings = create_test_settings();
+        settings.identity.link_store = "test_identity_store".to_string();
+        let store = IdentityStore::open(&settings)
+            .expect("should open the identity store")
+            .expect("should have an identity store configured");
+        let key = IdentityKey::PublisherUserId("wrong-user".to_string());
+        store
+            .link(&SyntheticId::new("rectified-synthetic".to_string()), &key)
+            .expect("should write to the store");
+        JsonKvStore::open(&settings.synthetic.opid_store)
+            .expect("should open the KV store")
+            .put_text("rectified-synthetic", "opid-789")
+            .expect("should write to the store");
+
+        let rectification = Rectification {
+            unlink: IdentityLinks {
+                pub_user_ids: vec!["wrong-user".to_string(), "unknown-user".to_string()],
+                ..Default::default()
+            },
+            clear_opid: true,
+        };
+        let summary = rectify_user_data(&settings, "rectified-synthetic", &rectification)
+            .expect("should rectify the user data");
+        assert_eq!(summary.unlinked.pub_user_ids, vec!["wrong-user"]);
+        assert!(summary.opid_cleared);
+        assert_eq!(store.lookup(&key).expect("should read the store"), None);
+
+        let summary = rectify_user_data(&settings, "rectified-synthetic", &rectification)
+            .expect("should rectify the user data");
+        assert!(summary.unlinked.pub_user_ids.is_empty());
+        assert!(!summary.opid_cleared);
+    }
+}
@@ -0,0 +1,323 @@
+//! Validation of outgoing OpenRTB bid requests.
+//!
+//! A bidder rejecting a bid request rarely says why. In debug mode (bid
+//! requests sent with `debug: 1`, see `prebid.debug`), every bid request is
+//! checked by [`validate_bid_request`] before it is sent: required fields,
+//! sane impression sizes and the coherence of the privacy fields with each
+//! other. Violations are reported with the path of the offending field, such
+//! as `imp[1].banner.format[0].w`, and the request is not sent.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use error_stack::Report;
+use serde_json::Value;
+
+use crate::error::TrustedServerError;
+
+/// Largest creative width or height accepted, in pixels.
+const MAX_CREATIVE_DIMENSION: u64 = 4096;
+
+/// A field of a bid request breaking a rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// Path of the field, e.g. `imp[0].banner.format`
+    pub path: String,
+    /// Rule the field breaks
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Collected violations of one bid request.
+#[derive(Default)]
+struct Violations(Vec<Violation>);
+
+impl Violations {
+    fn add(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.0.push(Violation {
+            path: path.into(),
+            message: message.into(),
+        });
+    }
+
+    /// Adds a violation unless `value` is a non-empty string.
+    fn require_string(&mut self, value: &Value, path: &str) {
+        if value.as_str().is_none_or(str::is_empty) {
+            self.add(path, "must be a non-empty string");
+        }
+    }
+
+    /// Adds a violation unless `value` is absent, 0 or 1.
+    fn require_flag(&mut self, value: &Value, path: &str) {
+        if !value.is_null() && !matches!(value.as_u64(), Some(0 | 1)) {
+            self.add(path, "must be 0 or 1");
+        }
+    }
+
+    /// Adds a violation unless `value` is a creative dimension in pixels.
+    fn require_dimension(&mut self, value: &Value, path: &str) {
+        if !value
+            .as_u64()
+            .is_some_and(|d| (1..=MAX_CREATIVE_DIMENSION).contains(&d))
+        {
+            self.add(
+                path,
+                format!("must be an integer from 1 to {MAX_CREATIVE_DIMENSION}"),
+            );
+        }
+    }
+}
+
+/// Checks the impressions of a bid request.
+fn check_imps(imps: &Value, violations: &mut Violations) {
+    let Some(imps) = imps.as_array().filter(|imps| !imps.is_empty()) else {
+        violations.add("imp", "must be a non-empty array");
+        return;
+    };
+    let mut ids = HashSet::new();
+    for (i, imp) in imps.iter().enumerate() {
+        let path = format!("imp[{i}]");
+        violations.require_string(&imp["id"], &format!("{path}.id"));
+        if let Some(id) = imp["id"].as_str() {
+            if !ids.insert(id) {
+                violations.add(format!("{path}.id"), format!("duplicates impression {id}"));
+            }
+        }
+        let media = ["banner", "video", "native"]
+            .into_iter()
+            .filter(|media| imp[media].is_object())
+            .count();
+        if media != 1 {
+            violations.add(
+                path.clone(),
+                "must have exactly one of banner, video and native",
+            );
+        }
+        let formats = &imp["banner"]["format"];
+        if imp["banner"].is_object() {
+            match formats.as_array().filter(|formats| !formats.is_empty()) {
+                Some(formats) => {
+                    for (j, format) in formats.iter().enumerate() {
+                        let path = format!("{path}.banner.format[{j}]");
+                        violations.require_dimension(&format["w"], &format!("{path}.w"));
+                        violations.require_dimension(&format["h"], &format!("{path}.h"));
+                    }
+                }
+                None => violations.add(format!("{path}.banner.format"), "must not be empty"),
+            }
+        }
+        let video = &imp["video"];
+        if video.is_object() {
+            if video["mimes"].as_array().is_none_or(Vec::is_empty) {
+                violations.add(format!("{path}.video.mimes"), "must not be empty");
+            }
+            violations.require_dimension(&video["w"], &format!("{path}.video.w"));
+            violations.require_dimension(&video["h"], &format!("{path}.video.h"));
+        }
+        if !imp["bidfloor"].as_f64().is_none_or(|floor| floor >= 0.0) {
+            violations.add(format!("{path}.bidfloor"), "must not be negative");
+        }
+        violations.require_flag(&imp["instl"], &format!("{path}.instl"));
+        violations.require_flag(&imp["rwdd"], &format!("{path}.rwdd"));
+        if imp["rwdd"] == 1 && imp["instl"] != 1 {
+            violations.add(format!("{path}.rwdd"), "requires instl");
+        }
+        if imp["ext"]["prebid"]["bidder"]
+            .as_object()
+            .is_none_or(|bidders| bidders.is_empty())
+        {
+            violations.add(format!("{path}.ext.prebid.bidder"), "must name a bidder");
+        }
+    }
+}
+
+/// Checks that the privacy fields of a bid request agree with each other.
+fn check_regs(body: &Value, violations: &mut Violations) {
+    let regs = &body["regs"];
+    if !regs.is_object() {
+        violations.add("regs", "must be an object");
+        return;
+    }
+    violations.require_flag(&regs["gdpr"], "regs.gdpr");
+    violations.require_flag(&regs["coppa"], "regs.coppa");
+    if !regs["ext"]["gdpr"].is_null() && regs["ext"]["gdpr"] != regs["gdpr"] {
+        violations.add("regs.ext.gdpr", "must equal regs.gdpr");
+    }
+    if !regs["ext"]["us_privacy"].is_null() && regs["ext"]["us_privacy"] != regs["us_privacy"] {
+        violations.add("regs.ext.us_privacy", "must equal regs.us_privacy");
+    }
+    if !regs["gpp_sid"].is_null() && regs["gpp"].as_str().is_none_or(str::is_empty) {
+        violations.add("regs.gpp_sid", "requires regs.gpp");
+    }
+
+    let user = &body["user"];
+    let has_eids = user["ext"]["eids"]
+        .as_array()
+        .is_some_and(|eids| !eids.is_empty());
+    let has_consent = user["ext"]["consent"]
+        .as_str()
+        .is_some_and(|consent| !consent.is_empty());
+    if regs["gdpr"] == 1 && has_eids && !has_consent {
+        violations.add(
+            "user.ext.eids",
+            "requires user.ext.consent where GDPR applies",
+        );
+    }
+    if regs["coppa"] == 1 {
+        if has_eids {
+            violations.add("user.ext.eids", "must be empty for child-directed requests");
+        }
+        if !user["id"].is_null() {
+            violations.add("user.id", "must be absent for child-directed requests");
+        }
+    }
+}
+
+/// Checks the OpenRTB bid request `body` before it is sent.
+///
+/// # Errors
+///
+/// - [`TrustedServerError::Prebid`] listing every violation with its field path
+pub fn validate_bid_request(body: &Value) -> Result<(), Report<TrustedServerError>> {
+    let mut violations = Violations::default();
+    violations.require_string(&body["id"], "id");
+    check_imps(&body["imp"], &mut violations);
+    violations.require_string(&body["site"]["page"], "site.page");
+    if matches!(body["tmax"].as_u64(), None | Some(0)) {
+        violations.add("tmax", "must be a positive integer");
+    }
+    check_regs(body, &mut violations);
+
+    if violations.0.is_empty() {
+        return Ok(());
+    }
+    let list = violations
+        .0
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    Err(Report::new(TrustedServerError::Prebid {
+        message: format!("Invalid bid request {}: {}", body["id"], list.join("; ")),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn valid_request() -> Value {
+        json!({
+            "id": "auction-1",
+            "imp": [
+                {
+                    "id": "top",
+                    "banner": { "format": [{ "w": 728, "h": 90 }] },
+                    "bidfloor": 0.01,
+                    "ext": { "prebid": { "bidder": { "smartadserver": {} } } }
+                },
+                {
+                    "id": "bonus",
+                    "video": { "mimes": ["video/mp4"], "w": 640, "h": 480 },
+                    "instl": 1,
+                    "rwdd": 1,
+                    "ext": { "prebid": { "bidder": { "smartadserver": {} } } }
+                }
+            ],
+            "site": { "page": "https://test.com" },
+            "user": { "ext": { "consent": "CPXxRfAPXxRfAAfKABENB-CgAAAAAAAAAAYgAAAAAAAA", "eids": [{}] } },
+            "tmax": 950,
+            "regs": { "gdpr": 1, "ext": { "gdpr": 1 } }
+        })
+    }
+
+    fn violations(body: &Value) -> String {
+        let err = validate_bid_request(body).unwrap_err();
+        match err.current_context() {
+            TrustedServerError::Prebid { message } => message.clone(),
+            other => panic!("unexpected error {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_valid_request() {
+        assert!(validate_bid_request(&valid_request()).is_ok());
+    }
+
+    #[test]
+    fn test_imp_violations() {
+        let mut body = valid_request();
+        body["imp"][0]["banner"]["format"][0]["w"] = json!(0);
+        body["imp"][1]["id"] = json!("top");
+        body["imp"][1]["instl"] = json!(0);
+        body["imp"][1]["banner"] = json!({ "format": [] });
+        let message = violations(&body);
+        for expected in [
+            "imp[0].banner.format[0].w: must be an integer from 1 to 4096",
+            "imp[1].id: duplicates impression top",
+            "imp[1]: must have exactly one of banner, video and native",
+            "imp[1].banner.format: must not be empty",
+            "imp[1].rwdd: requires instl",
+        ] {
+            assert!(
+                message.contains(expected),
+                "{expected} missing from {message}"
+            );
+        }
+        assert!(!message.contains("imp[0].banner.format[0].h"));
+
+        body["imp"] = json!([]);
+        assert!(violations(&body).contains("imp: must be a non-empty array"));
+    }
+
+    #[test]
+    fn test_regs_violations() {
+        let mut body = valid_request();
+        body["user"]["ext"]["consent"] = json!("");
+        body["regs"] = json!({
+            "gdpr": 1,
+            "coppa": 1,
+            "gpp_sid": [7],
+            "us_privacy": "1YNN",
+            "ext": { "gdpr": 0, "us_privacy": "1YYN" }
+        });
+        body["user"]["id"] = json!("user-1");
+        let message = violations(&body);
+        for expected in [
+            "regs.ext.gdpr: must equal regs.gdpr",
+            "regs.ext.us_privacy: must equal regs.us_privacy",
+            "regs.gpp_sid: requires regs.gpp",
+            "user.ext.eids: requires user.ext.consent where GDPR applies",
+            "user.ext.eids: must be empty for child-directed requests",
+            "user.id: must be absent for child-directed requests",
+        ] {
+            assert!(
+                message.contains(expected),
+                "{expected} missing from {message}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_missing_fields() {
+        let message = violations(&json!({}));
+        for expected in [
+            "id: must be a non-empty string",
+            "imp: must be a non-empty array",
+            "site.page: must be a non-empty string",
+            "tmax: must be a positive integer",
+            "regs: must be an object",
+        ] {
+            assert!(
+                message.contains(expected),
+                "{expected} missing from {message}"
+            );
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Retention of per-user KV entries.
+//!
+//! The privacy policy promises that per-user data is kept for at most 13
+//! months (`retention.default_days`, overridable per store in
+//! `retention.stores`). [`RetainedStore`] enforces it twice: every write sets
+//! a KV time-to-live of the retention period, and every read checks the write
+//! time [`JsonKvStore`] records, deleting entries that outlived the period
+//! before the TTL removed them, such as entries written before retention was
+//! configured.
+//!
+//! Entries without a recorded write time were written before write times were
+//! recorded. Their age is unknown, so they are kept until their next write.
+
+use std::time::Duration;
+
+use error_stack::Report;
+
+use crate::error::TrustedServerError;
+use crate::kv_store::{JsonKvStore, Stamped};
+use crate::settings::Settings;
+
+pub(crate) const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Retention period of `store_name`, or [`None`] if its entries are kept forever.
+pub fn retention_period(settings: &Settings, store_name: &str) -> Option<Duration> {
+    let days = settings
+        .retention
+        .stores
+        .get(store_name)
+        .copied()
+        .unwrap_or(settings.retention.default_days);
+    (days > 0).then(|| Duration::from_secs(u64::from(days) * SECONDS_PER_DAY))
+}
+
+/// Whether an entry written at `written_at` has outlived `period` at `now`
+/// (Unix timestamps in seconds).
+fn is_expired(written_at: Option<i64>, period: Option<Duration>, now: i64) -> bool {
+    match (written_at, period) {
+        (Some(written_at), Some(period)) => {
+            now.saturating_sub(written_at) > i64::try_from(period.as_secs()).unwrap_or(i64::MAX)
+        }
+        _ => false,
+    }
+}
+
+/// A KV store of plain text values kept for the store's retention period.
+pub struct RetainedStore {
+    store: JsonKvStore,
+    period: Option<Duration>,
+}
+
+impl RetainedStore {
+    /// Opens `store_name` with the retention period configured for it.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the store cannot be opened
+    pub fn open(settings: &Settings, store_name: &str) -> Result<Self, Report<TrustedServerError>> {
+        Ok(Self {
+            store: JsonKvStore::open(store_name)?,
+            period: retention_period(settings, store_name),
+        })
+    }
+
+    /// Looks up the value stored under `key` and the time it was written.
+    ///
+    /// Entries older than the retention period are deleted and reported as
+    /// missing.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the lookup or deletion fails
+    pub fn get_stamped(
+        &self,
+        key: &str,
+    ) -> Result<Option<Stamped<String>>, Report<TrustedServerError>> {
+        let Some(entry) = self.store.get_text_stamped(key)? else {
+            return Ok(None);
+        };
+        if is_expired(
+            entry.written_at,
+            self.period,
+            chrono::Utc::now().timestamp(),
+        ) {
+            log::info!("Expiring {} entry past retention", self.store.name());
+            self.store.delete(key)?;
+            return Ok(None);
+        }
+        Ok(Some(entry))
+    }
+
+    /// Looks up the value stored under `key`, expiring it if it is older than
+    /// the retention period.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the lookup or deletion fails
+    pub fn get(&self, key: &str) -> Result<Option<String>, Report<TrustedServerError>> {
+        Ok(self.get_stamped(key)?.map(|entry| entry.value))
+    }
+
+    /// Stores `value` under `key` for the retention period.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the insert fails
+    pub fn put(&self, key: &str, value: &str) -> Result<(), Report<TrustedServerError>> {
+        match self.period {
+            Some(period) => self.store.put_text_with_ttl(key, value, period),
+            None => self.store.put_text(key, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::tests::create_test_settings;
+
+    #[test]
+    fn test_retention_period() {
+        let mut settings = create_test_settings();
+        settings
+            .retention
+            .stores
+            .insert("short_store".to_string(), 30);
+        settings
+            .retention
+            .stores
+            .insert("forever_store".to_string(), 0);
+
+        assert_eq!(
+            retention_period(&settings, "other_store"),
+            Some(Duration::from_secs(395 * SECONDS_PER_DAY))
+        );
+        assert_eq!(
+            retention_period(&settings, "short_store"),
+            Some(Duration::from_secs(30 * SECONDS_PER_DAY))
+        );
+        assert_eq!(retention_period(&settings, "forever_store"), None);
+    }
+
+    #[test]
+    fn test_is_expired() {
+        let day = Some(Duration::from_secs(SECONDS_PER_DAY));
+        let now = 1_700_000_000;
+        assert!(!is_expired(Some(now - 60), day, now));
+        assert!(is_expired(Some(now - 2 * 86_400), day, now));
+        assert!(!is_expired(None, day, now));
+        assert!(!is_expired(Some(0), None, now));
+    }
+
+    #[test]
+    fn test_retained_store_expires_stale_entries() {
+        let settings = create_test_settings();
+        let store = RetainedStore::open(&settings, &settings.synthetic.counter_store).unwrap();
+        store.put("fresh-synthetic", "2").unwrap();
+        assert_eq!(store.get("fresh-synthetic").unwrap().as_deref(), Some("2"));
+
+        // Written 14 months ago, before the TTL was set
+        let written_at = chrono::Utc::now().timestamp() - 425 * 86_400;
+        fastly::KVStore::open(&settings.synthetic.counter_store)
+            .unwrap()
+            .unwrap()
+            .build_insert()
+            .metadata(&written_at.to_string())
+            .execute("stale-synthetic", "9")
+            .unwrap();
+        assert_eq!(store.get("stale-synthetic").unwrap(), None);
+        assert_eq!(
+            JsonKvStore::open(&settings.synthetic.counter_store)
+                .unwrap()
+                .get_text("stale-synthetic")
+                .unwrap(),
+            None
+        );
+    }
+}
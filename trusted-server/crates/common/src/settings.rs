@@ -1,8 +1,11 @@
 use core::str;
+use std::collections::HashMap;
+use std::fmt;
 
 use config::{Config, Environment, File, FileFormat};
 use error_stack::{Report, ResultExt};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use crate::error::TrustedServerError;
 
@@ -13,6 +16,14 @@ pub const ENVIRONMENT_VARIABLE_SEPARATOR: &str = "__";
 pub struct AdServer {
     pub ad_partner_url: String,
     pub sync_url: String,
+    /// IAB Global Vendor List ID of the ad partner, checked for TCF consent.
+    #[serde(default = "default_ad_server_vendor_id")]
+    pub vendor_id: u16,
+}
+
+/// Equativ (Smart AdServer)
+fn default_ad_server_vendor_id() -> u16 {
+    45
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
@@ -20,17 +31,413 @@ pub struct Publisher {
     pub domain: String,
     pub cookie_domain: String,
     pub origin_url: String,
+    /// Treat all traffic as child-directed under COPPA.
+    #[serde(default)]
+    pub coppa: bool,
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
 pub struct Prebid {
     pub server_url: String,
+    /// IAB Global Vendor List ID per Prebid bidder code. Bidders without an
+    /// entry are only sent bid requests when GDPR does not apply.
+    #[serde(default = "default_prebid_bidders")]
+    pub bidders: HashMap<String, u16>,
+    /// Further Prebid Server or SSP endpoints `/auction` requests are sent to
+    /// alongside `server_url`.
+    #[serde(default)]
+    pub endpoints: Vec<AuctionEndpoint>,
+    /// Seller node of the supply chain sent in bid requests.
+    #[serde(default)]
+    pub schain: SupplyChainNode,
+    /// KV store holding slot configurations by stored request ID, which
+    /// `/auction` slots can reference instead of sending the full slot.
+    /// Stored requests are disabled when empty.
+    #[serde(default)]
+    pub stored_requests_kv_store: String,
+    /// Keys `${AUCTION_PRICE}` is encrypted with, per bidder code. Bidders
+    /// without keys receive the price in clear text.
+    #[serde(default)]
+    pub price_encryption: HashMap<String, PriceEncryptionKeys>,
+    /// Time budget of a `/auction` request from its arrival, in
+    /// milliseconds. Bidders are sent what is left as `tmax` and bids
+    /// arriving later are ignored.
+    #[serde(default = "default_auction_timeout_ms")]
+    pub auction_timeout_ms: u64,
+    /// Deployment profile of the service. Production never sends debug
+    /// bid requests.
+    #[serde(default)]
+    pub profile: Profile,
+    /// Marks bid requests as test traffic (`test: 1`), which bidders do
+    /// not bill.
+    #[serde(default)]
+    pub test: bool,
+    /// Asks Prebid Server for debug output (`debug: 1`), outside the
+    /// production profile only.
+    #[serde(default)]
+    pub debug: bool,
+    /// What bid requests send as `user.id`.
+    #[serde(default)]
+    pub user_id: UserIdMode,
+    /// Sends bid requests to `server_url` gzip compressed.
+    #[serde(default)]
+    pub gzip: bool,
+}
+
+/// OpenRTB `user.id` of bid requests. Requests that do not allow
+/// personalized ads never carry one.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UserIdMode {
+    /// A different ID per auction endpoint, derived from the synthetic ID.
+    #[default]
+    Scoped,
+    /// The synthetic ID itself.
+    Synthetic,
+    /// No `user.id`.
+    Omit,
+}
+
+/// Deployment profile of the service.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    #[default]
+    Production,
+    Staging,
+    Development,
+}
+
+fn default_auction_timeout_ms() -> u64 {
+    1000
+}
+
+/// Price encryption keys agreed with a bidder, as web-safe base64.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PriceEncryptionKeys {
+    pub encryption_key: String,
+    pub integrity_key: String,
+}
+
+/// Publisher's node of the OpenRTB supply chain object (`source.ext.schain`).
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SupplyChainNode {
+    /// Canonical domain of the system the seller ID belongs to; no supply
+    /// chain is sent unless both `asi` and `sid` are set.
+    #[serde(default)]
+    pub asi: String,
+    /// Seller ID of the publisher in that system, as in its `sellers.json`.
+    #[serde(default)]
+    pub sid: String,
+    /// Whether the seller is paid for the impressions (`hp`).
+    #[serde(default = "default_supply_chain_hp")]
+    pub hp: bool,
+}
+
+impl Default for SupplyChainNode {
+    fn default() -> Self {
+        Self {
+            asi: String::new(),
+            sid: String::new(),
+            hp: default_supply_chain_hp(),
+        }
+    }
+}
+
+fn default_supply_chain_hp() -> bool {
+    true
+}
+
+/// OpenRTB auction endpoint of a Prebid Server or SSP.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuctionEndpoint {
+    /// Endpoint name used in logs.
+    pub name: String,
+    /// URL the OpenRTB bid request is POSTed to.
+    pub url: String,
+    /// Fastly backend the request is sent through.
+    pub backend: String,
+    /// Longest wait for this endpoint's bids in milliseconds, within
+    /// `prebid.auction_timeout_ms`.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Sends bid requests gzip compressed, for endpoints that accept
+    /// `Content-Encoding: gzip`.
+    #[serde(default)]
+    pub gzip: bool,
+}
+
+fn default_prebid_bidders() -> HashMap<String, u16> {
+    HashMap::from([("smartadserver".to_string(), 45)])
+}
+
+/// Settings for caching winning creatives, retrieved through `GET /cache`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PrebidCache {
+    /// KV store the creative markup is cached in.
+    #[serde(default)]
+    pub kv_store: String,
+    /// Prebid Cache endpoint (`/cache`) used when `kv_store` is empty; caching
+    /// is disabled when both are empty.
+    #[serde(default)]
+    pub endpoint: String,
+    /// Fastly backend for `endpoint`.
+    #[serde(default = "default_prebid_cache_backend")]
+    pub backend: String,
+    /// Seconds a cached creative stays retrievable.
+    #[serde(default = "default_prebid_cache_ttl_seconds")]
+    pub ttl_seconds: u64,
+}
+
+impl Default for PrebidCache {
+    fn default() -> Self {
+        Self {
+            kv_store: String::new(),
+            endpoint: String::new(),
+            backend: default_prebid_cache_backend(),
+            ttl_seconds: default_prebid_cache_ttl_seconds(),
+        }
+    }
+}
+
+fn default_prebid_cache_backend() -> String {
+    "prebid_cache".to_string()
+}
+
+fn default_prebid_cache_ttl_seconds() -> u64 {
+    300
+}
+
+/// Settings for loading winning creatives from first-party hosts.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct CreativeRewrite {
+    /// Creative hosts and the first-party hosts serving their files.
+    #[serde(default)]
+    pub hosts: Vec<HostMapping>,
+    /// Creative CDNs proxied by the edge under a path of the publisher domain.
+    #[serde(default)]
+    pub proxies: Vec<CreativeProxy>,
+}
+
+/// Third-party creative CDN whose files the edge serves under `path`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CreativeProxy {
+    /// CDN host, e.g. `tpc.googlesyndication.com`
+    pub host: String,
+    /// First-party path prefix, e.g. `/creative-cdn/tpc`
+    pub path: String,
+    /// Fastly backend of the CDN
+    pub backend: String,
+}
+
+/// Third-party host and the first-party host its URLs are rewritten to.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HostMapping {
+    pub from: String,
+    pub to: String,
+}
+
+/// Settings for the win (`nurl`), loss (`lurl`) and billing (`burl`)
+/// notifications of auction bids, fired from the edge.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WinNotifications {
+    /// Whether the `nurl` of each winning bid and the `lurl` of every other
+    /// bid are fired once the auction picked the winners.
+    #[serde(default)]
+    pub enabled: bool,
+    /// KV store the `burl` of each winning bid waits in until `POST /billing`
+    /// confirms the render; billing notifications are disabled when empty.
+    #[serde(default)]
+    pub kv_store: String,
+    /// Seconds a render can be confirmed after the auction.
+    #[serde(default = "default_win_notifications_ttl_seconds")]
+    pub ttl_seconds: u64,
+}
+
+impl Default for WinNotifications {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            kv_store: String::new(),
+            ttl_seconds: default_win_notifications_ttl_seconds(),
+        }
+    }
+}
+
+fn default_win_notifications_ttl_seconds() -> u64 {
+    3600
+}
+
+/// Settings for rejecting bids of blocked advertisers and creatives.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct BidValidation {
+    /// Advertiser domains (`adomain`) whose bids are rejected, including
+    /// their subdomains.
+    #[serde(default)]
+    pub blocked_advertiser_domains: Vec<String>,
+    /// Creative IDs (`crid`) whose bids are rejected.
+    #[serde(default)]
+    pub blocked_creative_ids: Vec<String>,
+    /// KV store with further `advertiser_domains` and `creative_ids` in a JSON
+    /// document under the key `blocklist`; not read when empty.
+    #[serde(default)]
+    pub kv_store: String,
+}
+
+/// Settings for reusing the bids of non-personalized auctions.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BidCache {
+    /// KV store the bids of each auctioned slot are cached in; bid caching is
+    /// disabled when empty.
+    #[serde(default)]
+    pub kv_store: String,
+    /// Seconds cached bids are reused for.
+    #[serde(default = "default_bid_cache_ttl_seconds")]
+    pub ttl_seconds: u64,
+}
+
+impl Default for BidCache {
+    fn default() -> Self {
+        Self {
+            kv_store: String::new(),
+            ttl_seconds: default_bid_cache_ttl_seconds(),
+        }
+    }
+}
+
+fn default_bid_cache_ttl_seconds() -> u64 {
+    30
+}
+
+/// Settings for tracking the outcomes of bid requests per auction endpoint
+/// and pausing endpoints that fail too often.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BidderHealth {
+    /// Whether outcomes are counted and failing endpoints paused.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Fastly rate counter the outcomes are counted in.
+    #[serde(default = "default_bidder_health_rate_counter")]
+    pub rate_counter: String,
+    /// Fastly penalty box paused endpoints are kept in.
+    #[serde(default = "default_bidder_health_penalty_box")]
+    pub penalty_box: String,
+    /// Share of errors and timeouts among the last minute's bid requests, in
+    /// percent, at which an endpoint is paused.
+    #[serde(default = "default_bidder_health_failure_threshold_percent")]
+    pub failure_threshold_percent: u32,
+    /// Bid requests in the last minute before an endpoint can be paused.
+    #[serde(default = "default_bidder_health_min_requests")]
+    pub min_requests: u32,
+    /// Minutes a paused endpoint is skipped for, from 1 to 60.
+    #[serde(default = "default_bidder_health_open_minutes")]
+    pub open_minutes: u64,
+}
+
+impl Default for BidderHealth {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rate_counter: default_bidder_health_rate_counter(),
+            penalty_box: default_bidder_health_penalty_box(),
+            failure_threshold_percent: default_bidder_health_failure_threshold_percent(),
+            min_requests: default_bidder_health_min_requests(),
+            open_minutes: default_bidder_health_open_minutes(),
+        }
+    }
+}
+
+fn default_bidder_health_rate_counter() -> String {
+    "bidder_health".to_string()
+}
+
+fn default_bidder_health_penalty_box() -> String {
+    "bidder_circuit".to_string()
+}
+
+fn default_bidder_health_failure_threshold_percent() -> u32 {
+    50
+}
+
+fn default_bidder_health_min_requests() -> u32 {
+    20
+}
+
+fn default_bidder_health_open_minutes() -> u64 {
+    1
+}
+
+/// Publisher ad slots shared by the Prebid and GAM requests.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SlotRegistry {
+    /// KV store holding further slots as JSON under `slot:<code>`; only
+    /// the slots in `slots` are used when empty.
+    #[serde(default)]
+    pub kv_store: String,
+    /// Registered slots.
+    #[serde(default)]
+    pub slots: Vec<RegisteredSlot>,
+}
+
+/// A publisher ad slot and what each ad stack needs to fill it.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RegisteredSlot {
+    /// Slot code the page uses, e.g. `homepage-leaderboard`.
+    pub code: String,
+    /// Creative sizes the slot accepts as `[width, height]` pairs.
+    pub sizes: Vec<(u32, u32)>,
+    /// GAM ad unit path of the slot, e.g. `/3790/trustedserver/homepage`.
+    #[serde(default)]
+    pub gam_ad_unit: String,
+    /// Prebid bidder parameters of the slot, per bidder code.
+    #[serde(default)]
+    pub bidders: HashMap<String, serde_json::Value>,
+}
+
+/// Settings for the `hb_*` ad server targeting keys of winning bids.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Targeting {
+    /// Price granularity `hb_pb` is rounded down to.
+    #[serde(default)]
+    pub price_granularity: PriceGranularity,
+    /// Buckets of the `custom` price granularity.
+    #[serde(default)]
+    pub price_buckets: Vec<PriceBucket>,
+}
+
+/// Prebid price granularity of the `hb_pb` targeting key.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PriceGranularity {
+    /// $0.50 steps up to $5.
+    Low,
+    /// $0.10 steps up to $20.
+    #[default]
+    Medium,
+    /// $0.01 steps up to $20.
+    High,
+    /// $0.05 steps up to $5, $0.10 up to $10 and $0.50 up to $20.
+    Auto,
+    /// $0.01 steps up to $3, $0.05 up to $8 and $0.50 up to $20.
+    Dense,
+    /// The buckets in `targeting.price_buckets`.
+    Custom,
+}
+
+/// Price range rounded down to multiples of `increment` above `min`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct PriceBucket {
+    pub min: f64,
+    pub max: f64,
+    pub increment: f64,
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
 #[allow(unused)]
 pub struct GamAdUnit {
     pub name: String,
+    /// Comma-separated `<w>x<h>` sizes, or `flexible` for a fluid ad unit.
+    /// Sizes wider than the visitor's viewport are left out of requests.
     pub size: String,
 }
 
@@ -40,6 +447,245 @@ pub struct Gam {
     pub publisher_id: String,
     pub server_url: String,
     pub ad_units: Vec<GamAdUnit>,
+    /// IAB Global Vendor List ID of Google Advertising Products.
+    #[serde(default = "default_gam_vendor_id")]
+    pub vendor_id: u16,
+    /// Sends a publisher provided ID (PPID) derived from the synthetic ID.
+    #[serde(default = "default_gam_ppid")]
+    pub ppid: bool,
+    /// Video ad unit of `/gam-vast` requests without an `iu` parameter, a
+    /// name or full path like `ad_units`.
+    #[serde(default)]
+    pub video_ad_unit: String,
+    /// Player sizes of `/gam-vast` requests without a `sz` parameter, as
+    /// `<w>x<h>` separated by `|`.
+    #[serde(default = "default_gam_video_sizes")]
+    pub video_sizes: String,
+}
+
+fn default_gam_vendor_id() -> u16 {
+    755
+}
+
+fn default_gam_ppid() -> bool {
+    true
+}
+
+fn default_gam_video_sizes() -> String {
+    "640x480".to_string()
+}
+
+/// Settings for sandboxed rendering of ad server creatives.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CreativeFrame {
+    /// KV store holding creatives until their one-time fetch; creatives are
+    /// not rendered when empty.
+    #[serde(default)]
+    pub kv_store: String,
+    /// Seconds an unfetched creative stays retrievable.
+    #[serde(default = "default_creative_frame_ttl_seconds")]
+    pub ttl_seconds: u64,
+}
+
+impl Default for CreativeFrame {
+    fn default() -> Self {
+        Self {
+            kv_store: String::new(),
+            ttl_seconds: default_creative_frame_ttl_seconds(),
+        }
+    }
+}
+
+fn default_creative_frame_ttl_seconds() -> u64 {
+    60
+}
+
+/// Settings for house ads filling slots GAM leaves empty.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct HouseAds {
+    /// KV store holding house ad creatives under `house_ad:<size>`, looked up
+    /// before `creatives`; not used when empty.
+    #[serde(default)]
+    pub kv_store: String,
+    /// House ad creatives by size.
+    #[serde(default)]
+    pub creatives: Vec<HouseAdCreative>,
+}
+
+/// House ad creative for slots of one size.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HouseAdCreative {
+    /// Slot size as `<w>x<h>`, or `default` for any slot
+    pub size: String,
+    /// Creative HTML
+    pub html: String,
+}
+
+/// Settings for server-side Permutive audience segments.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Permutive {
+    /// Segment API endpoint; segments are not fetched when empty.
+    #[serde(default)]
+    pub api_url: String,
+    /// API key sent in the `X-API-Key` header.
+    #[serde(default)]
+    pub api_key: String,
+    /// KV store caching the segments of each synthetic ID; not cached when empty.
+    #[serde(default)]
+    pub kv_store: String,
+    /// Seconds fetched segments are cached for.
+    #[serde(default = "default_permutive_cache_ttl_secs")]
+    pub cache_ttl_secs: u64,
+    /// IAB Global Vendor List ID of Permutive.
+    #[serde(default = "default_permutive_vendor_id")]
+    pub vendor_id: u16,
+    /// Comma-separated segments sent in place of those of the segment API,
+    /// for testing with known segments; not used when empty.
+    #[serde(default)]
+    pub segments_override: String,
+}
+
+impl Default for Permutive {
+    fn default() -> Self {
+        Self {
+            api_url: String::new(),
+            api_key: String::new(),
+            kv_store: String::new(),
+            cache_ttl_secs: default_permutive_cache_ttl_secs(),
+            vendor_id: default_permutive_vendor_id(),
+            segments_override: String::new(),
+        }
+    }
+}
+
+fn default_permutive_cache_ttl_secs() -> u64 {
+    300
+}
+
+fn default_permutive_vendor_id() -> u16 {
+    361
+}
+
+/// Settings for server-side Lotame audience segments.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Lotame {
+    /// Audience extraction endpoint; segments are not fetched when empty.
+    #[serde(default)]
+    pub api_url: String,
+    /// Lotame client ID, sent as the `c` query parameter.
+    #[serde(default)]
+    pub client_id: String,
+    /// API key sent in the `X-API-Key` header.
+    #[serde(default)]
+    pub api_key: String,
+    /// Milliseconds to wait for the segments before serving without them.
+    #[serde(default = "default_dmp_timeout_ms")]
+    pub timeout_ms: u64,
+    /// IAB Global Vendor List ID of Lotame.
+    #[serde(default = "default_lotame_vendor_id")]
+    pub vendor_id: Option<u16>,
+}
+
+impl Default for Lotame {
+    fn default() -> Self {
+        Self {
+            api_url: String::new(),
+            client_id: String::new(),
+            api_key: String::new(),
+            timeout_ms: default_dmp_timeout_ms(),
+            vendor_id: default_lotame_vendor_id(),
+        }
+    }
+}
+
+fn default_lotame_vendor_id() -> Option<u16> {
+    Some(95)
+}
+
+/// Settings for server-side Neustar audience segments.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Neustar {
+    /// Segment API endpoint; segments are not fetched when empty.
+    #[serde(default)]
+    pub api_url: String,
+    /// API key sent in the `X-API-Key` header.
+    #[serde(default)]
+    pub api_key: String,
+    /// Milliseconds to wait for the segments before serving without them.
+    #[serde(default = "default_dmp_timeout_ms")]
+    pub timeout_ms: u64,
+    /// IAB Global Vendor List ID of Neustar; without it, segments are only
+    /// fetched where GDPR does not apply.
+    #[serde(default)]
+    pub vendor_id: Option<u16>,
+}
+
+impl Default for Neustar {
+    fn default() -> Self {
+        Self {
+            api_url: String::new(),
+            api_key: String::new(),
+            timeout_ms: default_dmp_timeout_ms(),
+            vendor_id: None,
+        }
+    }
+}
+
+/// Settings shared by the audience data providers.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Audience {
+    /// Longest wait in milliseconds for the segments of all data providers
+    /// together; each provider's own timeout applies within it.
+    #[serde(default = "default_audience_budget_ms")]
+    pub budget_ms: u64,
+}
+
+impl Default for Audience {
+    fn default() -> Self {
+        Self {
+            budget_ms: default_audience_budget_ms(),
+        }
+    }
+}
+
+fn default_audience_budget_ms() -> u64 {
+    150
+}
+
+/// Publisher-defined HTTP data provider, such as an in-house DMP.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CustomProvider {
+    /// Provider name, used as the `cust_params` key of its segments.
+    pub name: String,
+    /// URL of the segment lookup; `{{synthetic_id}}` is replaced with the
+    /// visitor's URL-encoded synthetic ID.
+    pub url: String,
+    /// Fastly backend the lookup is sent through.
+    pub backend: String,
+    /// Header carrying `auth_value`, e.g. `Authorization`; none when empty.
+    #[serde(default)]
+    pub auth_header: String,
+    /// Value of `auth_header`.
+    #[serde(default)]
+    pub auth_value: String,
+    /// Dot-separated path of the segment array in the JSON response, e.g.
+    /// `data.segments`; the whole response when empty.
+    #[serde(default)]
+    pub segments_path: String,
+    /// `user.data` name of the segments in bid requests; `name` when unset.
+    #[serde(default)]
+    pub data_name: Option<String>,
+    /// Milliseconds to wait for the segments before serving without them.
+    #[serde(default = "default_dmp_timeout_ms")]
+    pub timeout_ms: u64,
+    /// IAB Global Vendor List ID of the provider; without it, segments are
+    /// only fetched where GDPR does not apply.
+    #[serde(default)]
+    pub vendor_id: Option<u16>,
+}
+
+fn default_dmp_timeout_ms() -> u64 {
+    100
 }
 
 #[allow(unused)]
@@ -49,15 +695,605 @@ pub struct Synthetic {
     pub opid_store: String,
     pub secret_key: String,
     pub template: String,
+    /// Retention window in days after which a synthetic ID is retired and regenerated.
+    #[serde(default = "default_synthetic_max_age_days")]
+    pub max_age_days: u32,
+    /// Prefix length kept from IPv4 client addresses before they feed the template (e.g. 24).
+    #[serde(default = "default_ipv4_prefix_len")]
+    pub ipv4_prefix_len: u8,
+    /// Prefix length kept from IPv6 client addresses before they feed the template (e.g. 48).
+    #[serde(default = "default_ipv6_prefix_len")]
+    pub ipv6_prefix_len: u8,
+    /// Template fields that are withheld from the synthetic ID template.
+    #[serde(default)]
+    pub exclude_fields: Vec<String>,
+    /// Cookie name prefix used when issuing the synthetic ID cookie.
+    #[serde(default)]
+    pub cookie_prefix: CookiePrefix,
+    /// Unix timestamp until which synthetic ID cookies issued under another
+    /// prefix are still read, while they are migrated to `cookie_prefix`.
+    /// Zero (the default) reads only the configured cookie name.
+    #[serde(default)]
+    pub legacy_cookies_until: i64,
+    /// Strict Do-Not-Track mode: `DNT: 1` suppresses synthetic ID persistence and visit counting.
+    #[serde(default)]
+    pub honor_dnt: bool,
+}
+
+/// Cookie name prefix restricting how browsers accept a cookie.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CookiePrefix {
+    /// No prefix; the cookie is scoped to `publisher.cookie_domain`.
+    #[default]
+    None,
+    /// `__Secure-`: the cookie must be set with the `Secure` attribute.
+    Secure,
+    /// `__Host-`: the cookie must be `Secure`, have `Path=/` and no `Domain`.
+    Host,
+}
+
+#[allow(unused)]
+impl CookiePrefix {
+    /// Returns the literal prefix prepended to the cookie name.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "",
+            Self::Secure => "__Secure-",
+            Self::Host => "__Host-",
+        }
+    }
+}
+
+/// Default synthetic ID retention window (13 months), matching the privacy policy.
+fn default_synthetic_max_age_days() -> u32 {
+    395
+}
+
+/// By default the full IPv4 address is used.
+fn default_ipv4_prefix_len() -> u8 {
+    32
+}
+
+/// By default the full IPv6 address is used.
+fn default_ipv6_prefix_len() -> u8 {
+    128
+}
+
+/// Settings for operator-only debug endpoints.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct DebugEndpoints {
+    /// Bearer token accepted by admin and debug endpoints, like an entry of
+    /// `admin.api_keys`.
+    #[serde(default)]
+    pub auth_token: String,
+}
+
+/// Credentials of operators calling admin and debug endpoints.
+///
+/// The endpoints are disabled while no API key and no JWT secret is
+/// configured, either here, in `debug.auth_token` or in `secret_store`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Admin {
+    /// API keys accepted as `Authorization: Bearer <key>` or `X-API-Key: <key>`.
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+    /// Shared secret validating HS256 JWT bearer tokens; JWTs are rejected when empty.
+    #[serde(default)]
+    pub jwt_secret: String,
+    /// Required `iss` claim of JWT bearer tokens; any issuer is accepted when empty.
+    #[serde(default)]
+    pub jwt_issuer: String,
+    /// Fastly secret store holding an `api_key` and/or `jwt_secret`, used in
+    /// addition to the values above; not consulted when empty.
+    #[serde(default)]
+    pub secret_store: String,
+}
+
+/// Settings for the ID5 identity provider.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Id5 {
+    /// ID5 partner number; the provider is disabled when zero.
+    #[serde(default)]
+    pub partner_id: u32,
+    #[serde(default = "default_id5_api_url")]
+    pub api_url: String,
+}
+
+impl Default for Id5 {
+    fn default() -> Self {
+        Self {
+            partner_id: 0,
+            api_url: default_id5_api_url(),
+        }
+    }
+}
+
+fn default_id5_api_url() -> String {
+    "https://id5-sync.com".to_string()
+}
+
+/// Settings for the LiveRamp ATS identity provider.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LiveRamp {
+    /// ATS placement ID; the provider is disabled when empty.
+    #[serde(default)]
+    pub placement_id: String,
+    #[serde(default = "default_liveramp_api_url")]
+    pub api_url: String,
+}
+
+impl Default for LiveRamp {
+    fn default() -> Self {
+        Self {
+            placement_id: String::new(),
+            api_url: default_liveramp_api_url(),
+        }
+    }
+}
+
+fn default_liveramp_api_url() -> String {
+    "https://api.rlcdn.com".to_string()
+}
+
+/// Settings for server-side identity linking and identity providers.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Identity {
+    /// KV store holding identity links; linking is disabled when empty.
+    #[serde(default)]
+    pub link_store: String,
+    /// Secret the publisher signs user IDs with; the `X-Pub-User-ID` header
+    /// and `pub_userid` cookie must hold `<id>.<hex HMAC-SHA256 of the id>`.
+    /// Publisher user IDs are ignored when empty.
+    #[serde(default)]
+    pub pub_user_id_secret: String,
+    #[serde(default)]
+    pub id5: Id5,
+    #[serde(default)]
+    pub liveramp: LiveRamp,
+}
+
+/// Settings for the UID2 operator integration.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Uid2 {
+    /// Base URL of the UID2 operator; the integration is disabled when empty.
+    #[serde(default)]
+    pub operator_url: String,
+    /// API key sent as a bearer token to the operator.
+    #[serde(default)]
+    pub api_key: String,
+    /// Base64-encoded client secret used to encrypt operator requests.
+    #[serde(default)]
+    pub client_secret: String,
+    /// KV store caching UID2 tokens per synthetic ID.
+    #[serde(default)]
+    pub token_store: String,
+}
+
+/// Settings for first-party GDPR consent handling.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Gdpr {
+    /// Encrypt the `gdpr_consent` cookie so its JSON cannot be edited client side.
+    #[serde(default)]
+    pub encrypt_consent_cookie: bool,
+    /// Days after its last update a TC string is treated as expired; zero disables the check.
+    #[serde(default = "default_tcf_max_age_days")]
+    pub tcf_max_age_days: u32,
+    /// Registered IAB CMP ID used when writing `euconsent-v2` from the consent
+    /// banner; no TC string is written when zero.
+    #[serde(default)]
+    pub cmp_id: u16,
+    #[serde(default = "default_cmp_version")]
+    pub cmp_version: u16,
+    /// ISO 639-1 language of the consent banner.
+    #[serde(default = "default_consent_language")]
+    pub consent_language: String,
+    /// IAB vendor IDs disclosed in the consent banner; the TC string written
+    /// for a banner choice only grants these vendors.
+    #[serde(default)]
+    pub disclosed_vendors: Vec<u16>,
+    /// KV store holding consent receipts per synthetic ID; receipts are disabled when empty.
+    #[serde(default)]
+    pub receipt_store: String,
+    /// KV store of erased synthetic IDs, which are never issued again; tombstones
+    /// are disabled when empty.
+    #[serde(default)]
+    pub tombstone_store: String,
+    /// Ad partner privacy endpoints notified when a user's data is erased.
+    #[serde(default)]
+    pub erasure_endpoints: Vec<ErasureEndpoint>,
+    /// Seconds a `/gdpr/data/verify` token authorizes data subject requests.
+    #[serde(default = "default_verification_token_ttl_secs")]
+    pub verification_token_ttl_secs: i64,
+    /// KV store tracking the state of data subject requests; tracking is
+    /// disabled when empty.
+    #[serde(default)]
+    pub request_store: String,
+    /// KV store of every consent choice per synthetic ID, returned by data
+    /// access requests; history is disabled when empty.
+    #[serde(default)]
+    pub consent_history_store: String,
+    /// KV store of synthetic IDs whose users objected to processing (GDPR
+    /// Article 21); objections are disabled when empty.
+    #[serde(default)]
+    pub objection_store: String,
+}
+
+impl Default for Gdpr {
+    fn default() -> Self {
+        Self {
+            encrypt_consent_cookie: false,
+            tcf_max_age_days: default_tcf_max_age_days(),
+            cmp_id: 0,
+            cmp_version: default_cmp_version(),
+            consent_language: default_consent_language(),
+            disclosed_vendors: Vec::new(),
+            receipt_store: String::new(),
+            tombstone_store: String::new(),
+            erasure_endpoints: Vec::new(),
+            verification_token_ttl_secs: default_verification_token_ttl_secs(),
+            request_store: String::new(),
+            consent_history_store: String::new(),
+            objection_store: String::new(),
+        }
+    }
+}
+
+/// Ad partner privacy endpoint receiving erasure requests.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ErasureEndpoint {
+    /// Partner name, reported back to the data subject.
+    pub name: String,
+    /// URL the erasure request is sent to. `{{synthetic_id}}`, `{{opid}}` and
+    /// `{{partner_uid}}` are replaced with the subject's URL-encoded identifiers.
+    pub url: String,
+    /// Fastly backend the request is sent through.
+    pub backend: String,
+    /// HTTP method of the request; POST requests carry a JSON body.
+    #[serde(default)]
+    pub method: ErasureMethod,
+    /// Prebid bidder code whose synced UID identifies the subject to this
+    /// partner. Subjects without a UID for the bidder are skipped.
+    #[serde(default)]
+    pub bidder: Option<String>,
+    /// Bearer token sent in the `Authorization` header, if the partner requires one.
+    #[serde(default)]
+    pub auth_token: String,
+    /// Attempts made before the partner is reported as failed, between 1 and
+    /// [`MAX_ERASURE_ATTEMPTS`]; only network errors, `429` and `5xx` responses
+    /// are retried.
+    #[serde(default = "default_erasure_max_attempts")]
+    pub max_attempts: u32,
+}
+
+/// HTTP method used to send an erasure request to a partner.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ErasureMethod {
+    /// POST a JSON erasure request.
+    #[default]
+    Post,
+    /// DELETE the URL, identifying the subject through URL placeholders.
+    Delete,
+}
+
+/// Upper bound of [`ErasureEndpoint::max_attempts`].
+pub const MAX_ERASURE_ATTEMPTS: u32 = 5;
+
+fn default_erasure_max_attempts() -> u32 {
+    3
+}
+
+fn default_cmp_version() -> u16 {
+    1
+}
+
+fn default_consent_language() -> String {
+    "EN".to_string()
+}
+
+/// By default TC strings expire after 13 months.
+fn default_tcf_max_age_days() -> u32 {
+    395
+}
+
+fn default_verification_token_ttl_secs() -> i64 {
+    15 * 60
+}
+
+/// Settings for fetching and caching the IAB Global Vendor List.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Gvl {
+    /// URL of the GVL JSON; vendor list lookups are disabled when empty.
+    #[serde(default)]
+    pub url: String,
+    /// Fastly backend the GVL is fetched through.
+    #[serde(default = "default_gvl_backend")]
+    pub backend: String,
+    /// KV store caching the parsed GVL; the list is fetched on every lookup when empty.
+    #[serde(default)]
+    pub cache_store: String,
+    /// Vendor list version to use; fetched lists with another version are
+    /// rejected in favour of the last accepted copy.
+    #[serde(default)]
+    pub pinned_version: Option<u32>,
+    /// Days after its `lastUpdated` date a fetched list is still accepted;
+    /// `0` accepts lists of any age.
+    #[serde(default = "default_gvl_max_age_days")]
+    pub max_age_days: u32,
+}
+
+impl Default for Gvl {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            backend: default_gvl_backend(),
+            cache_store: String::new(),
+            pinned_version: None,
+            max_age_days: default_gvl_max_age_days(),
+        }
+    }
+}
+
+fn default_gvl_backend() -> String {
+    "gvl_backend".to_string()
+}
+
+/// The GVL is republished weekly; a month leaves ample room for missed updates.
+fn default_gvl_max_age_days() -> u32 {
+    30
+}
+
+/// Settings for webhooks notifying the publisher of consent changes.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConsentWebhook {
+    /// URL consent events are POSTed to; webhooks are disabled when empty.
+    #[serde(default)]
+    pub url: String,
+    /// Fastly backend the webhook is sent through.
+    #[serde(default = "default_consent_webhook_backend")]
+    pub backend: String,
+    /// Secret each event is signed with (HMAC-SHA256 in `X-Trusted-Server-Signature`).
+    #[serde(default)]
+    pub secret: String,
+}
+
+impl Default for ConsentWebhook {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            backend: default_consent_webhook_backend(),
+            secret: String::new(),
+        }
+    }
+}
+
+fn default_consent_webhook_backend() -> String {
+    "consent_webhook".to_string()
+}
+
+/// Settings for the compliance log of accesses to subject data.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ComplianceLog {
+    /// Fastly log endpoint receiving one signed JSON event per access; the
+    /// compliance log is disabled when empty.
+    #[serde(default)]
+    pub endpoint: String,
 }
 
+/// Settings for honoring Global Privacy Control (`Sec-GPC`).
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Gpc {
+    /// Regions where GPC is a binding opt-out, as ISO 3166-1 country codes
+    /// (`US`), ISO 3166-2 subdivisions (`US-CA`), or `*` for everywhere.
+    #[serde(default = "default_gpc_binding_regions")]
+    pub binding_regions: Vec<String>,
+}
+
+impl Default for Gpc {
+    fn default() -> Self {
+        Self {
+            binding_regions: default_gpc_binding_regions(),
+        }
+    }
+}
+
+/// US states whose privacy laws require honoring GPC as an opt-out.
+fn default_gpc_binding_regions() -> Vec<String> {
+    [
+        "US-CA", "US-CO", "US-CT", "US-DE", "US-MN", "US-MT", "US-NH", "US-NJ", "US-OR", "US-TX",
+    ]
+    .iter()
+    .map(|region| region.to_string())
+    .collect()
+}
+
+/// Settings for server-side user syncing (`/setuid`).
 #[derive(Debug, Default, Deserialize, Serialize)]
+pub struct UserSync {
+    /// KV store holding partner UIDs per synthetic ID; syncing is disabled when empty.
+    #[serde(default)]
+    pub uid_store: String,
+}
+
+/// Settings for how long per-user KV entries are kept.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Retention {
+    /// Days an entry is kept after its last write, for stores not listed in
+    /// `stores`; 0 keeps entries forever.
+    #[serde(default = "default_retention_days")]
+    pub default_days: u32,
+    /// KV store name → days its entries are kept after their last write.
+    #[serde(default)]
+    pub stores: HashMap<String, u32>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            default_days: default_retention_days(),
+            stores: HashMap::new(),
+        }
+    }
+}
+
+/// 13 months, as promised by the privacy policy.
+fn default_retention_days() -> u32 {
+    395
+}
+
+/// Settings for folding visit counters into anonymized aggregates.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Anonymization {
+    /// KV store receiving the monthly aggregates; anonymization is disabled when empty.
+    #[serde(default)]
+    pub aggregate_store: String,
+    /// Days without a visit after which a synthetic ID's counter is folded
+    /// into the aggregates and deleted.
+    #[serde(default = "default_anonymization_window_days")]
+    pub window_days: u32,
+    /// Smallest number of synthetic IDs a bucket must hold to be reported (the
+    /// k of k-anonymity); smaller buckets are reported as suppressed.
+    #[serde(default = "default_anonymization_min_bucket_size")]
+    pub min_bucket_size: u64,
+    /// Lower bounds of the visit count buckets, in ascending order.
+    #[serde(default = "default_anonymization_visit_buckets")]
+    pub visit_buckets: Vec<u64>,
+}
+
+impl Default for Anonymization {
+    fn default() -> Self {
+        Self {
+            aggregate_store: String::new(),
+            window_days: default_anonymization_window_days(),
+            min_bucket_size: default_anonymization_min_bucket_size(),
+            visit_buckets: default_anonymization_visit_buckets(),
+        }
+    }
+}
+
+fn default_anonymization_window_days() -> u32 {
+    30
+}
+
+fn default_anonymization_min_bucket_size() -> u64 {
+    10
+}
+
+fn default_anonymization_visit_buckets() -> Vec<u64> {
+    vec![1, 2, 5, 10, 20, 50, 100]
+}
+
+/// Default cookie policy: the synthetic ID requires device storage consent (Purpose 1).
+fn default_cookie_policy() -> HashMap<String, u8> {
+    HashMap::from([("synthetic_id".to_string(), 1)])
+}
+
+#[derive(Default, Deserialize, Serialize)]
 pub struct Settings {
     pub ad_server: AdServer,
     pub publisher: Publisher,
     pub prebid: Prebid,
+    #[serde(default)]
+    pub prebid_cache: PrebidCache,
+    #[serde(default)]
+    pub targeting: Targeting,
+    #[serde(default)]
+    pub creative_rewrite: CreativeRewrite,
+    #[serde(default)]
+    pub win_notifications: WinNotifications,
+    #[serde(default)]
+    pub bid_validation: BidValidation,
+    #[serde(default)]
+    pub bid_cache: BidCache,
+    #[serde(default)]
+    pub bidder_health: BidderHealth,
+    #[serde(default)]
+    pub slot_registry: SlotRegistry,
     pub gam: Gam,
+    #[serde(default)]
+    pub creative_frame: CreativeFrame,
+    #[serde(default)]
+    pub house_ads: HouseAds,
+    #[serde(default)]
+    pub audience: Audience,
+    #[serde(default)]
+    pub permutive: Permutive,
+    #[serde(default)]
+    pub lotame: Lotame,
+    #[serde(default)]
+    pub neustar: Neustar,
+    #[serde(default)]
+    pub data_providers: Vec<CustomProvider>,
     pub synthetic: Synthetic,
+    #[serde(default)]
+    pub gdpr: Gdpr,
+    #[serde(default)]
+    pub identity: Identity,
+    #[serde(default)]
+    pub uid2: Uid2,
+    #[serde(default)]
+    pub user_sync: UserSync,
+    #[serde(default)]
+    pub gvl: Gvl,
+    #[serde(default)]
+    pub gpc: Gpc,
+    #[serde(default)]
+    pub consent_webhook: ConsentWebhook,
+    #[serde(default)]
+    pub compliance_log: ComplianceLog,
+    #[serde(default)]
+    pub retention: Retention,
+    #[serde(default)]
+    pub anonymization: Anonymization,
+    /// Cookie name (without `__Host-`/`__Secure-` prefix) → TCF purpose required to set it.
+    /// Cookies not listed are treated as strictly necessary.
+    #[serde(default = "default_cookie_policy")]
+    pub cookie_policy: HashMap<String, u8>,
+    #[serde(default)]
+    pub debug: DebugEndpoints,
+    #[serde(default)]
+    pub admin: Admin,
+}
+
+/// Whether the setting `name` holds a secret: keys, secrets and tokens.
+fn is_secret(name: &str) -> bool {
+    name.ends_with("secret")
+        || name.ends_with("_key")
+        || name.ends_with("_keys")
+        || name.ends_with("_token")
+}
+
+/// Replaces the configured values of secret settings in `value`, so only
+/// whether they are set shows.
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (name, value) in map.iter_mut() {
+                let configured = match value {
+                    Value::String(s) => !s.is_empty(),
+                    Value::Array(a) => !a.is_empty(),
+                    _ => false,
+                };
+                if is_secret(name) && configured {
+                    *value = Value::from("[redacted]");
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Settings with the values of keys, secrets and tokens redacted, safe to log.
+impl fmt::Debug for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut value = serde_json::to_value(self).map_err(|_| fmt::Error)?;
+        redact(&mut value);
+        write!(f, "Settings {value}")
+    }
 }
 
 #[allow(unused)]
@@ -96,7 +1332,8 @@ impl Settings {
     ///
     /// # Errors
     ///
-    /// - [`TrustedServerError::Configuration`] if the TOML is invalid or missing required fields
+    /// - [`TrustedServerError::Configuration`] if the TOML is invalid, missing
+    ///   required fields or holds out of range values
     pub fn from_toml(toml_str: &str) -> Result<Self, Report<TrustedServerError>> {
         let environment = Environment::default()
             .prefix(ENVIRONMENT_VARIABLE_PREFIX)
@@ -111,11 +1348,33 @@ impl Settings {
                 message: "Failed to build configuration".to_string(),
             })?;
         // You can deserialize (and thus freeze) the entire configuration as
-        config
-            .try_deserialize()
-            .change_context(TrustedServerError::Configuration {
-                message: "Failed to deserialize configuration".to_string(),
-            })
+        let settings: Self =
+            config
+                .try_deserialize()
+                .change_context(TrustedServerError::Configuration {
+                    message: "Failed to deserialize configuration".to_string(),
+                })?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Checks the values that deserialize but are out of range.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::Configuration`] naming the first invalid value
+    fn validate(&self) -> Result<(), Report<TrustedServerError>> {
+        for endpoint in &self.gdpr.erasure_endpoints {
+            if !(1..=MAX_ERASURE_ATTEMPTS).contains(&endpoint.max_attempts) {
+                return Err(Report::new(TrustedServerError::Configuration {
+                    message: format!(
+                        "gdpr.erasure_endpoints.{}.max_attempts must be between 1 and {}",
+                        endpoint.name, MAX_ERASURE_ATTEMPTS
+                    ),
+                }));
+            }
+        }
+        Ok(())
     }
 }
 
@@ -124,7 +1383,7 @@ mod tests {
     use super::*;
     use regex::Regex;
 
-    use crate::test_support::tests::crate_test_settings_str;
+    use crate::test_support::tests::{crate_test_settings_str, create_test_settings};
 
     #[test]
     fn test_settings_new() {
@@ -146,6 +1405,29 @@ mod tests {
         assert!(!settings.synthetic.template.is_empty());
     }
 
+    #[test]
+    fn test_settings_debug_redacts_secrets() {
+        let mut settings = create_test_settings();
+        settings.synthetic.secret_key = "synthetic-secret-1".to_string();
+        settings.admin.api_keys = vec!["admin-key-1".to_string()];
+        settings.admin.jwt_secret = "jwt-secret-1".to_string();
+        settings.debug.auth_token = "debug-token-1".to_string();
+        settings.identity.pub_user_id_secret = "pub-user-secret-1".to_string();
+
+        let debug = format!("{settings:?}");
+        for secret in [
+            "synthetic-secret-1",
+            "admin-key-1",
+            "jwt-secret-1",
+            "debug-token-1",
+            "pub-user-secret-1",
+        ] {
+            assert!(!debug.contains(secret), "{secret} leaked");
+        }
+        assert!(debug.contains("\"jwt_secret\":\"[redacted]\""));
+        assert!(debug.contains(&settings.publisher.domain));
+    }
+
     #[test]
     fn test_settings_from_valid_toml() {
         let toml_str = crate_test_settings_str();
@@ -176,6 +1458,76 @@ mod tests {
         assert_eq!(settings.synthetic.opid_store, "test-opid-store");
         assert_eq!(settings.synthetic.secret_key, "test-secret-key");
         assert!(settings.synthetic.template.contains("{{client_ip}}"));
+        assert_eq!(settings.synthetic.max_age_days, 395);
+        assert_eq!(settings.synthetic.ipv4_prefix_len, 32);
+        assert_eq!(settings.synthetic.ipv6_prefix_len, 128);
+        assert!(settings.synthetic.exclude_fields.is_empty());
+        assert!(settings.debug.auth_token.is_empty());
+    }
+
+    #[test]
+    fn test_settings_synthetic_entropy_controls() {
+        let toml_str = crate_test_settings_str().replace(
+            "[synthetic]",
+            "[synthetic]\nipv4_prefix_len = 24\nipv6_prefix_len = 48\nexclude_fields = [\"user_agent\"]",
+        );
+        let settings = Settings::from_toml(&toml_str).expect("should parse valid TOML");
+
+        assert_eq!(settings.synthetic.ipv4_prefix_len, 24);
+        assert_eq!(settings.synthetic.ipv6_prefix_len, 48);
+        assert_eq!(settings.synthetic.exclude_fields, vec!["user_agent"]);
+    }
+
+    #[test]
+    fn test_settings_cookie_policy() {
+        let settings =
+            Settings::from_toml(&crate_test_settings_str()).expect("should parse valid TOML");
+        assert_eq!(settings.cookie_policy.get("synthetic_id"), Some(&1));
+
+        let toml_str = format!(
+            "{}\n[cookie_policy]\nsynthetic_id = 1\nexperiment = 8\n",
+            crate_test_settings_str()
+        );
+        let settings = Settings::from_toml(&toml_str).expect("should parse valid TOML");
+        assert_eq!(settings.cookie_policy.get("experiment"), Some(&8));
+    }
+
+    #[test]
+    fn test_settings_erasure_max_attempts_is_capped() {
+        let endpoint = |max_attempts: u32| {
+            format!(
+                "{}\n[[gdpr.erasure_endpoints]]\nname = \"equativ\"\n\
+                 url = \"https://privacy.example.com/\"\nbackend = \"equativ_privacy\"\n\
+                 max_attempts = {max_attempts}\n",
+                crate_test_settings_str()
+            )
+        };
+        let settings = Settings::from_toml(&endpoint(MAX_ERASURE_ATTEMPTS))
+            .expect("should accept the maximum attempts");
+        assert_eq!(
+            settings.gdpr.erasure_endpoints[0].max_attempts,
+            MAX_ERASURE_ATTEMPTS
+        );
+
+        for max_attempts in [0, MAX_ERASURE_ATTEMPTS + 1, 33] {
+            assert!(
+                Settings::from_toml(&endpoint(max_attempts)).is_err(),
+                "{max_attempts} attempts accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn test_settings_synthetic_cookie_prefix() {
+        let settings =
+            Settings::from_toml(&crate_test_settings_str()).expect("should parse valid TOML");
+        assert_eq!(settings.synthetic.cookie_prefix, CookiePrefix::None);
+
+        let toml_str = crate_test_settings_str()
+            .replace("[synthetic]", "[synthetic]\ncookie_prefix = \"host\"");
+        let settings = Settings::from_toml(&toml_str).expect("should parse valid TOML");
+        assert_eq!(settings.synthetic.cookie_prefix, CookiePrefix::Host);
+        assert_eq!(settings.synthetic.cookie_prefix.as_str(), "__Host-");
     }
 
     #[test]
@@ -0,0 +1,146 @@
+//! Publisher ad slots shared by the Prebid and GAM requests.
+//!
+//! The registry maps the slot codes pages use, such as `homepage-leaderboard`,
+//! to the sizes the slot accepts, its GAM ad unit path and the Prebid bidder
+//! parameters of each bidder. Slots are configured in `slot_registry.slots`,
+//! or stored as JSON under `slot:<code>` in `slot_registry.kv_store` to change
+//! without a deploy. `/auction` impressions and GAM ad requests are both built
+//! from the registered slot, so the two ad stacks cannot drift apart.
+
+use crate::kv_store::JsonKvStore;
+use crate::settings::{RegisteredSlot, Settings};
+use crate::stored_request::is_valid_id;
+
+/// Registered slots of `slot_registry`.
+pub struct SlotRegistry<'a> {
+    configured: &'a [RegisteredSlot],
+    store: Option<JsonKvStore>,
+}
+
+impl<'a> SlotRegistry<'a> {
+    /// Registry of the slots in `slot_registry`, with the KV store in
+    /// `slot_registry.kv_store` when set. A store that cannot be opened is
+    /// logged and left out.
+    pub fn open(settings: &'a Settings) -> Self {
+        let config = &settings.slot_registry;
+        let store = if config.kv_store.is_empty() {
+            None
+        } else {
+            JsonKvStore::open(&config.kv_store)
+                .inspect_err(|e| log::warn!("Failed to open the slot registry: {:?}", e))
+                .ok()
+        };
+        Self {
+            configured: &config.slots,
+            store,
+        }
+    }
+
+    /// Slots configured in `slot_registry.slots`.
+    pub fn configured(&self) -> &'a [RegisteredSlot] {
+        self.configured
+    }
+
+    /// Slot registered under `code`, from the settings or else the KV store.
+    /// Lookup failures are logged and treated as an unregistered slot.
+    pub fn get(&self, code: &str) -> Option<RegisteredSlot> {
+        if let Some(slot) = self.configured.iter().find(|slot| slot.code == code) {
+            return Some(slot.clone());
+        }
+        let store = self.store.as_ref()?;
+        if !is_valid_id(code) {
+            return None;
+        }
+        store
+            .get(&format!("slot:{code}"))
+            .inspect_err(|e| log::warn!("Failed to look up slot {}: {:?}", code, e))
+            .ok()?
+    }
+}
+
+/// Sizes of `requested` that `registered` accepts, or all of its sizes when
+/// none of them is.
+pub fn accepted_sizes(registered: &RegisteredSlot, requested: &[(u32, u32)]) -> Vec<(u32, u32)> {
+    let accepted: Vec<_> = requested
+        .iter()
+        .filter(|size| registered.sizes.contains(size))
+        .copied()
+        .collect();
+    if accepted.is_empty() {
+        registered.sizes.clone()
+    } else {
+        accepted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::tests::create_test_settings;
+
+    fn leaderboard() -> RegisteredSlot {
+        RegisteredSlot {
+            code: "homepage-leaderboard".to_string(),
+            sizes: vec![(728, 90), (970, 250)],
+            gam_ad_unit: "/3790/trustedserver/homepage".to_string(),
+            bidders: HashMap::from([(
+                "smartadserver".to_string(),
+                json!({ "siteId": 686105, "formatId": 137675 }),
+            )]),
+        }
+    }
+
+    #[test]
+    fn test_get() {
+        let mut settings = create_test_settings();
+        settings.slot_registry.slots = vec![leaderboard()];
+        let registry = SlotRegistry::open(&settings);
+        assert_eq!(registry.get("homepage-leaderboard"), Some(leaderboard()));
+        assert_eq!(registry.configured(), &[leaderboard()]);
+        assert!(registry.get("article-sidebar").is_none());
+
+        settings.slot_registry.kv_store = "test_slot_registry_store".to_string();
+        let registry = SlotRegistry::open(&settings);
+        let stored = registry.get("article-sidebar").unwrap();
+        assert_eq!(stored.sizes, vec![(300, 250), (300, 600)]);
+        assert_eq!(stored.gam_ad_unit, "/3790/trustedserver/article");
+        assert_eq!(stored.bidders["smartadserver"]["formatId"], 137676);
+        assert!(registry.get("article-missing").is_none());
+        assert!(registry.get("../slot").is_none());
+    }
+
+    #[test]
+    fn test_accepted_sizes() {
+        let slot = leaderboard();
+        assert_eq!(
+            accepted_sizes(&slot, &[(970, 250), (300, 250)]),
+            vec![(970, 250)]
+        );
+        assert_eq!(accepted_sizes(&slot, &[(300, 250)]), slot.sizes);
+        assert_eq!(accepted_sizes(&slot, &[]), slot.sizes);
+    }
+
+    #[test]
+    fn test_registered_slot_from_toml() {
+        let toml = r#"
+            [[slot_registry.slots]]
+            code = "homepage-leaderboard"
+            sizes = [[728, 90], [970, 250]]
+            gam_ad_unit = "/3790/trustedserver/homepage"
+            [slot_registry.slots.bidders.smartadserver]
+            siteId = 686105
+            formatId = 137675
+        "#;
+        let settings = Settings::from_toml(&format!(
+            "{}{}",
+            crate::test_support::tests::crate_test_settings_str(),
+            toml
+        ))
+        .unwrap();
+        assert_eq!(settings.slot_registry.slots, vec![leaderboard()]);
+    }
+}
@@ -0,0 +1,189 @@
+//! Stored slot configurations for `/auction`.
+//!
+//! Like Prebid Server stored imps, the full configuration of a slot (sizes,
+//! position, video or native settings) can be kept in the KV store named in
+//! `prebid.stored_requests_kv_store`, as JSON under `imp:<id>`. The page then
+//! only sends `{"storedrequest": "<id>"}` for the slot and
+//! [`StoredRequests::expand`] replaces it with the stored [`AdSlot`]. Pages
+//! cannot request slot configurations the publisher did not store.
+
+use error_stack::Report;
+use serde::Deserialize;
+
+use crate::error::TrustedServerError;
+use crate::kv_store::JsonKvStore;
+use crate::prebid::AdSlot;
+use crate::settings::Settings;
+
+/// Longest accepted stored request ID.
+const MAX_ID_LENGTH: usize = 64;
+
+/// Reference to a stored slot configuration, sent in place of a slot
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StoredSlot {
+    /// ID of the stored slot configuration
+    pub storedrequest: String,
+    /// Slot code replacing the stored one, for a configuration shared by
+    /// several slots of a page
+    #[serde(default)]
+    pub code: Option<String>,
+}
+
+/// A slot of a `/auction` request: either sent in full or stored
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum SlotEntry {
+    /// Reference to a stored slot configuration
+    Stored(StoredSlot),
+    /// Full slot configuration
+    Slot(AdSlot),
+}
+
+/// Whether `id` is a valid stored request ID: 1 to 64 ASCII letters, digits,
+/// `-`, `_` or `.`.
+pub(crate) fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LENGTH
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+/// Stored slot configurations in `prebid.stored_requests_kv_store`.
+pub struct StoredRequests {
+    store: JsonKvStore,
+}
+
+impl StoredRequests {
+    /// Opens the KV store in `prebid.stored_requests_kv_store`.
+    ///
+    /// Returns [`None`] when stored requests are disabled.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the configured store cannot be opened
+    pub fn open(settings: &Settings) -> Result<Option<Self>, Report<TrustedServerError>> {
+        let name = &settings.prebid.stored_requests_kv_store;
+        if name.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            store: JsonKvStore::open(name)?,
+        }))
+    }
+
+    fn key(id: &str) -> String {
+        format!("imp:{id}")
+    }
+
+    /// Returns the slot configuration stored under `id`, or [`None`] if there
+    /// is none.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the lookup fails or the stored
+    ///   value is not a slot
+    pub fn get(&self, id: &str) -> Result<Option<AdSlot>, Report<TrustedServerError>> {
+        if !is_valid_id(id) {
+            return Ok(None);
+        }
+        self.store.get(&Self::key(id))
+    }
+
+    /// Replaces every stored slot reference in `entries` with its stored
+    /// configuration, keeping slots sent in full.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::Prebid`] if a referenced configuration does not exist
+    /// - [`TrustedServerError::KvStore`] if a lookup fails
+    pub fn expand(
+        stored: Option<&Self>,
+        entries: Vec<SlotEntry>,
+    ) -> Result<Vec<AdSlot>, Report<TrustedServerError>> {
+        entries
+            .into_iter()
+            .map(|entry| {
+                let reference = match entry {
+                    SlotEntry::Slot(slot) => return Ok(slot),
+                    SlotEntry::Stored(reference) => reference,
+                };
+                let not_found = || {
+                    Report::new(TrustedServerError::Prebid {
+                        message: format!("Unknown stored request {}", reference.storedrequest),
+                    })
+                };
+                let mut slot = match stored {
+                    Some(stored) => stored.get(&reference.storedrequest)?,
+                    None => None,
+                }
+                .ok_or_else(not_found)?;
+                if let Some(code) = reference.code {
+                    slot.code = code;
+                }
+                Ok(slot)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prebid::SlotPosition;
+    use crate::test_support::tests::create_test_settings;
+
+    fn stored_requests() -> StoredRequests {
+        let mut settings = create_test_settings();
+        settings.prebid.stored_requests_kv_store = "test_stored_request_store".to_string();
+        StoredRequests::open(&settings).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_is_valid_id() {
+        assert!(is_valid_id("homepage-top"));
+        assert!(is_valid_id("article_v2.sidebar"));
+        assert!(!is_valid_id(""));
+        assert!(!is_valid_id("../imp"));
+        assert!(!is_valid_id(&"a".repeat(MAX_ID_LENGTH + 1)));
+    }
+
+    #[test]
+    fn test_expand() {
+        let stored = stored_requests();
+        let entries: Vec<SlotEntry> = serde_json::from_str(
+            r#"[
+                { "storedrequest": "homepage-top" },
+                { "storedrequest": "homepage-top", "code": "div-gpt-ad-bottom" },
+                { "code": "side", "sizes": [[300, 250]] }
+            ]"#,
+        )
+        .unwrap();
+        let slots = StoredRequests::expand(Some(&stored), entries).unwrap();
+        assert_eq!(slots.len(), 3);
+        assert_eq!(slots[0].code, "div-gpt-ad-top");
+        assert_eq!(slots[0].sizes, vec![(728, 90), (970, 250)]);
+        assert_eq!(slots[0].position, SlotPosition::AboveTheFold);
+        assert_eq!(slots[1].code, "div-gpt-ad-bottom");
+        assert_eq!(slots[1].sizes, slots[0].sizes);
+        assert_eq!(slots[2].code, "side");
+    }
+
+    #[test]
+    fn test_expand_unknown() {
+        let entries = || {
+            vec![SlotEntry::Stored(StoredSlot {
+                storedrequest: "homepage-missing".to_string(),
+                code: None,
+            })]
+        };
+        let stored = stored_requests();
+        let err = StoredRequests::expand(Some(&stored), entries()).unwrap_err();
+        assert!(matches!(
+            err.current_context(),
+            TrustedServerError::Prebid { .. }
+        ));
+        assert!(StoredRequests::expand(None, entries()).is_err());
+    }
+}
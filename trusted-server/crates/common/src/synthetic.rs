@@ -3,34 +3,199 @@
 //! This module provides functionality for generating privacy-preserving synthetic IDs
 //! based on various request parameters and a secret key.
 
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
 use error_stack::{Report, ResultExt};
-use fastly::http::header;
-use fastly::Request;
+use fastly::http::{header, Method, StatusCode};
+use fastly::{Error, Request, Response};
 use handlebars::Handlebars;
-use hmac::{Hmac, Mac};
+use hmac::Mac;
+use serde::Serialize;
 use serde_json::json;
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
 
-use crate::constants::{HEADER_SYNTHETIC_PUB_USER_ID, HEADER_SYNTHETIC_TRUSTED_SERVER};
-use crate::cookies::handle_request_cookies;
+use crate::auth::authenticate;
+use crate::compliance_log::{AccessEvent, Actor};
+use crate::constants::{HEADER_DNT, HEADER_SYNTHETIC_PUB_USER_ID, HEADER_SYNTHETIC_TRUSTED_SERVER};
+use crate::cookies::{
+    get_synthetic_cookie, handle_request_cookies, sign_value, verify_value, SYNTHETIC_COOKIE,
+};
+use crate::erasure::is_tombstoned;
 use crate::error::TrustedServerError;
+use crate::identity::find_linked_synthetic_id;
 use crate::settings::Settings;
+use crate::signing::{keyed_mac, HmacSha256};
 
-type HmacSha256 = Hmac<Sha256>;
+/// Separator between the fields of a signed synthetic ID cookie value.
+const SIGNATURE_SEPARATOR: char = '.';
 
-/// Generates a fresh synthetic ID based on request parameters.
+/// Number of seconds in a day, used to convert the configured retention window.
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Template fields made available to the synthetic ID template.
+pub(crate) const TEMPLATE_FIELDS: &[&str] = &[
+    "client_ip",
+    "user_agent",
+    "first_party_id",
+    "auth_user_id",
+    "publisher_domain",
+    "accept_language",
+];
+
+/// Where a resolved synthetic ID came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyntheticIdSource {
+    /// Taken from the `X-Synthetic-Trusted-Server` request header.
+    Header,
+    /// Taken from a valid, unexpired `synthetic_id` cookie.
+    Cookie,
+    /// Linked to the request's publisher user ID in the identity store.
+    Linked,
+    /// Freshly generated from the request signals.
+    Fresh,
+}
+
+/// A synthetic ID together with the time it was first issued.
 ///
-/// Creates a deterministic ID using HMAC-SHA256 with the configured secret key
-/// and various request attributes including IP, user agent, cookies, and headers.
+/// The issue time travels with the ID inside the signed `synthetic_id` cookie so
+/// that IDs can be retired once they exceed the configured retention window.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntheticId {
+    /// The synthetic ID value.
+    pub value: String,
+    /// Unix timestamp (seconds) when the ID was first issued.
+    pub issued_at: i64,
+    /// Where the ID was resolved from.
+    pub source: SyntheticIdSource,
+}
+
+impl SyntheticId {
+    /// Creates a freshly generated [`SyntheticId`] issued at the current time.
+    pub fn new(value: String) -> Self {
+        Self {
+            value,
+            issued_at: chrono::Utc::now().timestamp(),
+            source: SyntheticIdSource::Fresh,
+        }
+    }
+
+    /// Returns the number of seconds this ID may still be used at `now`.
+    ///
+    /// Returns zero once the ID is older than `synthetic.max_age_days`.
+    pub fn remaining_lifetime(&self, settings: &Settings, now: i64) -> i64 {
+        let max_age = i64::from(settings.synthetic.max_age_days) * SECONDS_PER_DAY;
+        (self.issued_at + max_age - now).max(0)
+    }
+
+    /// Checks whether the server can vouch for this ID: it generated it, or
+    /// read it back from a `synthetic_id` cookie whose signature verified.
+    ///
+    /// IDs claimed through the `X-Synthetic-Trusted-Server` header or an
+    /// identity link are not proof that the requester owns them.
+    pub fn is_verified(&self) -> bool {
+        matches!(
+            self.source,
+            SyntheticIdSource::Cookie | SyntheticIdSource::Fresh
+        )
+    }
+
+    /// Checks whether the requester has proven they hold this ID by presenting
+    /// it in a `synthetic_id` cookie whose signature verified.
+    ///
+    /// Unlike [`is_verified`](Self::is_verified), freshly generated IDs do not
+    /// qualify: they are derived from the client's IP prefix and headers, so
+    /// anyone sharing the same network and browser signals can recompute them.
+    pub fn is_cookie_backed(&self) -> bool {
+        self.source == SyntheticIdSource::Cookie
+    }
+
+    /// Checks whether this ID has exceeded the configured retention window.
+    pub fn is_expired(&self, settings: &Settings, now: i64) -> bool {
+        self.remaining_lifetime(settings, now) == 0
+    }
+}
+
+/// Signs a synthetic ID for storage in the `synthetic_id` cookie.
 ///
-/// # Errors
+/// Returns the ID and its issue time followed by a hex-encoded HMAC-SHA256 tag
+/// computed with [`sign_value`], e.g. `<id>.<issued_at>.<signature>`.
+/// Use [`verify_signed_synthetic_id`] to check the value when reading it back.
+pub fn sign_synthetic_id(settings: &Settings, synthetic_id: &SyntheticId) -> String {
+    sign_value(
+        settings,
+        SYNTHETIC_COOKIE,
+        &format!(
+            "{}{}{}",
+            synthetic_id.value, SIGNATURE_SEPARATOR, synthetic_id.issued_at
+        ),
+    )
+}
+
+/// Verifies a signed synthetic ID produced by [`sign_synthetic_id`].
 ///
-/// - [`TrustedServerError::Template`] if the template rendering fails
-/// - [`TrustedServerError::SyntheticId`] if HMAC generation fails
-pub fn generate_synthetic_id(
-    settings: &Settings,
-    req: &Request,
-) -> Result<String, Report<TrustedServerError>> {
+/// Returns the [`SyntheticId`] if the signature matches, or [`None`] if the
+/// value is malformed or has been tampered with.
+pub fn verify_signed_synthetic_id(settings: &Settings, signed_value: &str) -> Option<SyntheticId> {
+    let payload = verify_value(settings, SYNTHETIC_COOKIE, signed_value)?;
+    let (synthetic_id, issued_at) = payload.rsplit_once(SIGNATURE_SEPARATOR)?;
+    if synthetic_id.is_empty() {
+        return None;
+    }
+
+    Some(SyntheticId {
+        value: synthetic_id.to_string(),
+        issued_at: issued_at.parse().ok()?,
+        source: SyntheticIdSource::Cookie,
+    })
+}
+
+/// Reads the synthetic ID from the request's signed `synthetic_id` cookie.
+///
+/// Returns [`None`] if the request carries no such cookie or its signature does
+/// not verify. The returned ID is not checked for expiry or erasure.
+pub fn signed_cookie_id(settings: &Settings, req: &Request) -> Option<SyntheticId> {
+    let jar = handle_request_cookies(req).ok().flatten()?;
+    let cookie = get_synthetic_cookie(settings, &jar)?;
+    verify_signed_synthetic_id(settings, cookie.value())
+}
+
+/// Truncates a client IP address to the configured prefix length.
+///
+/// Keeps the leading `synthetic.ipv4_prefix_len` or `synthetic.ipv6_prefix_len`
+/// bits and zeroes the rest, so that e.g. a `/24` setting maps every address in
+/// a subnet to the same template input.
+pub fn truncate_ip(settings: &Settings, ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let prefix = u32::from(settings.synthetic.ipv4_prefix_len.min(32));
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+        }
+        IpAddr::V6(v6) => {
+            let prefix = u32::from(settings.synthetic.ipv6_prefix_len.min(128));
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+        }
+    }
+}
+
+/// Returns `true` if strict Do-Not-Track mode is on and the request sends `DNT: 1`.
+///
+/// Callers must then neither persist the synthetic ID nor count visits against it.
+pub fn is_dnt_enforced(settings: &Settings, req: &Request) -> bool {
+    settings.synthetic.honor_dnt
+        && req
+            .get_header_str(HEADER_DNT)
+            .is_some_and(|value| value.trim() == "1")
+}
+
+/// Collects the request signals that feed the synthetic ID template.
+///
+/// Applies [`truncate_ip`] to the client IP and removes every field listed in
+/// `synthetic.exclude_fields`.
+fn template_data(settings: &Settings, req: &Request) -> serde_json::Value {
     let user_agent = req
         .get_header(header::USER_AGENT)
         .map(|h| h.to_str().unwrap_or("unknown"));
@@ -44,14 +209,15 @@ pub fn generate_synthetic_id(
     let publisher_domain = req
         .get_header(header::HOST)
         .map(|h| h.to_str().unwrap_or("unknown"));
-    let client_ip = req.get_client_ip_addr().map(|ip| ip.to_string());
+    let client_ip = req
+        .get_client_ip_addr()
+        .map(|ip| truncate_ip(settings, ip).to_string());
     let accept_language = req
         .get_header(header::ACCEPT_LANGUAGE)
         .and_then(|h| h.to_str().ok())
         .map(|lang| lang.split(',').next().unwrap_or("unknown"));
 
-    let handlebars = Handlebars::new();
-    let data = &json!({
+    let mut data = json!({
         "client_ip": client_ip.unwrap_or("unknown".to_string()),
         "user_agent": user_agent.unwrap_or("unknown"),
         "first_party_id": first_party_id.unwrap_or("anonymous".to_string()),
@@ -59,43 +225,136 @@ pub fn generate_synthetic_id(
         "publisher_domain": publisher_domain.unwrap_or("unknown.com"),
         "accept_language": accept_language.unwrap_or("unknown")
     });
+    if let Some(fields) = data.as_object_mut() {
+        for excluded in &settings.synthetic.exclude_fields {
+            fields.remove(excluded.as_str());
+        }
+    }
+    data
+}
 
-    let input_string = handlebars
+/// Generates a fresh synthetic ID based on request parameters.
+///
+/// Creates a deterministic ID using HMAC-SHA256 with the configured secret key
+/// and various request attributes including IP, user agent, cookies, and headers.
+/// The client IP is truncated with [`truncate_ip`] and any fields listed in
+/// `synthetic.exclude_fields` are withheld from the template. The ID may be one
+/// whose data was erased; [`resolve_synthetic_id`] replaces those before a
+/// fresh ID is persisted.
+///
+/// # Errors
+///
+/// - [`TrustedServerError::Template`] if the template rendering fails
+/// - [`TrustedServerError::SyntheticId`] if HMAC generation fails
+pub fn generate_synthetic_id(
+    settings: &Settings,
+    req: &Request,
+) -> Result<String, Report<TrustedServerError>> {
+    generate(settings, req, None)
+}
+
+/// Generates the synthetic ID replacing `retired`, an ID that exceeded
+/// `synthetic.max_age_days`.
+///
+/// [`generate_synthetic_id`] is deterministic and would hand the retired ID
+/// back, so the retired ID's issue time is mixed into the template input to
+/// rotate it.
+///
+/// # Errors
+///
+/// - [`TrustedServerError::Template`] if the template rendering fails
+/// - [`TrustedServerError::SyntheticId`] if HMAC generation fails
+pub fn rotate_synthetic_id(
+    settings: &Settings,
+    req: &Request,
+    retired: &SyntheticId,
+) -> Result<String, Report<TrustedServerError>> {
+    generate(settings, req, Some(retired.issued_at))
+}
+
+/// Renders the synthetic ID template, mixing in the issue time of a retired
+/// ID when rotating one, and derives the ID from it.
+fn generate(
+    settings: &Settings,
+    req: &Request,
+    retired_at: Option<i64>,
+) -> Result<String, Report<TrustedServerError>> {
+    let handlebars = Handlebars::new();
+    let data = &template_data(settings, req);
+
+    let mut input_string = handlebars
         .render_template(&settings.synthetic.template, data)
         .change_context(TrustedServerError::Template {
             message: "Failed to render synthetic ID template".to_string(),
         })?;
+    if let Some(retired_at) = retired_at {
+        input_string.push_str(&format!("#retired:{retired_at}"));
+    }
+
+    let fresh_id = hmac_id(settings, &input_string)?;
+    log::info!("Generated fresh ID: {}", fresh_id);
 
-    log::info!("Input string for fresh ID: {} {}", input_string, data);
+    Ok(fresh_id)
+}
 
+/// Returns `fresh_id`, or a random ID in its place if its data was erased.
+///
+/// Request signals regenerate an erased ID, so it is checked against the
+/// tombstones once, right before a fresh ID is persisted. The replacement is
+/// random rather than derived from the request, so it cannot collide with
+/// another erased ID.
+///
+/// # Errors
+///
+/// - [`TrustedServerError::SyntheticId`] if HMAC generation fails
+pub fn replace_erased_id(
+    settings: &Settings,
+    fresh_id: String,
+) -> Result<String, Report<TrustedServerError>> {
+    if !is_tombstoned(settings, &fresh_id) {
+        return Ok(fresh_id);
+    }
+    log::info!("Fresh ID was erased, issuing a random ID instead");
+    hmac_id(settings, &format!("erased#{}", Uuid::new_v4()))
+}
+
+/// Computes the hex-encoded HMAC-SHA256 of a rendered template.
+fn hmac_id(settings: &Settings, input: &str) -> Result<String, Report<TrustedServerError>> {
     let mut mac = HmacSha256::new_from_slice(settings.synthetic.secret_key.as_bytes())
         .change_context(TrustedServerError::SyntheticId {
             message: "Failed to create HMAC instance".to_string(),
         })?;
-    mac.update(input_string.as_bytes());
-    let fresh_id = hex::encode(mac.finalize().into_bytes());
-
-    log::info!("Generated fresh ID: {}", fresh_id);
-
-    Ok(fresh_id)
+    mac.update(input.as_bytes());
+    Ok(hex::encode(mac.finalize().into_bytes()))
 }
 
-/// Gets or creates a synthetic ID from the request.
+/// Resolves the synthetic ID for a request, including its issue time.
 ///
 /// Attempts to retrieve an existing synthetic ID from:
 /// 1. The `X-Synthetic-Trusted-Server` header
-/// 2. The `synthetic_id` cookie
+/// 2. The identity link of the request's signed publisher user ID, if the
+///    linked ID has not exceeded `synthetic.max_age_days`
+/// 3. The `synthetic_id` cookie, if its signature is valid and it has not
+///    exceeded `synthetic.max_age_days`
 ///
-/// If neither exists, generates a new synthetic ID.
+/// If none applies, generates a new synthetic ID, replacing it if its data was
+/// erased (see [`replace_erased_id`]). Cookies whose signature does
+/// not verify are ignored so that a tampered value is replaced by a fresh ID, and
+/// expired cookies are retired so that the ID is rotated (see
+/// [`rotate_synthetic_id`]) with a new issue time.
+///
+/// A header ID has no issue time of its own: it keeps that of the verified
+/// cookie holding the same ID, and is otherwise treated as issued at the Unix
+/// epoch, so a claimed ID can never extend the retention window.
 ///
 /// # Errors
 ///
 /// - [`TrustedServerError::Template`] if template rendering fails during generation
 /// - [`TrustedServerError::SyntheticId`] if ID generation fails
-pub fn get_or_generate_synthetic_id(
+pub fn resolve_synthetic_id(
     settings: &Settings,
     req: &Request,
-) -> Result<String, Report<TrustedServerError>> {
+) -> Result<SyntheticId, Report<TrustedServerError>> {
     // First try to get existing Trusted Server ID from header
     if let Some(synthetic_id) = req
         .get_header(HEADER_SYNTHETIC_TRUSTED_SERVER)
@@ -103,16 +362,61 @@ pub fn get_or_generate_synthetic_id(
         .map(|s| s.to_string())
     {
         log::info!("Using existing Synthetic ID from header: {}", synthetic_id);
-        return Ok(synthetic_id);
+        let issued_at = signed_cookie_id(settings, req)
+            .filter(|cookie_id| cookie_id.value == synthetic_id)
+            .map_or(0, |cookie_id| cookie_id.issued_at);
+        return Ok(SyntheticId {
+            value: synthetic_id,
+            issued_at,
+            source: SyntheticIdSource::Header,
+        });
+    }
+
+    // Reuse the synthetic ID linked to this publisher user on another device,
+    // so the user's devices converge on one ID
+    match find_linked_synthetic_id(settings, req) {
+        Ok(Some(linked)) if linked.is_expired(settings, chrono::Utc::now().timestamp()) => {
+            log::info!("Ignoring expired Synthetic ID linked to publisher user");
+        }
+        Ok(Some(linked)) if is_tombstoned(settings, &linked.value) => {
+            log::info!("Ignoring erased Synthetic ID linked to publisher user");
+        }
+        Ok(Some(linked)) => {
+            log::info!(
+                "Using Synthetic ID linked to publisher user: {}",
+                linked.value
+            );
+            return Ok(linked);
+        }
+        Ok(None) => {}
+        Err(e) => log::warn!("Failed to look up linked Synthetic ID: {:?}", e),
     }
 
     // Try to get synthetic ID from cookies
+    let mut retired = None;
     match handle_request_cookies(req)? {
         Some(jar) => {
-            if let Some(cookie) = jar.get("synthetic_id") {
-                let id = cookie.value().to_string();
-                log::info!("Using existing Trusted Server ID from cookie: {}", id);
-                return Ok(id);
+            if let Some(cookie) = get_synthetic_cookie(settings, &jar) {
+                match verify_signed_synthetic_id(settings, cookie.value()) {
+                    Some(id) if id.is_expired(settings, chrono::Utc::now().timestamp()) => {
+                        log::info!(
+                            "Retiring expired Trusted Server ID issued at {}: {}",
+                            id.issued_at,
+                            id.value
+                        );
+                        retired = Some(id);
+                    }
+                    Some(id) if is_tombstoned(settings, &id.value) => {
+                        log::info!("Retiring erased Trusted Server ID: {}", id.value);
+                    }
+                    Some(id) => {
+                        log::info!("Using existing Trusted Server ID from cookie: {}", id.value);
+                        return Ok(id);
+                    }
+                    None => {
+                        log::warn!("Ignoring synthetic_id cookie with invalid signature");
+                    }
+                }
             }
         }
         None => {
@@ -121,12 +425,124 @@ pub fn get_or_generate_synthetic_id(
     }
 
     // If no existing Synthetic ID found, generate a fresh one
-    let fresh_id = generate_synthetic_id(settings, req)?;
+    let fresh_id = match &retired {
+        Some(retired) => rotate_synthetic_id(settings, req, retired)?,
+        None => generate_synthetic_id(settings, req)?,
+    };
+    let fresh_id = replace_erased_id(settings, fresh_id)?;
     log::info!(
         "No existing Synthetic ID found, using fresh ID: {}",
         fresh_id
     );
-    Ok(fresh_id)
+    Ok(SyntheticId::new(fresh_id))
+}
+
+/// Gets or creates a synthetic ID from the request.
+///
+/// Convenience wrapper around [`resolve_synthetic_id`] for callers that only
+/// need the ID value.
+///
+/// # Errors
+///
+/// - [`TrustedServerError::Template`] if template rendering fails during generation
+/// - [`TrustedServerError::SyntheticId`] if ID generation fails
+pub fn get_or_generate_synthetic_id(
+    settings: &Settings,
+    req: &Request,
+) -> Result<String, Report<TrustedServerError>> {
+    resolve_synthetic_id(settings, req).map(|id| id.value)
+}
+
+/// Derives the ID `partner` knows the user with `synthetic_id` by.
+///
+/// Each partner gets a different, stable HMAC of the synthetic ID, so partners
+/// cannot join their user data on a shared identifier.
+pub fn partner_scoped_id(settings: &Settings, synthetic_id: &str, partner: &str) -> String {
+    let mac = keyed_mac(
+        settings.synthetic.secret_key.as_bytes(),
+        format!("partner-id:{partner}:").as_bytes(),
+        &[synthetic_id.as_bytes()],
+    );
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Returns a short, non-reversible identifier for the configured secret key.
+///
+/// Lets operators confirm which key version produced an ID (e.g. after a
+/// rotation) without exposing the key itself.
+pub fn key_version(settings: &Settings) -> String {
+    let digest = Sha256::digest(settings.synthetic.secret_key.as_bytes());
+    hex::encode(&digest[..4])
+}
+
+/// Redacts a signal value for display, keeping only a short prefix and its length.
+fn redact(value: &str) -> String {
+    let prefix: String = value.chars().take(3).collect();
+    format!("{}… ({} chars)", prefix, value.chars().count())
+}
+
+/// Handles the `/debug/synthetic-id` endpoint.
+///
+/// Explains how the synthetic ID for the current request was resolved: which
+/// source won in [`resolve_synthetic_id`], which template signals contributed
+/// (with redacted values), and the [`key_version`] in use. The endpoint
+/// requires an operator credential checked by [`authenticate`] and is disabled
+/// while none is configured.
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if response creation fails.
+pub fn handle_synthetic_id_debug(settings: &Settings, req: Request) -> Result<Response, Error> {
+    if *req.get_method() != Method::GET {
+        return Ok(
+            Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_body("Method not allowed")
+        );
+    }
+    let principal = match authenticate(settings, &req) {
+        Ok(principal) => principal,
+        Err(e) => return Ok(e.into_response()),
+    };
+
+    let data = template_data(settings, &req);
+    let signals: Vec<_> = TEMPLATE_FIELDS
+        .iter()
+        .map(|&name| {
+            let value = data.get(name).and_then(|v| v.as_str());
+            json!({
+                "name": name,
+                "included": value.is_some(),
+                "in_template": settings.synthetic.template.contains(name),
+                "value": value.map(redact),
+            })
+        })
+        .collect();
+
+    let resolved =
+        resolve_synthetic_id(settings, &req).map_err(|e| Error::msg(format!("{e:?}")))?;
+    let fresh_id =
+        generate_synthetic_id(settings, &req).map_err(|e| Error::msg(format!("{e:?}")))?;
+    let now = chrono::Utc::now().timestamp();
+    AccessEvent::new(
+        &req,
+        "debug_synthetic_id",
+        Actor::from(&principal),
+        Some(&resolved.value),
+    )
+    .record(settings, StatusCode::OK);
+
+    Ok(Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_header(header::CACHE_CONTROL, "no-store, private")
+        .with_body_json(&json!({
+            "synthetic_id": resolved.value,
+            "source": resolved.source,
+            "issued_at": resolved.issued_at,
+            "remaining_lifetime": resolved.remaining_lifetime(settings, now),
+            "fresh_id": fresh_id,
+            "matches_fresh": resolved.value == fresh_id,
+            "key_version": key_version(settings),
+            "signals": signals,
+        }))?)
 }
 
 #[cfg(test)]
@@ -135,6 +551,7 @@ mod tests {
     use fastly::http::{HeaderName, HeaderValue};
 
     use crate::constants::HEADER_X_PUB_USER_ID;
+    use crate::erasure::TombstoneStore;
     use crate::test_support::tests::create_test_settings;
 
     fn create_test_request(headers: Vec<(HeaderName, &str)>) -> Request {
@@ -149,6 +566,19 @@ mod tests {
         req
     }
 
+    #[test]
+    fn test_partner_scoped_id() {
+        let settings = create_test_settings();
+        let id = partner_scoped_id(&settings, "synthetic-1", "prebid");
+        assert_eq!(id.len(), 64);
+        assert_eq!(id, partner_scoped_id(&settings, "synthetic-1", "prebid"));
+        assert_ne!(
+            id,
+            partner_scoped_id(&settings, "synthetic-1", "example-ssp")
+        );
+        assert_ne!(id, partner_scoped_id(&settings, "synthetic-2", "prebid"));
+    }
+
     #[test]
     fn test_generate_synthetic_id() {
         let settings: Settings = create_test_settings();
@@ -169,6 +599,85 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_resolve_synthetic_id_replaces_erased_id() {
+        let mut settings: Settings = create_test_settings();
+        settings.gdpr.tombstone_store = "test_tombstone_store".to_string();
+        let req = create_test_request(vec![
+            (header::USER_AGENT, "Erased/1.0"),
+            (header::HOST, settings.publisher.domain.as_str()),
+        ]);
+
+        let erased_id =
+            generate_synthetic_id(&settings, &req).expect("should generate synthetic ID");
+        assert_eq!(
+            resolve_synthetic_id(&settings, &req)
+                .expect("should resolve synthetic ID")
+                .value,
+            erased_id
+        );
+        TombstoneStore::open(&settings)
+            .expect("should open the tombstone store")
+            .expect("should have a tombstone store configured")
+            .add(&erased_id)
+            .expect("should add the tombstone");
+
+        // Generation stays deterministic, but the erased ID is never issued
+        assert_eq!(
+            generate_synthetic_id(&settings, &req).expect("should generate synthetic ID"),
+            erased_id
+        );
+        let resolved = resolve_synthetic_id(&settings, &req).expect("should resolve synthetic ID");
+        assert_eq!(resolved.source, SyntheticIdSource::Fresh);
+        assert_ne!(resolved.value, erased_id);
+        assert_eq!(resolved.value.len(), 64);
+    }
+
+    #[test]
+    fn test_generate_synthetic_id_with_excluded_field() {
+        let mut settings: Settings = create_test_settings();
+        settings.synthetic.exclude_fields = vec!["user_agent".to_string()];
+        let req_a = create_test_request(vec![(header::USER_AGENT, "Mozilla/5.0")]);
+        let req_b = create_test_request(vec![(header::USER_AGENT, "curl/8.0")]);
+
+        let id_a = generate_synthetic_id(&settings, &req_a).expect("should generate synthetic ID");
+        let id_b = generate_synthetic_id(&settings, &req_b).expect("should generate synthetic ID");
+        assert_eq!(id_a, id_b);
+
+        settings.synthetic.exclude_fields.clear();
+        let id_c = generate_synthetic_id(&settings, &req_b).expect("should generate synthetic ID");
+        assert_ne!(id_a, id_c);
+    }
+
+    #[test]
+    fn test_truncate_ip() {
+        let mut settings = create_test_settings();
+        let v4: IpAddr = "192.168.17.42".parse().unwrap();
+        let v6: IpAddr = "2001:db8:abcd:12:1:2:3:4".parse().unwrap();
+
+        assert_eq!(truncate_ip(&settings, v4), v4);
+        assert_eq!(truncate_ip(&settings, v6), v6);
+
+        settings.synthetic.ipv4_prefix_len = 24;
+        settings.synthetic.ipv6_prefix_len = 48;
+        assert_eq!(
+            truncate_ip(&settings, v4),
+            "192.168.17.0".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            truncate_ip(&settings, v6),
+            "2001:db8:abcd::".parse::<IpAddr>().unwrap()
+        );
+
+        settings.synthetic.ipv4_prefix_len = 0;
+        settings.synthetic.ipv6_prefix_len = 0;
+        assert_eq!(
+            truncate_ip(&settings, v4),
+            "0.0.0.0".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(truncate_ip(&settings, v6), "::".parse::<IpAddr>().unwrap());
+    }
+
     #[test]
     fn test_get_or_generate_synthetic_id_with_header() {
         let settings = create_test_settings();
@@ -180,18 +689,187 @@ mod tests {
         let synthetic_id = get_or_generate_synthetic_id(&settings, &req)
             .expect("should get or generate synthetic ID");
         assert_eq!(synthetic_id, "existing_synthetic_id");
+
+        let resolved = resolve_synthetic_id(&settings, &req).expect("should resolve synthetic ID");
+        assert_eq!(resolved.source, SyntheticIdSource::Header);
     }
 
     #[test]
     fn test_get_or_generate_synthetic_id_with_cookie() {
         let settings = create_test_settings();
-        let req = create_test_request(vec![(header::COOKIE, "synthetic_id=existing_cookie_id")]);
+        let cookie = format!(
+            "synthetic_id={}",
+            sign_synthetic_id(
+                &settings,
+                &SyntheticId::new("existing_cookie_id".to_string())
+            )
+        );
+        let req = create_test_request(vec![(header::COOKIE, cookie.as_str())]);
 
         let synthetic_id = get_or_generate_synthetic_id(&settings, &req)
             .expect("should get or generate synthetic ID");
         assert_eq!(synthetic_id, "existing_cookie_id");
     }
 
+    #[test]
+    fn test_get_or_generate_synthetic_id_with_unsigned_cookie() {
+        let settings = create_test_settings();
+        let req = create_test_request(vec![(header::COOKIE, "synthetic_id=existing_cookie_id")]);
+
+        let synthetic_id = get_or_generate_synthetic_id(&settings, &req)
+            .expect("should get or generate synthetic ID");
+        assert_ne!(synthetic_id, "existing_cookie_id");
+        assert!(!synthetic_id.is_empty());
+    }
+
+    #[test]
+    fn test_get_or_generate_synthetic_id_with_tampered_cookie() {
+        let settings = create_test_settings();
+        let id = SyntheticId::new("existing_cookie_id".to_string());
+        let signed = sign_synthetic_id(&settings, &id);
+        let (_, signature) = signed.rsplit_once('.').expect("should contain a signature");
+        let cookie = format!(
+            "synthetic_id=forged_cookie_id.{}.{}",
+            id.issued_at, signature
+        );
+        let req = create_test_request(vec![(header::COOKIE, cookie.as_str())]);
+
+        let synthetic_id = get_or_generate_synthetic_id(&settings, &req)
+            .expect("should get or generate synthetic ID");
+        assert_ne!(synthetic_id, "forged_cookie_id");
+    }
+
+    #[test]
+    fn test_get_or_generate_synthetic_id_with_expired_cookie() {
+        let settings = create_test_settings();
+        let max_age = i64::from(settings.synthetic.max_age_days) * SECONDS_PER_DAY;
+        // The expired cookie holds the ID the request's signals generate
+        let deterministic_id = generate_synthetic_id(&settings, &create_test_request(vec![]))
+            .expect("should generate synthetic ID");
+        let expired = SyntheticId {
+            value: deterministic_id.clone(),
+            issued_at: chrono::Utc::now().timestamp() - max_age - 1,
+            source: SyntheticIdSource::Cookie,
+        };
+        let cookie = format!("synthetic_id={}", sign_synthetic_id(&settings, &expired));
+        let req = create_test_request(vec![(header::COOKIE, cookie.as_str())]);
+        assert_eq!(
+            generate_synthetic_id(&settings, &req).expect("should generate synthetic ID"),
+            deterministic_id
+        );
+
+        let synthetic_id =
+            resolve_synthetic_id(&settings, &req).expect("should resolve synthetic ID");
+        assert_ne!(synthetic_id.value, deterministic_id);
+        assert!(synthetic_id.issued_at > expired.issued_at);
+        assert_eq!(synthetic_id.source, SyntheticIdSource::Fresh);
+    }
+
+    #[test]
+    fn test_resolve_synthetic_id_header_keeps_cookie_issued_at() {
+        let settings = create_test_settings();
+        let id = SyntheticId {
+            value: "existing_cookie_id".to_string(),
+            issued_at: chrono::Utc::now().timestamp() - SECONDS_PER_DAY,
+            source: SyntheticIdSource::Cookie,
+        };
+        let cookie = format!("synthetic_id={}", sign_synthetic_id(&settings, &id));
+        let req = create_test_request(vec![
+            (header::COOKIE, cookie.as_str()),
+            (HEADER_SYNTHETIC_TRUSTED_SERVER, "existing_cookie_id"),
+        ]);
+        let synthetic_id =
+            resolve_synthetic_id(&settings, &req).expect("should resolve synthetic ID");
+        assert_eq!(synthetic_id.source, SyntheticIdSource::Header);
+        assert_eq!(synthetic_id.issued_at, id.issued_at);
+
+        // A claimed ID never starts a new retention window
+        let req = create_test_request(vec![(HEADER_SYNTHETIC_TRUSTED_SERVER, "claimed_id")]);
+        let synthetic_id =
+            resolve_synthetic_id(&settings, &req).expect("should resolve synthetic ID");
+        assert!(synthetic_id.is_expired(&settings, chrono::Utc::now().timestamp()));
+    }
+
+    #[test]
+    fn test_resolve_synthetic_id_keeps_issued_at() {
+        let settings = create_test_settings();
+        let id = SyntheticId {
+            value: "existing_cookie_id".to_string(),
+            issued_at: chrono::Utc::now().timestamp() - SECONDS_PER_DAY,
+            source: SyntheticIdSource::Cookie,
+        };
+        let cookie = format!("synthetic_id={}", sign_synthetic_id(&settings, &id));
+        let req = create_test_request(vec![(header::COOKIE, cookie.as_str())]);
+
+        let synthetic_id =
+            resolve_synthetic_id(&settings, &req).expect("should resolve synthetic ID");
+        assert_eq!(synthetic_id, id);
+        assert_eq!(synthetic_id.source, SyntheticIdSource::Cookie);
+    }
+
+    #[test]
+    fn test_synthetic_id_remaining_lifetime() {
+        let settings = create_test_settings();
+        let max_age = i64::from(settings.synthetic.max_age_days) * SECONDS_PER_DAY;
+        let id = SyntheticId {
+            value: "abc123".to_string(),
+            issued_at: 1_000,
+            source: SyntheticIdSource::Fresh,
+        };
+
+        assert_eq!(id.remaining_lifetime(&settings, 1_000), max_age);
+        assert_eq!(id.remaining_lifetime(&settings, 1_100), max_age - 100);
+        assert!(!id.is_expired(&settings, 1_000 + max_age - 1));
+        assert!(id.is_expired(&settings, 1_000 + max_age));
+    }
+
+    #[test]
+    fn test_verify_signed_synthetic_id() {
+        let settings = create_test_settings();
+        let id = SyntheticId {
+            value: "abc123".to_string(),
+            issued_at: 1_700_000_000,
+            source: SyntheticIdSource::Cookie,
+        };
+        let signed = sign_synthetic_id(&settings, &id);
+
+        assert_eq!(verify_signed_synthetic_id(&settings, &signed), Some(id));
+        assert_eq!(verify_signed_synthetic_id(&settings, "abc123"), None);
+        assert_eq!(verify_signed_synthetic_id(&settings, "abc123.zz"), None);
+        assert_eq!(verify_signed_synthetic_id(&settings, ".1.deadbeef"), None);
+
+        let (payload, signature) = signed.rsplit_once('.').expect("should contain a signature");
+        let (value, _) = payload
+            .rsplit_once('.')
+            .expect("should contain an issue time");
+        let backdated = format!("{}.{}.{}", value, 1, signature);
+        assert_eq!(verify_signed_synthetic_id(&settings, &backdated), None);
+    }
+
+    #[test]
+    fn test_verify_signed_synthetic_id_wrong_key() {
+        let settings = create_test_settings();
+        let signed = sign_synthetic_id(&settings, &SyntheticId::new("abc123".to_string()));
+
+        let mut other_settings = create_test_settings();
+        other_settings.synthetic.secret_key = "another-secret-key".to_string();
+        assert_eq!(verify_signed_synthetic_id(&other_settings, &signed), None);
+    }
+
+    #[test]
+    fn test_is_dnt_enforced() {
+        let mut settings = create_test_settings();
+        let req = create_test_request(vec![(HEADER_DNT, "1")]);
+        assert!(!is_dnt_enforced(&settings, &req));
+
+        settings.synthetic.honor_dnt = true;
+        assert!(is_dnt_enforced(&settings, &req));
+
+        let req = create_test_request(vec![(HEADER_DNT, "0")]);
+        assert!(!is_dnt_enforced(&settings, &req));
+        assert!(!is_dnt_enforced(&settings, &create_test_request(vec![])));
+    }
+
     #[test]
     fn test_get_or_generate_synthetic_id_generate_new() {
         let settings = create_test_settings();
@@ -201,4 +879,57 @@ mod tests {
             .expect("should get or generate synthetic ID");
         assert!(!synthetic_id.is_empty());
     }
+
+    #[test]
+    fn test_handle_synthetic_id_debug_disabled_without_token() {
+        let settings = create_test_settings();
+        let req = Request::get("https://example.com/debug/synthetic-id");
+
+        let response = handle_synthetic_id_debug(&settings, req).unwrap();
+        assert_eq!(response.get_status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_handle_synthetic_id_debug_requires_token() {
+        let mut settings = create_test_settings();
+        settings.debug.auth_token = "debug-token".to_string();
+        let req = Request::get("https://example.com/debug/synthetic-id")
+            .with_header(header::AUTHORIZATION, "Bearer wrong-token");
+
+        let response = handle_synthetic_id_debug(&settings, req).unwrap();
+        assert_eq!(response.get_status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_handle_synthetic_id_debug() {
+        let mut settings = create_test_settings();
+        settings.debug.auth_token = "debug-token".to_string();
+        settings.synthetic.exclude_fields = vec!["accept_language".to_string()];
+        let req = create_test_request(vec![
+            (header::AUTHORIZATION, "Bearer debug-token"),
+            (header::USER_AGENT, "Mozilla/5.0"),
+        ]);
+
+        let response = handle_synthetic_id_debug(&settings, req).unwrap();
+        assert_eq!(response.get_status(), StatusCode::OK);
+
+        let body: serde_json::Value = serde_json::from_str(&response.into_body_str()).unwrap();
+        assert_eq!(body["source"], "fresh");
+        assert_eq!(body["matches_fresh"], true);
+        assert_eq!(body["key_version"], key_version(&settings));
+
+        let signals = body["signals"].as_array().expect("should list signals");
+        let user_agent = signals
+            .iter()
+            .find(|s| s["name"] == "user_agent")
+            .expect("should include user_agent");
+        assert_eq!(user_agent["included"], true);
+        assert_eq!(user_agent["value"], "Moz… (11 chars)");
+        let accept_language = signals
+            .iter()
+            .find(|s| s["name"] == "accept_language")
+            .expect("should include accept_language");
+        assert_eq!(accept_language["included"], false);
+        assert!(accept_language["value"].is_null());
+    }
 }
@@ -0,0 +1,182 @@
+//! Ad server targeting keys for winning bids.
+//!
+//! Converts a [`WinningBid`] into the standard Prebid key-values the ad server
+//! line items match on: `hb_pb` (the price rounded down to the configured
+//! [`PriceGranularity`]), `hb_bidder`, `hb_adid` and `hb_size`, each also sent
+//! with a `_<bidder>` suffix. [`crate::gam_builder::CustParamsBuilder`]
+//! encodes them for the `cust_params` parameter of a GAM ad request.
+
+use std::collections::BTreeMap;
+
+use crate::prebid::WinningBid;
+use crate::settings::{PriceBucket, PriceGranularity, Settings};
+
+/// Targeting key of the price bucket.
+pub const KEY_PRICE: &str = "hb_pb";
+/// Targeting key of the winning bidder code.
+pub const KEY_BIDDER: &str = "hb_bidder";
+/// Targeting key of the winning bid ID.
+pub const KEY_AD_ID: &str = "hb_adid";
+/// Targeting key of the creative size, as `<width>x<height>`.
+pub const KEY_SIZE: &str = "hb_size";
+
+/// GAM truncates key names beyond 20 characters.
+pub(crate) const MAX_KEY_LENGTH: usize = 20;
+
+/// Tolerance for float division landing just below a bucket boundary.
+const EPSILON: f64 = 1e-9;
+
+const fn bucket(min: f64, max: f64, increment: f64) -> PriceBucket {
+    PriceBucket {
+        min,
+        max,
+        increment,
+    }
+}
+
+const LOW: &[PriceBucket] = &[bucket(0.0, 5.0, 0.5)];
+const MEDIUM: &[PriceBucket] = &[bucket(0.0, 20.0, 0.1)];
+const HIGH: &[PriceBucket] = &[bucket(0.0, 20.0, 0.01)];
+const AUTO: &[PriceBucket] = &[
+    bucket(0.0, 5.0, 0.05),
+    bucket(5.0, 10.0, 0.1),
+    bucket(10.0, 20.0, 0.5),
+];
+const DENSE: &[PriceBucket] = &[
+    bucket(0.0, 3.0, 0.01),
+    bucket(3.0, 8.0, 0.05),
+    bucket(8.0, 20.0, 0.5),
+];
+
+/// Price buckets of the configured granularity.
+fn price_buckets(settings: &Settings) -> &[PriceBucket] {
+    match settings.targeting.price_granularity {
+        PriceGranularity::Low => LOW,
+        PriceGranularity::Medium => MEDIUM,
+        PriceGranularity::High => HIGH,
+        PriceGranularity::Auto => AUTO,
+        PriceGranularity::Dense => DENSE,
+        PriceGranularity::Custom => &settings.targeting.price_buckets,
+    }
+}
+
+/// Rounds `price` down to its bucket in `buckets`, formatted with two decimals.
+///
+/// Prices above the highest bucket are capped at its maximum; prices outside
+/// every bucket, including those below the lowest, give `0.00`.
+pub fn price_bucket(price: f64, buckets: &[PriceBucket]) -> String {
+    let cap = buckets.iter().map(|b| b.max).fold(f64::NAN, f64::max);
+    if price >= cap {
+        return format!("{cap:.2}");
+    }
+    let value = buckets
+        .iter()
+        .find(|b| b.increment > 0.0 && price >= b.min && price < b.max)
+        .map(|b| b.min + ((price - b.min) / b.increment + EPSILON).floor() * b.increment)
+        .unwrap_or(0.0);
+    format!("{value:.2}")
+}
+
+/// The standard `hb_*` keys of `bid`, followed by their `_<bidder>` copies.
+pub fn targeting_keys(settings: &Settings, bid: &WinningBid) -> BTreeMap<String, String> {
+    let mut keys = BTreeMap::from([
+        (
+            KEY_PRICE.to_string(),
+            price_bucket(bid.price, price_buckets(settings)),
+        ),
+        (KEY_BIDDER.to_string(), bid.bidder.clone()),
+        (KEY_AD_ID.to_string(), bid.bid_id.clone()),
+    ]);
+    if let (Some(width), Some(height)) = (bid.width, bid.height) {
+        keys.insert(KEY_SIZE.to_string(), format!("{width}x{height}"));
+    }
+
+    let bidder_keys: Vec<_> = keys
+        .iter()
+        .map(|(key, value)| {
+            let key: String = format!("{key}_{}", bid.bidder)
+                .chars()
+                .take(MAX_KEY_LENGTH)
+                .collect();
+            (key, value.clone())
+        })
+        .collect();
+    keys.extend(bidder_keys);
+    keys
+}
+
+/// Adds the [`targeting_keys`] of every bid to its targeting, replacing keys
+/// Prebid Server set with another granularity.
+pub fn apply_targeting(settings: &Settings, bids: &mut [WinningBid]) {
+    for bid in bids.iter_mut() {
+        let keys = targeting_keys(settings, bid);
+        bid.targeting.extend(keys);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::tests::create_test_settings;
+
+    #[test]
+    fn test_price_bucket() {
+        let cases = [
+            (LOW, 1.74, "1.50"),
+            (LOW, 7.0, "5.00"),
+            (MEDIUM, 1.29, "1.20"),
+            (MEDIUM, 0.3, "0.30"),
+            (MEDIUM, 25.0, "20.00"),
+            (HIGH, 0.29, "0.29"),
+            (HIGH, 1.239, "1.23"),
+            (AUTO, 4.99, "4.95"),
+            (AUTO, 7.77, "7.70"),
+            (AUTO, 12.34, "12.00"),
+            (DENSE, 2.567, "2.56"),
+            (DENSE, 3.27, "3.25"),
+            (DENSE, 8.9, "8.50"),
+            (MEDIUM, -1.0, "0.00"),
+            (&[], 1.0, "0.00"),
+        ];
+        for (buckets, price, expected) in cases {
+            assert_eq!(price_bucket(price, buckets), expected, "{price}");
+        }
+    }
+
+    fn winning_bid() -> WinningBid {
+        WinningBid {
+            imp_id: "div-gpt-ad-top".to_string(),
+            bid_id: "bid-1".to_string(),
+            bidder: "smartadserver".to_string(),
+            price: 2.345,
+            currency: "USD".to_string(),
+            adm: None,
+            adomain: Vec::new(),
+            crid: None,
+            width: Some(300),
+            height: Some(250),
+            targeting: BTreeMap::from([("hb_pb".to_string(), "2.00".to_string())]),
+            nurl: None,
+            burl: None,
+            billing_id: None,
+        }
+    }
+
+    #[test]
+    fn test_apply_targeting() {
+        let mut settings = create_test_settings();
+        settings.targeting.price_granularity = PriceGranularity::Custom;
+        settings.targeting.price_buckets = vec![bucket(0.0, 10.0, 0.25)];
+
+        let mut bids = [winning_bid()];
+        apply_targeting(&settings, &mut bids);
+        let targeting = &bids[0].targeting;
+        assert_eq!(targeting["hb_pb"], "2.25");
+        assert_eq!(targeting["hb_bidder"], "smartadserver");
+        assert_eq!(targeting["hb_adid"], "bid-1");
+        assert_eq!(targeting["hb_size"], "300x250");
+        assert_eq!(targeting["hb_pb_smartadserver"], "2.25");
+        assert_eq!(targeting["hb_bidder_smartadser"], "smartadserver");
+        assert!(targeting.keys().all(|key| key.len() <= MAX_KEY_LENGTH));
+    }
+}
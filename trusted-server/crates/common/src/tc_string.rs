@@ -0,0 +1,249 @@
+//! IAB TCF v2 consent string encoding.
+//!
+//! This module encodes the core segment of a TCF v2 TC string so the
+//! server-rendered consent banner can write a standard `euconsent-v2` cookie
+//! that downstream TCF consumers understand. Strings produced here decode with
+//! the same parser used by [`crate::tcf_consent`].
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+
+use crate::cookies::{CookieBuilder, SameSite};
+use crate::settings::Settings;
+
+/// Name of the standard IAB TCF consent cookie.
+pub const TCF_CONSENT_COOKIE: &str = "euconsent-v2";
+
+/// Number of purposes encoded in the purpose bitfields.
+const PURPOSE_BITS: usize = 24;
+
+/// Number of special features encoded in the special feature bitfield.
+const SPECIAL_FEATURE_BITS: usize = 12;
+
+/// Core segment fields of a TCF v2 TC string.
+#[derive(Debug, Clone)]
+pub struct TcString {
+    /// Unix timestamp when consent was first given.
+    pub created: i64,
+    /// Unix timestamp when consent was last updated.
+    pub last_updated: i64,
+    /// Registered ID of the CMP writing the string.
+    pub cmp_id: u16,
+    pub cmp_version: u16,
+    pub consent_screen: u8,
+    /// Two-letter ISO 639-1 language code the consent UI was shown in.
+    pub consent_language: String,
+    pub vendor_list_version: u16,
+    pub tcf_policy_version: u16,
+    pub is_service_specific: bool,
+    pub special_feature_opt_ins: Vec<u8>,
+    pub purposes_consent: Vec<u8>,
+    pub purposes_li_transparency: Vec<u8>,
+    pub purpose_one_treatment: bool,
+    /// Two-letter ISO 3166-1 alpha-2 country code of the publisher.
+    pub publisher_country_code: String,
+    pub vendors_consent: Vec<u16>,
+    pub vendors_li_consent: Vec<u16>,
+}
+
+impl Default for TcString {
+    fn default() -> Self {
+        let now = chrono::Utc::now().timestamp();
+        Self {
+            created: now,
+            last_updated: now,
+            cmp_id: 0,
+            cmp_version: 1,
+            consent_screen: 1,
+            consent_language: "EN".to_string(),
+            vendor_list_version: 0,
+            tcf_policy_version: crate::tcf_consent::TCF_V2_2_POLICY_VERSION,
+            is_service_specific: true,
+            special_feature_opt_ins: Vec::new(),
+            purposes_consent: Vec::new(),
+            purposes_li_transparency: Vec::new(),
+            purpose_one_treatment: false,
+            publisher_country_code: "AA".to_string(),
+            vendors_consent: Vec::new(),
+            vendors_li_consent: Vec::new(),
+        }
+    }
+}
+
+/// MSB-first bit writer used to build TC string segments.
+struct BitWriter {
+    bits: Vec<bool>,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self { bits: Vec::new() }
+    }
+
+    fn int(&mut self, value: u64, width: usize) {
+        for i in (0..width).rev() {
+            self.bits.push((value >> i) & 1 == 1);
+        }
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.bits.push(value);
+    }
+
+    /// Writes a two-letter code as two 6-bit letter offsets from `A`.
+    fn letters(&mut self, code: &str) {
+        let mut letters = code.bytes().map(|b| b.to_ascii_uppercase());
+        for _ in 0..2 {
+            let letter = letters
+                .next()
+                .filter(u8::is_ascii_uppercase)
+                .unwrap_or(b'A');
+            self.int(u64::from(letter - b'A'), 6);
+        }
+    }
+
+    /// Writes a fixed-width bitfield where bit `n - 1` is set for each ID `n`.
+    fn bitfield<T: Copy + Into<u64>>(&mut self, ids: &[T], width: usize) {
+        for n in 1..=width as u64 {
+            self.bool(ids.iter().any(|&id| id.into() == n));
+        }
+    }
+
+    /// Writes a vendor section as `MaxVendorId` followed by a bitfield.
+    fn vendors(&mut self, ids: &[u16]) {
+        let max_vendor_id = ids.iter().copied().max().unwrap_or(0);
+        self.int(u64::from(max_vendor_id), 16);
+        // IsRangeEncoding = false
+        self.bool(false);
+        self.bitfield(ids, usize::from(max_vendor_id));
+    }
+
+    fn into_base64(self) -> String {
+        let bytes: Vec<u8> = self
+            .bits
+            .chunks(8)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .enumerate()
+                    .fold(0u8, |byte, (i, &bit)| byte | (u8::from(bit) << (7 - i)))
+            })
+            .collect();
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+}
+
+/// Converts a Unix timestamp in seconds to TCF deciseconds.
+fn deciseconds(timestamp: i64) -> u64 {
+    u64::try_from(timestamp).unwrap_or(0) * 10
+}
+
+impl TcString {
+    /// Encodes the core segment as a URL-safe base64 TC string.
+    ///
+    /// Vendors are bitfield encoded and no publisher restrictions are written.
+    pub fn encode(&self) -> String {
+        let mut w = BitWriter::new();
+        // Version
+        w.int(2, 6);
+        w.int(deciseconds(self.created), 36);
+        w.int(deciseconds(self.last_updated), 36);
+        w.int(u64::from(self.cmp_id), 12);
+        w.int(u64::from(self.cmp_version), 12);
+        w.int(u64::from(self.consent_screen), 6);
+        w.letters(&self.consent_language);
+        w.int(u64::from(self.vendor_list_version), 12);
+        w.int(u64::from(self.tcf_policy_version), 6);
+        w.bool(self.is_service_specific);
+        // UseNonStandardTexts
+        w.bool(false);
+        w.bitfield(&self.special_feature_opt_ins, SPECIAL_FEATURE_BITS);
+        w.bitfield(&self.purposes_consent, PURPOSE_BITS);
+        w.bitfield(&self.purposes_li_transparency, PURPOSE_BITS);
+        w.bool(self.purpose_one_treatment);
+        w.letters(&self.publisher_country_code);
+        w.vendors(&self.vendors_consent);
+        w.vendors(&self.vendors_li_consent);
+        // NumPubRestrictions
+        w.int(0, 12);
+        w.into_base64()
+    }
+}
+
+/// Creates the `euconsent-v2` cookie holding an encoded TC string.
+pub fn create_tcf_consent_cookie(settings: &Settings, tc_string: &str) -> String {
+    CookieBuilder::new(TCF_CONSENT_COOKIE, tc_string)
+        .domain(&settings.publisher.cookie_domain)
+        .path("/")
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .max_age(31536000)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib_tcstring::TcModelV2;
+    use std::convert::TryFrom;
+
+    use crate::test_support::tests::create_test_settings;
+
+    #[test]
+    fn test_encode_round_trip() {
+        let tc_string = TcString {
+            created: 1729180800,
+            last_updated: 1729184400,
+            cmp_id: 300,
+            cmp_version: 2,
+            vendor_list_version: 71,
+            special_feature_opt_ins: vec![1],
+            purposes_consent: vec![1, 2, 3, 4, 7],
+            purposes_li_transparency: vec![2, 7],
+            publisher_country_code: "FR".to_string(),
+            vendors_consent: vec![45, 755],
+            vendors_li_consent: vec![45],
+            ..Default::default()
+        }
+        .encode();
+
+        let model = TcModelV2::try_from(tc_string.as_str()).unwrap();
+        assert_eq!(model.created_at, 1729180800000);
+        assert_eq!(model.updated_at, 1729184400000);
+        assert_eq!(model.cmp_id, 300);
+        assert_eq!(model.cmp_version, 2);
+        assert_eq!(model.consent_language, "EN");
+        assert_eq!(model.vendor_list_version, 71);
+        assert_eq!(model.tcf_policy_version, 4);
+        assert!(model.is_service_specific);
+        assert_eq!(model.special_feature_opt_ins, vec![1]);
+        assert_eq!(model.purposes_consent, vec![1, 2, 3, 4, 7]);
+        assert_eq!(model.purposes_li_transparency, vec![2, 7]);
+        assert_eq!(model.publisher_country_code, "FR");
+        assert_eq!(model.vendors_consent, vec![45, 755]);
+        assert_eq!(model.vendors_li_consent, vec![45]);
+        assert!(model.publisher_restrictions.is_empty());
+    }
+
+    #[test]
+    fn test_encode_reject_all() {
+        let tc_string = TcString {
+            vendor_list_version: 71,
+            ..Default::default()
+        }
+        .encode();
+
+        let model = TcModelV2::try_from(tc_string.as_str()).unwrap();
+        assert!(model.purposes_consent.is_empty());
+        assert!(model.vendors_consent.is_empty());
+        assert!(model.vendors_li_consent.is_empty());
+    }
+
+    #[test]
+    fn test_create_tcf_consent_cookie() {
+        let settings = create_test_settings();
+        let cookie = create_tcf_consent_cookie(&settings, "CQABC");
+        assert!(cookie.starts_with("euconsent-v2=CQABC; Domain=.test-publisher.com; Path=/;"));
+        assert!(cookie.contains("SameSite=Lax"));
+    }
+}
@@ -0,0 +1,234 @@
+//! Bitsets for TCF purpose, special feature and vendor signals.
+//!
+//! [`crate::tcf_consent::TcfConsent`] stores every consent and legitimate
+//! interest signal as a bitset instead of a `HashMap<id, bool>`: lookups are a
+//! shift and a mask, and purpose sets live inline without allocating. Both
+//! types serialize as the `{"<id>": true}` map the `HashMap` representation
+//! produced, so stored and logged consent keeps its shape.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+const WORD_BITS: usize = u64::BITS as usize;
+
+fn word_and_mask(id: usize) -> (usize, u64) {
+    (id / WORD_BITS, 1 << (id % WORD_BITS))
+}
+
+fn ids(words: &[u64]) -> impl Iterator<Item = usize> + '_ {
+    words.iter().enumerate().flat_map(|(index, &word)| {
+        let mut remaining = word;
+        std::iter::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
+            let bit = remaining.trailing_zeros() as usize;
+            remaining &= remaining - 1;
+            Some(index * WORD_BITS + bit)
+        })
+    })
+}
+
+/// Set of purpose or special feature IDs.
+///
+/// Covers every `u8` ID inline, so it is `Copy` and never allocates.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct PurposeSet([u64; 4]);
+
+impl PurposeSet {
+    /// Creates an empty set.
+    pub const fn new() -> Self {
+        Self([0; 4])
+    }
+
+    /// Whether the set contains `id`.
+    pub fn contains(&self, id: u8) -> bool {
+        let (word, mask) = word_and_mask(usize::from(id));
+        self.0[word] & mask != 0
+    }
+
+    /// Whether the set contains every ID in `ids`.
+    pub fn contains_all(&self, ids: &[u8]) -> bool {
+        ids.iter().all(|&id| self.contains(id))
+    }
+
+    /// Adds `id` to the set.
+    pub fn insert(&mut self, id: u8) {
+        let (word, mask) = word_and_mask(usize::from(id));
+        self.0[word] |= mask;
+    }
+
+    /// Removes `id` from the set.
+    pub fn remove(&mut self, id: u8) {
+        let (word, mask) = word_and_mask(usize::from(id));
+        self.0[word] &= !mask;
+    }
+
+    /// Whether the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|&word| word == 0)
+    }
+
+    /// Number of IDs in the set.
+    pub fn len(&self) -> usize {
+        self.0.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    /// IDs in the set, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        ids(&self.0).map(|id| id as u8)
+    }
+}
+
+impl FromIterator<u8> for PurposeSet {
+    fn from_iter<I: IntoIterator<Item = u8>>(iter: I) -> Self {
+        let mut set = Self::new();
+        iter.into_iter().for_each(|id| set.insert(id));
+        set
+    }
+}
+
+/// Set of IAB Global Vendor List vendor IDs.
+///
+/// Grows to the highest vendor ID inserted, one bit per ID.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct VendorSet(Vec<u64>);
+
+impl VendorSet {
+    /// Creates an empty set.
+    pub const fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Whether the set contains `id`.
+    pub fn contains(&self, id: u16) -> bool {
+        let (word, mask) = word_and_mask(usize::from(id));
+        self.0.get(word).is_some_and(|&bits| bits & mask != 0)
+    }
+
+    /// Adds `id` to the set.
+    pub fn insert(&mut self, id: u16) {
+        let (word, mask) = word_and_mask(usize::from(id));
+        if word >= self.0.len() {
+            self.0.resize(word + 1, 0);
+        }
+        self.0[word] |= mask;
+    }
+
+    /// Removes `id` from the set.
+    pub fn remove(&mut self, id: u16) {
+        let (word, mask) = word_and_mask(usize::from(id));
+        if let Some(bits) = self.0.get_mut(word) {
+            *bits &= !mask;
+        }
+        // Trailing empty words are dropped so equal sets compare equal
+        while self.0.last() == Some(&0) {
+            self.0.pop();
+        }
+    }
+
+    /// Whether the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|&word| word == 0)
+    }
+
+    /// Number of IDs in the set.
+    pub fn len(&self) -> usize {
+        self.0.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    /// IDs in the set, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        ids(&self.0).map(|id| id as u16)
+    }
+}
+
+impl FromIterator<u16> for VendorSet {
+    fn from_iter<I: IntoIterator<Item = u16>>(iter: I) -> Self {
+        let mut set = Self::new();
+        iter.into_iter().for_each(|id| set.insert(id));
+        set
+    }
+}
+
+macro_rules! impl_id_set_traits {
+    ($set:ty, $id:ty) => {
+        impl fmt::Debug for $set {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.debug_set().entries(self.iter()).finish()
+            }
+        }
+
+        impl Serialize for $set {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_map(self.iter().map(|id| (id, true)))
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $set {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let map = HashMap::<$id, bool>::deserialize(deserializer)?;
+                Ok(map
+                    .into_iter()
+                    .filter(|&(_, granted)| granted)
+                    .map(|(id, _)| id)
+                    .collect())
+            }
+        }
+    };
+}
+
+impl_id_set_traits!(PurposeSet, u8);
+impl_id_set_traits!(VendorSet, u16);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_purpose_set() {
+        let mut set = PurposeSet::new();
+        assert!(set.is_empty());
+        set.insert(1);
+        set.insert(7);
+        set.insert(255);
+        assert!(set.contains(7));
+        assert!(!set.contains(2));
+        assert!(set.contains_all(&[1, 7]));
+        assert!(!set.contains_all(&[1, 2]));
+        assert_eq!(set.len(), 3);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![1, 7, 255]);
+
+        set.remove(7);
+        assert!(!set.contains(7));
+        assert_eq!(format!("{set:?}"), "{1, 255}");
+    }
+
+    #[test]
+    fn test_vendor_set() {
+        let mut set: VendorSet = [755, 2, 45].into_iter().collect();
+        assert!(set.contains(45));
+        assert!(!set.contains(44));
+        assert!(!set.contains(u16::MAX));
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![2, 45, 755]);
+
+        set.remove(755);
+        set.remove(u16::MAX);
+        assert_eq!(set.len(), 2);
+        assert_eq!(set, [2, 45].into_iter().collect());
+    }
+
+    #[test]
+    fn test_serde_keeps_map_shape() {
+        let set: PurposeSet = [2, 1].into_iter().collect();
+        assert_eq!(
+            serde_json::to_string(&set).unwrap(),
+            r#"{"1":true,"2":true}"#
+        );
+
+        let set: VendorSet = serde_json::from_str(r#"{"8":true,"6":false,"2":true}"#).unwrap();
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![2, 8]);
+    }
+}
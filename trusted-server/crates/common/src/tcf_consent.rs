@@ -11,38 +11,106 @@
 //! - Providing flexible consent checking for any vendor/purpose combination
 
 use fastly::Request;
-use lib_tcstring::TcModelV2;
+use lib_tcstring::{PublisherRestrictionType, TcModelV2};
 use log;
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 
 use crate::cookies;
+use crate::gdpr::gdpr_applies_to_request;
+use crate::settings::Settings;
+use crate::tcf_bitset::{PurposeSet, VendorSet};
 
-/// IAB TCF Purpose IDs for common consent categories
+/// IAB TCF Purpose IDs for common consent categories (TCF v2.2 purpose names)
 pub mod purpose_ids {
     /// Purpose 1: Store and/or access information on a device
     pub const DEVICE_ACCESS: &[u8] = &[1];
     
     /// Advertising purposes: Basic ads + personalized ads
-    /// - Purpose 2: Select basic ads
-    /// - Purpose 3: Create a personalised ads profile
-    /// - Purpose 4: Select personalised ads
+    /// - Purpose 2: Use limited data to select advertising
+    /// - Purpose 3: Create profiles for personalised advertising
+    /// - Purpose 4: Use profiles to select personalised advertising
     pub const ADVERTISING: &[u8] = &[2, 3, 4];
     
     /// Analytics purposes: Measurement and insights
-    /// - Purpose 7: Measure ad performance
+    /// - Purpose 7: Measure advertising performance
     /// - Purpose 8: Measure content performance
-    /// - Purpose 9: Apply market research to generate audience insights
+    /// - Purpose 9: Understand audiences through statistics or combinations of data
     pub const ANALYTICS: &[u8] = &[7, 8, 9];
     
     /// Basic advertising (non-personalized)
-    /// - Purpose 2: Select basic ads only
+    /// - Purpose 2: Use limited data to select advertising
     pub const BASIC_ADS: &[u8] = &[2];
+
+    /// Basic content selection (added in TCF v2.2)
+    /// - Purpose 11: Use limited data to select content
+    pub const BASIC_CONTENT: &[u8] = &[11];
+
+    /// Highest purpose ID defined by TCF v2.2
+    pub const MAX_PURPOSE_ID: u8 = 11;
+
+    /// Purposes TCF v2.2 only allows on the legal basis of consent:
+    /// device access (1) and the personalisation purposes (3-6)
+    pub const CONSENT_ONLY: &[u8] = &[1, 3, 4, 5, 6];
+}
+
+/// IAB TCF Special Feature IDs
+pub mod special_feature_ids {
+    /// Special Feature 1: Use precise geolocation data
+    pub const PRECISE_GEOLOCATION: u8 = 1;
+
+    /// Special Feature 2: Actively scan device characteristics for identification
+    pub const DEVICE_SCANNING: u8 = 2;
+}
+
+/// TCF policy version introduced by TCF v2.2
+pub const TCF_V2_2_POLICY_VERSION: u16 = 4;
+
+/// Publisher restriction type from the TC string's Publisher Restrictions section
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RestrictionType {
+    /// Purpose flatly not allowed by the publisher
+    NotAllowed,
+    /// Vendors need consent for the purpose
+    RequireConsent,
+    /// Vendors need legitimate interest for the purpose
+    RequireLegitimateInterest,
+}
+
+/// Publisher restriction on a purpose for a set of vendors
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PublisherRestriction {
+    pub purpose_id: u8,
+    pub restriction_type: RestrictionType,
+    pub vendors: Vec<u16>,
+}
+
+/// Data retention periods declared by a vendor in GVL v3, in days
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DataRetention {
+    #[serde(default)]
+    pub std_retention: Option<u32>,
+    #[serde(default)]
+    pub purposes: HashMap<u8, u32>,
+    #[serde(default)]
+    pub special_purposes: HashMap<u8, u32>,
+}
+
+/// Localized vendor URLs declared in GVL v3
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VendorUrls {
+    pub lang_id: String,
+    pub privacy: String,
+    #[serde(default)]
+    pub leg_int_claim: Option<String>,
 }
 
 /// IAB Global Vendor List entry
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct VendorInfo {
     pub id: u16,
     pub name: String,
@@ -50,6 +118,26 @@ pub struct VendorInfo {
     pub legitimate_interests: Vec<u8>,
     pub features: Vec<u8>,
     pub special_features: Vec<u8>,
+    /// Purposes the publisher may switch between consent and legitimate interest
+    #[serde(default)]
+    pub flexible_purposes: Vec<u8>,
+    #[serde(default)]
+    pub special_purposes: Vec<u8>,
+    /// Categories of data collected (GVL v3)
+    #[serde(default)]
+    pub data_declaration: Vec<u8>,
+    #[serde(default)]
+    pub data_retention: Option<DataRetention>,
+    #[serde(default)]
+    pub uses_cookies: bool,
+    #[serde(default)]
+    pub cookie_max_age_seconds: Option<i64>,
+    #[serde(default)]
+    pub uses_non_cookie_access: bool,
+    #[serde(default)]
+    pub device_storage_disclosure_url: Option<String>,
+    #[serde(default)]
+    pub urls: Vec<VendorUrls>,
 }
 
 /// IAB Global Vendor List cache
@@ -58,6 +146,9 @@ pub struct VendorList {
     pub vendors: HashMap<u16, VendorInfo>,
     pub last_updated: i64,
     pub version: u32,
+    /// TCF policy version the list was published under
+    #[serde(default)]
+    pub tcf_policy_version: u16,
 }
 
 impl VendorList {
@@ -67,6 +158,7 @@ impl VendorList {
             vendors: HashMap::new(),
             last_updated: chrono::Utc::now().timestamp(),
             version: 0,
+            tcf_policy_version: 0,
         }
     }
     
@@ -88,6 +180,23 @@ impl VendorList {
             false
         }
     }
+
+    /// Checks if vendor declares legitimate interest for a purpose, either directly
+    /// or as a flexible purpose
+    pub fn vendor_declares_legitimate_interest(&self, vendor_id: u16, purpose_id: u8) -> bool {
+        if let Some(vendor) = self.get_vendor(vendor_id) {
+            vendor.legitimate_interests.contains(&purpose_id)
+                || vendor.flexible_purposes.contains(&purpose_id)
+        } else {
+            false
+        }
+    }
+}
+
+impl Default for VendorList {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 /// TCF-based consent information from any CMP.
@@ -102,40 +211,186 @@ pub struct TcfConsent {
     /// Whether GDPR regulations apply to this user
     pub gdpr_applies: bool,
     
-    /// Purpose consent map: Purpose ID → user consent
-    pub purpose_consents: HashMap<u8, bool>,
+    /// Purposes the user consented to
+    pub purpose_consents: PurposeSet,
     
-    /// Vendor consent map: Vendor ID → user consent  
-    pub vendor_consents: HashMap<u16, bool>,
+    /// Vendors the user consented to
+    pub vendor_consents: VendorSet,
     
     /// Unix timestamp when consent was processed
     pub timestamp: i64,
     
     /// TCF version (should be "2" for TCF v2)
     pub version: String,
+
+    /// Unix timestamp when the TC string was first created
+    #[serde(default)]
+    pub created: i64,
+
+    /// Unix timestamp when the TC string was last updated
+    #[serde(default)]
+    pub last_updated: i64,
+
+    /// TCF policy version the string was created under (4 or later for TCF v2.2)
+    #[serde(default)]
+    pub policy_version: u16,
+
+    /// Global Vendor List version the string was created with
+    #[serde(default)]
+    pub vendor_list_version: u16,
+
+    /// Purposes with legitimate interest established and not objected to
+    #[serde(default)]
+    pub purpose_legitimate_interests: PurposeSet,
+
+    /// Vendors with legitimate interest established and not objected to
+    #[serde(default)]
+    pub vendor_legitimate_interests: VendorSet,
+
+    /// Publisher restrictions per purpose and vendor
+    #[serde(default)]
+    pub publisher_restrictions: Vec<PublisherRestriction>,
+
+    /// Special features the user opted in to
+    #[serde(default)]
+    pub special_feature_opt_ins: PurposeSet,
+
+    /// Whether the TCF string carries a Publisher TC segment
+    #[serde(default)]
+    pub has_publisher_tc: bool,
+
+    /// Publisher purposes the user consented to in the Publisher TC segment
+    #[serde(default)]
+    pub publisher_purpose_consents: PurposeSet,
+
+    /// Publisher purposes with legitimate interest in the Publisher TC segment
+    #[serde(default)]
+    pub publisher_purpose_legitimate_interests: PurposeSet,
+
+    /// Vendors the CMP disclosed to the user, if the string carries a Disclosed Vendors segment
+    #[serde(default)]
+    pub disclosed_vendors: Option<Vec<u16>>,
+}
+
+/// TCF segment types, encoded in the first three bits of every segment
+mod segment_types {
+    pub const DISCLOSED_VENDORS: u8 = 1;
+    pub const PUBLISHER_TC: u8 = 3;
+}
+
+/// Returns `true` if the TCF string has a segment of the given type.
+///
+/// The parsed model cannot tell an absent segment from an empty one, so the
+/// segment type is read from the leading base64url character of each
+/// segment after the core string.
+fn has_segment(tc_string: &str, segment_type: u8) -> bool {
+    const BASE64URL: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    tc_string.split('.').skip(1).any(|segment| {
+        segment
+            .bytes()
+            .next()
+            .and_then(|c| BASE64URL.iter().position(|&b| b == c))
+            .is_some_and(|sextet| (sextet >> 3) as u8 == segment_type)
+    })
+}
+
+/// Collects purposes that may be based on legitimate interest under TCF v2.2.
+fn legitimate_interest_purposes(purposes: &[u8]) -> PurposeSet {
+    purposes
+        .iter()
+        .copied()
+        .filter(|purpose_id| {
+            *purpose_id <= purpose_ids::MAX_PURPOSE_ID
+                && !purpose_ids::CONSENT_ONLY.contains(purpose_id)
+        })
+        .collect()
 }
 
 impl TcfConsent {
     /// Creates TcfConsent from a parsed TCF model.
     ///
-    /// Extracts purpose and vendor consents from the TCF string data.
+    /// Extracts purpose and vendor consents from the TCF string data, applying
+    /// TCF v2.2 rules:
+    /// - Purposes beyond [`purpose_ids::MAX_PURPOSE_ID`] are ignored
+    /// - Legitimate interest is never established for consent-only purposes
+    /// - Strings created under a pre-v2.2 policy are downgraded to consent
+    ///   signals only, as their legitimate interest disclosures are stale
     pub fn from_tc_model(tc_model: TcModelV2, tc_string: String) -> Result<Self, String> {
+        if tc_model.tcf_policy_version == 0 {
+            return Err("TCF string has no policy version".to_string());
+        }
+
         // Extract purpose consents from TcModelV2
         // From debug output: purposes_consent: [1, 2, 3]
-        let mut purpose_consents = HashMap::new();
-        for purpose_id in &tc_model.purposes_consent {
-            purpose_consents.insert(*purpose_id, true);
-        }
+        let purpose_consents: PurposeSet = tc_model
+            .purposes_consent
+            .iter()
+            .copied()
+            .filter(|&purpose_id| purpose_id <= purpose_ids::MAX_PURPOSE_ID)
+            .collect();
         
         // Extract vendor consents from TcModelV2  
         // From debug output: vendors_consent: [2, 6, 8]
-        let mut vendor_consents = HashMap::new();
-        for vendor_id in &tc_model.vendors_consent {
-            vendor_consents.insert(*vendor_id, true);
-        }
+        let vendor_consents: VendorSet = tc_model.vendors_consent.iter().copied().collect();
         
-        // Determine if GDPR applies based on TCF data
-        // For now, assume GDPR applies if we have a valid TCF string
+        let mut purpose_legitimate_interests = PurposeSet::new();
+        let mut vendor_legitimate_interests = VendorSet::new();
+        if tc_model.tcf_policy_version >= TCF_V2_2_POLICY_VERSION {
+            purpose_legitimate_interests =
+                legitimate_interest_purposes(&tc_model.purposes_li_transparency);
+            vendor_legitimate_interests = tc_model.vendors_li_consent.iter().copied().collect();
+        } else {
+            log::debug!(
+                "TCF string uses pre-v2.2 policy version {}, ignoring legitimate interest signals",
+                tc_model.tcf_policy_version
+            );
+        }
+
+        let special_feature_opt_ins: PurposeSet =
+            tc_model.special_feature_opt_ins.iter().copied().collect();
+
+        // Publisher purposes follow the same TCF v2.2 legitimate interest rules
+        let has_publisher_tc = has_segment(&tc_string, segment_types::PUBLISHER_TC);
+        let mut publisher_purpose_consents = PurposeSet::new();
+        let mut publisher_purpose_legitimate_interests = PurposeSet::new();
+        if has_publisher_tc {
+            publisher_purpose_consents = tc_model
+                .publisher_purposes_consent
+                .iter()
+                .copied()
+                .filter(|&purpose_id| purpose_id <= purpose_ids::MAX_PURPOSE_ID)
+                .collect();
+            if tc_model.tcf_policy_version >= TCF_V2_2_POLICY_VERSION {
+                publisher_purpose_legitimate_interests =
+                    legitimate_interest_purposes(&tc_model.publisher_purposes_li_transparency);
+            }
+        }
+        let disclosed_vendors = has_segment(&tc_string, segment_types::DISCLOSED_VENDORS)
+            .then(|| tc_model.disclosed_vendors.clone());
+
+        // Undefined restriction types must be ignored per the TCF specification
+        let publisher_restrictions = tc_model
+            .publisher_restrictions
+            .iter()
+            .filter_map(|restriction| {
+                let restriction_type = match restriction.restriction_type {
+                    PublisherRestrictionType::NotAllowed => RestrictionType::NotAllowed,
+                    PublisherRestrictionType::RequireConsent => RestrictionType::RequireConsent,
+                    PublisherRestrictionType::RequireLegitimateInterest => {
+                        RestrictionType::RequireLegitimateInterest
+                    }
+                    PublisherRestrictionType::Undefined => return None,
+                };
+                Some(PublisherRestriction {
+                    purpose_id: restriction.purpose_id,
+                    restriction_type,
+                    vendors: restriction.vendor_list.clone(),
+                })
+            })
+            .collect();
+
+        // Assume GDPR applies if we have a valid TCF string; the client's
+        // geolocation overrides this in apply_request_signals
         let gdpr_applies = !tc_string.is_empty();
         
         log::info!(
@@ -152,9 +407,107 @@ impl TcfConsent {
             vendor_consents,
             timestamp: chrono::Utc::now().timestamp(),
             version: "2".to_string(),
+            created: (tc_model.created_at / 1000) as i64,
+            last_updated: (tc_model.updated_at / 1000) as i64,
+            policy_version: tc_model.tcf_policy_version,
+            vendor_list_version: tc_model.vendor_list_version,
+            purpose_legitimate_interests,
+            vendor_legitimate_interests,
+            publisher_restrictions,
+            special_feature_opt_ins,
+            has_publisher_tc,
+            publisher_purpose_consents,
+            publisher_purpose_legitimate_interests,
+            disclosed_vendors,
+        })
+    }
+
+    /// Checks if the publisher may process all given purposes itself.
+    ///
+    /// Purposes the publisher (and trusted-server acting for it) pursues are
+    /// signalled in the Publisher TC segment, as consent or legitimate interest.
+    /// Strings without that segment fall back to the core purpose consents.
+    pub fn has_publisher_consent(&self, purposes: &[u8]) -> bool {
+        purposes.iter().all(|purpose_id| {
+            if !self.has_publisher_tc {
+                return self.purpose_consents.contains(*purpose_id);
+            }
+            self.publisher_purpose_consents.contains(*purpose_id)
+                || self
+                    .publisher_purpose_legitimate_interests
+                    .contains(*purpose_id)
         })
     }
     
+    /// Whether the CMP disclosed the vendor to the user.
+    ///
+    /// Always `true` when the string has no Disclosed Vendors segment.
+    pub fn is_vendor_disclosed(&self, vendor_id: u16) -> bool {
+        self.disclosed_vendors
+            .as_ref()
+            .is_none_or(|vendors| vendors.contains(&vendor_id))
+    }
+
+    /// Checks if the user opted in to a special feature (see [`special_feature_ids`])
+    pub fn has_special_feature(&self, feature_id: u8) -> bool {
+        self.special_feature_opt_ins.contains(feature_id)
+    }
+
+    /// Whether precise geolocation may be shared with ad partners.
+    ///
+    /// Requires the Special Feature 1 opt-in when GDPR applies.
+    pub fn allows_precise_geolocation(&self) -> bool {
+        !self.gdpr_applies || self.has_special_feature(special_feature_ids::PRECISE_GEOLOCATION)
+    }
+
+    /// Returns the publisher restriction for a vendor-purpose pair, if any
+    pub fn publisher_restriction(&self, vendor_id: u16, purpose_id: u8) -> Option<RestrictionType> {
+        self.publisher_restrictions
+            .iter()
+            .find(|r| r.purpose_id == purpose_id && r.vendors.contains(&vendor_id))
+            .map(|r| r.restriction_type)
+    }
+
+    /// Whether the TC string was last updated more than `max_age_days` ago.
+    ///
+    /// A `max_age_days` of zero disables the check.
+    pub fn is_expired(&self, max_age_days: u32) -> bool {
+        if max_age_days == 0 {
+            return false;
+        }
+        let max_age_secs = i64::from(max_age_days) * 24 * 60 * 60;
+        chrono::Utc::now().timestamp() - self.last_updated > max_age_secs
+    }
+
+    /// Whether the string was created under TCF v2.2 policies
+    pub fn is_tcf_v2_2(&self) -> bool {
+        self.policy_version >= TCF_V2_2_POLICY_VERSION
+    }
+
+    /// Checks if a vendor may process a purpose on the basis of legitimate interest.
+    ///
+    /// Requires the vendor and purpose legitimate interest signals in the TCF string
+    /// and, when a vendor list is given, an LI (or flexible) declaration by the vendor.
+    /// Always `false` for purposes TCF v2.2 reserves for consent.
+    pub fn has_legitimate_interest(
+        &self,
+        vendor_id: u16,
+        purpose_id: u8,
+        vendor_list: Option<&VendorList>,
+    ) -> bool {
+        if purpose_ids::CONSENT_ONLY.contains(&purpose_id) {
+            return false;
+        }
+        if let Some(vl) = vendor_list {
+            if !vl.vendor_declares_legitimate_interest(vendor_id, purpose_id) {
+                return false;
+            }
+        }
+
+        self.vendor_legitimate_interests.contains(vendor_id)
+            && self.purpose_legitimate_interests.contains(purpose_id)
+    }
+
     /// Checks if a specific vendor has consent for given purposes.
     ///
     /// This is the core consent validation method implementing TCF v2 logic:
@@ -162,6 +515,10 @@ impl TcfConsent {
     /// - ALL specified purposes must have consent
     /// - If either fails, returns false
     ///
+    /// Publisher restrictions override this per vendor-purpose pair: a purpose
+    /// that is not allowed always fails, and a purpose restricted to legitimate
+    /// interest is only allowed on the basis of legitimate interest.
+    ///
     /// # Arguments
     /// * `vendor_id` - IAB Global Vendor List ID
     /// * `purposes` - Array of purpose IDs to check
@@ -191,16 +548,61 @@ impl TcfConsent {
             }
         }
         
-        // Check vendor consent in TCF string
-        let vendor_consent = self.vendor_consents.get(&vendor_id).unwrap_or(&false);
-        if !vendor_consent {
+        // Check vendor consent in TCF string, unless every purpose is restricted to
+        // legitimate interest by the publisher
+        let needs_vendor_consent = purposes.is_empty()
+            || purposes.iter().any(|&purpose_id| {
+                self.publisher_restriction(vendor_id, purpose_id)
+                    != Some(RestrictionType::RequireLegitimateInterest)
+            });
+        let vendor_consent = self.vendor_consents.contains(vendor_id);
+        if needs_vendor_consent && !vendor_consent {
             log::debug!("Vendor {} consent denied in TCF string", vendor_id);
             return false;
         }
         
         // Check all purpose consents in TCF string
         for &purpose_id in purposes {
-            let purpose_consent = self.purpose_consents.get(&purpose_id).unwrap_or(&false);
+            match self.publisher_restriction(vendor_id, purpose_id) {
+                Some(RestrictionType::NotAllowed) => {
+                    log::debug!(
+                        "Purpose {} not allowed for vendor {} by publisher restriction",
+                        purpose_id,
+                        vendor_id
+                    );
+                    return false;
+                }
+                Some(RestrictionType::RequireLegitimateInterest) => {
+                    if !self.has_legitimate_interest(vendor_id, purpose_id, vendor_list) {
+                        log::debug!(
+                            "Purpose {} requires legitimate interest for vendor {}",
+                            purpose_id,
+                            vendor_id
+                        );
+                        return false;
+                    }
+                    continue;
+                }
+                Some(RestrictionType::RequireConsent) => {
+                    // Vendors declaring the purpose under legitimate interest only
+                    // cannot switch to consent
+                    if let Some(vendor) = vendor_list.and_then(|vl| vl.get_vendor(vendor_id)) {
+                        if !vendor.purposes.contains(&purpose_id)
+                            && !vendor.flexible_purposes.contains(&purpose_id)
+                        {
+                            log::debug!(
+                                "Vendor {} cannot use consent for purpose {}",
+                                vendor_id,
+                                purpose_id
+                            );
+                            return false;
+                        }
+                    }
+                }
+                None => {}
+            }
+
+            let purpose_consent = self.purpose_consents.contains(purpose_id);
             if !purpose_consent {
                 log::debug!("Purpose {} consent denied for vendor {} in TCF string", purpose_id, vendor_id);
                 return false;
@@ -248,7 +650,7 @@ impl TcfConsent {
 }
 
 /// Advertising consent levels for graduated consent handling
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AdvertisingConsentLevel {
     /// Full personalized advertising allowed
     Personalized,
@@ -267,10 +669,22 @@ impl Default for TcfConsent {
         Self {
             tc_string: String::new(),
             gdpr_applies: false, // Default false as specified
-            purpose_consents: HashMap::new(),
-            vendor_consents: HashMap::new(),
+            purpose_consents: PurposeSet::new(),
+            vendor_consents: VendorSet::new(),
             timestamp: chrono::Utc::now().timestamp(),
             version: "2".to_string(),
+            created: 0,
+            last_updated: 0,
+            policy_version: 0,
+            vendor_list_version: 0,
+            purpose_legitimate_interests: PurposeSet::new(),
+            vendor_legitimate_interests: VendorSet::new(),
+            publisher_restrictions: Vec::new(),
+            special_feature_opt_ins: PurposeSet::new(),
+            has_publisher_tc: false,
+            publisher_purpose_consents: PurposeSet::new(),
+            publisher_purpose_legitimate_interests: PurposeSet::new(),
+            disclosed_vendors: None,
         }
     }
 }
@@ -281,12 +695,107 @@ impl Default for TcfConsent {
 /// Looks for the standard euconsent-v2 cookie containing the IAB TCF consent string.
 ///
 /// # Arguments
+/// * `settings` - Settings providing the `gdpr.tcf_max_age_days` freshness limit
 /// * `req` - HTTP request containing cookies
 ///
 /// # Returns
-/// * `Some(TcfConsent)` if valid TCF consent found
-/// * `None` if no consent cookie or parsing fails (caller should use default)
-pub fn get_tcf_consent_from_request(req: &Request) -> Option<TcfConsent> {
+/// * `Some(TcfConsent)` if valid TCF consent found, with the request signals
+///   of [`apply_request_signals`] applied
+/// * `None` if no consent cookie or parsing fails (see [`get_tcf_consent_or_default`])
+pub fn get_tcf_consent_from_request(settings: &Settings, req: &Request) -> Option<TcfConsent> {
+    parse_tcf_consent_cookie(req).map(|consent| apply_request_signals(settings, req, consent))
+}
+
+/// Extracts TCF consent like [`get_tcf_consent_from_request`], falling back to
+/// an empty consent whose `gdpr_applies` reflects the client's location.
+///
+/// Without a TC string no purposes are granted, so handlers take the
+/// non-personalized path, while EEA/UK traffic is still flagged as in scope.
+/// Clients that cannot be located are flagged as in scope too, matching
+/// [`Regime::for_request`](crate::privacy::Regime::for_request).
+pub fn get_tcf_consent_or_default(settings: &Settings, req: &Request) -> TcfConsent {
+    get_tcf_consent_from_request(settings, req).unwrap_or_else(|| TcfConsent {
+        gdpr_applies: gdpr_applies_to_request(req).unwrap_or(true),
+        ..Default::default()
+    })
+}
+
+/// Applies the request context to consent parsed from a TC string.
+///
+/// Expired consent has all signals withdrawn so handlers fall back to the
+/// non-personalized path, and `gdpr_applies` follows the client's geolocation
+/// when it is known. A binding `Sec-GPC` signal is not folded into the TC
+/// string; see [`crate::gpc`].
+pub fn apply_request_signals(
+    settings: &Settings,
+    req: &Request,
+    consent: TcfConsent,
+) -> TcfConsent {
+    let mut consent = check_freshness(settings, consent);
+    if let Some(gdpr_applies) = gdpr_applies_to_request(req) {
+        consent.gdpr_applies = gdpr_applies;
+    }
+    consent
+}
+
+/// Withdraws all signals of consent whose TC string is older than `gdpr.tcf_max_age_days`.
+///
+/// GDPR still applies to expired consent, so only the TC string and its signals are dropped.
+pub fn check_freshness(settings: &Settings, consent: TcfConsent) -> TcfConsent {
+    if !consent.is_expired(settings.gdpr.tcf_max_age_days) {
+        return consent;
+    }
+    log::info!(
+        "TCF consent last updated at {} is older than {} days, treating as expired",
+        consent.last_updated,
+        settings.gdpr.tcf_max_age_days
+    );
+    TcfConsent {
+        gdpr_applies: consent.gdpr_applies,
+        created: consent.created,
+        last_updated: consent.last_updated,
+        ..Default::default()
+    }
+}
+
+thread_local! {
+    /// Most recently decoded TC string and its consent.
+    ///
+    /// A Compute instance serves a single request, so this decodes the TC string
+    /// once per request however many handlers ask for consent; keying by the TC
+    /// string keeps it correct when one instance handles several requests.
+    static DECODED_TC_STRING: RefCell<Option<(String, TcfConsent)>> = const { RefCell::new(None) };
+}
+
+/// Decodes a TC string into [`TcfConsent`], reusing the previous result when
+/// the same TC string is decoded again.
+///
+/// # Errors
+///
+/// Returns an error message if the TC string cannot be decoded.
+pub fn decode_tc_string(tc_string: &str) -> Result<TcfConsent, String> {
+    let cached = DECODED_TC_STRING.with(|cache| {
+        cache
+            .borrow()
+            .as_ref()
+            .filter(|(cached_string, _)| cached_string == tc_string)
+            .map(|(_, consent)| consent.clone())
+    });
+    if let Some(consent) = cached {
+        return Ok(consent);
+    }
+
+    // Parse TCF string using lib_tcstring
+    let tc_model = TcModelV2::try_from(tc_string).map_err(|e| format!("{e:?}"))?;
+    log::info!("Successfully parsed TCF consent string");
+    let consent = TcfConsent::from_tc_model(tc_model, tc_string.to_string())?;
+    DECODED_TC_STRING.with(|cache| {
+        *cache.borrow_mut() = Some((tc_string.to_string(), consent.clone()));
+    });
+    Ok(consent)
+}
+
+fn parse_tcf_consent_cookie(req: &Request) -> Option<TcfConsent> {
     match cookies::handle_request_cookies(req) {
         Ok(Some(jar)) => {
             // Look for euconsent-v2 cookie (standard IAB TCF cookie name)
@@ -294,18 +803,9 @@ pub fn get_tcf_consent_from_request(req: &Request) -> Option<TcfConsent> {
                 let tc_string = euconsent_cookie.value();
                 log::debug!("Found euconsent-v2 cookie: {}", tc_string);
                 
-                // Parse TCF string using lib_tcstring
-                match TcModelV2::try_from(tc_string) {
-                    Ok(tc_model) => {
-                        log::info!("Successfully parsed TCF consent string");
-                        match TcfConsent::from_tc_model(tc_model, tc_string.to_string()) {
-                            Ok(consent) => return Some(consent),
-                            Err(e) => log::warn!("Failed to create TcfConsent from TCF model: {}", e),
-                        }
-                    }
-                    Err(e) => {
-                        log::warn!("Failed to parse TCF consent string: {:?}", e);
-                    }
+                match decode_tc_string(tc_string) {
+                    Ok(consent) => return Some(consent),
+                    Err(e) => log::warn!("Failed to parse TCF consent string: {}", e),
                 }
             } else {
                 log::debug!("No euconsent-v2 cookie found");
@@ -323,29 +823,366 @@ pub fn get_tcf_consent_from_request(req: &Request) -> Option<TcfConsent> {
     }
 }
 
-/// TODO: Vendor list management functions
-/// These would be implemented to fetch and cache the IAB Global Vendor List
+/// IAB Global Vendor List fetching and KV caching.
+///
+/// The GVL is fetched from `gvl.url` through the `gvl.backend` Fastly backend,
+/// parsed into a [`VendorList`] and cached in the `gvl.cache_store` KV store
+/// for a week.
 pub mod vendor_list_manager {
     use super::*;
-    
-    /// Fetches the latest IAB Global Vendor List
-    /// TODO: Implement HTTP fetch from https://vendor-list.consensu.org/v3/vendor-list.json
-    pub async fn fetch_vendor_list() -> Result<VendorList, String> {
-        // Implementation would:
-        // 1. Fetch JSON from IAB endpoint
-        // 2. Parse into VendorList structure
-        // 3. Cache in KV store with TTL
-        Err("Not implemented yet".to_string())
+    use std::time::Duration;
+
+    use error_stack::{Report, ResultExt};
+    use fastly::http::StatusCode;
+
+    use crate::error::TrustedServerError;
+    use crate::kv_store::JsonKvStore;
+
+    /// How long a cached vendor list is used before it is fetched again.
+    pub const CACHE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+    /// How long a fallback copy is served before fetching the vendor list is retried.
+    pub const FALLBACK_RETRY_TTL: Duration = Duration::from_secs(60 * 60);
+
+    /// How long fetching is not retried after it failed with no fallback copy.
+    pub const FAILURE_RETRY_TTL: Duration = Duration::from_secs(5 * 60);
+
+    /// Lowest GVL specification version the parser understands.
+    const MIN_GVL_SPECIFICATION_VERSION: u8 = 3;
+
+    /// GVL v3 JSON document, reduced to the fields needed for consent checks.
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct GvlDocument {
+        #[serde(default)]
+        gvl_specification_version: u8,
+        vendor_list_version: u32,
+        #[serde(default)]
+        tcf_policy_version: u16,
+        last_updated: String,
+        vendors: HashMap<String, GvlVendor>,
     }
-    
-    /// Gets cached vendor list or fetches if stale
-    /// TODO: Implement KV store caching with weekly refresh
-    pub async fn get_vendor_list() -> Result<VendorList, String> {
-        // Implementation would:
-        // 1. Check KV store for cached list
-        // 2. If missing or older than 1 week, fetch new
-        // 3. Return cached or fresh list
-        Err("Not implemented yet".to_string())
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct GvlVendor {
+        id: u16,
+        name: String,
+        #[serde(default)]
+        purposes: Vec<u8>,
+        #[serde(default)]
+        leg_int_purposes: Vec<u8>,
+        #[serde(default)]
+        features: Vec<u8>,
+        #[serde(default)]
+        special_features: Vec<u8>,
+        #[serde(default)]
+        flexible_purposes: Vec<u8>,
+        #[serde(default)]
+        special_purposes: Vec<u8>,
+        #[serde(default)]
+        data_declaration: Vec<u8>,
+        #[serde(default)]
+        data_retention: Option<DataRetention>,
+        #[serde(default)]
+        uses_cookies: bool,
+        #[serde(default)]
+        cookie_max_age_seconds: Option<i64>,
+        #[serde(default)]
+        uses_non_cookie_access: bool,
+        #[serde(default)]
+        device_storage_disclosure_url: Option<String>,
+        #[serde(default)]
+        urls: Vec<VendorUrls>,
+        #[serde(default)]
+        deleted_date: Option<String>,
+    }
+
+    fn vendor_list_error(message: impl Into<String>) -> TrustedServerError {
+        TrustedServerError::VendorList {
+            message: message.into(),
+        }
+    }
+
+    /// KV key the vendor list fetched from `url` is cached under.
+    fn cache_key(url: &str) -> String {
+        format!("gvl:{}", url)
+    }
+
+    /// KV key the last accepted vendor list fetched from `url` is kept under,
+    /// without expiry, as a fallback.
+    fn fallback_key(url: &str) -> String {
+        format!("gvl-last:{}", url)
+    }
+
+    /// KV key marking that fetching the vendor list from `url` recently failed
+    /// with no fallback copy to serve.
+    fn failure_key(url: &str) -> String {
+        format!("gvl-failed:{}", url)
+    }
+
+    /// Reason a vendor list could not be used as fetched.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Rejection {
+        /// The list could not be fetched or parsed.
+        FetchFailed,
+        /// The list version differs from `gvl.pinned_version`.
+        VersionMismatch,
+        /// The list is older than `gvl.max_age_days`.
+        Stale,
+    }
+
+    impl Rejection {
+        fn as_str(self) -> &'static str {
+            match self {
+                Self::FetchFailed => "fetch_failed",
+                Self::VersionMismatch => "version_mismatch",
+                Self::Stale => "stale",
+            }
+        }
+    }
+
+    /// Checks a fetched vendor list against the pinned version and the grace
+    /// period, as of the Unix timestamp `now`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the [`Rejection`] if the list should not be used.
+    pub fn check_vendor_list(
+        settings: &Settings,
+        vendor_list: &VendorList,
+        now: i64,
+    ) -> Result<(), Rejection> {
+        if settings
+            .gvl
+            .pinned_version
+            .is_some_and(|pinned| pinned != vendor_list.version)
+        {
+            return Err(Rejection::VersionMismatch);
+        }
+        let max_age = i64::from(settings.gvl.max_age_days) * 24 * 60 * 60;
+        if max_age > 0 && now - vendor_list.last_updated > max_age {
+            return Err(Rejection::Stale);
+        }
+        Ok(())
+    }
+
+    /// Logs a structured warning about a rejected vendor list.
+    fn warn_rejected(
+        settings: &Settings,
+        rejection: Rejection,
+        fetched: Option<&VendorList>,
+        fallback: Option<&VendorList>,
+    ) {
+        let version = |list: Option<&VendorList>| {
+            list.map_or_else(|| "none".to_string(), |list| list.version.to_string())
+        };
+        log::warn!(
+            "gvl_rejected reason={} url={} fetched_version={} fetched_last_updated={} pinned_version={} fallback_version={}",
+            rejection.as_str(),
+            settings.gvl.url,
+            version(fetched),
+            fetched.map_or(0, |list| list.last_updated),
+            settings
+                .gvl
+                .pinned_version
+                .map_or_else(|| "none".to_string(), |pinned| pinned.to_string()),
+            version(fallback),
+        );
+    }
+
+    /// Resolves a rejected fetch when there is no cached copy to fall back to.
+    ///
+    /// A stale list is still better than none and is used; a list with the
+    /// wrong version or a failed fetch is an error.
+    fn without_fallback(
+        settings: &Settings,
+        rejection: Rejection,
+        fetched: Result<VendorList, Report<TrustedServerError>>,
+    ) -> Result<VendorList, Report<TrustedServerError>> {
+        warn_rejected(settings, rejection, fetched.as_ref().ok(), None);
+        match fetched? {
+            vendor_list if rejection == Rejection::Stale => Ok(vendor_list),
+            vendor_list => Err(Report::new(vendor_list_error(format!(
+                "Global Vendor List version {} does not match pinned version {}",
+                vendor_list.version,
+                settings.gvl.pinned_version.unwrap_or_default()
+            )))),
+        }
+    }
+
+    /// Parses a GVL v3 JSON document into a [`VendorList`].
+    ///
+    /// Vendors with a `deletedDate` are no longer valid and are skipped.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::VendorList`] if the document is not valid GVL JSON
+    ///   or uses a specification version older than v3
+    pub fn parse_vendor_list(json: &[u8]) -> Result<VendorList, Report<TrustedServerError>> {
+        let document: GvlDocument = serde_json::from_slice(json)
+            .change_context(vendor_list_error("Failed to parse Global Vendor List"))?;
+        if document.gvl_specification_version < MIN_GVL_SPECIFICATION_VERSION {
+            return Err(Report::new(vendor_list_error(format!(
+                "Unsupported Global Vendor List specification version {}",
+                document.gvl_specification_version
+            ))));
+        }
+
+        let last_updated = chrono::DateTime::parse_from_rfc3339(&document.last_updated)
+            .map(|date| date.timestamp())
+            .change_context(vendor_list_error(
+                "Invalid lastUpdated in Global Vendor List",
+            ))?;
+
+        let vendors = document
+            .vendors
+            .into_values()
+            .filter(|vendor| vendor.deleted_date.is_none())
+            .map(|vendor| {
+                (
+                    vendor.id,
+                    VendorInfo {
+                        id: vendor.id,
+                        name: vendor.name,
+                        purposes: vendor.purposes,
+                        legitimate_interests: vendor.leg_int_purposes,
+                        features: vendor.features,
+                        special_features: vendor.special_features,
+                        flexible_purposes: vendor.flexible_purposes,
+                        special_purposes: vendor.special_purposes,
+                        data_declaration: vendor.data_declaration,
+                        data_retention: vendor.data_retention,
+                        uses_cookies: vendor.uses_cookies,
+                        cookie_max_age_seconds: vendor.cookie_max_age_seconds,
+                        uses_non_cookie_access: vendor.uses_non_cookie_access,
+                        device_storage_disclosure_url: vendor.device_storage_disclosure_url,
+                        urls: vendor.urls,
+                    },
+                )
+            })
+            .collect();
+
+        Ok(VendorList {
+            vendors,
+            last_updated,
+            version: document.vendor_list_version,
+            tcf_policy_version: document.tcf_policy_version,
+        })
+    }
+
+    /// Fetches and parses the latest IAB Global Vendor List.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::VendorList`] if the request fails, returns a non-200
+    ///   status or the response is not valid GVL JSON
+    pub fn fetch_vendor_list(
+        settings: &Settings,
+    ) -> Result<VendorList, Report<TrustedServerError>> {
+        let mut resp = Request::get(&settings.gvl.url)
+            .send(&settings.gvl.backend)
+            .change_context(vendor_list_error("Failed to fetch Global Vendor List"))?;
+        if resp.get_status() != StatusCode::OK {
+            return Err(Report::new(vendor_list_error(format!(
+                "Unexpected status {} fetching Global Vendor List",
+                resp.get_status()
+            ))));
+        }
+
+        parse_vendor_list(&resp.take_body_bytes())
+    }
+
+    /// Gets the cached vendor list, fetching and caching it when missing or expired.
+    ///
+    /// Returns `None` when no GVL URL is configured. Failing to cache a freshly
+    /// fetched list is logged and does not fail the lookup.
+    ///
+    /// A fetched list that fails [`check_vendor_list`], or a failed fetch, logs
+    /// a `gvl_rejected` warning and falls back to the last accepted copy, which
+    /// is then served for [`FALLBACK_RETRY_TTL`] before fetching again. Without
+    /// a copy to fall back to, a stale list is served for as long, and other
+    /// failures are remembered for [`FAILURE_RETRY_TTL`], during which lookups
+    /// fail without fetching again.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the cache store cannot be read
+    /// - [`TrustedServerError::VendorList`] if the list has to be fetched, fetching
+    ///   fails or the version is not the pinned one, and no copy is cached
+    pub fn get_vendor_list(
+        settings: &Settings,
+    ) -> Result<Option<VendorList>, Report<TrustedServerError>> {
+        if settings.gvl.url.is_empty() {
+            return Ok(None);
+        }
+        let now = chrono::Utc::now().timestamp();
+        if settings.gvl.cache_store.is_empty() {
+            let vendor_list = fetch_vendor_list(settings)?;
+            return match check_vendor_list(settings, &vendor_list, now) {
+                Ok(()) => Ok(Some(vendor_list)),
+                Err(rejection) => without_fallback(settings, rejection, Ok(vendor_list)).map(Some),
+            };
+        }
+
+        let store = JsonKvStore::open(&settings.gvl.cache_store)?;
+        let key = cache_key(&settings.gvl.url);
+        if let Some(vendor_list) = store.get::<VendorList>(&key)? {
+            return Ok(Some(vendor_list));
+        }
+        let failure_key = failure_key(&settings.gvl.url);
+        if store.get_text(&failure_key)?.is_some() {
+            return Err(Report::new(vendor_list_error(
+                "Global Vendor List recently failed to fetch, not retrying yet",
+            )));
+        }
+
+        let fetched = fetch_vendor_list(settings);
+        let rejection = match &fetched {
+            Ok(vendor_list) => check_vendor_list(settings, vendor_list, now).err(),
+            Err(e) => {
+                log::warn!("Failed to fetch Global Vendor List: {:?}", e);
+                Some(Rejection::FetchFailed)
+            }
+        };
+        let fallback_key = fallback_key(&settings.gvl.url);
+        let Some(rejection) = rejection else {
+            let vendor_list = fetched?;
+            if let Err(e) = store
+                .put_with_ttl(&key, &vendor_list, CACHE_TTL)
+                .and_then(|()| store.put(&fallback_key, &vendor_list))
+            {
+                log::warn!("Failed to cache Global Vendor List: {:?}", e);
+            }
+            return Ok(Some(vendor_list));
+        };
+
+        let Some(fallback) = store.get::<VendorList>(&fallback_key)? else {
+            let result = without_fallback(settings, rejection, fetched);
+            let cached = match &result {
+                Ok(stale) => store.put_with_ttl(&key, stale, FALLBACK_RETRY_TTL),
+                Err(_) => store.put_text_with_ttl(&failure_key, "", FAILURE_RETRY_TTL),
+            };
+            if let Err(e) = cached {
+                log::warn!("Failed to cache Global Vendor List failure: {:?}", e);
+            }
+            return result.map(Some);
+        };
+        warn_rejected(settings, rejection, fetched.as_ref().ok(), Some(&fallback));
+        if let Err(e) = store.put_with_ttl(&key, &fallback, FALLBACK_RETRY_TTL) {
+            log::warn!("Failed to cache Global Vendor List: {:?}", e);
+        }
+        Ok(Some(fallback))
+    }
+
+    /// Like [`get_vendor_list`], but logs failures and returns `None` instead.
+    ///
+    /// Consent checks fall back to TC string signals alone without a vendor list.
+    pub fn load_vendor_list(settings: &Settings) -> Option<VendorList> {
+        get_vendor_list(settings).unwrap_or_else(|e| {
+            log::warn!("Global Vendor List unavailable: {:?}", e);
+            None
+        })
     }
 }
 
@@ -370,20 +1207,349 @@ mod tests {
         let mut vendor_list = VendorList::new();
         
         // Add test vendor
-        vendor_list.vendors.insert(45, VendorInfo {
-            id: 45,
-            name: "Equativ".to_string(),
-            purposes: vec![1, 2, 3, 4, 7],
-            legitimate_interests: vec![],
-            features: vec![],
-            special_features: vec![],
-        });
-        
+        vendor_list.vendors.insert(
+            45,
+            VendorInfo {
+                id: 45,
+                name: "Equativ".to_string(),
+                purposes: vec![1, 2, 3, 4, 7],
+                legitimate_interests: vec![],
+                features: vec![],
+                special_features: vec![],
+                ..Default::default()
+            },
+        );
+
         assert!(vendor_list.is_valid_vendor(45));
         assert!(!vendor_list.is_valid_vendor(999));
         assert!(vendor_list.vendor_declares_purpose(45, 2));
         assert!(!vendor_list.vendor_declares_purpose(45, 99));
     }
+
+    #[test]
+    fn test_parse_vendor_list() {
+        let json = br#"{
+            "gvlSpecificationVersion": 3,
+            "vendorListVersion": 71,
+            "tcfPolicyVersion": 5,
+            "lastUpdated": "2024-10-17T16:05:26Z",
+            "vendors": {
+                "45": {
+                    "id": 45,
+                    "name": "Equativ",
+                    "purposes": [1, 2, 3, 4],
+                    "legIntPurposes": [7],
+                    "flexiblePurposes": [2, 7],
+                    "specialPurposes": [1],
+                    "features": [1, 2],
+                    "specialFeatures": []
+                },
+                "99": {
+                    "id": 99,
+                    "name": "Deleted Vendor",
+                    "purposes": [1],
+                    "deletedDate": "2023-01-01T00:00:00Z"
+                }
+            }
+        }"#;
+
+        let vendor_list = vendor_list_manager::parse_vendor_list(json).unwrap();
+        assert_eq!(vendor_list.version, 71);
+        assert_eq!(vendor_list.last_updated, 1729181126);
+        assert!(vendor_list.is_valid_vendor(45));
+        assert!(!vendor_list.is_valid_vendor(99));
+
+        let equativ = vendor_list.get_vendor(45).unwrap();
+        assert_eq!(equativ.name, "Equativ");
+        assert_eq!(equativ.legitimate_interests, vec![7]);
+        assert_eq!(equativ.features, vec![1, 2]);
+        assert!(vendor_list.vendor_declares_purpose(45, 7));
+    }
+
+    #[test]
+    fn test_parse_vendor_list_invalid() {
+        assert!(vendor_list_manager::parse_vendor_list(b"not json").is_err());
+        assert!(vendor_list_manager::parse_vendor_list(
+            br#"{"vendorListVersion": 1, "lastUpdated": "yesterday", "vendors": {}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_check_vendor_list() {
+        use vendor_list_manager::{check_vendor_list, Rejection};
+
+        let mut settings = crate::test_support::tests::create_test_settings();
+        let vendor_list = VendorList {
+            version: 71,
+            last_updated: 1_700_000_000,
+            ..VendorList::new()
+        };
+        let day = 24 * 60 * 60;
+
+        assert_eq!(
+            check_vendor_list(&settings, &vendor_list, 1_700_000_000 + 30 * day),
+            Ok(())
+        );
+        assert_eq!(
+            check_vendor_list(&settings, &vendor_list, 1_700_000_000 + 31 * day),
+            Err(Rejection::Stale)
+        );
+
+        settings.gvl.max_age_days = 0;
+        assert_eq!(check_vendor_list(&settings, &vendor_list, i64::MAX), Ok(()));
+
+        settings.gvl.pinned_version = Some(71);
+        assert_eq!(
+            check_vendor_list(&settings, &vendor_list, 1_700_000_000),
+            Ok(())
+        );
+        settings.gvl.pinned_version = Some(72);
+        assert_eq!(
+            check_vendor_list(&settings, &vendor_list, 1_700_000_000),
+            Err(Rejection::VersionMismatch)
+        );
+    }
+
+    #[test]
+    fn test_get_vendor_list_falls_back_to_last_copy() {
+        let mut settings = crate::test_support::tests::create_test_settings();
+        settings.gvl.url = "https://gvl.invalid/fallback.json".to_string();
+        settings.gvl.cache_store = "test_gvl_store".to_string();
+        let fallback = VendorList {
+            version: 70,
+            ..VendorList::new()
+        };
+        crate::kv_store::JsonKvStore::open("test_gvl_store")
+            .unwrap()
+            .put("gvl-last:https://gvl.invalid/fallback.json", &fallback)
+            .unwrap();
+
+        // Fetching fails in tests, so the last accepted copy is served
+        let vendor_list = vendor_list_manager::get_vendor_list(&settings)
+            .unwrap()
+            .unwrap();
+        assert_eq!(vendor_list.version, 70);
+    }
+
+    #[test]
+    fn test_get_vendor_list_caches_failures() {
+        let mut settings = crate::test_support::tests::create_test_settings();
+        settings.gvl.url = "https://gvl.invalid/failing.json".to_string();
+        settings.gvl.cache_store = "test_gvl_store".to_string();
+        let store = crate::kv_store::JsonKvStore::open("test_gvl_store").unwrap();
+
+        // Fetching fails in tests and there is no copy to fall back to
+        assert!(vendor_list_manager::get_vendor_list(&settings).is_err());
+        assert!(store
+            .get_text("gvl-failed:https://gvl.invalid/failing.json")
+            .unwrap()
+            .is_some());
+
+        // Later lookups fail from the cached failure
+        let err = vendor_list_manager::get_vendor_list(&settings).unwrap_err();
+        assert!(format!("{err:?}").contains("not retrying yet"));
+    }
+
+    #[test]
+    fn test_get_vendor_list_disabled() {
+        let settings = crate::test_support::tests::create_test_settings();
+        assert!(vendor_list_manager::get_vendor_list(&settings)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_parse_vendor_list_v3_fields() {
+        let json = br#"{
+            "gvlSpecificationVersion": 3,
+            "vendorListVersion": 71,
+            "tcfPolicyVersion": 5,
+            "lastUpdated": "2024-10-17T16:05:26Z",
+            "vendors": {
+                "45": {
+                    "id": 45,
+                    "name": "Equativ",
+                    "purposes": [1, 2],
+                    "legIntPurposes": [],
+                    "flexiblePurposes": [7],
+                    "specialPurposes": [1, 2],
+                    "features": [],
+                    "specialFeatures": [1],
+                    "dataDeclaration": [1, 3, 4],
+                    "dataRetention": { "stdRetention": 365, "purposes": { "2": 30 }, "specialPurposes": {} },
+                    "usesCookies": true,
+                    "cookieMaxAgeSeconds": 31536000,
+                    "usesNonCookieAccess": false,
+                    "deviceStorageDisclosureUrl": "https://equativ.com/deviceStorage.json",
+                    "urls": [{ "langId": "en", "privacy": "https://equativ.com/privacy", "legIntClaim": "https://equativ.com/li" }]
+                }
+            }
+        }"#;
+
+        let vendor_list = vendor_list_manager::parse_vendor_list(json).unwrap();
+        assert_eq!(vendor_list.tcf_policy_version, 5);
+
+        let equativ = vendor_list.get_vendor(45).unwrap();
+        assert_eq!(equativ.flexible_purposes, vec![7]);
+        assert_eq!(equativ.special_purposes, vec![1, 2]);
+        assert_eq!(equativ.data_declaration, vec![1, 3, 4]);
+        let retention = equativ.data_retention.as_ref().unwrap();
+        assert_eq!(retention.std_retention, Some(365));
+        assert_eq!(retention.purposes.get(&2), Some(&30));
+        assert!(equativ.uses_cookies);
+        assert_eq!(equativ.cookie_max_age_seconds, Some(31536000));
+        assert_eq!(equativ.urls[0].lang_id, "en");
+        assert!(vendor_list.vendor_declares_legitimate_interest(45, 7));
+        assert!(!vendor_list.vendor_declares_legitimate_interest(45, 2));
+    }
+
+    #[test]
+    fn test_parse_vendor_list_rejects_pre_v3() {
+        let json = br#"{
+            "gvlSpecificationVersion": 2,
+            "vendorListVersion": 10,
+            "lastUpdated": "2020-01-01T00:00:00Z",
+            "vendors": {}
+        }"#;
+        assert!(vendor_list_manager::parse_vendor_list(json).is_err());
+    }
+
+    #[test]
+    fn test_tcf_v2_2_legitimate_interest() {
+        // Policy version 4; LI signalled for purposes 2, 3 and 7 and vendor 45
+        let tc_string = "COztr8AOztr8AAHABBENAPEAAPAAAGIAAAAAAWgAAAAAACAFoAAAAAAAgAA";
+        let tc_model = TcModelV2::try_from(tc_string).unwrap();
+        let consent = TcfConsent::from_tc_model(tc_model, tc_string.to_string()).unwrap();
+
+        assert_eq!(consent.policy_version, 4);
+        assert_eq!(consent.vendor_list_version, 15);
+        assert!(consent.is_tcf_v2_2());
+        assert!(consent.has_legitimate_interest(45, 2, None));
+        assert!(consent.has_legitimate_interest(45, 7, None));
+        // Purpose 3 is consent-only under TCF v2.2
+        assert!(!consent.has_legitimate_interest(45, 3, None));
+        assert!(!consent.has_legitimate_interest(46, 7, None));
+        assert!(consent.has_consent(45, purpose_ids::ADVERTISING, None));
+    }
+
+    #[test]
+    fn test_publisher_restrictions() {
+        // Purpose 2 not allowed for vendor 45, purpose 7 requires LI for vendors 45 and 46,
+        // plus an undefined restriction on purpose 1 that must be ignored
+        let tc_string =
+            "COztr8AOztr8AAHABBENAPEAAPIAAEIAAAAAAXAAAAAAADAC0AAAAAAAQAwgAEAFo8AEAC0AFwOACAC0";
+        let tc_model = TcModelV2::try_from(tc_string).unwrap();
+        let consent = TcfConsent::from_tc_model(tc_model, tc_string.to_string()).unwrap();
+
+        assert_eq!(consent.publisher_restrictions.len(), 2);
+        assert_eq!(
+            consent.publisher_restriction(45, 2),
+            Some(RestrictionType::NotAllowed)
+        );
+        assert_eq!(consent.publisher_restriction(46, 2), None);
+
+        assert!(!consent.has_consent(45, &[2], None));
+        assert!(consent.has_consent(46, &[2], None));
+        // Vendor 45 has LI for purpose 7, vendor 46 only has consent
+        assert!(consent.has_consent(45, &[7], None));
+        assert!(!consent.has_consent(46, &[7], None));
+        assert!(consent.has_consent(45, &[1], None));
+    }
+
+    #[test]
+    fn test_publisher_require_consent_with_vendor_list() {
+        let mut vendor_list = VendorList::new();
+        vendor_list.vendors.insert(
+            45,
+            VendorInfo {
+                id: 45,
+                name: "Equativ".to_string(),
+                purposes: vec![1],
+                legitimate_interests: vec![7],
+                ..Default::default()
+            },
+        );
+
+        let mut consent = TcfConsent::default();
+        consent.vendor_consents.insert(45);
+        consent.purpose_consents.insert(7);
+        assert!(consent.has_consent(45, &[7], Some(&vendor_list)));
+
+        consent.publisher_restrictions.push(PublisherRestriction {
+            purpose_id: 7,
+            restriction_type: RestrictionType::RequireConsent,
+            vendors: vec![45],
+        });
+        assert!(!consent.has_consent(45, &[7], Some(&vendor_list)));
+
+        vendor_list
+            .vendors
+            .get_mut(&45)
+            .unwrap()
+            .flexible_purposes
+            .push(7);
+        assert!(consent.has_consent(45, &[7], Some(&vendor_list)));
+    }
+
+    #[test]
+    fn test_publisher_tc_segment() {
+        // Core purposes 1-9 and 11; Publisher TC consents to publisher purpose 1 only
+        let tc_string = "COw4XqLOw4XqLAAAAAENAXCf-v-gAAAfwIAAACngAI8AEFABgACAA4A.IAPPwAPrwA.QAPPwAPrwA.cAEAPAAAC7gAHw4AAA";
+        let consent = decode_tc_string(tc_string).unwrap();
+
+        assert!(consent.has_publisher_tc);
+        assert!(consent.has_publisher_consent(&[1]));
+        assert!(!consent.has_publisher_consent(&[1, 2]));
+        assert!(consent.purpose_consents.contains(2));
+        assert!(consent.is_vendor_disclosed(19));
+        assert!(!consent.is_vendor_disclosed(7));
+    }
+
+    #[test]
+    fn test_publisher_consent_without_publisher_tc() {
+        let tc_string = "COvFyGBOvFyGBAbAAAENAPCAAOAAAAAAAAAAAEEUACCKAAA";
+        let consent = decode_tc_string(tc_string).unwrap();
+
+        assert!(!consent.has_publisher_tc);
+        assert!(consent.has_publisher_consent(&[1, 2, 3]));
+        assert!(!consent.has_publisher_consent(&[4]));
+        assert!(consent.is_vendor_disclosed(7));
+    }
+
+    #[test]
+    fn test_special_feature_opt_ins() {
+        // Opted in to Special Feature 1 (precise geolocation) only
+        let tc_string = "COztr8AOztr8AAHABBENAPEIAMAAAAAAAAAAAWgAAAAAACAAAAA";
+        let tc_model = TcModelV2::try_from(tc_string).unwrap();
+        let consent = TcfConsent::from_tc_model(tc_model, tc_string.to_string()).unwrap();
+
+        assert!(consent.has_special_feature(special_feature_ids::PRECISE_GEOLOCATION));
+        assert!(!consent.has_special_feature(special_feature_ids::DEVICE_SCANNING));
+        assert!(consent.allows_precise_geolocation());
+    }
+
+    #[test]
+    fn test_precise_geolocation_without_opt_in() {
+        let mut consent = TcfConsent::default();
+        assert!(consent.allows_precise_geolocation());
+
+        consent.gdpr_applies = true;
+        assert!(!consent.allows_precise_geolocation());
+    }
+
+    #[test]
+    fn test_tcf_pre_v2_2_downgraded() {
+        // Same signals as above, created under policy version 2
+        let tc_string = "COztr8AOztr8AAHABBENAPCAAPAAAGIAAAAAAWgAAAAAACAFoAAAAAAAgAA";
+        let tc_model = TcModelV2::try_from(tc_string).unwrap();
+        let consent = TcfConsent::from_tc_model(tc_model, tc_string.to_string()).unwrap();
+
+        assert!(!consent.is_tcf_v2_2());
+        assert!(consent.purpose_legitimate_interests.is_empty());
+        assert!(!consent.has_legitimate_interest(45, 7, None));
+        assert!(consent.has_consent(45, purpose_ids::ADVERTISING, None));
+    }
     
     #[test]
     fn test_advertising_consent_levels() {
@@ -397,18 +1563,18 @@ mod tests {
         );
         
         // Grant vendor consent
-        consent.vendor_consents.insert(vendor_id, true);
+        consent.vendor_consents.insert(vendor_id);
         
         // Test basic advertising only
-        consent.purpose_consents.insert(2, true);
+        consent.purpose_consents.insert(2);
         assert_eq!(
             consent.get_advertising_consent_level(vendor_id, None),
             AdvertisingConsentLevel::BasicOnly
         );
         
         // Test personalized advertising
-        consent.purpose_consents.insert(3, true);
-        consent.purpose_consents.insert(4, true);
+        consent.purpose_consents.insert(3);
+        consent.purpose_consents.insert(4);
         assert_eq!(
             consent.get_advertising_consent_level(vendor_id, None),
             AdvertisingConsentLevel::Personalized
@@ -417,8 +1583,67 @@ mod tests {
     
     #[test]
     fn test_get_tcf_consent_no_cookie() {
+        let settings = crate::test_support::tests::create_test_settings();
         let req = Request::get("https://example.com");
-        let consent = get_tcf_consent_from_request(&req);
+        let consent = get_tcf_consent_from_request(&settings, &req);
         assert!(consent.is_none());
+
+        // Without a client location GDPR is assumed to apply, as by Regime::for_request
+        let consent = get_tcf_consent_or_default(&settings, &req);
+        assert!(consent.gdpr_applies);
+        assert!(consent.purpose_consents.is_empty());
+    }
+
+    #[test]
+    fn test_decode_tc_string_reuses_result() {
+        let tc_string = "COvFyGBOvFyGBAbAAAENAPCAAOAAAAAAAAAAAEEUACCKAAA";
+        let first = decode_tc_string(tc_string).unwrap();
+        let second = decode_tc_string(tc_string).unwrap();
+        assert_eq!(first.timestamp, second.timestamp);
+        assert_eq!(first.purpose_consents, second.purpose_consents);
+
+        // A different TC string is decoded afresh
+        let other = decode_tc_string("COvFyGBOvFyGBAbAAAENAPCAAPAAAAAAAAAAAEEUACCKAAA").unwrap();
+        assert!(other.purpose_consents.contains(4));
+        assert!(!first.purpose_consents.contains(4));
+        assert!(decode_tc_string("invalid").is_err());
+    }
+
+    #[test]
+    fn test_tcf_consent_timestamps() {
+        let tc_string = "COvFyGBOvFyGBAbAAAENAPCAAOAAAAAAAAAAAEEUACCKAAA";
+        let tc_model = TcModelV2::try_from(tc_string).unwrap();
+        let consent = TcfConsent::from_tc_model(tc_model, tc_string.to_string()).unwrap();
+
+        assert_eq!(consent.created, 1582243059);
+        assert_eq!(consent.last_updated, 1582243059);
+    }
+
+    #[test]
+    fn test_tcf_consent_expiry() {
+        let consent = TcfConsent {
+            last_updated: chrono::Utc::now().timestamp() - 30 * 24 * 60 * 60,
+            ..Default::default()
+        };
+        assert!(!consent.is_expired(0));
+        assert!(!consent.is_expired(395));
+        assert!(consent.is_expired(7));
+    }
+
+    #[test]
+    fn test_get_tcf_consent_expired() {
+        let mut settings = crate::test_support::tests::create_test_settings();
+        let req = Request::get("https://example.com").with_header(
+            "Cookie",
+            "euconsent-v2=COvFyGBOvFyGBAbAAAENAPCAAOAAAAAAAAAAAEEUACCKAAA",
+        );
+        assert!(get_tcf_consent_from_request(&settings, &req).is_some());
+
+        settings.gdpr.tcf_max_age_days = 395;
+        let consent = get_tcf_consent_from_request(&settings, &req).unwrap();
+        assert!(consent.gdpr_applies);
+        assert!(consent.tc_string.is_empty());
+        assert!(consent.purpose_consents.is_empty());
+        assert!(!consent.allows_precise_geolocation());
     }
 }
\ No newline at end of file
@@ -55,9 +55,15 @@ mod tests {
                     match TcfConsent::from_tc_model(tc_model, tcf_string.to_string()) {
                         Ok(consent) => {
                             println!("✓ Created TcfConsent successfully");
-                            println!("  Purposes with consent: {:?}", consent.purpose_consents.keys().collect::<Vec<_>>());
-                            println!("  Vendors with consent: {:?}", consent.vendor_consents.keys().collect::<Vec<_>>());
-                            
+                            println!(
+                                "  Purposes with consent: {:?}",
+                                consent.purpose_consents.iter().collect::<Vec<_>>()
+                            );
+                            println!(
+                                "  Vendors with consent: {:?}",
+                                consent.vendor_consents.iter().collect::<Vec<_>>()
+                            );
+
                             // Test consent checking
                             let vendor_2 = 2u16;
                             let vendor_999 = 999u16;
@@ -1,5 +1,3 @@
-use std::collections::HashMap;
-
 pub const HTML_TEMPLATE: &str = r#"<!DOCTYPE html>
 <html lang="en">
 <head>
@@ -60,6 +58,48 @@ pub const HTML_TEMPLATE: &str = r#"<!DOCTYPE html>
             text-align: center;
             margin: 30px 0;
         }
+        .native-ad {
+            display: inline-block;
+            max-width: 600px;
+            text-align: left;
+            border: 1px solid #ddd;
+            border-radius: 8px;
+            overflow: hidden;
+        }
+        .native-ad-link {
+            color: inherit;
+            text-decoration: none;
+        }
+        .native-ad-image {
+            width: 100%;
+            height: auto;
+            display: block;
+        }
+        .native-ad-title {
+            margin: 15px 15px 5px;
+        }
+        .native-ad-sponsored,
+        .native-ad-description {
+            margin: 0 15px 10px;
+            color: #555;
+        }
+        .native-ad-cta {
+            display: inline-block;
+            margin: 0 15px 15px;
+            padding: 8px 16px;
+            background: #4CAF50;
+            color: white;
+            border-radius: 4px;
+        }
+        .native-ad-privacy {
+            display: block;
+            margin: 0 15px 10px;
+            font-size: 0.8em;
+            color: #999;
+        }
+        .native-ad-pixel {
+            display: none;
+        }
         
         /* GDPR Consent Banner */
         #gdpr-banner {
@@ -223,7 +263,7 @@ pub const HTML_TEMPLATE: &str = r#"<!DOCTYPE html>
                     const adLink = document.createElement('a');
                     adLink.href = 'https://iabtechlab.com/?potsi-test%3F';
                     const adImage = document.createElement('img');
-                    adImage.src = data.creativeUrl.replace('creatives.sascdn.com', 'creatives.auburndao.com');
+                    adImage.src = data.creativeUrl;
                     adImage.alt = 'Ad Creative';
                     adLink.appendChild(adImage);
                     adContainer.appendChild(adLink);
@@ -473,7 +513,7 @@ pub const GAM_TEST_TEMPLATE: &str = r#"
 
         <div class="phase">
             <h3>Phase 2: Dynamic Request Building</h3>
-            <p>Test dynamic parameter generation with hardcoded prmtvctx value.</p>
+            <p>Test dynamic parameter generation with the audience segments of the data providers.</p>
             
             <div class="test-section">
                 <h4>Dynamic GAM Request</h4>
@@ -491,7 +531,7 @@ pub const GAM_TEST_TEMPLATE: &str = r#"
                 <h4>Ad Render Test</h4>
                 <p>Test rendering the GAM response as an actual ad in an iframe:</p>
                 <button onclick="testAdRender()">🎯 Render Ad in iFrame</button>
-                <button onclick="window.open('/gam-render', '_blank')">🔄 Open Render Page</button>
+                <button onclick="window.open('/gam-render?pvsid=' + pvsid, '_blank')">🔄 Open Render Page</button>
                 <div id="renderResult" class="status info" style="display: none;">
                     Opening ad render page in new tab...
                 </div>
@@ -513,6 +553,9 @@ pub const GAM_TEST_TEMPLATE: &str = r#"
     </div>
 
     <script>
+        // Page view ID of all GAM requests from this page
+        const pvsid = Math.floor(Math.random() * (2 ** 53 - 1)) + 1;
+
         // Display request headers for debugging
         function displayHeaders() {
             const headers = {};
@@ -604,7 +647,7 @@ pub const GAM_TEST_TEMPLATE: &str = r#"
                 const trustedServerId = mainResponse.headers.get('X-Synthetic-Trusted-Server');
                 
                 // Now test the GAM request
-                const response = await fetch('/gam-test', {
+                const response = await fetch('/gam-test?pvsid=' + pvsid, {
                     headers: {
                         'X-Consent-Advertising': 'true',
                         'X-Synthetic-Fresh': freshId || '',
@@ -662,7 +705,7 @@ pub const GAM_TEST_TEMPLATE: &str = r#"
             resultDiv.textContent = 'Opening ad render page in new tab...';
             
             // Open the render page in a new tab
-            window.open('/gam-render', '_blank');
+            window.open('/gam-render?pvsid=' + pvsid, '_blank');
             
             // Update the result message
             setTimeout(() => {
@@ -680,66 +723,44 @@ pub const GAM_TEST_TEMPLATE: &str = r#"
 </body>
 </html>
 "#;
-// GAM Configuration Template
-#[allow(dead_code)]
-struct GamConfigTemplate {
-    publisher_id: String,
-    ad_units: Vec<AdUnitConfig>,
-    page_context: PageContext,
-    data_providers: Vec<DataProvider>,
-}
-#[allow(dead_code)]
-struct AdUnitConfig {
-    name: String,
-    sizes: Vec<String>,
-    position: String,
-    targeting: HashMap<String, String>,
-}
-#[allow(dead_code)]
-struct PageContext {
-    page_type: String,
-    section: String,
-    keywords: Vec<String>,
-}
-#[allow(dead_code)]
-enum DataProvider {
-    Permutive(PermutiveConfig),
-    Lotame(LotameConfig),
-    Neustar(NeustarConfig),
-    Custom(CustomProviderConfig),
-}
-#[allow(dead_code)]
-struct PermutiveConfig {}
-#[allow(dead_code)]
-struct LotameConfig {}
-#[allow(dead_code)]
-struct NeustarConfig {}
-#[allow(dead_code)]
-struct CustomProviderConfig {}
-#[allow(dead_code)]
-trait DataProviderTrait {
-    fn get_user_segments(&self, user_id: &str) -> Vec<String>;
-}
-
-#[allow(dead_code)]
-struct RequestContext {
-    user_id: String,
-    page_url: String,
-    consent_status: bool,
-}
-
-#[allow(dead_code)]
-struct DynamicGamBuilder {
-    base_config: GamConfigTemplate,
-    context: RequestContext,
-    data_providers: Vec<Box<dyn DataProviderTrait>>,
-}
-
-// Instead of hardcoded strings, use templates:
-// "cust_params": "{{#each data_providers}}{{name}}={{segments}}&{{/each}}puid={{user_id}}"
-
-// This could generate:
-// "permutive=129627,137412...&lotame=segment1,segment2&puid=abc123"
 
-// let context = data_provider_manager.build_context(&user_id, &request_context);
-// let gam_req_with_context = gam_req.with_dynamic_context(context);
+pub const CONSENT_DEBUG_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <title>Consent Debug - Trusted Server</title>
+    <style>
+        body { font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', Roboto, sans-serif; max-width: 1000px; margin: 0 auto; padding: 20px; }
+        th { text-align: left; padding-right: 20px; vertical-align: top; }
+        td { word-break: break-all; }
+        pre { background: #f5f5f5; padding: 10px; overflow-x: auto; }
+        .error { color: #c00; }
+    </style>
+</head>
+<body>
+    <h1>Consent Debug</h1>
+    {{#if consent.tc_string}}
+    <table>
+        <tr><th>Source</th><td>{{consent.source}}</td></tr>
+        <tr><th>TC string</th><td>{{consent.tc_string}}</td></tr>
+        {{#if consent.error}}
+        <tr><th>Error</th><td class="error">{{consent.error}}</td></tr>
+        {{else}}
+        <tr><th>Policy version</th><td>{{consent.policy_version}} (TCF v2.2: {{consent.tcf_v2_2}})</td></tr>
+        <tr><th>Vendor list version</th><td>{{consent.vendor_list_version}}</td></tr>
+        <tr><th>Created</th><td>{{consent.created}}</td></tr>
+        <tr><th>Last updated</th><td>{{consent.last_updated}} ({{consent.age_days}} days ago, expired: {{consent.expired}})</td></tr>
+        <tr><th>Purpose consents</th><td>{{#each consent.purposes.consents}}{{this}} {{/each}}</td></tr>
+        <tr><th>Purpose legitimate interests</th><td>{{#each consent.purposes.legitimate_interests}}{{this}} {{/each}}</td></tr>
+        <tr><th>Vendor consents</th><td>{{#each consent.vendors.consents}}{{this}} {{/each}}</td></tr>
+        <tr><th>Vendor legitimate interests</th><td>{{#each consent.vendors.legitimate_interests}}{{this}} {{/each}}</td></tr>
+        <tr><th>Special feature opt-ins</th><td>{{#each consent.special_features}}{{this}} {{/each}}</td></tr>
+        {{/if}}
+    </table>
+    {{else}}
+    <p>No TC string found. Pass one in the <code>tc_string</code> query parameter or set the <code>euconsent-v2</code> cookie.</p>
+    {{/if}}
+    <h2>Decoded</h2>
+    <pre>{{json}}</pre>
+</body>
+</html>"#;
@@ -1,6 +1,14 @@
 #[cfg(test)]
 pub mod tests {
-    use crate::settings::{AdServer, Gam, GamAdUnit, Prebid, Publisher, Settings, Synthetic};
+    use std::collections::HashMap;
+
+    use crate::settings::{
+        AdServer, Admin, Anonymization, Audience, BidCache, BidValidation, BidderHealth,
+        ComplianceLog, ConsentWebhook, CookiePrefix, CreativeFrame, CreativeRewrite,
+        DebugEndpoints, Gam, GamAdUnit, Gdpr, Gpc, Gvl, HouseAds, Identity, Lotame, Neustar,
+        Permutive, Prebid, PrebidCache, Profile, Publisher, Retention, Settings, SlotRegistry,
+        SupplyChainNode, Synthetic, Targeting, Uid2, UserIdMode, UserSync, WinNotifications,
+    };
 
     pub fn crate_test_settings_str() -> String {
         r#"
@@ -39,26 +47,82 @@ pub mod tests {
             ad_server: AdServer {
                 ad_partner_url: "https://test-adpartner.com".into(),
                 sync_url: "https://test-adpartner.com/synthetic_id={{synthetic_id}}".to_string(),
+                vendor_id: 45,
             },
             publisher: Publisher {
                 domain: "test-publisher.com".to_string(),
                 cookie_domain: ".test-publisher.com".to_string(),
                 origin_url: "origin.test-publisher.com".to_string(),
+                coppa: false,
             },
             prebid: Prebid {
                 server_url: "https://test-prebid.com/openrtb2/auction".to_string(),
+                bidders: HashMap::from([("smartadserver".to_string(), 45)]),
+                endpoints: Vec::new(),
+                schain: SupplyChainNode::default(),
+                stored_requests_kv_store: String::new(),
+                price_encryption: HashMap::new(),
+                auction_timeout_ms: 1000,
+                profile: Profile::Development,
+                test: false,
+                debug: false,
+                user_id: UserIdMode::Scoped,
+                gzip: false,
             },
+            prebid_cache: PrebidCache::default(),
+            targeting: Targeting::default(),
+            creative_rewrite: CreativeRewrite::default(),
+            win_notifications: WinNotifications::default(),
+            bid_validation: BidValidation::default(),
+            bid_cache: BidCache::default(),
+            bidder_health: BidderHealth::default(),
+            slot_registry: SlotRegistry::default(),
             gam: Gam {
                 publisher_id: "test-publisher-id".to_string(),
                 server_url: "https://securepubads.g.doubleclick.net/gampad/ads".to_string(),
                 ad_units: vec![GamAdUnit { name: "test-ad-unit".to_string(), size: "300x250".to_string() }],
+                vendor_id: 755,
+                ppid: true,
+                video_ad_unit: String::new(),
+                video_sizes: "640x480".to_string(),
             },
+            creative_frame: CreativeFrame::default(),
+            house_ads: HouseAds::default(),
+            audience: Audience::default(),
+            permutive: Permutive::default(),
+            lotame: Lotame::default(),
+            neustar: Neustar::default(),
+            data_providers: Vec::new(),
             synthetic: Synthetic {
                 counter_store: "test_counter_store".to_string(),
                 opid_store: "test-opid-store".to_string(),
                 secret_key: "test-secret-key".to_string(),
                 template: "{{client_ip}}:{{user_agent}}:{{first_party_id}}:{{auth_user_id}}:{{publisher_domain}}:{{accept_language}}".to_string(),
+                max_age_days: 395,
+                ipv4_prefix_len: 32,
+                ipv6_prefix_len: 128,
+                exclude_fields: Vec::new(),
+                cookie_prefix: CookiePrefix::None,
+                legacy_cookies_until: 0,
+                honor_dnt: false,
+            },
+            // Fixture TC strings are years old, so freshness is checked explicitly in tests
+            gdpr: Gdpr {
+                tcf_max_age_days: 0,
+                ..Gdpr::default()
             },
+            identity: Identity::default(),
+            uid2: Uid2::default(),
+            user_sync: UserSync::default(),
+            gvl: Gvl::default(),
+            gpc: Gpc::default(),
+            consent_webhook: ConsentWebhook::default(),
+            compliance_log: ComplianceLog::default(),
+            retention: Retention::default(),
+            anonymization: Anonymization::default(),
+            cookie_policy: HashMap::from([("synthetic_id".to_string(), 1)]),
+            debug: DebugEndpoints::default(),
+            admin: Admin::default(),
         }
     }
 }
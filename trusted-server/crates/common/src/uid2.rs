@@ -0,0 +1,644 @@
+//! Unified ID 2.0 (UID2) integration.
+//!
+//! This module proxies `token/generate` and `token/refresh` calls to the UID2
+//! operator using the credentials configured in the `[uid2]` settings section,
+//! caches the resulting tokens in a KV store keyed by synthetic ID, and builds
+//! the UID2 entry for Prebid `user.ext.eids`.
+//!
+//! Operator requests and responses use the UID2 v2 envelope: AES-256-GCM with
+//! the client secret (or the token's `refresh_response_key` for refreshes).
+
+use std::time::Duration;
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use error_stack::{Report, ResultExt};
+use fastly::http::{header, Method, StatusCode};
+use fastly::{Error, Request, Response};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::error::{IntoHttpResponse, TrustedServerError};
+use crate::identity::{has_storage_consent, normalize_hashed_email};
+use crate::kv_store::JsonKvStore;
+use crate::settings::Settings;
+use crate::synthetic::resolve_synthetic_id;
+use crate::tcf_consent::{get_tcf_consent_or_default, TcfConsent};
+
+/// Fastly backend pointing at the UID2 operator.
+const UID2_BACKEND: &str = "uid2_operator";
+
+/// `source` of UID2 entries in `user.ext.eids`.
+const UID2_SOURCE: &str = "uidapi.com";
+
+/// Version byte prefixed to encrypted request envelopes.
+const ENVELOPE_VERSION: u8 = 1;
+
+/// Length of the AES-GCM initialization vector.
+const IV_LEN: usize = 12;
+
+/// Length of the big-endian millisecond timestamp inside envelopes.
+const TIMESTAMP_LEN: usize = 8;
+
+/// Length of the request nonce echoed back by the operator.
+const REQUEST_NONCE_LEN: usize = 8;
+
+/// A UID2 identity as returned by the operator.
+///
+/// All timestamps are Unix epoch milliseconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Uid2Token {
+    /// Token sent to bidders in `user.ext.eids`.
+    pub advertising_token: String,
+    /// Token used to obtain a new identity from the operator.
+    pub refresh_token: String,
+    /// When the advertising token stops being valid.
+    pub identity_expires: i64,
+    /// When the identity should be refreshed.
+    pub refresh_from: i64,
+    /// When the refresh token stops being valid.
+    pub refresh_expires: i64,
+    /// Base64-encoded key used to decrypt the refresh response.
+    pub refresh_response_key: String,
+}
+
+impl Uid2Token {
+    /// Returns `true` while the advertising token may be sent to bidders.
+    pub fn is_usable(&self, now_ms: i64) -> bool {
+        now_ms < self.identity_expires
+    }
+
+    /// Returns `true` once the identity is due for a refresh.
+    pub fn needs_refresh(&self, now_ms: i64) -> bool {
+        now_ms >= self.refresh_from
+    }
+
+    /// Returns `true` while the refresh token may still be used.
+    pub fn is_refreshable(&self, now_ms: i64) -> bool {
+        now_ms < self.refresh_expires
+    }
+}
+
+/// Decrypted operator response body.
+#[derive(Debug, Deserialize)]
+struct OperatorResponse {
+    status: String,
+    #[serde(default)]
+    body: Option<Uid2Token>,
+}
+
+fn uid2_error(message: impl Into<String>) -> TrustedServerError {
+    TrustedServerError::Uid2 {
+        message: message.into(),
+    }
+}
+
+fn cipher(key_b64: &str) -> Result<Aes256Gcm, Report<TrustedServerError>> {
+    let key = BASE64
+        .decode(key_b64)
+        .change_context(uid2_error("Key is not valid base64"))?;
+    Aes256Gcm::new_from_slice(&key).map_err(|_| Report::new(uid2_error("Key must be 32 bytes")))
+}
+
+/// Encrypts `payload` into a base64 UID2 request envelope.
+///
+/// # Errors
+///
+/// - [`TrustedServerError::Uid2`] if the client secret is invalid
+pub fn encrypt_request(
+    client_secret: &str,
+    payload: &[u8],
+    timestamp_ms: i64,
+    nonce: [u8; REQUEST_NONCE_LEN],
+) -> Result<String, Report<TrustedServerError>> {
+    let cipher = cipher(client_secret)?;
+
+    let mut iv = [0u8; IV_LEN];
+    rand::thread_rng().fill_bytes(&mut iv);
+
+    let mut plaintext = Vec::with_capacity(TIMESTAMP_LEN + REQUEST_NONCE_LEN + payload.len());
+    plaintext.extend_from_slice(&timestamp_ms.to_be_bytes());
+    plaintext.extend_from_slice(&nonce);
+    plaintext.extend_from_slice(payload);
+
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&iv), plaintext.as_slice())
+        .map_err(|_| Report::new(uid2_error("Failed to encrypt request")))?;
+
+    let mut envelope = Vec::with_capacity(1 + IV_LEN + ciphertext.len());
+    envelope.push(ENVELOPE_VERSION);
+    envelope.extend_from_slice(&iv);
+    envelope.extend_from_slice(&ciphertext);
+    Ok(BASE64.encode(envelope))
+}
+
+/// Decrypts a base64 UID2 response with `key`.
+///
+/// Responses to encrypted requests carry a timestamp and the request nonce
+/// ahead of the JSON body; pass that nonce as `expected_nonce` to verify it.
+/// Refresh responses contain the JSON body only.
+///
+/// # Errors
+///
+/// - [`TrustedServerError::Uid2`] if the response cannot be decrypted or the nonce does not match
+pub fn decrypt_response(
+    key: &str,
+    body: &str,
+    expected_nonce: Option<[u8; REQUEST_NONCE_LEN]>,
+) -> Result<Vec<u8>, Report<TrustedServerError>> {
+    let cipher = cipher(key)?;
+    let bytes = BASE64
+        .decode(body.trim())
+        .change_context(uid2_error("Response is not valid base64"))?;
+    if bytes.len() < IV_LEN {
+        return Err(Report::new(uid2_error("Response is too short")));
+    }
+
+    let (iv, ciphertext) = bytes.split_at(IV_LEN);
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(iv), ciphertext)
+        .map_err(|_| Report::new(uid2_error("Failed to decrypt response")))?;
+
+    let Some(expected_nonce) = expected_nonce else {
+        return Ok(plaintext);
+    };
+    let header_len = TIMESTAMP_LEN + REQUEST_NONCE_LEN;
+    if plaintext.len() < header_len || plaintext[TIMESTAMP_LEN..header_len] != expected_nonce {
+        return Err(Report::new(uid2_error(
+            "Response nonce does not match request",
+        )));
+    }
+    Ok(plaintext[header_len..].to_vec())
+}
+
+fn parse_operator_response(
+    payload: &[u8],
+) -> Result<Option<Uid2Token>, Report<TrustedServerError>> {
+    let response: OperatorResponse =
+        serde_json::from_slice(payload).change_context(uid2_error("Invalid operator response"))?;
+    match response.status.as_str() {
+        "success" => response
+            .body
+            .map(Some)
+            .ok_or_else(|| Report::new(uid2_error("Operator response is missing a body"))),
+        "optout" => Ok(None),
+        status => Err(Report::new(uid2_error(format!(
+            "Operator returned status {status}"
+        )))),
+    }
+}
+
+fn send_to_operator(
+    settings: &Settings,
+    path: &str,
+    body: String,
+) -> Result<String, Report<TrustedServerError>> {
+    let url = format!(
+        "{}{}",
+        settings.uid2.operator_url.trim_end_matches('/'),
+        path
+    );
+    let mut resp = Request::new(Method::POST, url)
+        .with_header(header::CONTENT_TYPE, "text/plain")
+        .with_header(
+            header::AUTHORIZATION,
+            format!("Bearer {}", settings.uid2.api_key),
+        )
+        .with_body(body)
+        .send(UID2_BACKEND)
+        .change_context(uid2_error(format!("Failed to call {path}")))?;
+
+    if resp.get_status() != StatusCode::OK {
+        return Err(Report::new(uid2_error(format!(
+            "{path} returned {}",
+            resp.get_status()
+        ))));
+    }
+    Ok(resp.take_body_str())
+}
+
+/// Generates a UID2 identity for a SHA-256 hashed email (hex encoded).
+///
+/// Returns [`None`] if the user opted out of UID2.
+///
+/// # Errors
+///
+/// - [`TrustedServerError::Uid2`] if the operator call fails or returns an error
+pub fn generate_token(
+    settings: &Settings,
+    hashed_email: &str,
+) -> Result<Option<Uid2Token>, Report<TrustedServerError>> {
+    let email_hash =
+        hex::decode(hashed_email).change_context(uid2_error("Hashed email is not valid hex"))?;
+    let payload = serde_json::to_vec(&json!({
+        "email_hash": BASE64.encode(email_hash),
+        "optout_check": 1,
+    }))
+    .change_context(uid2_error("Failed to serialize request"))?;
+
+    let mut nonce = [0u8; REQUEST_NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let envelope = encrypt_request(
+        &settings.uid2.client_secret,
+        &payload,
+        chrono::Utc::now().timestamp_millis(),
+        nonce,
+    )?;
+
+    let body = send_to_operator(settings, "/v2/token/generate", envelope)?;
+    let payload = decrypt_response(&settings.uid2.client_secret, &body, Some(nonce))?;
+    parse_operator_response(&payload)
+}
+
+/// Refreshes a UID2 identity.
+///
+/// Returns [`None`] if the user opted out of UID2.
+///
+/// # Errors
+///
+/// - [`TrustedServerError::Uid2`] if the operator call fails or returns an error
+pub fn refresh_token(
+    settings: &Settings,
+    token: &Uid2Token,
+) -> Result<Option<Uid2Token>, Report<TrustedServerError>> {
+    let body = send_to_operator(settings, "/v2/token/refresh", token.refresh_token.clone())?;
+    let payload = decrypt_response(&token.refresh_response_key, &body, None)?;
+    parse_operator_response(&payload)
+}
+
+/// KV cache of UID2 identities keyed by synthetic ID.
+pub struct Uid2TokenCache {
+    store: JsonKvStore,
+}
+
+impl Uid2TokenCache {
+    /// Opens the cache configured in `uid2.token_store`.
+    ///
+    /// Returns [`None`] when the UID2 integration is disabled.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the configured store cannot be opened
+    pub fn open(settings: &Settings) -> Result<Option<Self>, Report<TrustedServerError>> {
+        if settings.uid2.operator_url.is_empty() || settings.uid2.token_store.is_empty() {
+            return Ok(None);
+        }
+        let store = JsonKvStore::open(&settings.uid2.token_store)?;
+        Ok(Some(Self { store }))
+    }
+
+    fn key(synthetic_id: &str) -> String {
+        format!("uid2:{synthetic_id}")
+    }
+
+    /// Returns the cached identity for `synthetic_id`.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the lookup fails
+    pub fn get(&self, synthetic_id: &str) -> Result<Option<Uid2Token>, Report<TrustedServerError>> {
+        self.store.get(&Self::key(synthetic_id))
+    }
+
+    /// Caches `token` for `synthetic_id` until its refresh token expires.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the insert fails
+    pub fn put(
+        &self,
+        synthetic_id: &str,
+        token: &Uid2Token,
+    ) -> Result<(), Report<TrustedServerError>> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let ttl_ms = u64::try_from(token.refresh_expires - now).unwrap_or(0);
+        self.store.put_with_ttl(
+            &Self::key(synthetic_id),
+            token,
+            Duration::from_millis(ttl_ms.max(1000)),
+        )
+    }
+
+    /// Removes the cached identity for `synthetic_id`.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the delete fails
+    pub fn remove(&self, synthetic_id: &str) -> Result<(), Report<TrustedServerError>> {
+        self.store.delete(&Self::key(synthetic_id))
+    }
+}
+
+/// Returns a usable UID2 identity for `synthetic_id`, refreshing it when due.
+///
+/// # Errors
+///
+/// - [`TrustedServerError::KvStore`] if the token cache cannot be accessed
+/// - [`TrustedServerError::Uid2`] if a required refresh fails
+pub fn get_uid2_token(
+    settings: &Settings,
+    synthetic_id: &str,
+) -> Result<Option<Uid2Token>, Report<TrustedServerError>> {
+    let Some(cache) = Uid2TokenCache::open(settings)? else {
+        return Ok(None);
+    };
+    let Some(token) = cache.get(synthetic_id)? else {
+        return Ok(None);
+    };
+
+    let now = chrono::Utc::now().timestamp_millis();
+    if token.needs_refresh(now) && token.is_refreshable(now) {
+        return match refresh_token(settings, &token)? {
+            Some(refreshed) => {
+                cache.put(synthetic_id, &refreshed)?;
+                Ok(Some(refreshed))
+            }
+            None => {
+                cache.remove(synthetic_id)?;
+                Ok(None)
+            }
+        };
+    }
+
+    Ok(token.is_usable(now).then_some(token))
+}
+
+/// Builds the UID2 entry for `user.ext.eids`.
+pub fn uid2_eid(token: &Uid2Token) -> serde_json::Value {
+    json!({
+        "source": UID2_SOURCE,
+        "uids": [{
+            "id": token.advertising_token,
+            "atype": 3
+        }]
+    })
+}
+
+/// Returns the UID2 `user.ext.eids` entries for `synthetic_id`.
+///
+/// Returns no entries without storage consent or when UID2 is disabled.
+///
+/// # Errors
+///
+/// - [`TrustedServerError::KvStore`] if the token cache cannot be accessed
+/// - [`TrustedServerError::Uid2`] if a required refresh fails
+pub fn uid2_eids(
+    settings: &Settings,
+    synthetic_id: &str,
+    consent: &TcfConsent,
+) -> Result<Vec<serde_json::Value>, Report<TrustedServerError>> {
+    if !has_storage_consent(consent) {
+        return Ok(Vec::new());
+    }
+    Ok(get_uid2_token(settings, synthetic_id)?
+        .iter()
+        .map(uid2_eid)
+        .collect())
+}
+
+#[derive(Debug, Deserialize)]
+struct GenerateRequest {
+    hem: String,
+}
+
+fn token_response(token: Option<Uid2Token>) -> Result<Response, Error> {
+    let body = match token {
+        Some(token) => json!({ "status": "success", "body": token }),
+        None => json!({ "status": "optout" }),
+    };
+    Ok(Response::from_status(StatusCode::OK)
+        .with_header(header::CONTENT_TYPE, "application/json")
+        .with_header(header::CACHE_CONTROL, "no-store, private")
+        .with_body_json(&body)?)
+}
+
+fn error_response(report: Report<TrustedServerError>) -> Response {
+    log::error!("UID2 request failed: {:?}", report);
+    let error = report.current_context();
+    Response::from_status(error.status_code()).with_body(error.user_message())
+}
+
+/// Handles `POST /uid2/token/generate` and `POST /uid2/token/refresh`.
+///
+/// Generate requests carry a JSON body `{"hem": "<sha256 hex>"}`. Both routes
+/// cache the resulting identity under the request's synthetic ID and require
+/// storage consent.
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if the response body cannot be serialized.
+pub fn handle_uid2_request(settings: &Settings, mut req: Request) -> Result<Response, Error> {
+    if *req.get_method() != Method::POST {
+        return Ok(
+            Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_body("Method not allowed")
+        );
+    }
+    let cache = match Uid2TokenCache::open(settings) {
+        Ok(Some(cache)) => cache,
+        Ok(None) => return Ok(Response::from_status(StatusCode::NOT_FOUND).with_body("Not Found")),
+        Err(e) => return Ok(error_response(e)),
+    };
+
+    let consent = get_tcf_consent_or_default(settings, &req);
+    if !has_storage_consent(&consent) {
+        return Ok(Response::from_status(StatusCode::FORBIDDEN)
+            .with_body("Storage consent (Purpose 1) is required"));
+    }
+
+    let synthetic_id = match resolve_synthetic_id(settings, &req) {
+        Ok(id) => id.value,
+        Err(e) => return Ok(error_response(e)),
+    };
+
+    let result = match req.get_path() {
+        "/uid2/token/generate" => {
+            let Some(hem) = serde_json::from_slice::<GenerateRequest>(&req.take_body_bytes())
+                .ok()
+                .and_then(|body| normalize_hashed_email(&body.hem))
+            else {
+                return Ok(Response::from_status(StatusCode::BAD_REQUEST)
+                    .with_body("Expected a SHA-256 hex digest in the `hem` field"));
+            };
+            generate_token(settings, &hem)
+        }
+        "/uid2/token/refresh" => match cache.get(&synthetic_id) {
+            Ok(Some(token)) => refresh_token(settings, &token),
+            Ok(None) => {
+                return Ok(Response::from_status(StatusCode::NOT_FOUND)
+                    .with_body("No UID2 identity for this user"))
+            }
+            Err(e) => Err(e),
+        },
+        _ => return Ok(Response::from_status(StatusCode::NOT_FOUND).with_body("Not Found")),
+    };
+
+    let token = match result {
+        Ok(token) => token,
+        Err(e) => return Ok(error_response(e)),
+    };
+    let cached = match &token {
+        Some(token) => cache.put(&synthetic_id, token),
+        None => cache.remove(&synthetic_id),
+    };
+    if let Err(e) = cached {
+        log::warn!("Failed to cache UID2 identity: {:?}", e);
+    }
+
+    token_response(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::HEADER_SYNTHETIC_TRUSTED_SERVER;
+    use crate::test_support::tests::create_test_settings;
+
+    /// Base64 encoding of 32 zero bytes.
+    const SECRET: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+
+    fn test_token(now: i64) -> Uid2Token {
+        Uid2Token {
+            advertising_token: "advertising-token".to_string(),
+            refresh_token: "refresh-token".to_string(),
+            identity_expires: now + 3_600_000,
+            refresh_from: now + 1_800_000,
+            refresh_expires: now + 7_200_000,
+            refresh_response_key: SECRET.to_string(),
+        }
+    }
+
+    fn uid2_settings() -> Settings {
+        let mut settings = create_test_settings();
+        settings.uid2.operator_url = "https://operator-integ.uidapi.com".to_string();
+        settings.uid2.client_secret = SECRET.to_string();
+        settings.uid2.token_store = "test_uid2_store".to_string();
+        settings
+    }
+
+    /// Encrypts a response payload the way the operator does.
+    fn encrypt_response(plaintext: &[u8]) -> String {
+        let iv = [7u8; IV_LEN];
+        let ciphertext = cipher(SECRET)
+            .expect("should create the cipher")
+            .encrypt(Nonce::from_slice(&iv), plaintext)
+            .expect("should encrypt the payload");
+        BASE64.encode([iv.as_slice(), ciphertext.as_slice()].concat())
+    }
+
+    #[test]
+    fn test_encrypt_request_envelope() {
+        let nonce = [1, 2, 3, 4, 5, 6, 7, 8];
+        let envelope = encrypt_request(SECRET, b"{}", 1_700_000_000_000, nonce)
+            .expect("should encrypt the request");
+        let bytes = BASE64.decode(&envelope).expect("should decode base64");
+        assert_eq!(bytes[0], ENVELOPE_VERSION);
+
+        // The request envelope decrypts like a response once the version byte is dropped
+        let payload = decrypt_response(SECRET, &BASE64.encode(&bytes[1..]), Some(nonce))
+            .expect("should decrypt the response");
+        assert_eq!(payload, b"{}");
+    }
+
+    #[test]
+    fn test_decrypt_response_checks_nonce() {
+        let mut plaintext = 1_700_000_000_000i64.to_be_bytes().to_vec();
+        plaintext.extend_from_slice(&[9u8; REQUEST_NONCE_LEN]);
+        plaintext.extend_from_slice(b"{\"status\":\"optout\"}");
+        let body = encrypt_response(&plaintext);
+
+        let payload = decrypt_response(SECRET, &body, Some([9u8; REQUEST_NONCE_LEN]))
+            .expect("should decrypt the response");
+        assert_eq!(
+            parse_operator_response(&payload).expect("should parse the operator response"),
+            None
+        );
+        assert!(decrypt_response(SECRET, &body, Some([0u8; REQUEST_NONCE_LEN])).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_refresh_response() {
+        let token = test_token(0);
+        let body = encrypt_response(
+            &serde_json::to_vec(&json!({ "status": "success", "body": token }))
+                .expect("should serialize JSON"),
+        );
+
+        let payload = decrypt_response(SECRET, &body, None).expect("should decrypt the response");
+        assert_eq!(
+            parse_operator_response(&payload).expect("should parse the operator response"),
+            Some(token)
+        );
+    }
+
+    #[test]
+    fn test_parse_operator_error_status() {
+        let result = parse_operator_response(b"{\"status\":\"client_error\"}");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_token_lifecycle() {
+        let token = test_token(0);
+
+        assert!(token.is_usable(0));
+        assert!(!token.needs_refresh(0));
+        assert!(token.needs_refresh(1_800_000));
+        assert!(!token.is_usable(3_600_000));
+        assert!(token.is_refreshable(3_600_000));
+        assert!(!token.is_refreshable(7_200_000));
+    }
+
+    #[test]
+    fn test_uid2_eid_format() {
+        let eid = uid2_eid(&test_token(0));
+
+        assert_eq!(eid["source"], "uidapi.com");
+        assert_eq!(eid["uids"][0]["id"], "advertising-token");
+        assert_eq!(eid["uids"][0]["atype"], 3);
+    }
+
+    #[test]
+    fn test_cached_token_injected_as_eid() {
+        let settings = uid2_settings();
+        let cache = Uid2TokenCache::open(&settings)
+            .expect("should open the UID2 token cache")
+            .expect("should have a UID2 token cache configured");
+        let token = test_token(chrono::Utc::now().timestamp_millis());
+        cache
+            .put("uid2-synthetic", &token)
+            .expect("should write to the store");
+
+        let mut consent = TcfConsent::default();
+        assert!(uid2_eids(&settings, "uid2-synthetic", &consent)
+            .expect("should build UID2 EIDs")
+            .is_empty());
+
+        consent.purpose_consents.insert(1);
+        let eids =
+            uid2_eids(&settings, "uid2-synthetic", &consent).expect("should build UID2 EIDs");
+        assert_eq!(eids, vec![uid2_eid(&token)]);
+    }
+
+    #[test]
+    fn test_handle_uid2_request_disabled() {
+        let settings = create_test_settings();
+        let req = Request::post("http://example.com/uid2/token/refresh")
+            .with_header(HEADER_SYNTHETIC_TRUSTED_SERVER, "uid2-synthetic");
+
+        let resp = handle_uid2_request(&settings, req).expect("should handle the request");
+        assert_eq!(resp.get_status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_handle_uid2_request_requires_consent() {
+        let settings = uid2_settings();
+        let req = Request::post("http://example.com/uid2/token/generate")
+            .with_header(HEADER_SYNTHETIC_TRUSTED_SERVER, "uid2-synthetic");
+
+        let resp = handle_uid2_request(&settings, req).expect("should handle the request");
+        assert_eq!(resp.get_status(), StatusCode::FORBIDDEN);
+    }
+}
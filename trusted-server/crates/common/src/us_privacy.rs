@@ -0,0 +1,144 @@
+//! IAB US Privacy (`usp_v1`) string support for CCPA.
+//!
+//! The US Privacy string is four characters: the specification version,
+//! whether notice was given, whether the user opted out of sale, and whether
+//! the transaction is covered by the LSPA. Each flag is `Y`, `N` or `-` (not
+//! applicable). It is read from the `us_privacy` cookie, or the `usprivacy`
+//! cookie some CMPs write instead.
+
+use fastly::Request;
+use serde::{Deserialize, Serialize};
+
+use crate::cookies;
+
+/// Cookie names the US Privacy string is read from, in order of preference.
+pub const US_PRIVACY_COOKIES: [&str; 2] = ["us_privacy", "usprivacy"];
+
+/// Supported US Privacy specification version.
+const US_PRIVACY_VERSION: u8 = 1;
+
+/// Parsed US Privacy string.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CcpaConsent {
+    /// Original US Privacy string.
+    pub us_privacy: String,
+    /// Explicit notice and opportunity to opt out was given.
+    pub notice_given: Option<bool>,
+    /// User opted out of the sale of personal information.
+    pub opted_out_of_sale: Option<bool>,
+    /// Publisher is a signatory to the IAB Limited Service Provider Agreement.
+    pub lspa_covered: Option<bool>,
+}
+
+fn parse_flag(flag: char) -> Result<Option<bool>, String> {
+    match flag.to_ascii_uppercase() {
+        'Y' => Ok(Some(true)),
+        'N' => Ok(Some(false)),
+        '-' => Ok(None),
+        other => Err(format!("Invalid US Privacy flag {:?}", other)),
+    }
+}
+
+impl CcpaConsent {
+    /// Parses a US Privacy string such as `1YNN`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error message if the string is not a version 1 US Privacy string.
+    pub fn parse(us_privacy: &str) -> Result<Self, String> {
+        let chars: Vec<char> = us_privacy.chars().collect();
+        if chars.len() != 4 {
+            return Err(format!("Invalid US Privacy string length {}", chars.len()));
+        }
+        if chars[0].to_digit(10) != Some(u32::from(US_PRIVACY_VERSION)) {
+            return Err(format!("Unsupported US Privacy version {:?}", chars[0]));
+        }
+
+        Ok(Self {
+            us_privacy: us_privacy.to_string(),
+            notice_given: parse_flag(chars[1])?,
+            opted_out_of_sale: parse_flag(chars[2])?,
+            lspa_covered: parse_flag(chars[3])?,
+        })
+    }
+
+    /// Whether the user opted out of sale, requiring non-personalized ads.
+    pub fn is_opted_out(&self) -> bool {
+        self.opted_out_of_sale == Some(true)
+    }
+}
+
+/// Extracts the US Privacy string from the `us_privacy` or `usprivacy` cookie.
+///
+/// Returns [`None`] if neither cookie is present or the string is invalid.
+pub fn get_ccpa_consent_from_request(req: &Request) -> Option<CcpaConsent> {
+    let jar = match cookies::handle_request_cookies(req) {
+        Ok(Some(jar)) => jar,
+        Ok(None) => return None,
+        Err(e) => {
+            log::warn!("Failed to parse cookies for US Privacy: {:?}", e);
+            return None;
+        }
+    };
+
+    let cookie = US_PRIVACY_COOKIES.iter().find_map(|name| jar.get(name))?;
+    match CcpaConsent::parse(cookie.value()) {
+        Ok(consent) => Some(consent),
+        Err(e) => {
+            log::warn!("Ignoring invalid US Privacy cookie: {}", e);
+            None
+        }
+    }
+}
+
+/// Returns `true` if the request carries a US Privacy opt-out of sale.
+pub fn is_ccpa_opted_out(req: &Request) -> bool {
+    get_ccpa_consent_from_request(req).is_some_and(|consent| consent.is_opted_out())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_us_privacy() {
+        let consent = CcpaConsent::parse("1YNN").unwrap();
+        assert_eq!(consent.us_privacy, "1YNN");
+        assert_eq!(consent.notice_given, Some(true));
+        assert_eq!(consent.opted_out_of_sale, Some(false));
+        assert_eq!(consent.lspa_covered, Some(false));
+        assert!(!consent.is_opted_out());
+
+        let consent = CcpaConsent::parse("1yy-").unwrap();
+        assert!(consent.is_opted_out());
+        assert_eq!(consent.lspa_covered, None);
+
+        let consent = CcpaConsent::parse("1---").unwrap();
+        assert!(!consent.is_opted_out());
+    }
+
+    #[test]
+    fn test_parse_us_privacy_invalid() {
+        assert!(CcpaConsent::parse("").is_err());
+        assert!(CcpaConsent::parse("1YN").is_err());
+        assert!(CcpaConsent::parse("2YNN").is_err());
+        assert!(CcpaConsent::parse("1YXN").is_err());
+    }
+
+    #[test]
+    fn test_get_ccpa_consent_from_request() {
+        let req = Request::get("https://example.com").with_header("Cookie", "us_privacy=1YYN");
+        assert!(is_ccpa_opted_out(&req));
+
+        let req = Request::get("https://example.com").with_header("Cookie", "usprivacy=1YNN");
+        let consent = get_ccpa_consent_from_request(&req).unwrap();
+        assert_eq!(consent.us_privacy, "1YNN");
+        assert!(!is_ccpa_opted_out(&req));
+
+        let req = Request::get("https://example.com").with_header("Cookie", "us_privacy=bogus");
+        assert!(get_ccpa_consent_from_request(&req).is_none());
+
+        let req = Request::get("https://example.com");
+        assert!(!is_ccpa_opted_out(&req));
+    }
+}
@@ -0,0 +1,405 @@
+//! Server-side user syncing compatible with Prebid Server's `/setuid`.
+//!
+//! Bidders redirect the browser to `/setuid?bidder=<name>&uid=<id>` after their
+//! own cookie sync. Instead of writing a `uids` cookie like Prebid Server does,
+//! the partner UID is stored in a KV store against the synthetic ID.
+
+use std::collections::HashMap;
+
+use error_stack::Report;
+use fastly::http::{header, StatusCode};
+use fastly::{Error, Request, Response};
+use serde::{Deserialize, Serialize};
+
+use crate::consent::ConsentDecision;
+use crate::error::TrustedServerError;
+use crate::gpc::is_gpc_binding;
+use crate::kv_store::JsonKvStore;
+use crate::settings::Settings;
+use crate::synthetic::resolve_synthetic_id;
+use crate::tcf_consent::{
+    apply_request_signals, decode_tc_string, get_tcf_consent_or_default, purpose_ids, TcfConsent,
+};
+
+/// Transparent 1x1 GIF returned to image sync requests.
+const PIXEL_GIF: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+/// Maximum accepted length of a bidder name or partner UID.
+const MAX_PARAM_LEN: usize = 256;
+
+/// A partner UID synced for a bidder.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartnerUid {
+    /// The bidder's user ID.
+    pub uid: String,
+    /// Unix timestamp (seconds) of the last sync.
+    pub synced_at: i64,
+}
+
+/// Partner UIDs synced for a synthetic ID, keyed by bidder.
+pub type PartnerUids = HashMap<String, PartnerUid>;
+
+/// KV store of partner UIDs keyed by synthetic ID.
+pub struct PartnerUidStore {
+    store: JsonKvStore,
+}
+
+impl PartnerUidStore {
+    /// Opens the store configured in `user_sync.uid_store`.
+    ///
+    /// Returns [`None`] when user syncing is disabled.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the configured store cannot be opened
+    pub fn open(settings: &Settings) -> Result<Option<Self>, Report<TrustedServerError>> {
+        if settings.user_sync.uid_store.is_empty() {
+            return Ok(None);
+        }
+        let store = JsonKvStore::open(&settings.user_sync.uid_store)?;
+        Ok(Some(Self { store }))
+    }
+
+    fn key(synthetic_id: &str) -> String {
+        format!("uids:{synthetic_id}")
+    }
+
+    /// Returns the partner UIDs synced for `synthetic_id`.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the lookup fails
+    pub fn get(&self, synthetic_id: &str) -> Result<PartnerUids, Report<TrustedServerError>> {
+        Ok(self
+            .store
+            .get(&Self::key(synthetic_id))?
+            .unwrap_or_default())
+    }
+
+    /// Records `uid` for `bidder`, or removes the bidder's UID when `uid` is empty.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the store cannot be read or written
+    pub fn set(
+        &self,
+        synthetic_id: &str,
+        bidder: &str,
+        uid: &str,
+    ) -> Result<(), Report<TrustedServerError>> {
+        let mut uids = self.get(synthetic_id)?;
+        if uid.is_empty() {
+            uids.remove(bidder);
+        } else {
+            uids.insert(
+                bidder.to_string(),
+                PartnerUid {
+                    uid: uid.to_string(),
+                    synced_at: chrono::Utc::now().timestamp(),
+                },
+            );
+        }
+        self.store.put(&Self::key(synthetic_id), &uids)
+    }
+
+    /// Deletes every partner UID synced for `synthetic_id`.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the deletion fails
+    pub fn delete(&self, synthetic_id: &str) -> Result<(), Report<TrustedServerError>> {
+        self.store.delete(&Self::key(synthetic_id))
+    }
+}
+
+/// Returns `true` if `bidder` is a plausible Prebid bidder code.
+fn is_valid_bidder(bidder: &str) -> bool {
+    !bidder.is_empty()
+        && bidder.len() <= MAX_PARAM_LEN
+        && bidder
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// Reads consent from the `gdpr_consent` query parameter, falling back to the
+/// `euconsent-v2` cookie. Expired consent is ignored.
+fn sync_consent(settings: &Settings, req: &Request) -> TcfConsent {
+    if let Some(tc_string) = req
+        .get_query_parameter("gdpr_consent")
+        .filter(|s| !s.is_empty())
+    {
+        match decode_tc_string(tc_string) {
+            Ok(consent) => return apply_request_signals(settings, req, consent),
+            Err(e) => log::warn!("Ignoring invalid gdpr_consent parameter: {}", e),
+        }
+    }
+    get_tcf_consent_or_default(settings, req)
+}
+
+/// Returns `true` if the user consented to personalized advertising.
+fn has_advertising_consent(consent: &TcfConsent) -> bool {
+    consent
+        .purpose_consents
+        .contains_all(purpose_ids::ADVERTISING)
+}
+
+/// Handles `GET /setuid?bidder=<bidder>&uid=<uid>[&gdpr=0|1][&f=i|b]`.
+///
+/// Mirrors Prebid Server's `/setuid`: the response is a 1x1 GIF (`f=i`, the
+/// default) or an empty page (`f=b`), and `451` is returned when the consent
+/// decision rules out storage, a binding GPC signal is sent, or GDPR applies
+/// without TCF consent to the advertising purposes. An empty `uid` removes
+/// the bidder's mapping.
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if the synthetic ID cannot be resolved or the UID
+/// store cannot be written.
+pub fn handle_setuid(
+    settings: &Settings,
+    consent: &ConsentDecision,
+    req: Request,
+) -> Result<Response, Error> {
+    let Some(store) = PartnerUidStore::open(settings).map_err(|e| Error::msg(format!("{e:?}")))?
+    else {
+        return Ok(Response::from_status(StatusCode::NOT_FOUND).with_body("Not Found"));
+    };
+
+    let Some(bidder) = req
+        .get_query_parameter("bidder")
+        .filter(|b| is_valid_bidder(b))
+    else {
+        return Ok(Response::from_status(StatusCode::BAD_REQUEST)
+            .with_body("\"bidder\" query param is required"));
+    };
+    let uid = req.get_query_parameter("uid").unwrap_or_default();
+    if uid.len() > MAX_PARAM_LEN {
+        return Ok(Response::from_status(StatusCode::BAD_REQUEST).with_body("\"uid\" is too long"));
+    }
+
+    if !consent.storage_allowed {
+        return Ok(
+            Response::from_status(StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS)
+                .with_body("Consent prevents cookies from being saved"),
+        );
+    }
+    if consent.signals.tcf.gdpr_applies && !has_advertising_consent(&sync_consent(settings, &req)) {
+        return Ok(
+            Response::from_status(StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS)
+                .with_body("The gdpr_consent string prevents cookies from being saved"),
+        );
+    }
+    if is_gpc_binding(settings, &req) {
+        return Ok(
+            Response::from_status(StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS)
+                .with_body("The Sec-GPC signal prevents cookies from being saved"),
+        );
+    }
+
+    let synthetic_id =
+        resolve_synthetic_id(settings, &req).map_err(|e| Error::msg(format!("{e:?}")))?;
+    store
+        .set(&synthetic_id.value, bidder, uid)
+        .map_err(|e| Error::msg(format!("{e:?}")))?;
+    log::info!(
+        "Synced {} UID for Synthetic ID: {}",
+        bidder,
+        synthetic_id.value
+    );
+
+    let resp = Response::from_status(StatusCode::OK)
+        .with_header(header::CACHE_CONTROL, "no-store, private");
+    Ok(match req.get_query_parameter("f") {
+        Some("b") => resp.with_header(header::CONTENT_TYPE, "text/html"),
+        _ => resp
+            .with_header(header::CONTENT_TYPE, "image/gif")
+            .with_body(PIXEL_GIF),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::HEADER_SYNTHETIC_TRUSTED_SERVER;
+    use crate::privacy::Regime;
+    use crate::test_support::tests::create_test_settings;
+
+    /// TC string granting consent to purposes 1-3 only.
+    const PARTIAL_CONSENT: &str = "COvFyGBOvFyGBAbAAAENAPCAAOAAAAAAAAAAAEEUACCKAAA";
+    /// TC string granting consent to purposes 1-4.
+    const ADVERTISING_CONSENT: &str = "COvFyGBOvFyGBAbAAAENAPCAAPAAAAAAAAAAAEEUACCKAAA";
+
+    fn sync_settings() -> Settings {
+        let mut settings = create_test_settings();
+        settings.user_sync.uid_store = "test_uid_store".to_string();
+        settings
+    }
+
+    /// Decision for `req` under `regime`, with storage allowed.
+    fn storage_consent(settings: &Settings, req: &Request, regime: Regime) -> ConsentDecision {
+        let mut consent = ConsentDecision::from_request(settings, req);
+        consent.regime = regime;
+        consent.signals.tcf.gdpr_applies = regime == Regime::Gdpr;
+        consent.storage_allowed = true;
+        consent
+    }
+
+    fn setuid(settings: &Settings, req: Request) -> Response {
+        let consent = storage_consent(settings, &req, Regime::Gdpr);
+        handle_setuid(settings, &consent, req).expect("should handle the sync")
+    }
+
+    #[test]
+    fn test_is_valid_bidder() {
+        assert!(is_valid_bidder("appnexus"));
+        assert!(is_valid_bidder("the_trade-desk"));
+        assert!(!is_valid_bidder(""));
+        assert!(!is_valid_bidder("bad bidder"));
+        assert!(!is_valid_bidder("../uids"));
+    }
+
+    #[test]
+    fn test_has_advertising_consent() {
+        let mut consent = TcfConsent::default();
+        assert!(!has_advertising_consent(&consent));
+
+        for purpose in [2, 3, 4] {
+            consent.purpose_consents.insert(purpose);
+        }
+        assert!(has_advertising_consent(&consent));
+    }
+
+    #[test]
+    fn test_setuid_disabled() {
+        let settings = create_test_settings();
+        let req = Request::get("http://example.com/setuid?bidder=appnexus&uid=123");
+
+        let resp = setuid(&settings, req);
+        assert_eq!(resp.get_status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_setuid_requires_bidder() {
+        let settings = sync_settings();
+        let req = Request::get("http://example.com/setuid?uid=123");
+
+        let resp = setuid(&settings, req);
+        assert_eq!(resp.get_status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_setuid_requires_advertising_consent() {
+        let settings = sync_settings();
+        let req = Request::get(format!(
+            "http://example.com/setuid?bidder=appnexus&uid=123&gdpr=1&gdpr_consent={PARTIAL_CONSENT}"
+        ));
+
+        let resp = setuid(&settings, req);
+        assert_eq!(resp.get_status(), StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+    }
+
+    #[test]
+    fn test_setuid_requires_storage_consent() {
+        let settings = sync_settings();
+        let req = Request::get(format!(
+            "http://example.com/setuid?bidder=appnexus&uid=123&gdpr=1&gdpr_consent={ADVERTISING_CONSENT}"
+        ));
+        let mut consent = storage_consent(&settings, &req, Regime::Gdpr);
+        consent.storage_allowed = false;
+
+        let resp = handle_setuid(&settings, &consent, req).expect("should handle the sync");
+        assert_eq!(resp.get_status(), StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+    }
+
+    #[test]
+    fn test_setuid_outside_gdpr_needs_no_tc_string() {
+        let settings = sync_settings();
+        let req = Request::get("http://example.com/setuid?bidder=appnexus&uid=us-1")
+            .with_header(HEADER_SYNTHETIC_TRUSTED_SERVER, "ccpa-synthetic");
+        let consent = storage_consent(&settings, &req, Regime::Ccpa);
+        let resp = handle_setuid(&settings, &consent, req).expect("should handle the sync");
+        assert_eq!(resp.get_status(), StatusCode::OK);
+
+        // gdpr=0 does not waive the TC string for clients located under GDPR
+        let req = Request::get("http://example.com/setuid?bidder=appnexus&uid=eu-1&gdpr=0");
+        let resp = setuid(&settings, req);
+        assert_eq!(resp.get_status(), StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+    }
+
+    #[test]
+    fn test_setuid_refuses_binding_gpc() {
+        let mut settings = sync_settings();
+        settings.gpc.binding_regions = vec!["*".to_string()];
+        let req = Request::get(format!(
+            "http://example.com/setuid?bidder=appnexus&uid=123&gdpr=1&gdpr_consent={ADVERTISING_CONSENT}"
+        ))
+        .with_header("Sec-GPC", "1");
+
+        let resp = setuid(&settings, req);
+        assert_eq!(resp.get_status(), StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+    }
+
+    #[test]
+    fn test_partner_uid_store() {
+        let settings = sync_settings();
+        let store = PartnerUidStore::open(&settings)
+            .expect("should open the partner UID store")
+            .expect("should have a partner UID store configured");
+
+        store
+            .set("sync-synthetic", "appnexus", "an-123")
+            .expect("should write to the store");
+        store
+            .set("sync-synthetic", "rubicon", "rp-456")
+            .expect("should write to the store");
+        let uids = store.get("sync-synthetic").expect("should read the store");
+        assert_eq!(uids["appnexus"].uid, "an-123");
+        assert_eq!(uids["rubicon"].uid, "rp-456");
+
+        store
+            .set("sync-synthetic", "appnexus", "")
+            .expect("should write to the store");
+        let uids = store.get("sync-synthetic").expect("should read the store");
+        assert!(!uids.contains_key("appnexus"));
+        assert!(uids.contains_key("rubicon"));
+    }
+
+    #[test]
+    fn test_setuid_returns_pixel() {
+        let settings = sync_settings();
+        let req = Request::get(format!(
+            "http://example.com/setuid?bidder=appnexus&uid=an-789&gdpr=1&gdpr_consent={ADVERTISING_CONSENT}"
+        ))
+        .with_header(HEADER_SYNTHETIC_TRUSTED_SERVER, "pixel-synthetic");
+
+        let mut resp = setuid(&settings, req);
+        assert_eq!(resp.get_status(), StatusCode::OK);
+        assert_eq!(resp.get_header_str(header::CONTENT_TYPE), Some("image/gif"));
+        assert_eq!(resp.take_body_bytes(), PIXEL_GIF);
+
+        let store = PartnerUidStore::open(&settings)
+            .expect("should open the partner UID store")
+            .expect("should have a partner UID store configured");
+        assert_eq!(
+            store.get("pixel-synthetic").expect("should read the store")["appnexus"].uid,
+            "an-789"
+        );
+    }
+
+    #[test]
+    fn test_setuid_blank_format() {
+        let settings = sync_settings();
+        let req = Request::get(format!(
+            "http://example.com/setuid?bidder=rubicon&uid=rp-1&f=b&gdpr_consent={ADVERTISING_CONSENT}"
+        ))
+        .with_header(HEADER_SYNTHETIC_TRUSTED_SERVER, "blank-synthetic");
+
+        let mut resp = setuid(&settings, req);
+        assert_eq!(resp.get_status(), StatusCode::OK);
+        assert!(resp.take_body_bytes().is_empty());
+    }
+}
@@ -0,0 +1,333 @@
+//! Win, loss and billing notifications of auction bids.
+//!
+//! Bidders learn that they won through the bid's `nurl`, that they lost
+//! through its `lurl`, and bill the advertiser once the creative rendered
+//! through its `burl`. Leaving these to client code means they never fire
+//! when the page navigates away or a script is blocked, so the edge fires
+//! them instead: [`WinNotifier::notify_auction`] fires the `nurl` of every
+//! winning bid and the `lurl` of every other bid right after the auction and
+//! keeps the `burl` of the winners in the KV store in
+//! `win_notifications.kv_store` under a billing ID, and `POST /billing?id=`
+//! ([`handle_billing`]) fires the `burl` when the page confirms the render.
+//! The OpenRTB auction macros such as `${AUCTION_PRICE}` are substituted
+//! before (see [`crate::macros`]).
+
+use std::time::Duration;
+
+use error_stack::Report;
+use fastly::backend::{Backend, BackendCreationError};
+use fastly::http::request::PendingRequest;
+use fastly::http::{Method, StatusCode};
+use fastly::{Error, Request, Response};
+use url::Url;
+use uuid::Uuid;
+
+use crate::error::TrustedServerError;
+use crate::kv_store::JsonKvStore;
+use crate::prebid::WinningBid;
+use crate::settings::Settings;
+
+/// Timeout for connecting to a bidder's notification host.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Timeout for the first byte of a notification response.
+const FIRST_BYTE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Why a bid lost the auction, sent as an OpenRTB loss reason code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LossReason {
+    /// The bid had no positive price
+    MissingBidPrice,
+    /// The bid was below the impression's floor
+    BelowAuctionFloor,
+    /// Another bid for the impression, possibly from another endpoint, was
+    /// higher
+    LostToHigherBid,
+    /// The publisher disapproved the creative
+    CreativeDisapproved,
+    /// The publisher blocked the advertiser
+    AdvertiserExcluded,
+}
+
+impl LossReason {
+    /// OpenRTB loss reason code
+    pub fn code(self) -> u16 {
+        match self {
+            Self::MissingBidPrice => 9,
+            Self::BelowAuctionFloor => 100,
+            Self::LostToHigherBid => 102,
+            Self::CreativeDisapproved => 202,
+            Self::AdvertiserExcluded => 205,
+        }
+    }
+}
+
+/// Dynamic backend for the host of `url`, created on first use.
+fn backend(url: &Url) -> Result<Backend, Report<TrustedServerError>> {
+    let error = |message: &str| {
+        Report::new(TrustedServerError::Prebid {
+            message: format!("{message} for {url}"),
+        })
+    };
+    let host = url.host_str().ok_or_else(|| error("No host"))?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let name = format!(
+        "notice_{}_{}",
+        host.replace(|c: char| !c.is_ascii_alphanumeric(), "_"),
+        port
+    );
+    match Backend::builder(&name, format!("{host}:{port}"))
+        .override_host(host)
+        .enable_ssl()
+        .sni_hostname(host)
+        .check_certificate(host)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .first_byte_timeout(FIRST_BYTE_TIMEOUT)
+        .finish()
+    {
+        Ok(backend) => Ok(backend),
+        Err(BackendCreationError::NameInUse) => {
+            Backend::from_name(&name).map_err(|_| error("Backend lookup failed"))
+        }
+        Err(e) => Err(error("Backend creation failed").attach_printable(e.to_string())),
+    }
+}
+
+/// Sends a `GET` to `url` without waiting for the response.
+fn send(url: &str) -> Result<PendingRequest, Report<TrustedServerError>> {
+    let parsed = Url::parse(url)
+        .ok()
+        .filter(|url| url.scheme() == "https")
+        .ok_or_else(|| {
+            Report::new(TrustedServerError::Prebid {
+                message: format!("Notification URLs must use https: {url}"),
+            })
+        })?;
+    Request::get(url)
+        .send_async(backend(&parsed)?)
+        .map_err(|e| {
+            Report::new(TrustedServerError::Prebid {
+                message: format!("Failed to send notification to {url}"),
+            })
+            .attach_printable(e.to_string())
+        })
+}
+
+/// Fires `urls` in parallel and waits for their responses. Failures are
+/// logged, as a missed notification must not fail the ad request.
+pub fn fire(urls: impl IntoIterator<Item = String>) {
+    let pending: Vec<(String, PendingRequest)> = urls
+        .into_iter()
+        .filter_map(|url| match send(&url) {
+            Ok(pending) => Some((url, pending)),
+            Err(e) => {
+                log::warn!("Notification not sent: {:?}", e);
+                None
+            }
+        })
+        .collect();
+    for (url, pending) in pending {
+        match pending.wait() {
+            Ok(resp) if resp.get_status().is_success() => {}
+            Ok(resp) => log::warn!("Notification to {} answered {}", url, resp.get_status()),
+            Err(e) => log::warn!("Notification to {} failed: {}", url, e),
+        }
+    }
+}
+
+/// Win, loss and billing notifications configured in `win_notifications`.
+pub struct WinNotifier {
+    store: Option<JsonKvStore>,
+    ttl: Duration,
+}
+
+impl WinNotifier {
+    /// Opens the notifier, with the billing store in
+    /// `win_notifications.kv_store` when set.
+    ///
+    /// Returns [`None`] when win notifications are disabled.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the configured store cannot be opened
+    pub fn open(settings: &Settings) -> Result<Option<Self>, Report<TrustedServerError>> {
+        let config = &settings.win_notifications;
+        if !config.enabled {
+            return Ok(None);
+        }
+        let store = if config.kv_store.is_empty() {
+            None
+        } else {
+            Some(JsonKvStore::open(&config.kv_store)?)
+        };
+        Ok(Some(Self {
+            store,
+            ttl: Duration::from_secs(config.ttl_seconds),
+        }))
+    }
+
+    fn key(billing_id: &str) -> String {
+        format!("burl:{billing_id}")
+    }
+
+    /// Fires the `nurl` of every winning bid in `bids` together with the
+    /// `loss_notices` of the other bids (see
+    /// [`crate::prebid::BidResponse::loss_notices`]) and stores the `burl` of
+    /// the winners for [`handle_billing`], setting the bid's `billing_id`.
+    ///
+    /// Bids whose `burl` cannot be stored are logged and returned without a
+    /// billing ID.
+    pub fn notify_auction(&self, bids: &mut [WinningBid], loss_notices: Vec<String>) {
+        if let Some(store) = &self.store {
+            for bid in bids.iter_mut() {
+                let Some(burl) = bid.burl.as_deref() else {
+                    continue;
+                };
+                let billing_id = Uuid::new_v4().to_string();
+                match store.put_text_with_ttl(&Self::key(&billing_id), burl, self.ttl) {
+                    Ok(()) => bid.billing_id = Some(billing_id),
+                    Err(e) => log::warn!("Failed to store burl of {}: {:?}", bid.imp_id, e),
+                }
+            }
+        }
+        fire(
+            bids.iter()
+                .filter_map(|bid| bid.nurl.clone())
+                .chain(loss_notices),
+        );
+    }
+
+    /// Removes and returns the `burl` stored under `billing_id`, so every
+    /// render is billed once. Returns [`None`] if it expired, was already
+    /// taken or never existed.
+    ///
+    /// # Errors
+    ///
+    /// - [`TrustedServerError::KvStore`] if the KV store cannot be read or updated
+    pub fn take_billing_url(
+        &self,
+        billing_id: &str,
+    ) -> Result<Option<String>, Report<TrustedServerError>> {
+        let Some(store) = &self.store else {
+            return Ok(None);
+        };
+        let key = Self::key(billing_id);
+        let burl = store.get_text(&key)?;
+        if burl.is_some() {
+            store.delete(&key)?;
+        }
+        Ok(burl)
+    }
+}
+
+/// Handles `POST /billing?id=`, firing the `burl` of the winning bid whose
+/// render the page confirms with the `billing_id` of the auction result.
+///
+/// Responds `204` once the notification was fired, `404` when billing
+/// notifications are disabled or the ID is unknown, expired or already used,
+/// and `400` when the ID is missing or malformed.
+///
+/// # Errors
+///
+/// Returns a Fastly [`Error`] if the billing store cannot be read.
+pub fn handle_billing(settings: &Settings, req: Request) -> Result<Response, Error> {
+    if *req.get_method() != Method::POST {
+        return Ok(
+            Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_body("Method not allowed")
+        );
+    }
+    let Some(notifier) = WinNotifier::open(settings).map_err(|e| Error::msg(format!("{e:?}")))?
+    else {
+        return Ok(Response::from_status(StatusCode::NOT_FOUND).with_body("Not Found"));
+    };
+    let Some(billing_id) = req
+        .get_query_parameter("id")
+        .filter(|id| Uuid::parse_str(id).is_ok())
+    else {
+        return Ok(Response::from_status(StatusCode::BAD_REQUEST).with_body("Invalid id"));
+    };
+
+    match notifier
+        .take_billing_url(billing_id)
+        .map_err(|e| Error::msg(format!("{e:?}")))?
+    {
+        Some(burl) => {
+            fire([burl]);
+            Ok(Response::from_status(StatusCode::NO_CONTENT))
+        }
+        None => Ok(Response::from_status(StatusCode::NOT_FOUND).with_body("Not Found")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::test_support::tests::create_test_settings;
+
+    fn billing_settings() -> Settings {
+        let mut settings = create_test_settings();
+        settings.win_notifications.enabled = true;
+        settings.win_notifications.kv_store = "test_billing_store".to_string();
+        settings
+    }
+
+    #[test]
+    fn test_notify_auction_stores_billing_urls() {
+        let settings = billing_settings();
+        let notifier = WinNotifier::open(&settings).unwrap().unwrap();
+        let bid = |burl: Option<&str>| WinningBid {
+            imp_id: "top".to_string(),
+            bid_id: "bid-1".to_string(),
+            bidder: "smartadserver".to_string(),
+            price: 1.5,
+            currency: "USD".to_string(),
+            adm: Some("<div>ad</div>".to_string()),
+            adomain: Vec::new(),
+            crid: None,
+            width: None,
+            height: None,
+            targeting: BTreeMap::new(),
+            nurl: None,
+            burl: burl.map(str::to_string),
+            billing_id: None,
+        };
+        let mut bids = [bid(Some("https://dsp.example/bill?p=1.5")), bid(None)];
+        notifier.notify_auction(&mut bids, Vec::new());
+        assert_eq!(bids[1].billing_id, None);
+
+        let billing_id = bids[0].billing_id.clone().unwrap();
+        assert_eq!(
+            notifier.take_billing_url(&billing_id).unwrap().as_deref(),
+            Some("https://dsp.example/bill?p=1.5")
+        );
+        assert_eq!(notifier.take_billing_url(&billing_id).unwrap(), None);
+    }
+
+    #[test]
+    fn test_handle_billing_rejections() {
+        let settings = billing_settings();
+        let missing = Uuid::new_v4();
+        let cases = [
+            ("https://example.com/billing", StatusCode::BAD_REQUEST),
+            ("https://example.com/billing?id=x", StatusCode::BAD_REQUEST),
+            (
+                &format!("https://example.com/billing?id={missing}"),
+                StatusCode::NOT_FOUND,
+            ),
+        ];
+        for (url, status) in cases {
+            let resp = handle_billing(&settings, Request::post(url)).unwrap();
+            assert_eq!(resp.get_status(), status, "{url}");
+        }
+
+        let url = format!("https://example.com/billing?id={missing}");
+        let resp = handle_billing(&settings, Request::get(&url)).unwrap();
+        assert_eq!(resp.get_status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let disabled = create_test_settings();
+        let resp = handle_billing(&disabled, Request::post(&url)).unwrap();
+        assert_eq!(resp.get_status(), StatusCode::NOT_FOUND);
+    }
+}
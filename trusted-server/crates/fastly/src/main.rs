@@ -1,8 +1,8 @@
 use std::env;
+use std::time::{Duration, Instant};
 
 use fastly::geo::geo_lookup;
 use fastly::http::{header, Method, StatusCode};
-use fastly::KVStore;
 use fastly::{Error, Request, Response};
 use log::LevelFilter::Info;
 use serde_json::json;
@@ -10,32 +10,83 @@ use serde_json::json;
 mod error;
 use crate::error::to_error_response;
 
+use trusted_server_common::amp::handle_amp_rtc;
+use trusted_server_common::anonymization::{
+    handle_aggregates, handle_anonymize, AGGREGATES_PATH, ANONYMIZE_PATH,
+};
+use trusted_server_common::bid_validation::BlockList;
+use trusted_server_common::bidder_health::{
+    handle_prebid_bidders, handle_prebid_status, BIDDERS_PATH, STATUS_PATH,
+};
+use trusted_server_common::consent::ConsentDecision;
+use trusted_server_common::consent_receipt::handle_consent_receipts;
 use trusted_server_common::constants::{
     HEADER_SYNTHETIC_FRESH, HEADER_SYNTHETIC_TRUSTED_SERVER, HEADER_X_COMPRESS_HINT,
     HEADER_X_CONSENT_ADVERTISING, HEADER_X_FORWARDED_FOR, HEADER_X_GEO_CITY,
     HEADER_X_GEO_CONTINENT, HEADER_X_GEO_COORDINATES, HEADER_X_GEO_COUNTRY,
     HEADER_X_GEO_INFO_AVAILABLE, HEADER_X_GEO_METRO_CODE,
 };
-use trusted_server_common::cookies::create_synthetic_cookie;
+use trusted_server_common::cookies::{
+    delete_legacy_synthetic_cookies, filter_for_consent, handle_request_cookies,
+    verified_synthetic_cookie, ResponseCookies,
+};
+use trusted_server_common::creative_frame::{handle_creative, CREATIVE_PATH};
+use trusted_server_common::creative_rewrite::{
+    creative_proxy, handle_creative_proxy, rewrite_creative,
+};
+use trusted_server_common::deadline::Deadline;
+use trusted_server_common::device::ACCEPT_CH;
 use trusted_server_common::didomi::DidomiProxy;
+use trusted_server_common::dsar::{handle_request_status, REQUEST_STATUS_PATH};
 use trusted_server_common::gam::{
     handle_gam_custom_url, handle_gam_golden_url, handle_gam_render, handle_gam_test,
+    handle_gam_vast,
 };
+use trusted_server_common::gam_handoff::handle_gam_auction;
 // Note: TrustedServerError is used internally by the common crate
 use trusted_server_common::gdpr::{
-    handle_consent_request, handle_data_subject_request,
+    handle_consent_debug, handle_consent_request, handle_data_subject_request,
+    handle_data_verification_request, handle_tcf_cookie_request,
 };
-use trusted_server_common::tcf_consent::get_tcf_consent_from_request;
+use trusted_server_common::identity::{handle_hem_request, link_request_identity};
 use trusted_server_common::models::AdResponse;
-use trusted_server_common::prebid::PrebidRequest;
+use trusted_server_common::objection::handle_objection_request;
+use trusted_server_common::prebid::{handle_auction, BidResponse, PrebidRequest};
+use trusted_server_common::prebid_cache::handle_cache;
 use trusted_server_common::privacy::PRIVACY_TEMPLATE;
+use trusted_server_common::processing::handle_processing_activities;
+use trusted_server_common::retention::RetainedStore;
 use trusted_server_common::settings::Settings;
-use trusted_server_common::synthetic::{generate_synthetic_id, get_or_generate_synthetic_id};
+use trusted_server_common::synthetic::{
+    generate_synthetic_id, get_or_generate_synthetic_id, handle_synthetic_id_debug,
+    replace_erased_id, resolve_synthetic_id,
+};
+use trusted_server_common::tcf_consent::vendor_list_manager::load_vendor_list;
+use trusted_server_common::tcf_consent::{get_tcf_consent_or_default, purpose_ids};
 use trusted_server_common::templates::{GAM_TEST_TEMPLATE, HTML_TEMPLATE};
+use trusted_server_common::uid2::handle_uid2_request;
+use trusted_server_common::user_sync::handle_setuid;
 use trusted_server_common::why::WHY_TEMPLATE;
+use trusted_server_common::win_notice::handle_billing;
+
+/// Routes whose handlers personalize ads or count visits, and so have to
+/// honor objections to processing.
+const OBJECTION_ROUTES: &[&str] = &[
+    "/",
+    "/ad-creative",
+    "/prebid-test",
+    "/auction",
+    "/gam-test",
+    "/gam-test-custom-url",
+    "/gam-render",
+    "/gam-vast",
+    "/gam-auction",
+];
 
 #[fastly::main]
 fn main(req: Request) -> Result<Response, Error> {
+    // The auction budget counts from the arrival of the request
+    let started = Instant::now();
     // Print Settings only once at the beginning
     let settings = match Settings::new() {
         Ok(s) => s,
@@ -52,6 +103,17 @@ fn main(req: Request) -> Result<Response, Error> {
         .unwrap_or_else(|| "Unknown".to_string());
     log::info!("User IP: {}", client_ip);
 
+    // Consent is decided once per request; ad handlers only branch on the decision.
+    // Objections are only looked up for the routes personalizing ads or counting visits.
+    let consent = ConsentDecision::from_request(&settings, &req);
+    let consent = if OBJECTION_ROUTES.contains(&req.get_path()) {
+        consent.with_objection(&settings, &req)
+    } else {
+        consent
+    };
+    let auction_budget = Duration::from_millis(settings.prebid.auction_timeout_ms);
+    let auction_deadline = Deadline::new(started, auction_budget);
+
     futures::executor::block_on(async {
         log::info!(
             "FASTLY_SERVICE_VERSION: {}",
@@ -59,21 +121,63 @@ fn main(req: Request) -> Result<Response, Error> {
         );
 
         match (req.get_method(), req.get_path()) {
-            (&Method::GET, "/") => handle_main_page(&settings, req),
-            (&Method::GET, "/ad-creative") => handle_ad_request(&settings, req),
-            (&Method::GET, "/prebid-test") => handle_prebid_test(&settings, req).await,
-            (&Method::GET, "/gam-test") => handle_gam_test(&settings, req).await,
+            (&Method::GET, "/") => handle_main_page(&settings, &consent, req),
+            (&Method::GET, "/ad-creative") => handle_ad_request(&settings, &consent, req),
+            (&Method::GET, "/prebid-test") => handle_prebid_test(&settings, &consent, req).await,
+            (&Method::POST, "/auction") => {
+                handle_auction(&settings, &consent, req, auction_deadline).await
+            }
+            (&Method::GET, "/amp-rtc") => handle_amp_rtc(&settings, req, auction_deadline).await,
+            (&Method::GET, "/cache") => handle_cache(&settings, req),
+            (&Method::GET, BIDDERS_PATH) => handle_prebid_bidders(&settings, req),
+            (&Method::GET, STATUS_PATH) => handle_prebid_status(&settings, req),
+            (&Method::POST, "/billing") => handle_billing(&settings, req),
+            (&Method::GET, "/gam-test") => handle_gam_test(&settings, &consent, req).await,
             (&Method::GET, "/gam-golden-url") => handle_gam_golden_url(&settings, req).await,
-            (&Method::POST, "/gam-test-custom-url") => handle_gam_custom_url(&settings, req).await,
-            (&Method::GET, "/gam-render") => handle_gam_render(&settings, req).await,
+            (&Method::POST, "/gam-test-custom-url") => {
+                handle_gam_custom_url(&settings, &consent, req).await
+            }
+            (&Method::GET, "/gam-render") => handle_gam_render(&settings, &consent, req).await,
+            (&Method::GET, "/gam-vast") => handle_gam_vast(&settings, &consent, req).await,
+            (&Method::POST, "/gam-auction") => {
+                handle_gam_auction(&settings, &consent, req, auction_deadline).await
+            }
             (&Method::GET, "/gam-test-page") => Ok(Response::from_status(StatusCode::OK)
                 .with_body(GAM_TEST_TEMPLATE)
                 .with_header(header::CONTENT_TYPE, "text/html")
                 .with_header("x-compress-hint", "on")),
             (&Method::GET, "/gdpr/consent") => handle_consent_request(&settings, req),
             (&Method::POST, "/gdpr/consent") => handle_consent_request(&settings, req),
+            (&Method::GET, "/gdpr/receipts") => handle_consent_receipts(&settings, req),
+            (&Method::POST, "/gdpr/object") => handle_objection_request(&settings, req),
+            (&Method::DELETE, "/gdpr/object") => handle_objection_request(&settings, req),
+            (&Method::POST, "/gdpr/data/verify") => {
+                handle_data_verification_request(&settings, req)
+            }
             (&Method::GET, "/gdpr/data") => handle_data_subject_request(&settings, req),
+            (&Method::GET, "/gdpr/data/export") => handle_data_subject_request(&settings, req),
+            (&Method::PATCH, "/gdpr/data") => handle_data_subject_request(&settings, req),
             (&Method::DELETE, "/gdpr/data") => handle_data_subject_request(&settings, req),
+            (&Method::GET, "/gdpr/processing-activities") => {
+                handle_processing_activities(&settings, req)
+            }
+            (&Method::GET, path) if path.starts_with(REQUEST_STATUS_PATH) => {
+                handle_request_status(&settings, req)
+            }
+            (&Method::GET, path) if path.starts_with(CREATIVE_PATH) => {
+                handle_creative(&settings, req)
+            }
+            (&Method::GET, path) if creative_proxy(&settings, path).is_some() => {
+                handle_creative_proxy(&settings, req)
+            }
+            (&Method::POST, "/identity/hem") => handle_hem_request(&settings, req),
+            (&Method::GET, "/setuid") => handle_setuid(&settings, &consent, req),
+            (&Method::POST, "/uid2/token/generate") => handle_uid2_request(&settings, req),
+            (&Method::POST, "/uid2/token/refresh") => handle_uid2_request(&settings, req),
+            (&Method::GET, "/debug/synthetic-id") => handle_synthetic_id_debug(&settings, req),
+            (&Method::GET, "/debug/consent") => handle_consent_debug(&settings, req),
+            (&Method::POST, ANONYMIZE_PATH) => handle_anonymize(&settings, req),
+            (&Method::GET, AGGREGATES_PATH) => handle_aggregates(&settings, req),
             (&Method::GET, "/privacy-policy") => Ok(Response::from_status(StatusCode::OK)
                 .with_body(PRIVACY_TEMPLATE)
                 .with_header(header::CONTENT_TYPE, "text/html")
@@ -82,6 +186,7 @@ fn main(req: Request) -> Result<Response, Error> {
                 .with_body(WHY_TEMPLATE)
                 .with_header(header::CONTENT_TYPE, "text/html")
                 .with_header(HEADER_X_COMPRESS_HINT, "on")),
+            (&Method::POST, "/consent/tcf") => handle_tcf_cookie_request(&settings, req),
             // Didomi CMP reverse proxy routes
             (_, path) if path.starts_with("/consent/") => DidomiProxy::handle_consent_request(&settings, req).await,
             _ => Ok(Response::from_status(StatusCode::NOT_FOUND)
@@ -148,11 +253,18 @@ fn get_dma_code(req: &mut Request) -> Option<String> {
 /// Handles the main page request.
 ///
 /// Serves the main page with synthetic ID generation and ad integration.
+/// The synthetic ID is neither persisted in a cookie nor linked to the
+/// publisher user when the consent decision rules out storage (COPPA, strict
+/// DNT or no storage consent).
 ///
 /// # Errors
 ///
 /// Returns a Fastly [`Error`] if response creation fails.
-fn handle_main_page(settings: &Settings, mut req: Request) -> Result<Response, Error> {
+fn handle_main_page(
+    settings: &Settings,
+    consent: &ConsentDecision,
+    mut req: Request,
+) -> Result<Response, Error> {
     log::info!(
         "Using ad_partner_url: {}, counter_store: {}",
         settings.ad_server.ad_partner_url,
@@ -166,8 +278,8 @@ fn handle_main_page(settings: &Settings, mut req: Request) -> Result<Response, E
     log::info!("Main page - DMA Code: {:?}", dma_code);
 
     // Extract TCF consent for functional consent checking
-    let tcf_consent = get_tcf_consent_from_request(&req).unwrap_or_default();
-    let functional_consent = tcf_consent.purpose_consents.get(&1).unwrap_or(&false);
+    let tcf_consent = get_tcf_consent_or_default(settings, &req);
+    let functional_consent = tcf_consent.has_publisher_consent(purpose_ids::DEVICE_ACCESS);
     
     log::debug!("Main page - TCF GDPR applies: {}, Functional consent (Purpose 1): {}", 
                 tcf_consent.gdpr_applies, functional_consent);
@@ -190,12 +302,23 @@ fn handle_main_page(settings: &Settings, mut req: Request) -> Result<Response, E
 
     // Check for existing Trusted Server ID in this specific order:
     // 1. X-Synthetic-Trusted-Server header
-    // 2. Cookie
-    // 3. Fall back to fresh ID
-    let synthetic_id = match get_or_generate_synthetic_id(settings, &req) {
+    // 2. ID linked to the signed publisher user ID
+    // 3. Cookie
+    // 4. Fall back to fresh ID
+    let resolved_id = match resolve_synthetic_id(settings, &req) {
         Ok(id) => id,
         Err(e) => return Ok(to_error_response(e)),
     };
+    let synthetic_id = resolved_id.value.clone();
+
+    if !consent.storage_allowed {
+        log::info!(
+            "Storage not allowed ({:?}), not persisting the synthetic ID",
+            consent.reasons
+        );
+    } else if let Err(e) = link_request_identity(settings, &req, &resolved_id, &tcf_consent) {
+        log::warn!("Failed to link publisher identity: {:?}", e);
+    }
 
     log::info!(
         "Existing Trusted Server header: {:?}",
@@ -215,6 +338,8 @@ fn handle_main_page(settings: &Settings, mut req: Request) -> Result<Response, E
             "X-Geo-City, X-Geo-Country, X-Geo-Continent, X-Geo-Coordinates, X-Geo-Metro-Code, X-Geo-Info-Available"
         )
         .with_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        // Ask for the viewport width GAM requests filter ad unit sizes with
+        .with_header("Accept-CH", ACCEPT_CH)
         .with_header("x-compress-hint", "on");
 
     // Copy geo headers from request to response
@@ -231,13 +356,21 @@ fn handle_main_page(settings: &Settings, mut req: Request) -> Result<Response, E
         }
     }
 
-    // Only set cookies if we have consent
-    if *functional_consent {
-        response.set_header(
-            header::SET_COOKIE,
-            create_synthetic_cookie(settings, &synthetic_id),
-        );
+    // Only set cookies the user consented to, and only sign IDs the server
+    // generated or verified: a claimed ID must not turn into proof of ownership
+    let mut cookies = ResponseCookies::new();
+    if let Some(cookie) =
+        verified_synthetic_cookie(settings, &resolved_id).filter(|_| consent.storage_allowed)
+    {
+        cookies.add(cookie);
+        // Cookies issued under a previous `synthetic.cookie_prefix` are replaced
+        if let Ok(Some(jar)) = handle_request_cookies(&req) {
+            for legacy in delete_legacy_synthetic_cookies(settings, &jar) {
+                cookies.add(legacy);
+            }
+        }
     }
+    filter_for_consent(settings, &tcf_consent, &cookies).apply(&mut response);
 
     // Debug: Print all request headers
     log::info!("All Request Headers:");
@@ -264,17 +397,46 @@ fn handle_main_page(settings: &Settings, mut req: Request) -> Result<Response, E
 /// # Errors
 ///
 /// Returns a Fastly [`Error`] if response creation fails.
-fn handle_ad_request(settings: &Settings, mut req: Request) -> Result<Response, Error> {
-    // Extract TCF consent for advertising consent checking
-    let tcf_consent = get_tcf_consent_from_request(&req).unwrap_or_default();
-    let advertising_consent = tcf_consent.purpose_consents.get(&2).unwrap_or(&false);
-    
-    log::debug!("Ad request - TCF GDPR applies: {}, Advertising consent (Purpose 2): {}", 
-                tcf_consent.gdpr_applies, advertising_consent);
+fn handle_ad_request(
+    settings: &Settings,
+    consent: &ConsentDecision,
+    mut req: Request,
+) -> Result<Response, Error> {
+    // The ad partner needs its own TCF consent to be called at all, and to personalize
+    let vendor_id = Some(settings.ad_server.vendor_id);
+    let vendor_list = load_vendor_list(settings);
+    if !consent.allows_vendor(vendor_id, purpose_ids::BASIC_ADS, vendor_list.as_ref()) {
+        log::info!(
+            "No TCF consent for ad partner vendor {}, skipping ad request",
+            settings.ad_server.vendor_id
+        );
+        return Ok(Response::from_status(StatusCode::NO_CONTENT)
+            .with_header(header::CONTENT_TYPE, "application/json")
+            .with_header(HEADER_X_COMPRESS_HINT, "on")
+            .with_body("{}"));
+    }
+    let advertising_consent = consent.is_personalized()
+        && consent.allows_vendor(vendor_id, purpose_ids::ADVERTISING, vendor_list.as_ref());
+
+    log::debug!(
+        "Ad request - Regime: {:?}, Advertising consent: {}, Reasons: {:?}",
+        consent.regime,
+        advertising_consent,
+        consent.reasons
+    );
 
     // Add DMA code extraction
     let dma_code = get_dma_code(&mut req);
 
+    // Precise coordinates are only passed on to ad partners with a Special Feature 1 opt-in
+    if !consent.precise_geolocation_allowed {
+        log::debug!(
+            "No precise geolocation opt-in, dropping {}",
+            HEADER_X_GEO_COORDINATES
+        );
+        req.remove_header(HEADER_X_GEO_COORDINATES);
+    }
+
     log::info!("Client location - DMA Code: {:?}", dma_code);
 
     // Log headers for debugging
@@ -290,9 +452,10 @@ fn handle_ad_request(settings: &Settings, mut req: Request) -> Result<Response,
     log::info!("X-Forwarded-For: {}", x_forwarded_for.unwrap_or("None"));
     log::info!("Advertising consent: {}", advertising_consent);
 
-    // Generate synthetic ID only if we have consent
-    let synthetic_id = if *advertising_consent {
-        match generate_synthetic_id(settings, &req) {
+    // Generate synthetic ID only if we have consent. Visits are counted
+    // against it, so an erased ID is replaced first
+    let synthetic_id = if advertising_consent {
+        match generate_synthetic_id(settings, &req).and_then(|id| replace_erased_id(settings, id)) {
             Ok(id) => id,
             Err(e) => return Ok(to_error_response(e)),
         }
@@ -302,44 +465,37 @@ fn handle_ad_request(settings: &Settings, mut req: Request) -> Result<Response,
     };
 
     // Only track visits if we have consent
-    if *advertising_consent {
+    if advertising_consent && consent.analytics_allowed {
         // Increment visit counter in KV store
         log::info!("Opening KV store: {}", settings.synthetic.counter_store);
-        if let Ok(Some(store)) = KVStore::open(settings.synthetic.counter_store.as_str()) {
+        if let Ok(store) = RetainedStore::open(settings, &settings.synthetic.counter_store) {
             log::info!("Fetching current count for synthetic ID: {}", synthetic_id);
             let current_count: i32 = store
-                .lookup(&synthetic_id)
-                .map(|mut val| match String::from_utf8(val.take_body_bytes()) {
-                    Ok(s) => {
-                        log::info!("Value from KV store: {}", s);
-                        Some(s)
-                    }
-                    Err(e) => {
-                        log::error!("Error converting bytes to string: {}", e);
-                        None
-                    }
+                .get(&synthetic_id)
+                .unwrap_or_else(|e| {
+                    log::error!("Error reading count from KV store: {:?}", e);
+                    None
                 })
-                .map(|opt_s| {
-                    log::info!("Parsing string value: {:?}", opt_s);
-                    opt_s.and_then(|s| s.parse().ok())
+                .and_then(|s| {
+                    log::info!("Parsing string value: {}", s);
+                    s.parse().ok()
                 })
-                .unwrap_or_else(|_| {
+                .unwrap_or_else(|| {
                     log::info!("No existing count found, starting at 0");
-                    None
-                })
-                .unwrap_or(0);
+                    0
+                });
 
             let new_count = current_count + 1;
             log::info!("Incrementing count from {} to {}", current_count, new_count);
 
-            if let Err(e) = store.insert(&synthetic_id, new_count.to_string().as_bytes()) {
+            if let Err(e) = store.put(&synthetic_id, &new_count.to_string()) {
                 log::error!("Error updating KV store: {:?}", e);
             }
         }
     }
 
     // Modify the ad server URL construction to include DMA code if available
-    let ad_server_url = if *advertising_consent {
+    let ad_server_url = if advertising_consent {
         let mut url = settings
             .ad_server
             .sync_url
@@ -364,7 +520,7 @@ fn handle_ad_request(settings: &Settings, mut req: Request) -> Result<Response,
     // Add consent information to the ad request
     ad_req.set_header(
         HEADER_X_CONSENT_ADVERTISING,
-        if *advertising_consent { "true" } else { "false" },
+        if advertising_consent { "true" } else { "false" },
     );
 
     log::info!("Request headers to Equativ:");
@@ -411,11 +567,15 @@ fn handle_ad_request(settings: &Settings, mut req: Request) -> Result<Response,
             }
 
             if res.get_status().is_success() {
-                let body = res.take_body_str();
+                // Load the creative from its first-party host
+                let body = rewrite_creative(settings, &res.take_body_str());
                 log::info!("Backend response body: {}", body);
 
-                // Parse the JSON response and extract opid
-                if let Ok(ad_response) = serde_json::from_str::<AdResponse>(&body) {
+                // Parse the JSON response and extract opid, if it may be persisted
+                if let Some(ad_response) = serde_json::from_str::<AdResponse>(&body)
+                    .ok()
+                    .filter(|_| consent.storage_allowed)
+                {
                     // Look for the callback with type "impression"
                     if let Some(callback) = ad_response
                         .callbacks
@@ -436,10 +596,10 @@ fn handle_ad_request(settings: &Settings, mut req: Request) -> Result<Response,
                                 "Attempting to open KV store: {}",
                                 settings.synthetic.opid_store
                             );
-                            match KVStore::open(settings.synthetic.opid_store.as_str()) {
-                                Ok(Some(store)) => {
+                            match RetainedStore::open(settings, &settings.synthetic.opid_store) {
+                                Ok(store) => {
                                     log::info!("Successfully opened KV store");
-                                    match store.insert(&synthetic_id, opid.as_bytes()) {
+                                    match store.put(&synthetic_id, opid) {
                                         Ok(_) => log::info!(
                                             "Successfully stored opid {} for synthetic ID: {}",
                                             opid,
@@ -450,12 +610,6 @@ fn handle_ad_request(settings: &Settings, mut req: Request) -> Result<Response,
                                         }
                                     }
                                 }
-                                Ok(None) => {
-                                    log::warn!(
-                                        "KV store returned None: {}",
-                                        settings.synthetic.opid_store
-                                    );
-                                }
                                 Err(e) => {
                                     log::error!(
                                         "Error opening KV store '{}': {:?}",
@@ -514,20 +668,21 @@ fn handle_ad_request(settings: &Settings, mut req: Request) -> Result<Response,
 }
 
 /// Handles the prebid test route with detailed error logging
-async fn handle_prebid_test(settings: &Settings, mut req: Request) -> Result<Response, Error> {
+async fn handle_prebid_test(
+    settings: &Settings,
+    consent: &ConsentDecision,
+    mut req: Request,
+) -> Result<Response, Error> {
     log::info!("Starting prebid test request handling");
 
-    // Extract TCF consent from euconsent-v2 cookie
-    let tcf_consent = get_tcf_consent_from_request(&req).unwrap_or_default();
-    
-    // For RTB, we need basic advertising consent (Purpose 2: Select basic ads)
     // This is vendor-agnostic - any vendor in bid request will be checked by SSP/DSP
-    // We only check if basic advertising purposes are consented in TCF string
-    let advertising_consent = !tcf_consent.purpose_consents.is_empty() 
-        && *tcf_consent.purpose_consents.get(&2).unwrap_or(&false);
-    
-    log::info!("TCF consent - GDPR applies: {}, Basic advertising consent: {}", 
-               tcf_consent.gdpr_applies, advertising_consent);
+    let advertising_consent = consent.is_personalized();
+
+    log::info!(
+        "TCF consent - GDPR applies: {}, Advertising consent: {}",
+        consent.signals.tcf.gdpr_applies,
+        advertising_consent
+    );
 
     // Calculate fresh ID and synthetic ID only if we have advertising consent
     let (fresh_id, synthetic_id) = if advertising_consent {
@@ -597,7 +752,7 @@ async fn handle_prebid_test(settings: &Settings, mut req: Request) -> Result<Res
 
     log::info!("Attempting to send bid request to Prebid Server at prebid_backend");
 
-    match prebid_req.send_bid_request(settings, &req).await {
+    match prebid_req.send_bid_request(settings, consent, &req).await {
         Ok(mut prebid_response) => {
             log::info!("Received response from Prebid Server");
             log::info!("Response status: {}", prebid_response.get_status());
@@ -607,8 +762,25 @@ async fn handle_prebid_test(settings: &Settings, mut req: Request) -> Result<Res
                 log::info!("  {}: {:?}", name, value);
             }
 
-            let body = prebid_response.take_body_str();
-            log::info!("Response body: {}", body);
+            let body = prebid_response.take_body_bytes();
+            log::info!("Response body: {}", String::from_utf8_lossy(&body));
+
+            let mut bid_response = match BidResponse::parse(&body) {
+                Ok(bid_response) => bid_response,
+                Err(e) => {
+                    log::error!("Error parsing bid response: {:?}", e);
+                    return Ok(Response::from_status(StatusCode::BAD_GATEWAY)
+                        .with_header(header::CONTENT_TYPE, "application/json")
+                        .with_body_json(&json!({
+                            "error": "Invalid bid response",
+                            "details": format!("{:?}", e)
+                        }))?);
+                }
+            };
+            let block_list = BlockList::load(settings).map_err(|e| Error::msg(format!("{e:?}")))?;
+            block_list.reject_blocked(settings, &mut bid_response);
+            let result = bid_response.auction_result(settings);
+            log::info!("Selected {} winning bids", result.bids.len());
 
             Ok(Response::from_status(StatusCode::OK)
                 .with_header(header::CONTENT_TYPE, "application/json")
@@ -619,7 +791,7 @@ async fn handle_prebid_test(settings: &Settings, mut req: Request) -> Result<Res
                     if advertising_consent { "true" } else { "false" },
                 )
                 .with_header(HEADER_X_COMPRESS_HINT, "on")
-                .with_body(body))
+                .with_body_json(&result)?)
         }
         Err(e) => {
             log::error!("Error sending bid request: {:?}", e);